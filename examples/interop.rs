@@ -0,0 +1,453 @@
+//! Checks that the peer wire protocol implementation works against another
+//! client, e.g. a local qBittorrent or Transmission seeding a test file.
+//!
+//! The example connects to the peer, performs the handshake, exchanges
+//! bitfields, and downloads and verifies a whole piece, then prints a
+//! conformance report of each step. The peer is either given directly, or
+//! it's the first peer returned by the torrent's tracker.
+//!
+//! ```txt
+//! cargo run --example interop -- <torrent file> [peer address]
+//! ```
+//!
+//! The process exits with an error if any of the steps failed.
+
+use std::{fmt, net::SocketAddr, process::ExitCode, time::Duration};
+
+use bt_rust::{
+  blockinfo::{block_count, block_len, BlockInfo},
+  conf::CLIENT_ID,
+  metainfo::Metainfo,
+  peer::codec::{
+    handshake::{Handshake, HandshakeCodec, PROTOCOL_STRING},
+    message::Message,
+    peercodec::PeerCodec,
+  },
+  tracker::prelude::{Announce, DefaultTrackerBackend, Event, TrackerBackend},
+  Bitfield, BLOCK_LEN,
+};
+use futures::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio::{net::TcpStream, time};
+use tokio_util::codec::{Framed, FramedParts};
+
+/// How long to wait for each step before failing it.
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of block requests kept in flight while downloading the piece.
+const REQUEST_QUEUE_LEN: usize = 16;
+
+type Result<T, E = String> = std::result::Result<T, E>;
+
+/// The outcome of a single step of the conformance check.
+enum Outcome {
+  Pass(String),
+  Skip(String),
+  Fail(String),
+}
+
+/// The outcomes of the steps run so far, in order.
+#[derive(Default)]
+struct Report {
+  steps: Vec<(&'static str, Outcome)>,
+}
+
+impl Report {
+  /// Records the step's outcome, returning whether it passed.
+  fn record(&mut self, step: &'static str, result: Result<String>) -> bool {
+    let passed = result.is_ok();
+    let outcome = match result {
+      Ok(detail) => Outcome::Pass(detail),
+      Err(e) => Outcome::Fail(e),
+    };
+    self.steps.push((step, outcome));
+    passed
+  }
+
+  fn skip(&mut self, step: &'static str, reason: impl Into<String>) {
+    self.steps.push((step, Outcome::Skip(reason.into())));
+  }
+
+  fn has_failed(&self) -> bool {
+    self
+      .steps
+      .iter()
+      .any(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+  }
+}
+
+impl fmt::Display for Report {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Conformance report")?;
+    for (step, outcome) in &self.steps {
+      let (status, detail) = match outcome {
+        Outcome::Pass(detail) => ("PASS", detail),
+        Outcome::Skip(detail) => ("SKIP", detail),
+        Outcome::Fail(detail) => ("FAIL", detail),
+      };
+      writeln!(f, "  [{}] {:<10} {}", status, step, detail)?;
+    }
+    Ok(())
+  }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+  let mut args = std::env::args().skip(1);
+  let Some(path) = args.next() else {
+    eprintln!("usage: interop <torrent file> [peer address]");
+    return ExitCode::FAILURE;
+  };
+  let metainfo = match std::fs::read(&path)
+    .map_err(|e| e.to_string())
+    .and_then(|bytes| Metainfo::from_bytes(&bytes).map_err(|e| e.to_string()))
+  {
+    Ok(metainfo) => metainfo,
+    Err(e) => {
+      eprintln!("cannot read torrent {}: {}", path, e);
+      return ExitCode::FAILURE;
+    }
+  };
+
+  let mut report = Report::default();
+  run(&metainfo, args.next(), &mut report).await;
+  print!("{}", report);
+  if report.has_failed() {
+    ExitCode::FAILURE
+  } else {
+    ExitCode::SUCCESS
+  }
+}
+
+/// Runs the steps in order, stopping at the first one that fails.
+async fn run(metainfo: &Metainfo, peer: Option<String>, report: &mut Report) {
+  let addr = match peer {
+    Some(peer) => {
+      report.skip("announce", "peer address given");
+      match peer.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(e) => {
+          report.record("connect", Err(format!("invalid address: {}", e)));
+          return;
+        }
+      }
+    }
+    None => match announce(metainfo).await {
+      Ok(addr) => {
+        report.record("announce", Ok(format!("tracker returned {}", addr)));
+        addr
+      }
+      Err(e) => {
+        report.record("announce", Err(e));
+        return;
+      }
+    },
+  };
+
+  let socket = match step(TcpStream::connect(addr)).await {
+    Ok(socket) => {
+      report.record("connect", Ok(format!("connected to {}", addr)));
+      Framed::new(socket, HandshakeCodec)
+    }
+    Err(e) => {
+      report.record("connect", Err(e));
+      return;
+    }
+  };
+
+  let mut socket = match handshake(socket, metainfo).await {
+    Ok((socket, detail)) => {
+      report.record("handshake", Ok(detail));
+      socket
+    }
+    Err(e) => {
+      report.record("handshake", Err(e));
+      return;
+    }
+  };
+
+  let mut peer_pieces = match exchange_bitfields(&mut socket, metainfo).await {
+    Ok(Some(peer_pieces)) => {
+      let detail = format!(
+        "peer has {}/{} pieces",
+        peer_pieces.count_ones(),
+        metainfo.piece_count()
+      );
+      report.record("bitfield", Ok(detail));
+      peer_pieces
+    }
+    Ok(None) => {
+      report.skip("bitfield", "peer sent no bitfield");
+      Bitfield::repeat(false, metainfo.piece_count())
+    }
+    Err(e) => {
+      report.record("bitfield", Err(e));
+      return;
+    }
+  };
+
+  let unchoke = wait_for_unchoke(&mut socket, &mut peer_pieces).await;
+  if !report.record("unchoke", unchoke.map(|_| "peer unchoked us".to_owned())) {
+    return;
+  }
+
+  let Some(index) = peer_pieces.first_one() else {
+    report.skip("transfer", "peer has no pieces");
+    return;
+  };
+  let transfer = download_piece(&mut socket, metainfo, index).await;
+  report.record("transfer", transfer);
+}
+
+/// Announces to the torrent's first supported tracker, returning the first
+/// peer.
+async fn announce(metainfo: &Metainfo) -> Result<SocketAddr> {
+  let url = metainfo
+    .trackers
+    .iter()
+    .find(|url| DefaultTrackerBackend.supports(url))
+    .ok_or_else(|| "torrent has no supported trackers".to_owned())?;
+  let tracker = DefaultTrackerBackend.new_client(
+    url.clone(),
+    &Default::default(),
+    &Default::default(),
+  );
+  let params = Announce {
+    info_hash: metainfo.info_hash,
+    peer_id: *CLIENT_ID,
+    port: 6881,
+    ip: None,
+    ipv4: None,
+    ipv6: None,
+    downloaded: 0,
+    uploaded: 0,
+    left: metainfo.download_len(),
+    peer_count: Some(1),
+    tracker_id: None,
+    key: None,
+    event: Some(Event::Started),
+  };
+  let resp = step(async {
+    tracker
+      .announce(params, None)
+      .await
+      .map_err(|e| e.to_string())
+  })
+  .await?;
+  resp
+    .peers
+    .first()
+    .copied()
+    .ok_or_else(|| format!("tracker {} returned no peers", url))
+}
+
+/// Sends our handshake and checks the peer's, switching the connection to
+/// the peer message codec.
+async fn handshake(
+  mut socket: Framed<TcpStream, HandshakeCodec>,
+  metainfo: &Metainfo,
+) -> Result<(Framed<TcpStream, PeerCodec>, String)> {
+  let handshake = Handshake::new(metainfo.info_hash, *CLIENT_ID);
+  step(socket.send(handshake)).await?;
+
+  let peer_handshake = step(async {
+    socket
+      .next()
+      .await
+      .ok_or_else(|| "connection closed".to_owned())?
+      .map_err(|e| e.to_string())
+  })
+  .await?;
+  if peer_handshake.prot != PROTOCOL_STRING.as_bytes() {
+    return Err("invalid protocol string".to_owned());
+  }
+  if peer_handshake.info_hash != metainfo.info_hash {
+    return Err("info hash mismatch".to_owned());
+  }
+
+  // keep what the peer may have sent after the handshake
+  let old_parts = socket.into_parts();
+  let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
+  new_parts.read_buf = old_parts.read_buf;
+  new_parts.write_buf = old_parts.write_buf;
+  let peer_id = String::from_utf8_lossy(&peer_handshake.peer_id).into_owned();
+  Ok((
+    Framed::from_parts(new_parts),
+    format!("peer id {:?}", peer_id),
+  ))
+}
+
+/// Sends our empty bitfield and interest, returning the peer's bitfield if
+/// it sent one as its first message.
+async fn exchange_bitfields(
+  socket: &mut Framed<TcpStream, PeerCodec>,
+  metainfo: &Metainfo,
+) -> Result<Option<Bitfield>> {
+  let piece_count = metainfo.piece_count();
+  let mut own_pieces = Bitfield::repeat(false, piece_count);
+  // the bitfield is sent in whole bytes
+  own_pieces.resize(piece_count.div_ceil(8) * 8, false);
+  step(socket.send(Message::Bitfield(own_pieces))).await?;
+  step(socket.send(Message::Interested)).await?;
+
+  // the bitfield is only valid as the first message, and peers without
+  // pieces may omit it
+  match time::timeout(STEP_TIMEOUT, socket.next()).await {
+    Ok(Some(Ok(Message::Bitfield(mut bitfield)))) => {
+      if bitfield.len() != piece_count.div_ceil(8) * 8 {
+        return Err(format!(
+          "bitfield has {} bits for {} pieces",
+          bitfield.len(),
+          piece_count
+        ));
+      }
+      if bitfield[piece_count..].any() {
+        return Err("bitfield has spare bits set".to_owned());
+      }
+      bitfield.truncate(piece_count);
+      Ok(Some(bitfield))
+    }
+    Ok(Some(Ok(msg))) => {
+      // the message still counts, e.g. a have or an unchoke
+      log_msg(&msg);
+      Ok(None)
+    }
+    Ok(Some(Err(e))) => Err(e.to_string()),
+    Ok(None) => Err("connection closed".to_owned()),
+    Err(_) => Ok(None),
+  }
+}
+
+/// Waits for the peer to unchoke us, recording the pieces it announces in
+/// the meantime.
+async fn wait_for_unchoke(
+  socket: &mut Framed<TcpStream, PeerCodec>,
+  peer_pieces: &mut Bitfield,
+) -> Result<()> {
+  step(async {
+    loop {
+      match next_msg(socket).await? {
+        Message::Unchoke => return Ok(()),
+        Message::Have { piece_index } => {
+          if piece_index >= peer_pieces.len() {
+            return Err(format!("invalid have index {}", piece_index));
+          }
+          peer_pieces.set(piece_index, true);
+        }
+        Message::Bitfield(_) => {
+          return Err("bitfield sent after the first message".to_owned())
+        }
+        msg => log_msg(&msg),
+      }
+    }
+  })
+  .await
+}
+
+/// Downloads all blocks of the piece and checks its hash.
+async fn download_piece(
+  socket: &mut Framed<TcpStream, PeerCodec>,
+  metainfo: &Metainfo,
+  index: usize,
+) -> Result<String> {
+  let piece_len = if index == metainfo.piece_count() - 1 {
+    let len =
+      metainfo.download_len() - index as u64 * metainfo.piece_len as u64;
+    len as u32
+  } else {
+    metainfo.piece_len
+  };
+  let count = block_count(piece_len);
+  let blocks: Vec<_> = (0..count)
+    .map(|block| BlockInfo {
+      piece_index: index,
+      offset: block as u32 * BLOCK_LEN,
+      len: block_len(piece_len, block),
+    })
+    .collect();
+
+  let mut data = vec![0; piece_len as usize];
+  let mut received = vec![false; count];
+  let mut received_count = 0;
+  let mut requested_count = 0;
+  step(async {
+    while received_count < count {
+      while requested_count < count
+        && requested_count - received_count < REQUEST_QUEUE_LEN
+      {
+        socket
+          .send(Message::Request(blocks[requested_count]))
+          .await
+          .map_err(|e| e.to_string())?;
+        requested_count += 1;
+      }
+
+      match next_msg(socket).await? {
+        Message::Block {
+          piece_index,
+          offset,
+          data: block,
+        } => {
+          let block_index = (offset / BLOCK_LEN) as usize;
+          let info = blocks.get(block_index).filter(|info| {
+            piece_index == index
+              && info.offset == offset
+              && info.len as usize == block.len()
+          });
+          let Some(info) = info else {
+            return Err(format!(
+              "unrequested block in piece {} at {}",
+              piece_index, offset
+            ));
+          };
+          if received[block_index] {
+            return Err(format!("duplicate block {}", info));
+          }
+          received[block_index] = true;
+          received_count += 1;
+          let start = info.offset as usize;
+          data[start..start + block.len()].copy_from_slice(&block);
+        }
+        Message::Choke => return Err("peer choked us mid-transfer".to_owned()),
+        msg => log_msg(&msg),
+      }
+    }
+    Ok(())
+  })
+  .await?;
+
+  let expected_hash = &metainfo.pieces[index * 20..index * 20 + 20];
+  if Sha1::digest(&data).as_slice() != expected_hash {
+    return Err(format!("piece {} failed the hash check", index));
+  }
+  Ok(format!(
+    "piece {} ({} bytes in {} blocks) is valid",
+    index, piece_len, count
+  ))
+}
+
+async fn next_msg(
+  socket: &mut Framed<TcpStream, PeerCodec>,
+) -> Result<Message> {
+  socket
+    .next()
+    .await
+    .ok_or_else(|| "connection closed".to_owned())?
+    .map_err(|e| e.to_string())
+}
+
+fn log_msg(msg: &Message) {
+  if !matches!(msg, Message::Block { .. }) {
+    println!("  peer sent {:?}", msg);
+  }
+}
+
+/// Runs the step with a timeout, turning its error into the failure detail.
+async fn step<T, E: fmt::Display>(
+  fut: impl std::future::Future<Output = std::result::Result<T, E>>,
+) -> Result<T> {
+  match time::timeout(STEP_TIMEOUT, fut).await {
+    Ok(result) => result.map_err(|e| e.to_string()),
+    Err(_) => Err(format!("timed out after {:?}", STEP_TIMEOUT)),
+  }
+}
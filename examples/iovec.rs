@@ -14,7 +14,7 @@ impl IoVec {
 }
 
 fn main() {
-  let mut bytes = vec![
+  let mut bytes = [
     (0..16).collect::<Vec<u8>>(),
     (16..32).collect::<Vec<u8>>(),
     (32..48).collect::<Vec<u8>>(),
@@ -17,9 +17,18 @@
 //! - [latest downloaded pieces]
 //! - [peers]
 
+use std::{net::IpAddr, path::PathBuf, time::Duration};
+
+use reqwest::Url;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-use crate::{error::Error, torrent::stats::TorrentStats, TorrentId};
+use crate::{
+  engine::SessionStats,
+  error::{Error, TrackerError, WatchError},
+  torrent::stats::{SampleReport, TorrentStats},
+  watchdog::Component,
+  TorrentId,
+};
 
 pub type AlertSender = UnboundedSender<Alert>;
 /// The channel on which alerts from the engine can be received ([`Alert`])
@@ -30,14 +39,105 @@ pub type AlertReceiver = UnboundedReceiver<Alert>;
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum Alert {
-  /// Posted when the torrent has finished downloading.
+  /// Posted when the torrent has finished downloading, or only its wanted
+  /// files if some are skipped.
   TorrentComplete(TorrentId),
+  /// Posted when the torrent is paused because the maximum number of active
+  /// downloads or seeds is reached.
+  TorrentQueued(TorrentId),
+  /// Posted when a queued torrent is resumed as a slot was freed up.
+  TorrentDequeued(TorrentId),
   /// Each running torrent sends an update of its latest statistics
   /// every second via this alert.
   TorrentStats {
     id: TorrentId,
     stats: Box<TorrentStats>,
   },
+  /// The totals of all torrents in the engine, posted periodically as
+  /// configured in [`EngineConf::session_stats_interval`].
+  ///
+  /// [`EngineConf::session_stats_interval`]:
+  /// crate::conf::EngineConf::session_stats_interval
+  SessionStats(Box<SessionStats>),
+  /// Posted when the free space on the disk of a download directory drops
+  /// below the configured reserve. The writes of the torrents downloading to
+  /// the directory are paused, and resumed automatically once enough space
+  /// is freed up.
+  LowDiskSpace {
+    dir: PathBuf,
+    /// The free space left on the disk, in bytes.
+    available: u64,
+  },
+  /// Posted when the engine's watchdog detects that a task of the engine
+  /// hasn't processed any commands for longer than the configured stall
+  /// timeout.
+  TaskStalled { component: Component },
+  /// Posted for each of a new torrent's trackers that didn't respond to the
+  /// probe requested with [`TorrentParams::probe_trackers`].
+  ///
+  /// The torrent still tries to announce to the tracker, which may be
+  /// only temporarily down.
+  ///
+  /// [`TorrentParams::probe_trackers`]:
+  /// crate::engine::TorrentParams::probe_trackers
+  TrackerUnreachable {
+    id: TorrentId,
+    url: Url,
+    error: TrackerError,
+  },
+  /// Posted when an announce to one of the torrent's trackers failed, after
+  /// which the tracker isn't announced to until the delay is up, as
+  /// configured in [`TorrentConf::tracker_backoff`]. The announce error
+  /// itself is posted as an [`Alert::Error`].
+  ///
+  /// [`TorrentConf::tracker_backoff`]:
+  /// crate::conf::TorrentConf::tracker_backoff
+  TrackerDisabled {
+    id: TorrentId,
+    url: Url,
+    /// The number of announces to the tracker that failed in a row.
+    failure_count: u32,
+    /// How long until the tracker is announced to again.
+    retry_in: Duration,
+  },
+  /// Posted when a tracker responds again after an [`Alert::TrackerDisabled`].
+  TrackerRecovered { id: TorrentId, url: Url },
+  /// Posted when a tracker reports the IP address it sees us by, which is
+  /// the first time one does or when it differs from the one last reported.
+  ExternalIp {
+    id: TorrentId,
+    /// The tracker that reported the address.
+    url: Url,
+    ip: IpAddr,
+  },
+  /// Posted once for each of the torrent's files when its first bytes are
+  /// downloaded, as configured in [`TorrentAlertConf::playable_prefix`],
+  /// while the torrent is streamed. Files whose prefix is already on disk
+  /// are posted once the data is checked and streaming started.
+  ///
+  /// [`TorrentAlertConf::playable_prefix`]:
+  /// crate::conf::TorrentAlertConf::playable_prefix
+  FilePlayable {
+    id: TorrentId,
+    /// The index of the file in the torrent's metainfo.
+    file_index: usize,
+  },
+  /// Posted when the torrent's files were moved into a new directory. If they
+  /// could not be moved, an [`Alert::Error`] is posted instead and the files
+  /// are left in their old directory.
+  StorageMoved { id: TorrentId, dir: PathBuf },
+  /// Posted when the sample requested with [`TorrentHandle::sample`] is
+  /// downloaded, after which the torrent is paused.
+  ///
+  /// [`TorrentHandle::sample`]: crate::torrent::handle::TorrentHandle::sample
+  SampleComplete {
+    id: TorrentId,
+    report: Box<SampleReport>,
+  },
+  /// Posted when a file in the watch directory could not be added as a
+  /// torrent. The file is renamed with an `.invalid` suffix, unless the
+  /// error is that it could not be renamed.
+  WatchDirError { path: PathBuf, error: WatchError },
   /// An error from somewhere inside the engine.
   Error(Error),
 }
@@ -1,6 +1,10 @@
-use std::{fmt, ops::Deref, sync::Arc};
+use std::{
+  fmt,
+  ops::{Deref, Range},
+  sync::Arc,
+};
 
-use crate::{PieceIndex, BLOCK_LEN};
+use crate::{error::BlockInfoError, PieceIndex, BLOCK_LEN, MAX_REQUEST_LEN};
 
 /// A block is a fixed size chunk of a piece, which in turn is a fixed size
 /// chunk of a content. Downloading torrents happen at this block level
@@ -17,6 +21,83 @@ pub struct BlockInfo {
 }
 
 impl BlockInfo {
+  /// Creates a block info, checking that it describes one of the blocks of a
+  /// piece of the given length.
+  ///
+  /// The block must start at a multiple of the 16 KiB block length, and it
+  /// must be exactly 16 KiB long, unless it's the last block in the piece,
+  /// in which case it must end where the piece ends.
+  pub fn try_new(
+    piece_index: PieceIndex,
+    offset: u32,
+    len: u32,
+    piece_len: u32,
+  ) -> Result<Self, BlockInfoError> {
+    Self::validate_geometry(offset, len)?;
+    let end = offset.checked_add(len).ok_or(BlockInfoError::OutOfBounds)?;
+    if end > piece_len {
+      return Err(BlockInfoError::OutOfBounds);
+    }
+    if len < BLOCK_LEN && end != piece_len {
+      return Err(BlockInfoError::InvalidLength);
+    }
+    Ok(Self {
+      piece_index,
+      offset,
+      len,
+    })
+  }
+
+  /// Checks the parts of a block's geometry that don't depend on the length
+  /// of its piece, for when that is not known (e.g. when decoding messages).
+  pub fn validate_geometry(
+    offset: u32,
+    len: u32,
+  ) -> Result<(), BlockInfoError> {
+    if !offset.is_multiple_of(BLOCK_LEN) {
+      return Err(BlockInfoError::Misaligned);
+    }
+    if len == 0 || len > BLOCK_LEN {
+      return Err(BlockInfoError::InvalidLength);
+    }
+    Ok(())
+  }
+
+  /// Checks that a peer's request describes a range within a piece of the
+  /// given length. Unlike our own blocks, requests needn't follow the 16 KiB
+  /// block grid, as other clients may split pieces differently, and they may
+  /// be up to [`MAX_REQUEST_LEN`] long.
+  pub fn validate_request(&self, piece_len: u32) -> Result<(), BlockInfoError> {
+    Self::validate_request_len(self.len)?;
+    let end = self
+      .offset
+      .checked_add(self.len)
+      .ok_or(BlockInfoError::OutOfBounds)?;
+    if end > piece_len {
+      return Err(BlockInfoError::OutOfBounds);
+    }
+    Ok(())
+  }
+
+  /// Checks the part of a request's geometry that doesn't depend on the
+  /// length of its piece, its own length, for when that is not known (e.g.
+  /// when decoding messages).
+  pub fn validate_request_len(len: u32) -> Result<(), BlockInfoError> {
+    if len == 0 || len > MAX_REQUEST_LEN {
+      return Err(BlockInfoError::InvalidLength);
+    }
+    Ok(())
+  }
+
+  /// Returns the indices of the 16 KiB blocks within its piece that the
+  /// block overlaps, which are more than one for requests that don't follow
+  /// the block grid.
+  pub fn block_range(&self) -> Range<usize> {
+    let start = self.offset / BLOCK_LEN;
+    let end = (self.offset + self.len).div_ceil(BLOCK_LEN);
+    start as usize..end as usize
+  }
+
   /// Returns the index of the block within its pieces, assuming the default
   /// block length of 16 KiB.
   pub fn index_in_piece(&self) -> usize {
@@ -59,7 +140,7 @@ pub fn block_count(piece_len: u32) -> usize {
   // all but the last piece are a multiple of the block length,
   // but the last piece may be shorter so we need to account for this
   // by rounding up before dividing to get the number of blocks in piece.
-  (piece_len as usize + (BLOCK_LEN as usize - 1)) / BLOCK_LEN as usize
+  (piece_len as usize).div_ceil(BLOCK_LEN as usize)
 }
 
 pub struct Block {
@@ -150,6 +231,77 @@ mod tests {
     block_len(BLOCK_LEN_MULTIPLE_PIECE_LEN, 2);
   }
 
+  #[test]
+  fn test_block_info_try_new() {
+    assert!(BlockInfo::try_new(0, 0, BLOCK_LEN, UNEVEN_PIECE_LEN).is_ok());
+    assert!(
+      BlockInfo::try_new(0, 2 * BLOCK_LEN, OVERLAP, UNEVEN_PIECE_LEN).is_ok()
+    );
+
+    assert_eq!(
+      BlockInfo::try_new(0, 100, BLOCK_LEN, UNEVEN_PIECE_LEN),
+      Err(BlockInfoError::Misaligned)
+    );
+    assert_eq!(
+      BlockInfo::try_new(0, 0, 0, UNEVEN_PIECE_LEN),
+      Err(BlockInfoError::InvalidLength)
+    );
+    assert_eq!(
+      BlockInfo::try_new(0, 0, BLOCK_LEN + 1, UNEVEN_PIECE_LEN),
+      Err(BlockInfoError::InvalidLength)
+    );
+    // only the last block may be shorter
+    assert_eq!(
+      BlockInfo::try_new(0, BLOCK_LEN, OVERLAP, UNEVEN_PIECE_LEN),
+      Err(BlockInfoError::InvalidLength)
+    );
+    assert_eq!(
+      BlockInfo::try_new(0, 2 * BLOCK_LEN, BLOCK_LEN, UNEVEN_PIECE_LEN),
+      Err(BlockInfoError::OutOfBounds)
+    );
+    assert_eq!(
+      BlockInfo::try_new(0, 0x4000 * 0x3ffff, BLOCK_LEN, u32::MAX),
+      Err(BlockInfoError::OutOfBounds)
+    );
+  }
+
+  #[test]
+  fn test_block_info_validate_request() {
+    let request = |offset, len| BlockInfo {
+      piece_index: 0,
+      offset,
+      len,
+    };
+    // requests may span whole blocks, up to the end of the piece
+    let whole = request(0, UNEVEN_PIECE_LEN);
+    assert_eq!(whole.validate_request(UNEVEN_PIECE_LEN), Ok(()));
+    assert_eq!(whole.block_range(), 0..3);
+    let first = request(0, BLOCK_LEN);
+    assert_eq!(first.validate_request(UNEVEN_PIECE_LEN), Ok(()));
+    assert_eq!(first.block_range(), 0..1);
+
+    // nor do they have to follow the block grid
+    let unaligned = request(100, BLOCK_LEN + OVERLAP);
+    assert_eq!(unaligned.validate_request(UNEVEN_PIECE_LEN), Ok(()));
+    assert_eq!(unaligned.block_range(), 0..2);
+    let short = request(BLOCK_LEN, 1);
+    assert_eq!(short.validate_request(UNEVEN_PIECE_LEN), Ok(()));
+    assert_eq!(short.block_range(), 1..2);
+
+    assert_eq!(
+      request(0, 0).validate_request(UNEVEN_PIECE_LEN),
+      Err(BlockInfoError::InvalidLength)
+    );
+    assert_eq!(
+      request(0, MAX_REQUEST_LEN + BLOCK_LEN).validate_request(u32::MAX),
+      Err(BlockInfoError::InvalidLength)
+    );
+    assert_eq!(
+      request(BLOCK_LEN + 1, 2 * BLOCK_LEN).validate_request(UNEVEN_PIECE_LEN),
+      Err(BlockInfoError::OutOfBounds)
+    );
+  }
+
   #[test]
   fn test_block_count() {
     assert_eq!(block_count(BLOCK_LEN_MULTIPLE_PIECE_LEN), 2);
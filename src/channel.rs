@@ -0,0 +1,207 @@
+//! The command channels of the engine, torrent and disk tasks.
+//!
+//! The channels are bounded, but only for bulk messages, e.g. the blocks
+//! peer sessions write to disk, as those are what may pile up if the
+//! receiving task can't keep up. What happens to a bulk message sent on a
+//! full channel depends on the channel's [`Overflow`] policy.
+//!
+//! Control messages (shutting down, pausing, etc) are never held back: the
+//! tasks send each other commands in both directions, so waiting on a full
+//! channel to send one could deadlock them.
+
+use std::{fmt, sync::Arc};
+
+use tokio::sync::{
+  mpsc::{
+    self,
+    error::{SendError, TryRecvError, TrySendError},
+  },
+  OwnedSemaphorePermit, Semaphore,
+};
+
+/// What a sender does with a bulk message if the channel is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+  /// The sender waits for room in the channel, which slows down the
+  /// producer, e.g. a peer session stops reading from its peer.
+  Wait,
+  /// The message is dropped, for messages that are sent periodically and
+  /// are superseded by the next one anyway.
+  Drop,
+}
+
+/// A message in the channel, with the room it takes up if it's a bulk
+/// message, which is freed when it's received.
+type Slot<T> = (T, Option<OwnedSemaphorePermit>);
+
+/// Creates a channel with room for `capacity` bulk messages.
+pub fn channel<T>(
+  capacity: usize,
+  overflow: Overflow,
+) -> (Sender<T>, Receiver<T>) {
+  let (tx, rx) = mpsc::unbounded_channel();
+  let sender = Sender {
+    tx,
+    room: Arc::new(Semaphore::new(capacity)),
+    overflow,
+  };
+  (sender, Receiver { rx })
+}
+
+/// The sending half of a channel, which may be cloned.
+pub struct Sender<T> {
+  tx: mpsc::UnboundedSender<Slot<T>>,
+  /// The room left for bulk messages.
+  room: Arc<Semaphore>,
+  overflow: Overflow,
+}
+
+impl<T> Sender<T> {
+  /// Sends a control message, which is sent right away even if the channel
+  /// is full.
+  ///
+  /// Fails only if the receiver was dropped.
+  pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+    self
+      .tx
+      .send((msg, None))
+      .map_err(|SendError((msg, _))| SendError(msg))
+  }
+
+  /// Sends a bulk message, which takes up room in the channel until it's
+  /// received.
+  ///
+  /// If the channel is full, the sender either waits for room or the message
+  /// is dropped with [`TrySendError::Full`], depending on the channel's
+  /// [`Overflow`] policy.
+  pub async fn send_bulk(&self, msg: T) -> Result<(), TrySendError<T>> {
+    let permit = match self.overflow {
+      Overflow::Wait => Arc::clone(&self.room)
+        .acquire_owned()
+        .await
+        .expect("channel room is never closed"),
+      Overflow::Drop => match Arc::clone(&self.room).try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => return Err(TrySendError::Full(msg)),
+      },
+    };
+    self
+      .tx
+      .send((msg, Some(permit)))
+      .map_err(|SendError((msg, _))| TrySendError::Closed(msg))
+  }
+
+  /// Sends a bulk message that must not be lost, e.g. a peer session's last
+  /// state, which takes up room in the channel if there's any left, but is
+  /// sent right away even if there isn't.
+  ///
+  /// Only a bounded number of these may be in flight at once, e.g. one per
+  /// peer session, so they don't make the channel grow without bound, but
+  /// they still make the other bulk messages wait or be dropped sooner.
+  pub fn send_bulk_forced(&self, msg: T) -> Result<(), SendError<T>> {
+    let permit = Arc::clone(&self.room).try_acquire_owned().ok();
+    self
+      .tx
+      .send((msg, permit))
+      .map_err(|SendError((msg, _))| SendError(msg))
+  }
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Self {
+    Self {
+      tx: self.tx.clone(),
+      room: Arc::clone(&self.room),
+      overflow: self.overflow,
+    }
+  }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Sender")
+      .field("room", &self.room.available_permits())
+      .field("overflow", &self.overflow)
+      .finish()
+  }
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T> {
+  rx: mpsc::UnboundedReceiver<Slot<T>>,
+}
+
+impl<T> Receiver<T> {
+  /// Receives the next message, or `None` if all senders were dropped.
+  pub async fn recv(&mut self) -> Option<T> {
+    self.rx.recv().await.map(|(msg, _)| msg)
+  }
+
+  /// Receives the next message if there is one, without waiting.
+  pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+    self.rx.try_recv().map(|(msg, _)| msg)
+  }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Receiver").finish_non_exhaustive()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use tokio::time::timeout;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn should_wait_for_room_on_full_channel() {
+    let (tx, mut rx) = channel(1, Overflow::Wait);
+    tx.send_bulk(1).await.unwrap();
+    // control messages are not held back
+    tx.send(2).unwrap();
+    assert!(timeout(Duration::from_millis(50), tx.send_bulk(3))
+      .await
+      .is_err());
+
+    // receiving the bulk message frees its room
+    assert_eq!(rx.recv().await, Some(1));
+    tx.send_bulk(3).await.unwrap();
+    assert_eq!(rx.recv().await, Some(2));
+    assert_eq!(rx.recv().await, Some(3));
+  }
+
+  #[tokio::test]
+  async fn should_drop_on_full_channel() {
+    let (tx, mut rx) = channel(1, Overflow::Drop);
+    tx.send_bulk(1).await.unwrap();
+    assert!(matches!(tx.send_bulk(2).await, Err(TrySendError::Full(2))));
+    assert_eq!(rx.recv().await, Some(1));
+    tx.send_bulk(3).await.unwrap();
+    assert_eq!(rx.try_recv(), Ok(3));
+
+    drop(rx);
+    assert!(matches!(
+      tx.send_bulk(4).await,
+      Err(TrySendError::Closed(4))
+    ));
+    assert!(tx.send(5).is_err());
+  }
+
+  #[tokio::test]
+  async fn should_force_bulk_msgs_into_full_channel() {
+    let (tx, mut rx) = channel(1, Overflow::Drop);
+    // the forced message takes up the room of other bulk messages
+    tx.send_bulk_forced(1).unwrap();
+    assert!(matches!(tx.send_bulk(2).await, Err(TrySendError::Full(2))));
+    // but isn't dropped on a full channel
+    tx.send_bulk_forced(3).unwrap();
+    assert_eq!(rx.recv().await, Some(1));
+    assert_eq!(rx.recv().await, Some(3));
+    tx.send_bulk(4).await.unwrap();
+    assert_eq!(rx.try_recv(), Ok(4));
+  }
+}
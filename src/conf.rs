@@ -1,10 +1,28 @@
 //! This module defines types used to configure the engine and its parts.
 
-use std::{path::PathBuf, time::Duration};
+use std::{
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  path::PathBuf,
+  time::Duration,
+};
 
-use crate::PeerId;
+use reqwest::{
+  header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT},
+  Certificate,
+};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::{
+  error::{EngineResult, Error},
+  hook::CompletionHook,
+  PeerId, BLOCK_LEN, MAX_REQUEST_LEN,
+};
 
 pub const CLIENT_ID: &PeerId = b"cbt-0000000000000000";
+/// The default `User-Agent` sent to trackers, naming the same client as
+/// [`CLIENT_ID`].
+pub const CLIENT_USER_AGENT: &str = concat!("cbt/", env!("CARGO_PKG_VERSION"));
 // pub const CLIENT_ID: &PeerId = b"-qB1450-352885928458";
 // pub static CLIENT_ID: Lazy<PeerId> = Lazy::new(|| {
 //     let mut id = [0u8; 20];
@@ -29,6 +47,12 @@ pub struct Conf {
 // }
 
 impl Conf {
+  /// Checks that the engine and the default torrent configurations are valid.
+  pub fn validate(&self) -> EngineResult<()> {
+    self.engine.validate()?;
+    self.torrent.validate()
+  }
+
   /// Returns the torrent configuration with reasonable defaults,
   /// expected for the download directory, as it is not sensible
   /// to guess that for the user. It uses the default client id
@@ -38,6 +62,31 @@ impl Conf {
       engine: EngineConf {
         client_id: *CLIENT_ID,
         download_dir: download_dir.into(),
+        // any interface of either family, on a port picked by the OS for
+        // each torrent
+        listen_addr: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        disk: DiskConf::default(),
+        max_active_downloads: None,
+        max_active_seeds: None,
+        // Well below the common default file descriptor limit of 1024, which
+        // also has to cover the torrents' files.
+        max_connected_peer_count: 500,
+        download_rate_limit: None,
+        upload_rate_limit: None,
+        max_memory: None,
+        // Enough to flush pending writes and tell trackers we're leaving,
+        // unless something is stuck.
+        shutdown_grace_period: Duration::from_secs(10),
+        watchdog: WatchdogConf::default(),
+        watch_dir: None,
+        completion_hook: None,
+        // as often as the torrents' own stats
+        session_stats_interval: Some(Duration::from_secs(1)),
+        tracker_http: TrackerHttpConf::default(),
+        // whichever address the OS routes through
+        source_addr: None,
+        // joining the DHT makes us known to many hosts, so it's opted into
+        dht: None,
       },
       torrent: TorrentConf::default(),
     }
@@ -52,31 +101,831 @@ pub struct EngineConf {
   /// The directory in which a torrent's files are placed upon download and
   /// from which they are seeded.
   pub download_dir: PathBuf,
+  /// The address on which torrents listen for peers, unless they are given
+  /// their own address when created.
+  ///
+  /// Each torrent listens on its own socket, so with a non-zero port only
+  /// one torrent may use this address. The IPv6 unspecified address accepts
+  /// peers of both families, falling back to IPv4 only on hosts without
+  /// IPv6.
+  pub listen_addr: SocketAddr,
+  /// Configuration of the disk task.
+  pub disk: DiskConf,
+  /// The maximum number of torrents that may download at the same time, or
+  /// no limit if not set.
+  ///
+  /// Torrents over the limit are stopped and queued, and they are started in
+  /// the order they were added as others complete or are removed. Whether a
+  /// torrent is paused by the user is independent of this.
+  pub max_active_downloads: Option<usize>,
+  /// The maximum number of torrents that may seed at the same time, or no
+  /// limit if not set. Queued the same way as downloads.
+  pub max_active_seeds: Option<usize>,
+  /// The maximum number of peer connections across all torrents.
+  ///
+  /// This is on top of each torrent's own limit, so that many torrents can't
+  /// collectively exhaust the host's file descriptors or bandwidth.
+  pub max_connected_peer_count: usize,
+  /// The maximum download rate of all torrents combined, in bytes per
+  /// second, on top of each torrent's own limit. `None` means unlimited.
+  pub download_rate_limit: Option<u64>,
+  /// The maximum upload rate of all torrents combined, in bytes per second,
+  /// on top of each torrent's own limit. `None` means unlimited.
+  ///
+  /// Both limits count the headers of protocol messages as well as the
+  /// payload, but only the payload is ever held back by them.
+  pub upload_rate_limit: Option<u64>,
+  /// The bytes the engine's buffers may hold in total, see
+  /// [`MemoryStats`], past which peer sessions stop requesting blocks until
+  /// the buffers drain. `None` means no limit.
+  ///
+  /// [`MemoryStats`]: crate::memory::MemoryStats
+  pub max_memory: Option<u64>,
+  /// On shutdown, how long to wait for the torrents to stop, and then for the
+  /// disk task to flush its writes, before aborting the tasks that remain.
+  pub shutdown_grace_period: Duration,
+  /// Configuration of the watchdog that detects stuck tasks.
+  pub watchdog: WatchdogConf,
+  /// The directory from which new torrents are added automatically, if any.
+  pub watch_dir: Option<WatchDirConf>,
+  /// What to run when a torrent finishes downloading, unless the torrent has
+  /// its own [`TorrentConf::completion_command`].
+  pub completion_hook: Option<CompletionHook>,
+  /// How often the engine posts an [`Alert::SessionStats`] with the totals
+  /// of all of its torrents, or never if not set.
+  ///
+  /// [`Alert::SessionStats`]: crate::alert::Alert::SessionStats
+  pub session_stats_interval: Option<Duration>,
+  /// Configuration of the HTTP requests made to trackers.
+  pub tracker_http: TrackerHttpConf,
+  /// The local address from which peers and trackers are connected to, so
+  /// that on a multi-homed host, e.g. one with a VPN, torrent traffic goes
+  /// out through the interface with this address. If not set the OS picks
+  /// the address by its routes.
+  ///
+  /// Peers and trackers of the other IP family can't be reached from it.
+  pub source_addr: Option<IpAddr>,
+  /// Configuration of the DHT node, through which the peers of torrents
+  /// that aren't private are found without trackers, or none if the DHT is
+  /// not used.
+  pub dht: Option<DhtConf>,
+}
+
+impl EngineConf {
+  /// Checks that the configuration values are valid.
+  pub fn validate(&self) -> EngineResult<()> {
+    if self.max_connected_peer_count == 0
+      || self.max_connected_peer_count > Semaphore::MAX_PERMITS
+    {
+      return Err(Error::InvalidConf(
+        "engine max connected peer count must be positive and not huge",
+      ));
+    }
+    if self.download_rate_limit == Some(0) || self.upload_rate_limit == Some(0)
+    {
+      return Err(Error::InvalidConf("engine rate limits must not be zero"));
+    }
+    self.watchdog.validate()?;
+    if self.session_stats_interval.is_some_and(|i| i.is_zero()) {
+      return Err(Error::InvalidConf(
+        "session stats interval must not be zero",
+      ));
+    }
+    if let Some(watch_dir) = &self.watch_dir {
+      watch_dir.validate()?;
+    }
+    self.tracker_http.validate()?;
+    if self
+      .source_addr
+      .is_some_and(|ip| ip.is_unspecified() || ip.is_multicast())
+    {
+      return Err(Error::InvalidConf(
+        "source address must be a unicast address",
+      ));
+    }
+    if let Some(dht) = &self.dht {
+      dht.validate()?;
+      // the node is bound to the source address, and it's IPv4 only
+      if self.source_addr.is_some_and(|ip| ip.is_ipv6()) {
+        return Err(Error::InvalidConf("DHT requires an IPv4 source address"));
+      }
+    }
+    if let Some(CompletionHook::Command(args)) = &self.completion_hook {
+      if args.is_empty() {
+        return Err(Error::InvalidConf("completion command must not be empty"));
+      }
+    }
+    self.disk.validate()
+  }
+}
+
+/// Configuration of the DHT node (BEP 5).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtConf {
+  /// The address on which the node listens for queries, whose port is told
+  /// to the peers that support the DHT. Only IPv4 is supported.
+  pub listen_addr: SocketAddr,
+  /// The nodes through which the DHT is joined, as `host:port`, which are
+  /// queried whenever too few other nodes are known.
+  pub bootstrap_nodes: Vec<String>,
+}
+
+impl DhtConf {
+  /// Checks that the configuration values are valid.
+  pub fn validate(&self) -> EngineResult<()> {
+    if !self.listen_addr.is_ipv4() {
+      return Err(Error::InvalidConf("DHT listen address must be IPv4"));
+    }
+    Ok(())
+  }
+}
+
+impl Default for DhtConf {
+  fn default() -> Self {
+    Self {
+      // the port commonly used for the DHT
+      listen_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 6881),
+      bootstrap_nodes: [
+        "router.bittorrent.com:6881",
+        "router.utorrent.com:6881",
+        "dht.transmissionbt.com:6881",
+        "dht.libtorrent.org:25401",
+      ]
+      .map(str::to_owned)
+      .to_vec(),
+    }
+  }
+}
+
+/// Configuration of the engine's watchdog, which periodically pings the
+/// torrent and disk tasks to detect the ones that got stuck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogConf {
+  /// How often the tasks are pinged.
+  pub check_interval: Duration,
+
+  /// A task that doesn't acknowledge its ping within this time is considered
+  /// stalled, which is logged and posted as an alert.
+  pub stall_timeout: Duration,
+
+  /// Whether to restart stalled torrents, by shutting down their task and
+  /// starting them again, which checks their data again.
+  ///
+  /// The restarted torrent keeps its id, and handles to it obtained before
+  /// the restart keep working. Torrents added from magnet links and the disk
+  /// task are never restarted.
+  pub restart_stalled_torrents: bool,
+}
+
+impl WatchdogConf {
+  /// Checks that the configuration values are valid.
+  pub fn validate(&self) -> EngineResult<()> {
+    if self.check_interval.is_zero() {
+      return Err(Error::InvalidConf(
+        "watchdog check interval must not be zero",
+      ));
+    }
+    if self.stall_timeout < self.check_interval {
+      return Err(Error::InvalidConf(
+        "watchdog stall timeout must not be shorter than its check interval",
+      ));
+    }
+    Ok(())
+  }
+}
+
+impl Default for WatchdogConf {
+  fn default() -> Self {
+    WatchdogConf {
+      // Pinging is cheap, but there is no need to detect stalls right away.
+      check_interval: Duration::from_secs(5),
+      // Long enough to not mistake a slow tracker announce or a large disk
+      // write for a stall.
+      stall_timeout: Duration::from_secs(60),
+      // Restarting is disruptive, so it must be opted into.
+      restart_stalled_torrents: false,
+    }
+  }
+}
+
+/// Configuration of the HTTP requests made to trackers, shared by all
+/// torrents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerHttpConf {
+  /// The `User-Agent` header sent to trackers, or none if not set.
+  ///
+  /// Some trackers only admit known clients, and expect this to name the
+  /// same client as the peer id does.
+  pub user_agent: Option<String>,
+  /// Additional headers sent to trackers as name and value pairs, e.g. the
+  /// cookie or passkey that a private tracker requires.
+  pub headers: Vec<(String, String)>,
+  /// PEM encoded certificates trusted as roots in addition to the system's,
+  /// e.g. the self-signed certificate of a private tracker.
+  pub root_certificates: Vec<Vec<u8>>,
+  /// Whether the system's proxy configuration, i.e. the `HTTP_PROXY` and
+  /// `HTTPS_PROXY` environment variables, applies to trackers.
+  pub use_system_proxy: bool,
+  /// Whether trackers' TLS certificates are accepted without being verified.
+  ///
+  /// This lets anyone in the path impersonate the trackers, so it's only
+  /// meant as a last resort, with [`Self::root_certificates`] preferred.
+  pub danger_accept_invalid_certs: bool,
+}
+
+impl TrackerHttpConf {
+  /// Checks that the configuration values are valid.
+  pub fn validate(&self) -> EngineResult<()> {
+    self.header_map()?;
+    self.root_certificates()?;
+    Ok(())
+  }
+
+  /// Returns the headers to send with each request to trackers, or an error
+  /// if any of them isn't a valid HTTP header.
+  pub fn header_map(&self) -> EngineResult<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    if let Some(user_agent) = &self.user_agent {
+      let value = HeaderValue::from_str(user_agent)
+        .map_err(|_| Error::InvalidConf("invalid tracker user agent"))?;
+      headers.insert(USER_AGENT, value);
+    }
+    for (name, value) in &self.headers {
+      let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| Error::InvalidConf("invalid tracker header name"))?;
+      let value = HeaderValue::from_str(value)
+        .map_err(|_| Error::InvalidConf("invalid tracker header value"))?;
+      headers.append(name, value);
+    }
+    Ok(headers)
+  }
+
+  /// Returns the root certificates to trust, or an error if any of them
+  /// isn't a valid PEM encoded certificate.
+  pub fn root_certificates(&self) -> EngineResult<Vec<Certificate>> {
+    self
+      .root_certificates
+      .iter()
+      .map(|pem| Certificate::from_pem(pem))
+      .collect::<Result<_, _>>()
+      .map_err(|_| Error::InvalidConf("invalid tracker root certificate"))
+  }
+}
+
+impl Default for TrackerHttpConf {
+  fn default() -> Self {
+    TrackerHttpConf {
+      user_agent: Some(CLIENT_USER_AGENT.to_string()),
+      headers: Vec::new(),
+      root_certificates: Vec::new(),
+      use_system_proxy: true,
+      // Verifying certificates must not be turned off by accident.
+      danger_accept_invalid_certs: false,
+    }
+  }
+}
+
+/// Configuration of the directory watched for new torrents.
+///
+/// Each `.torrent` metainfo file and `.magnet` file holding a magnet link
+/// that appears in the directory is added as a torrent with the default
+/// configuration. Afterwards the file is renamed with an `.added` suffix, or
+/// with an `.invalid` suffix if it could not be added, so that it's not
+/// picked up again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchDirConf {
+  /// The directory to watch. Its subdirectories are not watched.
+  pub dir: PathBuf,
+  /// How often the directory is scanned for new files.
+  pub poll_interval: Duration,
+}
+
+impl WatchDirConf {
+  /// Returns the configuration for watching the directory, with the default
+  /// poll interval.
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Self {
+      dir: dir.into(),
+      // New torrents are usually added by hand, for which a few seconds of
+      // delay is not noticeable.
+      poll_interval: Duration::from_secs(5),
+    }
+  }
+
+  /// Checks that the configuration values are valid.
+  pub fn validate(&self) -> EngineResult<()> {
+    if self.poll_interval.is_zero() {
+      return Err(Error::InvalidConf(
+        "watch directory poll interval must not be zero",
+      ));
+    }
+    Ok(())
+  }
+}
+
+/// Configuration of the disk task, shared by all torrents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskConf {
+  /// The amount of free space, in bytes, to leave on the disks of the
+  /// torrents' download directories.
+  ///
+  /// When the free space drops below this, the writes of the torrents
+  /// downloading to that disk are paused until space is freed up.
+  pub min_free_space: u64,
+
+  /// The bytes of completed pieces each torrent may hold in memory while its
+  /// writes are paused, past which it stops downloading until they're
+  /// written.
+  pub max_paused_write_len: u64,
+
+  /// How often the free space of the download directories is checked.
+  pub free_space_check_interval: Duration,
+}
+
+impl DiskConf {
+  /// Checks that the configuration values are valid.
+  pub fn validate(&self) -> EngineResult<()> {
+    if self.free_space_check_interval.is_zero() {
+      return Err(Error::InvalidConf(
+        "free space check interval must not be zero",
+      ));
+    }
+    Ok(())
+  }
+}
+
+impl Default for DiskConf {
+  fn default() -> Self {
+    DiskConf {
+      // Running the disk completely full makes the whole system misbehave,
+      // so leave some room for other programs.
+      min_free_space: 100 * 1024 * 1024,
+      // Enough to keep downloading through a short dip in free space.
+      max_paused_write_len: 64 * 1024 * 1024,
+      // Free space doesn't usually change quickly, and the check is cheap.
+      free_space_check_interval: Duration::from_secs(10),
+    }
+  }
 }
 
 /// Configuration for a torrent
 ///
 /// The engine will have a default instance of this applied to all torrents
 /// by default, but individual torrents may override this configuration.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TorrentConf {
   /// The minimum number of peers we want to keep in torrent at all times.
   /// This will be configurable later.
   pub min_requested_peer_count: usize,
 
   /// The max number of connected peers the torrent should have.
+  ///
+  /// Once reached, a peer connecting to us only replaces the connected peer
+  /// with the lowest canonical priority ([BEP 40]), if its own is higher.
+  ///
+  /// [BEP 40]: http://bittorrent.org/beps/bep_0040.html
   pub max_connected_peer_count: usize,
 
-  /// If the tracer doesn't provide a minimum announce interval, we default
-  /// to announcing every 30 seconds.
+  /// The max number of connections to peers sharing an IP, which are usually
+  /// the same client connecting more than once rather than distinct peers.
+  #[serde(default = "default_max_connections_per_ip")]
+  pub max_connections_per_ip: usize,
+
+  /// The number of pieces failing their hash a peer may be blamed for before
+  /// its IP is banned from the torrent.
+  ///
+  /// A piece is only blamed on a peer if the peer sent all of its blocks. A
+  /// failed piece with blocks from several peers is downloaded again from a
+  /// single peer, so that the peer sending corrupt data is found out.
+  #[serde(default = "default_max_hash_failures")]
+  pub max_hash_failures: u32,
+
+  /// The interval at which to announce to trackers that don't specify their
+  /// own.
+  ///
+  /// Trackers usually do, in which case theirs is used, clamped between
+  /// [`Self::min_announce_interval`] and [`Self::max_announce_interval`].
   pub announce_interval: Duration,
 
-  /// After this many attempts, the torrent stops announcing to a tracker.
-  pub tracker_error_threshold: usize,
+  /// The longest a tracker may have us wait between announces, so that the
+  /// swarm doesn't lose track of us.
+  ///
+  /// A tracker's min interval is still honored past this, unless
+  /// [`Self::ignore_tracker_min_interval`] is set.
+  pub max_announce_interval: Duration,
+
+  /// When the torrent is short of peers, it may announce before the regular
+  /// announce interval is up, but no sooner than this after the last
+  /// announce, unless the tracker specifies its own minimum interval.
+  ///
+  /// Each consecutive early announce doubles this wait, up to the regular
+  /// interval, so that a torrent with no peers to be had doesn't keep
+  /// hammering its trackers.
+  pub min_announce_interval: Duration,
+
+  /// Whether the minimum interval a tracker asks for is ignored in favor of
+  /// [`Self::min_announce_interval`] when announcing early, e.g. for a
+  /// private tracker known to be lenient.
+  #[serde(default)]
+  pub ignore_tracker_min_interval: bool,
+
+  /// The number of peers to ask trackers for when the torrent is short of
+  /// peers. By default it's as many as the torrent has room for, but at
+  /// least [`Self::min_requested_peer_count`].
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub numwant: Option<usize>,
+
+  /// How long a tracker is left alone after its announces fail.
+  #[serde(default)]
+  pub tracker_backoff: TrackerBackoffConf,
+
+  /// How long to wait for a tracker to respond to an announce before giving
+  /// up on it, which then counts as a failed announce.
+  ///
+  /// This also bounds how long the torrent waits for its trackers to be told
+  /// that it's stopping when it's shut down.
+  #[serde(default = "default_tracker_timeout")]
+  pub tracker_timeout: Duration,
+
+  /// The number of interested peers that are unchoked for being the ones
+  /// we download from the fastest, or when seeding upload to the fastest,
+  /// not counting the one peer that is unchoked optimistically.
+  ///
+  /// `None` means the count is derived from the upload bandwidth on each
+  /// choke round: the upload rate limit if there is one, or else the
+  /// highest upload rate the torrent reached, so that each slot gets a
+  /// useful share of it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub unchoke_slot_count: Option<usize>,
+
+  /// The timeouts and intervals used by the torrent's peer sessions.
+  pub session: SessionConf,
 
   /// Specifies which optional alerts to send, besides the default periodic
   /// stats update.
   pub alerts: TorrentAlertConf,
+
+  /// How important the torrent is compared to the engine's other torrents
+  /// when competing for the engine's connection slots.
+  pub priority: Priority,
+
+  /// The maximum download rate of the torrent, in bytes per second, shared
+  /// by all of its peers. `None` means unlimited.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub download_rate_limit: Option<u64>,
+
+  /// The maximum upload rate of the torrent, in bytes per second, shared by
+  /// all of its peers. `None` means unlimited.
+  ///
+  /// The headers of protocol messages count against both limits, but are
+  /// never held back by them.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub upload_rate_limit: Option<u64>,
+
+  /// The command to run when the torrent finishes downloading, instead of
+  /// the engine's [`EngineConf::completion_hook`]. See
+  /// [`CompletionHook::Command`] for the format.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub completion_command: Option<Vec<String>>,
+}
+
+/// The priority of a torrent.
+///
+/// Lower priority torrents may not take the last of the engine's connection
+/// slots, leaving them for more important torrents, so that e.g. an urgent
+/// download can connect to peers even when background seeds are using up
+/// most of the slots. Likewise they must leave part of the engine's rate
+/// limits, so that the more important torrents get most of the bandwidth
+/// when it's contended.
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  Default,
+  PartialEq,
+  Eq,
+  PartialOrd,
+  Ord,
+  Hash,
+  Serialize,
+  Deserialize,
+)]
+pub enum Priority {
+  Low,
+  #[default]
+  Normal,
+  High,
+}
+
+impl Priority {
+  /// Returns how many of the engine's connection slots, out of the given
+  /// total, torrents of this priority must leave free.
+  pub fn reserved_connections(self, total: usize) -> usize {
+    match self {
+      Priority::Low => total / 4,
+      Priority::Normal => total / 10,
+      Priority::High => 0,
+    }
+  }
+
+  /// Returns how many bytes of the engine's rate limit, out of the given
+  /// rate, torrents of this priority must leave in the limiter's bucket.
+  pub fn reserved_bandwidth(self, rate: u64) -> u64 {
+    match self {
+      Priority::Low => rate / 2,
+      Priority::Normal => rate / 4,
+      Priority::High => 0,
+    }
+  }
+}
+
+impl TorrentConf {
+  /// Checks that the configuration values are valid.
+  pub fn validate(&self) -> EngineResult<()> {
+    if self.max_connected_peer_count == 0 {
+      return Err(Error::InvalidConf(
+        "max connected peer count must not be zero",
+      ));
+    }
+    if self.max_connections_per_ip == 0 {
+      return Err(Error::InvalidConf(
+        "max connections per IP must not be zero",
+      ));
+    }
+    if self.max_hash_failures == 0 {
+      return Err(Error::InvalidConf("max hash failures must not be zero"));
+    }
+    if self.min_announce_interval.is_zero() {
+      return Err(Error::InvalidConf("min announce interval must not be zero"));
+    }
+    if self.min_announce_interval > self.announce_interval
+      || self.announce_interval > self.max_announce_interval
+    {
+      return Err(Error::InvalidConf(
+        "announce interval must be between the min and max intervals",
+      ));
+    }
+    if self.numwant == Some(0) {
+      return Err(Error::InvalidConf("numwant must not be zero"));
+    }
+    if self.tracker_timeout.is_zero() {
+      return Err(Error::InvalidConf("tracker timeout must not be zero"));
+    }
+    if self.tracker_backoff.initial_delay.is_zero()
+      || self.tracker_backoff.initial_delay > self.tracker_backoff.max_delay
+    {
+      return Err(Error::InvalidConf(
+        "tracker retry delay must be non-zero and at most the max delay",
+      ));
+    }
+    if self.download_rate_limit == Some(0) || self.upload_rate_limit == Some(0)
+    {
+      return Err(Error::InvalidConf("rate limits must not be zero"));
+    }
+    if self
+      .completion_command
+      .as_ref()
+      .is_some_and(|args| args.is_empty())
+    {
+      return Err(Error::InvalidConf("completion command must not be empty"));
+    }
+    self.session.validate()
+  }
+}
+
+/// Returns the default [`TorrentConf::tracker_timeout`], also used for
+/// configurations saved before it existed.
+fn default_tracker_timeout() -> Duration {
+  // Trackers respond within a few seconds when they're up, but a busy one
+  // may take longer.
+  Duration::from_secs(30)
+}
+
+/// Returns the default [`TorrentConf::max_connections_per_ip`], also used
+/// for configurations saved before it existed.
+fn default_max_connections_per_ip() -> usize {
+  1
+}
+
+/// Returns the default [`TorrentConf::max_hash_failures`], also used for
+/// configurations saved before it existed.
+fn default_max_hash_failures() -> u32 {
+  // a single failure may be an honest peer's disk or memory error
+  3
+}
+
+/// The delays before a failing tracker is announced to again.
+///
+/// After an announce to a tracker fails, the tracker is disabled for the
+/// initial delay, which doubles with each consecutive failure up to the max
+/// delay. Each delay is shortened by a random part of up to a quarter, so
+/// that the torrents of a tracker that went down don't all retry at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackerBackoffConf {
+  /// The delay after the first failed announce.
+  pub initial_delay: Duration,
+  /// The longest delay, which a tracker that keeps failing is retried at.
+  pub max_delay: Duration,
+}
+
+impl Default for TrackerBackoffConf {
+  fn default() -> Self {
+    Self {
+      // A tracker that is only briefly down is retried soon.
+      initial_delay: Duration::from_secs(30),
+      // A tracker that is down for good is retried about as often as it's
+      // announced to when up.
+      max_delay: Duration::from_secs(60 * 60),
+    }
+  }
+}
+
+/// The timing knobs of a peer session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionConf {
+  /// If the peer doesn't send us any message, not even a keep-alive, for
+  /// this long, the connection is severed.
+  pub inactivity_timeout: Duration,
+
+  /// If neither side of the connection becomes interested in the other
+  /// within this time after connecting, the connection is severed.
+  pub interest_grace_period: Duration,
+
+  /// The time within which the connection must be established and the
+  /// handshakes exchanged, counted from when we start connecting to the peer
+  /// or the peer connected to us, or the connection is dropped.
+  pub handshake_timeout: Duration,
+
+  /// If we haven't sent the peer any message for this long, a keep-alive
+  /// message is sent so that the peer doesn't disconnect us.
+  ///
+  /// This must be shorter than the inactivity timeout, as the peer likely
+  /// uses a similar timeout.
+  pub keep_alive_interval: Duration,
+
+  /// How often the session does its periodic work, such as updating its
+  /// transfer rates and timing out requests, and reports to the torrent,
+  /// which it only does if something changed since its last report.
+  ///
+  /// A longer interval cuts the traffic between the sessions and the
+  /// torrent, at the cost of coarser timeouts and staler rates. It must be
+  /// shorter than the keep-alive interval, which is checked on each tick.
+  #[serde(default = "default_tick_interval")]
+  pub tick_interval: Duration,
+
+  /// If the peer doesn't send us any of the blocks we have requested for
+  /// this long, it's considered to be snubbing us: its requests are freed
+  /// for other peers to download and it's sent a single request at a time
+  /// until it delivers a block again.
+  #[serde(default = "default_snub_timeout")]
+  pub snub_timeout: Duration,
+
+  /// The most requests the peer may have outstanding with us, each of which
+  /// takes up a disk read and the memory of its block until the block is
+  /// sent. Requests beyond this are rejected, and the limit is told to
+  /// peers that support the extension protocol.
+  #[serde(default = "default_max_incoming_request_count")]
+  pub max_incoming_request_count: usize,
+
+  /// The longest block the peer may request. Longer requests are rejected,
+  /// and peers that keep making requests beyond this or the request count
+  /// are disconnected. It may be raised for clients that request more than
+  /// a 16 KiB block at a time, up to [`MAX_REQUEST_LEN`].
+  #[serde(default = "default_max_request_len")]
+  pub max_request_len: u32,
+
+  /// Whether some of the pieces we have are left out of the bitfield sent
+  /// to peers and announced with have messages right after, so that ISPs
+  /// inspecting connections can't tell that we're seeding.
+  #[serde(default)]
+  pub lazy_bitfield: bool,
+
+  /// How eagerly blocks are requested from more than one peer once the
+  /// torrent is in endgame.
+  #[serde(default)]
+  pub endgame: EndgameConf,
+}
+
+/// Bounds on requesting the same block from several peers in endgame, when
+/// all missing blocks are already requested and the last of them are
+/// requested again from other peers so that a slow peer doesn't hold up
+/// the download.
+///
+/// Each duplicate request may end up as a wasted block, so on large swarms
+/// these bound the bandwidth wasted on the last pieces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndgameConf {
+  /// The most peers a block may be requested from at the same time.
+  pub max_requests_per_block: usize,
+  /// The least time between requesting a block and requesting it again from
+  /// another peer, so that the first peer has a chance to deliver it.
+  pub min_duplicate_interval: Duration,
+}
+
+impl Default for EndgameConf {
+  fn default() -> Self {
+    Self {
+      // one duplicate request is enough to route around a slow peer
+      max_requests_per_block: 2,
+      min_duplicate_interval: Duration::ZERO,
+    }
+  }
+}
+
+impl SessionConf {
+  /// Checks that the timeouts are non-zero and consistent with each other.
+  pub fn validate(&self) -> EngineResult<()> {
+    if self.inactivity_timeout.is_zero()
+      || self.interest_grace_period.is_zero()
+      || self.handshake_timeout.is_zero()
+      || self.keep_alive_interval.is_zero()
+      || self.snub_timeout.is_zero()
+    {
+      return Err(Error::InvalidConf("session timeouts must not be zero"));
+    }
+    if self.tick_interval.is_zero() {
+      return Err(Error::InvalidConf("session tick interval must not be zero"));
+    }
+    if self.tick_interval >= self.keep_alive_interval {
+      return Err(Error::InvalidConf(
+        "session tick interval must be shorter than the keep-alive interval",
+      ));
+    }
+    if self.max_incoming_request_count == 0 {
+      return Err(Error::InvalidConf(
+        "max incoming request count must not be zero",
+      ));
+    }
+    if !(BLOCK_LEN..=MAX_REQUEST_LEN).contains(&self.max_request_len) {
+      return Err(Error::InvalidConf(
+        "max request length must be between 16 KiB and 128 KiB",
+      ));
+    }
+    if self.endgame.max_requests_per_block == 0 {
+      return Err(Error::InvalidConf(
+        "endgame requests per block must not be zero",
+      ));
+    }
+    if self.keep_alive_interval >= self.inactivity_timeout {
+      return Err(Error::InvalidConf(
+        "keep-alive interval must be shorter than the inactivity timeout",
+      ));
+    }
+    Ok(())
+  }
+}
+
+impl Default for SessionConf {
+  fn default() -> Self {
+    SessionConf {
+      // The spec recommends closing connections after two minutes of
+      // silence.
+      inactivity_timeout: Duration::from_secs(2 * 60),
+      // This used to be hard-coded, and it works well in practice.
+      interest_grace_period: Duration::from_secs(60),
+      // A healthy peer replies in well under a second, anything slower is
+      // likely not worth waiting for.
+      handshake_timeout: Duration::from_secs(10),
+      // Half the inactivity timeout so that even a late tick won't get us
+      // disconnected.
+      keep_alive_interval: Duration::from_secs(60),
+      tick_interval: default_tick_interval(),
+      snub_timeout: default_snub_timeout(),
+      max_incoming_request_count: default_max_incoming_request_count(),
+      max_request_len: default_max_request_len(),
+      lazy_bitfield: false,
+      endgame: EndgameConf::default(),
+    }
+  }
+}
+
+/// Returns the default [`SessionConf::tick_interval`], also used for
+/// configurations saved before it existed.
+fn default_tick_interval() -> Duration {
+  // Rates are per second, so this is as often as they may change.
+  Duration::from_secs(1)
+}
+
+/// Returns the default [`SessionConf::snub_timeout`], also used for
+/// configurations saved before it existed.
+fn default_snub_timeout() -> Duration {
+  // A peer that keeps us waiting this long for a single block is not worth
+  // a pipeline of requests.
+  Duration::from_secs(60)
+}
+
+/// Returns the default [`SessionConf::max_incoming_request_count`], also
+/// used for configurations saved before it existed.
+fn default_max_incoming_request_count() -> usize {
+  // Enough to saturate a fast link with 16 KiB blocks, at 4 MiB per peer.
+  250
+}
+
+/// Returns the default [`SessionConf::max_request_len`], also used for
+/// configurations saved before it existed.
+fn default_max_request_len() -> u32 {
+  // The block length all clients use for their own requests.
+  BLOCK_LEN
 }
 
 /// Configuration of a torrent's optional alerts.
@@ -84,7 +933,7 @@ pub struct TorrentConf {
 /// By default, all optional alerts are turned off. This is because some of
 /// these alerts may have overhead that shouldn't be paid when the alerts are
 /// not used.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TorrentAlertConf {
   /// Receive the pieces that were completed each round.
   ///
@@ -99,6 +948,29 @@ pub struct TorrentAlertConf {
   /// when it is specifically needed, e.g. when the UI is showing the peers of
   /// a torrent.
   pub peers: bool,
+
+  /// Receive the torrent's files with their progress.
+  ///
+  /// As with peers, this may be expensive for torrents with many files, and
+  /// it's suggested to only turn it on while the files are shown.
+  #[serde(default)]
+  pub files: bool,
+
+  /// Receive an [`Alert::FilePlayable`] for each file once this many of its
+  /// first bytes, or all of it if it's shorter, are downloaded, e.g. so that
+  /// a media player streaming the file knows when it may start playback.
+  ///
+  /// This only applies to torrents that are streamed, i.e. once any of their
+  /// pieces is given a deadline with [`TorrentHandle::set_piece_deadline`].
+  /// Pieces are otherwise picked rarest first, so the prefix is only
+  /// downloaded first, rather than in random order, if its pieces are given
+  /// deadlines too.
+  ///
+  /// [`Alert::FilePlayable`]: crate::alert::Alert::FilePlayable
+  /// [`TorrentHandle::set_piece_deadline`]:
+  /// crate::torrent::handle::TorrentHandle::set_piece_deadline
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub playable_prefix: Option<u64>,
 }
 
 impl Default for TorrentConf {
@@ -111,11 +983,164 @@ impl Default for TorrentConf {
       // This value is mostly picked for performance while keeping in mind
       // not to overwhelm the host.
       max_connected_peer_count: 50,
-      // need testing
-      announce_interval: Duration::from_secs(60 * 60),
-      // need testing
-      tracker_error_threshold: 15,
+      // This is what most trackers ask for.
+      announce_interval: Duration::from_secs(30 * 60),
+      max_announce_interval: Duration::from_secs(2 * 60 * 60),
+      // Most trackers ask for a similar minimum interval.
+      min_announce_interval: Duration::from_secs(60),
+      ignore_tracker_min_interval: false,
+      numwant: None,
+      tracker_backoff: TrackerBackoffConf::default(),
+      tracker_timeout: default_tracker_timeout(),
+      max_connections_per_ip: default_max_connections_per_ip(),
+      max_hash_failures: default_max_hash_failures(),
+      unchoke_slot_count: None,
+      session: Default::default(),
       alerts: Default::default(),
+      priority: Default::default(),
+      download_rate_limit: None,
+      upload_rate_limit: None,
+      completion_command: None,
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_default_conf_is_valid() {
+    assert!(Conf::new("/tmp").validate().is_ok());
+  }
+
+  #[test]
+  fn test_tracker_http_conf() {
+    let mut conf = TrackerHttpConf::default();
+    conf.headers.push(("Cookie".into(), "passkey=abc".into()));
+    let headers = conf.header_map().unwrap();
+    assert_eq!(headers[USER_AGENT], CLIENT_USER_AGENT);
+    assert_eq!(headers["cookie"], "passkey=abc");
+
+    conf.headers.push(("bad name".into(), "value".into()));
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+    conf.headers.pop();
+    conf.headers.push(("X-Passkey".into(), "bad\nvalue".into()));
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+    conf.headers.pop();
+
+    conf.root_certificates.push(b"not a certificate".to_vec());
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+  }
+
+  #[test]
+  fn test_invalid_session_conf() {
+    let mut conf = SessionConf {
+      handshake_timeout: Duration::ZERO,
+      ..Default::default()
+    };
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.handshake_timeout = Duration::from_secs(10);
+    conf.keep_alive_interval = conf.inactivity_timeout;
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.keep_alive_interval = conf.inactivity_timeout / 2;
+    assert!(conf.validate().is_ok());
+
+    conf.tick_interval = Duration::ZERO;
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+    conf.tick_interval = conf.keep_alive_interval;
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+    conf.tick_interval = Duration::from_secs(5);
+    assert!(conf.validate().is_ok());
+
+    conf.endgame.max_requests_per_block = 0;
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+  }
+
+  #[test]
+  fn test_invalid_announce_conf() {
+    let mut conf = TorrentConf {
+      announce_interval: Duration::from_secs(3 * 60 * 60),
+      ..Default::default()
+    };
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.announce_interval = Duration::from_secs(10);
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.announce_interval = conf.min_announce_interval;
+    assert!(conf.validate().is_ok());
+
+    conf.numwant = Some(0);
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+    conf.numwant = Some(200);
+    assert!(conf.validate().is_ok());
+  }
+
+  #[test]
+  fn test_invalid_tracker_backoff_conf() {
+    let mut conf = TorrentConf::default();
+    conf.tracker_backoff.initial_delay = Duration::ZERO;
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.tracker_backoff.initial_delay =
+      conf.tracker_backoff.max_delay + Duration::from_secs(1);
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.tracker_backoff.initial_delay = conf.tracker_backoff.max_delay;
+    assert!(conf.validate().is_ok());
+
+    conf.tracker_timeout = Duration::ZERO;
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+  }
+
+  #[test]
+  fn test_invalid_rate_limit_conf() {
+    let mut conf = TorrentConf {
+      upload_rate_limit: Some(0),
+      ..Default::default()
+    };
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.upload_rate_limit = Some(1);
+    assert!(conf.validate().is_ok());
+  }
+
+  #[test]
+  fn test_invalid_completion_command() {
+    let mut conf = Conf::new("/tmp");
+    conf.engine.completion_hook = Some(CompletionHook::Command(Vec::new()));
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.engine.completion_hook = None;
+    conf.torrent.completion_command = Some(Vec::new());
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.torrent.completion_command = Some(vec!["true".to_owned()]);
+    assert!(conf.validate().is_ok());
+  }
+
+  #[test]
+  fn test_dht_source_addr_conf() {
+    let mut conf = Conf::new("/tmp");
+    conf.engine.source_addr = Some("::1".parse().unwrap());
+    assert!(conf.validate().is_ok());
+
+    conf.engine.dht = Some(DhtConf::default());
+    assert!(matches!(conf.validate(), Err(Error::InvalidConf(_))));
+
+    conf.engine.source_addr = Some("127.0.0.1".parse().unwrap());
+    assert!(conf.validate().is_ok());
+  }
+
+  #[test]
+  fn test_priority_reserved_connections() {
+    assert_eq!(Priority::Low.reserved_connections(500), 125);
+    assert_eq!(Priority::Normal.reserved_connections(500), 50);
+    assert_eq!(Priority::High.reserved_connections(500), 0);
+    // a high priority torrent may take the very last slot
+    assert_eq!(Priority::High.reserved_connections(1), 0);
+  }
+}
@@ -1,4 +1,4 @@
-use std::ops::AddAssign;
+use std::{ops::AddAssign, time::Duration};
 
 /// Counts statistics about the communication channels used in torrents.
 #[derive(Clone, Copy, Debug, Default)]
@@ -19,9 +19,25 @@ impl ThruputCounters {
   ///
   /// This should be called once a second to provide accurate per second.
   pub fn reset(&mut self) {
-    self.protocol.reset();
-    self.payload.reset();
-    self.waste.reset();
+    self.reset_over(Duration::from_secs(1));
+  }
+
+  /// Resets the per-round accumulators of the counters, for a round that
+  /// lasted the given time.
+  pub fn reset_over(&mut self, round_len: Duration) {
+    self.protocol.reset_over(round_len);
+    self.payload.reset_over(round_len);
+    self.waste.reset_over(round_len);
+  }
+
+  /// Returns counters whose rounds hold what was counted since the totals
+  /// of the given earlier snapshot of these counters.
+  pub fn since(&self, prev: &ThruputCounters) -> ThruputCounters {
+    ThruputCounters {
+      protocol: self.protocol.since(&prev.protocol),
+      payload: self.payload.since(&prev.payload),
+      waste: self.waste.since(&prev.waste),
+    }
   }
 }
 
@@ -47,8 +63,21 @@ impl ChannelCounter {
   ///
   /// This should be called once a second to provide accurate per second thruput rate.
   pub fn reset(&mut self) {
-    self.down.reset();
-    self.up.reset();
+    self.reset_over(Duration::from_secs(1));
+  }
+
+  /// Resets the per-round accumulators of the counters, for a round that
+  /// lasted the given time.
+  pub fn reset_over(&mut self, round_len: Duration) {
+    self.down.reset_over(round_len);
+    self.up.reset_over(round_len);
+  }
+
+  fn since(&self, prev: &ChannelCounter) -> ChannelCounter {
+    ChannelCounter {
+      down: self.down.since(&prev.down),
+      up: self.up.since(&prev.up),
+    }
   }
 }
 
@@ -84,6 +113,15 @@ pub struct Counter {
 impl Counter {
   const WEIGHT: u64 = 5;
 
+  /// Creates a counter that starts from the given total, e.g. one restored
+  /// from a previous session, without affecting the throughput rates.
+  pub fn with_total(total: u64) -> Self {
+    Self {
+      total,
+      ..Default::default()
+    }
+  }
+
   /// Records some bytes that were transferred.
   pub fn add(&mut self, bytes: u64) {
     self.total += bytes;
@@ -96,9 +134,18 @@ impl Counter {
   ///
   /// This assumes that this function is called once a second.
   pub fn reset(&mut self) {
+    self.reset_over(Duration::from_secs(1));
+  }
+
+  /// Finishes counting a round that lasted the given time, which needn't be
+  /// a second, and updates the moving average of the per second rate.
+  ///
+  /// The average still spans 5 rounds, so longer rounds make it smoother.
+  pub fn reset_over(&mut self, round_len: Duration) {
+    let rate = self.round as f64 / round_len.as_secs_f64();
     // https://github.com/arvidn/libtorrent/blob/master/src/stat.cpp
     self.avg = (self.avg * (Self::WEIGHT - 1) as f64 / Self::WEIGHT as f64)
-      + (self.round as f64 / Self::WEIGHT as f64);
+      + (rate / Self::WEIGHT as f64);
 
     self.round = 0;
 
@@ -126,6 +173,14 @@ impl Counter {
   pub fn round(&self) -> u64 {
     self.round
   }
+
+  /// Returns a counter whose round holds the number recorded since the
+  /// total of the given earlier snapshot of this counter.
+  fn since(&self, prev: &Counter) -> Counter {
+    let mut counter = Counter::default();
+    counter += self.total - prev.total;
+    counter
+  }
 }
 
 impl AddAssign<u64> for Counter {
@@ -191,4 +246,24 @@ mod tests {
     assert_eq!(c.round(), 0);
     assert_eq!(c.total(), 46);
   }
+
+  #[test]
+  fn test_counter_over_longer_rounds() {
+    let mut c = Counter::default();
+
+    // 10 bytes over 2 seconds is a rate of 5 per second:
+    // 4 * 0 / 5 + 5 / 5 = 1
+    c += 10;
+    c.reset_over(Duration::from_secs(2));
+    assert_eq!(c.avg(), 1);
+    assert_eq!(c.total(), 10);
+
+    // what was counted since a snapshot ends up in the round
+    let snapshot = c;
+    c += 7;
+    c.reset_over(Duration::from_secs(2));
+    let delta = c.since(&snapshot);
+    assert_eq!(delta.round(), 7);
+    assert_eq!(delta.total(), 7);
+  }
 }
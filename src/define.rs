@@ -26,6 +26,12 @@ pub type Bitfield = bitvec::prelude::BitVec<u8>;
 pub const BLOCK_LEN: u32 = 0x4000;
 // pub const BLOCK_LEN: u32 = 4;
 
+/// The longest block a peer may request, past which the request is a protocol
+/// violation. Some clients request more than [`BLOCK_LEN`] at a time, which
+/// are served up to
+/// [`SessionConf::max_request_len`](crate::conf::SessionConf::max_request_len).
+pub const MAX_REQUEST_LEN: u32 = 8 * BLOCK_LEN;
+
 /// The type of a piece's index.
 ///
 /// On the wire all integers are sent as 4-byte big endian integers, but in the
@@ -37,7 +43,7 @@ pub(crate) type FileIndex = usize;
 
 /// Each torrent gets a randomly assigned ID that is globally unique.
 /// This id used in engine APIs to interact with torrents.
-#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Hash)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TorrentId(u32);
 
 impl TorrentId {
@@ -0,0 +1,358 @@
+//! The KRPC protocol of the DHT (BEP 5), whose queries, responses and errors
+//! are bencoded dictionaries sent in UDP datagrams.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use bytes::{Buf, BufMut};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+
+use super::routing::NodeId;
+use crate::Sha1Hash;
+
+/// The error code of a query that is malformed, e.g. of an unknown method,
+/// or has an invalid token.
+pub const PROTOCOL_ERROR: i64 = 203;
+
+/// A KRPC message, whose transaction id is chosen by the querying node and
+/// echoed in the response, so that responses can be matched to queries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+  pub transaction_id: Vec<u8>,
+  pub body: Body,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Body {
+  Query(Query),
+  Response(Response),
+  Error { code: i64, message: String },
+}
+
+/// A query, which carries the id of the querying node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+  Ping {
+    id: NodeId,
+  },
+  FindNode {
+    id: NodeId,
+    target: NodeId,
+  },
+  GetPeers {
+    id: NodeId,
+    info_hash: Sha1Hash,
+  },
+  AnnouncePeer {
+    id: NodeId,
+    info_hash: Sha1Hash,
+    /// The port on which the peer accepts connections, unless
+    /// `implied_port` is set, in which case the port the query is sent from
+    /// is used instead, e.g. as only that one is mapped through a NAT.
+    port: u16,
+    implied_port: bool,
+    /// The token the queried node gave in response to an earlier
+    /// `get_peers` query.
+    token: Vec<u8>,
+  },
+}
+
+impl Query {
+  /// Returns the id of the querying node.
+  pub fn id(&self) -> NodeId {
+    match self {
+      Self::Ping { id }
+      | Self::FindNode { id, .. }
+      | Self::GetPeers { id, .. }
+      | Self::AnnouncePeer { id, .. } => *id,
+    }
+  }
+}
+
+/// A response, which doesn't tell the method of the query it answers, so
+/// all values are optional and only those of the query's method are set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Response {
+  /// The id of the responding node.
+  pub id: NodeId,
+  /// The nodes closest to the target or info hash, in answer to
+  /// `find_node` and `get_peers` queries.
+  pub nodes: Vec<(NodeId, SocketAddrV4)>,
+  /// The peers of the torrent, in answer to `get_peers` queries.
+  pub values: Vec<SocketAddr>,
+  /// The token to include in an `announce_peer` query, in answer to
+  /// `get_peers` queries.
+  pub token: Option<Vec<u8>>,
+}
+
+/// The bencoded form of a message, whose keys are declared in sorted order
+/// as bencoded dictionaries must be.
+#[derive(Default, Serialize, Deserialize)]
+struct RawMessage {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  a: Option<RawArgs>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  e: Option<(i64, String)>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  q: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  r: Option<RawResponse>,
+  t: ByteBuf,
+  y: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct RawArgs {
+  id: ByteBuf,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  implied_port: Option<u8>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  info_hash: Option<ByteBuf>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  port: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  target: Option<ByteBuf>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  token: Option<ByteBuf>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct RawResponse {
+  id: ByteBuf,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  nodes: Option<ByteBuf>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  token: Option<ByteBuf>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  values: Option<Vec<ByteBuf>>,
+}
+
+impl Message {
+  pub fn encode(&self) -> Vec<u8> {
+    let mut raw = RawMessage {
+      t: ByteBuf::from(self.transaction_id.clone()),
+      ..Default::default()
+    };
+    match &self.body {
+      Body::Query(query) => {
+        let mut args = RawArgs {
+          id: ByteBuf::from(query.id().to_vec()),
+          ..Default::default()
+        };
+        let method = match query {
+          Query::Ping { .. } => "ping",
+          Query::FindNode { target, .. } => {
+            args.target = Some(ByteBuf::from(target.to_vec()));
+            "find_node"
+          }
+          Query::GetPeers { info_hash, .. } => {
+            args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+            "get_peers"
+          }
+          Query::AnnouncePeer {
+            info_hash,
+            port,
+            implied_port,
+            token,
+            ..
+          } => {
+            args.implied_port = Some(*implied_port as u8);
+            args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+            args.port = Some(*port);
+            args.token = Some(ByteBuf::from(token.clone()));
+            "announce_peer"
+          }
+        };
+        raw.y = "q".to_owned();
+        raw.q = Some(method.to_owned());
+        raw.a = Some(args);
+      }
+      Body::Response(resp) => {
+        let nodes = (!resp.nodes.is_empty()).then(|| {
+          let mut nodes = Vec::with_capacity(resp.nodes.len() * 26);
+          for (id, addr) in &resp.nodes {
+            nodes.put_slice(id);
+            nodes.put_slice(&addr.ip().octets());
+            nodes.put_u16(addr.port());
+          }
+          ByteBuf::from(nodes)
+        });
+        let values = (!resp.values.is_empty())
+          .then(|| resp.values.iter().filter_map(encode_peer).collect());
+        raw.y = "r".to_owned();
+        raw.r = Some(RawResponse {
+          id: ByteBuf::from(resp.id.to_vec()),
+          nodes,
+          token: resp.token.clone().map(ByteBuf::from),
+          values,
+        });
+      }
+      Body::Error { code, message } => {
+        raw.y = "e".to_owned();
+        raw.e = Some((*code, message.clone()));
+      }
+    }
+    // the raw message only holds values that can be bencoded
+    serde_bencoded::to_vec(&raw).expect("KRPC message must be bencodable")
+  }
+
+  /// Decodes a message, returning `None` if it's malformed.
+  pub fn decode(buf: &[u8]) -> Option<Self> {
+    let raw: RawMessage = serde_bencoded::from_bytes(buf).ok()?;
+    let body = match raw.y.as_str() {
+      "q" => {
+        let args = raw.a?;
+        let id = node_id(&args.id)?;
+        let query = match raw.q?.as_str() {
+          "ping" => Query::Ping { id },
+          "find_node" => Query::FindNode {
+            id,
+            target: node_id(args.target.as_deref()?)?,
+          },
+          "get_peers" => Query::GetPeers {
+            id,
+            info_hash: node_id(args.info_hash.as_deref()?)?,
+          },
+          "announce_peer" => Query::AnnouncePeer {
+            id,
+            info_hash: node_id(args.info_hash.as_deref()?)?,
+            port: args.port.unwrap_or_default(),
+            implied_port: args.implied_port.is_some_and(|p| p != 0),
+            token: args.token?.into_vec(),
+          },
+          _ => return None,
+        };
+        Body::Query(query)
+      }
+      "r" => {
+        let r = raw.r?;
+        Body::Response(Response {
+          id: node_id(&r.id)?,
+          nodes: r
+            .nodes
+            .as_deref()
+            .map_or(&[][..], |nodes| nodes)
+            .chunks_exact(26)
+            .map(|mut node| {
+              let id = node_id(&node[..20]).expect("node must have an id");
+              node.advance(20);
+              let ip = Ipv4Addr::from(node.get_u32());
+              (id, SocketAddrV4::new(ip, node.get_u16()))
+            })
+            .collect(),
+          values: r
+            .values
+            .unwrap_or_default()
+            .iter()
+            .filter(|peer| peer.len() == 6)
+            .map(|peer| {
+              let mut peer = &peer[..];
+              let ip = Ipv4Addr::from(peer.get_u32());
+              SocketAddr::new(ip.into(), peer.get_u16())
+            })
+            .collect(),
+          token: r.token.map(ByteBuf::into_vec),
+        })
+      }
+      "e" => {
+        let (code, message) = raw.e?;
+        Body::Error { code, message }
+      }
+      _ => return None,
+    };
+    Some(Self {
+      transaction_id: raw.t.into_vec(),
+      body,
+    })
+  }
+
+  /// Returns the transaction id of a query that can't be decoded, e.g. as
+  /// its method is unknown, so that it can be answered with an error rather
+  /// than ignored.
+  pub fn query_transaction_id(buf: &[u8]) -> Option<Vec<u8>> {
+    let raw: RawMessage = serde_bencoded::from_bytes(buf).ok()?;
+    (raw.y == "q").then(|| raw.t.into_vec())
+  }
+}
+
+/// Encodes a peer in the compact form of 6 bytes, or returns `None` if it's
+/// not an IPv4 peer, as only those are exchanged in the DHT.
+fn encode_peer(addr: &SocketAddr) -> Option<ByteBuf> {
+  let SocketAddr::V4(addr) = addr else {
+    return None;
+  };
+  let mut peer = Vec::with_capacity(6);
+  peer.put_slice(&addr.ip().octets());
+  peer.put_u16(addr.port());
+  Some(ByteBuf::from(peer))
+}
+
+fn node_id(buf: &[u8]) -> Option<NodeId> {
+  buf.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_query() {
+    // the examples of BEP 5
+    let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    let msg = Message {
+      transaction_id: b"aa".to_vec(),
+      body: Body::Query(Query::Ping {
+        id: *b"abcdefghij0123456789",
+      }),
+    };
+    assert_eq!(Message::decode(ping).unwrap(), msg);
+    assert_eq!(msg.encode(), ping);
+
+    let announce = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e\
+      9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe\
+      1:q13:announce_peer1:t2:aa1:y1:qe";
+    let msg = Message {
+      transaction_id: b"aa".to_vec(),
+      body: Body::Query(Query::AnnouncePeer {
+        id: *b"abcdefghij0123456789",
+        info_hash: *b"mnopqrstuvwxyz123456",
+        port: 6881,
+        implied_port: true,
+        token: b"aoeusnth".to_vec(),
+      }),
+    };
+    assert_eq!(Message::decode(announce).unwrap(), msg);
+    assert_eq!(msg.encode(), announce);
+
+    let unknown = b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe";
+    assert_eq!(Message::decode(unknown), None);
+    assert_eq!(Message::query_transaction_id(unknown), Some(b"aa".to_vec()));
+  }
+
+  #[test]
+  fn test_response() {
+    let msg = Message {
+      transaction_id: b"aa".to_vec(),
+      body: Body::Response(Response {
+        id: [1; 20],
+        nodes: vec![([2; 20], "1.2.3.4:6881".parse().unwrap())],
+        values: vec!["5.6.7.8:1234".parse().unwrap()],
+        token: Some(b"aoeusnth".to_vec()),
+      }),
+    };
+    assert_eq!(Message::decode(&msg.encode()).unwrap(), msg);
+
+    let error = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+    let msg = Message {
+      transaction_id: b"aa".to_vec(),
+      body: Body::Error {
+        code: 201,
+        message: "A Generic Error Ocurred".to_owned(),
+      },
+    };
+    assert_eq!(Message::decode(error).unwrap(), msg);
+    assert_eq!(msg.encode(), error);
+
+    assert_eq!(Message::decode(b"d1:t2:aa1:y1:re"), None);
+  }
+}
@@ -0,0 +1,793 @@
+//! A node of the BitTorrent DHT (BEP 5), the Kademlia based distributed hash
+//! table through which the peers of torrents are found without trackers.
+//!
+//! The node runs in its own task, which answers the queries of other nodes
+//! and, when asked to via its [`DhtHandle`], looks up the peers of torrents
+//! and announces that we're one of them. Only IPv4 is supported.
+
+mod krpc;
+mod routing;
+
+use std::{
+  collections::HashMap,
+  io,
+  net::{IpAddr, SocketAddr, SocketAddrV4},
+  time::{Duration, Instant},
+};
+
+use futures::future::{self, BoxFuture, FutureExt};
+use sha1::{Digest, Sha1};
+use tokio::{
+  net::UdpSocket,
+  sync::{mpsc, oneshot},
+  task, time,
+};
+
+use self::{
+  krpc::{Body, Message, Query, Response},
+  routing::{distance, RoutingTable, K},
+};
+use crate::{conf::DhtConf, Sha1Hash};
+
+pub use routing::NodeId;
+
+/// How often torrents look up their peers in the DHT and announce
+/// themselves, which is more often than announced peers expire.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// How long a torrent that ran out of peers waits before looking them up in
+/// the DHT again.
+pub const MIN_LOOKUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The number of queries a lookup has in flight at a time.
+const ALPHA: usize = 3;
+
+/// The time after which a query that wasn't responded to is given up.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// While the routing table holds fewer than [`K`] nodes, the bootstrap
+/// nodes are queried again this often.
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the secret from which tokens are derived is changed. Tokens
+/// derived from the previous secret are accepted too, so a token is valid
+/// for up to twice as long.
+const TOKEN_SECRET_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long the peers announced to us are kept.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// The most peers kept of each torrent, and the number of torrents whose
+/// peers are kept, so that other nodes can't make us use an arbitrary amount
+/// of memory.
+const MAX_STORED_PEER_COUNT: usize = 100;
+const MAX_STORED_TORRENT_COUNT: usize = 1000;
+
+/// The most peers returned in response to a `get_peers` query, so that the
+/// response fits in a datagram.
+const MAX_RETURNED_PEER_COUNT: usize = 50;
+
+/// The largest datagram that is read.
+const MAX_MESSAGE_LEN: usize = 2048;
+
+pub type JoinHandle = task::JoinHandle<()>;
+
+/// Spawns the DHT node's task, listening on the configured address, and
+/// returns its join handle and the handle through which it's used.
+///
+/// If the listen address is unspecified and a source address is given, the
+/// node is bound to the source address instead, so that its traffic goes
+/// out through the same interface as that of the peers and trackers.
+pub fn spawn(
+  conf: &DhtConf,
+  source_addr: Option<IpAddr>,
+) -> io::Result<(JoinHandle, DhtHandle)> {
+  let listen_addr = match source_addr {
+    Some(ip) if conf.listen_addr.ip().is_unspecified() => {
+      SocketAddr::new(ip, conf.listen_addr.port())
+    }
+    _ => conf.listen_addr,
+  };
+  log::info!("Spawning DHT task on {}", listen_addr);
+  let socket = std::net::UdpSocket::bind(listen_addr)?;
+  socket.set_nonblocking(true)?;
+  let socket = UdpSocket::from_std(socket)?;
+  let port = socket.local_addr()?.port();
+  let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+  let mut dht = Dht::new(socket, cmd_rx, conf.bootstrap_nodes.clone());
+  let join_handle = task::spawn(async move { dht.run().await });
+  Ok((join_handle, DhtHandle { cmd_tx, port }))
+}
+
+/// The handle to the DHT node's task, shared by the torrents.
+#[derive(Clone, Debug)]
+pub struct DhtHandle {
+  cmd_tx: mpsc::UnboundedSender<Command>,
+  port: u16,
+}
+
+impl DhtHandle {
+  /// Returns the port on which the node listens, which is told to peers
+  /// that support the DHT.
+  pub fn port(&self) -> u16 {
+    self.port
+  }
+
+  /// Looks up the peers of the torrent, also announcing that we accept its
+  /// peers on the given port if set, and returns them once the lookup is
+  /// done.
+  pub fn get_peers(
+    &self,
+    info_hash: Sha1Hash,
+    announce_port: Option<u16>,
+  ) -> oneshot::Receiver<Vec<SocketAddr>> {
+    let (result_tx, result_rx) = oneshot::channel();
+    self
+      .cmd_tx
+      .send(Command::GetPeers {
+        info_hash,
+        announce_port,
+        result_tx,
+      })
+      .ok();
+    result_rx
+  }
+
+  /// Adds the node at the address to the routing table if it responds, e.g.
+  /// as a peer told us its DHT port.
+  pub fn add_node(&self, addr: SocketAddr) {
+    self.cmd_tx.send(Command::AddNode(addr)).ok();
+  }
+
+  /// Tells the node's task to stop.
+  pub fn shutdown(&self) {
+    self.cmd_tx.send(Command::Shutdown).ok();
+  }
+}
+
+#[derive(Debug)]
+enum Command {
+  GetPeers {
+    info_hash: Sha1Hash,
+    announce_port: Option<u16>,
+    result_tx: oneshot::Sender<Vec<SocketAddr>>,
+  },
+  AddNode(SocketAddr),
+  Shutdown,
+}
+
+/// A query awaiting a response.
+struct Transaction {
+  addr: SocketAddrV4,
+  /// The id of the queried node, which is not known for the bootstrap nodes
+  /// and the nodes told by peers.
+  node_id: Option<NodeId>,
+  time: Instant,
+  /// The lookup the query is part of, if any.
+  lookup_id: Option<u64>,
+}
+
+/// An iterative lookup of the nodes closest to a target, which queries the
+/// closest nodes found so far until the [`K`] closest have responded.
+struct Lookup {
+  target: NodeId,
+  kind: LookupKind,
+  /// The nodes found so far, closest first, with the nodes of unknown id
+  /// last.
+  nodes: Vec<LookupNode>,
+  peers: Vec<SocketAddr>,
+  pending_count: usize,
+}
+
+enum LookupKind {
+  /// Finds the nodes closest to our own id, filling the routing table.
+  Bootstrap,
+  /// Finds the peers of the torrent whose info hash is the target.
+  GetPeers {
+    announce_port: Option<u16>,
+    result_tx: oneshot::Sender<Vec<SocketAddr>>,
+  },
+}
+
+struct LookupNode {
+  id: Option<NodeId>,
+  addr: SocketAddrV4,
+  state: QueryState,
+  /// The token with which we may announce to the node.
+  token: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum QueryState {
+  NotQueried,
+  Pending,
+  Responded,
+  Failed,
+}
+
+impl Lookup {
+  /// Adds the nodes that aren't part of the lookup yet.
+  fn add_nodes(
+    &mut self,
+    nodes: impl IntoIterator<Item = (Option<NodeId>, SocketAddrV4)>,
+  ) {
+    for (id, addr) in nodes {
+      if !self.nodes.iter().any(|n| n.addr == addr) {
+        self.nodes.push(LookupNode {
+          id,
+          addr,
+          state: QueryState::NotQueried,
+          token: None,
+        });
+      }
+    }
+    let target = self.target;
+    self
+      .nodes
+      .sort_by_key(|n| (n.id.is_none(), n.id.map(|id| distance(&id, &target))));
+  }
+
+  /// Returns the closest node to query next, if any of the [`K`] closest
+  /// nodes that haven't failed is yet to be queried.
+  fn next_node(&mut self) -> Option<&mut LookupNode> {
+    self
+      .nodes
+      .iter_mut()
+      .filter(|n| n.state != QueryState::Failed)
+      .take(K)
+      .find(|n| n.state == QueryState::NotQueried)
+  }
+}
+
+struct Dht {
+  socket: UdpSocket,
+  cmd_rx: mpsc::UnboundedReceiver<Command>,
+  table: RoutingTable,
+  /// The nodes through which we join the DHT, as `host:port`, and their
+  /// addresses once resolved, which are queried while the routing table is
+  /// not full.
+  bootstrap_nodes: Vec<String>,
+  bootstrap_addrs: Vec<SocketAddrV4>,
+  last_bootstrap_time: Option<Instant>,
+  transactions: HashMap<u16, Transaction>,
+  next_transaction_id: u16,
+  lookups: HashMap<u64, Lookup>,
+  next_lookup_id: u64,
+  /// The peers announced to us, by torrent, with the time of their
+  /// announce.
+  peers: HashMap<Sha1Hash, Vec<(SocketAddr, Instant)>>,
+  /// The current and the previous secret from which tokens are derived.
+  token_secrets: [[u8; 20]; 2],
+  token_secret_time: Instant,
+}
+
+impl Dht {
+  fn new(
+    socket: UdpSocket,
+    cmd_rx: mpsc::UnboundedReceiver<Command>,
+    bootstrap_nodes: Vec<String>,
+  ) -> Self {
+    Self {
+      socket,
+      cmd_rx,
+      table: RoutingTable::new(rand::random()),
+      bootstrap_nodes,
+      bootstrap_addrs: Vec::new(),
+      last_bootstrap_time: None,
+      transactions: HashMap::new(),
+      next_transaction_id: 0,
+      lookups: HashMap::new(),
+      next_lookup_id: 0,
+      peers: HashMap::new(),
+      token_secrets: rand::random(),
+      token_secret_time: Instant::now(),
+    }
+  }
+
+  async fn run(&mut self) {
+    let mut tick_timer = time::interval(Duration::from_secs(1));
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    // the resolution of the bootstrap nodes, which is not awaited in the
+    // handlers so as not to hold up the queries of other nodes
+    let mut resolving: Option<BoxFuture<'static, Vec<SocketAddrV4>>> = None;
+    loop {
+      tokio::select! {
+        tick_time = tick_timer.tick() => {
+          let now = tick_time.into_std();
+          self.tick(now);
+          let is_bootstrap_due = self.table.len() < K
+            && self
+              .last_bootstrap_time
+              .is_none_or(|t| now.saturating_duration_since(t) >= BOOTSTRAP_INTERVAL);
+          if is_bootstrap_due && resolving.is_none() {
+            self.last_bootstrap_time = Some(now);
+            if self.bootstrap_nodes.is_empty() {
+              self.start_lookup(*self.table.id(), LookupKind::Bootstrap);
+            } else {
+              resolving =
+                Some(resolve(self.bootstrap_nodes.clone()).boxed());
+            }
+          }
+        }
+        addrs = async {
+          match &mut resolving {
+            Some(resolving) => resolving.await,
+            None => future::pending().await,
+          }
+        } => {
+          resolving = None;
+          if addrs.is_empty() {
+            log::warn!("Couldn't resolve any DHT bootstrap node");
+          }
+          self.bootstrap_addrs = addrs;
+          self.start_lookup(*self.table.id(), LookupKind::Bootstrap);
+        }
+        result = self.socket.recv_from(&mut buf) => match result {
+          Ok((len, SocketAddr::V4(addr))) => {
+            self.handle_message(&buf[..len], addr);
+          }
+          Ok(_) => {}
+          Err(e) => log::debug!("DHT receive error: {}", e),
+        },
+        cmd = self.cmd_rx.recv() => match cmd {
+          Some(Command::GetPeers { info_hash, announce_port, result_tx }) => {
+            self.start_lookup(
+              info_hash,
+              LookupKind::GetPeers { announce_port, result_tx },
+            );
+          }
+          Some(Command::AddNode(SocketAddr::V4(addr))) => {
+            let query = Query::Ping { id: *self.table.id() };
+            self.send_query(addr, None, query, None);
+          }
+          Some(Command::AddNode(_)) => {}
+          Some(Command::Shutdown) | None => break,
+        },
+      }
+    }
+    log::info!("DHT task stopped");
+  }
+
+  /// Gives up on the queries that timed out and forgets the expired peers
+  /// and token secret.
+  fn tick(&mut self, now: Instant) {
+    let timed_out: Vec<_> = self
+      .transactions
+      .iter()
+      .filter(|(_, t)| now.saturating_duration_since(t.time) >= QUERY_TIMEOUT)
+      .map(|(&id, _)| id)
+      .collect();
+    for id in timed_out {
+      let transaction = self.transactions.remove(&id).expect("transaction");
+      if let Some(node_id) = &transaction.node_id {
+        self.table.mark_failed(node_id);
+      }
+      self.finish_query(&transaction, QueryState::Failed);
+    }
+
+    if now.saturating_duration_since(self.token_secret_time)
+      >= TOKEN_SECRET_INTERVAL
+    {
+      self.token_secrets = [rand::random(), self.token_secrets[0]];
+      self.token_secret_time = now;
+    }
+
+    self.peers.retain(|_, peers| {
+      peers.retain(|(_, time)| now.saturating_duration_since(*time) < PEER_TTL);
+      !peers.is_empty()
+    });
+  }
+
+  fn handle_message(&mut self, buf: &[u8], addr: SocketAddrV4) {
+    let Some(msg) = Message::decode(buf) else {
+      // queries are answered even if they can't be decoded, so that the
+      // querying node doesn't have to wait for them to time out
+      if let Some(transaction_id) = Message::query_transaction_id(buf) {
+        self.send(
+          addr,
+          &Message {
+            transaction_id,
+            body: Body::Error {
+              code: krpc::PROTOCOL_ERROR,
+              message: "invalid query".to_owned(),
+            },
+          },
+        );
+      }
+      return;
+    };
+    match msg.body {
+      Body::Query(query) => {
+        self.handle_query(msg.transaction_id, query, addr);
+      }
+      Body::Response(resp) => {
+        let Some(transaction) =
+          self.take_transaction(&msg.transaction_id, addr)
+        else {
+          return;
+        };
+        self.table.insert(resp.id, addr);
+        self.handle_response(&transaction, resp);
+      }
+      Body::Error { code, message } => {
+        let Some(transaction) =
+          self.take_transaction(&msg.transaction_id, addr)
+        else {
+          return;
+        };
+        log::debug!("DHT node {} error {}: {}", addr, code, message);
+        self.finish_query(&transaction, QueryState::Failed);
+      }
+    }
+  }
+
+  /// Returns the transaction of a response, if it's one of ours and the
+  /// response is from the queried address.
+  fn take_transaction(
+    &mut self,
+    transaction_id: &[u8],
+    addr: SocketAddrV4,
+  ) -> Option<Transaction> {
+    let id = u16::from_be_bytes(transaction_id.try_into().ok()?);
+    if self.transactions.get(&id)?.addr != addr {
+      return None;
+    }
+    self.transactions.remove(&id)
+  }
+
+  fn handle_query(
+    &mut self,
+    transaction_id: Vec<u8>,
+    query: Query,
+    addr: SocketAddrV4,
+  ) {
+    log::trace!("DHT node {} query: {:?}", addr, query);
+    // the source of a query may be spoofed, so an unknown node is only added
+    // once it answers our ping, which isn't sent to ourselves
+    let id = query.id();
+    if id != *self.table.id() && !self.table.contains(&id) {
+      let ping = Query::Ping {
+        id: *self.table.id(),
+      };
+      self.send_query(addr, None, ping, None);
+    }
+    let mut resp = Response {
+      id: *self.table.id(),
+      ..Default::default()
+    };
+    match query {
+      Query::Ping { .. } => {}
+      Query::FindNode { target, .. } => {
+        resp.nodes = self.table.closest(&target, K);
+      }
+      Query::GetPeers { info_hash, .. } => {
+        resp.nodes = self.table.closest(&info_hash, K);
+        resp.values = self
+          .peers
+          .get(&info_hash)
+          .into_iter()
+          .flatten()
+          .rev()
+          .take(MAX_RETURNED_PEER_COUNT)
+          .map(|(peer, _)| *peer)
+          .collect();
+        resp.token = Some(self.token(&addr, 0));
+      }
+      Query::AnnouncePeer {
+        info_hash,
+        port,
+        implied_port,
+        token,
+        ..
+      } => {
+        if token != self.token(&addr, 0) && token != self.token(&addr, 1) {
+          let body = Body::Error {
+            code: krpc::PROTOCOL_ERROR,
+            message: "invalid token".to_owned(),
+          };
+          self.send(
+            addr,
+            &Message {
+              transaction_id,
+              body,
+            },
+          );
+          return;
+        }
+        let port = if implied_port { addr.port() } else { port };
+        self.store_peer(info_hash, SocketAddr::new((*addr.ip()).into(), port));
+      }
+    }
+    self.send(
+      addr,
+      &Message {
+        transaction_id,
+        body: Body::Response(resp),
+      },
+    );
+  }
+
+  fn handle_response(&mut self, transaction: &Transaction, resp: Response) {
+    let Some(lookup) = transaction
+      .lookup_id
+      .and_then(|id| self.lookups.get_mut(&id))
+    else {
+      return;
+    };
+    if let Some(node) =
+      lookup.nodes.iter_mut().find(|n| n.addr == transaction.addr)
+    {
+      node.id = Some(resp.id);
+      node.token = resp.token;
+    }
+    for peer in resp.values {
+      if !lookup.peers.contains(&peer) {
+        lookup.peers.push(peer);
+      }
+    }
+    lookup.add_nodes(resp.nodes.into_iter().map(|(id, addr)| (Some(id), addr)));
+    self.finish_query(transaction, QueryState::Responded);
+  }
+
+  /// Records the outcome of a lookup's query, and continues the lookup.
+  fn finish_query(&mut self, transaction: &Transaction, state: QueryState) {
+    let Some(lookup_id) = transaction.lookup_id else {
+      return;
+    };
+    let Some(lookup) = self.lookups.get_mut(&lookup_id) else {
+      return;
+    };
+    if let Some(node) =
+      lookup.nodes.iter_mut().find(|n| n.addr == transaction.addr)
+    {
+      node.state = state;
+    }
+    lookup.pending_count -= 1;
+    self.advance_lookup(lookup_id);
+  }
+
+  /// Starts a lookup of the target from the closest nodes we know of, and
+  /// the bootstrap nodes if we don't know enough.
+  fn start_lookup(&mut self, target: NodeId, kind: LookupKind) {
+    let mut lookup = Lookup {
+      target,
+      kind,
+      nodes: Vec::new(),
+      peers: Vec::new(),
+      pending_count: 0,
+    };
+    let closest = self.table.closest(&target, K);
+    if closest.len() < K {
+      lookup.add_nodes(self.bootstrap_addrs.iter().map(|&addr| (None, addr)));
+    }
+    lookup.add_nodes(closest.into_iter().map(|(id, addr)| (Some(id), addr)));
+
+    let id = self.next_lookup_id;
+    self.next_lookup_id += 1;
+    self.lookups.insert(id, lookup);
+    self.advance_lookup(id);
+  }
+
+  /// Queries the next closest nodes of the lookup, or finishes the lookup
+  /// if the closest nodes have all responded or failed.
+  fn advance_lookup(&mut self, id: u64) {
+    let own_id = *self.table.id();
+    let Some(lookup) = self.lookups.get_mut(&id) else {
+      return;
+    };
+    let mut queries = Vec::new();
+    while lookup.pending_count < ALPHA {
+      let target = lookup.target;
+      let is_bootstrap = matches!(lookup.kind, LookupKind::Bootstrap);
+      let Some(node) = lookup.next_node() else {
+        break;
+      };
+      node.state = QueryState::Pending;
+      let query = if is_bootstrap {
+        Query::FindNode { id: own_id, target }
+      } else {
+        Query::GetPeers {
+          id: own_id,
+          info_hash: target,
+        }
+      };
+      queries.push((node.addr, node.id, query));
+      lookup.pending_count += 1;
+    }
+    let is_done = lookup.pending_count == 0;
+    for (addr, node_id, query) in queries {
+      self.send_query(addr, node_id, query, Some(id));
+    }
+    if !is_done {
+      return;
+    }
+
+    let lookup = self.lookups.remove(&id).expect("lookup");
+    if let LookupKind::GetPeers {
+      announce_port,
+      result_tx,
+    } = lookup.kind
+    {
+      log::debug!(
+        "DHT lookup of {} found {} peer(s)",
+        hex::encode(lookup.target),
+        lookup.peers.len()
+      );
+      if let Some(port) = announce_port {
+        let closest = lookup
+          .nodes
+          .iter()
+          .filter(|n| n.state == QueryState::Responded)
+          .take(K);
+        for node in closest {
+          let Some(token) = node.token.clone() else {
+            continue;
+          };
+          let query = Query::AnnouncePeer {
+            id: own_id,
+            info_hash: lookup.target,
+            port,
+            implied_port: false,
+            token,
+          };
+          self.send_query(node.addr, node.id, query, None);
+        }
+      }
+      result_tx.send(lookup.peers).ok();
+    }
+  }
+
+  fn send_query(
+    &mut self,
+    addr: SocketAddrV4,
+    node_id: Option<NodeId>,
+    query: Query,
+    lookup_id: Option<u64>,
+  ) {
+    // once the ids wrap around, those of queries still pending are skipped,
+    // so that their responses aren't taken for those of the new query
+    if self.transactions.len() > u16::MAX as usize {
+      log::warn!("Too many pending DHT queries, dropping query to {}", addr);
+      return;
+    }
+    let mut id = self.next_transaction_id;
+    while self.transactions.contains_key(&id) {
+      id = id.wrapping_add(1);
+    }
+    self.next_transaction_id = id.wrapping_add(1);
+    self.transactions.insert(
+      id,
+      Transaction {
+        addr,
+        node_id,
+        time: Instant::now(),
+        lookup_id,
+      },
+    );
+    self.send(
+      addr,
+      &Message {
+        transaction_id: id.to_be_bytes().to_vec(),
+        body: Body::Query(query),
+      },
+    );
+  }
+
+  /// Sends the message without waiting, dropping it if the socket's buffer
+  /// is full, as any datagram may be lost anyway.
+  fn send(&self, addr: SocketAddrV4, msg: &Message) {
+    if let Err(e) = self.socket.try_send_to(&msg.encode(), addr.into()) {
+      log::debug!("DHT send to {} error: {}", addr, e);
+    }
+  }
+
+  fn store_peer(&mut self, info_hash: Sha1Hash, peer: SocketAddr) {
+    if !self.peers.contains_key(&info_hash)
+      && self.peers.len() >= MAX_STORED_TORRENT_COUNT
+    {
+      return;
+    }
+    let peers = self.peers.entry(info_hash).or_default();
+    peers.retain(|(addr, _)| *addr != peer);
+    if peers.len() >= MAX_STORED_PEER_COUNT {
+      peers.remove(0);
+    }
+    peers.push((peer, Instant::now()));
+  }
+
+  /// Returns the token given to the address, derived from the current or
+  /// the previous secret, which proves that the address received it when
+  /// it announces.
+  fn token(&self, addr: &SocketAddrV4, secret: usize) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(self.token_secrets[secret]);
+    hasher.update(addr.ip().octets());
+    hasher.finalize()[..8].to_vec()
+  }
+}
+
+/// Resolves the `host:port` of each node to its IPv4 addresses.
+async fn resolve(nodes: Vec<String>) -> Vec<SocketAddrV4> {
+  let mut addrs = Vec::new();
+  for node in nodes {
+    match tokio::net::lookup_host(&node).await {
+      Ok(resolved) => addrs.extend(resolved.filter_map(|addr| match addr {
+        SocketAddr::V4(addr) => Some(addr),
+        SocketAddr::V6(_) => None,
+      })),
+      Err(e) => log::debug!("Couldn't resolve DHT node {}: {}", node, e),
+    }
+  }
+  addrs
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv4Addr;
+
+  use super::*;
+
+  fn spawn_node(bootstrap_nodes: Vec<String>) -> (JoinHandle, DhtHandle) {
+    // the nodes are bound to the loopback interface as their source address
+    spawn(
+      &DhtConf {
+        listen_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        bootstrap_nodes,
+      },
+      Some(Ipv4Addr::LOCALHOST.into()),
+    )
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn should_skip_pending_transaction_ids() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    let mut dht = Dht::new(socket, cmd_rx, Vec::new());
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1);
+    let ping = || Query::Ping { id: [0; 20] };
+
+    dht.send_query(addr, None, ping(), None);
+    dht.next_transaction_id = u16::MAX;
+    dht.send_query(addr, None, ping(), None);
+    // the ids wrapped around to that of the first query, still pending
+    dht.send_query(addr, None, ping(), None);
+    let mut ids: Vec<_> = dht.transactions.keys().copied().collect();
+    ids.sort_unstable();
+    assert_eq!(ids, [0, 1, u16::MAX]);
+    assert_eq!(dht.next_transaction_id, 2);
+  }
+
+  #[tokio::test]
+  async fn should_find_announced_peers() {
+    let (_, bootstrap) = spawn_node(Vec::new());
+    let bootstrap_node = format!("127.0.0.1:{}", bootstrap.port());
+    let (_, seed) = spawn_node(vec![bootstrap_node.clone()]);
+    let (_, leech) = spawn_node(vec![bootstrap_node]);
+    let info_hash = [7; 20];
+
+    // the nodes join in the background, so announcing is retried until the
+    // announced peer can be found
+    let peers = time::timeout(Duration::from_secs(10), async {
+      loop {
+        seed.get_peers(info_hash, Some(6881)).await.unwrap();
+        let peers = leech.get_peers(info_hash, None).await.unwrap();
+        if !peers.is_empty() {
+          return peers;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+      }
+    })
+    .await
+    .unwrap();
+    assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], 6881))]);
+
+    // a node that doesn't know of the torrent has no peers to return
+    let peers = leech.get_peers([8; 20], None).await.unwrap();
+    assert!(peers.is_empty());
+
+    for node in [bootstrap, seed, leech] {
+      node.shutdown();
+    }
+  }
+}
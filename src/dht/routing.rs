@@ -0,0 +1,203 @@
+//! The routing table of the DHT, which holds the nodes we know of, more of
+//! them the closer they are to our own id.
+
+use std::net::SocketAddrV4;
+
+/// The id of a DHT node, in the same 160 bit space as info hashes.
+pub type NodeId = [u8; 20];
+
+/// The number of nodes in each bucket of the routing table, which is also
+/// the number of closest nodes that lookups find.
+pub const K: usize = 8;
+
+/// The number of queries in a row a node may fail to respond to before it's
+/// considered bad, in which case it may be replaced by a new node.
+const MAX_FAILED_QUERY_COUNT: usize = 2;
+
+/// Returns the distance between two ids, which is their XOR, compared as a
+/// big endian number.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+  let mut distance = [0; 20];
+  for (d, (a, b)) in distance.iter_mut().zip(a.iter().zip(b)) {
+    *d = a ^ b;
+  }
+  distance
+}
+
+/// A node in the routing table.
+#[derive(Clone, Debug)]
+struct Node {
+  id: NodeId,
+  addr: SocketAddrV4,
+  /// The number of queries in a row the node didn't respond to.
+  failed_query_count: usize,
+}
+
+impl Node {
+  fn is_bad(&self) -> bool {
+    self.failed_query_count >= MAX_FAILED_QUERY_COUNT
+  }
+}
+
+/// The routing table, whose buckets are indexed by the number of leading
+/// bits the ids of their nodes share with our id, so that the table holds
+/// at most [`K`] nodes of each distance range.
+pub struct RoutingTable {
+  id: NodeId,
+  buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+  pub fn new(id: NodeId) -> Self {
+    Self {
+      id,
+      buckets: vec![Vec::new(); 160],
+    }
+  }
+
+  /// Returns our own node id.
+  pub fn id(&self) -> &NodeId {
+    &self.id
+  }
+
+  /// Returns the number of nodes in the table, including bad ones.
+  pub fn len(&self) -> usize {
+    self.buckets.iter().map(Vec::len).sum()
+  }
+
+  /// Adds a node that responded to us, or refreshes it if it's already in
+  /// the table, returning whether it's in the table.
+  ///
+  /// If the node's bucket is full, it replaces a bad node of the bucket, or
+  /// otherwise it's not added, as the nodes that have been around longer
+  /// are more likely to stay.
+  pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) -> bool {
+    let Some(index) = self.bucket_index(&id) else {
+      return false;
+    };
+    let bucket = &mut self.buckets[index];
+    let node = Node {
+      id,
+      addr,
+      failed_query_count: 0,
+    };
+    if let Some(existing) = bucket.iter_mut().find(|n| n.id == id) {
+      *existing = node;
+    } else if bucket.len() < K {
+      bucket.push(node);
+    } else if let Some(bad) = bucket.iter_mut().find(|n| n.is_bad()) {
+      *bad = node;
+    } else {
+      return false;
+    }
+    true
+  }
+
+  /// Returns whether the node is in the table.
+  pub fn contains(&self, id: &NodeId) -> bool {
+    self
+      .bucket_index(id)
+      .is_some_and(|index| self.buckets[index].iter().any(|n| n.id == *id))
+  }
+
+  /// Records that the node didn't respond to a query.
+  pub fn mark_failed(&mut self, id: &NodeId) {
+    let Some(index) = self.bucket_index(id) else {
+      return;
+    };
+    if let Some(node) = self.buckets[index].iter_mut().find(|n| n.id == *id) {
+      node.failed_query_count += 1;
+    }
+  }
+
+  /// Returns the given number of nodes closest to the target, closest
+  /// first, skipping bad nodes.
+  pub fn closest(
+    &self,
+    target: &NodeId,
+    count: usize,
+  ) -> Vec<(NodeId, SocketAddrV4)> {
+    let mut nodes: Vec<_> = self
+      .buckets
+      .iter()
+      .flatten()
+      .filter(|node| !node.is_bad())
+      .collect();
+    nodes.sort_unstable_by_key(|node| distance(&node.id, target));
+    nodes
+      .into_iter()
+      .take(count)
+      .map(|node| (node.id, node.addr))
+      .collect()
+  }
+
+  /// Returns the index of the node's bucket, or `None` if it has our id.
+  fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+    let distance = distance(&self.id, id);
+    let leading_zeros = distance
+      .iter()
+      .position(|&b| b != 0)
+      .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+    Some(leading_zeros)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn node_id(first_byte: u8) -> NodeId {
+    let mut id = [0; 20];
+    id[0] = first_byte;
+    id
+  }
+
+  fn addr(port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new([127, 0, 0, 1].into(), port)
+  }
+
+  #[test]
+  fn test_routing_table() {
+    let mut table = RoutingTable::new([0; 20]);
+    // our own id is never added
+    assert!(!table.insert([0; 20], addr(1)));
+    assert_eq!(table.len(), 0);
+
+    // the ids starting with a set bit all go into the first bucket, which
+    // only fits K of them
+    for i in 0..K as u8 {
+      assert!(table.insert(node_id(0x80 + i), addr(i as u16)));
+    }
+    assert!(!table.insert(node_id(0xff), addr(100)));
+    assert!(table.insert(node_id(0x01), addr(101)));
+    assert!(table.insert(node_id(0x02), addr(102)));
+    assert_eq!(table.len(), K + 2);
+    assert!(table.contains(&node_id(0x02)));
+    assert!(!table.contains(&node_id(0xff)));
+
+    let closest = table.closest(&node_id(0x03), 3);
+    assert_eq!(
+      closest,
+      vec![
+        (node_id(0x02), addr(102)),
+        (node_id(0x01), addr(101)),
+        (node_id(0x83), addr(3)),
+      ]
+    );
+
+    // a bad node is skipped and replaced once its bucket is full
+    for _ in 0..MAX_FAILED_QUERY_COUNT {
+      table.mark_failed(&node_id(0x80));
+    }
+    assert_eq!(
+      table.closest(&node_id(0x80), 1),
+      vec![(node_id(0x81), addr(1))]
+    );
+    assert!(table.insert(node_id(0xff), addr(100)));
+    assert_eq!(table.len(), K + 2);
+    assert_eq!(
+      table.closest(&node_id(0xff), 1),
+      vec![(node_id(0xff), addr(100))]
+    );
+  }
+}
@@ -26,6 +26,7 @@ impl TorrentFile {
     let path = download_dir.join(&info.path);
     let handle = OpenOptions::new()
       .create(true)
+      .truncate(false)
       .write(true)
       .read(true)
       .open(&path)
@@ -37,4 +38,14 @@ impl TorrentFile {
     debug_assert!(path.exists());
     Ok(Self { info, handle })
   }
+
+  /// Opens the file again in the given download directory, after it was
+  /// moved there.
+  pub fn reopen(&mut self, download_dir: &Path) -> std::io::Result<()> {
+    self.handle = OpenOptions::new()
+      .write(true)
+      .read(true)
+      .open(download_dir.join(&self.info.path))?;
+    Ok(())
+  }
 }
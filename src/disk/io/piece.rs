@@ -39,14 +39,17 @@ pub struct Piece {
 }
 
 impl Piece {
-  /// Places block into piece's writer buffer if it doesn't exist.
-  pub fn enqueue_block(&mut self, offset: u32, data: Vec<u8>) {
+  /// Places block into piece's writer buffer if it doesn't exist, returning
+  /// whether it was placed.
+  pub fn enqueue_block(&mut self, offset: u32, data: Vec<u8>) -> bool {
     use std::collections::btree_map::Entry;
     let entry = self.blocks.entry(offset);
     if matches!(entry, Entry::Occupied(_)) {
       log::warn!("Duplicate piece block at offset {}", offset);
+      false
     } else {
       entry.or_insert(data);
+      true
     }
   }
 
@@ -146,13 +149,13 @@ impl Piece {
 /// # Arguments
 ///
 /// * `torrent_piece_offset` - The absolute offset of the piece's first byte
-///     in the whole torrent. From this value the relative offset of piece
-///     within file is calculated.
+///   in the whole torrent. From this value the relative offset of piece
+///   within file is calculated.
 /// * `file_range` - The files that contain data of the piece.
 /// * `files` - A slice of all files in torrent.
 /// * `len` - The length of the piece to read in. While this function is
-///     currently used to read the whole piece, it could also be used to
-///     read only a portion of the piece or serval pieces with this argument.
+///   currently used to read the whole piece, it could also be used to
+///   read only a portion of the piece or serval pieces with this argument.
 pub fn read(
   torrent_piece_offset: u64,
   file_range: Range<FileIndex>,
@@ -164,8 +167,7 @@ pub fn read(
   let mut blocks = Vec::with_capacity(block_count);
   for i in 0..block_count {
     let block_len = block_len(len, i);
-    let mut buf = Vec::new();
-    buf.resize(block_len as usize, 0u8);
+    let buf = vec![0u8; block_len as usize];
     blocks.push(Arc::new(buf));
   }
 
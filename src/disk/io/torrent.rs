@@ -1,7 +1,8 @@
 use std::{
-  collections::{BTreeMap, HashMap},
+  collections::{BTreeMap, BTreeSet, HashMap},
   fs,
   num::NonZeroUsize,
+  path::{Path, PathBuf},
   sync::{
     self,
     atomic::{AtomicU64, AtomicUsize, Ordering},
@@ -10,16 +11,18 @@ use std::{
 };
 
 use lru::LruCache;
+use sha1::{Digest, Sha1};
 use tokio::task;
 
 use crate::{
-  blockinfo::{BlockInfo, CachedBlock},
+  blockinfo::{BlockData, BlockInfo, CachedBlock},
   disk::io::piece,
   error::*,
+  memory::{Buffer, MemoryCharge, MemoryCounters},
   peer::{Command, Sender},
-  storage_info::StorageInfo,
+  storage_info::{FileInfo, StorageInfo},
   torrent::{self, PieceCompletion},
-  Block, PieceIndex,
+  Bitfield, Block, PieceIndex, BLOCK_LEN,
 };
 
 use super::{file::TorrentFile, piece::Piece};
@@ -49,6 +52,33 @@ pub struct Torrent {
 
   /// The concatenation of all expected piece hashes.
   piece_hashes: Vec<u8>,
+
+  /// Whether completed pieces are held back in memory rather than written to
+  /// disk, which is the case while the disk is low on free space.
+  is_write_paused: bool,
+
+  /// The completed pieces that were not written to disk due to writes being
+  /// paused. They are flushed once writes are resumed.
+  paused_pieces: Vec<(PieceIndex, Piece)>,
+
+  /// The bytes of the paused pieces past which the torrent is told to stop
+  /// downloading, so that they don't grow without bound.
+  max_paused_len: u64,
+
+  /// Whether the torrent was told to stop downloading because the paused
+  /// pieces reached their limit.
+  is_write_stalled: bool,
+
+  /// Whether the torrent's files are being moved to another directory, in
+  /// which case completed pieces are held back as when writes are paused.
+  is_moving: bool,
+
+  /// The piece writes that may still be running on the blocking threads.
+  writes: Vec<task::JoinHandle<()>>,
+
+  /// The bytes of the write buffer and the paused pieces. A piece's bytes
+  /// are split off into its write job when it's flushed.
+  write_buf_charge: MemoryCharge,
 }
 
 /// Contains fields that are commonly accessed by torrent's IO threads.
@@ -89,7 +119,7 @@ struct ThreadContext {
   ///
   /// Both of these are very short lived and shouldn't bog down the reactor by
   /// too much.
-  read_cache: sync::Mutex<LruCache<PieceIndex, Vec<CachedBlock>>>,
+  read_cache: sync::Mutex<LruCache<PieceIndex, CachedPiece>>,
 
   /// Handles of all files in torrent, opened in advance during torrent
   /// creation.
@@ -107,6 +137,50 @@ struct ThreadContext {
   ///
   /// Stats are atomically updated by the IO worker threads themselves.
   stats: Stats,
+
+  /// The number of IO jobs of all torrents that are queued or running on
+  /// the blocking threads, shared by the disk task with the engine.
+  queue_len: Arc<AtomicUsize>,
+
+  /// The engine-wide memory counters, to which the read cache is charged.
+  memory: Arc<MemoryCounters>,
+}
+
+impl ThreadContext {
+  /// Places the piece's blocks in the read cache, charging them to it for as
+  /// long as they're cached.
+  fn cache_piece(&self, piece_index: PieceIndex, blocks: Vec<CachedBlock>) {
+    let len = blocks.iter().map(|block| block.len() as u64).sum();
+    let piece = CachedPiece {
+      blocks,
+      _charge: MemoryCharge::with_len(&self.memory, Buffer::ReadCache, len),
+    };
+    self.read_cache.lock().unwrap().put(piece_index, piece);
+  }
+}
+
+/// A piece in the read cache.
+struct CachedPiece {
+  blocks: Vec<CachedBlock>,
+  /// The piece's bytes, uncharged from the cache once the piece is evicted.
+  _charge: MemoryCharge,
+}
+
+/// Counts an IO job in the disk queue for as long as it's alive, so that
+/// the job is uncounted however it ends.
+struct QueuedJob(Arc<AtomicUsize>);
+
+impl QueuedJob {
+  fn new(queue_len: &Arc<AtomicUsize>) -> Self {
+    queue_len.fetch_add(1, Ordering::Relaxed);
+    Self(Arc::clone(queue_len))
+  }
+}
+
+impl Drop for QueuedJob {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
 }
 
 #[derive(Default)]
@@ -131,6 +205,8 @@ impl Torrent {
     info: StorageInfo,
     piece_hashes: Vec<u8>,
     torrent_tx: torrent::Sender,
+    queue_len: Arc<AtomicUsize>,
+    memory: Arc<MemoryCounters>,
   ) -> Result<Self, NewTorrentError> {
     // TODO: Should tokio_fs?
     if !info.download_dir.is_dir() {
@@ -201,11 +277,160 @@ impl Torrent {
         )),
         files,
         stats: Stats::default(),
+        queue_len,
+        memory: Arc::clone(&memory),
       }),
       piece_hashes,
+      is_write_paused: false,
+      paused_pieces: Vec::new(),
+      max_paused_len: u64::MAX,
+      is_write_stalled: false,
+      is_moving: false,
+      writes: Vec::new(),
+      write_buf_charge: MemoryCharge::new(&memory, Buffer::WriteBuf),
     })
   }
 
+  /// Returns the directory in which the torrent's files are placed.
+  pub fn download_dir(&self) -> &Path {
+    &self.info.download_dir
+  }
+
+  /// Returns the number of bytes the torrent's files still need to grow by
+  /// to reach their full length.
+  ///
+  /// This is an estimate of how much disk space the rest of the download
+  /// requires, assuming that files are not sparse.
+  pub fn remaining_alloc_len(&self) -> u64 {
+    self
+      .thread_ctx
+      .files
+      .iter()
+      .map(|file| {
+        let file = file.read().unwrap();
+        let len = file.handle.metadata().map(|m| m.len()).unwrap_or(0);
+        file.info.len.saturating_sub(len)
+      })
+      .sum()
+  }
+
+  /// Returns whether writes are paused due to low disk space.
+  pub fn is_write_paused(&self) -> bool {
+    self.is_write_paused
+  }
+
+  /// Stops writing completed pieces to disk, holding them in memory instead,
+  /// until they reach the given number of bytes, after which the torrent is
+  /// told to stop downloading.
+  pub fn pause_writes(&mut self, max_paused_len: u64) {
+    self.is_write_paused = true;
+    self.max_paused_len = max_paused_len;
+  }
+
+  /// Resumes writing to disk, flushing the pieces completed while writes
+  /// were paused, unless the files are being moved.
+  pub fn resume_writes(&mut self) {
+    self.is_write_paused = false;
+    self.flush_paused_pieces();
+  }
+
+  /// Returns whether completed pieces may be written to disk.
+  fn can_write(&self) -> bool {
+    !self.is_write_paused && !self.is_moving
+  }
+
+  /// Flushes the pieces that were held back, if writing is possible again.
+  fn flush_paused_pieces(&mut self) {
+    if !self.can_write() {
+      return;
+    }
+    for (piece_index, piece) in std::mem::take(&mut self.paused_pieces) {
+      self.flush_piece(piece_index, piece);
+    }
+    if self.is_write_stalled {
+      log::info!("Held back pieces flushed, torrent may download again");
+      self.is_write_stalled = false;
+      self
+        .thread_ctx
+        .tx
+        .send(torrent::Command::WriteStalled(false))
+        .ok();
+    }
+  }
+
+  /// Starts moving the torrent's files into the new directory, returning the
+  /// job that moves them, which is to be run on a blocking thread.
+  ///
+  /// An archive's own directory is moved into the new directory, while a
+  /// single file is placed directly in it. Writes are held back until
+  /// the move is finished with [`Self::finish_move`].
+  ///
+  /// If a move is already in progress, or the files are already in the new
+  /// directory, the torrent is notified right away and `None` is returned.
+  pub fn start_move(
+    &mut self,
+    new_dir: PathBuf,
+  ) -> Option<impl FnOnce() -> std::io::Result<PathBuf> + Send + 'static> {
+    if self.is_moving {
+      log::warn!("Torrent storage is already being moved");
+      self
+        .thread_ctx
+        .tx
+        .send(torrent::Command::StorageMoved(Err(
+          MoveError::AlreadyMoving,
+        )))
+        .ok();
+      return None;
+    }
+
+    let old_dir = self.info.download_dir.clone();
+    let new_dir = match old_dir.file_name() {
+      Some(name) if self.info.files.len() > 1 => new_dir.join(name),
+      _ => new_dir,
+    };
+    if new_dir == old_dir {
+      log::info!("Torrent storage is already in {:?}", new_dir);
+      self
+        .thread_ctx
+        .tx
+        .send(torrent::Command::StorageMoved(Ok(new_dir)))
+        .ok();
+      return None;
+    }
+
+    log::info!("Moving torrent storage from {:?} to {:?}", old_dir, new_dir);
+    self.is_moving = true;
+    let ctx = Arc::clone(&self.thread_ctx);
+    Some(move || {
+      move_files(&ctx.files, &old_dir, &new_dir)?;
+      Ok(new_dir)
+    })
+  }
+
+  /// Finishes the move of the torrent's files with the result of the job
+  /// returned by [`Self::start_move`], notifying the torrent of it, and
+  /// resumes writes, unless they are paused due to low disk space.
+  pub fn finish_move(&mut self, result: std::io::Result<PathBuf>) {
+    self.is_moving = false;
+    let result = match result {
+      Ok(new_dir) => {
+        log::info!("Moved torrent storage to {:?}", new_dir);
+        self.info.download_dir = new_dir.clone();
+        Ok(new_dir)
+      }
+      Err(e) => {
+        log::error!("Failed to move torrent storage: {}", e);
+        Err(MoveError::Io(e))
+      }
+    };
+    self
+      .thread_ctx
+      .tx
+      .send(torrent::Command::StorageMoved(result))
+      .ok();
+    self.flush_paused_pieces();
+  }
+
   pub fn write_block(
     &mut self,
     info: BlockInfo,
@@ -213,6 +438,17 @@ impl Torrent {
   ) -> EngineResult<()> {
     log::trace!("Saving block {} to disk", info);
 
+    // peer sessions validate blocks, so this is not expected to happen, but
+    // an invalid block must not corrupt the piece's write buffer
+    if let Err(e) = self.validate_block_info(&info) {
+      log::warn!("Dropping invalid block {}: {}", info, e);
+      return Ok(());
+    }
+    if data.len() != info.len as usize {
+      log::warn!("Dropping block {} with {} bytes", info, data.len());
+      return Ok(());
+    }
+
     let piece_index = info.piece_index;
 
     if !self.write_buf.contains_key(&piece_index) {
@@ -223,7 +459,10 @@ impl Torrent {
       .get_mut(&piece_index)
       .expect("Newly inserted piece not present");
 
-    piece.enqueue_block(info.offset, data);
+    let len = data.len() as u64;
+    if piece.enqueue_block(info.offset, data) {
+      self.write_buf_charge.add(len);
+    }
 
     // if the piece has all its blocks,
     // it means we can hash it and save it to disk
@@ -233,69 +472,146 @@ impl Torrent {
       // succeeded (otherwise we need to retry later).
       let piece = self.write_buf.remove(&piece_index).unwrap();
 
-      log::debug!(
-        "Piece {} is complete ({} bytes), flushing {} block(s) to disk",
-        info.piece_index,
-        piece.len,
-        piece.blocks.len()
-      );
+      if !self.can_write() {
+        log::debug!(
+          "Piece {} is complete but writes are paused, holding it in memory",
+          piece_index
+        );
+        self.paused_pieces.push((piece_index, piece));
+        self.check_paused_len();
+      } else {
+        self.flush_piece(piece_index, piece);
+      }
+    }
 
-      // don't block the reactor with the potentially expensive hashing
-      // and sync file writing.
-      let torrent_piece_offset = self.info.torrent_piece_offset(piece_index);
-      let ctx = Arc::clone(&self.thread_ctx);
+    Ok(())
+  }
 
-      // create a new thread-green thread for writing the block.
-      task::spawn_blocking(move || {
-        let is_piece_valid = piece.match_hash();
+  /// Tells the torrent to stop downloading if the held back pieces reached
+  /// their limit, since the blocks downloaded from then on would only be
+  /// held back too.
+  fn check_paused_len(&mut self) {
+    let paused_len: u64 = self
+      .paused_pieces
+      .iter()
+      .map(|(_, piece)| piece.len as u64)
+      .sum();
+    if self.is_write_stalled || paused_len < self.max_paused_len {
+      return;
+    }
+    log::warn!(
+      "{} bytes of pieces held back while writes are paused, stalling torrent",
+      paused_len
+    );
+    self.is_write_stalled = true;
+    self
+      .thread_ctx
+      .tx
+      .send(torrent::Command::WriteStalled(true))
+      .ok();
+  }
 
-        // save piece to disk if it's valid.
-        if is_piece_valid {
-          log::debug!("Piece {} is valid, writing to disk", piece_index);
+  /// Returns the piece writes that may still be running, which are done once
+  /// the handles resolve.
+  pub fn take_writes(&mut self) -> Vec<task::JoinHandle<()>> {
+    std::mem::take(&mut self.writes)
+  }
 
-          if let Err(e) = piece.write(torrent_piece_offset, &ctx.files) {
-            log::error!("Error writing piece {} to disk: {}", piece_index, e);
-            ctx
-              .stats
-              .write_failure_count
-              .fetch_add(1, Ordering::Relaxed);
+  /// Hashes the completed piece and, if it's valid, writes it to disk, after
+  /// which the torrent is notified of the result.
+  fn flush_piece(&mut self, piece_index: PieceIndex, piece: Piece) {
+    log::debug!(
+      "Piece {} is complete ({} bytes), flushing {} block(s) to disk",
+      piece_index,
+      piece.len,
+      piece.blocks.len()
+    );
 
-            // alert torrent of block write failure.
-            ctx
-              .tx
-              .send(torrent::Command::PieceCompletion(Err(e)))
-              .map_err(|e| {
-                log::error!("Error sending piece result: {}", e);
-                e
-              })
-              .ok();
-            return;
-          }
-          log::debug!("Wrote piece {} to disk", piece_index);
+    // don't block the reactor with the potentially expensive hashing
+    // and sync file writing.
+    let torrent_piece_offset = self.info.torrent_piece_offset(piece_index);
+    let ctx = Arc::clone(&self.thread_ctx);
+    let job = QueuedJob::new(&ctx.queue_len);
+    // the piece is held in memory until its write is done
+    let charge = self.write_buf_charge.split(piece.len as u64);
+
+    // only the writes that may still be running are kept
+    self.writes.retain(|write| !write.is_finished());
+
+    // create a new thread-green thread for writing the block.
+    let write = task::spawn_blocking(move || {
+      let _job = job;
+      let _charge = charge;
+      let is_piece_valid = piece.match_hash();
+
+      // save piece to disk if it's valid.
+      if is_piece_valid {
+        log::debug!("Piece {} is valid, writing to disk", piece_index);
+
+        if let Err(e) = piece.write(torrent_piece_offset, &ctx.files) {
+          log::error!("Error writing piece {} to disk: {}", piece_index, e);
           ctx
             .stats
-            .write_count
-            .fetch_add(piece.len as u64, Ordering::Relaxed);
-        } else {
-          log::warn!("Piece {} is not valid", info.piece_index);
-        }
+            .write_failure_count
+            .fetch_add(1, Ordering::Relaxed);
 
-        // alert torrent of piece completion and hash result
+          // alert torrent of block write failure.
+          ctx
+            .tx
+            .send(torrent::Command::PieceCompletion(Err(e)))
+            .map_err(|e| {
+              log::error!("Error sending piece result: {}", e);
+              e
+            })
+            .ok();
+          return;
+        }
+        log::debug!("Wrote piece {} to disk", piece_index);
         ctx
-          .tx
-          .send(torrent::Command::PieceCompletion(Ok(PieceCompletion {
-            index: piece_index,
-            is_valid: is_piece_valid,
-          })))
-          .map_err(|e| {
-            log::error!("Error sending piece result: {}", e);
-            e
-          })
-          .ok();
-      });
+          .stats
+          .write_count
+          .fetch_add(piece.len as u64, Ordering::Relaxed);
+      } else {
+        log::warn!("Piece {} is not valid", piece_index);
+      }
+
+      // alert torrent of piece completion and hash result
+      ctx
+        .tx
+        .send(torrent::Command::PieceCompletion(Ok(PieceCompletion {
+          index: piece_index,
+          is_valid: is_piece_valid,
+        })))
+        .map_err(|e| {
+          log::error!("Error sending piece result: {}", e);
+          e
+        })
+        .ok();
+    });
+    self.writes.push(write);
+  }
+
+  /// Checks that the block is one of the torrent's blocks. A block of a piece
+  /// not in the torrent is out of bounds.
+  fn validate_block_info(
+    &self,
+    info: &BlockInfo,
+  ) -> Result<(), BlockInfoError> {
+    if info.piece_index >= self.info.piece_count {
+      return Err(BlockInfoError::OutOfBounds);
     }
+    let piece_len = self.info.piece_len(info.piece_index);
+    BlockInfo::try_new(info.piece_index, info.offset, info.len, piece_len)
+      .map(|_| ())
+  }
 
-    Ok(())
+  /// Checks that the peer's request is for whole blocks of one of the
+  /// torrent's pieces.
+  fn validate_request(&self, info: &BlockInfo) -> Result<(), BlockInfoError> {
+    if info.piece_index >= self.info.piece_count {
+      return Err(BlockInfoError::OutOfBounds);
+    }
+    info.validate_request(self.info.piece_len(info.piece_index))
   }
 
   /// Starts a new in-progress piece, creating metadata for it in self.
@@ -356,9 +672,9 @@ impl Torrent {
   /// how the CPU pulls in the next 64 bytes of the program into its L1 cache
   /// when hitting a cache miss.
   ///
-  /// For now, this is simplified in that we don't pull in blocks from the
-  /// next piece. Later, we will make the read cache line size configurable
-  /// and it will be applied across piece boundaries.
+  /// Blocks from the next piece are not pulled in here, but peers that
+  /// request pieces sequentially have the next piece read in advance via
+  /// [`Self::read_ahead`].
   pub fn read_block(
     &self,
     block_info: BlockInfo,
@@ -366,16 +682,25 @@ impl Torrent {
   ) -> DiskResult<()> {
     log::trace!("Reading {} from disk", block_info);
 
+    if let Err(error) = self.validate_request(&block_info) {
+      log::warn!("Cannot read invalid block {}: {}", block_info, error);
+      self.thread_ctx.tx.send(torrent::Command::ReadError {
+        block_info,
+        error: ReadError::InvalidBlockInfo(error),
+      })?;
+      return Ok(());
+    }
+
     let piece_index = block_info.piece_index;
-    let block_index = block_info.index_in_piece();
+    let block_range = block_info.block_range();
 
     // check if piece is in the read cache
-    if let Some(blocks) =
+    if let Some(CachedPiece { blocks, .. }) =
       self.thread_ctx.read_cache.lock().unwrap().get(&piece_index)
     {
       log::debug!("Piece {} is in the read cache", piece_index);
       // the block's index in piece may be invalid
-      if block_index >= blocks.len() {
+      if block_range.end > blocks.len() {
         log::debug!(
           "Piece {} block offset {} is invalid",
           piece_index,
@@ -390,7 +715,7 @@ impl Torrent {
       }
 
       // return block via sender
-      let block = Arc::clone(&blocks[block_index]);
+      let block = request_data(blocks, &block_info);
       result_tx.send(Command::Block(Block::new(block_info, block)))?;
 
       return Ok(());
@@ -409,7 +734,9 @@ impl Torrent {
 
       let piece_len = self.info.piece_len(piece_index);
       let ctx = Arc::clone(&self.thread_ctx);
+      let job = QueuedJob::new(&ctx.queue_len);
       task::spawn_blocking(move || {
+        let _job = job;
         match piece::read(
           torrent_piece_offset,
           file_range,
@@ -419,13 +746,13 @@ impl Torrent {
           Ok(blocks) => {
             log::debug!("Read piece {}", piece_index);
             // pick requested block
-            let block = Arc::clone(&blocks[block_index]);
+            let block = request_data(&blocks, &block_info);
 
             // Place piece in read cache. Another concurrent read
             // could already have read the piece just before this
             // thread, but replacing it shouldn't be an issue since
             // we're reading the same data.
-            ctx.read_cache.lock().unwrap().put(piece_index, blocks);
+            ctx.cache_piece(piece_index, blocks);
             ctx
               .stats
               .read_count
@@ -460,4 +787,318 @@ impl Torrent {
     }
     Ok(())
   }
+
+  /// Reads the piece into the read cache on a blocking thread, unless it's
+  /// already cached, so that the upcoming requests for its blocks are served
+  /// from memory.
+  ///
+  /// The caller must make sure the piece is complete on disk. As no one
+  /// waits for the result, an invalid piece or a failed read is only logged.
+  pub fn read_ahead(&self, piece_index: PieceIndex) {
+    if piece_index >= self.info.piece_count {
+      log::warn!("Cannot read ahead invalid piece {}", piece_index);
+      return;
+    }
+    // unlike `get`, this doesn't count as a use of the cached piece
+    if self
+      .thread_ctx
+      .read_cache
+      .lock()
+      .unwrap()
+      .contains(&piece_index)
+    {
+      log::trace!("Piece {} to read ahead is already cached", piece_index);
+      return;
+    }
+
+    let file_range = self.info.files_intersecting_piece(piece_index);
+    let torrent_piece_offset = self.info.torrent_piece_offset(piece_index);
+    let piece_len = self.info.piece_len(piece_index);
+    let ctx = Arc::clone(&self.thread_ctx);
+    let job = QueuedJob::new(&ctx.queue_len);
+    task::spawn_blocking(move || {
+      let _job = job;
+      match piece::read(
+        torrent_piece_offset,
+        file_range,
+        &ctx.files[..],
+        piece_len,
+      ) {
+        Ok(blocks) => {
+          log::debug!("Read ahead piece {}", piece_index);
+          ctx.cache_piece(piece_index, blocks);
+          ctx
+            .stats
+            .read_count
+            .fetch_add(piece_len as u64, Ordering::Relaxed);
+        }
+        Err(e) => {
+          log::warn!("Error reading ahead piece {}: {}", piece_index, e);
+          ctx.stats.read_failure_count.fetch_add(1, Ordering::Relaxed);
+        }
+      }
+    });
+  }
+
+  /// Verifies the torrent's existing data and sends the torrent the bitfield
+  /// of the pieces that are present and match their expected hashes.
+  ///
+  /// Hashing happens on a blocking thread. If none of the files contain any
+  /// data yet (i.e. this is a new download), it's skipped altogether.
+  pub fn check_pieces(&self) {
+    let info = self.info.clone();
+    let piece_hashes = self.piece_hashes.clone();
+    let ctx = Arc::clone(&self.thread_ctx);
+    let job = QueuedJob::new(&ctx.queue_len);
+
+    task::spawn_blocking(move || {
+      let _job = job;
+      let mut own_pieces = Bitfield::repeat(false, info.piece_count);
+
+      let has_data = ctx.files.iter().any(|file| {
+        let file = file.read().unwrap();
+        file.handle.metadata().map(|m| m.len() > 0).unwrap_or(false)
+      });
+
+      if has_data {
+        log::info!("Checking {} pieces on disk", info.piece_count);
+        for index in 0..info.piece_count {
+          let is_valid = is_piece_valid(&info, &ctx, &piece_hashes, index);
+          own_pieces.set(index, is_valid);
+        }
+      }
+      log::info!(
+        "Found {}/{} valid pieces on disk",
+        own_pieces.count_ones(),
+        info.piece_count
+      );
+
+      ctx
+        .tx
+        .send(torrent::Command::PiecesChecked(own_pieces))
+        .map_err(|e| {
+          log::error!("Error sending check result: {}", e);
+          e
+        })
+        .ok();
+    });
+  }
+
+  /// Verifies only the given pieces of the torrent's data, trusting the rest
+  /// of the bitfield, and sends the torrent the bitfield with the pieces
+  /// that are missing or corrupt cleared.
+  ///
+  /// Hashing happens on a blocking thread.
+  pub fn verify_pieces(
+    &self,
+    mut own_pieces: Bitfield,
+    pieces: Vec<PieceIndex>,
+  ) {
+    let info = self.info.clone();
+    let piece_hashes = self.piece_hashes.clone();
+    let ctx = Arc::clone(&self.thread_ctx);
+    let job = QueuedJob::new(&ctx.queue_len);
+
+    task::spawn_blocking(move || {
+      let _job = job;
+      log::info!("Verifying {} pieces on disk", pieces.len());
+      let mut invalid_count = 0;
+      for index in pieces {
+        if index < info.piece_count
+          && own_pieces[index]
+          && !is_piece_valid(&info, &ctx, &piece_hashes, index)
+        {
+          log::warn!("Piece {} is no longer valid on disk", index);
+          own_pieces.set(index, false);
+          invalid_count += 1;
+        }
+      }
+      log::info!("Found {} invalid pieces on disk", invalid_count);
+
+      ctx
+        .tx
+        .send(torrent::Command::PiecesChecked(own_pieces))
+        .map_err(|e| {
+          log::error!("Error sending verification result: {}", e);
+          e
+        })
+        .ok();
+    });
+  }
+
+  /// Closes and deletes all files of the torrent from disk.
+  ///
+  /// Files that no longer exist are skipped. Subdirectories created for the
+  /// torrent's files, and the torrent's own directory in case of an archive,
+  /// are removed as well if they're left empty.
+  ///
+  /// This performs blocking IO and so must not be called from an async
+  /// context.
+  pub fn delete_files(self) -> std::io::Result<()> {
+    let Torrent {
+      info, thread_ctx, ..
+    } = self;
+    // close the file handles before deleting the files
+    drop(thread_ctx);
+
+    for file in info.files.iter() {
+      let path = info.download_dir.join(&file.path);
+      log::debug!("Deleting torrent file {:?}", path);
+      match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+          log::warn!("Torrent file {:?} not found", path);
+        }
+        Err(e) => return Err(e),
+      }
+    }
+    remove_empty_dirs(&info.download_dir, &info.files);
+
+    Ok(())
+  }
+}
+
+/// Returns the data of the request from the cached blocks of its piece,
+/// which is only copied if the request isn't exactly one of the blocks, i.e.
+/// if it's longer than a block or doesn't follow the block grid.
+fn request_data(blocks: &[CachedBlock], info: &BlockInfo) -> BlockData {
+  let range = info.block_range();
+  let mut start = (info.offset % BLOCK_LEN) as usize;
+  let len = info.len as usize;
+  if range.len() == 1 && start == 0 && blocks[range.start].len() == len {
+    return BlockData::Cached(Arc::clone(&blocks[range.start]));
+  }
+
+  let mut data = Vec::with_capacity(len);
+  for block in &blocks[range] {
+    let end = block.len().min(start + len - data.len());
+    data.extend_from_slice(&block[start..end]);
+    start = 0;
+  }
+  BlockData::Owned(data)
+}
+
+/// Reads the piece from disk and returns whether it matches its expected
+/// hash. A piece that hasn't been downloaded or can't be read is invalid.
+fn is_piece_valid(
+  info: &StorageInfo,
+  ctx: &ThreadContext,
+  piece_hashes: &[u8],
+  index: PieceIndex,
+) -> bool {
+  let blocks = match piece::read(
+    info.torrent_piece_offset(index),
+    info.files_intersecting_piece(index),
+    &ctx.files,
+    info.piece_len(index),
+  ) {
+    Ok(blocks) => blocks,
+    // the piece hasn't been downloaded
+    Err(ReadError::MissingData) => return false,
+    Err(e) => {
+      log::warn!("Error reading piece {} for checking: {}", index, e);
+      return false;
+    }
+  };
+
+  let mut hasher = Sha1::new();
+  for block in blocks.iter() {
+    hasher.update(block.as_slice());
+  }
+  let hash_pos = index * 20;
+  hasher.finalize().as_slice() == &piece_hashes[hash_pos..hash_pos + 20]
+}
+
+/// Removes the directories between the files and the download directory,
+/// including the download directory itself, that are left empty.
+fn remove_empty_dirs(download_dir: &Path, files: &[FileInfo]) {
+  // Only remove directories if this is an archive: a single file torrent
+  // is placed directly in the user's download directory, which we must
+  // not touch.
+  if files.len() <= 1 {
+    return;
+  }
+
+  // collect all directories between the files and the download directory
+  let mut dirs = BTreeSet::new();
+  for file in files {
+    let path = download_dir.join(&file.path);
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+      if !d.starts_with(download_dir) {
+        break;
+      }
+      dirs.insert(d.to_path_buf());
+      dir = d.parent();
+    }
+  }
+
+  // deeper paths are removed first, which are ordered last
+  for dir in dirs.iter().rev() {
+    // this fails if the directory is not empty, which is what we want
+    if fs::remove_dir(dir).is_ok() {
+      log::debug!("Deleted torrent directory {:?}", dir);
+    }
+  }
+}
+
+/// Moves the files from the old download directory to the new one and
+/// reopens them there, after which the old directory is removed if empty.
+///
+/// If a file can't be moved, the files that were already moved are moved
+/// back, so that the torrent stays intact in its old directory.
+fn move_files(
+  files: &[sync::RwLock<TorrentFile>],
+  old_dir: &Path,
+  new_dir: &Path,
+) -> std::io::Result<()> {
+  // holding the locks of all files waits for the writes in progress and
+  // holds back reads until the files are reopened in their new place
+  let mut files: Vec<_> = files.iter().map(|f| f.write().unwrap()).collect();
+
+  for i in 0..files.len() {
+    let path = files[i].info.path.clone();
+    let result = move_file(&old_dir.join(&path), &new_dir.join(&path))
+      .and_then(|_| files[i].reopen(new_dir));
+    if let Err(e) = result {
+      log::warn!("Failed to move torrent file {:?}: {}", path, e);
+      for file in files[..=i].iter_mut() {
+        let path = file.info.path.clone();
+        let result = move_file(&new_dir.join(&path), &old_dir.join(&path))
+          .and_then(|_| file.reopen(old_dir));
+        if let Err(e) = result {
+          log::error!("Failed to move back torrent file {:?}: {}", path, e);
+        }
+      }
+      return Err(e);
+    }
+  }
+
+  let infos: Vec<_> = files.iter().map(|file| file.info.clone()).collect();
+  remove_empty_dirs(old_dir, &infos);
+  Ok(())
+}
+
+/// Moves the file, creating the destination's directories as needed.
+///
+/// If the file can't be renamed as the destination is on a different
+/// filesystem, it's copied and synced to disk before the original is
+/// removed.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+  if !from.exists() {
+    // the file that failed to move may not have been moved
+    return Ok(());
+  }
+  if let Some(parent) = to.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  match fs::rename(from, to) {
+    Err(e) if e.raw_os_error() == Some(nix::errno::Errno::EXDEV as i32) => {
+      log::debug!("Copying {:?} to {:?} across filesystems", from, to);
+      fs::copy(from, to)?;
+      fs::File::open(to)?.sync_all()?;
+      fs::remove_file(from)
+    }
+    result => result,
+  }
 }
@@ -1,15 +1,23 @@
-use std::collections::HashMap;
+use std::{
+  collections::{HashMap, HashSet},
+  path::{Path, PathBuf},
+  sync::{atomic::AtomicUsize, Arc},
+};
 
 use crate::{
-  blockinfo::BlockInfo, engine, error::*, peer, storage_info::StorageInfo,
-  torrent, TorrentId,
+  blockinfo::BlockInfo,
+  channel::{self, Overflow},
+  conf::DiskConf,
+  engine,
+  error::*,
+  memory::{MemoryCharge, MemoryCounters},
+  peer,
+  storage_info::StorageInfo,
+  torrent, Bitfield, PieceIndex, TorrentId,
 };
 use tokio::{
-  sync::{
-    mpsc::{self, UnboundedReceiver, UnboundedSender},
-    RwLock,
-  },
-  task,
+  sync::{oneshot, RwLock},
+  task, time,
 };
 
 use self::io::torrent::Torrent;
@@ -18,9 +26,18 @@ pub mod io;
 
 /// Spawns a disk IO task and returns a tuple with the task join handle
 /// and the disk handle used for sending commands.
-pub fn spawn(engine_tx: engine::Sender) -> EngineResult<(JoinHandle, Sender)> {
+///
+/// The number of IO jobs queued or running is kept up to date in
+/// `queue_len`, and the bytes held by the write buffers and read caches are
+/// charged to `memory`.
+pub fn spawn(
+  engine_tx: engine::Sender,
+  conf: DiskConf,
+  queue_len: Arc<AtomicUsize>,
+  memory: Arc<MemoryCounters>,
+) -> EngineResult<(JoinHandle, Sender)> {
   log::info!("Spawning disk IO task");
-  let (mut disk, dist_tx) = Disk::new(engine_tx)?;
+  let (mut disk, dist_tx) = Disk::new(engine_tx, conf, queue_len, memory)?;
   let join_handle = task::spawn(async move { disk.start().await });
   log::info!("Spawned disk IO task");
 
@@ -30,9 +47,13 @@ pub fn spawn(engine_tx: engine::Sender) -> EngineResult<(JoinHandle, Sender)> {
 pub type JoinHandle = task::JoinHandle<DiskResult<()>>;
 
 /// The channel for sending commands to the disk task.
-pub type Sender = UnboundedSender<Command>;
+pub type Sender = channel::Sender<Command>;
 /// The channel for the disk task uses to listen for commands.
-type Receiver = UnboundedReceiver<Command>;
+type Receiver = channel::Receiver<Command>;
+
+/// The number of block reads and writes that may be queued in the disk
+/// task's channel, past which peer sessions wait for the disk to catch up.
+const CHANNEL_CAPACITY: usize = 4096;
 
 /// The type of commands that the disk can execute.
 #[derive(Debug)]
@@ -44,11 +65,25 @@ pub enum Command {
     piece_hashes: Vec<u8>,
     torrent_tx: torrent::Sender,
   },
+  /// Verify the torrent's existing data on disk and send the torrent the
+  /// bitfield of the pieces that are present and valid.
+  CheckPieces { id: TorrentId },
+  /// Verify only the given pieces of the torrent's data, e.g. the ones that
+  /// may not have been written before an unclean shutdown, and send the
+  /// torrent its own pieces without the ones that turned out invalid.
+  VerifyPieces {
+    id: TorrentId,
+    own_pieces: Bitfield,
+    pieces: Vec<PieceIndex>,
+  },
   /// Request to eventually write a block to disk.
   WriteBlock {
     id: TorrentId,
     block_info: BlockInfo,
     data: Vec<u8>,
+    /// The block's bytes, charged to the disk queue until the block is
+    /// taken into its torrent's write buffer.
+    charge: MemoryCharge,
   },
   /// Request to eventually read a block from disk and return it via the
   /// sender.
@@ -57,6 +92,36 @@ pub enum Command {
     block_info: BlockInfo,
     result_tx: peer::Sender,
   },
+  /// Read a whole piece into the read cache, as a peer is expected to request
+  /// it soon.
+  ReadAhead {
+    id: TorrentId,
+    piece_index: PieceIndex,
+  },
+  /// Remove the torrent from `Disk`, closing its files but leaving them on
+  /// disk.
+  RemoveTorrent { id: TorrentId },
+  /// Remove the torrent from `Disk` and delete all of its downloaded files,
+  /// as well as any directories created for them that become empty.
+  DeleteTorrentFiles { id: TorrentId },
+  /// Move the torrent's files into the new directory, holding back writes
+  /// while they are being moved. The result is sent to the torrent.
+  MoveTorrent { id: TorrentId, new_dir: PathBuf },
+  /// Notify the sender once the torrent's blocks sent before this command are
+  /// written, by which time the torrent has been sent their pieces' results.
+  ///
+  /// Blocks of incomplete pieces can't be verified and are not written, and
+  /// neither are pieces held back while writes are paused.
+  Flush {
+    id: TorrentId,
+    result_tx: oneshot::Sender<()>,
+  },
+  /// Replaces the disk task's configuration, taking effect with the next free
+  /// space check.
+  SetConf(DiskConf),
+  /// Sent by the engine's watchdog, acknowledged right away via the sender to
+  /// show that the disk task's event loop is not stuck.
+  Ping(oneshot::Sender<()>),
   /// Eventually shutdown the disk task.
   Shutdown,
 }
@@ -72,19 +137,39 @@ struct Disk {
   cmd_rx: Receiver,
   /// Channel on which `Disk` sends alerts to the torrent engine.
   engine_tx: engine::Sender,
+  conf: DiskConf,
+  /// The download directories whose disk is low on free space, and thus
+  /// whose torrents' writes are paused.
+  low_space_dirs: HashSet<PathBuf>,
+  /// The moves of torrents' files in progress, run on blocking threads.
+  moves: task::JoinSet<(TorrentId, std::io::Result<PathBuf>)>,
+  /// The number of IO jobs of all torrents that are queued or running.
+  queue_len: Arc<AtomicUsize>,
+  /// The engine-wide memory counters, shared with the torrents.
+  memory: Arc<MemoryCounters>,
 }
 
 impl Disk {
   /// Creates a new `Disk` instance and returns a command sender and
   /// an alert receiver.
-  fn new(engine_tx: engine::Sender) -> DiskResult<(Self, Sender)> {
-    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+  fn new(
+    engine_tx: engine::Sender,
+    conf: DiskConf,
+    queue_len: Arc<AtomicUsize>,
+    memory: Arc<MemoryCounters>,
+  ) -> DiskResult<(Self, Sender)> {
+    let (cmd_tx, cmd_rx) = channel::channel(CHANNEL_CAPACITY, Overflow::Wait);
 
     Ok((
       Disk {
         torrents: HashMap::new(),
         cmd_rx,
         engine_tx,
+        conf,
+        low_space_dirs: HashSet::new(),
+        moves: task::JoinSet::new(),
+        queue_len,
+        memory,
       },
       cmd_tx,
     ))
@@ -94,7 +179,26 @@ impl Disk {
   /// unrecoverable error is encountered. (e.g. mpsc channel failure).
   async fn start(&mut self) -> DiskResult<()> {
     log::info!("Starting disk IO event loop");
-    while let Some(cmd) = self.cmd_rx.recv().await {
+    let mut space_check_timer =
+      time::interval(self.conf.free_space_check_interval);
+    loop {
+      let cmd = tokio::select! {
+        cmd = self.cmd_rx.recv() => match cmd {
+          Some(cmd) => cmd,
+          None => break,
+        },
+        _ = space_check_timer.tick() => {
+          self.check_free_space().await?;
+          continue;
+        }
+        Some(result) = self.moves.join_next(), if !self.moves.is_empty() => {
+          match result {
+            Ok((id, result)) => self.finish_move(id, result).await,
+            Err(e) => log::error!("Torrent storage move task failed: {}", e),
+          }
+          continue;
+        }
+      };
       match cmd {
         Command::NewTorrent {
           id,
@@ -120,11 +224,17 @@ impl Disk {
           // NOTE: Do not return on failure, we don't want to kill
           // the disk task due to potential disk IO errors:
           // we just want to log it and notify engine of it.
-          let torrent_res =
-            Torrent::new(storage_info, piece_hashes, torrent_tx);
+          let torrent_res = Torrent::new(
+            storage_info,
+            piece_hashes,
+            torrent_tx,
+            Arc::clone(&self.queue_len),
+            Arc::clone(&self.memory),
+          );
           match torrent_res {
-            Ok(torrent) => {
+            Ok(mut torrent) => {
               log::info!("Torrent {} successfully allocated", id);
+              self.preflight_free_space(&mut torrent);
               self.torrents.insert(id, RwLock::new(torrent));
               self.engine_tx.send(engine::Command::TorrentAllocation {
                 id,
@@ -141,16 +251,46 @@ impl Disk {
             }
           }
         }
+        Command::CheckPieces { id } => self.check_pieces(id).await,
+        Command::VerifyPieces {
+          id,
+          own_pieces,
+          pieces,
+        } => self.verify_pieces(id, own_pieces, pieces).await,
         Command::WriteBlock {
           id,
           block_info,
           data,
+          charge: _charge,
         } => self.write_block(id, block_info, data).await?,
         Command::ReadBlock {
           id,
           block_info,
           result_tx,
         } => self.read_block(id, block_info, result_tx).await?,
+        Command::ReadAhead { id, piece_index } => {
+          self.read_ahead(id, piece_index).await
+        }
+        Command::RemoveTorrent { id } => self.remove_torrent(id, false).await,
+        Command::DeleteTorrentFiles { id } => {
+          self.remove_torrent(id, true).await
+        }
+        Command::MoveTorrent { id, new_dir } => {
+          self.move_torrent(id, new_dir).await
+        }
+        Command::Flush { id, result_tx } => self.flush(id, result_tx).await,
+        Command::SetConf(conf) => {
+          log::info!("Reloading disk configuration: {:?}", conf);
+          if conf.free_space_check_interval
+            != self.conf.free_space_check_interval
+          {
+            space_check_timer = time::interval(conf.free_space_check_interval);
+          }
+          self.conf = conf;
+        }
+        Command::Ping(ack_tx) => {
+          ack_tx.send(()).ok();
+        }
         Command::Shutdown => {
           log::info!("Shutting down disk event loop");
           break;
@@ -160,9 +300,174 @@ impl Disk {
     Ok(())
   }
 
+  /// Checks whether the new torrent's disk has enough free space for the
+  /// download, and pauses the torrent's writes right away if its download
+  /// directory is already known to be low on space.
+  ///
+  /// A download that doesn't fit is not rejected as space may be freed up
+  /// in the meantime, and the ongoing check pauses its writes if not.
+  fn preflight_free_space(&self, torrent: &mut Torrent) {
+    if self.low_space_dirs.contains(torrent.download_dir()) {
+      torrent.pause_writes(self.conf.max_paused_write_len);
+    }
+    let dir = torrent.download_dir();
+    match available_space(dir) {
+      Ok(available) => {
+        let required = torrent
+          .remaining_alloc_len()
+          .saturating_add(self.conf.min_free_space);
+        if available < required {
+          log::warn!(
+            "Download directory {:?} has {} bytes free, but torrent needs {}",
+            dir,
+            available,
+            required
+          );
+        }
+      }
+      Err(e) => log::warn!("Cannot get free space of {:?}: {}", dir, e),
+    }
+  }
+
+  /// Checks the free space of each download directory's disk, pausing the
+  /// writes of the torrents in the directories that are low on space and
+  /// resuming those that are no longer.
+  ///
+  /// The engine is notified of each directory that becomes low on space.
+  async fn check_free_space(&mut self) -> DiskResult<()> {
+    let dirs: HashSet<PathBuf> = {
+      let mut dirs = HashSet::new();
+      for torrent in self.torrents.values() {
+        dirs.insert(torrent.read().await.download_dir().to_path_buf());
+      }
+      dirs
+    };
+    // forget about directories that no longer have torrents
+    self.low_space_dirs.retain(|dir| dirs.contains(dir));
+
+    for dir in dirs {
+      let available = match available_space(&dir) {
+        Ok(available) => available,
+        Err(e) => {
+          log::warn!("Cannot get free space of {:?}: {}", dir, e);
+          continue;
+        }
+      };
+      let is_low = available < self.conf.min_free_space;
+      if is_low == self.low_space_dirs.contains(&dir) {
+        continue;
+      }
+
+      for torrent in self.torrents.values() {
+        let mut torrent = torrent.write().await;
+        if torrent.download_dir() != dir {
+          continue;
+        }
+        if is_low {
+          torrent.pause_writes(self.conf.max_paused_write_len);
+        } else {
+          torrent.resume_writes();
+        }
+      }
+
+      if is_low {
+        log::warn!(
+          "Pausing writes to {:?}, only {} bytes free",
+          dir,
+          available
+        );
+        self.low_space_dirs.insert(dir.clone());
+        self
+          .engine_tx
+          .send(engine::Command::LowDiskSpace { dir, available })?;
+      } else {
+        log::info!("Resuming writes to {:?}, {} bytes free", dir, available);
+        self.low_space_dirs.remove(&dir);
+      }
+    }
+    Ok(())
+  }
+
+  /// Starts verifying the torrent's existing data, the result of which is
+  /// sent to the torrent.
+  ///
+  /// An unknown torrent id is only logged as the torrent may have failed to
+  /// allocate.
+  async fn check_pieces(&self, id: TorrentId) {
+    match self.torrents.get(&id) {
+      Some(torrent) => torrent.read().await.check_pieces(),
+      None => log::warn!("Cannot check torrent {}: not found", id),
+    }
+  }
+
+  /// Starts verifying the given pieces of the torrent's data, the result of
+  /// which is sent to the torrent.
+  ///
+  /// An unknown torrent id is only logged as the torrent may have failed to
+  /// allocate.
+  async fn verify_pieces(
+    &self,
+    id: TorrentId,
+    own_pieces: Bitfield,
+    pieces: Vec<PieceIndex>,
+  ) {
+    match self.torrents.get(&id) {
+      Some(torrent) => torrent.read().await.verify_pieces(own_pieces, pieces),
+      None => log::warn!("Cannot verify torrent {}: not found", id),
+    }
+  }
+
+  /// Starts moving the torrent's files into the new directory on a blocking
+  /// thread.
+  ///
+  /// An unknown torrent id is only logged as the torrent may have failed to
+  /// allocate.
+  async fn move_torrent(&mut self, id: TorrentId, new_dir: PathBuf) {
+    let torrent = match self.torrents.get(&id) {
+      Some(torrent) => torrent,
+      None => {
+        log::warn!("Cannot move torrent {}: not found", id);
+        return;
+      }
+    };
+    if let Some(job) = torrent.write().await.start_move(new_dir) {
+      self.moves.spawn(async move {
+        let result = task::spawn_blocking(job)
+          .await
+          .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+        (id, result)
+      });
+    }
+  }
+
+  /// Finishes the move of the torrent's files, after which its writes are
+  /// paused or resumed depending on the free space of its new directory.
+  async fn finish_move(
+    &mut self,
+    id: TorrentId,
+    result: std::io::Result<PathBuf>,
+  ) {
+    let torrent = match self.torrents.get(&id) {
+      Some(torrent) => torrent,
+      None => {
+        log::warn!("Moved torrent {} no longer exists", id);
+        return;
+      }
+    };
+    let mut torrent = torrent.write().await;
+    if let Ok(new_dir) = &result {
+      if self.low_space_dirs.contains(new_dir) {
+        torrent.pause_writes(self.conf.max_paused_write_len);
+      } else {
+        torrent.resume_writes();
+      }
+    }
+    torrent.finish_move(result);
+  }
+
   /// Queues a block for writing.
   ///
-  /// Returns an error if the torrent id is invalid.
+  /// Blocks of an unknown torrent are dropped.
   ///
   /// If the block could not be written dut to IO failure,
   /// the torrent is notified of it.
@@ -176,20 +481,22 @@ impl Disk {
 
     // check torrent id
     //
-    // TODO: maybe we don't want to crash the disk task due to an invalid
-    // torrent id: could it be that disk requests for a torrent arrive after
-    // a torrent has been removed?
-    let torrent = self.torrents.get(&id).ok_or_else(|| {
-      log::error!("Torrent {} not found", id);
-      Error::InvalidTorrentId
-    })?;
+    // Don't crash the disk task due to an invalid torrent id: requests of a
+    // torrent's peer sessions may still arrive after it has been removed.
+    let torrent = match self.torrents.get(&id) {
+      Some(torrent) => torrent,
+      None => {
+        log::warn!("Torrent {} not found", id);
+        return Ok(());
+      }
+    };
     torrent.write().await.write_block(block_info, data)
   }
 
   /// Attempts to read a block from disk and return the result via the given
   /// sender.
   ///
-  /// Returns an error if the torrent id is invalid.
+  /// Requests for an unknown torrent are dropped.
   ///
   /// If the block could not be read due to IO failure, the torrent is
   /// notified of it.
@@ -203,26 +510,94 @@ impl Disk {
 
     // check torrent id
     //
-    // TODO: maybe we don't want to crash the disk task due to an invalid
-    // torrent id: could it be that disk requests for a torrent arrive after
-    // a torrent has been removed?
-    let torrent = self.torrents.get(&id).ok_or_else(|| {
-      log::error!("Torrent {} not found", id);
-      Error::InvalidTorrentId
-    })?;
+    // Don't crash the disk task due to an invalid torrent id: requests of a
+    // torrent's peer sessions may still arrive after it has been removed.
+    let torrent = match self.torrents.get(&id) {
+      Some(torrent) => torrent,
+      None => {
+        log::warn!("Torrent {} not found", id);
+        return Ok(());
+      }
+    };
     torrent.read().await.read_block(block_info, tx)
   }
+
+  /// Reads the piece into the torrent's read cache, unless it's already
+  /// there.
+  ///
+  /// As with reads, requests for an unknown torrent are dropped.
+  async fn read_ahead(&self, id: TorrentId, piece_index: PieceIndex) {
+    match self.torrents.get(&id) {
+      Some(torrent) => torrent.read().await.read_ahead(piece_index),
+      None => log::warn!("Torrent {} not found", id),
+    }
+  }
+
+  /// Waits for the torrent's piece writes in progress in the background,
+  /// notifying the sender once they're done.
+  ///
+  /// The sender is dropped for an unknown torrent.
+  async fn flush(&self, id: TorrentId, result_tx: oneshot::Sender<()>) {
+    let writes = match self.torrents.get(&id) {
+      Some(torrent) => torrent.write().await.take_writes(),
+      None => {
+        log::warn!("Cannot flush torrent {}: not found", id);
+        return;
+      }
+    };
+    log::debug!("Flushing {} torrent {} write(s)", writes.len(), id);
+    task::spawn(async move {
+      for write in writes {
+        write.await.ok();
+      }
+      result_tx.send(()).ok();
+    });
+  }
+
+  /// Removes the torrent's entry, optionally deleting its files as well.
+  ///
+  /// An unknown torrent id is only logged as the torrent may have failed to
+  /// allocate in the first place.
+  async fn remove_torrent(&mut self, id: TorrentId, delete_files: bool) {
+    let torrent = match self.torrents.remove(&id) {
+      Some(torrent) => torrent.into_inner(),
+      None => {
+        log::warn!("Cannot remove torrent {}: not found", id);
+        return;
+      }
+    };
+    log::info!("Removed torrent {} from disk", id);
+
+    if delete_files {
+      // deleting many files may take a while, so don't block the reactor
+      let result = task::spawn_blocking(move || torrent.delete_files()).await;
+      match result {
+        Ok(Ok(())) => log::info!("Deleted torrent {} files", id),
+        Ok(Err(e)) => log::error!("Error deleting torrent {} files: {}", id, e),
+        Err(e) => log::error!("Torrent {} file deletion panicked: {}", id, e),
+      }
+    }
+  }
+}
+
+/// Returns the number of bytes available to unprivileged users on the disk
+/// containing the path.
+fn available_space(path: &Path) -> std::io::Result<u64> {
+  let stat = nix::sys::statvfs::statvfs(path)?;
+  Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
 }
 
 #[cfg(test)]
 mod tests {
-  use std::{fs, path::PathBuf};
+  use std::{fs, path::PathBuf, time::Duration};
 
   use sha1::{Digest, Sha1};
   use tempfile::tempdir;
   use tokio::sync::mpsc;
 
-  use crate::{blockinfo::block_count, storage_info::FileInfo, BLOCK_LEN};
+  use crate::{
+    blockinfo::block_count, memory::Buffer, storage_info::FileInfo, BLOCK_LEN,
+  };
 
   use super::*;
 
@@ -230,8 +605,14 @@ mod tests {
   /// torrent returning an error.
   #[tokio::test]
   async fn should_allocate_new_torrent() {
-    let (tx, mut rx) = mpsc::unbounded_channel();
-    let (_, disk_tx) = spawn(tx).unwrap();
+    let (tx, mut rx) = engine::channel();
+    let (_, disk_tx) = spawn(
+      tx,
+      DiskConf::default(),
+      Default::default(),
+      Default::default(),
+    )
+    .unwrap();
 
     let Env {
       id,
@@ -286,8 +667,14 @@ mod tests {
   /// alert of each disk write is returned by the disk task.
   #[tokio::test]
   async fn should_write_all_pieces() {
-    let (tx, mut rx) = mpsc::unbounded_channel();
-    let (_, disk_tx) = spawn(tx).unwrap();
+    let (tx, mut rx) = engine::channel();
+    let (_, disk_tx) = spawn(
+      tx,
+      DiskConf::default(),
+      Default::default(),
+      Default::default(),
+    )
+    .unwrap();
 
     let Env {
       id,
@@ -320,11 +707,7 @@ mod tests {
         //     "Writing piece {index} block {block}"
         // );
         disk_tx
-          .send(Command::WriteBlock {
-            id,
-            block_info: block,
-            data: data.to_vec(),
-          })
+          .send(write_block_cmd(id, block, data.to_vec()))
           .unwrap();
       });
 
@@ -350,8 +733,14 @@ mod tests {
   /// is returned by the disk task.
   #[tokio::test]
   async fn should_reject_writing_invalid_piece() {
-    let (tx, mut rx) = mpsc::unbounded_channel();
-    let (_, disk_tx) = spawn(tx).unwrap();
+    let (tx, mut rx) = engine::channel();
+    let (_, disk_tx) = spawn(
+      tx,
+      DiskConf::default(),
+      Default::default(),
+      Default::default(),
+    )
+    .unwrap();
 
     let Env {
       id,
@@ -384,11 +773,7 @@ mod tests {
       debug_assert_eq!(data.len(), block.len as usize);
       //println!("Writing invalid piece {index} block {block}");
       disk_tx
-        .send(Command::WriteBlock {
-          id,
-          block_info: block,
-          data: data.to_vec(),
-        })
+        .send(write_block_cmd(id, block, data.to_vec()))
         .unwrap();
     });
 
@@ -407,8 +792,14 @@ mod tests {
   /// returned via the provided sender.
   #[tokio::test]
   async fn should_read_piece_blocks() {
-    let (tx, mut rx) = mpsc::unbounded_channel();
-    let (_, disk_tx) = spawn(tx).unwrap();
+    let (tx, mut rx) = engine::channel();
+    let (_, disk_tx) = spawn(
+      tx,
+      DiskConf::default(),
+      Default::default(),
+      Default::default(),
+    )
+    .unwrap();
 
     let Env {
       id,
@@ -442,11 +833,7 @@ mod tests {
       //     "Writing piece {index} block {block}"
       // );
       disk_tx
-        .send(Command::WriteBlock {
-          id,
-          block_info: block,
-          data: data.to_vec(),
-        })
+        .send(write_block_cmd(id, block, data.to_vec()))
         .unwrap();
     });
 
@@ -488,18 +875,513 @@ mod tests {
       block_offset += block_len;
     }
 
+    // other clients' requests needn't follow our block grid
+    let block_info = BlockInfo {
+      piece_index: index,
+      offset: 100,
+      len: piece.len() as u32 - 200,
+    };
+    disk_tx
+      .send(Command::ReadBlock {
+        id,
+        block_info,
+        result_tx: tx.clone(),
+      })
+      .unwrap();
+    if let Some(peer::Command::Block(block)) = rx.recv().await {
+      assert_eq!(block.info(), block_info);
+      assert_eq!(&*block.data, &piece[100..piece.len() - 100]);
+    } else {
+      panic!("unaligned block could not be read from disk");
+    }
+
     // clean up test env
     let file = info.files.first().unwrap();
     fs::remove_file(info.download_dir.join(&file.path))
       .expect("cannot clean up disk test torrent file");
   }
 
+  /// Tests that deleting a torrent removes its files from disk and its entry
+  /// from the disk task, so that it may be allocated again.
+  #[tokio::test]
+  async fn should_delete_torrent_files() {
+    let (tx, mut rx) = engine::channel();
+    let (join_handle, disk_tx) = spawn(
+      tx,
+      DiskConf::default(),
+      Default::default(),
+      Default::default(),
+    )
+    .unwrap();
+
+    let Env {
+      id,
+      piece_hashes,
+      info,
+      torrent_tx,
+      ..
+    } = Env::new("delete_torrent_files");
+
+    // allocate torrent via channel
+    disk_tx
+      .send(Command::NewTorrent {
+        id,
+        storage_info: info.clone(),
+        piece_hashes: piece_hashes.clone(),
+        torrent_tx: torrent_tx.clone(),
+      })
+      .unwrap();
+    rx.recv().await.expect("cannot allocate torrent");
+    let file = info.files.first().unwrap();
+    let path = info.download_dir.join(&file.path);
+    assert!(path.is_file());
+
+    // delete torrent and then allocate it again: if the torrent had not been
+    // removed we'd get an already exists error
+    disk_tx.send(Command::DeleteTorrentFiles { id }).unwrap();
+    disk_tx
+      .send(Command::NewTorrent {
+        id,
+        storage_info: info.clone(),
+        piece_hashes,
+        torrent_tx,
+      })
+      .unwrap();
+    let alert = rx.recv().await.unwrap();
+    assert!(matches!(
+      alert,
+      engine::Command::TorrentAllocation { result: Ok(()), .. }
+    ));
+
+    // delete it once more, and wait for the disk task to finish so that we
+    // know the deletion went through
+    disk_tx.send(Command::DeleteTorrentFiles { id }).unwrap();
+    disk_tx.send(Command::Shutdown).unwrap();
+    join_handle.await.unwrap().unwrap();
+    assert!(!path.exists());
+    // the user's download directory must be left intact
+    assert!(info.download_dir.is_dir());
+  }
+
+  /// Tests that checking a torrent's existing data finds exactly the pieces
+  /// that are present and valid.
+  #[tokio::test]
+  async fn should_check_existing_pieces() {
+    let (tx, mut rx) = engine::channel();
+    let (_, disk_tx) = spawn(
+      tx,
+      DiskConf::default(),
+      Default::default(),
+      Default::default(),
+    )
+    .unwrap();
+
+    let Env {
+      id,
+      pieces,
+      piece_hashes,
+      info,
+      torrent_tx,
+      mut torrent_rx,
+    } = Env::new("check_existing_pieces");
+
+    // a fresh download has no valid pieces
+    disk_tx
+      .send(Command::NewTorrent {
+        id,
+        storage_info: info.clone(),
+        piece_hashes: piece_hashes.clone(),
+        torrent_tx: torrent_tx.clone(),
+      })
+      .unwrap();
+    rx.recv().await.expect("cannot allocate torrent");
+    disk_tx.send(Command::CheckPieces { id }).unwrap();
+    match torrent_rx.recv().await {
+      Some(torrent::Command::PiecesChecked(own_pieces)) => {
+        assert_eq!(own_pieces.len(), pieces.len());
+        assert!(own_pieces.not_any());
+      }
+      _ => panic!("torrent was not checked"),
+    }
+
+    // write the first piece, a corrupt second piece, and the third piece,
+    // leaving the last piece missing
+    let file = info.files.first().unwrap();
+    let path = info.download_dir.join(&file.path);
+    let mut data = pieces[0].clone();
+    data.extend(pieces[1].iter().map(|b| b.wrapping_add(1)));
+    data.extend(&pieces[2]);
+    fs::write(&path, data).unwrap();
+
+    disk_tx.send(Command::CheckPieces { id }).unwrap();
+    match torrent_rx.recv().await {
+      Some(torrent::Command::PiecesChecked(own_pieces)) => {
+        assert_eq!(
+          own_pieces.iter().by_vals().collect::<Vec<_>>(),
+          vec![true, false, true, false]
+        );
+      }
+      _ => panic!("torrent was not checked"),
+    }
+
+    fs::remove_file(path).expect("cannot clean up disk test torrent file");
+  }
+
+  /// Tests that verifying some of a torrent's pieces clears only those of
+  /// them that are invalid, trusting the rest.
+  #[tokio::test]
+  async fn should_verify_given_pieces() {
+    let (tx, mut rx) = engine::channel();
+    let (_, disk_tx) = spawn(
+      tx,
+      DiskConf::default(),
+      Default::default(),
+      Default::default(),
+    )
+    .unwrap();
+
+    let Env {
+      id,
+      pieces,
+      piece_hashes,
+      info,
+      torrent_tx,
+      mut torrent_rx,
+    } = Env::new("verify_given_pieces");
+    disk_tx
+      .send(Command::NewTorrent {
+        id,
+        storage_info: info.clone(),
+        piece_hashes,
+        torrent_tx,
+      })
+      .unwrap();
+    rx.recv().await.expect("cannot allocate torrent");
+
+    // the second piece is corrupt and the last one missing
+    let file = info.files.first().unwrap();
+    let path = info.download_dir.join(&file.path);
+    let mut data = pieces[0].clone();
+    data.extend(pieces[1].iter().map(|b| b.wrapping_add(1)));
+    data.extend(&pieces[2]);
+    fs::write(&path, data).unwrap();
+
+    // only owned pieces are verified, and the rest of the bitfield is kept
+    // as is
+    let mut own_pieces = Bitfield::repeat(true, pieces.len());
+    own_pieces.set(0, false);
+    own_pieces.set(3, false);
+    disk_tx
+      .send(Command::VerifyPieces {
+        id,
+        own_pieces,
+        pieces: vec![1, 2, 3],
+      })
+      .unwrap();
+    match torrent_rx.recv().await {
+      Some(torrent::Command::PiecesChecked(own_pieces)) => {
+        assert_eq!(
+          own_pieces.iter().by_vals().collect::<Vec<_>>(),
+          vec![false, false, true, false]
+        );
+      }
+      _ => panic!("torrent was not verified"),
+    }
+
+    fs::remove_file(path).expect("cannot clean up disk test torrent file");
+  }
+
+  /// Tests that the writes of a torrent whose disk is low on free space are
+  /// paused and that the engine is notified of it.
+  #[tokio::test]
+  async fn should_pause_writes_on_low_disk_space() {
+    let (tx, mut rx) = engine::channel();
+    // no disk has this much space, so it's always low
+    let conf = DiskConf {
+      min_free_space: u64::MAX,
+      max_paused_write_len: u64::MAX,
+      free_space_check_interval: Duration::from_millis(10),
+    };
+    let (_, disk_tx) =
+      spawn(tx, conf, Default::default(), Default::default()).unwrap();
+
+    let Env {
+      id,
+      pieces,
+      piece_hashes,
+      info,
+      torrent_tx,
+      mut torrent_rx,
+    } = Env::new("pause_writes_on_low_disk_space");
+
+    disk_tx
+      .send(Command::NewTorrent {
+        id,
+        storage_info: info.clone(),
+        piece_hashes,
+        torrent_tx,
+      })
+      .unwrap();
+    rx.recv().await.expect("cannot allocate torrent");
+    match rx.recv().await {
+      Some(engine::Command::LowDiskSpace { dir, .. }) => {
+        assert_eq!(dir, info.download_dir);
+      }
+      _ => panic!("low disk space was not reported"),
+    }
+
+    // a complete piece must not be written
+    let index = 0;
+    let piece = &pieces[index];
+    for_each_block(index, piece.len() as u32, |block| {
+      let block_end = block.offset + block.len;
+      disk_tx
+        .send(write_block_cmd(
+          id,
+          block,
+          piece[block.offset as usize..block_end as usize].to_vec(),
+        ))
+        .unwrap();
+    });
+    let result =
+      time::timeout(Duration::from_millis(100), torrent_rx.recv()).await;
+    assert!(result.is_err());
+  }
+
+  /// Tests that the pieces completed while writes are paused are written
+  /// once writes are resumed, and that the torrent is told to stop
+  /// downloading while they're over their limit.
+  #[tokio::test]
+  async fn should_flush_pieces_when_writes_resumed() {
+    let Env {
+      pieces,
+      piece_hashes,
+      info,
+      torrent_tx,
+      mut torrent_rx,
+      ..
+    } = Env::new("flush_pieces_when_writes_resumed");
+    let memory = Arc::new(MemoryCounters::default());
+    let mut torrent = Torrent::new(
+      info,
+      piece_hashes,
+      torrent_tx,
+      Default::default(),
+      Arc::clone(&memory),
+    )
+    .unwrap();
+
+    let index = 2;
+    let piece = &pieces[index];
+    // a single held back piece reaches the limit
+    torrent.pause_writes(piece.len() as u64);
+    assert!(torrent.is_write_paused());
+    for_each_block(index, piece.len() as u32, |block| {
+      let block_end = block.offset + block.len;
+      let data = piece[block.offset as usize..block_end as usize].to_vec();
+      torrent.write_block(block, data).unwrap();
+    });
+    // so the torrent is told to stop downloading
+    assert!(matches!(
+      torrent_rx.try_recv(),
+      Ok(torrent::Command::WriteStalled(true))
+    ));
+    assert!(torrent_rx.try_recv().is_err());
+    // the held back piece is still in memory
+    assert_eq!(memory.stats().write_buf_bytes, piece.len() as u64);
+
+    torrent.resume_writes();
+    assert!(matches!(
+      torrent_rx.recv().await,
+      Some(torrent::Command::WriteStalled(false))
+    ));
+    match torrent_rx.recv().await {
+      Some(torrent::Command::PieceCompletion(Ok(piece))) => {
+        assert_eq!(piece.index, index);
+        assert!(piece.is_valid);
+      }
+      _ => panic!("piece was not written after resuming writes"),
+    }
+    for write in torrent.take_writes() {
+      write.await.unwrap();
+    }
+    assert_eq!(memory.stats().write_buf_bytes, 0);
+  }
+
+  /// Tests that a flush is done only once the torrent has been sent the
+  /// results of the pieces whose blocks were written before it.
+  #[tokio::test]
+  async fn should_flush_written_pieces() {
+    let (tx, mut rx) = engine::channel();
+    let (_, disk_tx) = spawn(
+      tx,
+      DiskConf::default(),
+      Default::default(),
+      Default::default(),
+    )
+    .unwrap();
+    let Env {
+      id,
+      pieces,
+      piece_hashes,
+      info,
+      torrent_tx,
+      mut torrent_rx,
+    } = Env::new("flush_written_pieces");
+    disk_tx
+      .send(Command::NewTorrent {
+        id,
+        storage_info: info,
+        piece_hashes,
+        torrent_tx,
+      })
+      .unwrap();
+    rx.recv().await.expect("cannot allocate torrent");
+
+    for (index, piece) in pieces.iter().enumerate() {
+      for_each_block(index, piece.len() as u32, |block| {
+        let block_end = block.offset + block.len;
+        let data = piece[block.offset as usize..block_end as usize].to_vec();
+        disk_tx.send(write_block_cmd(id, block, data)).unwrap();
+      });
+    }
+    let (result_tx, result_rx) = oneshot::channel();
+    disk_tx.send(Command::Flush { id, result_tx }).unwrap();
+    result_rx.await.unwrap();
+
+    let mut written = Vec::new();
+    while let Ok(torrent::Command::PieceCompletion(Ok(piece))) =
+      torrent_rx.try_recv()
+    {
+      assert!(piece.is_valid);
+      written.push(piece.index);
+    }
+    written.sort_unstable();
+    assert_eq!(written, (0..pieces.len()).collect::<Vec<_>>());
+
+    // an unknown torrent has nothing to flush
+    let (result_tx, result_rx) = oneshot::channel();
+    disk_tx
+      .send(Command::Flush {
+        id: TorrentId::new(),
+        result_tx,
+      })
+      .unwrap();
+    assert!(result_rx.await.is_err());
+  }
+
+  /// Tests that moving a torrent's storage moves its file into the new
+  /// directory, from which its blocks are then read.
+  #[tokio::test]
+  async fn should_move_torrent_storage() {
+    let (tx, mut rx) = engine::channel();
+    let (_, disk_tx) = spawn(
+      tx,
+      DiskConf::default(),
+      Default::default(),
+      Default::default(),
+    )
+    .unwrap();
+
+    let Env {
+      id,
+      pieces,
+      piece_hashes,
+      info,
+      torrent_tx,
+      mut torrent_rx,
+    } = Env::new("move_torrent_storage");
+
+    disk_tx
+      .send(Command::NewTorrent {
+        id,
+        storage_info: info.clone(),
+        piece_hashes,
+        torrent_tx,
+      })
+      .unwrap();
+    rx.recv().await.expect("cannot allocate torrent");
+
+    let index = 0;
+    let piece = &pieces[index];
+    for_each_block(index, piece.len() as u32, |block| {
+      let block_end = block.offset + block.len;
+      disk_tx
+        .send(write_block_cmd(
+          id,
+          block,
+          piece[block.offset as usize..block_end as usize].to_vec(),
+        ))
+        .unwrap();
+    });
+    assert!(torrent_rx.recv().await.is_some());
+
+    let new_dir = tempdir().unwrap();
+    disk_tx
+      .send(Command::MoveTorrent {
+        id,
+        new_dir: new_dir.path().to_path_buf(),
+      })
+      .unwrap();
+    match torrent_rx.recv().await {
+      Some(torrent::Command::StorageMoved(Ok(dir))) => {
+        assert_eq!(dir, new_dir.path());
+      }
+      _ => panic!("torrent storage was not moved"),
+    }
+    let file = info.files.first().unwrap();
+    assert!(!info.download_dir.join(&file.path).exists());
+    assert!(new_dir.path().join(&file.path).is_file());
+
+    // the written piece is read from its new place
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let block_info = BlockInfo {
+      piece_index: index,
+      offset: 0,
+      len: BLOCK_LEN,
+    };
+    disk_tx
+      .send(Command::ReadBlock {
+        id,
+        block_info,
+        result_tx: tx,
+      })
+      .unwrap();
+    match rx.recv().await {
+      Some(peer::Command::Block(block)) => {
+        assert_eq!(block.info(), block_info);
+        assert_eq!(&*block.data, &piece[..BLOCK_LEN as usize]);
+      }
+      _ => panic!("block could not be read from disk"),
+    }
+  }
+
+  /// Returns the command to write the block, with its bytes charged to
+  /// counters of its own.
+  fn write_block_cmd(
+    id: TorrentId,
+    block_info: BlockInfo,
+    data: Vec<u8>,
+  ) -> Command {
+    let charge = MemoryCharge::with_len(
+      &Default::default(),
+      Buffer::DiskQueue,
+      data.len() as u64,
+    );
+    Command::WriteBlock {
+      id,
+      block_info,
+      data,
+      charge,
+    }
+  }
+
   /// Calls the provided function for each block in piece, passing it the
   /// block's `BlockInfo`.
   fn for_each_block(
     piece_index: usize,
     piece_len: u32,
-    block_visitor: impl Fn(BlockInfo),
+    mut block_visitor: impl FnMut(BlockInfo),
   ) {
     let block_count = block_count(piece_len) as u32;
     // all pieces have four blocks in this test
@@ -602,7 +1484,7 @@ mod tests {
         }],
       };
 
-      let (torrent_tx, torrent_rx) = mpsc::unbounded_channel();
+      let (torrent_tx, torrent_rx) = torrent::channel();
 
       Self {
         id,
@@ -1,23 +1,21 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, net::SocketAddr, time::Instant};
+
+use sha1::{Digest, Sha1};
 
 use crate::{
   blockinfo::{block_count, block_len, BlockInfo},
-  PieceIndex, BLOCK_LEN,
+  conf::EndgameConf,
+  PieceIndex, Sha1Hash, BLOCK_LEN,
 };
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub enum BlockStatus {
+  #[default]
   Free,
   Requested,
   Received,
 }
 
-impl Default for BlockStatus {
-  fn default() -> Self {
-    BlockStatus::Free
-  }
-}
-
 /// Tracks the completion of an ongoing piece download and is used to request
 /// missing blocks in piece.
 pub struct PieceDownload {
@@ -28,6 +26,38 @@ pub struct PieceDownload {
   /// The blocks in this piece, tracking which are downloaded, pending, or
   /// received. The vec is preallocated to the number of blocks in piece.
   blocks: Vec<BlockStatus>,
+  /// The outstanding requests of each block, which in endgame may be more
+  /// than one.
+  requests: Vec<BlockRequests>,
+  /// The peer from which each received block was accepted, so that a piece
+  /// failing its hash can be blamed on the peers that sent it.
+  senders: Vec<Option<BlockSender>>,
+  /// The senders of the blocks of the last attempt that failed its hash,
+  /// kept while the piece is on parole.
+  failed_senders: Vec<Option<BlockSender>>,
+  /// Whether the piece failed its hash with blocks from several peers, in
+  /// which case it's downloaded again from a single peer, so that the peer
+  /// can be blamed if it fails again, or the peers whose blocks differ from
+  /// the new ones if it passes.
+  is_on_parole: bool,
+  /// The peer to which the piece on parole is left, until it has no more
+  /// blocks requested of it.
+  parole_peer: Option<SocketAddr>,
+}
+
+/// The peer a block was accepted from, and the hash of the block's data.
+#[derive(Debug, Clone, Copy)]
+struct BlockSender {
+  addr: SocketAddr,
+  hash: Sha1Hash,
+}
+
+/// The outstanding requests of a block, across all peers.
+#[derive(Debug, Default, Clone, Copy)]
+struct BlockRequests {
+  count: usize,
+  /// When the block was last requested.
+  last_time: Option<Instant>,
 }
 
 impl PieceDownload {
@@ -36,7 +66,16 @@ impl PieceDownload {
     let block_count = block_count(len);
     let mut blocks = Vec::new();
     blocks.resize_with(block_count, Default::default);
-    PieceDownload { index, len, blocks }
+    PieceDownload {
+      index,
+      len,
+      blocks,
+      requests: vec![BlockRequests::default(); block_count],
+      senders: vec![None; block_count],
+      failed_senders: Vec::new(),
+      is_on_parole: false,
+      parole_peer: None,
+    }
   }
 
   /// Returns the index of the piece that is downloaded.
@@ -45,14 +84,17 @@ impl PieceDownload {
   }
 
   /// Picks the requested number of blocks or fewer, if fewer are remaining.
-  /// If we're in end game mode, we ignore blocks requested by other peers.
+  ///
+  /// In endgame, given by its configuration, blocks already requested by
+  /// other peers are picked as well, within the configured bounds. Returns
+  /// the number of such duplicate picks.
   pub fn pick_blocks(
     &mut self,
     count: usize,
     pick_buf: &mut Vec<BlockInfo>,
-    in_end_game: bool,
+    endgame: Option<&EndgameConf>,
     prev_picked: &HashSet<BlockInfo>,
-  ) {
+  ) -> usize {
     log::trace!(
       "Trying to pick {} block(s)a in piece {} (length: {}, blocks: {})",
       count,
@@ -62,8 +104,12 @@ impl PieceDownload {
     );
 
     let mut picked = 0;
+    let mut duplicate_count = 0;
+    let now = Instant::now();
 
-    for (i, block) in self.blocks.iter_mut().enumerate() {
+    for (i, (block, requests)) in
+      self.blocks.iter_mut().zip(&mut self.requests).enumerate()
+    {
       // don't pick more than requested.
       if picked == count {
         break;
@@ -71,26 +117,32 @@ impl PieceDownload {
 
       // only pick block if it's free
       if *block == BlockStatus::Free {
-        pick_buf.push(BlockInfo {
-          piece_index: self.index,
-          offset: i as u32 * BLOCK_LEN,
-          len: block_len(self.len, i),
-        });
+        pick_buf.push(block_info(self.index, self.len, i));
         *block = BlockStatus::Requested;
+        requests.count += 1;
+        requests.last_time = Some(now);
         picked += 1;
-      } else if in_end_game && *block == BlockStatus::Requested {
-        // in endgame it's to pick blocks already requested but
+      } else if let Some(endgame) = endgame {
+        // in endgame it's to pick blocks already requested, as long as the
+        // block isn't requested from too many peers already or too recently
+        if *block != BlockStatus::Requested
+          || requests.count >= endgame.max_requests_per_block
+          || requests.last_time.is_some_and(|t| {
+            now.saturating_duration_since(t) < endgame.min_duplicate_interval
+          })
+        {
+          continue;
+        }
         // don't pick the same block twice from the same peer.
-        let block_info = BlockInfo {
-          piece_index: self.index,
-          offset: i as u32 * BLOCK_LEN,
-          len: block_len(self.len, i),
-        };
+        let block_info = block_info(self.index, self.len, i);
 
         // TODO: could be optimized by checking if peer is present
         if !prev_picked.contains(&block_info) {
           pick_buf.push(block_info);
+          requests.count += 1;
+          requests.last_time = Some(now);
           picked += 1;
+          duplicate_count += 1;
         }
       }
     }
@@ -105,30 +157,68 @@ impl PieceDownload {
     } else {
       log::trace!("Cannot pick any blocks in piece {}", self.index);
     }
+    duplicate_count
+  }
+
+  /// Returns whether the peer may pick blocks of the piece, which is always
+  /// the case unless the piece is on parole, in which case it's left to the
+  /// first peer to ask.
+  pub fn claim(&mut self, addr: SocketAddr) -> bool {
+    !self.is_on_parole || *self.parole_peer.get_or_insert(addr) == addr
+  }
+
+  /// Returns the distinct peers from which the piece's blocks were
+  /// accepted.
+  pub fn senders(&self) -> HashSet<SocketAddr> {
+    self
+      .senders
+      .iter()
+      .flatten()
+      .map(|sender| sender.addr)
+      .collect()
+  }
+
+  /// Returns the peers whose blocks in the attempt that failed its hash
+  /// differ from those of the current one, which is assumed to have passed
+  /// it.
+  pub fn corrupt_senders(&self) -> HashSet<SocketAddr> {
+    self
+      .failed_senders
+      .iter()
+      .zip(&self.senders)
+      .filter_map(|(failed, sender)| match (failed, sender) {
+        (Some(failed), Some(sender)) if failed.hash != sender.hash => {
+          Some(failed.addr)
+        }
+        _ => None,
+      })
+      .collect()
   }
 
-  /// Marks the given block as received so that it is not picked again.
+  /// Marks the given block as received from the peer so that it is not
+  /// picked again.
   ///
   /// The previous status of the block is returned. This can be used to
   /// check whether the block has already been downloaded, for example.
-  pub fn received_block(&mut self, block: &BlockInfo) -> BlockStatus {
+  pub fn received_block(
+    &mut self,
+    block: &BlockInfo,
+    from: SocketAddr,
+    data: &[u8],
+  ) -> BlockStatus {
     log::trace!("Received piece {} block {:?}", self.index, block);
 
-    // debug_assert_eq!(block.piece_index, self.index);
-    // debug_assert!(block.offset < self.len);
-    // debug_assert!(block.len <= self.len);
-
-    // TODO: we should only receive blocks that we have requested before
-    debug_assert!(
-      matches!(self.blocks[block.index_in_piece()], BlockStatus::Requested),
-      "else the {:?} block status is : {:?}",
-      block,
-      self.blocks[block.index_in_piece()]
-    );
-
-    let block = &mut self.blocks[block.index_in_piece()];
-    let prev_status = *block;
-    *block = BlockStatus::Received;
+    // sessions only pass on blocks they requested, but the request may have
+    // been freed since, so the block may be in any state
+    let index = block.index_in_piece();
+    let prev_status = self.blocks[index];
+    if prev_status != BlockStatus::Received {
+      self.senders[index] = Some(BlockSender {
+        addr: from,
+        hash: Sha1::digest(data).into(),
+      });
+    }
+    self.blocks[index] = BlockStatus::Received;
     prev_status
   }
 
@@ -144,24 +234,70 @@ impl PieceDownload {
     debug_assert!(block.offset < self.len);
     debug_assert!(block.len <= self.len);
 
-    self.blocks[block.index_in_piece()] = BlockStatus::Free;
+    let index = block.index_in_piece();
+    self.blocks[index] = BlockStatus::Free;
+    let requests = &mut self.requests[index];
+    requests.count = requests.count.saturating_sub(1);
+
+    // the peer the piece on parole was left to choked us or went away, so
+    // another one is let to finish it
+    if !self.blocks.contains(&BlockStatus::Requested) {
+      self.parole_peer = None;
+    }
   }
 
-  /// Marks all blocks free to be requested again.
+  /// Marks all blocks free to be requested again, forgetting who sent them.
   pub fn free_all_blocks(&mut self) {
     log::trace!("Canceling all blocks in piece {}", self.index,);
     for block in self.blocks.iter_mut() {
       *block = BlockStatus::Free;
     }
+    for requests in self.requests.iter_mut() {
+      *requests = BlockRequests::default();
+    }
+    for sender in self.senders.iter_mut() {
+      *sender = None;
+    }
+    self.parole_peer = None;
+  }
+
+  /// Marks all blocks free to be requested again, with the piece left to a
+  /// single peer from now on.
+  ///
+  /// The senders of the failed attempt are kept, to be compared with the
+  /// next one.
+  pub fn put_on_parole(&mut self) {
+    self.failed_senders = self.senders.clone();
+    self.free_all_blocks();
+    self.is_on_parole = true;
   }
 }
 
+/// Returns the info of the block at the index in the piece.
+fn block_info(
+  piece_index: PieceIndex,
+  piece_len: u32,
+  index: usize,
+) -> BlockInfo {
+  BlockInfo::try_new(
+    piece_index,
+    index as u32 * BLOCK_LEN,
+    block_len(piece_len, index),
+    piece_len,
+  )
+  .expect("block of a piece download must be valid")
+}
+
 #[cfg(test)]
 mod tests {
-  use std::collections::HashSet;
+  use std::{collections::HashSet, time::Duration};
 
   use super::*;
 
+  fn peer(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+  }
+
   /// Tests that repeatedly requesting as many blocks as are in the piece
   /// returns all blocks, none of them previously picked.
   #[test]
@@ -169,7 +305,7 @@ mod tests {
     let index = 0;
     let piece_len = 6 * BLOCK_LEN;
     let block_count = block_count(piece_len);
-    let in_end_game = false;
+    let endgame = None;
 
     let mut download = PieceDownload::new(index, piece_len);
     // save picked blocks
@@ -178,7 +314,7 @@ mod tests {
     // pick all blocks one by one
     for _ in 0..block_count {
       let mut picked_blocks = Vec::new();
-      download.pick_blocks(1, &mut picked_blocks, in_end_game, &picked);
+      download.pick_blocks(1, &mut picked_blocks, endgame, &picked);
       assert_eq!(picked_blocks.len(), 1);
       let block = *picked_blocks.first().unwrap();
       // assert that this block hasn't been picked before
@@ -200,7 +336,7 @@ mod tests {
   fn should_pick_all_blocks_in_new_download() {
     let piece_index = 0;
     let piece_len = 6 * BLOCK_LEN;
-    let in_end_game = false;
+    let endgame = None;
 
     let mut download = PieceDownload::new(piece_index, piece_len);
 
@@ -210,7 +346,7 @@ mod tests {
     download.pick_blocks(
       block_count,
       &mut picked_blocks,
-      in_end_game,
+      endgame,
       &HashSet::new(),
     );
     assert_eq!(picked_blocks.len(), block_count);
@@ -228,7 +364,7 @@ mod tests {
     let piece_index = 0;
     let piece_len = 6 * BLOCK_LEN;
     let block_count = block_count(piece_len);
-    let in_end_game = false;
+    let endgame = None;
 
     let mut download = PieceDownload::new(piece_index, piece_len);
 
@@ -236,21 +372,21 @@ mod tests {
     download.pick_blocks(
       block_count,
       &mut picked_blocks,
-      in_end_game,
+      endgame,
       &HashSet::new(),
     );
     assert_eq!(picked_blocks.len(), block_count);
 
     // mark all blocks as requested
     for block in picked_blocks.iter() {
-      download.received_block(block);
+      download.received_block(block, peer(1), &[]);
     }
 
     let mut picked_blocks = Vec::new();
     download.pick_blocks(
       block_count,
       &mut picked_blocks,
-      in_end_game,
+      endgame,
       &HashSet::new(),
     );
     assert!(picked_blocks.is_empty());
@@ -262,7 +398,7 @@ mod tests {
   fn should_pick_only_free_blocks_from_all() {
     let piece_index = 0;
     let piece_len = 6 * BLOCK_LEN;
-    let in_end_game = false;
+    let endgame = None;
 
     let mut download = PieceDownload::new(piece_index, piece_len);
 
@@ -272,7 +408,7 @@ mod tests {
     download.pick_blocks(
       picked_block_indices.len(),
       &mut picked_blocks,
-      in_end_game,
+      endgame,
       &HashSet::new(),
     );
     assert_eq!(picked_blocks.len(), picked_block_indices.len());
@@ -280,7 +416,7 @@ mod tests {
     // mark 3 of them as received
     let received_block_count = 3;
     for block in picked_blocks.iter().take(received_block_count) {
-      download.received_block(block);
+      download.received_block(block, peer(1), &[]);
     }
 
     let block_count = block_count(piece_len);
@@ -301,7 +437,7 @@ mod tests {
     download.pick_blocks(
       block_count,
       &mut picked_blocks,
-      in_end_game,
+      endgame,
       &HashSet::new(),
     );
     assert_eq!(
@@ -317,7 +453,7 @@ mod tests {
     let piece_index = 0;
     let piece_len = 6 * BLOCK_LEN;
     let block_count = block_count(piece_len);
-    let in_end_game = true;
+    let endgame = Some(&EndgameConf::default());
 
     let mut download = PieceDownload::new(piece_index, piece_len);
 
@@ -327,7 +463,7 @@ mod tests {
       download.pick_blocks(
         block_count,
         &mut picked_blocks,
-        in_end_game,
+        endgame,
         &HashSet::new(),
       );
       assert_eq!(picked_blocks.len(), block_count);
@@ -341,7 +477,7 @@ mod tests {
     let piece_index = 0;
     let piece_len = 6 * BLOCK_LEN;
     let block_count = block_count(piece_len);
-    let in_end_game = true;
+    let endgame = Some(&EndgameConf::default());
 
     let mut download = PieceDownload::new(piece_index, piece_len);
     // save picked blocks
@@ -350,7 +486,7 @@ mod tests {
     // pick all blocks one by one
     for _ in 0..block_count {
       let mut picked_blocks = Vec::new();
-      download.pick_blocks(1, &mut picked_blocks, in_end_game, &picked);
+      download.pick_blocks(1, &mut picked_blocks, endgame, &picked);
       assert_eq!(picked_blocks.len(), 1);
       let block = *picked_blocks.first().unwrap();
       // assert that this block hasn't been picked before
@@ -359,4 +495,115 @@ mod tests {
       picked.insert(block);
     }
   }
+
+  /// Tests that in endgame a block is requested from no more peers than
+  /// configured, and not again sooner than configured.
+  #[test]
+  fn should_bound_duplicate_requests_in_end_game() {
+    let piece_len = 2 * BLOCK_LEN;
+    let mut download = PieceDownload::new(0, piece_len);
+    let endgame = EndgameConf {
+      max_requests_per_block: 3,
+      min_duplicate_interval: Duration::ZERO,
+    };
+
+    let mut duplicate_counts = Vec::new();
+    for _ in 0..4 {
+      let mut picked_blocks = Vec::new();
+      duplicate_counts.push(download.pick_blocks(
+        2,
+        &mut picked_blocks,
+        Some(&endgame),
+        &HashSet::new(),
+      ));
+    }
+    assert_eq!(duplicate_counts, vec![0, 2, 2, 0]);
+
+    // a freed request makes room for another one
+    download.free_block(&block_info(0, piece_len, 1));
+    let mut picked_blocks = Vec::new();
+    download.pick_blocks(
+      2,
+      &mut picked_blocks,
+      Some(&endgame),
+      &HashSet::new(),
+    );
+    assert_eq!(picked_blocks, vec![block_info(0, piece_len, 1)]);
+
+    let mut download = PieceDownload::new(0, piece_len);
+    let endgame = EndgameConf {
+      max_requests_per_block: 3,
+      min_duplicate_interval: Duration::from_secs(60),
+    };
+    let mut picked_blocks = Vec::new();
+    download.pick_blocks(
+      2,
+      &mut picked_blocks,
+      Some(&endgame),
+      &HashSet::new(),
+    );
+    assert_eq!(
+      download.pick_blocks(
+        2,
+        &mut picked_blocks,
+        Some(&endgame),
+        &HashSet::new()
+      ),
+      0
+    );
+    assert_eq!(picked_blocks.len(), 2);
+  }
+
+  /// Tests that the senders of the blocks are recorded, and that a piece on
+  /// parole is left to a single peer until it has nothing requested.
+  #[test]
+  fn should_leave_piece_on_parole_to_single_peer() {
+    let piece_len = 2 * BLOCK_LEN;
+    let mut download = PieceDownload::new(0, piece_len);
+    let mut picked_blocks = Vec::new();
+    download.pick_blocks(2, &mut picked_blocks, None, &HashSet::new());
+    download.received_block(&picked_blocks[0], peer(1), b"a");
+    download.received_block(&picked_blocks[1], peer(2), b"b");
+    assert_eq!(download.senders(), [peer(1), peer(2)].into());
+
+    // any peer may pick blocks of a piece that's not on parole
+    assert!(download.claim(peer(1)));
+    assert!(download.claim(peer(2)));
+
+    download.put_on_parole();
+    assert!(download.senders().is_empty());
+    assert!(download.claim(peer(2)));
+    assert!(!download.claim(peer(1)));
+    let mut picked_blocks = Vec::new();
+    download.pick_blocks(2, &mut picked_blocks, None, &HashSet::new());
+
+    // once the peer has nothing requested the piece is left to another
+    download.free_block(&picked_blocks[0]);
+    assert!(!download.claim(peer(1)));
+    download.free_block(&picked_blocks[1]);
+    assert!(download.claim(peer(1)));
+    assert!(!download.claim(peer(2)));
+  }
+
+  /// Tests that once a piece on parole passes its hash, it's blamed on the
+  /// peers whose blocks differ from the new ones.
+  #[test]
+  fn should_find_corrupt_senders_of_piece_on_parole() {
+    let piece_len = 3 * BLOCK_LEN;
+    let mut download = PieceDownload::new(0, piece_len);
+    let mut picked_blocks = Vec::new();
+    download.pick_blocks(3, &mut picked_blocks, None, &HashSet::new());
+    download.received_block(&picked_blocks[0], peer(1), b"a");
+    download.received_block(&picked_blocks[1], peer(2), b"corrupt");
+    download.received_block(&picked_blocks[2], peer(3), b"c");
+    assert!(download.corrupt_senders().is_empty());
+
+    download.put_on_parole();
+    let mut picked_blocks = Vec::new();
+    download.pick_blocks(3, &mut picked_blocks, None, &HashSet::new());
+    download.received_block(&picked_blocks[0], peer(4), b"a");
+    download.received_block(&picked_blocks[1], peer(4), b"b");
+    download.received_block(&picked_blocks[2], peer(4), b"c");
+    assert_eq!(download.corrupt_senders(), [peer(2)].into());
+  }
 }
@@ -11,31 +11,78 @@
 //! user seeds a shutdown command.
 
 use std::{
-  collections::HashMap,
-  net::{Ipv4Addr, SocketAddr},
+  cmp::Reverse,
+  collections::{HashMap, HashSet},
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
 };
 
+use futures::future;
+use reqwest::Url;
 use tokio::{
-  sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+  runtime,
+  sync::{mpsc, oneshot, Semaphore},
   task,
+  time::{self, Instant},
 };
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-  alert::{AlertReceiver, AlertSender},
-  conf::{Conf, TorrentConf},
+  alert::{Alert, AlertReceiver, AlertSender},
+  channel::{self, Overflow},
+  conf::{Conf, Priority, TorrentConf},
+  dht::{self, DhtHandle},
   disk::{self, JoinHandle},
   error::{EngineResult, Error, NewTorrentError, TorrentResult},
+  hook::{CompletedTorrent, CompletionHook},
+  magnet::Magnet,
+  memory::MemoryCounters,
   metainfo::Metainfo,
+  rate_limiter::{EngineRateLimiter, RateLimiter, TorrentCount},
+  session,
   storage_info::StorageInfo,
-  torrent::{self, Torrent},
-  tracker::tracker::Tracker,
-  Bitfield, TorrentId,
+  torrent::{
+    self,
+    handle::TorrentHandle,
+    metadata::{self, PendingTorrent},
+    stats::{TorrentState, TorrentStats},
+    ResumeData, RuntimeChanges, Torrent,
+  },
+  tracker::{
+    client::{DefaultTrackerBackend, TrackerBackend},
+    tracker::HttpClient,
+    udp::UdpConnections,
+  },
+  transport::{PeerTransport, TcpTransport},
+  watch_dir,
+  watchdog::{Component, Heartbeat},
+  PeerId, Sha1Hash, TorrentId,
 };
 
+pub use crate::{memory::MemoryStats, session::TorrentExport};
+
 /// The channel through which the user can send commands to the engine.
-pub type Sender = UnboundedSender<Command>;
+pub type Sender = channel::Sender<Command>;
 /// The channel on which the engine listens for commands from the user.
-type Receiver = UnboundedReceiver<Command>;
+type Receiver = channel::Receiver<Command>;
+
+/// The number of torrent ticks that may be queued in the engine's channel,
+/// past which they are dropped until the engine catches up.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// How long a stalled torrent's task is given to shut down when it's
+/// restarted, after which it's aborted.
+const RESTART_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Creates the channel on which the engine receives commands.
+pub(crate) fn channel() -> (Sender, Receiver) {
+  channel::channel(CHANNEL_CAPACITY, Overflow::Drop)
+}
 
 /// The type of commands that the engine can receive.
 pub enum Command {
@@ -44,6 +91,12 @@ pub enum Command {
   CreateTorrent {
     id: TorrentId,
     params: Box<TorrentParams>,
+    /// The torrent's command channel, the sender half of which is already
+    /// held by the user's [`TorrentHandle`].
+    torrent_tx: torrent::Sender,
+    torrent_rx: torrent::Receiver,
+    /// The torrent's state from a saved session, if it's being restored.
+    resume: Option<Box<ResumeData>>,
   },
   /// Torrent allocation result. If successful, the id of the allocated
   /// torrent is returned for identification, if not, the reason of the
@@ -52,9 +105,78 @@ pub enum Command {
     id: TorrentId,
     result: Result<(), NewTorrentError>,
   },
+  /// Sent once the metadata of a torrent added without it is fetched, with
+  /// the name in its metainfo, which replaces the torrent's placeholder name.
+  MetadataFetched { id: TorrentId, name: String },
+  /// Sent by a torrent when it has all its pieces, either from downloading
+  /// them or from finding them on disk, so that it's queued as a seed.
+  TorrentComplete { id: TorrentId },
+  /// Sent by a torrent when it finished downloading, with the path of its
+  /// files and its own completion command, if any, so that the completion
+  /// hook is run.
+  TorrentDownloaded {
+    id: TorrentId,
+    path: PathBuf,
+    completion_command: Option<Vec<String>>,
+  },
+  /// Sent by a torrent when its files were moved into the directory, from
+  /// which it's restarted from then on.
+  StorageMoved { id: TorrentId, dir: PathBuf },
+  /// Sent by a torrent's handle along with a command that changes the
+  /// torrent, which is recorded to apply it again if the torrent is
+  /// restarted.
+  TorrentChanged {
+    id: TorrentId,
+    cmd: Box<torrent::Command>,
+  },
+  /// Sent by a torrent when its sample is downloaded, after which it's no
+  /// longer sampled if it's restarted.
+  SampleComplete { id: TorrentId },
+  /// Sent by a torrent with each tick, with the figures the engine totals in
+  /// its session stats.
+  TorrentTick {
+    id: TorrentId,
+    state: TorrentState,
+    download_rate: u64,
+    upload_rate: u64,
+    peer_count: usize,
+  },
+  /// Sent by the disk task when the free space of a download directory's disk
+  /// drops below the configured reserve, to be forwarded to the user.
+  LowDiskSpace { dir: PathBuf, available: u64 },
+  /// Shuts down the torrent and removes it from the engine, optionally
+  /// deleting its downloaded files.
+  RemoveTorrent { id: TorrentId, delete_data: bool },
+  /// Returns a summary of each torrent in the engine via the sender, only
+  /// of the torrents with the label if given.
+  ListTorrents {
+    label: Option<String>,
+    result_tx: oneshot::Sender<Vec<TorrentSummary>>,
+  },
+  /// Replaces the labels attached to the torrent.
+  SetLabels { id: TorrentId, labels: Vec<String> },
+  /// Changes the torrent's priority, which may queue or dequeue torrents.
+  SetPriority { id: TorrentId, priority: Priority },
+  /// Removes the torrent from the engine, leaving its files intact, and
+  /// returns its state via the sender, to be imported by another engine.
+  ExportTorrent {
+    id: TorrentId,
+    result_tx: oneshot::Sender<EngineResult<TorrentExport>>,
+  },
+  /// Saves the torrents in the session directory, returning the result via
+  /// the sender.
+  SaveSession {
+    dir: PathBuf,
+    result_tx: oneshot::Sender<EngineResult<()>>,
+  },
+  /// Applies the new, already validated configuration to the engine and
+  /// propagates the applicable changes to its disk task and torrents.
+  ReloadConf(Box<Conf>),
   /// Gracefully shuts down the engine and waits for all its torrents to do
-  /// the same.
+  /// the same, aborting them after the configured grace period.
   Shutdown,
+  /// Shuts down the engine and aborts all its tasks right away.
+  ShutdownNow,
 }
 
 /// Spawns the engine as a tokio task.
@@ -65,12 +187,26 @@ pub enum Command {
 /// The return value is a tuple of an [`EngineHandle`], with may be used to
 /// send the engine commands, and an [`AlertReceiver`], to which
 /// various components in the engine will send alerts of events.
+///
+/// An error is returned if the configuration is invalid.
 pub fn spawn(conf: Conf) -> EngineResult<(EngineHandle, AlertReceiver)> {
+  spawn_engine(conf, None)
+}
+
+/// Spawns the engine, which marks the session directory, if any, as no
+/// longer running when it shuts down cleanly.
+fn spawn_engine(
+  conf: Conf,
+  session_dir: Option<PathBuf>,
+) -> EngineResult<(EngineHandle, AlertReceiver)> {
   log::info!("Spawning engine task");
+  conf.validate()?;
 
   // crate alert channels and return alert port to user
   let (alert_tx, alert_rx) = mpsc::unbounded_channel();
   let (mut engine, tx) = Engine::new(conf, alert_tx)?;
+  engine.session_dir = session_dir;
+  let memory = Arc::clone(&engine.memory);
 
   let join_handle = task::spawn(async move { engine.run().await });
   log::info!("Spawning engine task");
@@ -79,51 +215,169 @@ pub fn spawn(conf: Conf) -> EngineResult<(EngineHandle, AlertReceiver)> {
     EngineHandle {
       tx,
       join_handle: Some(join_handle),
+      memory,
     },
     alert_rx,
   ))
 }
 
+/// Spawns the engine as a tokio task, like [`spawn`], and restores the
+/// torrents saved in the session directory by [`EngineHandle::save_session`].
+///
+/// Besides the engine's handle and alert receiver, the handles of the
+/// restored torrents are returned in the order they were added. Torrents
+/// whose data was checked in the previous session are not checked again.
+/// If no session was saved in the directory yet, no torrents are restored.
+///
+/// If the engine previously restored from the directory didn't shut down
+/// cleanly, e.g. because the process crashed, the pieces each torrent
+/// completed last before the session was saved are verified again, as
+/// their data may not have made it to the disk.
+///
+/// An error is returned if the configuration or the saved session is
+/// invalid.
+pub fn spawn_with_session(
+  conf: Conf,
+  dir: impl AsRef<Path>,
+) -> EngineResult<(EngineHandle, AlertReceiver, Vec<TorrentHandle>)> {
+  let dir = dir.as_ref();
+  let mut torrents = session::load(dir)?;
+  if session::mark_running(dir)? {
+    log::warn!("Previous engine didn't shut down cleanly");
+  } else {
+    for (_, resume) in torrents.iter_mut() {
+      resume.recent_pieces.clear();
+    }
+  }
+  let (engine, alert_rx) = spawn_engine(conf, Some(dir.to_path_buf()))?;
+  log::info!("Restoring {} torrent(s) from session", torrents.len());
+  let handles = torrents
+    .into_iter()
+    .map(|(metainfo, resume)| engine.restore_torrent(metainfo, resume))
+    .collect::<EngineResult<_>>()?;
+  Ok((engine, alert_rx, handles))
+}
+
+/// Runs the engine on a multithreaded tokio runtime of its own, so that
+/// applications that don't use tokio, e.g. GUI apps or C callers, can embed
+/// it.
+///
+/// The engine is spawned and `f` is called on the current thread with its
+/// handle and alert receiver, after which the engine is shut down gracefully
+/// and the runtime with it. While `f` runs the runtime is entered, so the
+/// handle's methods may be called directly, alerts may be received with
+/// [`AlertReceiver::blocking_recv`], and the handle's async methods may be
+/// run with [`Handle::block_on`] on [`Handle::current`].
+///
+/// This must not be called from within an async context, as the runtime
+/// can't be dropped there.
+///
+/// An error is returned if the runtime can't be created, the configuration
+/// is invalid, or the engine fails to shut down.
+///
+/// [`Handle::block_on`]: tokio::runtime::Handle::block_on
+/// [`Handle::current`]: tokio::runtime::Handle::current
+pub fn run_blocking<T>(
+  conf: Conf,
+  f: impl FnOnce(&EngineHandle, &mut AlertReceiver) -> T,
+) -> EngineResult<T> {
+  let runtime = runtime::Builder::new_multi_thread()
+    .enable_all()
+    .thread_name("bt-rust")
+    .build()?;
+  let (engine, result) = {
+    let _guard = runtime.enter();
+    let (engine, mut alert_rx) = spawn(conf)?;
+    let result = f(&engine, &mut alert_rx);
+    (engine, result)
+  };
+  runtime.block_on(engine.shutdown())?;
+  Ok(result)
+}
+
 /// Information for creating a new torrent.
 pub struct TorrentParams {
-  /// Contains the torrent's metadata.
-  pub metainfo: Metainfo,
+  /// Where the torrent's metadata comes from.
+  pub source: TorrentSource,
   /// If set, overrides the default global config.
   pub conf: Option<TorrentConf>,
-  /// Whether to download or seed the torrent.
+  /// If set, overrides the priority in the torrent's configuration. Higher
+  /// priority torrents take the active slots of the queue first, and may
+  /// take connection slots that lower priority torrents leave free.
+  pub priority: Option<Priority>,
+  /// Peers to connect to, in addition to the ones returned by trackers.
   ///
-  /// This is expected to be removed as this will become automatic once
-  /// torrent resume data is supported.
-  pub mode: Mode,
+  /// Whether the torrent downloads or seeds is not configured but detected
+  /// by verifying the torrent's existing data on disk.
+  pub peers: Vec<SocketAddr>,
   /// The address on which the torrent should listen for new peers.
   pub listen_addr: Option<SocketAddr>,
+  /// If set, each of the torrent's trackers is probed right away with a
+  /// request that times out after this long, and an
+  /// [`Alert::TrackerUnreachable`] is posted for each tracker that doesn't
+  /// respond, so that a torrent from a stale source is noticed up front.
+  ///
+  /// The probe runs alongside the torrent and doesn't delay it.
+  pub probe_trackers: Option<Duration>,
+  /// Arbitrary labels to group the torrent by, e.g. "linux-isos". They are
+  /// included in the torrent's stats and summary, and torrents may be
+  /// listed by label with [`EngineHandle::list_labeled`].
+  pub labels: Vec<String>,
+  /// How connections with the torrent's peers are established, over TCP if
+  /// not set.
+  pub transport: Option<Arc<dyn PeerTransport>>,
+  /// How the clients of the torrent's trackers are created, which by
+  /// default supports HTTP(S) and UDP trackers.
+  pub tracker_backend: Option<Arc<dyn TrackerBackend>>,
 }
 
-/// The download mode.
-///
-/// TODO: remove in favor of automatic detection.
-///
-/// TODO: when seeding is specified, we need to verify that the files to be
-/// seeded exist and are complete.
+impl TorrentParams {
+  /// Creates parameters for a torrent with the default configuration.
+  pub fn new(source: impl Into<TorrentSource>) -> Self {
+    Self {
+      source: source.into(),
+      conf: None,
+      priority: None,
+      peers: Vec::new(),
+      listen_addr: None,
+      probe_trackers: None,
+      labels: Vec::new(),
+      transport: None,
+      tracker_backend: None,
+    }
+  }
+}
+
+/// The source of a torrent's metadata.
 #[derive(Debug)]
-pub enum Mode {
-  Download { seeds: Vec<SocketAddr> },
-  Seed,
+pub enum TorrentSource {
+  /// The metadata is known upfront, from a metainfo file.
+  Metainfo(Metainfo),
+  /// Only the info hash is known, from a magnet link. The torrent's info
+  /// dictionary is fetched before the download can begin, during which the
+  /// torrent is in the [`TorrentState::FetchingMetadata`] state.
+  Magnet(Magnet),
+  /// Only the bare info hash is known. As with magnet links, the metadata
+  /// is fetched first, for which the torrent's peers should be given in
+  /// [`TorrentParams::peers`] as there are no trackers to find them.
+  InfoHash(Sha1Hash),
 }
 
-impl Mode {
-  fn own_pieces(&self, piece_count: usize) -> Bitfield {
-    match self {
-      Mode::Download { .. } => Bitfield::repeat(false, piece_count),
-      Mode::Seed => Bitfield::repeat(true, piece_count),
-    }
+impl From<Metainfo> for TorrentSource {
+  fn from(metainfo: Metainfo) -> Self {
+    Self::Metainfo(metainfo)
   }
+}
 
-  fn seeds(self) -> Vec<SocketAddr> {
-    match self {
-      Mode::Download { seeds } => seeds,
-      _ => Vec::new(),
-    }
+impl From<Magnet> for TorrentSource {
+  fn from(magnet: Magnet) -> Self {
+    Self::Magnet(magnet)
+  }
+}
+
+impl From<Sha1Hash> for TorrentSource {
+  fn from(info_hash: Sha1Hash) -> Self {
+    Self::InfoHash(info_hash)
   }
 }
 
@@ -139,27 +393,460 @@ struct Engine {
   disk_tx: disk::Sender,
   disk_join_handle: Option<disk::JoinHandle>,
 
+  /// The DHT node's task, if the DHT is used.
+  dht_join_handle: Option<dht::JoinHandle>,
+
   /// The channel on which tasks in the engine post alerts to user.
   alert_tx: AlertSender,
 
   /// The global engine configuration that includes defaults for torrents
   /// whose config is not overridden.
   conf: Conf,
+
+  /// Used to set up new torrents.
+  setup: TorrentSetup,
+
+  /// The watchdog's pings to the disk task.
+  disk_heartbeat: Heartbeat,
+
+  /// The task polling the watch directory, if one is configured.
+  watch_dir: Option<task::JoinHandle<()>>,
+
+  /// The number of jobs queued or running in the disk task, reported in the
+  /// session stats.
+  disk_queue_len: Arc<AtomicUsize>,
+
+  /// The bytes held by the engine's buffers, which the engine handle reads.
+  memory: Arc<MemoryCounters>,
+
+  /// The session directory the engine was restored from, which is marked as
+  /// no longer running once the engine shuts down cleanly.
+  session_dir: Option<PathBuf>,
+}
+
+/// The parts of the engine needed to set up a torrent once its metainfo is
+/// known, which may happen outside the engine task.
+#[derive(Clone)]
+struct TorrentSetup {
+  disk_tx: disk::Sender,
+  alert_tx: AlertSender,
+  engine_tx: Sender,
+  connection_permits: Arc<Semaphore>,
+  connection_permit_count: Arc<AtomicUsize>,
+  client_id: PeerId,
+  download_dir: PathBuf,
+  memory: Arc<MemoryCounters>,
+  /// The HTTP client shared by all trackers, whose connection pool is thus
+  /// shared too.
+  http_client: HttpClient,
+  /// The connection ids of the UDP trackers, shared by all torrents so that
+  /// a tracker is connected to once for all of them. Unlike the HTTP
+  /// client, it's kept when the settings are reloaded.
+  udp_connections: UdpConnections,
+  /// The DHT node, through which the torrents that aren't private find
+  /// peers, if the DHT is used.
+  dht: Option<DhtHandle>,
+  /// The engine-wide rate limiters, which all torrents draw from.
+  download_limiter: EngineRateLimiter,
+  upload_limiter: EngineRateLimiter,
+  /// The number of torrents in the engine, kept up to date by the engine as
+  /// torrents are added and removed.
+  torrent_count: TorrentCount,
+}
+
+impl TorrentSetup {
+  /// Creates a new torrent and allocates it on disk, after which it's ready
+  /// to be started.
+  ///
+  /// The torrent's files are in the given directory if they were moved out
+  /// of the engine's download directory.
+  #[allow(clippy::too_many_arguments)]
+  fn new_torrent(
+    &self,
+    id: TorrentId,
+    metainfo: Metainfo,
+    conf: TorrentConf,
+    listen_addr: SocketAddr,
+    cmd_tx: torrent::Sender,
+    cmd_rx: torrent::Receiver,
+    resume: Option<ResumeData>,
+    download_dir: Option<PathBuf>,
+    labels: Vec<String>,
+    transport: Arc<dyn PeerTransport>,
+    tracker_backend: Arc<dyn TrackerBackend>,
+    shutdown_token: CancellationToken,
+  ) -> TorrentResult<Torrent> {
+    let mut storage_info =
+      StorageInfo::new(&metainfo, self.download_dir.clone());
+    if let Some(download_dir) = download_dir {
+      storage_info.download_dir = download_dir;
+    }
+    // the peers of private torrents are only obtained from their trackers
+    let dht = self.dht.clone().filter(|_| !metainfo.private);
+    // files deleted or replaced since the pieces were saved would have us
+    // serve data we don't have, so they're checked again, before the disk
+    // task creates any missing files
+    let own_pieces =
+      resume
+        .as_ref()
+        .and_then(|r| r.own_pieces.clone())
+        .filter(|own_pieces| {
+          let has_files = storage_info.has_files_of(own_pieces);
+          if !has_files {
+            log::warn!("Torrent {} files changed, checking them again", id);
+          }
+          has_files
+        });
+    let recent_pieces = resume
+      .as_ref()
+      .map(|r| r.recent_pieces.clone())
+      .unwrap_or_default();
+    let is_paused = resume.as_ref().is_some_and(|r| r.is_paused);
+
+    // a tracker may be listed in more than one tier, but it's announced to
+    // once, and the trackers of all torrents share the connections to their
+    // hosts through the engine's client
+    let mut urls = HashSet::new();
+    // the trackers saved with the torrent replace the metainfo's, as they
+    // may have been edited while it ran or come from its magnet link
+    let trackers = resume
+      .as_ref()
+      .and_then(|r| r.trackers.clone())
+      .unwrap_or(metainfo.trackers)
+      .into_iter()
+      .filter(|url| {
+        let is_supported = tracker_backend.supports(url);
+        if !is_supported {
+          log::info!("Torrent {} skipping unsupported tracker {}", id, url);
+        }
+        is_supported
+      })
+      .filter(|url| urls.insert(url.clone()))
+      .map(|url| {
+        tracker_backend.new_client(
+          url,
+          &self.http_client,
+          &self.udp_connections,
+        )
+      })
+      .collect::<Vec<_>>();
+
+    let torrent = Torrent::new(torrent::Params {
+      id,
+      cmd_tx: cmd_tx.clone(),
+      cmd_rx,
+      disk_tx: self.disk_tx.clone(),
+      info_hash: metainfo.info_hash,
+      storage_info: storage_info.clone(),
+      trackers,
+      tracker_backend,
+      http_client: self.http_client.clone(),
+      udp_connections: self.udp_connections.clone(),
+      client_id: self.client_id,
+      listen_addr,
+      conf,
+      alert_tx: self.alert_tx.clone(),
+      engine_tx: self.engine_tx.clone(),
+      connection_permits: Arc::clone(&self.connection_permits),
+      connection_permit_count: Arc::clone(&self.connection_permit_count),
+      memory: Arc::clone(&self.memory),
+      engine_download_limiter: Arc::clone(&self.download_limiter),
+      engine_upload_limiter: Arc::clone(&self.upload_limiter),
+      engine_torrent_count: Arc::clone(&self.torrent_count),
+      transport,
+      raw_metainfo: metainfo.raw,
+      metadata: metainfo.info,
+      dht,
+      resume,
+      labels,
+      shutdown_token,
+    });
+
+    // Allocate torrent on disk. This is an asynchronous process and we can
+    // start the torrent in the meantime.
+    //
+    // Technically we could have issues if the torrent connects to peers
+    // that send data before we manage to allocate the (empty) files on
+    // disk. However, this should be an extremely pathological case for
+    // 2 reasons:
+    // - Most torrents would be started without peers, so a torrent would
+    //   have to wait for peers from its tracker(s). This should be a
+    //   a sufficiently long time to allocate torrent on disk.
+    // - Then, even if we manage to connect peers quickly, testing shows
+    //   that they don't tend to unchoke use immediately.
+    //
+    // Thus there is little chance to receive data and thus cause a disk
+    // write or disk read immediately.
+    self.disk_tx.send(disk::Command::NewTorrent {
+      id,
+      storage_info,
+      piece_hashes: metainfo.pieces,
+      torrent_tx: cmd_tx.clone(),
+    })?;
+
+    // a torrent paused in its previous session stays paused, this is
+    // processed before anything else once the torrent runs
+    if is_paused {
+      cmd_tx.send(torrent::Command::Pause)?;
+    }
+
+    match own_pieces {
+      // the last pieces before an unclean shutdown may not have been
+      // written, so those are verified again
+      Some(own_pieces) if !recent_pieces.is_empty() => {
+        self.disk_tx.send(disk::Command::VerifyPieces {
+          id,
+          own_pieces,
+          pieces: recent_pieces,
+        })?
+      }
+      // the data was already checked in a previous session
+      Some(own_pieces) => {
+        cmd_tx.send(torrent::Command::PiecesChecked(own_pieces))?
+      }
+      // Verify any existing data of the torrent, e.g. if it's being resumed
+      // or seeded. The torrent doesn't connect to peers until it gets the
+      // result.
+      None => self.disk_tx.send(disk::Command::CheckPieces { id })?,
+    }
+
+    Ok(torrent)
+  }
+}
+
+/// A short summary of a torrent's state, as returned by
+/// [`EngineHandle::list`].
+#[derive(Clone, Debug)]
+pub struct TorrentSummary {
+  pub id: TorrentId,
+  /// The name of the torrent, as given in its metainfo.
+  pub name: String,
+  pub state: TorrentState,
+  /// The ratio of the wanted bytes downloaded, between 0 and 1.
+  pub progress: f64,
+  /// The number of currently connected peers.
+  pub peer_count: usize,
+  /// The payload download rate, in bytes per second.
+  pub download_rate: u64,
+  /// The payload upload rate, in bytes per second.
+  pub upload_rate: u64,
+  /// The labels attached to the torrent.
+  pub labels: Vec<String>,
+}
+
+impl TorrentSummary {
+  fn new(id: TorrentId, name: String, stats: &TorrentStats) -> Self {
+    Self {
+      id,
+      name,
+      state: stats.state,
+      progress: stats.progress_wanted,
+      peer_count: stats.peers.len(),
+      download_rate: stats.thruput.payload.down.rate,
+      upload_rate: stats.thruput.payload.up.rate,
+      labels: stats.labels.clone(),
+    }
+  }
+}
+
+/// The totals of all torrents in the engine, as posted periodically in an
+/// [`Alert::SessionStats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionStats {
+  /// The payload download rate of all torrents, in bytes per second.
+  pub download_rate: u64,
+  /// The payload upload rate of all torrents, in bytes per second.
+  pub upload_rate: u64,
+  /// The number of peers connected across all torrents.
+  pub peer_count: usize,
+  /// The number of disk reads, writes and checks that are queued or running.
+  pub disk_queue_len: usize,
+  /// The number of torrents in the engine, including those whose metadata is
+  /// being fetched, which are not counted in any of the states below.
+  pub torrent_count: usize,
+  pub downloading_count: usize,
+  pub seeding_count: usize,
+  pub checking_count: usize,
+  /// The number of torrents paused, whether by the user or by the queue.
+  pub paused_count: usize,
+  /// The number of torrents paused by the queue, waiting for a free slot.
+  pub queued_count: usize,
+}
+
+/// The figures of a torrent's latest tick, from which session stats are
+/// totalled.
+#[derive(Clone, Copy)]
+struct TorrentTick {
+  state: TorrentState,
+  download_rate: u64,
+  upload_rate: u64,
+  peer_count: usize,
 }
 
 /// A running torrent's entry in the engine.
 struct TorrentEntry {
+  /// The torrent's name, kept for listing torrents.
+  name: String,
+  /// The torrent's info hash, by which its metainfo is saved in the session
+  /// directory.
+  info_hash: Sha1Hash,
   /// The torrent's command channel on which engine sends commands to torrent.
   tx: torrent::Sender,
+  /// Cancelled along with sending the torrent the shutdown command, so that
+  /// the torrent doesn't finish an announce in flight first.
+  shutdown_token: CancellationToken,
   /// The torrent task's join handle, used during shutdown.
   join_handle: Option<task::JoinHandle<TorrentResult<()>>>,
+  /// Whether the torrent has all its pieces, in which case it takes up a
+  /// seed slot rather than a download slot.
+  is_seed: bool,
+  /// Whether the torrent is stopped by the engine as there are no free slots.
+  is_queued: bool,
+  /// The watchdog's pings to the torrent task.
+  heartbeat: Heartbeat,
+  /// What the torrent was created from and the changes made to it since,
+  /// used to restart it if it stalls.
+  /// Torrents whose metadata had to be fetched are not restarted.
+  restart: Option<RestartParams>,
+  /// Whether the torrent was created without its own configuration, in which
+  /// case it follows changes to the engine's default torrent configuration.
+  uses_default_conf: bool,
+  /// Whether the torrent was created without its own listen address, in
+  /// which case it follows changes to the engine's listen address.
+  uses_default_listen_addr: bool,
+  /// The labels attached to the torrent, by which torrents are listed.
+  labels: Vec<String>,
+  /// The torrent's priority, by which it gets an active slot. It's kept when
+  /// the engine's default torrent configuration is reloaded.
+  priority: Priority,
+  /// The torrent's latest tick, if it has ticked yet.
+  last_tick: Option<TorrentTick>,
+}
+
+/// The parameters with which a torrent is started again after it stalled.
+struct RestartParams {
+  /// The torrent's command channel, which the restarted torrent takes over
+  /// once the stalled one releases it.
+  cmd_rx: Arc<tokio::sync::Mutex<crate::channel::Receiver<torrent::Command>>>,
+  metainfo: Metainfo,
+  conf: TorrentConf,
+  listen_addr: SocketAddr,
+  /// The directory holding the torrent's files, if they're not in the
+  /// engine's download directory, which changes as the storage is moved.
+  download_dir: Option<PathBuf>,
+  transport: Arc<dyn PeerTransport>,
+  tracker_backend: Arc<dyn TrackerBackend>,
+  /// The torrent's trackers, as added and removed at runtime.
+  trackers: Vec<Url>,
+  /// The other changes made to the torrent at runtime.
+  changes: RuntimeChanges,
+  /// The torrent's latest resume data, requested with every health check,
+  /// from which its pieces and totals are restored.
+  resume: Option<ResumeData>,
+  /// The pending request for the torrent's resume data, if any.
+  resume_rx: Option<oneshot::Receiver<ResumeData>>,
+}
+
+impl RestartParams {
+  /// Records the change made to the torrent through its handle, so that
+  /// it's applied again when the torrent is restarted.
+  fn record_change(&mut self, cmd: torrent::Command) {
+    match cmd {
+      torrent::Command::SetLimits(limits) => limits.apply(&mut self.conf),
+      torrent::Command::SetRateLimits { down, up } => {
+        self.conf.download_rate_limit = down;
+        self.conf.upload_rate_limit = up;
+      }
+      torrent::Command::AddTracker(url) if !self.trackers.contains(&url) => {
+        self.trackers.push(url)
+      }
+      torrent::Command::RemoveTracker(url) => {
+        self.trackers.retain(|tracker| *tracker != url)
+      }
+      // the torrent rejects priorities and deadlines that don't fit it
+      torrent::Command::SetFilePriorities(priorities)
+        if priorities.len() == self.metainfo.files.len() =>
+      {
+        self.changes.file_priorities = Some(priorities)
+      }
+      torrent::Command::SetPieceDeadline { index, deadline }
+        if index < self.metainfo.piece_count() =>
+      {
+        let deadline = (Instant::now() + deadline).into_std();
+        self.changes.deadlines.insert(index, deadline);
+      }
+      torrent::Command::Sample(len) => self.changes.sample = Some(len),
+      _ => (),
+    }
+  }
+
+  /// Takes the resume data the torrent sent since it was last requested,
+  /// and requests it again, unless the torrent didn't send it yet.
+  fn update_resume_data(&mut self, tx: &torrent::Sender) {
+    if let Some(resume_rx) = &mut self.resume_rx {
+      match resume_rx.try_recv() {
+        Ok(resume) => self.resume = Some(resume),
+        Err(oneshot::error::TryRecvError::Empty) => return,
+        Err(oneshot::error::TryRecvError::Closed) => (),
+      }
+    }
+    let (resume_tx, resume_rx) = oneshot::channel();
+    // the torrent task may no longer be running
+    self.resume_rx = tx
+      .send(torrent::Command::GetResumeData(resume_tx))
+      .ok()
+      .map(|_| resume_rx);
+  }
 }
 
 impl Engine {
   /// Creates a new engine, spawning the disk task.
   fn new(conf: Conf, alert_tx: AlertSender) -> EngineResult<(Self, Sender)> {
-    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-    let (disk_join_handle, disk_tx) = disk::spawn(cmd_tx.clone())?;
+    let http_client =
+      HttpClient::new(&conf.engine.tracker_http, conf.engine.source_addr)?;
+    let (cmd_tx, cmd_rx) = channel();
+    let disk_queue_len = Arc::new(AtomicUsize::new(0));
+    let memory = Arc::new(MemoryCounters::new(conf.engine.max_memory));
+    let (disk_join_handle, disk_tx) = disk::spawn(
+      cmd_tx.clone(),
+      conf.engine.disk,
+      Arc::clone(&disk_queue_len),
+      Arc::clone(&memory),
+    )?;
+    let (dht_join_handle, dht) = conf
+      .engine
+      .dht
+      .as_ref()
+      .map(|dht| dht::spawn(dht, conf.engine.source_addr))
+      .transpose()?
+      .unzip();
+    let setup = TorrentSetup {
+      disk_tx: disk_tx.clone(),
+      alert_tx: alert_tx.clone(),
+      engine_tx: cmd_tx.clone(),
+      connection_permits: Arc::new(Semaphore::new(
+        conf.engine.max_connected_peer_count,
+      )),
+      connection_permit_count: Arc::new(AtomicUsize::new(
+        conf.engine.max_connected_peer_count,
+      )),
+      client_id: conf.engine.client_id,
+      download_dir: conf.engine.download_dir.clone(),
+      memory: Arc::clone(&memory),
+      http_client,
+      udp_connections: UdpConnections::default(),
+      dht,
+      download_limiter: Arc::new(Mutex::new(RateLimiter::new(
+        conf.engine.download_rate_limit,
+      ))),
+      upload_limiter: Arc::new(Mutex::new(RateLimiter::new(
+        conf.engine.upload_rate_limit,
+      ))),
+      torrent_count: TorrentCount::default(),
+    };
+    let watch_dir = conf.engine.watch_dir.clone().map(|watch_dir| {
+      watch_dir::spawn(watch_dir, cmd_tx.clone(), alert_tx.clone())
+    });
 
     Ok((
       Engine {
@@ -167,8 +854,15 @@ impl Engine {
         cmd_rx,
         disk_tx,
         disk_join_handle: Some(disk_join_handle),
+        dht_join_handle,
         alert_tx,
         conf,
+        setup,
+        disk_heartbeat: Heartbeat::default(),
+        watch_dir,
+        disk_queue_len,
+        memory,
+        session_dir: None,
       },
       cmd_tx,
     ))
@@ -177,10 +871,43 @@ impl Engine {
   async fn run(&mut self) -> EngineResult<()> {
     log::info!("Starting engine");
 
-    while let Some(cmd) = self.cmd_rx.recv().await {
+    let mut watchdog_timer =
+      time::interval(self.conf.engine.watchdog.check_interval);
+    let mut session_stats_timer =
+      self.conf.engine.session_stats_interval.map(time::interval);
+    loop {
+      let cmd = tokio::select! {
+        cmd = self.cmd_rx.recv() => match cmd {
+          Some(cmd) => cmd,
+          None => break,
+        },
+        _ = watchdog_timer.tick() => {
+          self.check_health()?;
+          continue;
+        }
+        _ = async {
+          match &mut session_stats_timer {
+            Some(timer) => {
+              timer.tick().await;
+            }
+            None => future::pending().await,
+          }
+        } => {
+          self.post_session_stats()?;
+          continue;
+        }
+      };
       match cmd {
-        Command::CreateTorrent { id, params } => {
-          self.create_torrent(id, params).await?
+        Command::CreateTorrent {
+          id,
+          params,
+          torrent_tx,
+          torrent_rx,
+          resume,
+        } => {
+          self
+            .create_torrent(id, params, torrent_tx, torrent_rx, resume)
+            .await?
         }
         Command::TorrentAllocation { id, result } => match result {
           Ok(_) => {
@@ -190,8 +917,106 @@ impl Engine {
             log::error!("Error allocating torrent {} on disk: {}", id, e);
           }
         },
+        Command::MetadataFetched { id, name } => {
+          if let Some(torrent) = self.torrents.get_mut(&id) {
+            log::info!("Torrent {} metadata fetched, named {}", id, name);
+            torrent.name = name;
+          }
+        }
+        Command::TorrentComplete { id } => {
+          if let Some(torrent) = self.torrents.get_mut(&id) {
+            torrent.is_seed = true;
+            self.update_queue()?;
+          }
+        }
+        Command::TorrentDownloaded {
+          id,
+          path,
+          completion_command,
+        } => self.run_completion_hook(id, path, completion_command),
+        Command::StorageMoved { id, dir } => {
+          if let Some(restart) = self
+            .torrents
+            .get_mut(&id)
+            .and_then(|torrent| torrent.restart.as_mut())
+          {
+            restart.download_dir = Some(dir);
+          }
+        }
+        Command::TorrentChanged { id, cmd } => {
+          if let Some(restart) = self
+            .torrents
+            .get_mut(&id)
+            .and_then(|torrent| torrent.restart.as_mut())
+          {
+            restart.record_change(*cmd);
+          }
+        }
+        Command::SampleComplete { id } => {
+          if let Some(restart) = self
+            .torrents
+            .get_mut(&id)
+            .and_then(|torrent| torrent.restart.as_mut())
+          {
+            restart.changes.sample = None;
+          }
+        }
+        Command::TorrentTick {
+          id,
+          state,
+          download_rate,
+          upload_rate,
+          peer_count,
+        } => {
+          if let Some(torrent) = self.torrents.get_mut(&id) {
+            torrent.last_tick = Some(TorrentTick {
+              state,
+              download_rate,
+              upload_rate,
+              peer_count,
+            });
+          }
+        }
+        Command::LowDiskSpace { dir, available } => {
+          self.alert_tx.send(Alert::LowDiskSpace { dir, available })?;
+        }
+        Command::RemoveTorrent { id, delete_data } => {
+          self.remove_torrent(id, delete_data).await?
+        }
+        Command::ListTorrents { label, result_tx } => {
+          self.list_torrents(label, result_tx)
+        }
+        Command::SetLabels { id, labels } => self.set_labels(id, labels)?,
+        Command::SetPriority { id, priority } => {
+          self.set_priority(id, priority)?
+        }
+        Command::ExportTorrent { id, result_tx } => {
+          self.export_torrent(id, result_tx).await?
+        }
+        Command::SaveSession { dir, result_tx } => {
+          self.save_session(dir, result_tx)
+        }
+        Command::ReloadConf(conf) => {
+          let check_interval = self.conf.engine.watchdog.check_interval;
+          let session_stats_interval = self.conf.engine.session_stats_interval;
+          self.reload_conf(*conf)?;
+          if self.conf.engine.watchdog.check_interval != check_interval {
+            watchdog_timer =
+              time::interval(self.conf.engine.watchdog.check_interval);
+          }
+          if self.conf.engine.session_stats_interval != session_stats_interval {
+            session_stats_timer =
+              self.conf.engine.session_stats_interval.map(time::interval);
+          }
+        }
         Command::Shutdown => {
-          self.shutdown().await?;
+          self
+            .shutdown(self.conf.engine.shutdown_grace_period)
+            .await?;
+          break;
+        }
+        Command::ShutdownNow => {
+          self.shutdown(Duration::ZERO).await?;
           break;
         }
       }
@@ -201,154 +1026,1245 @@ impl Engine {
   }
 
   /// Creates and spawns a new torrent based on the parameters given.
+  ///
+  /// If the torrent's metadata is not yet known, the torrent is set up in
+  /// its own task once it is fetched. Resume data is only given with a
+  /// metainfo source.
   async fn create_torrent(
     &mut self,
     id: TorrentId,
     params: Box<TorrentParams>,
+    torrent_tx: torrent::Sender,
+    torrent_rx: torrent::Receiver,
+    resume: Option<Box<ResumeData>>,
   ) -> EngineResult<()> {
-    let conf = params.conf.unwrap_or_else(|| self.conf.torrent.clone());
-    let storage_info =
-      StorageInfo::new(&params.metainfo, self.conf.engine.download_dir.clone());
-
-    // TODO: don't duplicate trackers if multiple torrents use the same
-    // ones (common in practice)
-    let trackers = params
-      .metainfo
-      .trackers
-      .into_iter()
-      .map(Tracker::new)
-      .collect::<Vec<_>>();
-
-    let own_pieces = params.mode.own_pieces(storage_info.piece_count);
-
-    // crate and spawn torrent
-    // TODO: For now we spawn automatically, but later we add torrent
-    // pause/restart APIs, this will be separate step. There should be
-    // a `start` flag in `params` that says whether to immediately spawn
-    // a new torrent (or maybe in `TorrentConf`).
-    let (mut torrent, torrent_tx) = Torrent::new(torrent::Params {
-      id,
-      disk_tx: self.disk_tx.clone(),
-      info_hash: params.metainfo.info_hash,
-      storage_info: storage_info.clone(),
-      own_pieces,
-      trackers,
-      client_id: self.conf.engine.client_id,
-      listen_addr: params
-        .listen_addr
-        .unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)),
+    let TorrentParams {
+      source,
       conf,
-      alert_tx: self.alert_tx.clone(),
+      priority,
+      peers,
+      listen_addr,
+      probe_trackers,
+      labels,
+      transport,
+      tracker_backend,
+    } = *params;
+    let transport = transport.unwrap_or_else(|| {
+      Arc::new(TcpTransport::with_source_addr(self.conf.engine.source_addr))
     });
+    let tracker_backend =
+      tracker_backend.unwrap_or_else(|| Arc::new(DefaultTrackerBackend));
+    let uses_default_conf = conf.is_none();
+    let uses_default_listen_addr = listen_addr.is_none();
+    let mut conf = conf.unwrap_or_else(|| self.conf.torrent.clone());
+    if let Some(priority) = priority {
+      conf.priority = priority;
+    }
+    let priority = conf.priority;
+    let listen_addr = listen_addr.unwrap_or(self.conf.engine.listen_addr);
 
-    // Allocate torrent on disk. This is an asynchronous process and we can
-    // start the torrent in the meantime.
-    //
-    // Technically we could have issues if the torrent connects to peers
-    // that send data before we manage to allocate the (empty) files on
-    // disk. However, this should be an extremely pathological case for
-    // 2 reasons:
-    // - Most torrents would be started without peers, so a torrent would
-    //   have to wait for peers from its tracker(s). This should be a
-    //   a sufficiently long time to allocate torrent on disk.
-    // - Then, even if we manage to connect peers quickly, testing shows
-    //   that they don't tend to unchoke use immediately.
-    //
-    // Thus there is little chance to receive data and thus cause a disk
-    // write or disk read immediately.
-    self.disk_tx.send(disk::Command::NewTorrent {
-      id,
-      storage_info,
-      piece_hashes: params.metainfo.pieces,
-      torrent_tx: torrent_tx.clone(),
-    })?;
+    // the torrent either has its metadata or fetches it for a magnet link,
+    // as which a bare info hash is handled, without any parameters
+    let source = match source {
+      TorrentSource::Metainfo(metainfo) => Ok(metainfo),
+      TorrentSource::Magnet(magnet) => Err(magnet),
+      TorrentSource::InfoHash(info_hash) => Err(Magnet::new(info_hash)),
+    };
+    let info_hash = match &source {
+      Ok(metainfo) => metainfo.info_hash,
+      Err(magnet) => magnet.info_hash,
+    };
+
+    let shutdown_token = CancellationToken::new();
+    let (name, join_handle, restart) = match source {
+      Ok(metainfo) => {
+        if let Some(timeout) = probe_trackers {
+          self.probe_trackers(
+            id,
+            metainfo.trackers.clone(),
+            timeout,
+            Arc::clone(&tracker_backend),
+          );
+        }
+        let name = metainfo.name.clone();
+        let download_dir = resume
+          .as_ref()
+          .and_then(|resume| resume.download_dir.clone());
+        let trackers = resume
+          .as_ref()
+          .and_then(|resume| resume.trackers.clone())
+          .unwrap_or_else(|| metainfo.trackers.clone());
+        let restart = RestartParams {
+          cmd_rx: Arc::clone(tokio::sync::OwnedMutexGuard::mutex(&torrent_rx)),
+          metainfo: metainfo.clone(),
+          conf: conf.clone(),
+          listen_addr,
+          download_dir: download_dir.clone(),
+          transport: Arc::clone(&transport),
+          tracker_backend: Arc::clone(&tracker_backend),
+          trackers,
+          changes: RuntimeChanges::default(),
+          resume: resume.as_deref().cloned(),
+          resume_rx: None,
+        };
+        let mut torrent = self
+          .setup
+          .new_torrent(
+            id,
+            metainfo,
+            conf,
+            listen_addr,
+            torrent_tx.clone(),
+            torrent_rx,
+            resume.map(|resume| *resume),
+            download_dir,
+            labels.clone(),
+            transport,
+            tracker_backend,
+            shutdown_token.clone(),
+          )
+          .map_err(|error| Error::Torrent { id, error })?;
+        let join_handle =
+          task::spawn(async move { torrent.start(&peers).await });
+        (name, join_handle, Some(restart))
+      }
+      Err(mut magnet) => {
+        let name = magnet
+          .name
+          .clone()
+          .unwrap_or_else(|| hex::encode(magnet.info_hash));
+        if let Some(timeout) = probe_trackers {
+          self.probe_trackers(
+            id,
+            magnet.trackers.clone(),
+            timeout,
+            Arc::clone(&tracker_backend),
+          );
+        }
+        // the pending torrent holds on to the peers, as they may be used to
+        // fetch the metadata
+        magnet.peers.extend_from_slice(&peers);
+
+        let pending = PendingTorrent::new(metadata::Params {
+          id,
+          magnet,
+          cmd_rx: torrent_rx,
+          conf,
+          listen_addr,
+          labels: labels.clone(),
+          client_id: self.setup.client_id,
+          transport: Arc::clone(&transport),
+          tracker_backend: Arc::clone(&tracker_backend),
+          http_client: self.setup.http_client.clone(),
+          udp_connections: self.setup.udp_connections.clone(),
+          dht: self.setup.dht.clone(),
+        });
+        let setup = self.setup.clone();
+        let torrent_tx = torrent_tx.clone();
+        let shutdown_token = shutdown_token.clone();
+        let join_handle = task::spawn(async move {
+          let fetched = match pending.fetch().await {
+            Some(fetched) => fetched,
+            // the torrent was shut down before its metadata arrived
+            None => return Ok(()),
+          };
+          // the torrent is listed by the info hash or the magnet link's
+          // display name until now, while its files are saved under the
+          // metainfo's name as the storage is only set up from here on
+          setup
+            .engine_tx
+            .send(Command::MetadataFetched {
+              id,
+              name: fetched.metainfo.name.clone(),
+            })
+            .ok();
 
-    let seeds = params.mode.seeds();
-    let join_handle = task::spawn(async move { torrent.start(&seeds).await });
+          let mut torrent = setup.new_torrent(
+            id,
+            fetched.metainfo,
+            fetched.conf,
+            fetched.listen_addr,
+            torrent_tx.clone(),
+            fetched.cmd_rx,
+            None,
+            None,
+            fetched.labels,
+            transport,
+            tracker_backend,
+            shutdown_token,
+          )?;
+          // apply the settings that were changed while fetching, these are
+          // processed before anything else once the torrent runs
+          if fetched.is_paused {
+            torrent_tx.send(torrent::Command::Pause)?;
+          }
+          if fetched.is_queued {
+            torrent_tx.send(torrent::Command::SetQueued(true))?;
+          }
+          if fetched.external_port.is_some() {
+            torrent_tx
+              .send(torrent::Command::SetExternalPort(fetched.external_port))?;
+          }
+          if let Some(len) = fetched.sample {
+            torrent_tx.send(torrent::Command::Sample(len))?;
+          }
+          torrent.start(&fetched.peers).await
+        });
+        (name, join_handle, None)
+      }
+    };
 
     self.torrents.insert(
       id,
       TorrentEntry {
+        name,
+        info_hash,
         tx: torrent_tx,
+        shutdown_token,
         join_handle: Some(join_handle),
+        is_seed: false,
+        is_queued: false,
+        heartbeat: Heartbeat::default(),
+        restart,
+        uses_default_conf,
+        uses_default_listen_addr,
+        labels,
+        priority,
+        last_tick: None,
       },
     );
+    self
+      .setup
+      .torrent_count
+      .store(self.torrents.len(), Ordering::Relaxed);
 
-    Ok(())
+    self.update_queue()
   }
 
-  async fn shutdown(&mut self) -> EngineResult<()> {
-    log::info!("Shutting down engine");
+  /// Applies the new configuration to the engine, its disk task, and its
+  /// torrents.
+  ///
+  /// The default torrent configuration and listen address only apply to the
+  /// torrents that were created without their own. The client id, the
+  /// download directory, the tracker HTTP configuration and the source
+  /// address only apply to torrents created from now on, while the DHT
+  /// configuration, and the source address the DHT node is bound to, only
+  /// apply once the engine is restarted.
+  fn reload_conf(&mut self, conf: Conf) -> EngineResult<()> {
+    log::info!("Reloading engine configuration");
+    let old = std::mem::replace(&mut self.conf, conf);
+    let conf = &self.conf.engine;
 
-    // tell all torrents to shut down and join their tasks
-    for torrent in self.torrents.values_mut() {
-      // the torrent task may no longer be running, so don't panic here
-      torrent.tx.send(torrent::Command::Shutdown).ok();
+    if conf.tracker_http != old.engine.tracker_http
+      || conf.source_addr != old.engine.source_addr
+    {
+      self.setup.http_client =
+        HttpClient::new(&conf.tracker_http, conf.source_addr)?;
     }
 
-    for torrent in self.torrents.values_mut() {
-      // TODO: if torrent task is not running, does this panic.
-      if let Err(e) = torrent
-        .join_handle
-        .take()
-        .expect("torrent join handle missing")
-        .await
-        .expect("task error")
-      {
-        log::error!("Torrent error: {}", e);
-      }
+    if conf.disk != old.engine.disk {
+      self.disk_tx.send(disk::Command::SetConf(conf.disk))?;
     }
 
-    // send a shutdown command to disk
-    self.disk_tx.send(disk::Command::Shutdown)?;
-    // and join on its handle
+    self.setup.client_id = conf.client_id;
+    self.setup.download_dir = conf.download_dir.clone();
+    self.set_max_connected_peer_count(
+      old.engine.max_connected_peer_count,
+      conf.max_connected_peer_count,
+    );
+    // the torrents' peer sessions pick up the new limits right away
+    let now = std::time::Instant::now();
     self
-      .disk_join_handle
-      .take()
-      .expect("disk join handle missing")
-      .await
-      .expect("disk task has panicked")
-      .map_err(Error::from)?;
+      .setup
+      .download_limiter
+      .lock()
+      .unwrap()
+      .set_rate(conf.download_rate_limit, now);
+    self
+      .setup
+      .upload_limiter
+      .lock()
+      .unwrap()
+      .set_rate(conf.upload_rate_limit, now);
 
-    Ok(())
-  }
-}
+    if conf.watch_dir != old.engine.watch_dir {
+      if let Some(watch_dir) = self.watch_dir.take() {
+        watch_dir.abort();
+      }
+      self.watch_dir = conf.watch_dir.clone().map(|watch_dir| {
+        watch_dir::spawn(
+          watch_dir,
+          self.setup.engine_tx.clone(),
+          self.alert_tx.clone(),
+        )
+      });
+    }
 
-/// A handle to the currently running torrent engine.
-pub struct EngineHandle {
-  tx: Sender,
-  join_handle: Option<JoinHandle>,
-}
+    let listen_addr = conf.listen_addr;
+    let is_listen_addr_changed = listen_addr != old.engine.listen_addr;
+    for torrent in self.torrents.values_mut() {
+      // the torrent task may no longer be running, so don't fail here
+      if torrent.uses_default_conf {
+        let conf = TorrentConf {
+          priority: torrent.priority,
+          ..self.conf.torrent.clone()
+        };
+        torrent
+          .tx
+          .send(torrent::Command::SetConf(conf.clone()))
+          .ok();
+        if let Some(restart) = &mut torrent.restart {
+          restart.conf = conf;
+        }
+      }
+      if torrent.uses_default_listen_addr && is_listen_addr_changed {
+        torrent
+          .tx
+          .send(torrent::Command::SetListenAddr(listen_addr))
+          .ok();
+        if let Some(restart) = &mut torrent.restart {
+          restart.listen_addr = listen_addr;
+        }
+      }
+    }
 
-impl EngineHandle {
-  /// Creates and starts a torrent, if its metainfo is valid.
+    // the active torrent limits may have changed
+    self.update_queue()
+  }
+
+  /// Changes the number of the engine's connection permits.
   ///
-  /// If successful, it returns the id of the torrent.
-  /// This id can be used to identify the torrent when
-  /// issuing further commands to engine.
-  pub fn create_torrent(
+  /// Permits held by running peer sessions can't be taken back, so when the
+  /// limit is lowered, the surplus permits are removed as they are released.
+  fn set_max_connected_peer_count(&self, old_count: usize, new_count: usize) {
+    self
+      .setup
+      .connection_permit_count
+      .store(new_count, Ordering::Relaxed);
+    if new_count > old_count {
+      self
+        .setup
+        .connection_permits
+        .add_permits(new_count - old_count);
+    } else if new_count < old_count {
+      let permits = Arc::clone(&self.setup.connection_permits);
+      // the counts are validated to be at most `Semaphore::MAX_PERMITS`
+      let surplus = (old_count - new_count) as u32;
+      task::spawn(async move {
+        if let Ok(permits) = permits.acquire_many_owned(surplus).await {
+          permits.forget();
+        }
+      });
+    }
+  }
+
+  /// Runs the torrent's own completion command, or else the engine's
+  /// completion hook, if either is set.
+  fn run_completion_hook(
     &self,
-    params: TorrentParams,
-  ) -> EngineResult<TorrentId> {
-    log::trace!("Creating torrent");
-    let id = TorrentId::new();
-    self.tx.send(Command::CreateTorrent {
+    id: TorrentId,
+    path: PathBuf,
+    completion_command: Option<Vec<String>>,
+  ) {
+    let Some(torrent) = self.torrents.get(&id) else {
+      return;
+    };
+    let hook = match completion_command {
+      Some(args) => CompletionHook::Command(args),
+      None => match &self.conf.engine.completion_hook {
+        Some(hook) => hook.clone(),
+        None => return,
+      },
+    };
+    let completed = CompletedTorrent {
       id,
-      params: Box::new(params),
-    })?;
-    Ok(id)
+      name: torrent.name.clone(),
+      path,
+    };
+    hook.run(completed, self.alert_tx.clone());
   }
 
-  /// Gracefully shuts down the engine and waits for all
+  /// Probes the torrent's trackers concurrently in a separate task, posting
+  /// an alert for each one that is unreachable.
+  fn probe_trackers(
+    &self,
+    id: TorrentId,
+    urls: Vec<Url>,
+    timeout: Duration,
+    tracker_backend: Arc<dyn TrackerBackend>,
+  ) {
+    let alert_tx = self.alert_tx.clone();
+    let client = self.setup.http_client.clone();
+    let udp_connections = self.setup.udp_connections.clone();
+    task::spawn(async move {
+      let probes = urls
+        .into_iter()
+        .filter(|url| tracker_backend.supports(url))
+        .map(|url| {
+          let tracker =
+            tracker_backend.new_client(url.clone(), &client, &udp_connections);
+          async move { (url, tracker.probe(timeout).await) }
+        });
+      for (url, result) in future::join_all(probes).await {
+        match result {
+          Ok(()) => log::debug!("Torrent {} tracker {} is reachable", id, url),
+          Err(error) => {
+            log::warn!(
+              "Torrent {} tracker {} is unreachable: {}",
+              id,
+              url,
+              error
+            );
+            alert_tx
+              .send(Alert::TrackerUnreachable { id, url, error })
+              .ok();
+          }
+        }
+      }
+    });
+  }
+
+  /// Totals the latest ticks of all torrents and posts them to the user.
+  fn post_session_stats(&self) -> EngineResult<()> {
+    let mut stats = SessionStats {
+      disk_queue_len: self.disk_queue_len.load(Ordering::Relaxed),
+      torrent_count: self.torrents.len(),
+      ..Default::default()
+    };
+    for torrent in self.torrents.values() {
+      if torrent.is_queued {
+        stats.queued_count += 1;
+      }
+      let Some(tick) = torrent.last_tick else {
+        continue;
+      };
+      stats.download_rate += tick.download_rate;
+      stats.upload_rate += tick.upload_rate;
+      stats.peer_count += tick.peer_count;
+      match tick.state {
+        TorrentState::FetchingMetadata => {}
+        TorrentState::Checking => stats.checking_count += 1,
+        TorrentState::Downloading => stats.downloading_count += 1,
+        TorrentState::Seeding => stats.seeding_count += 1,
+        TorrentState::Paused => stats.paused_count += 1,
+        // counted by the engine's own queue above
+        TorrentState::Queued => {}
+      }
+    }
+    self.alert_tx.send(Alert::SessionStats(Box::new(stats)))?;
+    Ok(())
+  }
+
+  /// Checks whether the torrents and the disk task acknowledged their last
+  /// ping, reporting the ones that stalled, and pings them again.
+  fn check_health(&mut self) -> EngineResult<()> {
+    let now = Instant::now();
+    let conf = self.conf.engine.watchdog;
+
+    let mut stalled_torrents = Vec::new();
+    for (id, torrent) in self.torrents.iter_mut() {
+      if let Some(elapsed) = torrent.heartbeat.check(now, conf.stall_timeout) {
+        log::error!(
+          "Torrent {} ({}) stalled: unresponsive for {:?}, task finished: {}, \
+          seed: {}, queued: {}",
+          id,
+          torrent.name,
+          elapsed,
+          torrent
+            .join_handle
+            .as_ref()
+            .is_none_or(|join_handle| join_handle.is_finished()),
+          torrent.is_seed,
+          torrent.is_queued,
+        );
+        self.alert_tx.send(Alert::TaskStalled {
+          component: Component::Torrent(*id),
+        })?;
+        stalled_torrents.push(*id);
+      }
+      if let Some(ping) = torrent.heartbeat.next_ping(now) {
+        // the torrent task may no longer be running
+        torrent.tx.send(torrent::Command::Ping(ping)).ok();
+      }
+      if conf.restart_stalled_torrents {
+        if let Some(restart) = &mut torrent.restart {
+          restart.update_resume_data(&torrent.tx);
+        }
+      }
+    }
+
+    if let Some(elapsed) = self.disk_heartbeat.check(now, conf.stall_timeout) {
+      log::error!(
+        "Disk task stalled: unresponsive for {:?}, task finished: {}, \
+        torrent count: {}",
+        elapsed,
+        self
+          .disk_join_handle
+          .as_ref()
+          .is_none_or(|join_handle| join_handle.is_finished()),
+        self.torrents.len(),
+      );
+      self.alert_tx.send(Alert::TaskStalled {
+        component: Component::Disk,
+      })?;
+    }
+    if let Some(ping) = self.disk_heartbeat.next_ping(now) {
+      self.disk_tx.send(disk::Command::Ping(ping))?;
+    }
+
+    if conf.restart_stalled_torrents {
+      for id in stalled_torrents {
+        self.restart_torrent(id)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Shuts down the torrent's task and starts the torrent again from its
+  /// latest resume data and the changes made to it since it was created, on
+  /// the same command channel, so that the torrent's handles keep working.
+  ///
+  /// The stalled task is given a grace period to shut down along with its
+  /// peer sessions, after which it's aborted. Whether the torrent was paused
+  /// or queued is applied to the new task before it starts.
+  ///
+  /// Torrents that can't be restarted are left as they are.
+  fn restart_torrent(&mut self, id: TorrentId) -> EngineResult<()> {
+    let torrent = self.torrents.get_mut(&id).expect("torrent missing");
+    let params = match &mut torrent.restart {
+      Some(params) => params,
+      None => {
+        log::warn!("Cannot restart torrent {}: metadata was fetched", id);
+        return Ok(());
+      }
+    };
+    log::warn!("Restarting stalled torrent {}", id);
+
+    let old_join_handle = torrent.join_handle.take();
+    // the old torrent shuts down on the cancelled token alone, as a shutdown
+    // command would be left in the channel for the new torrent if the old
+    // one doesn't get to it
+    let shutdown_token = CancellationToken::new();
+    std::mem::replace(&mut torrent.shutdown_token, shutdown_token.clone())
+      .cancel();
+    // the stalled torrent can't be asked whether it's paused, so its latest
+    // tick tells
+    let is_paused = torrent
+      .last_tick
+      .is_some_and(|tick| tick.state == TorrentState::Paused);
+    let is_queued = torrent.is_queued;
+
+    let setup = self.setup.clone();
+    let cmd_rx = Arc::clone(&params.cmd_rx);
+    let torrent_tx = torrent.tx.clone();
+    let metainfo = params.metainfo.clone();
+    let conf = params.conf.clone();
+    let listen_addr = params.listen_addr;
+    let download_dir = params.download_dir.clone();
+    let labels = torrent.labels.clone();
+    let transport = Arc::clone(&params.transport);
+    let tracker_backend = Arc::clone(&params.tracker_backend);
+    let changes = params.changes.clone();
+    // the torrent may have answered the last request before it stalled
+    if let Some(Ok(resume)) =
+      params.resume_rx.take().map(|mut rx| rx.try_recv())
+    {
+      params.resume = Some(resume);
+    }
+    // without resume data the torrent's data is checked again
+    let mut resume = params.resume.clone().unwrap_or_else(|| ResumeData {
+      info_hash: metainfo.info_hash,
+      metainfo: metainfo.raw.clone(),
+      ..Default::default()
+    });
+    resume.trackers = Some(params.trackers.clone());
+    // the pieces were written before they were completed, and whether the
+    // torrent is paused is set on the torrent itself below
+    resume.recent_pieces.clear();
+    resume.is_paused = false;
+    let peers = std::mem::take(&mut resume.peers);
+    torrent.join_handle = Some(task::spawn(async move {
+      if let Some(mut join_handle) = old_join_handle {
+        if time::timeout(RESTART_GRACE_PERIOD, &mut join_handle)
+          .await
+          .is_err()
+        {
+          log::warn!("Torrent {} didn't shut down in time, aborting", id);
+          join_handle.abort();
+          join_handle.await.ok();
+        }
+      }
+      // the old torrent releases the channel along with its task
+      let cmd_rx = cmd_rx.lock_owned().await;
+      // the disk task processes commands in order, so the torrent's old
+      // entry is removed before the new one is allocated
+      setup.disk_tx.send(disk::Command::RemoveTorrent { id })?;
+
+      let mut torrent = setup.new_torrent(
+        id,
+        metainfo,
+        conf,
+        listen_addr,
+        torrent_tx.clone(),
+        cmd_rx,
+        Some(resume),
+        download_dir,
+        labels,
+        transport,
+        tracker_backend,
+        shutdown_token,
+      )?;
+      // the channel may already hold commands sent since the restart, so
+      // these are set on the torrent itself rather than sent after them
+      torrent.apply_changes(changes).await?;
+      torrent.set_stopped(is_paused, is_queued);
+      torrent.start(&peers).await
+    }));
+    torrent.heartbeat = Heartbeat::default();
+    Ok(())
+  }
+
+  /// Queues or dequeues torrents so that the number of active downloads and
+  /// seeds is within the configured limits.
+  ///
+  /// Higher priority torrents get slots first, and torrents of the same
+  /// priority get them in the order they were added, so a newly added
+  /// torrent only takes the slot of a running one of lower priority.
+  fn update_queue(&mut self) -> EngineResult<()> {
+    for is_seed in [false, true] {
+      let limit = if is_seed {
+        self.conf.engine.max_active_seeds
+      } else {
+        self.conf.engine.max_active_downloads
+      };
+
+      // torrent ids are assigned in increasing order
+      let mut ids: Vec<_> = self
+        .torrents
+        .iter()
+        .filter(|(_, torrent)| torrent.is_seed == is_seed)
+        .map(|(id, torrent)| (Reverse(torrent.priority), *id))
+        .collect();
+      ids.sort_unstable();
+
+      for (i, (_, id)) in ids.into_iter().enumerate() {
+        let should_queue = limit.is_some_and(|limit| i >= limit);
+        let torrent = self.torrents.get_mut(&id).expect("torrent missing");
+        if torrent.is_queued == should_queue {
+          continue;
+        }
+        torrent.is_queued = should_queue;
+
+        // the torrent task may no longer be running, so don't fail here
+        torrent
+          .tx
+          .send(torrent::Command::SetQueued(should_queue))
+          .ok();
+        if should_queue {
+          log::info!("Queueing torrent {}", id);
+          self.alert_tx.send(Alert::TorrentQueued(id))?;
+        } else {
+          log::info!("Dequeueing torrent {}", id);
+          self.alert_tx.send(Alert::TorrentDequeued(id))?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Collects the stats of all torrents, or only of those with the label if
+  /// given, and sends their summaries, ordered by torrent id, on the sender.
+  /// Torrents that have already stopped are omitted.
+  fn list_torrents(
+    &self,
+    label: Option<String>,
+    result_tx: oneshot::Sender<Vec<TorrentSummary>>,
+  ) {
+    let mut requests: Vec<_> = self
+      .torrents
+      .iter()
+      .filter(|(_, torrent)| {
+        label
+          .as_ref()
+          .is_none_or(|label| torrent.labels.contains(label))
+      })
+      .filter_map(|(id, torrent)| {
+        let (tx, rx) = oneshot::channel();
+        torrent.tx.send(torrent::Command::GetStats(tx)).ok()?;
+        Some((*id, torrent.name.clone(), rx))
+      })
+      .collect();
+    requests.sort_unstable_by_key(|(id, ..)| *id);
+
+    // torrents may take a while to respond (e.g. while announcing), so the
+    // responses are awaited in a separate task to not block the engine
+    task::spawn(async move {
+      let mut summaries = Vec::with_capacity(requests.len());
+      for (id, name, rx) in requests {
+        if let Ok(stats) = rx.await {
+          summaries.push(TorrentSummary::new(id, name, &stats));
+        }
+      }
+      result_tx.send(summaries).ok();
+    });
+  }
+
+  /// Replaces the torrent's labels, alerting the user if the torrent doesn't
+  /// exist.
+  fn set_labels(
+    &mut self,
+    id: TorrentId,
+    labels: Vec<String>,
+  ) -> EngineResult<()> {
+    let Some(torrent) = self.torrents.get_mut(&id) else {
+      log::warn!("Cannot set labels of torrent {}: not found", id);
+      self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+      return Ok(());
+    };
+    // the torrent task may no longer be running
+    torrent
+      .tx
+      .send(torrent::Command::SetLabels(labels.clone()))
+      .ok();
+    torrent.labels = labels;
+    Ok(())
+  }
+
+  /// Changes the torrent's priority and moves it in the queue accordingly.
+  fn set_priority(
+    &mut self,
+    id: TorrentId,
+    priority: Priority,
+  ) -> EngineResult<()> {
+    let Some(torrent) = self.torrents.get_mut(&id) else {
+      log::warn!("Cannot set priority of torrent {}: not found", id);
+      self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+      return Ok(());
+    };
+    log::info!("Setting torrent {} priority to {:?}", id, priority);
+    // the torrent task may no longer be running
+    torrent
+      .tx
+      .send(torrent::Command::SetPriority(priority))
+      .ok();
+    torrent.priority = priority;
+    if let Some(restart) = &mut torrent.restart {
+      restart.conf.priority = priority;
+    }
+    self.update_queue()
+  }
+
+  /// Collects the resume data of all torrents and saves them in the session
+  /// directory, sending the result on the sender.
+  ///
+  /// Torrents whose metadata is still being fetched, or that have already
+  /// stopped, are left out.
+  fn save_session(
+    &self,
+    dir: PathBuf,
+    result_tx: oneshot::Sender<EngineResult<()>>,
+  ) {
+    let mut requests: Vec<_> = self
+      .torrents
+      .iter()
+      .filter_map(|(id, torrent)| {
+        let (tx, rx) = oneshot::channel();
+        torrent.tx.send(torrent::Command::GetResumeData(tx)).ok()?;
+        Some((*id, rx))
+      })
+      .collect();
+    // torrents are restored in the same order, so they keep their place in
+    // the queue
+    requests.sort_unstable_by_key(|(id, ..)| *id);
+
+    // as with listing, the torrents and the disk writes are awaited in a
+    // separate task to not block the engine
+    task::spawn(async move {
+      let mut torrents = Vec::with_capacity(requests.len());
+      for (_, rx) in requests {
+        if let Ok(resume) = rx.await {
+          torrents.push(resume);
+        }
+      }
+      log::info!("Saving {} torrent(s) in session {:?}", torrents.len(), dir);
+      result_tx.send(session::save(&dir, torrents).await).ok();
+    });
+  }
+
+  /// Takes the torrent's state and then removes it, leaving its files
+  /// intact, sending the state on the sender.
+  async fn export_torrent(
+    &mut self,
+    id: TorrentId,
+    result_tx: oneshot::Sender<EngineResult<TorrentExport>>,
+  ) -> EngineResult<()> {
+    let torrent = match self.torrents.get(&id) {
+      Some(torrent) => torrent,
+      None => {
+        log::warn!("Cannot export torrent {}: not found", id);
+        result_tx.send(Err(Error::InvalidTorrentId)).ok();
+        return Ok(());
+      }
+    };
+    let (tx, rx) = oneshot::channel();
+    if torrent
+      .tx
+      .send(torrent::Command::GetResumeData(tx))
+      .is_err()
+    {
+      result_tx.send(Err(Error::Channel)).ok();
+      return Ok(());
+    }
+    // a torrent fetching its metadata drops the request, and a stuck one
+    // must not hold up the engine for longer than it would on shutdown
+    let grace_period = self.conf.engine.shutdown_grace_period;
+    let mut resume = match time::timeout(grace_period, rx).await {
+      Ok(Ok(resume)) => resume,
+      Ok(Err(_)) => {
+        result_tx.send(Err(Error::MetadataUnknown)).ok();
+        return Ok(());
+      }
+      Err(_) => {
+        log::warn!("Cannot export torrent {}: not responding", id);
+        result_tx.send(Err(Error::TorrentUnresponsive)).ok();
+        return Ok(());
+      }
+    };
+    // a piece is only completed once it's written, so none need verifying
+    // again
+    resume.recent_pieces.clear();
+
+    log::info!("Exporting torrent {}", id);
+    self.remove_torrent(id, false).await?;
+    result_tx.send(Ok(TorrentExport { resume })).ok();
+    Ok(())
+  }
+
+  /// Shuts down the torrent, waits for its task to finish, up to the
+  /// shutdown grace period, and then removes it from disk, after which a
+  /// queued torrent may take its slot.
+  ///
+  /// The user is alerted if the torrent doesn't exist.
+  async fn remove_torrent(
+    &mut self,
+    id: TorrentId,
+    delete_data: bool,
+  ) -> EngineResult<()> {
+    let mut torrent = match self.torrents.remove(&id) {
+      Some(torrent) => torrent,
+      None => {
+        log::warn!("Cannot remove torrent {}: not found", id);
+        self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+        return Ok(());
+      }
+    };
+    log::info!("Removing torrent {}", id);
+    self
+      .setup
+      .torrent_count
+      .store(self.torrents.len(), Ordering::Relaxed);
+
+    // the torrent task may no longer be running, so don't panic here
+    torrent.shutdown_token.cancel();
+    torrent.tx.send(torrent::Command::Shutdown).ok();
+    // a stuck torrent is aborted after the grace period, so that it doesn't
+    // hold up the engine
+    if let Some(mut join_handle) = torrent.join_handle.take() {
+      let grace_period = self.conf.engine.shutdown_grace_period;
+      match time::timeout(grace_period, &mut join_handle).await {
+        Ok(Ok(Err(e))) => log::error!("Torrent {} error: {}", id, e),
+        Ok(Err(e)) => log::error!("Torrent {} task error: {}", id, e),
+        Ok(Ok(Ok(()))) => {}
+        Err(_) => {
+          log::warn!("Torrent {} didn't shut down in time, aborting", id);
+          join_handle.abort();
+          join_handle.await.ok();
+        }
+      }
+    }
+
+    // the disk task processes commands in order, so the torrent's pending
+    // writes are flushed before its files are closed
+    if delete_data {
+      self
+        .disk_tx
+        .send(disk::Command::DeleteTorrentFiles { id })?;
+    } else {
+      self.disk_tx.send(disk::Command::RemoveTorrent { id })?;
+    }
+
+    // the torrent's saved metainfo isn't needed by the next session
+    if let Some(dir) = &self.session_dir {
+      if let Err(e) = session::remove_metainfo(dir, torrent.info_hash).await {
+        log::warn!("Failed to remove torrent {} from session: {}", id, e);
+      }
+    }
+
+    // the removed torrent may have freed up a slot
+    self.update_queue()
+  }
+
+  /// Shuts down all torrents and then the disk task, waiting for each up to
+  /// the grace period before aborting the tasks that are still running.
+  ///
+  /// The torrents share a single grace period, after which the disk task
+  /// gets its own to flush the torrents' pending writes.
+  async fn shutdown(&mut self, grace_period: Duration) -> EngineResult<()> {
+    log::info!("Shutting down engine");
+
+    // no new torrents are added from now on
+    if let Some(watch_dir) = self.watch_dir.take() {
+      watch_dir.abort();
+    }
+
+    // tell all torrents to shut down and join their tasks
+    for torrent in self.torrents.values_mut() {
+      // the torrent task may no longer be running, so don't panic here
+      torrent.shutdown_token.cancel();
+      torrent.tx.send(torrent::Command::Shutdown).ok();
+    }
+
+    let deadline = Instant::now() + grace_period;
+    for (id, torrent) in self.torrents.iter_mut() {
+      let mut join_handle = match torrent.join_handle.take() {
+        Some(join_handle) => join_handle,
+        None => continue,
+      };
+      match time::timeout_at(deadline, &mut join_handle).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => log::error!("Torrent {} error: {}", id, e),
+        Ok(Err(e)) => log::error!("Torrent {} task error: {}", id, e),
+        Err(_) => {
+          log::warn!("Torrent {} didn't shut down in time, aborting", id);
+          join_handle.abort();
+        }
+      }
+    }
+
+    // the torrents no longer look up peers, so the DHT node can stop
+    if let Some(dht) = &self.setup.dht {
+      dht.shutdown();
+    }
+    if let Some(mut join_handle) = self.dht_join_handle.take() {
+      if time::timeout(grace_period, &mut join_handle).await.is_err() {
+        log::warn!("DHT task didn't shut down in time, aborting");
+        join_handle.abort();
+      }
+    }
+
+    // the disk task processes commands in order, so the pending writes are
+    // flushed before it shuts down
+    self.disk_tx.send(disk::Command::Shutdown)?;
+    if let Some(mut join_handle) = self.disk_join_handle.take() {
+      match time::timeout(grace_period, &mut join_handle).await {
+        Ok(Ok(result)) => result?,
+        Ok(Err(e)) => {
+          log::error!("Disk task error: {}", e);
+          return Ok(());
+        }
+        Err(_) => {
+          log::warn!("Disk task didn't shut down in time, aborting");
+          join_handle.abort();
+          return Ok(());
+        }
+      }
+    }
+
+    // all writes were flushed, so nothing needs to be verified on restoring
+    if let Some(dir) = &self.session_dir {
+      session::mark_stopped(dir)?;
+    }
+
+    Ok(())
+  }
+}
+
+/// A handle to the currently running torrent engine.
+///
+/// Dropping the handle without calling [`Self::shutdown`] still shuts the
+/// engine down gracefully, though without waiting for it. To leave the
+/// engine running instead, use [`Self::detach`].
+pub struct EngineHandle {
+  tx: Sender,
+  join_handle: Option<JoinHandle>,
+  memory: Arc<MemoryCounters>,
+}
+
+impl EngineHandle {
+  /// Creates and starts a torrent, if its metainfo and configuration
+  /// override, if any, are valid.
+  ///
+  /// If successful, it returns a handle to the torrent, through which the
+  /// torrent can be controlled directly. The handle's id can be used to
+  /// identify the torrent when issuing further commands to engine.
+  pub fn create_torrent(
+    &self,
+    mut params: TorrentParams,
+  ) -> EngineResult<TorrentHandle> {
+    log::trace!("Creating torrent");
+    if let Some(conf) = &params.conf {
+      conf.validate()?;
+    }
+    let tracker_backend = Arc::clone(
+      params
+        .tracker_backend
+        .get_or_insert_with(|| Arc::new(DefaultTrackerBackend)),
+    );
+    let id = TorrentId::new();
+    // the channel is created here rather than by the torrent so that the
+    // handle can be returned right away
+    let (torrent_tx, torrent_rx) = torrent::channel();
+    self.tx.send(Command::CreateTorrent {
+      id,
+      params: Box::new(params),
+      torrent_tx: torrent_tx.clone(),
+      torrent_rx,
+      resume: None,
+    })?;
+    Ok(TorrentHandle::new(
+      id,
+      torrent_tx,
+      self.tx.clone(),
+      tracker_backend,
+    ))
+  }
+
+  /// Creates a torrent from its state saved in a session or exported from
+  /// another engine.
+  fn restore_torrent(
+    &self,
+    metainfo: Metainfo,
+    resume: ResumeData,
+  ) -> EngineResult<TorrentHandle> {
+    let id = TorrentId::new();
+    let (torrent_tx, torrent_rx) = torrent::channel();
+    let tracker_backend: Arc<dyn TrackerBackend> =
+      Arc::new(DefaultTrackerBackend);
+    let params = TorrentParams {
+      conf: Some(resume.conf.clone()),
+      labels: resume.labels.clone(),
+      peers: resume.peers.clone(),
+      tracker_backend: Some(Arc::clone(&tracker_backend)),
+      ..TorrentParams::new(metainfo)
+    };
+    self.tx.send(Command::CreateTorrent {
+      id,
+      params: Box::new(params),
+      torrent_tx: torrent_tx.clone(),
+      torrent_rx,
+      resume: Some(Box::new(resume)),
+    })?;
+    Ok(TorrentHandle::new(
+      id,
+      torrent_tx,
+      self.tx.clone(),
+      tracker_backend,
+    ))
+  }
+
+  /// Reloads the engine's configuration, if valid, without restarting the
+  /// engine.
+  ///
+  /// Changed limits take effect right away, and torrents created without
+  /// their own configuration or listen address pick up the new defaults.
+  /// The client id and download directory only apply to new torrents.
+  pub fn reload_conf(&self, conf: Conf) -> EngineResult<()> {
+    conf.validate()?;
+    self.tx.send(Command::ReloadConf(Box::new(conf)))?;
+    Ok(())
+  }
+
+  /// Saves all torrents in the session directory, from which they can be
+  /// restored with [`spawn_with_session`].
+  ///
+  /// Each torrent's metainfo, own pieces, transfer totals, and configuration
+  /// are saved, so that restored torrents continue where they left off.
+  /// Torrents whose metadata is still being fetched are not saved.
+  pub async fn save_session(
+    &self,
+    dir: impl Into<PathBuf>,
+  ) -> EngineResult<()> {
+    let (result_tx, rx) = oneshot::channel();
+    self.tx.send(Command::SaveSession {
+      dir: dir.into(),
+      result_tx,
+    })?;
+    rx.await?
+  }
+
+  /// Hands the torrent off to another engine: its state is returned and the
+  /// torrent is removed from this engine, leaving its files intact.
+  ///
+  /// The export may be imported with [`Self::import_torrent`] by another
+  /// engine in the same process, or encoded with [`TorrentExport::to_bytes`]
+  /// for an engine in another process, e.g. the next version of a daemon.
+  /// The other engine should use the same download directory, so that it
+  /// finds the torrent's files where the export says its pieces are.
+  ///
+  /// The torrent's peer sessions can't be moved, but the peers are
+  /// reconnected by the importing engine, which doesn't wait for a tracker
+  /// announce to find them.
+  ///
+  /// An [`Error::InvalidTorrentId`] is returned if the torrent doesn't
+  /// exist, and an [`Error::MetadataUnknown`] if it's still fetching its
+  /// metadata, in which case it's left running.
+  pub async fn export_torrent(
+    &self,
+    id: TorrentId,
+  ) -> EngineResult<TorrentExport> {
+    log::trace!("Exporting torrent {}", id);
+    let (result_tx, rx) = oneshot::channel();
+    self.tx.send(Command::ExportTorrent { id, result_tx })?;
+    rx.await?
+  }
+
+  /// Creates a torrent handed off by another engine with
+  /// [`Self::export_torrent`], which continues with the pieces, transfer
+  /// totals, configuration, labels, and peers it had there.
+  ///
+  /// The torrent's data is not checked again, unless it was still being
+  /// checked when exported.
+  pub fn import_torrent(
+    &self,
+    export: TorrentExport,
+  ) -> EngineResult<TorrentHandle> {
+    log::trace!("Importing torrent");
+    // the metainfo was already validated by the exporting engine or when
+    // decoding the export
+    let metainfo = Metainfo::from_bytes(&export.resume.metainfo)
+      .map_err(|_| Error::InvalidSession)?;
+    self.restore_torrent(metainfo, export.resume)
+  }
+
+  /// Shuts down the torrent and removes it from the engine.
+  ///
+  /// If `delete_data` is set, the torrent's downloaded files are deleted from
+  /// disk as well, otherwise they are left intact. The torrent's metainfo
+  /// saved in the engine's session directory is always deleted.
+  ///
+  /// If the torrent doesn't exist, an [`Alert::Error`] with
+  /// [`Error::InvalidTorrentId`] is posted.
+  pub fn remove_torrent(
+    &self,
+    id: TorrentId,
+    delete_data: bool,
+  ) -> EngineResult<()> {
+    log::trace!("Removing torrent {}", id);
+    self.tx.send(Command::RemoveTorrent { id, delete_data })?;
+    Ok(())
+  }
+
+  /// Returns a summary of each torrent in the engine, ordered by the time
+  /// they were created.
+  pub async fn list(&self) -> EngineResult<Vec<TorrentSummary>> {
+    let (result_tx, rx) = oneshot::channel();
+    self.tx.send(Command::ListTorrents {
+      label: None,
+      result_tx,
+    })?;
+    Ok(rx.await?)
+  }
+
+  /// Returns a summary of each torrent with the given label, ordered by the
+  /// time they were created.
+  pub async fn list_labeled(
+    &self,
+    label: impl Into<String>,
+  ) -> EngineResult<Vec<TorrentSummary>> {
+    let (result_tx, rx) = oneshot::channel();
+    self.tx.send(Command::ListTorrents {
+      label: Some(label.into()),
+      result_tx,
+    })?;
+    Ok(rx.await?)
+  }
+
+  /// Replaces the labels attached to the torrent, which apply to listing
+  /// torrents right away.
+  ///
+  /// If the torrent doesn't exist, an [`Alert::Error`] with
+  /// [`Error::InvalidTorrentId`] is posted.
+  pub fn set_labels(
+    &self,
+    id: TorrentId,
+    labels: Vec<String>,
+  ) -> EngineResult<()> {
+    self.tx.send(Command::SetLabels { id, labels })?;
+    Ok(())
+  }
+
+  /// Changes the torrent's priority while it's running, see
+  /// [`TorrentHandle::set_priority`].
+  ///
+  /// If the torrent doesn't exist, an [`Alert::Error`] with
+  /// [`Error::InvalidTorrentId`] is posted.
+  pub fn set_priority(
+    &self,
+    id: TorrentId,
+    priority: Priority,
+  ) -> EngineResult<()> {
+    self.tx.send(Command::SetPriority { id, priority })?;
+    Ok(())
+  }
+
+  /// Returns the bytes currently held by the engine's buffers: the disk
+  /// write buffers and read caches, and the blocks peer sessions hold back
+  /// from their peers.
+  ///
+  /// The figures are updated as the buffers change, so this doesn't go
+  /// through the engine task.
+  pub fn memory_stats(&self) -> MemoryStats {
+    self.memory.stats()
+  }
+
+  /// Gracefully shuts down the engine and waits for all
   /// its torrents to do the same.
   ///
+  /// Torrents, and then the disk task, that don't stop within
+  /// [`EngineConf::shutdown_grace_period`] are aborted, in which case
+  /// trackers may not be told that we're leaving and pending writes may be
+  /// lost.
+  ///
   /// # Panics
   ///
   /// This method panics if the engine has already been
   /// shut down.
-  pub async fn shutdown(mut self) -> EngineResult<()> {
+  ///
+  /// [`EngineConf::shutdown_grace_period`]:
+  /// crate::conf::EngineConf::shutdown_grace_period
+  pub async fn shutdown(self) -> EngineResult<()> {
     log::trace!("Shutting down engine task");
-    self.tx.send(Command::Shutdown)?;
+    self.join(Command::Shutdown).await
+  }
+
+  /// Shuts down the engine without waiting for its torrents and disk task,
+  /// aborting them right away.
+  ///
+  /// Trackers are not told that we're leaving and pending writes are lost,
+  /// so this should only be used when [`Self::shutdown`] takes too long.
+  ///
+  /// # Panics
+  ///
+  /// This method panics if the engine has already been
+  /// shut down.
+  pub async fn shutdown_now(self) -> EngineResult<()> {
+    log::trace!("Shutting down engine task now");
+    self.join(Command::ShutdownNow).await
+  }
+
+  /// Consumes the handle while leaving the engine running, e.g. for a daemon
+  /// that keeps seeding for the lifetime of the process.
+  ///
+  /// The engine can no longer be shut down gracefully, and it keeps running
+  /// until the runtime is shut down.
+  pub fn detach(mut self) {
+    log::trace!("Detaching engine task");
+    self.join_handle = None;
+  }
+
+  /// Sends the engine the shutdown command and waits for it to stop.
+  async fn join(mut self, cmd: Command) -> EngineResult<()> {
+    self.tx.send(cmd)?;
     if let Err(e) = self
       .join_handle
       .take()
@@ -361,3 +2277,702 @@ impl EngineHandle {
     Ok(())
   }
 }
+
+impl Drop for EngineHandle {
+  /// Starts a graceful shutdown of the engine, unless it was already shut
+  /// down or detached. The engine stops in the background, as dropping
+  /// can't wait for it.
+  fn drop(&mut self) {
+    if self.join_handle.is_some() {
+      log::info!("Engine handle dropped, shutting down engine");
+      // the engine may have stopped on its own
+      self.tx.send(Command::Shutdown).ok();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::tempdir;
+  use tokio::time::timeout;
+
+  use super::*;
+
+  /// The engine's alert channel closes once the engine task stops.
+  async fn is_stopped(alert_rx: &mut AlertReceiver) -> bool {
+    timeout(Duration::from_secs(1), alert_rx.recv())
+      .await
+      .is_ok_and(|alert| alert.is_none())
+  }
+
+  #[tokio::test]
+  async fn should_shut_down_engine_on_drop() {
+    let dir = tempdir().unwrap();
+    let (engine, mut alert_rx) = spawn(Conf::new(dir.path())).unwrap();
+    drop(engine);
+    assert!(is_stopped(&mut alert_rx).await);
+  }
+
+  /// Returns the next queue alert, skipping any other alert.
+  async fn next_queue_alert(alert_rx: &mut AlertReceiver) -> Alert {
+    loop {
+      let alert = timeout(Duration::from_secs(1), alert_rx.recv())
+        .await
+        .expect("no queue alert")
+        .expect("engine stopped");
+      if matches!(alert, Alert::TorrentQueued(_) | Alert::TorrentDequeued(_)) {
+        return alert;
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn should_give_active_slots_by_priority() {
+    let dir = tempdir().unwrap();
+    let mut conf = Conf::new(dir.path());
+    conf.engine.max_active_downloads = Some(1);
+    let (engine, mut alert_rx) = spawn(conf).unwrap();
+
+    // torrents fetching their metadata take a download slot
+    let low = engine.create_torrent(TorrentParams::new([1; 20])).unwrap();
+    let high = engine
+      .create_torrent(TorrentParams {
+        priority: Some(Priority::High),
+        ..TorrentParams::new([2; 20])
+      })
+      .unwrap();
+    assert!(matches!(
+      next_queue_alert(&mut alert_rx).await,
+      Alert::TorrentQueued(id) if id == low.id()
+    ));
+
+    high.set_priority(Priority::Low).unwrap();
+    assert!(matches!(
+      next_queue_alert(&mut alert_rx).await,
+      Alert::TorrentDequeued(id) if id == low.id()
+    ));
+    assert!(matches!(
+      next_queue_alert(&mut alert_rx).await,
+      Alert::TorrentQueued(id) if id == high.id()
+    ));
+  }
+
+  #[tokio::test]
+  async fn should_keep_queueing_apart_from_pausing() {
+    let dir = tempdir().unwrap();
+    let mut conf = Conf::new(dir.path());
+    conf.engine.max_active_downloads = Some(1);
+    let (engine, mut alert_rx) = spawn(conf).unwrap();
+    let active = engine.create_torrent(TorrentParams::new([1; 20])).unwrap();
+    let queued = engine.create_torrent(TorrentParams::new([2; 20])).unwrap();
+    assert!(matches!(
+      next_queue_alert(&mut alert_rx).await,
+      Alert::TorrentQueued(id) if id == queued.id()
+    ));
+
+    // resuming a queued torrent doesn't take it past the slot limit
+    queued.pause().unwrap();
+    queued.resume().unwrap();
+    assert_eq!(queued.stats().await.unwrap().state, TorrentState::Queued);
+
+    // and dequeueing a paused torrent doesn't resume it
+    queued.pause().unwrap();
+    engine.remove_torrent(active.id(), false).unwrap();
+    assert!(matches!(
+      next_queue_alert(&mut alert_rx).await,
+      Alert::TorrentDequeued(id) if id == queued.id()
+    ));
+    assert_eq!(queued.stats().await.unwrap().state, TorrentState::Paused);
+    queued.resume().unwrap();
+    assert_eq!(
+      queued.stats().await.unwrap().state,
+      TorrentState::FetchingMetadata
+    );
+  }
+
+  #[tokio::test]
+  async fn should_post_session_stats() {
+    let dir = tempdir().unwrap();
+    let mut conf = Conf::new(dir.path());
+    conf.engine.session_stats_interval = Some(Duration::from_millis(10));
+    conf.engine.max_active_downloads = Some(1);
+    let (engine, mut alert_rx) = spawn(conf).unwrap();
+    engine.create_torrent(TorrentParams::new([1; 20])).unwrap();
+    engine.create_torrent(TorrentParams::new([2; 20])).unwrap();
+
+    let stats = loop {
+      let alert = timeout(Duration::from_secs(1), alert_rx.recv())
+        .await
+        .expect("no session stats")
+        .expect("engine stopped");
+      match alert {
+        Alert::SessionStats(stats) if stats.queued_count > 0 => break stats,
+        _ => {}
+      }
+    };
+    assert_eq!(stats.torrent_count, 2);
+    assert_eq!(stats.queued_count, 1);
+  }
+
+  #[tokio::test]
+  async fn should_mark_session_stopped_on_clean_shutdown() {
+    let dir = tempdir().unwrap();
+    let session_dir = dir.path().join("session");
+    let (engine, _, _) =
+      spawn_with_session(Conf::new(dir.path()), &session_dir).unwrap();
+    engine.shutdown().await.unwrap();
+    assert!(!session::mark_running(&session_dir).unwrap());
+  }
+
+  #[tokio::test]
+  async fn should_remove_torrent_from_session() {
+    let dir = tempdir().unwrap();
+    let session_dir = dir.path().join("session");
+    let (engine, _alert_rx, _) =
+      spawn_with_session(Conf::new(dir.path()), &session_dir).unwrap();
+    let metainfo = partially_downloaded_torrent(dir.path());
+    let metainfo_path =
+      session_dir.join(format!("{}.torrent", hex::encode(metainfo.info_hash)));
+    let torrent = engine.create_torrent(TorrentParams::new(metainfo)).unwrap();
+    engine.save_session(&session_dir).await.unwrap();
+    assert!(metainfo_path.exists());
+
+    engine.remove_torrent(torrent.id(), false).unwrap();
+    timeout(Duration::from_secs(2), async {
+      while metainfo_path.exists() {
+        time::sleep(Duration::from_millis(10)).await;
+      }
+    })
+    .await
+    .expect("metainfo of removed torrent wasn't deleted");
+    engine.shutdown().await.unwrap();
+    assert!(session::load(&session_dir).unwrap().is_empty());
+  }
+
+  /// Returns a single file torrent of 3 pieces, of which all but the second
+  /// are already in the directory.
+  fn partially_downloaded_torrent(dir: &Path) -> Metainfo {
+    use sha1::{Digest, Sha1};
+
+    let mut data = vec![7; 40000];
+    let mut metainfo = b"d4:infod6:lengthi40000e4:name4:file\
+      12:piece lengthi16384e6:pieces60:"
+      .to_vec();
+    for piece in data.chunks(16384) {
+      metainfo.extend_from_slice(&Sha1::digest(piece));
+    }
+    metainfo.extend_from_slice(b"ee");
+    data[20000] = 0;
+    std::fs::write(dir.join("file"), &data).unwrap();
+    Metainfo::from_bytes(&metainfo).unwrap()
+  }
+
+  /// Returns a torrent announced to a tracker that accepts connections but
+  /// never responds, along with that tracker's listener.
+  async fn hung_tracker_torrent() -> (tokio::net::TcpListener, Metainfo) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let mut metainfo = format!(
+      "d8:announce{}:{}4:infod6:lengthi16384e4:name4:file\
+      12:piece lengthi16384e6:pieces20:",
+      url.len(),
+      url
+    )
+    .into_bytes();
+    metainfo.extend_from_slice(&[0; 20]);
+    metainfo.extend_from_slice(b"ee");
+    (listener, Metainfo::from_bytes(&metainfo).unwrap())
+  }
+
+  #[tokio::test]
+  async fn should_time_out_hung_tracker() {
+    let dir = tempdir().unwrap();
+    let (_listener, metainfo) = hung_tracker_torrent().await;
+    let mut conf = Conf::new(dir.path());
+    conf.torrent.tracker_timeout = Duration::from_millis(100);
+    let (engine, mut alert_rx) = spawn(conf).unwrap();
+    engine.create_torrent(TorrentParams::new(metainfo)).unwrap();
+
+    loop {
+      let alert = timeout(Duration::from_secs(2), alert_rx.recv())
+        .await
+        .expect("no tracker timeout")
+        .expect("engine stopped");
+      if let Alert::Error(Error::Tracker { error, .. }) = alert {
+        assert!(matches!(error, crate::error::TrackerError::Timeout));
+        break;
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn should_abandon_announce_on_shutdown() {
+    let dir = tempdir().unwrap();
+    let (listener, metainfo) = hung_tracker_torrent().await;
+    let mut conf = Conf::new(dir.path());
+    conf.engine.shutdown_grace_period = Duration::from_secs(1);
+    conf.torrent.tracker_timeout = Duration::from_secs(60);
+    let (engine, _alert_rx) = spawn(conf).unwrap();
+    engine.create_torrent(TorrentParams::new(metainfo)).unwrap();
+
+    // the started announce is in flight
+    let (_started, _) = timeout(Duration::from_secs(2), listener.accept())
+      .await
+      .expect("no announce")
+      .unwrap();
+
+    // it's given up on so that the exit is announced right away, rather
+    // than once the started announce times out
+    let shutdown = task::spawn(engine.shutdown());
+    let (stopped, _) = timeout(Duration::from_millis(500), listener.accept())
+      .await
+      .expect("announce not abandoned")
+      .unwrap();
+    let mut request = [0; 1024];
+    stopped.readable().await.unwrap();
+    let len = stopped.try_read(&mut request).unwrap();
+    let request = String::from_utf8_lossy(&request[..len]);
+    assert!(request.contains("event=stopped"), "{}", request);
+    shutdown.await.unwrap().unwrap();
+  }
+
+  #[tokio::test]
+  async fn should_announce_left_bytes_of_verified_pieces() {
+    let dir = tempdir().unwrap();
+    let (listener, metainfo) = hung_tracker_torrent().await;
+    let mut conf = Conf::new(dir.path());
+    conf.torrent.tracker_timeout = Duration::from_millis(100);
+    let (old, _old_alert_rx) = spawn(conf).unwrap();
+    let torrent = old.create_torrent(TorrentParams::new(metainfo)).unwrap();
+    let mut export = old.export_torrent(torrent.id()).await.unwrap();
+    old.shutdown().await.unwrap();
+
+    // more was downloaded than the torrent's length, e.g. because some of it
+    // failed verification, yet none of it was kept
+    export.resume.downloaded = 3 * 0x4000;
+    let (new, _new_alert_rx) = spawn(Conf::new(dir.path())).unwrap();
+    new.import_torrent(export).unwrap();
+
+    loop {
+      let (stream, _) = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .expect("no announce")
+        .unwrap();
+      let mut request = [0; 1024];
+      stream.readable().await.unwrap();
+      let len = stream.try_read(&mut request).unwrap_or(0);
+      let request = String::from_utf8_lossy(&request[..len]);
+      if request.contains("downloaded=49152") {
+        assert!(request.contains("left=16384"), "{}", request);
+        break;
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn should_post_playable_files_found_on_disk() {
+    let dir = tempdir().unwrap();
+    let metainfo = partially_downloaded_torrent(dir.path());
+    let mut conf = Conf::new(dir.path());
+    conf.torrent.alerts.playable_prefix = Some(16384);
+    let (engine, mut alert_rx) = spawn(conf).unwrap();
+    let torrent = engine.create_torrent(TorrentParams::new(metainfo)).unwrap();
+
+    // files aren't told playable unless the torrent is streamed
+    let not_streamed = timeout(Duration::from_millis(500), async {
+      loop {
+        match alert_rx.recv().await {
+          Some(Alert::FilePlayable { .. }) | None => break,
+          Some(_) => (),
+        }
+      }
+    })
+    .await;
+    assert!(
+      not_streamed.is_err(),
+      "file told playable without streaming"
+    );
+
+    torrent
+      .set_piece_deadline(1, Duration::from_secs(10))
+      .unwrap();
+    loop {
+      let alert = timeout(Duration::from_secs(1), alert_rx.recv())
+        .await
+        .expect("no playable file alert")
+        .expect("engine stopped");
+      if let Alert::FilePlayable { id, file_index } = alert {
+        assert_eq!(id, torrent.id());
+        assert_eq!(file_index, 0);
+        break;
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn should_return_file_progress() {
+    let dir = tempdir().unwrap();
+    let metainfo = partially_downloaded_torrent(dir.path());
+    let mut conf = Conf::new(dir.path());
+    conf.torrent.alerts.files = true;
+    let (engine, _alert_rx) = spawn(conf).unwrap();
+    let torrent = engine.create_torrent(TorrentParams::new(metainfo)).unwrap();
+
+    // wait for the data to be checked
+    let stats = loop {
+      let stats = torrent.stats().await.unwrap();
+      if stats.state != TorrentState::Checking {
+        break stats;
+      }
+      time::sleep(Duration::from_millis(10)).await;
+    };
+    let files = torrent.files().await.unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].info.path, PathBuf::from("file"));
+    assert_eq!(files[0].info.len, 40000);
+    assert_eq!(files[0].completed_bytes, 40000 - 16384);
+    assert_eq!(stats.files, Some(files));
+  }
+
+  #[tokio::test]
+  async fn should_hand_off_torrent_to_another_engine() {
+    // a single file torrent of 3 pieces
+    const METAINFO: &[u8] = b"d4:infod6:lengthi40000e4:name4:file\
+      12:piece lengthi16384e6:pieces60:\
+      aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbccccccccccccccccccccee";
+    let dir = tempdir().unwrap();
+    let (old, _old_alert_rx) = spawn(Conf::new(dir.path())).unwrap();
+    let (new, _new_alert_rx) = spawn(Conf::new(dir.path())).unwrap();
+
+    let torrent = old
+      .create_torrent(TorrentParams {
+        labels: vec!["linux-isos".to_owned()],
+        ..TorrentParams::new(Metainfo::from_bytes(METAINFO).unwrap())
+      })
+      .unwrap();
+    let export = old.export_torrent(torrent.id()).await.unwrap();
+    let export = TorrentExport::from_bytes(&export.to_bytes().unwrap());
+    new.import_torrent(export.unwrap()).unwrap();
+
+    assert!(old.list().await.unwrap().is_empty());
+    let torrents = new.list().await.unwrap();
+    assert_eq!(torrents.len(), 1);
+    assert_eq!(torrents[0].name, "file");
+    assert_eq!(torrents[0].labels, vec!["linux-isos".to_owned()]);
+
+    assert!(matches!(
+      old.export_torrent(torrent.id()).await,
+      Err(Error::InvalidTorrentId)
+    ));
+    // there's nothing to export before the metadata is fetched
+    let magnet = old.create_torrent(TorrentParams::new([1; 20])).unwrap();
+    assert!(matches!(
+      old.export_torrent(magnet.id()).await,
+      Err(Error::MetadataUnknown)
+    ));
+  }
+
+  #[tokio::test]
+  async fn should_import_torrent_from_moved_storage() {
+    let dir = tempdir().unwrap();
+    let moved_dir = dir.path().join("moved");
+    std::fs::create_dir(&moved_dir).unwrap();
+    let (old, mut alert_rx) = spawn(Conf::new(dir.path())).unwrap();
+    let torrent = old
+      .create_torrent(TorrentParams::new(partially_downloaded_torrent(
+        dir.path(),
+      )))
+      .unwrap();
+    torrent.move_storage(&moved_dir).unwrap();
+    loop {
+      let alert = timeout(Duration::from_secs(2), alert_rx.recv())
+        .await
+        .expect("storage not moved")
+        .expect("engine stopped");
+      if let Alert::StorageMoved { dir, .. } = alert {
+        assert_eq!(dir, moved_dir);
+        break;
+      }
+    }
+
+    /// Imports the torrent into a new engine and returns the bytes of its
+    /// file found once its data is checked, if it is.
+    async fn import(dir: &Path, export: TorrentExport) -> u64 {
+      let (engine, _alert_rx) = spawn(Conf::new(dir)).unwrap();
+      let torrent = engine.import_torrent(export).unwrap();
+      while torrent.stats().await.unwrap().state == TorrentState::Checking {
+        time::sleep(Duration::from_millis(10)).await;
+      }
+      torrent.files().await.unwrap()[0].completed_bytes
+    }
+
+    // the files are found where they were moved, without checking them
+    let export = old.export_torrent(torrent.id()).await.unwrap();
+    assert_eq!(export.resume.download_dir, Some(moved_dir.clone()));
+    assert_eq!(import(dir.path(), export.clone()).await, 40000 - 16384);
+    assert!(!dir.path().join("file").exists());
+
+    // files gone since the export are checked again
+    std::fs::remove_file(moved_dir.join("file")).unwrap();
+    assert_eq!(import(dir.path(), export).await, 0);
+  }
+
+  #[tokio::test]
+  async fn should_not_wait_for_stuck_torrent_to_export() {
+    let dir = tempdir().unwrap();
+    let mut conf = Conf::new(dir.path());
+    conf.engine.shutdown_grace_period = Duration::from_millis(100);
+    let (alert_tx, _alert_rx) = mpsc::unbounded_channel();
+    let (mut engine, _) = Engine::new(conf, alert_tx).unwrap();
+    let id = TorrentId::new();
+    let (torrent_tx, torrent_rx) = torrent::channel();
+    let params = TorrentParams::new(partially_downloaded_torrent(dir.path()));
+    engine
+      .create_torrent(id, Box::new(params), torrent_tx, torrent_rx, None)
+      .await
+      .unwrap();
+
+    // the torrent no longer reads its commands
+    let (stuck_tx, _stuck_rx) = torrent::channel();
+    engine.torrents.get_mut(&id).unwrap().tx = stuck_tx;
+
+    let (result_tx, result_rx) = oneshot::channel();
+    timeout(Duration::from_secs(2), engine.export_torrent(id, result_tx))
+      .await
+      .expect("engine blocked on stuck torrent")
+      .unwrap();
+    assert!(matches!(
+      result_rx.await.unwrap(),
+      Err(Error::TorrentUnresponsive)
+    ));
+    assert!(engine.torrents.contains_key(&id));
+  }
+
+  #[tokio::test]
+  async fn should_restart_torrent_on_same_channel() {
+    let dir = tempdir().unwrap();
+    let (alert_tx, _alert_rx) = mpsc::unbounded_channel();
+    let (mut engine, _) = Engine::new(Conf::new(dir.path()), alert_tx).unwrap();
+    let id = TorrentId::new();
+    let (torrent_tx, torrent_rx) = torrent::channel();
+    let params = TorrentParams::new(partially_downloaded_torrent(dir.path()));
+    engine
+      .create_torrent(
+        id,
+        Box::new(params),
+        torrent_tx.clone(),
+        torrent_rx,
+        None,
+      )
+      .await
+      .unwrap();
+    let handle = TorrentHandle::new(
+      id,
+      torrent_tx,
+      engine.setup.engine_tx.clone(),
+      Arc::new(DefaultTrackerBackend),
+    );
+
+    // the torrent was paused as of its latest tick, which the restarted
+    // torrent is too, and the handle obtained before still reaches it
+    handle.pause().unwrap();
+    assert_eq!(handle.stats().await.unwrap().state, TorrentState::Paused);
+    engine.torrents.get_mut(&id).unwrap().last_tick = Some(TorrentTick {
+      state: TorrentState::Paused,
+      download_rate: 0,
+      upload_rate: 0,
+      peer_count: 0,
+    });
+    engine.restart_torrent(id).unwrap();
+    let stats = timeout(Duration::from_secs(2), handle.stats())
+      .await
+      .expect("restarted torrent unresponsive")
+      .unwrap();
+    assert_eq!(stats.state, TorrentState::Paused);
+  }
+
+  #[tokio::test]
+  async fn should_restart_torrent_with_runtime_changes() {
+    use crate::torrent::{FilePriority, Limits};
+
+    let dir = tempdir().unwrap();
+    let mut conf = Conf::new(dir.path());
+    conf.engine.watchdog.restart_stalled_torrents = true;
+    let (alert_tx, _alert_rx) = mpsc::unbounded_channel();
+    let (mut engine, _) = Engine::new(conf, alert_tx).unwrap();
+    let id = TorrentId::new();
+    let (torrent_tx, torrent_rx) = torrent::channel();
+    let params = TorrentParams::new(partially_downloaded_torrent(dir.path()));
+    engine
+      .create_torrent(
+        id,
+        Box::new(params),
+        torrent_tx.clone(),
+        torrent_rx,
+        None,
+      )
+      .await
+      .unwrap();
+    let handle = TorrentHandle::new(
+      id,
+      torrent_tx.clone(),
+      engine.setup.engine_tx.clone(),
+      Arc::new(DefaultTrackerBackend),
+    );
+    let resume_data = || async {
+      let (tx, rx) = oneshot::channel();
+      torrent_tx.send(torrent::Command::GetResumeData(tx)).ok();
+      timeout(Duration::from_secs(2), rx)
+        .await
+        .expect("torrent unresponsive")
+        .unwrap()
+    };
+
+    let tracker: Url = "http://127.0.0.1:1/announce".parse().unwrap();
+    handle
+      .set_limits(Limits {
+        max_connected_peer_count: Some(3),
+        ..Default::default()
+      })
+      .unwrap();
+    handle.add_tracker(tracker.clone()).unwrap();
+    handle
+      .set_file_priorities(vec![FilePriority::High])
+      .unwrap();
+    // the engine task isn't running, so its commands are handled here
+    while let Ok(cmd) = engine.cmd_rx.try_recv() {
+      if let Command::TorrentChanged { id, cmd } = cmd {
+        let torrent = engine.torrents.get_mut(&id).unwrap();
+        torrent.restart.as_mut().unwrap().record_change(*cmd);
+      }
+    }
+    // the first health check requests the resume data, the next one takes it
+    let announce_key = resume_data().await.announce_key;
+    engine.check_health().unwrap();
+    resume_data().await;
+    engine.check_health().unwrap();
+
+    engine.restart_torrent(id).unwrap();
+    let resume = resume_data().await;
+    assert_eq!(resume.announce_key, announce_key);
+    assert_eq!(resume.conf.max_connected_peer_count, 3);
+    assert!(resume.trackers.unwrap().contains(&tracker));
+    let files = handle.files().await.unwrap();
+    assert_eq!(files[0].priority, FilePriority::High);
+  }
+
+  #[tokio::test]
+  async fn should_keep_trackers_edited_at_runtime() {
+    let dir = tempdir().unwrap();
+    let (listener, metainfo) = hung_tracker_torrent().await;
+    let (engine, _alert_rx) = spawn(Conf::new(dir.path())).unwrap();
+    let torrent = engine.create_torrent(TorrentParams::new(metainfo)).unwrap();
+
+    let dead = format!("http://{}/announce", listener.local_addr().unwrap());
+    let new: Url = "http://127.0.0.1:1/announce".parse().unwrap();
+    torrent.add_tracker(new.clone()).unwrap();
+    torrent.add_tracker(new.clone()).unwrap();
+    torrent.remove_tracker(dead.parse().unwrap()).unwrap();
+
+    let export = engine.export_torrent(torrent.id()).await.unwrap();
+    let export = TorrentExport::from_bytes(&export.to_bytes().unwrap());
+    assert_eq!(export.unwrap().resume.trackers, Some(vec![new]));
+  }
+
+  #[tokio::test]
+  async fn should_rename_magnet_torrent_once_metadata_is_fetched() {
+    let bytes = std::fs::read("fixtures/debian-iso.torrent").unwrap();
+    let metainfo = Metainfo::from_bytes(&bytes).unwrap();
+    let mut server = mockito::Server::new_async().await;
+    let _mock = server
+      .mock("GET", "/t.torrent")
+      .with_body(bytes)
+      .create_async()
+      .await;
+    let magnet = Magnet {
+      info_hash: metainfo.info_hash,
+      name: None,
+      trackers: Vec::new(),
+      peers: Vec::new(),
+      sources: vec![format!("{}/t.torrent", server.url()).parse().unwrap()],
+    };
+
+    let dir = tempdir().unwrap();
+    let (engine, _alert_rx) = spawn(Conf::new(dir.path())).unwrap();
+    engine.create_torrent(TorrentParams::new(magnet)).unwrap();
+
+    timeout(Duration::from_secs(5), async {
+      loop {
+        let torrents = engine.list().await.unwrap();
+        if torrents[0].name == metainfo.name {
+          break;
+        }
+        assert_eq!(torrents[0].name, hex::encode(metainfo.info_hash));
+        time::sleep(Duration::from_millis(50)).await;
+      }
+    })
+    .await
+    .expect("torrent not renamed");
+  }
+
+  #[tokio::test]
+  async fn should_keep_trackers_of_magnet_link() {
+    let bytes = std::fs::read("fixtures/debian-iso.torrent").unwrap();
+    let metainfo = Metainfo::from_bytes(&bytes).unwrap();
+    let mut server = mockito::Server::new_async().await;
+    let _mock = server
+      .mock("GET", "/t.torrent")
+      .with_body(bytes)
+      .create_async()
+      .await;
+    let tracker: Url = "http://127.0.0.1:1/announce".parse().unwrap();
+    let magnet = Magnet {
+      info_hash: metainfo.info_hash,
+      name: None,
+      trackers: vec![tracker.clone()],
+      peers: Vec::new(),
+      sources: vec![format!("{}/t.torrent", server.url()).parse().unwrap()],
+    };
+
+    let dir = tempdir().unwrap();
+    let (engine, _alert_rx) = spawn(Conf::new(dir.path())).unwrap();
+    let torrent = engine.create_torrent(TorrentParams::new(magnet)).unwrap();
+    let export = timeout(Duration::from_secs(5), async {
+      loop {
+        match engine.export_torrent(torrent.id()).await {
+          Err(Error::MetadataUnknown) => {
+            time::sleep(Duration::from_millis(50)).await
+          }
+          export => break export.unwrap(),
+        }
+      }
+    })
+    .await
+    .expect("metadata not fetched");
+
+    // the magnet link's tracker isn't in the metainfo, but is restored too
+    let export = TorrentExport::from_bytes(&export.to_bytes().unwrap());
+    let trackers = export.unwrap().resume.trackers.unwrap();
+    assert!(trackers.contains(&tracker));
+  }
+
+  #[test]
+  fn should_run_engine_on_its_own_runtime() {
+    let dir = tempdir().unwrap();
+    let count = run_blocking(Conf::new(dir.path()), |engine, _| {
+      engine.create_torrent(TorrentParams::new([1; 20])).unwrap();
+      let torrents = runtime::Handle::current().block_on(engine.list());
+      torrents.unwrap().len()
+    })
+    .unwrap();
+    assert_eq!(count, 1);
+  }
+
+  #[tokio::test]
+  async fn should_keep_detached_engine_running() {
+    let dir = tempdir().unwrap();
+    let (engine, mut alert_rx) = spawn(Conf::new(dir.path())).unwrap();
+    engine.detach();
+    assert!(!is_stopped(&mut alert_rx).await);
+  }
+}
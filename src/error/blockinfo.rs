@@ -0,0 +1,18 @@
+/// Error type returned when a block's geometry is invalid, i.e. it doesn't
+/// describe one of the 16 KiB blocks a piece is split into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum BlockInfoError {
+  #[error("block offset is not a multiple of the block length")]
+  /// The block doesn't start at a block boundary.
+  Misaligned,
+
+  #[error("block extends beyond its piece")]
+  /// The block ends after the end of its piece.
+  OutOfBounds,
+
+  #[error("invalid block length")]
+  /// The block is empty, longer than 16 KiB, or shorter than 16 KiB while
+  /// not being the last block in its piece. Peers' requests may be of any
+  /// length up to [`MAX_REQUEST_LEN`](crate::MAX_REQUEST_LEN).
+  InvalidLength,
+}
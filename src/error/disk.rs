@@ -1,4 +1,4 @@
-use crate::error::Error;
+use crate::error::{BlockInfoError, Error};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -33,6 +33,21 @@ pub enum WriteError {
   Io(std::io::Error),
 }
 
+/// Error type returned on failed moves of a torrent's storage.
+///
+/// This error is non-fatal: the torrent keeps its files where they were.
+#[derive(Debug, thiserror::Error)]
+pub enum MoveError {
+  #[error("storage is already being moved")]
+  /// A move of the torrent's storage is already in progress.
+  AlreadyMoving,
+
+  #[error("{0}")]
+  /// An IO error occurred, after which the files that had already been moved
+  /// were moved back.
+  Io(std::io::Error),
+}
+
 /// Error type returned on failed block reads.
 ///
 /// This error is non-fatal so it should not be grouped with the global `Error`
@@ -43,6 +58,10 @@ pub enum ReadError {
   /// The block's offset in piece is invalid.
   InvalidBlockOffset,
 
+  #[error("invalid block info: {0}")]
+  /// The block doesn't describe a valid block of the torrent.
+  InvalidBlockInfo(BlockInfoError),
+
   #[error("torrent data missing")]
   /// The block is valid within torrent but its data has not been downloaded
   /// yet or has been deleted.
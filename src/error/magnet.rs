@@ -0,0 +1,27 @@
+pub(crate) type Result<T> = std::result::Result<T, MagnetError>;
+
+/// Error type returned when parsing a magnet link fails.
+#[derive(thiserror::Error, Debug)]
+pub enum MagnetError {
+  #[error("invalid uri")]
+  /// The link is not a valid URI.
+  InvalidUri,
+
+  #[error("not a magnet link")]
+  /// The URI's scheme is not `magnet`.
+  NotMagnet,
+
+  #[error("missing info hash")]
+  /// The link has no `xt=urn:btih:` parameter.
+  MissingInfoHash,
+
+  #[error("invalid info hash")]
+  /// The info hash is neither 40 hex nor 32 base32 characters.
+  InvalidInfoHash,
+}
+
+impl From<url::ParseError> for MagnetError {
+  fn from(_: url::ParseError) -> Self {
+    Self::InvalidUri
+  }
+}
@@ -1,17 +1,29 @@
 //! Set of module Error
+pub mod blockinfo;
 pub mod disk;
+pub mod magnet;
 pub mod metainfo;
 pub mod peer;
 pub mod torrent;
 pub mod tracker;
+pub mod watch;
 
 use std::net::SocketAddr;
 
-pub use disk::{NewTorrentError, ReadError, Result as DiskResult, WriteError};
+use reqwest::Url;
+
+pub use blockinfo::BlockInfoError;
+pub use disk::{
+  MoveError, NewTorrentError, ReadError, Result as DiskResult, WriteError,
+};
 pub use peer::{PeerError, Result as PeerResult};
-pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
+pub use tokio::{
+  io::Error as IoError,
+  sync::{mpsc::error::SendError, oneshot::error::RecvError},
+};
 pub use torrent::{Result as TorrentResult, TorrentError};
 pub use tracker::{Result as TrackerResult, TrackerError};
+pub use watch::WatchError;
 
 use crate::TorrentId;
 
@@ -24,10 +36,19 @@ pub enum Error {
   /// The cannel on which some component in engine was listening or sending died.
   Channel,
 
+  #[error("invalid configuration: {0}")]
+  /// A configuration value is invalid, with the reason included.
+  InvalidConf(&'static str),
+
   #[error("invalid download path")]
   /// The torrent download location is not valid.
   InvalidDownloadPath,
 
+  #[error("invalid session")]
+  /// The saved session could not be restored as its session file or one of
+  /// its torrents' metainfo files is corrupt.
+  InvalidSession,
+
   #[error("invalid torrent id")]
   /// The torrent ID did not correspond to any entry.
   /// This is returned when user specified a torrent that does not exist.
@@ -37,14 +58,29 @@ pub enum Error {
   /// Holds global IO related errors.
   Io(IoError),
 
+  #[error("torrent metadata not yet known")]
+  /// The torrent's metadata is still being fetched, so it has no state to
+  /// export yet.
+  MetadataUnknown,
+
   #[error("torrent {id} error: {error}")]
   /// An error specific to a torrent
   Torrent { id: TorrentId, error: TorrentError },
 
+  #[error("torrent did not respond in time")]
+  /// The torrent's task didn't answer the engine within the shutdown grace
+  /// period, e.g. because it's stuck.
+  TorrentUnresponsive,
+
   #[error("torrent {id} tracker error: {error}")]
   /// An error that occurred while a torrent was announcing to tracker.
   Tracker { id: TorrentId, error: TrackerError },
 
+  #[error("unsupported tracker {0}")]
+  /// The tracker's protocol is not supported by the torrent's tracker
+  /// backend, e.g. it's a WebSocket tracker.
+  UnsupportedTracker(Url),
+
   #[error("torrent {id} peer {addr} error: {error}")]
   /// An error that occurred in a torrent's session with a peer.
   Peer {
@@ -67,3 +103,9 @@ impl<T> From<SendError<T>> for Error {
     Self::Channel
   }
 }
+
+impl From<RecvError> for Error {
+  fn from(_: RecvError) -> Self {
+    Self::Channel
+  }
+}
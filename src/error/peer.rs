@@ -1,4 +1,9 @@
-pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
+pub use tokio::{
+  io::Error as IoError,
+  sync::mpsc::error::{SendError, TrySendError},
+};
+
+use super::BlockInfoError;
 
 pub type Result<T, E = PeerError> = std::result::Result<T, E>;
 
@@ -24,14 +29,36 @@ pub enum PeerError {
   /// Peers are not allowed to request blocks while they are chocked. If they do so, their connection is severed.
   RequestWhileChocked,
 
+  #[error("peer kept making requests beyond limits")]
+  /// The peer kept making requests that were too long, too many, or
+  /// duplicates. These are rejected at first, but a peer that doesn't stop
+  /// is likely abusive, so its connection is severed.
+  RequestLimitViolation,
+
+  #[error("peer kept sending unsolicited blocks")]
+  /// The peer kept sending blocks we never requested, which are dropped at
+  /// first, but a peer that doesn't stop only wastes our bandwidth, so its
+  /// connection is severed.
+  UnsolicitedBlocks,
+
   #[error("inactivity timeout")]
+  /// A peer session timed out because the peer didn't send any message,
+  /// not even a keep-alive, within the inactivity timeout.
+  InactivityTimeout,
+
+  #[error("interest timeout")]
   /// A peer session timed out because neither side of the
   /// connection became interested in each other.
-  InactivityTimeout,
+  InterestTimeout,
+
+  #[error("handshake timeout")]
+  /// The connection could not be established or the peer didn't send its
+  /// handshake in time.
+  HandshakeTimeout,
 
-  #[error("invalid block info")]
+  #[error("invalid block info: {0}")]
   /// The block information the peer sent is invalid.
-  InvalidBlockInfo,
+  InvalidBlockInfo(BlockInfoError),
 
   #[error("invalid piece index")]
   /// The block's piece index is invalid.
@@ -41,9 +68,54 @@ pub enum PeerError {
   /// Peer's torrent info hash did not match ours.
   InvalidInfoHash,
 
+  #[error("fast extension message from peer without it")]
+  /// The peer sent a message of the Fast extension (BEP 6) without telling
+  /// in its handshake that it supports the extension.
+  UnsupportedFastMessage,
+
+  #[error("invalid extension message")]
+  /// The peer sent an extension protocol message that couldn't be parsed,
+  /// or that doesn't fit the exchange.
+  InvalidExtensionMessage,
+
+  #[error("invalid metadata")]
+  /// The metadata the peer sent doesn't match the torrent's info hash.
+  InvalidMetadata,
+
+  #[error("peer has no metadata")]
+  /// The peer doesn't support the metadata exchange, doesn't have the
+  /// torrent's metadata, or rejected our request for it.
+  NoMetadata,
+
   #[error("{0}")]
   /// An IO error occurred.
   Io(std::io::Error),
+
+  #[error("session panicked ({count} time(s) with this peer): {message}")]
+  /// The session's task panicked. The session is torn down without
+  /// affecting the torrent, and the panics with the same peer are counted
+  /// so that it's not reconnected endlessly.
+  Panic { message: String, count: usize },
+}
+
+impl PeerError {
+  /// Returns whether the error is the peer's fault for breaking the
+  /// protocol or our limits, as opposed to e.g. a network error or a
+  /// timeout, which may happen to any peer.
+  pub fn is_misbehavior(&self) -> bool {
+    matches!(
+      self,
+      Self::BitfieldNotAfterHandshake
+        | Self::RequestWhileChocked
+        | Self::RequestLimitViolation
+        | Self::UnsolicitedBlocks
+        | Self::InvalidBlockInfo(_)
+        | Self::InvalidPieceIndex
+        | Self::UnsupportedFastMessage
+        | Self::InvalidExtensionMessage
+        | Self::InvalidMetadata
+    )
+  }
 }
 
 impl From<IoError> for PeerError {
@@ -59,3 +131,9 @@ impl<T> From<SendError<T>> for PeerError {
     Self::Channel
   }
 }
+
+impl<T> From<TrySendError<T>> for PeerError {
+  fn from(_: TrySendError<T>) -> Self {
+    Self::Channel
+  }
+}
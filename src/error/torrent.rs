@@ -1,6 +1,8 @@
 use tokio::io::Error as IoError;
 use tokio::sync::mpsc::error::SendError;
 
+use super::MoveError;
+
 pub type Result<T, E = TorrentError> = std::result::Result<T, E>;
 
 #[derive(Debug, thiserror::Error)]
@@ -13,6 +15,18 @@ pub enum TorrentError {
   #[error("{0}")]
   /// An Io error occurred.
   Io(std::io::Error),
+
+  #[error("failed to move storage: {0}")]
+  /// The torrent's files could not be moved to the new download directory.
+  MoveStorage(MoveError),
+
+  #[error("the number of file priorities doesn't match the number of files")]
+  /// The file priorities given don't cover exactly the torrent's files.
+  InvalidFilePriorities,
+
+  #[error("invalid piece index {0}")]
+  /// The piece index given is not within the torrent.
+  InvalidPieceIndex(usize),
 }
 
 impl From<IoError> for TorrentError {
@@ -1,4 +1,5 @@
 use crate::error::metainfo::{BencodeDeError, BencodeSerError};
+use crate::tracker::tracker::IpFamily;
 use reqwest::Error as HttpError;
 
 pub type Result<T, E = TrackerError> = std::result::Result<T, E>;
@@ -12,6 +13,29 @@ pub enum TrackerError {
 
   #[error("{0}")]
   Http(HttpError),
+
+  #[error("{0}")]
+  Io(std::io::Error),
+
+  #[error("tracker has no {0} address")]
+  /// The tracker's host has no address of the IP family we announce over.
+  NoAddress(IpFamily),
+
+  #[error("tracker timed out")]
+  /// The tracker didn't respond within the configured timeout.
+  Timeout,
+
+  #[error("tracker failure: {0}")]
+  /// The tracker rejected the request with the given reason.
+  Failure(String),
+
+  #[error("invalid tracker response")]
+  /// The tracker's response couldn't be parsed.
+  InvalidResponse,
+
+  #[error("tracker doesn't support scraping")]
+  /// The tracker's URL has no scrape counterpart (BEP 48).
+  ScrapeUnsupported,
 }
 
 impl From<BencodeDeError> for TrackerError {
@@ -26,6 +50,12 @@ impl From<BencodeSerError> for TrackerError {
   }
 }
 
+impl From<std::io::Error> for TrackerError {
+  fn from(error: std::io::Error) -> Self {
+    Self::Io(error)
+  }
+}
+
 impl From<HttpError> for TrackerError {
   fn from(value: HttpError) -> Self {
     Self::Http(value)
@@ -0,0 +1,38 @@
+use std::io;
+
+use super::{magnet::MagnetError, metainfo::MetainfoError};
+
+/// Error type returned when a file in the watched directory could not be
+/// added as a torrent.
+#[derive(thiserror::Error, Debug)]
+pub enum WatchError {
+  #[error("{0}")]
+  /// The file could not be read or renamed.
+  Io(io::Error),
+
+  #[error("invalid metainfo: {0}")]
+  /// The `.torrent` file is not a valid metainfo file.
+  Metainfo(MetainfoError),
+
+  #[error("invalid magnet link: {0}")]
+  /// The `.magnet` file doesn't hold a valid magnet link.
+  Magnet(MagnetError),
+}
+
+impl From<io::Error> for WatchError {
+  fn from(error: io::Error) -> Self {
+    Self::Io(error)
+  }
+}
+
+impl From<MetainfoError> for WatchError {
+  fn from(error: MetainfoError) -> Self {
+    Self::Metainfo(error)
+  }
+}
+
+impl From<MagnetError> for WatchError {
+  fn from(error: MagnetError) -> Self {
+    Self::Magnet(error)
+  }
+}
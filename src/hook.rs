@@ -0,0 +1,132 @@
+//! Hooks run when a torrent finishes downloading, e.g. to hand the files
+//! over to a post-processing pipeline.
+
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use tokio::{process, task};
+
+use crate::{
+  alert::{Alert, AlertSender},
+  error::Error,
+  TorrentId,
+};
+
+/// The torrent that finished downloading, as given to a [`CompletionHook`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompletedTorrent {
+  pub id: TorrentId,
+  /// The name of the torrent, as given in its metainfo.
+  pub name: String,
+  /// Where the torrent's files are: the file itself for a single file
+  /// torrent, and the directory holding the files for an archive.
+  pub path: PathBuf,
+}
+
+/// What to run when a torrent finishes downloading.
+///
+/// The hook is not run for torrents that were already complete when added.
+#[derive(Clone)]
+pub enum CompletionHook {
+  /// A callback, run on a thread where it may block.
+  Callback(Arc<dyn Fn(&CompletedTorrent) + Send + Sync>),
+  /// An external command, as the program followed by its arguments. The
+  /// `{id}`, `{name}` and `{path}` placeholders in the arguments are
+  /// replaced with those of the torrent.
+  ///
+  /// The command is not run through a shell.
+  Command(Vec<String>),
+}
+
+impl fmt::Debug for CompletionHook {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Callback(_) => f.write_str("Callback"),
+      Self::Command(args) => f.debug_tuple("Command").field(args).finish(),
+    }
+  }
+}
+
+impl CompletionHook {
+  /// Runs the hook for the torrent in the background. If the command can't
+  /// be started, an [`Alert::Error`] is posted.
+  pub(crate) fn run(&self, torrent: CompletedTorrent, alert_tx: AlertSender) {
+    match self {
+      Self::Callback(callback) => {
+        let callback = Arc::clone(callback);
+        task::spawn_blocking(move || callback(&torrent));
+      }
+      Self::Command(template) => {
+        let args = expand(template, &torrent);
+        log::info!("Running completion command {:?}", args);
+        let mut cmd = process::Command::new(&args[0]);
+        cmd.args(&args[1..]);
+        task::spawn(async move {
+          match cmd.status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => log::warn!(
+              "Torrent {} completion command exited with {}",
+              torrent.id,
+              status
+            ),
+            Err(e) => {
+              log::warn!(
+                "Failed to run torrent {} completion command: {}",
+                torrent.id,
+                e
+              );
+              alert_tx
+                .send(Alert::Error(Error::Torrent {
+                  id: torrent.id,
+                  error: e.into(),
+                }))
+                .ok();
+            }
+          }
+        });
+      }
+    }
+  }
+}
+
+/// Replaces the placeholders in the command template with the torrent's
+/// values.
+fn expand(template: &[String], torrent: &CompletedTorrent) -> Vec<String> {
+  let id = torrent.id.to_string();
+  let path = torrent.path.to_string_lossy();
+  template
+    .iter()
+    .map(|arg| {
+      arg
+        .replace("{id}", &id)
+        .replace("{name}", &torrent.name)
+        .replace("{path}", &path)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_expand_command_template() {
+    let torrent = CompletedTorrent {
+      id: TorrentId::new(),
+      name: "debian".to_owned(),
+      path: PathBuf::from("/downloads/debian.iso"),
+    };
+    let template = vec![
+      "mv".to_owned(),
+      "{path}".to_owned(),
+      "/done/{name}-{id}".to_owned(),
+    ];
+    assert_eq!(
+      expand(&template, &torrent),
+      vec![
+        "mv".to_owned(),
+        "/downloads/debian.iso".to_owned(),
+        format!("/done/debian-{}", torrent.id),
+      ]
+    );
+  }
+}
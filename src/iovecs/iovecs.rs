@@ -73,9 +73,9 @@ impl<'a> IoVecs<'a> {
   /// # Arguments
   ///
   /// * `bufs` - A slice that points to a contiguous list of IO vectors, which
-  ///     in turn point to the actual blocks of memory used for file IO.
+  ///   in turn point to the actual blocks of memory used for file IO.
   /// * `max_len` - The maximum byte count of the total number of bytes in the
-  ///     IO vectors.
+  ///   IO vectors.
   ///
   /// # Panics
   ///
@@ -518,8 +518,7 @@ mod tests {
   #[test]
   fn should_not_split_buffers_same_size_as_file() {
     let file_len = 32;
-    let blocks =
-      vec![(0..16).collect::<Vec<u8>>(), (16..32).collect::<Vec<u8>>()];
+    let blocks = [(0..16).collect::<Vec<u8>>(), (16..32).collect::<Vec<u8>>()];
     let blocks_len: usize = blocks.iter().map(Vec::len).sum();
 
     let mut bufs: Vec<_> = blocks.iter().map(|buf| IoSlice::new(buf)).collect();
@@ -559,8 +558,7 @@ mod tests {
   #[test]
   fn should_not_split_buffers_smaller_than_file() {
     let file_len = 42;
-    let blocks =
-      vec![(0..16).collect::<Vec<u8>>(), (16..32).collect::<Vec<u8>>()];
+    let blocks = [(0..16).collect::<Vec<u8>>(), (16..32).collect::<Vec<u8>>()];
     let blocks_len: usize = blocks.iter().map(Vec::len).sum();
 
     let mut bufs: Vec<_> = blocks.iter().map(|buf| IoSlice::new(buf)).collect();
@@ -601,8 +599,7 @@ mod tests {
   #[test]
   fn should_split_last_buffer_not_at_boundary() {
     let file_len = 25;
-    let blocks =
-      vec![(0..16).collect::<Vec<u8>>(), (16..32).collect::<Vec<u8>>()];
+    let blocks = [(0..16).collect::<Vec<u8>>(), (16..32).collect::<Vec<u8>>()];
 
     let mut bufs: Vec<_> = blocks.iter().map(|buf| IoSlice::new(buf)).collect();
     let iovecs = IoVecs::bounded(&mut bufs, file_len);
@@ -650,7 +647,7 @@ mod tests {
   #[test]
   fn should_split_middle_buffer_not_at_boundary() {
     let file_len = 25;
-    let blocks = vec![
+    let blocks = [
       (0..16).collect::<Vec<u8>>(),
       (16..32).collect::<Vec<u8>>(),
       (32..48).collect::<Vec<u8>>(),
@@ -698,7 +695,7 @@ mod tests {
   #[test]
   fn partial_advance_in_first_half_should_not_affect_rest() {
     let file_len = 25;
-    let blocks = vec![
+    let blocks = [
       (0..16).collect::<Vec<u8>>(),
       (16..32).collect::<Vec<u8>>(),
       (32..48).collect::<Vec<u8>>(),
@@ -751,7 +748,7 @@ mod tests {
   #[test]
   fn advances_in_first_half_should_not_affect_rest() {
     let file_len = 25;
-    let blocks = vec![
+    let blocks = [
       (0..16).collect::<Vec<u8>>(),
       (16..32).collect::<Vec<u8>>(),
       (32..48).collect::<Vec<u8>>(),
@@ -820,7 +817,7 @@ mod tests {
   #[test]
   fn consuming_first_half_should_not_affect_second_half() {
     let file_len = 32;
-    let blocks = vec![
+    let blocks = [
       (0..16).collect::<Vec<u8>>(),
       (16..32).collect::<Vec<u8>>(),
       (32..48).collect::<Vec<u8>>(),
@@ -860,7 +857,7 @@ mod tests {
   #[should_panic]
   fn should_panic_advancing_past_end() {
     let file_len = 32;
-    let blocks = vec![
+    let blocks = [
       (0..16).collect::<Vec<u8>>(),
       (16..32).collect::<Vec<u8>>(),
       (32..48).collect::<Vec<u8>>(),
@@ -875,7 +872,7 @@ mod tests {
 
   #[test]
   fn should_advance_into_first_buffer() {
-    let mut bufs = vec![vec![0, 1, 2], vec![3, 4, 5]];
+    let mut bufs = [vec![0, 1, 2], vec![3, 4, 5]];
     let mut iovecs = bufs
       .iter_mut()
       .map(|b| IoSliceMut::new(b))
@@ -894,7 +891,7 @@ mod tests {
 
   #[test]
   fn should_trim_whole_first_buffer() {
-    let mut bufs = vec![vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8]];
+    let mut bufs = [vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8]];
     let mut iovecs = bufs
       .iter_mut()
       .map(|b| IoSliceMut::new(b))
@@ -913,7 +910,7 @@ mod tests {
 
   #[test]
   fn should_advance_into_second_buffer() {
-    let mut bufs = vec![vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8]];
+    let mut bufs = [vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8]];
     let mut iovecs = bufs
       .iter_mut()
       .map(|b| IoSliceMut::new(b))
@@ -932,7 +929,7 @@ mod tests {
 
   #[test]
   fn should_trim_all_buffers() {
-    let mut bufs = vec![vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8]];
+    let mut bufs = [vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8]];
     let mut iovecs = bufs
       .iter_mut()
       .map(|b| IoSliceMut::new(b))
@@ -947,7 +944,7 @@ mod tests {
 
   #[test]
   fn should_advance_one_buffer() {
-    let mut bufs = vec![vec![0], vec![1, 2, 3], vec![4, 5, 6]];
+    let mut bufs = [vec![0], vec![1, 2, 3], vec![4, 5, 6]];
     let mut iovecs = bufs
       .iter_mut()
       .map(|b| IoSliceMut::new(b))
@@ -971,7 +968,7 @@ mod tests {
   #[test]
   fn advances_one_buffer_and_tail_should_nice() {
     let file_len = 16;
-    let blocks = vec![
+    let blocks = [
       (0..4).collect::<Vec<u8>>(),
       (4..8).collect::<Vec<u8>>(),
       (8..16).collect::<Vec<u8>>(),
@@ -1,13 +1,18 @@
 pub mod blockinfo;
+pub mod channel;
+pub mod dht;
 pub mod disk;
 pub mod download;
 pub mod error;
+pub mod magnet;
+pub mod memory;
 pub mod metainfo;
 pub mod peer;
 pub mod piece_picker;
 pub mod storage_info;
 pub mod torrent;
 pub mod tracker;
+pub mod transport;
 
 pub mod iovecs;
 
@@ -17,17 +22,25 @@ pub mod counter;
 
 pub mod conf;
 pub mod engine;
+pub mod hook;
+pub mod watchdog;
 
 mod define;
 pub use define::*;
 
+mod rate_limiter;
+mod session;
+mod watch_dir;
+
 pub mod prelude {
   pub use crate::{
     alert::{Alert, AlertReceiver},
-    conf::Conf,
-    engine::{self, EngineHandle, Mode, TorrentParams},
+    conf::{Conf, Priority},
+    engine::{self, EngineHandle, TorrentParams, TorrentSource},
     error::Error,
+    magnet::Magnet,
     metainfo::Metainfo,
+    torrent::{handle::TorrentHandle, FilePriority, Limits},
     TorrentId,
   };
   pub use futures::stream::StreamExt;
@@ -0,0 +1,238 @@
+//! Parsing of magnet links, as described in [BEP 9].
+//!
+//! A magnet link identifies a torrent by its info hash only, so unlike with
+//! a metainfo file, the torrent's info dictionary has to be obtained before
+//! the download can begin.
+//!
+//! [BEP 9]: http://bittorrent.org/beps/bep_0009.html
+
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use url::Url;
+
+use crate::{
+  error::magnet::{MagnetError, Result},
+  Sha1Hash,
+};
+
+/// The prefix of the `xt` (exact topic) parameter's value for BitTorrent
+/// info hashes.
+const BTIH_PREFIX: &str = "urn:btih:";
+
+/// A parsed magnet link.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Magnet {
+  /// The torrent's info hash, from the `xt` parameter.
+  pub info_hash: Sha1Hash,
+  /// The torrent's display name, from the `dn` parameter.
+  pub name: Option<String>,
+  /// The trackers to announce to, from the `tr` parameters.
+  ///
+  /// As with metainfo, trackers of any protocol are kept, and those the
+  /// torrent's tracker backend doesn't support are skipped when it starts.
+  pub trackers: Vec<Url>,
+  /// Peers to connect to, from the `x.pe` parameters.
+  pub peers: Vec<SocketAddr>,
+  /// URLs of the torrent's metainfo file, from the `xs` parameters, from
+  /// which the torrent's metadata may be downloaded.
+  pub sources: Vec<Url>,
+}
+
+impl fmt::Debug for Magnet {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Magnet")
+      .field("info_hash", &hex::encode(self.info_hash))
+      .field("name", &self.name)
+      .field("trackers", &self.trackers)
+      .field("peers", &self.peers)
+      .field("sources", &self.sources)
+      .finish()
+  }
+}
+
+impl Magnet {
+  /// Creates a magnet link with only the info hash set, which is all that is
+  /// needed to identify a torrent.
+  pub fn new(info_hash: Sha1Hash) -> Self {
+    Self {
+      info_hash,
+      name: None,
+      trackers: Vec::new(),
+      peers: Vec::new(),
+      sources: Vec::new(),
+    }
+  }
+
+  /// Parses a magnet link.
+  ///
+  /// The only mandatory parameter is the `urn:btih` exact topic, with the
+  /// info hash encoded either as 40 hex or 32 base32 characters. Unknown
+  /// parameters are ignored, as are trackers, peers and sources that can't
+  /// be parsed.
+  pub fn parse(uri: &str) -> Result<Self> {
+    let url = Url::parse(uri)?;
+    if url.scheme() != "magnet" {
+      return Err(MagnetError::NotMagnet);
+    }
+
+    let mut info_hash = None;
+    let mut name = None;
+    let mut trackers = Vec::new();
+    let mut peers = Vec::new();
+    let mut sources = Vec::new();
+
+    // the values are percent-decoded by the url crate
+    for (key, value) in url.query_pairs() {
+      match key.as_ref() {
+        "xt" => {
+          // other exact topics (e.g. ed2k) may be present, which we skip
+          if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+            if info_hash.is_none() {
+              info_hash = Some(decode_info_hash(hash)?);
+            }
+          }
+        }
+        "dn" => name = Some(value.into_owned()),
+        "tr" => match Url::parse(&value) {
+          Ok(url) => trackers.push(url),
+          Err(e) => log::warn!("Invalid tracker {} in magnet: {}", value, e),
+        },
+        "x.pe" => match value.parse() {
+          Ok(addr) => peers.push(addr),
+          Err(e) => log::warn!("Invalid peer {} in magnet: {}", value, e),
+        },
+        "xs" => match Url::parse(&value) {
+          Ok(url) => sources.push(url),
+          Err(e) => log::warn!("Invalid source {} in magnet: {}", value, e),
+        },
+        _ => log::trace!("Ignoring magnet parameter {}", key),
+      }
+    }
+
+    Ok(Self {
+      info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+      name,
+      trackers,
+      peers,
+      sources,
+    })
+  }
+}
+
+impl FromStr for Magnet {
+  type Err = MagnetError;
+
+  fn from_str(s: &str) -> Result<Self> {
+    Self::parse(s)
+  }
+}
+
+/// Decodes a hex or base32 encoded info hash.
+fn decode_info_hash(s: &str) -> Result<Sha1Hash> {
+  let mut info_hash = [0; 20];
+  match s.len() {
+    40 => hex::decode_to_slice(s, &mut info_hash)
+      .map_err(|_| MagnetError::InvalidInfoHash)?,
+    32 => {
+      // RFC 4648 base32, without padding: each character encodes 5 bits
+      let mut bits = 0u64;
+      let mut bit_count = 0;
+      let mut i = 0;
+      for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+          c @ b'A'..=b'Z' => c - b'A',
+          c @ b'2'..=b'7' => c - b'2' + 26,
+          _ => return Err(MagnetError::InvalidInfoHash),
+        };
+        bits = (bits << 5) | value as u64;
+        bit_count += 5;
+        if bit_count >= 8 {
+          bit_count -= 8;
+          info_hash[i] = (bits >> bit_count) as u8;
+          i += 1;
+        }
+      }
+      debug_assert_eq!(i, 20);
+    }
+    _ => return Err(MagnetError::InvalidInfoHash),
+  }
+  Ok(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const INFO_HASH_HEX: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+  const INFO_HASH_BASE32: &str = "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+
+  #[test]
+  fn test_parse_full_magnet() {
+    let uri = format!(
+      "magnet:?xt=urn:btih:{}&dn=Big+Buck%20Bunny\
+      &tr=http%3A%2F%2Ftracker.example.com%3A8080%2Fannounce\
+      &tr=udp%3A%2F%2Ftracker.example.com%3A1337\
+      &x.pe=127.0.0.1:6881&x.pe=[::1]:6882&x.pe=not-a-peer\
+      &xs=http%3A%2F%2Fexample.com%2Ft.torrent",
+      INFO_HASH_HEX
+    );
+    let magnet = Magnet::parse(&uri).unwrap();
+
+    assert_eq!(hex::encode(magnet.info_hash), INFO_HASH_HEX);
+    assert_eq!(magnet.name.as_deref(), Some("Big Buck Bunny"));
+    assert_eq!(
+      magnet.trackers,
+      vec![
+        Url::parse("http://tracker.example.com:8080/announce").unwrap(),
+        Url::parse("udp://tracker.example.com:1337").unwrap()
+      ]
+    );
+    assert_eq!(
+      magnet.peers,
+      vec![
+        "127.0.0.1:6881".parse().unwrap(),
+        "[::1]:6882".parse().unwrap()
+      ]
+    );
+    assert_eq!(
+      magnet.sources,
+      vec![Url::parse("http://example.com/t.torrent").unwrap()]
+    );
+  }
+
+  #[test]
+  fn test_parse_base32_info_hash() {
+    let hex =
+      Magnet::parse(&format!("magnet:?xt=urn:btih:{}", INFO_HASH_HEX)).unwrap();
+    let base32 = format!("magnet:?xt=urn:btih:{}", INFO_HASH_BASE32)
+      .parse::<Magnet>()
+      .unwrap();
+    assert_eq!(hex.info_hash, base32.info_hash);
+    assert!(base32.name.is_none());
+    assert!(base32.trackers.is_empty());
+  }
+
+  #[test]
+  fn test_parse_invalid_magnet() {
+    assert!(matches!(
+      Magnet::parse("http://example.com/?xt=urn:btih:00"),
+      Err(MagnetError::NotMagnet)
+    ));
+    assert!(matches!(
+      Magnet::parse("magnet:?dn=foo"),
+      Err(MagnetError::MissingInfoHash)
+    ));
+    assert!(matches!(
+      Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba"),
+      Err(MagnetError::InvalidInfoHash)
+    ));
+    assert!(matches!(
+      Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1"),
+      Err(MagnetError::InvalidInfoHash)
+    ));
+    assert!(matches!(
+      Magnet::parse("not a uri"),
+      Err(MagnetError::InvalidUri)
+    ));
+  }
+}
@@ -0,0 +1,241 @@
+//! Accounting of the bytes held in memory by the engine's buffers.
+//!
+//! The buffers charge the bytes they hold to the engine-wide counters for as
+//! long as they hold them, so that their sizes can be watched, e.g. to find
+//! the right caps for a seedbox or to detect a leak, without an external
+//! profiler.
+
+use std::sync::{
+  atomic::{AtomicU64, Ordering},
+  Arc,
+};
+
+use serde_derive::Serialize;
+
+/// The bytes held by the engine's buffers, as returned by
+/// [`EngineHandle::memory_stats`].
+///
+/// [`EngineHandle::memory_stats`]: crate::engine::EngineHandle::memory_stats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize)]
+pub struct MemoryStats {
+  /// The bytes of the downloaded blocks sent to the disk task that it
+  /// hasn't taken into its write buffer yet.
+  pub disk_queue_bytes: u64,
+  /// The bytes of the downloaded blocks in the disk write buffer, including
+  /// the completed pieces not yet written to disk.
+  pub write_buf_bytes: u64,
+  /// The bytes of the pieces in the disk read cache.
+  pub read_cache_bytes: u64,
+  /// The bytes of the blocks that peer sessions hold back until they may be
+  /// sent to their peers, or that they have yet to write to their sockets.
+  pub peer_queue_bytes: u64,
+}
+
+impl MemoryStats {
+  /// Returns the bytes held by all buffers.
+  pub fn total(&self) -> u64 {
+    self.disk_queue_bytes
+      + self.write_buf_bytes
+      + self.read_cache_bytes
+      + self.peer_queue_bytes
+  }
+}
+
+/// The engine-wide counters to which the buffers charge the bytes they hold.
+#[derive(Debug, Default)]
+pub struct MemoryCounters {
+  disk_queue: AtomicU64,
+  write_buf: AtomicU64,
+  read_cache: AtomicU64,
+  peer_queues: AtomicU64,
+  /// The bytes the buffers may hold in total before downloading is held
+  /// back, or no limit if not set.
+  budget: Option<u64>,
+}
+
+impl MemoryCounters {
+  /// Creates the counters with the bytes the buffers may hold in total.
+  pub fn new(budget: Option<u64>) -> Self {
+    Self {
+      budget,
+      ..Default::default()
+    }
+  }
+
+  /// Returns whether the buffers hold more than the budget, in which case
+  /// no more blocks should be requested until they drain.
+  pub fn is_over_budget(&self) -> bool {
+    self
+      .budget
+      .is_some_and(|budget| self.stats().total() > budget)
+  }
+
+  /// Returns the bytes currently charged to each buffer.
+  pub fn stats(&self) -> MemoryStats {
+    MemoryStats {
+      disk_queue_bytes: self.disk_queue.load(Ordering::Relaxed),
+      write_buf_bytes: self.write_buf.load(Ordering::Relaxed),
+      read_cache_bytes: self.read_cache.load(Ordering::Relaxed),
+      peer_queue_bytes: self.peer_queues.load(Ordering::Relaxed),
+    }
+  }
+
+  fn counter(&self, buffer: Buffer) -> &AtomicU64 {
+    match buffer {
+      Buffer::DiskQueue => &self.disk_queue,
+      Buffer::WriteBuf => &self.write_buf,
+      Buffer::ReadCache => &self.read_cache,
+      Buffer::PeerQueue => &self.peer_queues,
+    }
+  }
+}
+
+/// The buffers whose bytes are accounted for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Buffer {
+  DiskQueue,
+  WriteBuf,
+  ReadCache,
+  PeerQueue,
+}
+
+/// Bytes charged to one of the buffers, which are uncharged when this is
+/// dropped, so that the bytes are uncharged however the buffer lets go of
+/// them.
+#[derive(Debug)]
+pub struct MemoryCharge {
+  counters: Arc<MemoryCounters>,
+  buffer: Buffer,
+  len: u64,
+}
+
+impl MemoryCharge {
+  /// Creates an empty charge to the buffer.
+  pub(crate) fn new(counters: &Arc<MemoryCounters>, buffer: Buffer) -> Self {
+    Self {
+      counters: Arc::clone(counters),
+      buffer,
+      len: 0,
+    }
+  }
+
+  /// Creates a charge of the given bytes to the buffer.
+  pub(crate) fn with_len(
+    counters: &Arc<MemoryCounters>,
+    buffer: Buffer,
+    len: u64,
+  ) -> Self {
+    let mut charge = Self::new(counters, buffer);
+    charge.add(len);
+    charge
+  }
+
+  /// Returns the bytes of the charge.
+  pub(crate) fn len(&self) -> u64 {
+    self.len
+  }
+
+  /// Charges the given bytes to the buffer.
+  pub(crate) fn add(&mut self, len: u64) {
+    self
+      .counters
+      .counter(self.buffer)
+      .fetch_add(len, Ordering::Relaxed);
+    self.len += len;
+  }
+
+  /// Uncharges the given bytes from the buffer, or all bytes of the charge
+  /// if it has fewer.
+  pub(crate) fn sub(&mut self, len: u64) {
+    let len = len.min(self.len);
+    self
+      .counters
+      .counter(self.buffer)
+      .fetch_sub(len, Ordering::Relaxed);
+    self.len -= len;
+  }
+
+  /// Uncharges all bytes of the charge.
+  pub(crate) fn clear(&mut self) {
+    self.sub(self.len);
+  }
+
+  /// Moves the given bytes of this charge, or all its bytes if it has fewer,
+  /// into a new charge, e.g. when a piece leaves the write buffer for its
+  /// disk write while still being held in memory.
+  pub(crate) fn split(&mut self, len: u64) -> Self {
+    let len = len.min(self.len);
+    self.len -= len;
+    Self {
+      counters: Arc::clone(&self.counters),
+      buffer: self.buffer,
+      len,
+    }
+  }
+}
+
+impl Drop for MemoryCharge {
+  fn drop(&mut self) {
+    self.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_memory_charge() {
+    let counters = Arc::new(MemoryCounters::default());
+    let mut write_buf = MemoryCharge::new(&counters, Buffer::WriteBuf);
+    write_buf.add(3 * 0x4000);
+    let cached = MemoryCharge::with_len(&counters, Buffer::ReadCache, 0x8000);
+    assert_eq!(
+      counters.stats(),
+      MemoryStats {
+        write_buf_bytes: 3 * 0x4000,
+        read_cache_bytes: 0x8000,
+        ..Default::default()
+      }
+    );
+    assert_eq!(counters.stats().total(), 5 * 0x4000);
+
+    // the split off bytes stay charged until that charge is dropped
+    let piece = write_buf.split(2 * 0x4000);
+    assert_eq!(counters.stats().write_buf_bytes, 3 * 0x4000);
+    drop(piece);
+    assert_eq!(counters.stats().write_buf_bytes, 0x4000);
+
+    // more can't be uncharged than was charged
+    write_buf.sub(2 * 0x4000);
+    assert_eq!(counters.stats().write_buf_bytes, 0);
+
+    write_buf.add(0x4000);
+    drop(write_buf);
+    drop(cached);
+    assert_eq!(counters.stats(), MemoryStats::default());
+  }
+
+  #[test]
+  fn test_memory_budget() {
+    let counters = Arc::new(MemoryCounters::new(Some(0x8000)));
+    let queued = MemoryCharge::with_len(&counters, Buffer::DiskQueue, 0x4000);
+    let mut write_buf =
+      MemoryCharge::with_len(&counters, Buffer::WriteBuf, 0x4000);
+    assert_eq!(counters.stats().disk_queue_bytes, 0x4000);
+    assert!(!counters.is_over_budget());
+
+    // all buffers count towards the budget
+    write_buf.add(1);
+    assert!(counters.is_over_budget());
+    drop(queued);
+    assert!(!counters.is_over_budget());
+    write_buf.clear();
+    assert_eq!(counters.stats(), MemoryStats::default());
+
+    // without a budget the buffers may hold any amount
+    let counters = Arc::new(MemoryCounters::default());
+    let _charge = MemoryCharge::with_len(&counters, Buffer::PeerQueue, 1 << 40);
+    assert!(!counters.is_over_budget());
+  }
+}
@@ -25,8 +25,18 @@ pub struct Metainfo {
   /// A list of strings corresponding to subdirectory names,
   /// the last of which is the actual file name
   pub files: Vec<FileInfo>,
-  /// The trackers that we can announce to.
+  /// The torrent's trackers, of any protocol, as the tracker layer decides
+  /// which ones it can announce to.
   pub trackers: Vec<Url>,
+  /// Whether the torrent is private (BEP 27), in which case its peers are
+  /// only obtained from its trackers, and not from the DHT.
+  pub private: bool,
+  /// The bencoded metainfo this was parsed from, kept so that the torrent
+  /// can be saved with the engine's session.
+  pub(crate) raw: Vec<u8>,
+  /// The bencoded info dictionary, whose hash is the info hash, kept so
+  /// that it can be sent to peers fetching the torrent's metadata.
+  pub(crate) info: Vec<u8>,
 }
 
 impl fmt::Debug for Metainfo {
@@ -57,7 +67,7 @@ impl Metainfo {
 
     // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
     // must be a multiple of 20
-    if metainfo.info.pieces.len() % 20 != 0 {
+    if !metainfo.info.pieces.len().is_multiple_of(20) {
       return Err(MetainfoError::InvalidMetainfo);
     }
 
@@ -142,27 +152,20 @@ impl Metainfo {
 
       for announce in metainfo.announce_list.iter() {
         for tracker in announce.iter() {
-          let url = Url::parse(tracker)?;
-
-          // TODO: may use UDP ???
-          if url.scheme() == "http" || url.scheme() == "https" {
-            trackers.push(url);
-          }
+          trackers.push(Url::parse(tracker)?);
         }
       }
     } else if let Some(tracker) = &metainfo.announce {
-      let url = Url::parse(tracker)?;
-      if url.scheme() == "http" || url.scheme() == "https" {
-        trackers.push(url);
-      }
+      trackers.push(Url::parse(tracker)?);
     }
 
     if trackers.is_empty() {
-      log::warn!("No HTTP trackers in metainfo");
+      log::warn!("No trackers in metainfo");
     }
 
     // create the info hash.
-    let info_hash = metainfo.create_info_hash()?;
+    let info = metainfo.encode_info()?;
+    let info_hash = <sha1::Sha1 as sha1::Digest>::digest(&info).into();
 
     Ok(Metainfo {
       name: metainfo.info.name,
@@ -171,9 +174,22 @@ impl Metainfo {
       piece_len: metainfo.info.piece_len,
       files,
       trackers,
+      private: metainfo.info.private == Some(1),
+      raw: bytes.to_vec(),
+      info,
     })
   }
 
+  /// Creates the metainfo of a torrent from its bencoded info dictionary,
+  /// e.g. as fetched from peers, without any trackers.
+  pub fn from_info(info: &[u8]) -> Result<Self> {
+    let mut bytes = Vec::with_capacity(info.len() + 8);
+    bytes.extend_from_slice(b"d4:info");
+    bytes.extend_from_slice(info);
+    bytes.push(b'e');
+    Self::from_bytes(&bytes)
+  }
+
   /// Return true if the download multi files
   pub fn is_archive(&self) -> bool {
     self.files.len() > 1
@@ -196,11 +212,8 @@ impl Metainfo {
 mod raw {
   //! Only for `bencode` crate deserialize to
   //! convert into ``
-  use serde_derive::{Deserialize, Serialize};
-  use sha1::Digest;
-
   use super::*;
-  use crate::Sha1Hash;
+  use serde_derive::{Deserialize, Serialize};
 
   /// Details field meaning in [.torrent file](https://en.wikipedia.org/wiki/Torrent_file)
   #[derive(Debug, Deserialize)]
@@ -215,12 +228,9 @@ mod raw {
   }
 
   impl Metainfo {
-    pub fn create_info_hash(&self) -> Result<Sha1Hash> {
-      let info = serde_bencoded::to_vec(&self.info)?;
-      let digest = sha1::Sha1::digest(info);
-      let mut info_hash = [0; 20];
-      info_hash.copy_from_slice(&digest);
-      Ok(info_hash)
+    /// Encodes the info dictionary, whose hash is the info hash.
+    pub fn encode_info(&self) -> Result<Vec<u8>> {
+      Ok(serde_bencoded::to_vec(&self.info)?)
     }
   }
 
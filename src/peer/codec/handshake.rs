@@ -4,6 +4,26 @@ use bytes::{Buf, BufMut};
 use tokio_util::codec::{Decoder, Encoder};
 
 pub const PROTOCOL_STRING: &str = "BitTorrent protocol";
+
+/// The length of the handshake on the wire, including the protocol string's
+/// length prefix.
+pub const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+
+/// The byte and bit of the reserved field by which a peer tells that it
+/// supports the extension protocol (BEP 10).
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
+/// The byte and bit of the reserved field by which a peer tells that it
+/// supports the Fast extension (BEP 6).
+const FAST_EXTENSION_BYTE: usize = 7;
+const FAST_EXTENSION_BIT: u8 = 0x04;
+
+/// The byte and bit of the reserved field by which a peer tells that it
+/// runs a DHT node (BEP 5).
+const DHT_BYTE: usize = 7;
+const DHT_BIT: u8 = 0x01;
+
 /// The message sent at the beginning of a peer session by both
 /// sides of the connection.
 ///
@@ -19,8 +39,9 @@ pub struct Handshake {
   /// The protocol string, which must equal "BitTorrent protocol",
   /// as otherwise the connection will aborted.
   pub prot: [u8; 19],
-  /// A reserved field, currently all zero. This is where the client's
-  /// supported extensions are announced.
+  /// A reserved field, where the client's supported extensions are
+  /// announced. We announce the extension protocol and the Fast extension,
+  /// and the DHT if we run a node.
   pub reserved: [u8; 8],
   /// The torrent's SHA1 info hash, used to identify the torrent in the
   /// handshake and to verify the peer.
@@ -34,13 +55,40 @@ impl Handshake {
   pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
     let mut prot = [0; 19];
     prot.copy_from_slice(PROTOCOL_STRING.as_bytes());
+    let mut reserved = [0; 8];
+    reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+    reserved[FAST_EXTENSION_BYTE] |= FAST_EXTENSION_BIT;
     Handshake {
       prot,
-      reserved: [0; 8],
+      reserved,
       info_hash,
       peer_id,
     }
   }
+
+  /// Returns whether the peer supports the extension protocol, through which
+  /// e.g. the torrent's metadata may be exchanged.
+  pub fn supports_extensions(&self) -> bool {
+    self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+  }
+
+  /// Returns whether the peer supports the Fast extension, with which e.g.
+  /// requests that won't be served are rejected explicitly.
+  pub fn supports_fast(&self) -> bool {
+    self.reserved[FAST_EXTENSION_BYTE] & FAST_EXTENSION_BIT != 0
+  }
+
+  /// Tells the peer that we run a DHT node, whose port we send it after the
+  /// handshake.
+  pub fn set_dht(&mut self) {
+    self.reserved[DHT_BYTE] |= DHT_BIT;
+  }
+
+  /// Returns whether the peer runs a DHT node.
+  pub fn supports_dht(&self) -> bool {
+    self.reserved[DHT_BYTE] & DHT_BIT != 0
+  }
+
   /// Returns the length of handshake, in bytes.
   #[allow(clippy::len_without_is_empty)]
   pub const fn len(&self) -> u64 {
@@ -98,7 +146,12 @@ impl Decoder for HandshakeCodec {
     // we just want to peek at this value.
     let mut tmp_buf = Cursor::new(&buf);
     let prot_len = tmp_buf.get_u8() as usize;
-    if prot_len != PROTOCOL_STRING.as_bytes().len() {
+    // the protocol string is checked as far as it arrived, so that a peer
+    // speaking another protocol is dropped without waiting for more bytes
+    let prot_prefix = &buf[1..buf.len().min(1 + prot_len)];
+    if prot_len != PROTOCOL_STRING.len()
+      || !PROTOCOL_STRING.as_bytes().starts_with(prot_prefix)
+    {
       return Err(io::Error::new(
         io::ErrorKind::InvalidInput,
         r#"Handshake must have the string "BitTorrent protocol"."#,
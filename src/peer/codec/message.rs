@@ -1,6 +1,6 @@
 use std::io;
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 use crate::{
   blockinfo::{BlockData, BlockInfo},
@@ -22,6 +22,13 @@ pub enum MessageId {
   Request = 6,
   Block = 7,
   Cancel = 8,
+  Port = 9,
+  SuggestPiece = 13,
+  HaveAll = 14,
+  HaveNone = 15,
+  RejectRequest = 16,
+  AllowedFast = 17,
+  Extended = 20,
 }
 
 impl MessageId {
@@ -41,6 +48,13 @@ impl MessageId {
       MessageId::Request => 4 + 1 + 3 * 4,
       MessageId::Block => 4 + 1 + 2 * 4,
       MessageId::Cancel => 4 + 1 + 3 * 4,
+      MessageId::Port => 4 + 1 + 2,
+      MessageId::SuggestPiece => 4 + 1 + 4,
+      MessageId::HaveAll => 4 + 1,
+      MessageId::HaveNone => 4 + 1,
+      MessageId::RejectRequest => 4 + 1 + 3 * 4,
+      MessageId::AllowedFast => 4 + 1 + 4,
+      MessageId::Extended => 4 + 1 + 1,
     }
   }
 }
@@ -59,6 +73,13 @@ impl TryFrom<u8> for MessageId {
       k if k == Request as u8 => Ok(Request),
       k if k == Block as u8 => Ok(Block),
       k if k == Cancel as u8 => Ok(Cancel),
+      k if k == Port as u8 => Ok(Port),
+      k if k == SuggestPiece as u8 => Ok(SuggestPiece),
+      k if k == HaveAll as u8 => Ok(HaveAll),
+      k if k == HaveNone as u8 => Ok(HaveNone),
+      k if k == RejectRequest as u8 => Ok(RejectRequest),
+      k if k == AllowedFast as u8 => Ok(AllowedFast),
+      k if k == Extended as u8 => Ok(Extended),
       _ => Err(io::Error::new(
         io::ErrorKind::InvalidInput,
         "Unknown message id",
@@ -87,6 +108,32 @@ pub enum Message {
     data: BlockData,
   },
   Cancel(BlockInfo),
+  /// The port of the peer's DHT node (BEP 5), on the peer's IP address.
+  Port(u16),
+  /// The Fast extension's (BEP 6) hint that the peer would like us to
+  /// download the piece, e.g. because it's in its cache.
+  SuggestPiece {
+    piece_index: usize,
+  },
+  /// The Fast extension's replacement of the bitfield for a peer with all
+  /// pieces.
+  HaveAll,
+  /// The Fast extension's replacement of the bitfield for a peer with no
+  /// pieces.
+  HaveNone,
+  /// Tells that a request won't be served, which with the Fast extension
+  /// is sent for every request that is dropped, e.g. on choking.
+  RejectRequest(BlockInfo),
+  /// Tells that the piece may be requested even while choked.
+  AllowedFast {
+    piece_index: usize,
+  },
+  /// A message of the extension protocol (BEP 10), with the id of the
+  /// extension message and its payload, which is decoded by the extension.
+  Extended {
+    id: u8,
+    payload: Bytes,
+  },
 }
 
 impl Message {
@@ -103,6 +150,13 @@ impl Message {
       Message::Request(_) => Some(MessageId::Request),
       Message::Block { .. } => Some(MessageId::Block),
       Message::Cancel(_) => Some(MessageId::Cancel),
+      Message::Port(_) => Some(MessageId::Port),
+      Message::SuggestPiece { .. } => Some(MessageId::SuggestPiece),
+      Message::HaveAll => Some(MessageId::HaveAll),
+      Message::HaveNone => Some(MessageId::HaveNone),
+      Message::RejectRequest(_) => Some(MessageId::RejectRequest),
+      Message::AllowedFast { .. } => Some(MessageId::AllowedFast),
+      Message::Extended { .. } => Some(MessageId::Extended),
     }
   }
 
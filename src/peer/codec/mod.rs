@@ -1,6 +1,7 @@
 pub mod handshake;
 pub mod message;
 pub mod peercodec;
+pub mod testvectors;
 
 #[cfg(test)]
 mod tests {
@@ -166,6 +167,10 @@ mod tests {
     };
     let result = HandshakeCodec.decode(&mut invalid_encoded);
     assert!(result.is_err());
+
+    // a wrong protocol string is detected before the rest arrives
+    let mut invalid_encoded = BytesMut::from(&b"\x13GET / HTTP"[..]);
+    assert!(HandshakeCodec.decode(&mut invalid_encoded).is_err());
   }
 
   // Returns a `Handshake` and its expected encoded variant.
@@ -3,10 +3,18 @@ use std::io::{self, Cursor};
 use bytes::{Buf, BufMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::{blockinfo::BlockInfo, peer::codec::message::MessageId, Bitfield};
+use crate::{
+  blockinfo::BlockInfo,
+  peer::{codec::message::MessageId, extension::METADATA_PIECE_LEN},
+  Bitfield,
+};
 
 use super::message::Message;
 
+/// The longest extension message accepted, which fits a metadata piece along
+/// with its bencoded header and the message and extension ids.
+const MAX_EXTENDED_MSG_LEN: usize = METADATA_PIECE_LEN + 512;
+
 /// Codec for encoding and decoding messages exchanged by peers
 /// (other than the handshake).
 pub struct PeerCodec;
@@ -123,6 +131,57 @@ impl Encoder<Message> for PeerCodec {
         // payload
         block.encode(buf)?;
       }
+      Port(port) => {
+        // message length prefix: 1 byte message id and 2 byte port
+        let msg_len = 1 + 2;
+        buf.put_u32(msg_len);
+        // message id
+        buf.put_u8(MessageId::Port as u8);
+        // payload
+        buf.put_u16(port);
+      }
+      SuggestPiece { piece_index } | AllowedFast { piece_index } => {
+        // message length prefix:
+        // 1 byte message id and 4 byte piece index
+        let msg_len = 1 + 4;
+        buf.put_u32(msg_len);
+        // message id
+        buf.put_u8(msg.id().expect("message has id") as u8);
+        // payload
+        let piece_index = piece_index
+          .try_into()
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        buf.put_u32(piece_index);
+      }
+      HaveAll | HaveNone => {
+        // message length prefix: 1 byte message id
+        let msg_len = 1;
+        buf.put_u32(msg_len);
+        // message id
+        buf.put_u8(msg.id().expect("message has id") as u8);
+        // no payload
+      }
+      RejectRequest(block) => {
+        // message length prefix:
+        // 1 byte message id, 4 byte piece index, 4 byte offset, 4 byte length
+        let msg_len = 1 + 4 + 4 + 4;
+        buf.put_u32(msg_len);
+        // message id
+        buf.put_u8(MessageId::RejectRequest as u8);
+        // payload
+        block.encode(buf)?;
+      }
+      Extended { id, payload } => {
+        // message length prefix:
+        // 1 byte message id, 1 byte extension message id, and n byte payload
+        let msg_len = 1 + 1 + payload.len() as u32;
+        buf.put_u32(msg_len);
+        // message id
+        buf.put_u8(MessageId::Extended as u8);
+        // payload
+        buf.put_u8(id);
+        buf.put(&payload[..]);
+      }
     }
 
     Ok(())
@@ -153,6 +212,16 @@ impl Decoder for PeerCodec {
 
     tmp_buf.set_position(0);
 
+    // reject oversized extension messages before buffering them
+    if msg_len > MAX_EXTENDED_MSG_LEN
+      && buf.get(4) == Some(&(MessageId::Extended as u8))
+    {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "extended message too long",
+      ));
+    }
+
     // check that we got the full payload in the buffer
     // NOTE: we need to add the message length prefix's byte count to msg_len
     // since the buffer cursor was not advanced and thus we need to consider the
@@ -177,6 +246,24 @@ impl Decoder for PeerCodec {
     }
 
     let msg_id = MessageId::try_from(buf.get_u8())?;
+
+    // messages of a fixed size must be exactly that long, and the others at
+    // least as long as their headers, so that the fields are never read
+    // from past the message
+    let header_len = msg_id.header_len() as usize - 4;
+    let is_valid_len = match msg_id {
+      MessageId::Bitfield | MessageId::Block | MessageId::Extended => {
+        msg_len >= header_len
+      }
+      _ => msg_len == header_len,
+    };
+    if !is_valid_len {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{:?} message of invalid length {}", msg_id, msg_len),
+      ));
+    }
+
     let msg = match msg_id {
       MessageId::Choke => Message::Choke,
       MessageId::Unchoke => Message::Unchoke,
@@ -204,6 +291,7 @@ impl Decoder for PeerCodec {
         let piece_index = piece_index
           .try_into()
           .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        validate_request_len(len)?;
         Message::Request(BlockInfo {
           piece_index,
           offset,
@@ -222,6 +310,7 @@ impl Decoder for PeerCodec {
         // preallocate buffer to the length of bitfield, which
         // is the value gotten by subtracting the id length from the
         // message length.
+        validate_block_geometry(offset, (msg_len - 9) as u32)?;
         let mut data = vec![0; msg_len - 9];
         buf.copy_to_slice(&mut data);
         Message::Block {
@@ -238,18 +327,73 @@ impl Decoder for PeerCodec {
 
         let offset = buf.get_u32();
         let len = buf.get_u32();
+        validate_request_len(len)?;
         Message::Cancel(BlockInfo {
           piece_index,
           offset,
           len,
         })
       }
+      MessageId::Port => Message::Port(buf.get_u16()),
+      MessageId::SuggestPiece | MessageId::AllowedFast => {
+        let piece_index = buf.get_u32();
+        let piece_index = piece_index
+          .try_into()
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if msg_id == MessageId::SuggestPiece {
+          Message::SuggestPiece { piece_index }
+        } else {
+          Message::AllowedFast { piece_index }
+        }
+      }
+      MessageId::HaveAll => Message::HaveAll,
+      MessageId::HaveNone => Message::HaveNone,
+      MessageId::RejectRequest => {
+        let piece_index = buf.get_u32();
+        let piece_index = piece_index
+          .try_into()
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let offset = buf.get_u32();
+        let len = buf.get_u32();
+        validate_block_geometry(offset, len)?;
+        Message::RejectRequest(BlockInfo {
+          piece_index,
+          offset,
+          len,
+        })
+      }
+      MessageId::Extended => {
+        if msg_len < 2 {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "extended message without id",
+          ));
+        }
+        let id = buf.get_u8();
+        let payload = buf.split_to(msg_len - 2).freeze();
+        Message::Extended { id, payload }
+      }
     };
 
     Ok(Some(msg))
   }
 }
 
+/// Rejects blocks that can't be valid in any piece. The rest of the
+/// validation is left to the peer session, which knows the piece lengths.
+fn validate_block_geometry(offset: u32, len: u32) -> io::Result<()> {
+  BlockInfo::validate_geometry(offset, len)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Rejects requests, and their cancels, that can't be valid in any piece.
+/// Unlike blocks, they may have any offset and be longer than a block.
+fn validate_request_len(len: u32) -> io::Result<()> {
+  BlockInfo::validate_request_len(len)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
 #[cfg(test)]
 mod tests {
   use bytes::{Bytes, BytesMut};
@@ -257,7 +401,7 @@ mod tests {
   use super::*;
   use crate::{
     peer::codec::handshake::{Handshake, HandshakeCodec, PROTOCOL_STRING},
-    BLOCK_LEN,
+    BLOCK_LEN, MAX_REQUEST_LEN,
   };
 
   /// Tests a stream of arbitrary messages to ensure that not only do they
@@ -283,6 +427,7 @@ mod tests {
       make_cancel(),
       make_block(),
       make_not_interested(),
+      make_extended(),
       make_choke(),
       make_choke(),
     ];
@@ -383,6 +528,16 @@ mod tests {
     // decode same handshake
     let decoded = HandshakeCodec.decode(&mut encoded).unwrap();
     assert_eq!(decoded, Some(handshake));
+
+    // we tell peers that we support the extension protocol
+    assert!(!handshake.supports_extensions());
+    let mut handshake = Handshake::new(handshake.info_hash, handshake.peer_id);
+    assert!(handshake.supports_extensions());
+
+    // and that we run a DHT node only if we do
+    assert!(!handshake.supports_dht());
+    handshake.set_dht();
+    assert!(handshake.supports_dht());
   }
 
   /// Tests that the decoding of various invalid handshake messages results in
@@ -419,8 +574,7 @@ mod tests {
     let mut prot = [0; 19];
     prot.copy_from_slice(PROTOCOL_STRING.as_bytes());
 
-    // the reserved field is all zeros for now as we don't use extensions
-    // yet so we're not testing it
+    // the reserved field is all zeros, as of a peer without extensions
     let reserved = [0; 8];
 
     // this is not a valid info hash but it doesn't matter for the purposes
@@ -531,6 +685,80 @@ mod tests {
     assert_message_codec(msg, expected_encoded);
   }
 
+  /// Tests the encoding and subsequent decoding of a valid extension
+  /// protocol message.
+  #[test]
+  fn test_extended_codec() {
+    let (msg, expected_encoded) = make_extended();
+    assert_message_codec(msg, expected_encoded);
+
+    // the extension message id is mandatory
+    let mut encoded =
+      BytesMut::from(&[0, 0, 0, 1, MessageId::Extended as u8][..]);
+    assert!(PeerCodec.decode(&mut encoded).is_err());
+
+    // and messages longer than a metadata piece are rejected as soon as
+    // their header arrives
+    let mut encoded = BytesMut::new();
+    encoded.put_u32(MAX_EXTENDED_MSG_LEN as u32 + 1);
+    encoded.put_u8(MessageId::Extended as u8);
+    assert!(PeerCodec.decode(&mut encoded).is_err());
+  }
+
+  /// Tests that messages of a fixed size are rejected by the decoder if
+  /// their length differs, rather than read into the next message.
+  #[test]
+  fn test_invalid_fixed_len_decoding() {
+    for (id, len) in [
+      (MessageId::Choke, 2),
+      (MessageId::Have, 4),
+      (MessageId::SuggestPiece, 3),
+      (MessageId::AllowedFast, 6),
+      (MessageId::RejectRequest, 9),
+      (MessageId::Port, 5),
+      (MessageId::Block, 8),
+    ] {
+      let mut encoded = BytesMut::new();
+      encoded.put_u32(len);
+      encoded.put_u8(id as u8);
+      encoded.put_bytes(0, len as usize - 1);
+      // a well-formed message follows, which mustn't be read into
+      PeerCodec.encode(Message::Unchoke, &mut encoded).unwrap();
+      let err = PeerCodec.decode(&mut encoded).unwrap_err();
+      assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", id);
+    }
+  }
+
+  /// Tests that requests for blocks that can't be valid in any piece are
+  /// rejected by the decoder.
+  #[test]
+  fn test_invalid_request_decoding() {
+    for (offset, len) in [(0, 0), (0, MAX_REQUEST_LEN + 1)] {
+      let mut encoded = BytesMut::from(
+        &make_block_info_encoded_msg_payload(
+          MessageId::Request,
+          42,
+          offset,
+          len,
+        )[..],
+      );
+      assert!(PeerCodec.decode(&mut encoded).is_err());
+    }
+
+    // but requests may be longer than a block, and off the block grid
+    for (offset, len) in [(0, MAX_REQUEST_LEN), (100, BLOCK_LEN)] {
+      let mut encoded = BytesMut::from(
+        &make_block_info_encoded_msg_payload(
+          MessageId::Request,
+          42,
+          offset,
+          len,
+        )[..],
+      );
+      assert!(PeerCodec.decode(&mut encoded).unwrap().is_some());
+    }
+  }
+
   /// Helper function that asserts that a message is encoded and subsequently
   /// decoded correctly.
   fn assert_message_codec(msg: Message, expected_encoded: Bytes) {
@@ -598,6 +826,24 @@ mod tests {
     buf.into()
   }
 
+  /// Returns `Extended` and its expected encoded variant.
+  fn make_extended() -> (Message, Bytes) {
+    let payload = Bytes::from_static(b"d1:md11:ut_metadatai1eee");
+    let encoded = {
+      // 1 byte message id, 1 byte extension message id and n byte payload
+      let msg_len = 1 + 1 + payload.len();
+      // 4 byte message length prefix and message length
+      let buf_len = 4 + msg_len;
+      let mut buf = BytesMut::with_capacity(buf_len);
+      buf.put_u32(msg_len as u32);
+      buf.put_u8(MessageId::Extended as u8);
+      buf.put_u8(0);
+      buf.extend_from_slice(&payload);
+      buf
+    };
+    (Message::Extended { id: 0, payload }, encoded.into())
+  }
+
   /// Returns `Bitfield` and its expected encoded variant.
   fn make_bitfield() -> (Message, Bytes) {
     let bitfield = Bitfield::from_vec(vec![0b11001001, 0b10000011, 0b11111011]);
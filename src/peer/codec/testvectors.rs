@@ -0,0 +1,311 @@
+//! Canonical wire encodings of every peer protocol message.
+//!
+//! The byte sequences here are written out by hand from the BitTorrent
+//! specification (BEP 3) rather than produced by our own encoder, so they can
+//! be used to check the codec (or another implementation) for conformance.
+//! All integers are big endian and every message, except for the handshake,
+//! is prefixed by its 4 byte length.
+
+use bytes::Bytes;
+
+use crate::{blockinfo::BlockInfo, Bitfield, BLOCK_LEN};
+
+use super::{handshake::Handshake, message::Message};
+
+/// The info hash used in the handshake vector.
+pub const INFO_HASH: [u8; 20] = *b"da39a3ee5e6b4b0d3255";
+
+/// The peer id used in the handshake vector.
+pub const PEER_ID: [u8; 20] = *b"cbt-2020-03-03-00000";
+
+/// `<pstrlen=19><pstr><reserved><info_hash><peer_id>`, where the reserved
+/// field has the extension protocol (BEP 10) and Fast extension (BEP 6) bits
+/// set.
+pub const HANDSHAKE: [u8; 68] = *b"\x13BitTorrent protocol\
+  \x00\x00\x00\x00\x00\x10\x00\x04\
+  da39a3ee5e6b4b0d3255\
+  cbt-2020-03-03-00000";
+
+/// `<len=0>`
+pub const KEEP_ALIVE: [u8; 4] = [0, 0, 0, 0];
+
+/// `<len=1><id=0>`
+pub const CHOKE: [u8; 5] = [0, 0, 0, 1, 0];
+
+/// `<len=1><id=1>`
+pub const UNCHOKE: [u8; 5] = [0, 0, 0, 1, 1];
+
+/// `<len=1><id=2>`
+pub const INTERESTED: [u8; 5] = [0, 0, 0, 1, 2];
+
+/// `<len=1><id=3>`
+pub const NOT_INTERESTED: [u8; 5] = [0, 0, 0, 1, 3];
+
+/// `<len=5><id=4><piece index=42>`
+pub const HAVE: [u8; 9] = [0, 0, 0, 5, 4, 0, 0, 0, 42];
+
+/// `<len=5><id=4><piece index=u32::MAX>`
+pub const HAVE_MAX_INDEX: [u8; 9] = [0, 0, 0, 5, 4, 0xff, 0xff, 0xff, 0xff];
+
+/// `<len=4><id=5><bitfield=0b11001001 0b10000011 0b11111011>`
+pub const BITFIELD: [u8; 8] =
+  [0, 0, 0, 4, 5, 0b1100_1001, 0b1000_0011, 0b1111_1011];
+
+/// `<len=1><id=5>`, a bitfield message without any payload.
+pub const EMPTY_BITFIELD: [u8; 5] = [0, 0, 0, 1, 5];
+
+/// `<len=13><id=6><piece index=42><offset=0x4000><len=0x4000>`
+pub const REQUEST: [u8; 17] =
+  [0, 0, 0, 13, 6, 0, 0, 0, 42, 0, 0, 0x40, 0, 0, 0, 0x40, 0];
+
+/// `<len=13><id=8><piece index=42><offset=0x4000><len=0x4000>`
+pub const CANCEL: [u8; 17] =
+  [0, 0, 0, 13, 8, 0, 0, 0, 42, 0, 0, 0x40, 0, 0, 0, 0x40, 0];
+
+/// `<len=3><id=9><port=6881>`
+pub const PORT: [u8; 7] = [0, 0, 0, 3, 9, 0x1a, 0xe1];
+
+/// `<len=5><id=13><piece index=42>`
+pub const SUGGEST_PIECE: [u8; 9] = [0, 0, 0, 5, 13, 0, 0, 0, 42];
+
+/// `<len=1><id=14>`
+pub const HAVE_ALL: [u8; 5] = [0, 0, 0, 1, 14];
+
+/// `<len=1><id=15>`
+pub const HAVE_NONE: [u8; 5] = [0, 0, 0, 1, 15];
+
+/// `<len=13><id=16><piece index=42><offset=0x4000><len=0x4000>`
+pub const REJECT_REQUEST: [u8; 17] =
+  [0, 0, 0, 13, 16, 0, 0, 0, 42, 0, 0, 0x40, 0, 0, 0, 0x40, 0];
+
+/// `<len=5><id=17><piece index=42>`
+pub const ALLOWED_FAST: [u8; 9] = [0, 0, 0, 5, 17, 0, 0, 0, 42];
+
+/// `<len=4><id=20><extension message id=0><payload="de">`, an extension
+/// handshake with an empty dictionary (BEP 10).
+pub const EXTENDED: [u8; 8] = [0, 0, 0, 4, 20, 0, b'd', b'e'];
+
+/// `<len=12><id=7><piece index=42><offset=0x4000><block=[1, 2, 3]>`
+pub const BLOCK: [u8; 16] =
+  [0, 0, 0, 12, 7, 0, 0, 0, 42, 0, 0, 0x40, 0, 1, 2, 3];
+
+/// The header of a block message carrying a full [`BLOCK_LEN`] long block:
+/// `<len=0x4009><id=7><piece index=42><offset=0x4000>`
+///
+/// The payload that follows the header is [`MAX_BLOCK_PAYLOAD`] repeated
+/// `BLOCK_LEN` times.
+pub const MAX_BLOCK_HEADER: [u8; 13] =
+  [0, 0, 0x40, 0x09, 7, 0, 0, 0, 42, 0, 0, 0x40, 0];
+
+/// The value of every byte in the max length block vector's payload.
+pub const MAX_BLOCK_PAYLOAD: u8 = 0xab;
+
+/// A single conformance test vector: a message and its canonical encoding.
+#[derive(Debug)]
+pub struct MessageVector {
+  /// A short, human readable name of the vector.
+  pub name: &'static str,
+  /// The decoded message.
+  pub msg: Message,
+  /// The exact bytes the message must be encoded to and decoded from.
+  pub encoded: Vec<u8>,
+}
+
+/// Returns the handshake vector, made up of [`INFO_HASH`] and [`PEER_ID`],
+/// and its canonical encoding.
+pub fn handshake() -> (Handshake, [u8; 68]) {
+  (Handshake::new(INFO_HASH, PEER_ID), HANDSHAKE)
+}
+
+/// Returns a vector for each message type, including edge cases such as an
+/// empty bitfield and a block of the maximum length.
+pub fn messages() -> Vec<MessageVector> {
+  let block_info = BlockInfo {
+    piece_index: 42,
+    offset: 0x4000,
+    len: BLOCK_LEN,
+  };
+
+  let mut max_block = MAX_BLOCK_HEADER.to_vec();
+  max_block.resize(
+    MAX_BLOCK_HEADER.len() + BLOCK_LEN as usize,
+    MAX_BLOCK_PAYLOAD,
+  );
+
+  vec![
+    MessageVector {
+      name: "keep alive",
+      msg: Message::KeepAlive,
+      encoded: KEEP_ALIVE.to_vec(),
+    },
+    MessageVector {
+      name: "choke",
+      msg: Message::Choke,
+      encoded: CHOKE.to_vec(),
+    },
+    MessageVector {
+      name: "unchoke",
+      msg: Message::Unchoke,
+      encoded: UNCHOKE.to_vec(),
+    },
+    MessageVector {
+      name: "interested",
+      msg: Message::Interested,
+      encoded: INTERESTED.to_vec(),
+    },
+    MessageVector {
+      name: "not interested",
+      msg: Message::NotInterested,
+      encoded: NOT_INTERESTED.to_vec(),
+    },
+    MessageVector {
+      name: "have",
+      msg: Message::Have { piece_index: 42 },
+      encoded: HAVE.to_vec(),
+    },
+    MessageVector {
+      name: "have with max piece index",
+      msg: Message::Have {
+        piece_index: u32::MAX as usize,
+      },
+      encoded: HAVE_MAX_INDEX.to_vec(),
+    },
+    MessageVector {
+      name: "bitfield",
+      msg: Message::Bitfield(Bitfield::from_vec(vec![
+        0b1100_1001,
+        0b1000_0011,
+        0b1111_1011,
+      ])),
+      encoded: BITFIELD.to_vec(),
+    },
+    MessageVector {
+      name: "empty bitfield",
+      msg: Message::Bitfield(Bitfield::new()),
+      encoded: EMPTY_BITFIELD.to_vec(),
+    },
+    MessageVector {
+      name: "request",
+      msg: Message::Request(block_info),
+      encoded: REQUEST.to_vec(),
+    },
+    MessageVector {
+      name: "block",
+      msg: Message::Block {
+        piece_index: 42,
+        offset: 0x4000,
+        data: vec![1, 2, 3].into(),
+      },
+      encoded: BLOCK.to_vec(),
+    },
+    MessageVector {
+      name: "max length block",
+      msg: Message::Block {
+        piece_index: 42,
+        offset: 0x4000,
+        data: vec![MAX_BLOCK_PAYLOAD; BLOCK_LEN as usize].into(),
+      },
+      encoded: max_block,
+    },
+    MessageVector {
+      name: "cancel",
+      msg: Message::Cancel(block_info),
+      encoded: CANCEL.to_vec(),
+    },
+    MessageVector {
+      name: "port",
+      msg: Message::Port(6881),
+      encoded: PORT.to_vec(),
+    },
+    MessageVector {
+      name: "suggest piece",
+      msg: Message::SuggestPiece { piece_index: 42 },
+      encoded: SUGGEST_PIECE.to_vec(),
+    },
+    MessageVector {
+      name: "have all",
+      msg: Message::HaveAll,
+      encoded: HAVE_ALL.to_vec(),
+    },
+    MessageVector {
+      name: "have none",
+      msg: Message::HaveNone,
+      encoded: HAVE_NONE.to_vec(),
+    },
+    MessageVector {
+      name: "reject request",
+      msg: Message::RejectRequest(block_info),
+      encoded: REJECT_REQUEST.to_vec(),
+    },
+    MessageVector {
+      name: "allowed fast",
+      msg: Message::AllowedFast { piece_index: 42 },
+      encoded: ALLOWED_FAST.to_vec(),
+    },
+    MessageVector {
+      name: "extended",
+      msg: Message::Extended {
+        id: 0,
+        payload: Bytes::from_static(b"de"),
+      },
+      encoded: EXTENDED.to_vec(),
+    },
+  ]
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::BytesMut;
+  use tokio_util::codec::{Decoder, Encoder};
+
+  use super::*;
+  use crate::peer::codec::{handshake::HandshakeCodec, peercodec::PeerCodec};
+
+  /// Tests that every vector is encoded to exactly its canonical bytes and
+  /// that those bytes decode back to the same message.
+  #[test]
+  fn test_message_vectors() {
+    for MessageVector { name, msg, encoded } in messages() {
+      let mut buf = BytesMut::new();
+      PeerCodec.encode(msg.clone(), &mut buf).unwrap();
+      assert_eq!(&buf[..], &encoded[..], "encoding {}", name);
+
+      let mut buf = BytesMut::from(&encoded[..]);
+      let decoded = PeerCodec.decode(&mut buf).unwrap();
+      assert_eq!(decoded, Some(msg), "decoding {}", name);
+      assert!(buf.is_empty(), "{} not fully consumed", name);
+    }
+  }
+
+  /// Tests that all vectors decode correctly when concatenated in a single
+  /// stream, after the handshake.
+  #[test]
+  fn test_vector_stream() {
+    let (handshake, encoded_handshake) = handshake();
+    let vectors = messages();
+
+    let mut buf = BytesMut::from(&encoded_handshake[..]);
+    for v in &vectors {
+      buf.extend_from_slice(&v.encoded);
+    }
+
+    assert_eq!(HandshakeCodec.decode(&mut buf).unwrap(), Some(handshake));
+    for v in vectors {
+      assert_eq!(PeerCodec.decode(&mut buf).unwrap(), Some(v.msg));
+    }
+    assert!(buf.is_empty());
+  }
+
+  /// Tests the handshake vector against the handshake codec.
+  #[test]
+  fn test_handshake_vector() {
+    let (handshake, encoded) = handshake();
+
+    let mut buf = BytesMut::new();
+    HandshakeCodec.encode(handshake, &mut buf).unwrap();
+    assert_eq!(&buf[..], &encoded[..]);
+
+    let decoded = HandshakeCodec.decode(&mut buf).unwrap();
+    assert_eq!(decoded, Some(handshake));
+  }
+}
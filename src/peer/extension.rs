@@ -0,0 +1,440 @@
+//! The extension protocol (BEP 10) and the metadata exchange built on it
+//! (BEP 9), through which torrents started from a magnet link fetch their
+//! info dictionary from peers, and through which we serve ours.
+//!
+//! Extension messages are carried by [`Message::Extended`], whose id is 0
+//! for the extension handshake, in which each side tells the ids by which it
+//! wants to receive the messages of each extension it supports, and
+//! otherwise one of those ids.
+//!
+//! Most extension payloads are a bencoded dictionary, some followed by raw
+//! data, which [`encode_payload`], [`decode_payload`] and
+//! [`decode_payload_prefix`] convert to and from the extension's types.
+//!
+//! [`Message::Extended`]: super::codec::message::Message::Extended
+
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::{
+  error::peer::{PeerError, Result},
+  Sha1Hash,
+};
+
+/// The id of the extension handshake message.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// The id by which we receive metadata messages, as told to peers in our
+/// extension handshake.
+pub const UT_METADATA_ID: u8 = 1;
+
+/// The name of the metadata exchange extension in extension handshakes.
+const UT_METADATA: &str = "ut_metadata";
+
+/// The length of each piece of the metadata, but the last.
+pub const METADATA_PIECE_LEN: usize = 0x4000;
+
+/// The largest metadata accepted from peers, so that a peer can't make us
+/// allocate an arbitrary amount of memory.
+pub const MAX_METADATA_LEN: usize = 16 * 1024 * 1024;
+
+/// The extension handshake, which tells the extensions a peer supports.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+  /// The supported extensions, by the ids with which their messages are to
+  /// be sent. An id of 0 means that the extension was disabled.
+  #[serde(default)]
+  pub m: BTreeMap<String, u8>,
+  /// The length of the torrent's info dictionary, if the peer has it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub metadata_size: Option<usize>,
+  /// The most requests the peer accepts outstanding at a time.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub reqq: Option<usize>,
+  /// The name and version of the peer's client.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub v: Option<String>,
+  /// 1 if the peer only uploads, being a partial seed that has all the
+  /// pieces it wants (BEP 21).
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub upload_only: Option<u8>,
+}
+
+impl ExtendedHandshake {
+  /// Creates our extension handshake, which tells the length of our
+  /// metadata if we have it.
+  pub fn new(metadata_size: Option<usize>) -> Self {
+    Self {
+      m: BTreeMap::from([(UT_METADATA.to_owned(), UT_METADATA_ID)]),
+      metadata_size,
+      reqq: None,
+      v: Some(crate::conf::CLIENT_USER_AGENT.to_owned()),
+      upload_only: None,
+    }
+  }
+
+  /// Returns the id with which metadata messages are sent to the peer, if it
+  /// supports the metadata exchange.
+  pub fn ut_metadata_id(&self) -> Option<u8> {
+    self.m.get(UT_METADATA).copied().filter(|&id| id != 0)
+  }
+
+  pub fn encode(&self) -> Result<Bytes> {
+    encode_payload(self)
+  }
+
+  pub fn decode(payload: &[u8]) -> Result<Self> {
+    decode_payload(payload)
+  }
+}
+
+/// A message of the metadata exchange, whose data messages carry a piece of
+/// the metadata after the bencoded dictionary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataMessage {
+  Request {
+    piece: usize,
+  },
+  Data {
+    piece: usize,
+    total_size: usize,
+    data: Bytes,
+  },
+  Reject {
+    piece: usize,
+  },
+}
+
+/// The bencoded dictionary at the start of each metadata message.
+#[derive(Serialize, Deserialize)]
+struct MetadataHeader {
+  msg_type: u8,
+  piece: usize,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+  pub fn encode(&self) -> Result<Bytes> {
+    let (header, data) = match self {
+      Self::Request { piece } => (
+        MetadataHeader {
+          msg_type: 0,
+          piece: *piece,
+          total_size: None,
+        },
+        None,
+      ),
+      Self::Data {
+        piece,
+        total_size,
+        data,
+      } => (
+        MetadataHeader {
+          msg_type: 1,
+          piece: *piece,
+          total_size: Some(*total_size),
+        },
+        Some(data),
+      ),
+      Self::Reject { piece } => (
+        MetadataHeader {
+          msg_type: 2,
+          piece: *piece,
+          total_size: None,
+        },
+        None,
+      ),
+    };
+    let header = encode_payload(&header)?;
+    match data {
+      Some(data) => Ok([&header[..], data].concat().into()),
+      None => Ok(header),
+    }
+  }
+
+  pub fn decode(payload: &[u8]) -> Result<Self> {
+    let (header, data): (MetadataHeader, _) = decode_payload_prefix(payload)?;
+    let piece = header.piece;
+    match header.msg_type {
+      0 => Ok(Self::Request { piece }),
+      1 => Ok(Self::Data {
+        piece,
+        total_size: header
+          .total_size
+          .ok_or(PeerError::InvalidExtensionMessage)?,
+        data: Bytes::copy_from_slice(data),
+      }),
+      2 => Ok(Self::Reject { piece }),
+      _ => Err(PeerError::InvalidExtensionMessage),
+    }
+  }
+}
+
+/// Returns the piece of the metadata to send to a peer requesting it, or
+/// `None` if the piece doesn't exist.
+pub fn metadata_piece(metadata: &[u8], piece: usize) -> Option<&[u8]> {
+  let start = piece.checked_mul(METADATA_PIECE_LEN)?;
+  if start >= metadata.len() {
+    return None;
+  }
+  let end = (start + METADATA_PIECE_LEN).min(metadata.len());
+  Some(&metadata[start..end])
+}
+
+/// The metadata being downloaded from a peer, piece by piece.
+pub struct MetadataDownload {
+  info_hash: Sha1Hash,
+  /// The length of the metadata, as told by the peer.
+  len: usize,
+  metadata: Vec<u8>,
+  /// The number of pieces received so far, which are received in order.
+  received_count: usize,
+}
+
+impl MetadataDownload {
+  /// Starts downloading the metadata of the given length, as told by the
+  /// peer, or returns an error if the length can't be valid.
+  pub fn new(info_hash: Sha1Hash, len: usize) -> Result<Self> {
+    if len == 0 || len > MAX_METADATA_LEN {
+      return Err(PeerError::InvalidExtensionMessage);
+    }
+    Ok(Self {
+      info_hash,
+      len,
+      metadata: Vec::with_capacity(len),
+      received_count: 0,
+    })
+  }
+
+  /// Returns the number of pieces of the metadata.
+  pub fn piece_count(&self) -> usize {
+    self.len.div_ceil(METADATA_PIECE_LEN)
+  }
+
+  /// Returns the next piece to request, or `None` if all were received.
+  pub fn next_piece(&self) -> Option<usize> {
+    (self.received_count < self.piece_count()).then_some(self.received_count)
+  }
+
+  /// Adds the next piece of the metadata, returning the whole metadata once
+  /// its last piece arrived and it matches the info hash.
+  pub fn add_piece(
+    &mut self,
+    piece: usize,
+    data: &[u8],
+  ) -> Result<Option<Vec<u8>>> {
+    if Some(piece) != self.next_piece() {
+      return Err(PeerError::InvalidExtensionMessage);
+    }
+    let expected_len = if piece + 1 == self.piece_count() {
+      self.len - piece * METADATA_PIECE_LEN
+    } else {
+      METADATA_PIECE_LEN
+    };
+    if data.len() != expected_len {
+      return Err(PeerError::InvalidExtensionMessage);
+    }
+    self.metadata.extend_from_slice(data);
+    self.received_count += 1;
+    if self.next_piece().is_some() {
+      return Ok(None);
+    }
+
+    let digest = Sha1::digest(&self.metadata);
+    if digest.as_slice() != self.info_hash {
+      return Err(PeerError::InvalidMetadata);
+    }
+    Ok(Some(std::mem::take(&mut self.metadata)))
+  }
+}
+
+/// Bencodes the value as the payload of an extension message.
+pub fn encode_payload<T: serde::Serialize>(value: &T) -> Result<Bytes> {
+  serde_bencoded::to_vec(value)
+    .map(Bytes::from)
+    .map_err(|_| PeerError::InvalidExtensionMessage)
+}
+
+/// Decodes the payload of an extension message that is a single bencoded
+/// value, ignoring any keys of its dictionaries that the type doesn't know.
+pub fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+  // bound the nesting before handing the payload to the recursive decoder
+  bencode_len(payload).ok_or(PeerError::InvalidExtensionMessage)?;
+  serde_bencoded::from_bytes(payload)
+    .map_err(|_| PeerError::InvalidExtensionMessage)
+}
+
+/// Decodes the bencoded value at the start of the payload of an extension
+/// message, returning it along with the raw data that follows it.
+pub fn decode_payload_prefix<T: DeserializeOwned>(
+  payload: &[u8],
+) -> Result<(T, &[u8])> {
+  let len = bencode_len(payload).ok_or(PeerError::InvalidExtensionMessage)?;
+  let value = decode_payload(&payload[..len])?;
+  Ok((value, &payload[len..]))
+}
+
+/// The deepest nesting of lists and dictionaries accepted in a payload.
+const MAX_BENCODE_DEPTH: usize = 64;
+
+/// Returns the length of the bencoded value at the start of the buffer, or
+/// `None` if it doesn't start with a complete value or nests deeper than
+/// [`MAX_BENCODE_DEPTH`].
+///
+/// The value is scanned without recursion, as the payload comes from the
+/// peer and mustn't be able to exhaust the stack.
+fn bencode_len(buf: &[u8]) -> Option<usize> {
+  let mut len = 0;
+  let mut depth = 0;
+  loop {
+    match *buf.get(len)? {
+      b'i' => len += buf[len..].iter().position(|&b| b == b'e')? + 1,
+      b'l' | b'd' => {
+        depth += 1;
+        if depth > MAX_BENCODE_DEPTH {
+          return None;
+        }
+        len += 1;
+        continue;
+      }
+      b'e' if depth > 0 => {
+        depth -= 1;
+        len += 1;
+      }
+      b'0'..=b'9' => {
+        let colon = len + buf[len..].iter().position(|&b| b == b':')?;
+        let str_len: usize =
+          std::str::from_utf8(&buf[len..colon]).ok()?.parse().ok()?;
+        len = colon.checked_add(1)?.checked_add(str_len)?;
+        if len > buf.len() {
+          return None;
+        }
+      }
+      _ => return None,
+    }
+    if depth == 0 {
+      return Some(len);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_payload_codec() {
+    type Dict = BTreeMap<String, u8>;
+
+    let value = Dict::from([("ut_pex".to_owned(), 2)]);
+    let encoded = encode_payload(&value).unwrap();
+    assert_eq!(&encoded[..], b"d6:ut_pexi2ee");
+    assert_eq!(decode_payload::<Dict>(&encoded).unwrap(), value);
+
+    // a value may be followed by raw data
+    let payload = [&encoded[..], b"data"].concat();
+    let (decoded, rest) = decode_payload_prefix::<Dict>(&payload).unwrap();
+    assert_eq!(decoded, value);
+    assert_eq!(rest, b"data");
+
+    // but not if the value is cut short or isn't bencoded
+    assert!(decode_payload::<Dict>(b"d6:ut_pexi2e").is_err());
+    assert!(decode_payload_prefix::<Dict>(b"d6:ut_pex").is_err());
+    assert!(decode_payload_prefix::<Dict>(b"xyz").is_err());
+
+    // nor if it nests deeper than allowed
+    let nested =
+      |depth| [vec![b'l'; depth], b"i1e".to_vec(), vec![b'e'; depth]].concat();
+    assert_eq!(
+      bencode_len(&nested(MAX_BENCODE_DEPTH)),
+      Some(2 * MAX_BENCODE_DEPTH + 3)
+    );
+    assert_eq!(bencode_len(&nested(MAX_BENCODE_DEPTH + 1)), None);
+    assert!(decode_payload_prefix::<Dict>(&vec![b'l'; 2 << 20]).is_err());
+    assert!(decode_payload::<Dict>(&vec![b'l'; 2 << 20]).is_err());
+  }
+
+  #[test]
+  fn test_extended_handshake() {
+    let mut handshake = ExtendedHandshake::new(Some(31235));
+    handshake.upload_only = Some(1);
+    let encoded = handshake.encode().unwrap();
+    assert_eq!(ExtendedHandshake::decode(&encoded).unwrap(), handshake);
+    assert_eq!(handshake.ut_metadata_id(), Some(UT_METADATA_ID));
+
+    // unknown keys are ignored, and a disabled extension has an id of 0
+    let handshake =
+      ExtendedHandshake::decode(b"d1:md11:ut_metadatai0ee1:pi6881ee").unwrap();
+    assert_eq!(handshake.ut_metadata_id(), None);
+    assert_eq!(handshake.metadata_size, None);
+  }
+
+  #[test]
+  fn test_metadata_message() {
+    let msgs = [
+      MetadataMessage::Request { piece: 0 },
+      MetadataMessage::Data {
+        piece: 1,
+        total_size: METADATA_PIECE_LEN + 3,
+        data: Bytes::from_static(b"abc"),
+      },
+      MetadataMessage::Reject { piece: 2 },
+    ];
+    for msg in msgs {
+      let encoded = msg.encode().unwrap();
+      assert_eq!(MetadataMessage::decode(&encoded).unwrap(), msg);
+    }
+
+    // the data follows the dictionary
+    let encoded = b"d8:msg_typei1e5:piecei0e10:total_sizei4eeinfo";
+    assert_eq!(
+      MetadataMessage::decode(encoded).unwrap(),
+      MetadataMessage::Data {
+        piece: 0,
+        total_size: 4,
+        data: Bytes::from_static(b"info"),
+      }
+    );
+    assert!(MetadataMessage::decode(b"d8:msg_typei1e5:piecei0").is_err());
+  }
+
+  #[test]
+  fn test_metadata_download() {
+    let metadata: Vec<u8> =
+      (0..2 * METADATA_PIECE_LEN + 10).map(|i| i as u8).collect();
+    let info_hash: Sha1Hash = Sha1::digest(&metadata).into();
+
+    let mut download =
+      MetadataDownload::new(info_hash, metadata.len()).unwrap();
+    assert_eq!(download.piece_count(), 3);
+    for piece in 0..3 {
+      assert_eq!(download.next_piece(), Some(piece));
+      let data = metadata_piece(&metadata, piece).unwrap();
+      let result = download.add_piece(piece, data).unwrap();
+      assert_eq!(result.is_some(), piece == 2);
+      if let Some(result) = result {
+        assert_eq!(result, metadata);
+      }
+    }
+    assert_eq!(download.next_piece(), None);
+    assert_eq!(metadata_piece(&metadata, 3), None);
+
+    // metadata that doesn't hash to the info hash is rejected
+    let mut download = MetadataDownload::new([0; 20], 10).unwrap();
+    assert!(matches!(
+      download.add_piece(0, &metadata[..10]),
+      Err(PeerError::InvalidMetadata)
+    ));
+
+    // as is a piece of the wrong length
+    let mut download =
+      MetadataDownload::new(info_hash, metadata.len()).unwrap();
+    assert!(download.add_piece(0, &metadata[..10]).is_err());
+    assert!(MetadataDownload::new(info_hash, MAX_METADATA_LEN + 1).is_err());
+  }
+}
@@ -9,50 +9,74 @@
 //! one, due to making use of shared data in torrent.
 
 use std::{
-  collections::HashSet,
+  collections::{HashMap, HashSet, VecDeque},
+  io,
   net::SocketAddr,
   sync::Arc,
   time::{Duration, Instant},
 };
 
+use bytes::Bytes;
+use bytes::BytesMut;
 use futures::{stream::SplitSink, SinkExt, StreamExt};
 use tokio::{
-  net::TcpStream,
+  io::AsyncReadExt,
   sync::{
-    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    mpsc::{self, error::TrySendError, UnboundedReceiver, UnboundedSender},
     RwLock,
   },
   time,
 };
-use tokio_util::codec::{Framed, FramedParts};
+use tokio_util::codec::{Decoder, Framed, FramedParts};
 
 use crate::{
   alert::Alert,
   blockinfo::BlockInfo,
+  conf::SessionConf,
   counter::ThruputCounters,
   disk,
   download::{BlockStatus, PieceDownload},
   error::{Error, PeerError, PeerResult},
+  memory::{Buffer, MemoryCharge},
   peer::{
     codec::{
-      handshake::{Handshake, HandshakeCodec, PROTOCOL_STRING},
+      handshake::{Handshake, HandshakeCodec, HANDSHAKE_LEN, PROTOCOL_STRING},
       message::{Message, MessageId},
       peercodec::PeerCodec,
     },
+    extension::{
+      metadata_piece, ExtendedHandshake, MetadataMessage,
+      EXTENDED_HANDSHAKE_ID, UT_METADATA_ID,
+    },
     session::ConnectionState,
   },
-  torrent::{self, TorrentContext},
-  Bitfield, Block, PeerId, PieceIndex,
+  torrent::{
+    self,
+    stats::{EndgameStats, ThruputStats},
+    TorrentContext,
+  },
+  transport::PeerConnection,
+  Bitfield, Block, PeerId, PieceIndex, Sha1Hash, BLOCK_LEN,
 };
 
-use self::session::{SessionContext, SessionState};
+use self::session::{SequentialDetector, SessionContext, SessionState};
 
 pub mod codec;
+pub mod extension;
 pub mod session;
 
-/// After this timeout if the peers haven't become interested in each other,
-/// the connection is severed.
-const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a block is still accepted after its request was freed for other
+/// sessions or cancelled, as it may already have been on its way.
+const FREED_REQUEST_LIFETIME: Duration = Duration::from_secs(120);
+
+/// The most commands handled in one turn of the session loop, after which
+/// the rest wait for the next turn, so that a flood of them can't keep the
+/// session from reading its peer's messages.
+const MAX_CMDS_PER_TURN: usize = 64;
+
+/// The most bytes of blocks held back by the upload rate limit, past which
+/// the peer's further requests aren't read from disk until some are sent.
+const MAX_THROTTLED_LEN: u64 = 1024 * 1024;
 
 /// The most essential information of a peer session
 /// that is sent to torrent with each session tick.
@@ -61,8 +85,24 @@ pub struct SessionTick {
   pub state: SessionState,
   /// Various transfer statistics.
   pub counters: ThruputCounters,
+  /// What was transferred since the previous update, in the counters'
+  /// rounds, as the session doesn't send an update every tick.
+  pub transferred: ThruputCounters,
   /// The number of pieces the peer has available.
   pub piece_count: usize,
+  /// The number of duplicate requests dropped so far in the session.
+  pub duplicate_request_count: u64,
+  /// The number of duplicate blocks received so far in the session.
+  pub duplicate_block_count: u64,
+  /// The cost of the duplicate requests made in endgame so far.
+  pub endgame: EndgameStats,
+  /// The number of our requests the peer has yet to serve.
+  pub outgoing_request_count: usize,
+  /// The number of the peer's requests we have yet to serve.
+  pub incoming_request_count: usize,
+  /// Whether the session ended because the peer broke the protocol or our
+  /// limits.
+  pub has_misbehaved: bool,
 }
 
 /// The channel on which torrent can send a command to the peer session task.
@@ -80,6 +120,15 @@ pub enum Command {
     /// Tell the session to enter endgame mode.
     in_endgame: bool,
   },
+  /// Tells the session to recalculate its interest in the peer, as the
+  /// pieces we want changed.
+  UpdateInterest,
+  /// Tells the session to choke the peer, as decided by the torrent's
+  /// choker.
+  Choke,
+  /// Tells the session to unchoke the peer, as decided by the torrent's
+  /// choker.
+  Unchoke,
   /// Eventually shutdown the peer session.
   Shutdown,
 }
@@ -91,6 +140,37 @@ enum Direction {
   Inbound,
 }
 
+/// Reads the handshake of a peer that connected to us, returning it if it's
+/// for the torrent, before any session is allocated for the peer.
+///
+/// Nothing past the handshake is read, and its protocol string is checked
+/// before the rest, so that connections speaking another protocol, sending
+/// garbage, or trickling their bytes past the timeout are dropped early.
+pub async fn accept_handshake(
+  mut socket: PeerConnection,
+  info_hash: Sha1Hash,
+  timeout: Duration,
+) -> PeerResult<(PeerConnection, Handshake)> {
+  let read_handshake = async {
+    let mut buf = BytesMut::zeroed(1 + PROTOCOL_STRING.len());
+    socket.read_exact(&mut buf).await?;
+    // only checks the protocol string, as the rest is yet to be read
+    HandshakeCodec.decode(&mut buf)?;
+    let prefix_len = buf.len();
+    buf.resize(HANDSHAKE_LEN, 0);
+    socket.read_exact(&mut buf[prefix_len..]).await?;
+    let handshake: Option<Handshake> = HandshakeCodec.decode(&mut buf)?;
+    io::Result::Ok(handshake.expect("whole handshake was read"))
+  };
+  let handshake = time::timeout(timeout, read_handshake)
+    .await
+    .map_err(|_| PeerError::HandshakeTimeout)??;
+  if handshake.info_hash != info_hash {
+    return Err(PeerError::InvalidInfoHash);
+  }
+  Ok((socket, handshake))
+}
+
 /// A stopped or active connection with another BitTorrent peer.
 ///
 /// This entity implements the BitTorrent wire protocol:
@@ -125,6 +205,10 @@ pub struct PeerSession {
   /// Information about the peer.
   peer: PeerInfo,
 
+  /// The session's timeouts and intervals, copied from the torrent's
+  /// configuration.
+  conf: SessionConf,
+
   /// Most of the session's information and state is stored here,
   /// i.e. it's the "context" of the session.
   ctx: SessionContext,
@@ -156,9 +240,10 @@ pub struct PeerSession {
   /// will be wasted. Thus this method avoids bandwidth wast and cuts down
   /// overall download times.
   ///
-  /// Since the Fast extension is not supported (yet), this is emptied when
-  /// we're choked, as in that case we don't expect outstanding requests to
-  /// be served.
+  /// Unless the peer supports the Fast extension, this is emptied when we're
+  /// choked, as in that case we don't expect outstanding requests to be
+  /// served. With the extension, the peer rejects each request it drops
+  /// explicitly instead.
   ///
   /// Note that if a reused for a piece's block is in this queue, there must
   /// be a corresponding entry for the piece download in `download`
@@ -167,12 +252,32 @@ pub struct PeerSession {
   /// have to enforce this invariant (keeping in mind that later PieceDownload will
   /// be shared among PeerSession)>
   outgoing_requests: HashSet<BlockInfo>,
+  /// The requests removed from `outgoing_requests` without the peer
+  /// rejecting them, i.e. timed out, freed on choking, or cancelled, and
+  /// when, whose blocks are still accepted for a while.
+  ///
+  /// Any other block the peer sends was never asked for, and is dropped.
+  freed_requests: HashMap<BlockInfo, Instant>,
   /// The requests we got from peer.
   ///
   /// The request's entry is removed from here when the block is transmitted
   /// or when the peer cancels it. If a peer sends a request and cancels it
   /// before the disk read is done, the read block is dropped.
   incoming_requests: HashSet<BlockInfo>,
+  /// Detects whether the peer requests pieces in order, in which case the
+  /// next piece is read into the disk cache ahead of its requests.
+  sequential_detector: SequentialDetector,
+  /// The blocks read from disk that are held back by the torrent's upload
+  /// rate limit, in the order they are to be sent.
+  throttled_blocks: VecDeque<Block>,
+  /// The bytes of the held back blocks, charged to the peer queues.
+  throttled_charge: MemoryCharge,
+  /// The requests whose disk reads wait for the held back blocks to fall
+  /// under [`MAX_THROTTLED_LEN`], in the order they were made.
+  deferred_reads: VecDeque<BlockInfo>,
+  /// The bytes of the blocks sent since the sink was last flushed, which it
+  /// may still buffer, charged to the peer queues.
+  send_buf_charge: MemoryCharge,
 }
 
 /// Information about the peer we're connected to.
@@ -193,6 +298,19 @@ pub struct PeerInfo {
   /// is updated every time the peer sends us an announcement
   /// of a new piece.
   pub piece_count: usize,
+  /// Whether the peer supports the extension protocol (BEP 10), as told in
+  /// its handshake.
+  pub supports_extensions: bool,
+  /// The id with which metadata messages are sent to the peer, if it told
+  /// in its extension handshake that it supports the metadata exchange.
+  pub ut_metadata_id: Option<u8>,
+  /// Whether the peer supports the Fast extension (BEP 6), as told in its
+  /// handshake. As we support it too, its messages may be exchanged.
+  pub supports_fast: bool,
+  /// The pieces the peer allows us to request even while it chokes us.
+  pub allowed_fast: HashSet<PieceIndex>,
+  /// Whether the peer runs a DHT node (BEP 5), as told in its handshake.
+  pub supports_dht: bool,
 }
 
 impl PeerSession {
@@ -202,11 +320,18 @@ impl PeerSession {
   ///
   /// This constructor only initializes the session components but does not
   /// actually start it.
-  pub fn new(torrent: Arc<TorrentContext>, addr: SocketAddr) -> (Self, Sender) {
+  pub fn new(
+    torrent: Arc<TorrentContext>,
+    conf: SessionConf,
+    addr: SocketAddr,
+  ) -> (Self, Sender) {
     let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
 
     let piece_count = torrent.storage.piece_count;
     let log_target = format!("peer [{}][{}]", torrent.id, addr);
+    let throttled_charge =
+      MemoryCharge::new(&torrent.memory, Buffer::PeerQueue);
+    let send_buf_charge = MemoryCharge::new(&torrent.memory, Buffer::PeerQueue);
 
     (
       PeerSession {
@@ -218,13 +343,25 @@ impl PeerSession {
           id: Default::default(),
           pieces: Bitfield::repeat(false, piece_count),
           piece_count: 0,
+          supports_extensions: false,
+          ut_metadata_id: None,
+          supports_fast: false,
+          allowed_fast: HashSet::new(),
+          supports_dht: false,
         },
+        conf,
         ctx: SessionContext {
           log_target,
           ..Default::default()
         },
         outgoing_requests: HashSet::new(),
+        freed_requests: HashMap::new(),
         incoming_requests: HashSet::new(),
+        sequential_detector: SequentialDetector::default(),
+        throttled_blocks: VecDeque::new(),
+        deferred_reads: VecDeque::new(),
+        throttled_charge,
+        send_buf_charge,
       },
       cmd_tx,
     )
@@ -242,14 +379,25 @@ impl PeerSession {
         "Starting outbound session"
     );
 
-    // establish the TCP connection
+    // establish the connection over the torrent's transport
     log::info!(
         target: &self.ctx.log_target,
         "Connecting to peer"
     );
 
     self.ctx.set_connection_state(ConnectionState::Connecting);
-    let socket = TcpStream::connect(self.peer.addr).await?;
+    // connecting and exchanging handshakes share a deadline, so that a slow
+    // peer can't hold the connection by taking its time with each step
+    let deadline = time::Instant::now() + self.conf.handshake_timeout;
+    let socket = time::timeout_at(
+      deadline,
+      self.torrent.transport.connect(self.peer.addr),
+    )
+    .await
+    .map_err(|_| {
+      log::warn!(target: &self.ctx.log_target, "Connect timed out");
+      PeerError::HandshakeTimeout
+    })??;
 
     log::info!(
         target: &self.ctx.log_target,
@@ -257,40 +405,54 @@ impl PeerSession {
     );
     let socket = Framed::new(socket, HandshakeCodec);
 
-    self.start(socket, Direction::Outbound).await
+    self.start(socket, None, deadline).await
   }
 
-  /// Starts an inbound peer session from an existing TCP connection.
+  /// Starts an inbound peer session from an existing connection, over which
+  /// the peer's handshake was already read by [`accept_handshake`].
   ///
-  /// The method waits for the peer to send its handshake, responds
-  /// with a handshake, and starts the session.
+  /// The method responds with a handshake and starts the session.
   ///
   /// It returns if the connection is closed or an error occurred.
-  pub async fn start_inbound(&mut self, socket: TcpStream) -> PeerResult<()> {
+  pub async fn start_inbound(
+    &mut self,
+    socket: PeerConnection,
+    peer_handshake: Handshake,
+  ) -> PeerResult<()> {
     log::info!(
         target: &self.ctx.log_target,
         "Starting inbound session"
     );
 
     self.ctx.set_connection_state(ConnectionState::Connecting);
+    let deadline = time::Instant::now() + self.conf.handshake_timeout;
     let socket = Framed::new(socket, HandshakeCodec);
 
-    self.start(socket, Direction::Inbound).await
+    self.start(socket, Some(peer_handshake), deadline).await
   }
 
   /// Helper method for the common steps of setting up a session.
+  ///
+  /// The peer's handshake is given if it connected to us, in which case we
+  /// reply with ours, while otherwise we send ours first and wait for the
+  /// peer's until the deadline.
   async fn start(
     &mut self,
-    mut socket: Framed<TcpStream, HandshakeCodec>,
-    direction: Direction,
+    mut socket: Framed<PeerConnection, HandshakeCodec>,
+    peer_handshake: Option<Handshake>,
+    deadline: time::Instant,
   ) -> PeerResult<()> {
     self.ctx.set_connection_state(ConnectionState::Handshaking);
+    let direction = if peer_handshake.is_some() {
+      Direction::Inbound
+    } else {
+      Direction::Outbound
+    };
 
     // if this is an outbound connection, we have to send the first
     // handshake
     if direction == Direction::Outbound {
-      let handshake =
-        Handshake::new(self.torrent.info_hash, self.torrent.client_id);
+      let handshake = self.handshake();
 
       log::info!(
           target: &self.ctx.log_target,
@@ -306,16 +468,28 @@ impl PeerSession {
       //     .unwrap();
       // file.write_all(hs.as_bytes()).unwrap();
 
-      socket.send(handshake).await?;
+      time::timeout_at(deadline, socket.send(handshake))
+        .await
+        .map_err(|_| PeerError::HandshakeTimeout)??;
     }
 
     // receive peer's handshake
-    log::info!(
-        target: &self.ctx.log_target,
-        "Waiting for peer handshake"
-    );
-
-    if let Some(peer_handshake) = socket.next().await {
+    let peer_handshake = match peer_handshake {
+      Some(peer_handshake) => Some(Ok(peer_handshake)),
+      None => {
+        log::info!(
+            target: &self.ctx.log_target,
+            "Waiting for peer handshake"
+        );
+        time::timeout_at(deadline, socket.next())
+          .await
+          .map_err(|_| {
+            log::warn!(target: &self.ctx.log_target, "Handshake timed out");
+            PeerError::HandshakeTimeout
+          })?
+      }
+    };
+    if let Some(peer_handshake) = peer_handshake {
       let peer_handshake = peer_handshake?;
 
       log::info!(
@@ -346,11 +520,13 @@ impl PeerSession {
 
       // set the peer's id
       self.peer.id = Some(peer_handshake.peer_id);
+      self.peer.supports_extensions = peer_handshake.supports_extensions();
+      self.peer.supports_fast = peer_handshake.supports_fast();
+      self.peer.supports_dht = peer_handshake.supports_dht();
 
       // if this is an inbound connection, we reply with the handshake
       if direction == Direction::Inbound {
-        let handshake =
-          Handshake::new(self.torrent.info_hash, self.torrent.client_id);
+        let handshake = self.handshake();
 
         log::info!(
             target: &self.ctx.log_target,
@@ -358,7 +534,9 @@ impl PeerSession {
         );
 
         self.ctx.counters.protocol.up += handshake.len();
-        socket.send(handshake).await?;
+        time::timeout_at(deadline, socket.send(handshake))
+          .await
+          .map_err(|_| PeerError::HandshakeTimeout)??;
       }
 
       // now that we have the handshake, we need to switch to the peer
@@ -375,10 +553,12 @@ impl PeerSession {
       let socket = Framed::from_parts(new_parts);
 
       // update torrent of connection
-      self.torrent.cmd_tx.send(torrent::Command::PeerConnected {
-        addr: self.peer.addr,
-        id: peer_handshake.peer_id,
-      })?;
+      self.torrent.cmd_tx.send_bulk_forced(
+        torrent::Command::PeerConnected {
+          addr: self.peer.addr,
+          id: peer_handshake.peer_id,
+        },
+      )?;
 
       // enter the piece availability exchange state
       self
@@ -398,11 +578,8 @@ impl PeerSession {
         );
 
         self.ctx.set_connection_state(ConnectionState::Disconnected);
-
-        self.torrent.cmd_tx.send(torrent::Command::PeerState {
-          addr: self.peer.addr,
-          info: self.session_info(),
-        })?;
+        self.ctx.has_misbehaved = e.is_misbehavior();
+        self.report_state()?;
         self.torrent.alert_tx.send(Alert::Error(Error::Peer {
           id: self.torrent.id,
           addr: self.peer.addr,
@@ -415,10 +592,7 @@ impl PeerSession {
           "No handshake received"
       );
       self.ctx.set_connection_state(ConnectionState::Disconnected);
-      self.torrent.cmd_tx.send(torrent::Command::PeerState {
-        addr: self.peer.addr,
-        info: self.session_info(),
-      })?;
+      self.report_state()?;
     }
 
     // session exited as a result of a clean shutdown or an error,
@@ -435,13 +609,13 @@ impl PeerSession {
       self.free_pending_blocks().await;
     }
 
+    // the peer's pieces are no longer available from it
+    self.unregister_peer_pieces().await;
+
     // send a state update message to torrent to actualize possible download
     // stats changes.
     self.ctx.set_connection_state(ConnectionState::Disconnected);
-    self.torrent.cmd_tx.send(torrent::Command::PeerState {
-      addr: self.peer.addr,
-      info: self.session_info(),
-    })?;
+    self.report_state()?;
     Ok(())
   }
 
@@ -451,7 +625,7 @@ impl PeerSession {
   /// logic: exchange of messages, timeout logic, etc.
   async fn run(
     &mut self,
-    socket: Framed<TcpStream, PeerCodec>,
+    socket: Framed<PeerConnection, PeerCodec>,
   ) -> PeerResult<()> {
     self.ctx.connected_time = Some(Instant::now());
 
@@ -461,49 +635,113 @@ impl PeerSession {
 
     // This is the beginning of the session, which is the only time
     // a peer is allowed to advertise their pieces. If we have pieces
-    // available, send a bitfield message.
-    {
-      let piece_picker_guard = self.torrent.piece_picker.read().await;
-      let own_pieces = piece_picker_guard.own_pieces();
-      if own_pieces.any() {
-        log::info!(
-            target: &self.ctx.log_target,
-            "Sending piece availability"
-        );
+    // available, send a bitfield message, while with the Fast extension
+    // the availability is always sent, for all or no pieces in short.
+    let mut own_pieces = self.torrent.own_pieces().await;
+    let withheld_pieces = if self.conf.lazy_bitfield {
+      session::withhold_pieces(&mut own_pieces)
+    } else {
+      Vec::new()
+    };
+    let availability = if !self.peer.supports_fast {
+      own_pieces.any().then_some(Message::Bitfield(own_pieces))
+    } else if own_pieces.all() {
+      Some(Message::HaveAll)
+    } else if own_pieces.not_any() {
+      Some(Message::HaveNone)
+    } else {
+      Some(Message::Bitfield(own_pieces))
+    };
+    if let Some(availability) = availability {
+      log::info!(
+          target: &self.ctx.log_target,
+          "Sending piece availability"
+      );
 
-        sink.send(Message::Bitfield(own_pieces.clone())).await?;
+      self.send_msg(&mut sink, availability).await?;
 
-        log::info!(
-            target: &self.ctx.log_target,
-            "Sent piece availability"
-        );
+      log::info!(
+          target: &self.ctx.log_target,
+          "Sent piece availability"
+      );
+    }
+    for piece_index in withheld_pieces {
+      self
+        .send_msg(&mut sink, Message::Have { piece_index })
+        .await?;
+    }
+
+    // tell the peer that we serve the torrent's metadata, how many requests
+    // it may have outstanding, and whether we're a partial seed, so that it
+    // doesn't count on us for the pieces we skip
+    if self.peer.supports_extensions {
+      let mut handshake =
+        ExtendedHandshake::new(Some(self.torrent.metadata.len()));
+      handshake.reqq = Some(self.conf.max_incoming_request_count);
+      if self.torrent.is_partial_seed() {
+        handshake.upload_only = Some(1);
       }
+      let handshake = handshake.encode()?;
+      self
+        .send_msg(
+          &mut sink,
+          Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: handshake,
+          },
+        )
+        .await?;
     }
 
-    // used for collecting session stats every second
-    let mut tick_timer = time::interval(Duration::from_secs(1));
+    // tell the peer the port of our DHT node, so that it may add it
+    if let Some(dht) =
+      self.torrent.dht.as_ref().filter(|_| self.peer.supports_dht)
+    {
+      self.send_msg(&mut sink, Message::Port(dht.port())).await?;
+    }
+
+    // used for collecting session stats and reporting them to torrent
+    let mut tick_timer = time::interval(self.conf.tick_interval);
 
     // start the loop for receiving messages from peer and commands
-    // from other parts of the engine
-    loop {
+    // from other parts of the engine, at the end of each turn of which the
+    // messages sent in the meantime are written to the socket at once
+    'session: loop {
       tokio::select! {
           now = tick_timer.tick() => {
               self.tick(&mut sink, now.into_std()).await?;
           }
           Some(msg) = stream.next() => {
               let msg = msg?;
-
-              // handle bitfield message separately as it may only be
-              // received directly after the handshake (later once we
-              // implement the FAST extension, there will be other piece
-              // availability related messages to handle)
-              if self.ctx.state.connection == ConnectionState::AvailabilityExchange {
-                  if let Message::Bitfield(bitfield) = msg {
-                      self.handle_bitfield_msg(&mut sink, bitfield).await?;
-                  } else {
+              self.ctx.last_incoming_msg_time = Some(Instant::now());
+              // the message's protocol bytes count against the download
+              // rate limit, but are never held back by it
+              self.torrent.download_limiter.consume(msg.protocol_len());
+
+              // handle piece availability messages separately as they may
+              // only be received directly after the handshake, while the
+              // extension handshake and the DHT port may come before or
+              // after them
+              if self.ctx.state.connection == ConnectionState::AvailabilityExchange
+                  && !matches!(msg, Message::Extended { .. } | Message::Port(_))
+              {
+                  let piece_count = self.torrent.storage.piece_count;
+                  match msg {
+                      Message::Bitfield(bitfield) => {
+                          self.handle_bitfield_msg(&mut sink, bitfield).await?;
+                      }
+                      // the Fast extension's shorthands for a bitfield
+                      Message::HaveAll if self.peer.supports_fast => {
+                          let bitfield = Bitfield::repeat(true, piece_count);
+                          self.handle_bitfield_msg(&mut sink, bitfield).await?;
+                      }
+                      Message::HaveNone if self.peer.supports_fast => {
+                          let bitfield = Bitfield::repeat(false, piece_count);
+                          self.handle_bitfield_msg(&mut sink, bitfield).await?;
+                      }
                       // it's not mandatory to send a bitfield message
                       // right after the handshake
-                      self.handle_msg(&mut sink, msg).await?;
+                      msg => self.handle_msg(&mut sink, msg).await?,
                   }
 
                   if self
@@ -511,8 +749,10 @@ impl PeerSession {
                       .piece_picker
                       .read()
                       .await
-                      .own_pieces()
-                      .not_any()
+                      .as_ref()
+                      .is_some_and(|piece_picker| {
+                          piece_picker.own_pieces().not_any()
+                      })
                       && self.peer.pieces.not_any()
                   {
                       log::warn!(
@@ -534,30 +774,70 @@ impl PeerSession {
               }
           }
           Some(cmd) = self.cmd_rx.recv() => {
-              match cmd {
-                  Command::Block(block) => {
-                      self.send_block(&mut sink, block).await?;
-                  },
-                  Command::PieceCompletion { index, in_endgame } => {
-                      self.ctx.in_endgame = in_endgame;
-                      self.handle_piece_completion(&mut sink, index).await?;
-                  },
-                  Command::Shutdown => {
-                      log::info!(
-                          target: &self.ctx.log_target,
-                          "Shutting down session"
-                      );
+              // the commands queued in the meantime are handled in the same
+              // turn, so that e.g. the have messages of a burst of piece
+              // completions go out in a single write, but only so many, so
+              // that the peer's messages are read in between
+              let mut cmd = Some(cmd);
+              let mut cmd_count = 0;
+              while let Some(next) = cmd {
+                  if !self.handle_cmd(&mut sink, next).await? {
+                      // what was sent before the shutdown still goes out
+                      sink.flush().await?;
+                      break 'session;
+                  }
+                  cmd_count += 1;
+                  if cmd_count == MAX_CMDS_PER_TURN {
                       break;
-                  },
+                  }
+                  cmd = self.cmd_rx.try_recv().ok();
               }
           }
       }
+      sink.flush().await?;
+      self.send_buf_charge.clear();
     }
     Ok(())
   }
 
-  /// The session tick, as in "the tick of a clock", which runs every second
-  /// to perform periodic updates.
+  /// Handles a command from other parts of the engine, returning whether
+  /// the session should go on.
+  async fn handle_cmd(
+    &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
+    cmd: Command,
+  ) -> PeerResult<bool> {
+    match cmd {
+      Command::Block(block) => {
+        self.upload_block(sink, block).await?;
+      }
+      Command::PieceCompletion { index, in_endgame } => {
+        self.ctx.in_endgame = in_endgame;
+        self.handle_piece_completion(sink, index).await?;
+      }
+      Command::UpdateInterest => {
+        let is_interested =
+          self.torrent.piece_picker.read().await.as_ref().is_some_and(
+            |piece_picker| piece_picker.is_interested_in(&self.peer.pieces),
+          );
+        self.update_interest(sink, is_interested).await?;
+      }
+      Command::Choke => {
+        self.choke_peer(sink).await?;
+      }
+      Command::Unchoke => {
+        self.unchoke_peer(sink).await?;
+      }
+      Command::Shutdown => {
+        log::info!(target: &self.ctx.log_target, "Shutting down session");
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  /// The session tick, as in "the tick of a clock", which runs every
+  /// [`SessionConf::tick_interval`] to perform periodic updates.
   ///
   /// This is when we update statistics and report them to torrent
   /// (and later perhaps to the user directly, if requested),
@@ -565,43 +845,90 @@ impl PeerSession {
   /// and when it updates the target request queue size.
   async fn tick(
     &mut self,
-    sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
     now: Instant,
   ) -> PeerResult<()> {
+    let connected_time = self.ctx.connected_time.expect("not connected");
+
     // if we haven't become interested in each other for too long, disconnect.
     if !self.ctx.state.is_interested
       && !self.ctx.state.is_peer_interested
-      && now.saturating_duration_since(
-        self.ctx.connected_time.expect("not connected"),
-      ) >= INACTIVITY_TIMEOUT
+      && now.saturating_duration_since(connected_time)
+        >= self.conf.interest_grace_period
     {
       log::warn!(target: &self.ctx.log_target, "Not interested in each other, disconnecting");
+      return Err(PeerError::InterestTimeout);
+    }
+
+    // if peer hasn't sent us anything for too long, disconnect.
+    let last_incoming_msg_time =
+      self.ctx.last_incoming_msg_time.unwrap_or(connected_time);
+    if now.saturating_duration_since(last_incoming_msg_time)
+      >= self.conf.inactivity_timeout
+    {
+      log::warn!(target: &self.ctx.log_target, "Peer inactive, disconnecting");
       return Err(PeerError::InactivityTimeout);
     }
 
+    // blocks of long freed requests are no longer expected
+    self.freed_requests.retain(|_, freed_time| {
+      now.saturating_duration_since(*freed_time) < FREED_REQUEST_LIFETIME
+    });
+
     // reset requests if we have pending requests and more time has elapsed
     // since the last request than the current timeout value
     if !self.outgoing_requests.is_empty() {
+      self.check_snub(sink, now).await?;
       self.check_request_timeout(sink).await?;
     }
 
-    // send keep-alive
+    // the requests and blocks held back by the torrent's rate limits are
+    // made and sent as the limiters refill, and requests held back by
+    // stalled disk writes once they resume
+    let is_idle = self.outgoing_requests.is_empty()
+      && self.ctx.state.is_interested
+      && !self.ctx.state.is_choked;
+    if self.torrent.download_limiter.is_limited() || is_idle {
+      self.make_requests(sink).await?;
+    }
+    self.send_throttled_blocks(sink).await?;
+
+    // send keep-alive if we haven't sent anything in a while
+    let last_outgoing_msg_time =
+      self.ctx.last_outgoing_msg_time.unwrap_or(connected_time);
+    if now.saturating_duration_since(last_outgoing_msg_time)
+      >= self.conf.keep_alive_interval
+    {
+      log::debug!(target: &self.ctx.log_target, "Sending keep-alive");
+      self.send_msg(sink, Message::KeepAlive).await?;
+      self.ctx.counters.protocol.up += Message::KeepAlive.protocol_len();
+    }
 
-    // if there was any state change, notify torrent
-    if self.ctx.changed {
+    // notify torrent only if the state changed or the rates are still
+    // settling, so that idle sessions don't keep flooding its channel
+    let is_thruput_changed = ThruputStats::from(&self.ctx.counters)
+      != ThruputStats::from(&self.ctx.reported_counters);
+    if self.ctx.changed || is_thruput_changed {
       log::debug!(
           target: &self.ctx.log_target,
           "State changed, updating torrent"
       );
-      self.torrent.cmd_tx.send(torrent::Command::PeerState {
+      // if the torrent falls behind the update is dropped, and what it
+      // would've reported is sent with the next tick instead
+      let update = torrent::Command::PeerState {
         addr: self.peer.addr,
         info: self.session_info(),
-      })?;
+      };
+      match self.torrent.cmd_tx.send_bulk(update).await {
+        Ok(()) => self.ctx.mark_reported(),
+        Err(TrySendError::Full(_)) => {}
+        Err(TrySendError::Closed(_)) => return Err(PeerError::Channel),
+      }
     }
 
     // update session context
     let prev_queue_len = self.ctx.target_request_queue_len;
-    self.ctx.tick();
+    self.ctx.tick(self.conf.tick_interval);
     if let (Some(prev_queue_len), Some(curr_queue_len)) =
       (prev_queue_len, self.ctx.target_request_queue_len)
     {
@@ -639,10 +966,37 @@ impl PeerSession {
     Ok(())
   }
 
+  /// Marks the peer as snubbing us if it hasn't sent any of the blocks we
+  /// requested in too long, in which case its requests are freed for other
+  /// peers to download and a single one is made in their place.
+  async fn check_snub(
+    &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
+    now: Instant,
+  ) -> PeerResult<()> {
+    // a choked peer isn't expected to serve our requests, nor is a peer
+    // that we haven't asked for anything
+    if self.ctx.state.is_choked
+      || self.outgoing_requests.is_empty()
+      || self.ctx.state.is_snubbed
+      || !self.ctx.is_snubbing(now, self.conf.snub_timeout)
+    {
+      return Ok(());
+    }
+    log::warn!(
+        target: &self.ctx.log_target,
+        "Peer snubbed us, freeing {} request(s)",
+        self.outgoing_requests.len()
+    );
+    self.free_pending_blocks().await;
+    self.ctx.register_snub();
+    self.make_requests(sink).await
+  }
+
   /// Times out the peer if it hasn't sent a request in too long.
   async fn check_request_timeout(
     &mut self,
-    sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
   ) -> PeerResult<()> {
     if let Some(last_outgoing_request_time) =
       self.ctx.last_outgoing_request_time
@@ -685,14 +1039,25 @@ impl PeerSession {
 
   /// Marks requests blocks as free in their respective downloads so that
   /// other peer sessions may download them.
-  async fn free_pending_blocks(&mut self) {
+  ///
+  /// This is also used by the torrent to return the requests of a session
+  /// that panicked.
+  pub(crate) async fn free_pending_blocks(&mut self) {
+    if self.outgoing_requests.is_empty() {
+      return;
+    }
     let downloads_guard = self.torrent.downloads.read().await;
+    let now = Instant::now();
     for block in self.outgoing_requests.drain() {
+      self.freed_requests.insert(block, now);
       // The piece may no longer be present if it was completed by
       // another peer in the meantime and torrent removed it from
       // the shared download store. This is fine, in this case we
       // don't have anything to do.
-      if let Some(download) = downloads_guard.get(&block.piece_index) {
+      if let Some(download) = downloads_guard
+        .as_ref()
+        .and_then(|downloads| downloads.get(&block.piece_index))
+      {
         log::debug!(
             target: &self.ctx.log_target,
             "Freeing block {} for download",
@@ -703,13 +1068,47 @@ impl PeerSession {
     }
   }
 
+  /// Removes the peer's pieces from the swarm's availability kept by the
+  /// piece picker, once the session is over.
+  pub(crate) async fn unregister_peer_pieces(&mut self) {
+    if self.torrent.is_seed() {
+      return;
+    }
+    if let Some(piece_picker) = self.torrent.piece_picker.write().await.as_mut()
+    {
+      piece_picker.unregister_peer_pieces(&self.peer.pieces);
+      self.peer.pieces.fill(false);
+    }
+  }
+
+  /// Sends the session state to torrent regardless of whether it changed,
+  /// as when the session ends, even if the torrent's channel is full.
+  fn report_state(&mut self) -> PeerResult<()> {
+    self
+      .torrent
+      .cmd_tx
+      .send_bulk_forced(torrent::Command::PeerState {
+        addr: self.peer.addr,
+        info: self.session_info(),
+      })?;
+    self.ctx.mark_reported();
+    Ok(())
+  }
+
   /// Returns a summary of the most important information of the session
   /// state to send to torrent.
   fn session_info(&self) -> SessionTick {
     SessionTick {
       state: self.ctx.state,
       counters: self.ctx.counters,
+      transferred: self.ctx.counters.since(&self.ctx.reported_counters),
       piece_count: self.peer.piece_count,
+      duplicate_request_count: self.ctx.duplicate_request_count,
+      duplicate_block_count: self.ctx.duplicate_block_count,
+      endgame: self.ctx.endgame,
+      outgoing_request_count: self.outgoing_requests.len(),
+      incoming_request_count: self.incoming_requests.len(),
+      has_misbehaved: self.ctx.has_misbehaved,
     }
   }
 
@@ -717,7 +1116,7 @@ impl PeerSession {
   /// (currently only the bitfield message).
   async fn handle_bitfield_msg(
     &mut self,
-    sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
     mut bitfield: Bitfield,
   ) -> PeerResult<()> {
     log::info!(
@@ -746,13 +1145,18 @@ impl PeerSession {
     // there doesn't seem much harm in it so we skip the check.
     bitfield.resize(self.torrent.storage.piece_count, false);
 
-    // register peer's pieces with piece picker and determine interest in it
-    let is_interested = self
-      .torrent
-      .piece_picker
-      .write()
-      .await
-      .register_peer_pieces(&bitfield);
+    // register peer's pieces with piece picker and determine interest in it,
+    // unless we're a seed, in which case we can't be interested
+    let is_interested = !self.torrent.is_seed()
+      && self
+        .torrent
+        .piece_picker
+        .write()
+        .await
+        .as_mut()
+        .is_some_and(|piece_picker| {
+          piece_picker.register_peer_pieces(&bitfield)
+        });
     self.peer.pieces = bitfield;
     self.peer.piece_count = self.peer.pieces.count_ones();
 
@@ -779,12 +1183,28 @@ impl PeerSession {
   /// Handles messages from peer that are expected in the `Connected` state.
   async fn handle_msg(
     &mut self,
-    sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
     msg: Message,
   ) -> PeerResult<()> {
     // record protocol message size
     self.ctx.counters.protocol.down += msg.protocol_len();
 
+    let is_fast_msg = matches!(
+      msg,
+      Message::SuggestPiece { .. }
+        | Message::HaveAll
+        | Message::HaveNone
+        | Message::RejectRequest(_)
+        | Message::AllowedFast { .. }
+    );
+    if is_fast_msg && !self.peer.supports_fast {
+      log::warn!(
+          target: &self.ctx.log_target,
+          "Peer sent Fast extension message without supporting it"
+      );
+      return Err(PeerError::UnsupportedFastMessage);
+    }
+
     match msg {
       Message::KeepAlive => {
         log::info!(
@@ -792,7 +1212,7 @@ impl PeerSession {
             "Peer sent keep alive"
         );
       }
-      Message::Bitfield(_) => {
+      Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
         log::info!(
             target: &self.ctx.log_target,
             "Peer sent bitfield message not after handshake"
@@ -807,8 +1227,11 @@ impl PeerSession {
           );
           // since we're choked we don't expect to receive blocks
           // for our pending requests and free them for other peers to
-          // download
-          self.free_pending_blocks().await;
+          // download, unless the peer rejects the requests it won't serve
+          // one by one
+          if !self.peer.supports_fast {
+            self.free_pending_blocks().await;
+          }
           self.ctx.update_state(|state| state.is_choked = true);
         }
       }
@@ -830,25 +1253,16 @@ impl PeerSession {
         }
       }
       Message::Interested => {
-        if !self.ctx.state.is_peer_choked {
-          // TODO: currently unchoked peer unconditionally, but we
-          // should implement the proper unchoked algorithm in `Torrent`
+        // whether the peer is unchoked is up to the torrent's choker, which
+        // learns of the peer's interest from the session's next tick
+        if !self.ctx.state.is_peer_interested {
           log::info!(
               target: &self.ctx.log_target,
               "Peer became interested"
           );
-
-          log::info!(
-              target: &self.ctx.log_target,
-              "Unchoking peer"
-          );
-
           self.ctx.update_state(|state| {
-            state.is_peer_choked = false;
             state.is_peer_interested = true;
           });
-
-          sink.send(Message::Unchoke).await?;
         }
       }
       Message::NotInterested => {
@@ -866,7 +1280,7 @@ impl PeerSession {
         self.handle_have_msg(sink, piece_index).await?;
       }
       Message::Request(block_info) => {
-        self.handle_request_msg(block_info).await?;
+        self.handle_request_msg(sink, block_info).await?;
       }
       Message::Block {
         piece_index,
@@ -878,6 +1292,7 @@ impl PeerSession {
           offset,
           len: data.len() as u32,
         };
+        self.validate_block_info(&block_info)?;
         self.handle_block_msg(block_info, data.into_owned()).await?;
 
         // we may be able to make more requests now that a block
@@ -886,13 +1301,182 @@ impl PeerSession {
       }
       Message::Cancel(block_info) => {
         // before processing request validate block info
-        self.validate_block_info(&block_info)?;
+        self.validate_request(&block_info)?;
         log::info!(
             target: &self.ctx.log_target,
             "Peer cancelled block {}",
             block_info
         );
-        self.incoming_requests.remove(&block_info);
+        // with the Fast extension, a cancelled request is answered with
+        // either the block or a rejection
+        if self.incoming_requests.remove(&block_info) {
+          self.reject_request(sink, block_info).await?;
+        }
+      }
+      Message::SuggestPiece { piece_index } => {
+        self.validate_piece_index(piece_index)?;
+        // the piece picker doesn't take suggestions
+        log::debug!(
+            target: &self.ctx.log_target,
+            "Peer suggested piece {}",
+            piece_index
+        );
+      }
+      Message::RejectRequest(block_info) => {
+        self.validate_block_info(&block_info)?;
+        self.handle_reject_request_msg(block_info).await;
+      }
+      Message::AllowedFast { piece_index } => {
+        self.validate_piece_index(piece_index)?;
+        log::info!(
+            target: &self.ctx.log_target,
+            "Peer allows fast piece {}",
+            piece_index
+        );
+        self.peer.allowed_fast.insert(piece_index);
+        // we may be able to make requests even if we're choked
+        if self.ctx.state.is_choked && self.ctx.state.is_interested {
+          self.make_requests(sink).await?;
+        }
+      }
+      Message::Extended { id, payload } => {
+        self.handle_extended_msg(sink, id, &payload).await?;
+      }
+      Message::Port(port) => {
+        log::info!(
+            target: &self.ctx.log_target,
+            "Peer sent DHT port {}",
+            port
+        );
+        if let Some(dht) = &self.torrent.dht {
+          dht.add_node(SocketAddr::new(self.peer.addr.ip(), port));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns our handshake, which tells the peer that we run a DHT node if
+  /// the torrent uses the DHT.
+  fn handshake(&self) -> Handshake {
+    let mut handshake =
+      Handshake::new(self.torrent.info_hash, self.torrent.client_id);
+    if self.torrent.dht.is_some() {
+      handshake.set_dht();
+    }
+    handshake
+  }
+
+  /// Handles the peer's rejection of our request (BEP 6) by freeing the
+  /// block so that it may be downloaded from other peers.
+  ///
+  /// The block isn't requested again right away, as the peer would likely
+  /// reject it again.
+  async fn handle_reject_request_msg(&mut self, block_info: BlockInfo) {
+    // a request that was timed out, and thus already freed, may be rejected
+    // too, so this is not a protocol violation
+    if !self.outgoing_requests.remove(&block_info) {
+      log::debug!(
+          target: &self.ctx.log_target,
+          "Peer rejected block {} not pending",
+          block_info
+      );
+      return;
+    }
+    log::info!(
+        target: &self.ctx.log_target,
+        "Peer rejected block {}",
+        block_info
+    );
+    if let Some(download) = self
+      .torrent
+      .downloads
+      .read()
+      .await
+      .as_ref()
+      .and_then(|downloads| downloads.get(&block_info.piece_index))
+    {
+      download.write().await.free_block(&block_info);
+    }
+  }
+
+  /// Handles a message of the extension protocol, of which we support the
+  /// extension handshake and the metadata exchange.
+  async fn handle_extended_msg(
+    &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
+    id: u8,
+    payload: &[u8],
+  ) -> PeerResult<()> {
+    match id {
+      EXTENDED_HANDSHAKE_ID => {
+        let handshake = ExtendedHandshake::decode(payload)?;
+        log::info!(
+            target: &self.ctx.log_target,
+            "Peer sent extension handshake: {:?}",
+            handshake
+        );
+        self.peer.ut_metadata_id = handshake.ut_metadata_id();
+        // the client is only informational, so it's dropped if the
+        // torrent falls behind
+        if let Some(client) = handshake.v {
+          let cmd = torrent::Command::PeerClient {
+            addr: self.peer.addr,
+            client,
+          };
+          if let Err(TrySendError::Closed(_)) =
+            self.torrent.cmd_tx.send_bulk(cmd).await
+          {
+            return Err(PeerError::Channel);
+          }
+        }
+      }
+      UT_METADATA_ID => {
+        let Some(peer_id) = self.peer.ut_metadata_id else {
+          return Err(PeerError::InvalidExtensionMessage);
+        };
+        let resp = match MetadataMessage::decode(payload)? {
+          MetadataMessage::Request { piece } => {
+            log::info!(
+                target: &self.ctx.log_target,
+                "Peer requested metadata piece {}",
+                piece
+            );
+            match metadata_piece(&self.torrent.metadata, piece) {
+              Some(data) => MetadataMessage::Data {
+                piece,
+                total_size: self.torrent.metadata.len(),
+                data: Bytes::copy_from_slice(data),
+              },
+              None => MetadataMessage::Reject { piece },
+            }
+          }
+          // we already have the metadata, so we don't request it
+          msg => {
+            log::debug!(
+                target: &self.ctx.log_target,
+                "Ignoring unrequested metadata message: {:?}",
+                msg
+            );
+            return Ok(());
+          }
+        };
+        self
+          .send_msg(
+            sink,
+            Message::Extended {
+              id: peer_id,
+              payload: resp.encode()?,
+            },
+          )
+          .await?;
+      }
+      _ => {
+        log::debug!(
+            target: &self.ctx.log_target,
+            "Ignoring message of unknown extension {}",
+            id
+        );
       }
     }
     Ok(())
@@ -902,22 +1486,69 @@ impl PeerSession {
   /// requests.
   async fn make_requests(
     &mut self,
-    sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
   ) -> PeerResult<()> {
     log::trace!(
         target: &self.ctx.log_target,
         "Cannot make requests while choked"
     );
 
-    if self.ctx.state.is_choked {
+    // a seed has nothing to download
+    if self.torrent.is_seed() {
+      return Ok(());
+    }
+
+    // nor may a torrent whose disk can't keep up with the download
+    if self.torrent.is_write_stalled() {
       log::debug!(
           target: &self.ctx.log_target,
-          "Cannot make requests while choked"
+          "Cannot make requests while disk writes are stalled"
       );
+      return Ok(());
+    }
 
+    // and no more blocks are taken in while the engine's buffers are over
+    // their budget, until they drain
+    if self.torrent.memory.is_over_budget() {
+      log::debug!(
+          target: &self.ctx.log_target,
+          "Cannot make requests while memory is over budget"
+      );
       return Ok(());
     }
 
+    // while choked, only the pieces the peer allows us to download anyway
+    // and that it has may be requested
+    let allowed_fast: Option<HashSet<PieceIndex>> = if self.ctx.state.is_choked
+    {
+      let allowed_fast: HashSet<_> = self
+        .peer
+        .allowed_fast
+        .iter()
+        .copied()
+        .filter(|&index| self.peer.pieces[index])
+        .collect();
+      if allowed_fast.is_empty() {
+        log::debug!(
+            target: &self.ctx.log_target,
+            "Cannot make requests while choked"
+        );
+
+        return Ok(());
+      }
+      Some(allowed_fast)
+    } else {
+      None
+    };
+    // otherwise any piece the peer has may be requested
+    let peer_pieces = &self.peer.pieces;
+    let is_allowed = |index: PieceIndex| {
+      peer_pieces[index]
+        && allowed_fast
+          .as_ref()
+          .is_none_or(|allowed_fast| allowed_fast.contains(&index))
+    };
+
     if !self.ctx.state.is_interested {
       log::debug!(
           target: &self.ctx.log_target,
@@ -929,12 +1560,32 @@ impl PeerSession {
 
     // TODO: optimize this by using the preallocated hash-set in self
     let mut requests = Vec::new();
-    let target_request_queue_len =
-      self.ctx.target_request_queue_len.unwrap_or_default();
+    // allowed fast pieces may be requested before the download pipeline is
+    // prepared on being unchoked
+    let mut target_request_queue_len = match self.ctx.target_request_queue_len {
+      Some(len) => len,
+      None if allowed_fast.is_some() => SessionContext::START_REQUEST_QUEUE_LEN,
+      None => 0,
+    };
+
+    // don't request more than the torrent's and the engine's download rate
+    // limits allow
+    let available = self.torrent.download_limiter.available(Instant::now());
+    if let Some(available) = available {
+      let allowed_count = available.div_ceil(BLOCK_LEN as u64) as usize;
+      target_request_queue_len = target_request_queue_len
+        .min(self.outgoing_requests.len() + allowed_count);
+    }
 
     // If we have active downloads, prefer to continue those.
     // This will result in less in-progress pieces.
-    for download in self.torrent.downloads.write().await.values_mut() {
+    for (&index, download) in
+      self.torrent.downloads.write().await.iter_mut().flatten()
+    {
+      if !is_allowed(index) {
+        continue;
+      }
+
       // check and calculate the number of requests we can make now
       let outgoing_request_count =
         requests.len() + self.outgoing_requests.len();
@@ -948,6 +1599,10 @@ impl PeerSession {
       let to_request_count = target_request_queue_len - outgoing_request_count;
 
       let mut download_write_guard = download.write().await;
+      // a piece on parole is only downloaded from the peer it's left to
+      if !download_write_guard.claim(self.peer.addr) {
+        continue;
+      }
 
       log::trace!(
           target: &self.ctx.log_target,
@@ -955,12 +1610,12 @@ impl PeerSession {
           download_write_guard.piece_index()
       );
 
-      download_write_guard.pick_blocks(
+      self.ctx.endgame.duplicate_requests += download_write_guard.pick_blocks(
         to_request_count,
         &mut requests,
-        self.ctx.in_endgame,
+        self.ctx.in_endgame.then_some(&self.conf.endgame),
         &self.outgoing_requests,
-      );
+      ) as u64;
     }
 
     // while we can make more requests we start new download(s)
@@ -983,7 +1638,13 @@ impl PeerSession {
 
       // old version:
       // if let Some(index) = self.torrent.piece_picker.write().await.pick_piece()
-      if let Some(index) = self.torrent.piece_picker.write().await.pick_piece()
+      if let Some(index) = self
+        .torrent
+        .piece_picker
+        .write()
+        .await
+        .as_mut()
+        .and_then(|piece_picker| piece_picker.pick_piece_among(is_allowed))
       {
         log::info!(
             target: &self.ctx.log_target,
@@ -994,25 +1655,29 @@ impl PeerSession {
         let mut download =
           PieceDownload::new(index, self.torrent.storage.piece_len(index));
 
+        // a new download has no requested blocks to pick again
         download.pick_blocks(
           to_request_count,
           &mut requests,
-          self.ctx.in_endgame,
+          None,
           &self.outgoing_requests,
         );
         // save download
-        self
-          .torrent
-          .downloads
-          .write()
-          .await
-          .insert(index, RwLock::new(download));
+        if let Some(downloads) = self.torrent.downloads.write().await.as_mut() {
+          downloads.insert(index, RwLock::new(download));
+        }
       } else {
         log::debug!(
             target: &self.ctx.log_target,
             "Cannot pick more pieces (pending \
             pieces: {}, blocks: {})",
-            self.torrent.downloads.read().await.len(),
+            self
+              .torrent
+              .downloads
+              .read()
+              .await
+              .as_ref()
+              .map_or(0, HashMap::len),
             self.outgoing_requests.len()
         );
 
@@ -1027,20 +1692,35 @@ impl PeerSession {
           requests.len(),
           self.outgoing_requests.len()
       );
-      self.ctx.last_outgoing_request_time = Some(Instant::now());
+      self
+        .ctx
+        .register_requests(!self.outgoing_requests.is_empty(), Instant::now());
 
       // make the actual requests
       for req in requests.into_iter() {
+        // never request the same block twice from the same peer
+        if !self.outgoing_requests.insert(req) {
+          log::debug!(
+              target: &self.ctx.log_target,
+              "Dropping duplicate request for block {}",
+              req
+          );
+          self.ctx.record_duplicate_request();
+          continue;
+        }
         log::debug!(
             target: &self.ctx.log_target,
             "Requesting block {}",
             req
         );
-        self.outgoing_requests.insert(req);
+
+        // the block is accounted for in the rate limit once requested, as
+        // that's when we commit to downloading it
+        self.torrent.download_limiter.consume(req.len as u64);
 
         // TODO: batch these in a single sys-call, or is this already
         // being done by the tokio codec type?
-        sink.send(Message::Request(req)).await?;
+        self.send_msg(sink, Message::Request(req)).await?;
         self.ctx.counters.protocol.up += MessageId::Request.header_len();
       }
     }
@@ -1051,14 +1731,34 @@ impl PeerSession {
   /// Verifies block validity, registers the download, and records statistics.
   ///
   /// Blocks are accepted even if timed out/cancelled, if the block has not
-  /// been downloaded (e.g. from another peer) since then.
+  /// been downloaded (e.g. from another peer) since then. Blocks we never
+  /// requested are dropped, and a peer sending too many of them is
+  /// disconnected.
   async fn handle_block_msg(
     &mut self,
     block_info: BlockInfo,
     data: Vec<u8>,
   ) -> PeerResult<()> {
     // remove pending block request
-    self.outgoing_requests.remove(&block_info);
+    let was_requested = self.outgoing_requests.remove(&block_info);
+    let was_freed = self.freed_requests.remove(&block_info).is_some();
+    if !was_requested && !was_freed {
+      log::warn!(
+          target: &self.ctx.log_target,
+          "Dropping unsolicited block {}",
+          block_info
+      );
+      self.ctx.record_waste(block_info.len);
+      if self.ctx.record_unsolicited_block() {
+        log::warn!(
+            target: &self.ctx.log_target,
+            "Peer sent {} unsolicited blocks, disconnecting",
+            self.ctx.unsolicited_block_count
+        );
+        return Err(PeerError::UnsolicitedBlocks);
+      }
+      return Ok(());
+    }
 
     // try to find the piece to which this block corresponds
     // and mark the block in piece as downloaded
@@ -1067,9 +1767,14 @@ impl PeerSession {
       .downloads
       .read()
       .await
-      .get(&block_info.piece_index)
+      .as_ref()
+      .and_then(|downloads| downloads.get(&block_info.piece_index))
     {
-      Some(download) => download.write().await.received_block(&block_info),
+      Some(download) => download.write().await.received_block(
+        &block_info,
+        self.peer.addr,
+        &data,
+      ),
       None => {
         log::warn!(
             target: &self.ctx.log_target,
@@ -1089,18 +1794,31 @@ impl PeerSession {
     // don't process the block if already downloaded
     if prev_status == BlockStatus::Received {
       self.ctx.record_waste(block_info.len);
-      log::info!(
-          target: &self.ctx.log_target,
-          "Already downloaded block {}",
-          block_info
-      );
-    } else {
-      log::info!(
-          target: &self.ctx.log_target,
-          "Got block {}{}",
-          block_info,
-          if self.ctx.in_slow_start {
-              " in slow-start"
+      if was_requested && self.ctx.in_endgame {
+        self.ctx.endgame.wasted_bytes += block_info.len as u64;
+      }
+      if was_requested {
+        // e.g. in endgame another peer may have been faster
+        log::info!(
+            target: &self.ctx.log_target,
+            "Already downloaded block {}",
+            block_info
+        );
+      } else {
+        log::debug!(
+            target: &self.ctx.log_target,
+            "Dropping duplicate block {}",
+            block_info
+        );
+        self.ctx.record_duplicate_block();
+      }
+    } else {
+      log::info!(
+          target: &self.ctx.log_target,
+          "Got block {}{}",
+          block_info,
+          if self.ctx.in_slow_start {
+              " in slow-start"
           } else if self.ctx.in_endgame {
               " in endgame"
           }else {
@@ -1113,11 +1831,22 @@ impl PeerSession {
 
       // validate and save the block to disk by sending a write
       // command to the disk task.
-      self.torrent.disk_tx.send(disk::Command::WriteBlock {
-        id: self.torrent.id,
-        block_info,
-        data,
-      })?;
+      // this waits if the disk falls behind, so that we stop reading
+      // blocks from the peer until it catches up
+      self
+        .torrent
+        .disk_tx
+        .send_bulk(disk::Command::WriteBlock {
+          id: self.torrent.id,
+          block_info,
+          charge: MemoryCharge::with_len(
+            &self.torrent.memory,
+            Buffer::DiskQueue,
+            data.len() as u64,
+          ),
+          data,
+        })
+        .await?;
     }
     Ok(())
   }
@@ -1131,6 +1860,7 @@ impl PeerSession {
   /// if by the request is not cancelled by then.
   async fn handle_request_msg(
     &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
     block_info: BlockInfo,
   ) -> PeerResult<()> {
     log::info!(
@@ -1139,43 +1869,222 @@ impl PeerSession {
         block_info
     );
 
-    // before processing request validate block info
-    self.validate_block_info(&block_info)?;
+    // before processing request validate block info, which unlike a block
+    // may span more than one block
+    self.validate_request(&block_info)?;
 
     // check if peer is not chocked:
-    // if they are, they can't request blocks.
+    // if they are, they can't request blocks, although with the Fast
+    // extension we tell them so instead of disconnecting
     if self.ctx.state.is_peer_choked {
       log::warn!(
           target: &self.ctx.log_target,
           "Choked peer sent request"
       );
+      if self.peer.supports_fast {
+        return self
+          .send_msg(sink, Message::RejectRequest(block_info))
+          .await;
+      }
       return Err(PeerError::RequestWhileChocked);
     }
 
+    // only pieces we have can be served, as others are not yet on disk
+    if !self.has_piece(block_info.piece_index).await {
+      log::warn!(
+          target: &self.ctx.log_target,
+          "Peer requested piece {} we don't have",
+          block_info.piece_index
+      );
+      return self.reject_request(sink, block_info).await;
+    }
+
+    if block_info.len > self.conf.max_request_len {
+      log::warn!(
+          target: &self.ctx.log_target,
+          "Peer requested {} bytes, over the max of {}",
+          block_info.len,
+          self.conf.max_request_len
+      );
+      self.register_request_violation()?;
+      return self.reject_request(sink, block_info).await;
+    }
+
+    // each request takes up a disk read and the memory of its block until
+    // the block is sent, so the peer may only have so many outstanding
+    if self.incoming_requests.len() >= self.conf.max_incoming_request_count
+      && !self.incoming_requests.contains(&block_info)
+    {
+      log::warn!(
+          target: &self.ctx.log_target,
+          "Peer exceeded request queue of {}",
+          self.conf.max_incoming_request_count
+      );
+      self.register_request_violation()?;
+      return self.reject_request(sink, block_info).await;
+    }
+
     // check if peer is not already requesting this block
-    if self.incoming_requests.contains(&block_info) {
-      // TODO: if peer keeps spamming us, close connection.
+    if !self.incoming_requests.insert(block_info) {
       log::warn!(
           target: &self.ctx.log_target,
           "Peer sent duplicate request"
       );
-      return Ok(());
+      return self.register_request_violation();
+    }
+
+    // while too many blocks are held back by the upload rate limit, the
+    // read waits for them to be sent, as does any read after it
+    if self.throttled_charge.len() >= MAX_THROTTLED_LEN
+      || !self.deferred_reads.is_empty()
+    {
+      log::debug!(
+          target: &self.ctx.log_target,
+          "Upload rate limited, deferring read of {}",
+          block_info
+      );
+      self.deferred_reads.push_back(block_info);
+    } else {
+      self.read_block(block_info).await?;
+    }
+
+    // pre-read the next piece for peers streaming the torrent, so that their
+    // upcoming requests are served from the cache
+    if let Some(piece_index) = self
+      .sequential_detector
+      .record_request(block_info.piece_index)
+    {
+      // only pieces we have may be read (this also skips the piece past the
+      // last one)
+      if !self.has_piece(piece_index).await {
+        return Ok(());
+      }
+      log::debug!(
+          target: &self.ctx.log_target,
+          "Peer requests sequentially, reading ahead piece {}",
+          piece_index
+      );
+      self.torrent.disk_tx.send(disk::Command::ReadAhead {
+        id: self.torrent.id,
+        piece_index,
+      })?;
     }
 
+    Ok(())
+  }
+
+  /// Instructs the disk task to read the requested block, which is sent to
+  /// the session once read.
+  async fn read_block(&mut self, block_info: BlockInfo) -> PeerResult<()> {
     log::info!(
         target: &self.ctx.log_target,
         "Issuing disk IO read for block {}",
         block_info
     );
+    self
+      .torrent
+      .disk_tx
+      .send_bulk(disk::Command::ReadBlock {
+        id: self.torrent.id,
+        block_info,
+        result_tx: self.cmd_tx.clone(),
+      })
+      .await?;
+    Ok(())
+  }
+
+  /// Returns whether we have the piece, and so can serve its blocks.
+  async fn has_piece(&self, piece_index: PieceIndex) -> bool {
+    if !self.torrent.is_seed() {
+      if let Some(piece_picker) = &*self.torrent.piece_picker.read().await {
+        return piece_picker
+          .own_pieces()
+          .get(piece_index)
+          .is_some_and(|bit| *bit);
+      }
+    }
+    // a seed has all pieces
+    piece_index < self.torrent.storage.piece_count
+  }
+
+  /// Tells the peer that we won't serve its request, if it supports the
+  /// Fast extension, while other peers are left to time out the request.
+  async fn reject_request(
+    &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
+    block_info: BlockInfo,
+  ) -> PeerResult<()> {
+    if self.peer.supports_fast {
+      self
+        .send_msg(sink, Message::RejectRequest(block_info))
+        .await?;
+    }
+    Ok(())
+  }
+
+  /// Sends the block read from disk to peer, unless the torrent's upload
+  /// rate limit is reached, in which case it's queued to be sent once the
+  /// limit allows.
+  async fn upload_block(
+    &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
+    block: Block,
+  ) -> PeerResult<()> {
+    // a canceled block is dropped by `send_block` without counting against
+    // the limit, and queued blocks are sent first to keep them in order
+    if self.incoming_requests.contains(&block.info()) {
+      let is_allowed = self.throttled_blocks.is_empty()
+        && self
+          .torrent
+          .upload_limiter
+          .try_consume(block.info().len as u64, Instant::now());
+      if !is_allowed {
+        log::debug!(
+            target: &self.ctx.log_target,
+            "Upload rate limited, queuing {}",
+            block.info()
+        );
+        self.throttled_charge.add(block.info().len as u64);
+        self.throttled_blocks.push_back(block);
+        return Ok(());
+      }
+    }
+    self.send_block(sink, block).await
+  }
 
-    // validate and save the block to disk by sending a write command
-    // to the disk task.
-    self.torrent.disk_tx.send(disk::Command::ReadBlock {
-      id: self.torrent.id,
-      block_info,
-      result_tx: self.cmd_tx.clone(),
-    })?;
+  /// Sends the blocks held back by the upload rate limit, as many as the
+  /// limit allows.
+  async fn send_throttled_blocks(
+    &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
+  ) -> PeerResult<()> {
+    while let Some(block) = self.throttled_blocks.front() {
+      let info = block.info();
+      // canceled blocks are dropped without counting against the limit
+      if self.incoming_requests.contains(&info) {
+        let is_allowed = self
+          .torrent
+          .upload_limiter
+          .try_consume(info.len as u64, Instant::now());
+        if !is_allowed {
+          break;
+        }
+      }
+      let block = self.throttled_blocks.pop_front().expect("queue is empty");
+      self.throttled_charge.sub(info.len as u64);
+      self.send_block(sink, block).await?;
+    }
 
+    // the deferred reads are issued as the held back blocks make room
+    while self.throttled_charge.len() < MAX_THROTTLED_LEN {
+      let Some(block_info) = self.deferred_reads.pop_front() else {
+        break;
+      };
+      // canceled requests aren't read
+      if self.incoming_requests.contains(&block_info) {
+        self.read_block(block_info).await?;
+      }
+    }
     Ok(())
   }
 
@@ -1183,7 +2092,7 @@ impl PeerSession {
   /// (hasn't canceled the request)
   async fn send_block(
     &mut self,
-    sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
     block: Block,
   ) -> PeerResult<()> {
     let info = block.info();
@@ -1214,12 +2123,16 @@ impl PeerSession {
         info
     );
 
-    sink
-      .send(Message::Block {
-        piece_index: block.piece_index,
-        offset: block.offset,
-        data: block.data,
-      })
+    self.send_buf_charge.add(block.data.len() as u64);
+    self
+      .send_msg(
+        sink,
+        Message::Block {
+          piece_index: block.piece_index,
+          offset: block.offset,
+          data: block.data,
+        },
+      )
       .await?;
 
     log::info!(
@@ -1239,7 +2152,7 @@ impl PeerSession {
   /// start making requests.
   async fn handle_have_msg(
     &mut self,
-    sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
     piece_index: PieceIndex,
   ) -> PeerResult<()> {
     log::info!(
@@ -1262,12 +2175,16 @@ impl PeerSession {
     self.peer.piece_count += 1;
 
     // need to recalculate interest with each received piece
-    let is_interested = self
-      .torrent
-      .piece_picker
-      .write()
-      .await
-      .register_peer_piece(piece_index);
+    let is_interested = !self.torrent.is_seed()
+      && self
+        .torrent
+        .piece_picker
+        .write()
+        .await
+        .as_mut()
+        .is_some_and(|piece_picker| {
+          piece_picker.register_peer_piece(piece_index)
+        });
 
     // we may have become interested in peer
     self.update_interest(sink, is_interested).await
@@ -1276,7 +2193,7 @@ impl PeerSession {
   /// Checks whether we have become or stopped being interested in the peer.
   async fn update_interest(
     &mut self,
-    sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
     is_interested: bool,
   ) -> PeerResult<()> {
     // we may have become interested in peer
@@ -1290,7 +2207,7 @@ impl PeerSession {
         .ctx
         .update_state(|state| state.is_interested = is_interested);
       // send interested message to peer
-      sink.send(Message::Interested).await?;
+      self.send_msg(sink, Message::Interested).await?;
     } else if self.ctx.state.is_interested && !is_interested {
       log::info!(
           target: &self.ctx.log_target,
@@ -1304,6 +2221,71 @@ impl PeerSession {
     Ok(())
   }
 
+  /// Chokes the peer if it's not yet choked, after which its pending
+  /// requests are no longer served.
+  async fn choke_peer(
+    &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
+  ) -> PeerResult<()> {
+    if self.ctx.state.is_peer_choked {
+      return Ok(());
+    }
+    log::info!(
+        target: &self.ctx.log_target,
+        "Choking peer"
+    );
+    self.ctx.counters.protocol.up += MessageId::Choke.header_len();
+    self.ctx.update_state(|state| state.is_peer_choked = true);
+    self.send_msg(sink, Message::Choke).await?;
+
+    // choking implicitly discards the peer's pending requests, but with
+    // the Fast extension each of them must be rejected explicitly, and
+    // blocks that are read or held back for them are dropped as if the
+    // requests had been canceled
+    self.deferred_reads.clear();
+    for block_info in std::mem::take(&mut self.incoming_requests) {
+      self.reject_request(sink, block_info).await?;
+    }
+    Ok(())
+  }
+
+  /// Unchokes the peer if it's choked, allowing it to request blocks.
+  async fn unchoke_peer(
+    &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
+  ) -> PeerResult<()> {
+    if !self.ctx.state.is_peer_choked {
+      return Ok(());
+    }
+    log::info!(
+        target: &self.ctx.log_target,
+        "Unchoking peer"
+    );
+    self.ctx.counters.protocol.up += MessageId::Unchoke.header_len();
+    self.ctx.update_state(|state| state.is_peer_choked = false);
+    self.send_msg(sink, Message::Unchoke).await
+  }
+
+  /// Sends a message to peer, recording the time of sending so that we know
+  /// when a keep-alive is due.
+  ///
+  /// The message is only buffered, to be written along with the others sent
+  /// in the same turn of the session loop, which flushes the sink at the
+  /// end of each turn. The buffer is still written as soon as it fills up,
+  /// e.g. with blocks.
+  async fn send_msg(
+    &mut self,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
+    msg: Message,
+  ) -> PeerResult<()> {
+    // the message's protocol bytes count against the upload rate limit, but
+    // are never held back by it
+    self.torrent.upload_limiter.consume(msg.protocol_len());
+    sink.feed(msg).await?;
+    self.ctx.last_outgoing_msg_time = Some(Instant::now());
+    Ok(())
+  }
+
   /// Validates that the block info refers to a valid piece's valid block in
   /// torrent.
   fn validate_block_info(&self, info: &BlockInfo) -> PeerResult<()> {
@@ -1314,16 +2296,48 @@ impl PeerSession {
     );
     self.validate_piece_index(info.piece_index)?;
     let piece_len = self.torrent.storage.piece_len(info.piece_index);
-    if info.len > 0 && info.offset + info.len <= piece_len {
-      Ok(())
-    } else {
+    match BlockInfo::try_new(info.piece_index, info.offset, info.len, piece_len)
+    {
+      Ok(_) => Ok(()),
+      Err(e) => {
+        log::warn!(
+            target: &self.ctx.log_target,
+            "Peer sent invalid {}: {}",
+            info,
+            e
+        );
+        Err(PeerError::InvalidBlockInfo(e))
+      }
+    }
+  }
+
+  /// Validates the peer's request, which may span more than one block.
+  fn validate_request(&self, info: &BlockInfo) -> PeerResult<()> {
+    self.validate_piece_index(info.piece_index)?;
+    let piece_len = self.torrent.storage.piece_len(info.piece_index);
+    info.validate_request(piece_len).map_err(|e| {
       log::warn!(
           target: &self.ctx.log_target,
-          "Peer sent invalid {}",
-          info
+          "Peer sent invalid request {}: {}",
+          info,
+          e
+      );
+      PeerError::InvalidBlockInfo(e)
+    })
+  }
+
+  /// Records a request beyond our limits, which is rejected, returning an
+  /// error if the peer made too many of them.
+  fn register_request_violation(&mut self) -> PeerResult<()> {
+    if self.ctx.record_request_violation() {
+      log::warn!(
+          target: &self.ctx.log_target,
+          "Peer made {} requests beyond limits, disconnecting",
+          self.ctx.request_violation_count
       );
-      Err(PeerError::InvalidBlockInfo)
+      return Err(PeerError::RequestLimitViolation);
     }
+    Ok(())
   }
 
   /// Validates that the index refers to a valid piece in torrent.
@@ -1346,7 +2360,7 @@ impl PeerSession {
   /// that we need to cancel. If peer doesn't have the piece, we announce it.
   async fn handle_piece_completion(
     &mut self,
-    sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    sink: &mut SplitSink<Framed<PeerConnection, PeerCodec>, Message>,
     piece_index: PieceIndex,
   ) -> PeerResult<()> {
     // if peer doesn't have the piece, announce it.
@@ -1356,11 +2370,13 @@ impl PeerSession {
           "Announcing piece {}",
           piece_index
       );
-      sink.send(Message::Have { piece_index }).await?;
+      self.send_msg(sink, Message::Have { piece_index }).await?;
     } else {
       // Otherwise peer has it and we may have requested it.
       // Check if there are any pending requests for blocks in
-      // this piece, and if so, cancel them.
+      // this piece, and if so, cancel them, freeing their places in the
+      // pipeline for blocks we still need. If the blocks arrive anyway,
+      // they are discarded as the piece's download is gone.
       // TODO:
       // We could actually send the cancel messages much sooner,
       // when we first receive the block (rather than waiting for the
@@ -1368,17 +2384,769 @@ impl PeerSession {
       // torrent and all other peers, for each of these blocks received in
       // endgame, so it is questionable whether it's worth it at the cost
       // of slowing down the engine.
-      for block in self.outgoing_requests.iter() {
-        if block.piece_index == piece_index {
-          log::info!(
-              target: &self.ctx.log_target,
-              "Already have block {}, cancelling",
-              block
+      let cancels: Vec<_> = self
+        .outgoing_requests
+        .iter()
+        .filter(|block| block.piece_index == piece_index)
+        .copied()
+        .collect();
+      for block in &cancels {
+        log::info!(
+            target: &self.ctx.log_target,
+            "Already have block {}, cancelling",
+            block
+        );
+        self.outgoing_requests.remove(block);
+        self.freed_requests.insert(*block, Instant::now());
+        self.send_msg(sink, Message::Cancel(*block)).await?;
+        self.ctx.counters.protocol.up += MessageId::Cancel.header_len();
+        self.ctx.endgame.cancelled_bytes += block.len as u64;
+      }
+      if !cancels.is_empty() && self.ctx.state.is_interested {
+        self.make_requests(sink).await?;
+      }
+    }
+
+    // with the last piece the torrent became a seed, so from now on the
+    // session only uploads
+    if self.torrent.is_seed() {
+      self.update_interest(sink, false).await?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    path::PathBuf,
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      Mutex,
+    },
+  };
+
+  use tokio::{
+    io::{duplex, AsyncWriteExt, DuplexStream},
+    sync::Semaphore,
+    task,
+    time::timeout,
+  };
+  use tokio_util::codec::Encoder;
+
+  use super::*;
+  use crate::{
+    channel::{self, Overflow},
+    conf::Priority,
+    memory::MemoryCounters,
+    piece_picker::PiecePicker,
+    rate_limiter::{RateLimiter, TorrentRateLimiter},
+    storage_info::{FileInfo, StorageInfo},
+    transport::TcpTransport,
+  };
+
+  const PIECE_COUNT: usize = 4;
+  const PIECE_LEN: u32 = 4 * BLOCK_LEN;
+
+  /// The channels of a torrent context that the test keeps open, so that the
+  /// session doesn't stop when it sends on them.
+  struct Channels {
+    torrent_rx: torrent::Receiver,
+    _disk_rx: channel::Receiver<disk::Command>,
+    _alert_rx: UnboundedReceiver<Alert>,
+  }
+
+  fn torrent_ctx(
+    download_limiter: RateLimiter,
+    upload_limiter: RateLimiter,
+  ) -> (Arc<TorrentContext>, Channels) {
+    let (cmd_tx, torrent_rx) = torrent::channel();
+    let (disk_tx, disk_rx) = channel::channel(16, Overflow::Wait);
+    let (alert_tx, alert_rx) = mpsc::unbounded_channel();
+    let download_len = PIECE_COUNT as u64 * PIECE_LEN as u64;
+    let ctx = TorrentContext {
+      id: crate::TorrentId::new(),
+      info_hash: [1; 20],
+      client_id: [2; 20],
+      cmd_tx,
+      piece_picker: Arc::new(RwLock::new(Some(PiecePicker::new(
+        Bitfield::repeat(false, PIECE_COUNT),
+      )))),
+      downloads: RwLock::new(Some(Default::default())),
+      is_seed: AtomicBool::new(false),
+      is_partial_seed: AtomicBool::new(false),
+      is_write_stalled: AtomicBool::new(false),
+      alert_tx,
+      disk_tx,
+      storage: StorageInfo {
+        piece_count: PIECE_COUNT,
+        piece_len: PIECE_LEN,
+        last_piece_len: PIECE_LEN,
+        download_len,
+        download_dir: PathBuf::from("/"),
+        files: vec![FileInfo {
+          path: PathBuf::from("file"),
+          len: download_len,
+          torrent_offset: 0,
+        }],
+      },
+      metadata: b"d4:name4:teste".to_vec(),
+      dht: None,
+      connection_permits: Arc::new(Semaphore::new(1)),
+      connection_permit_count: Default::default(),
+      transport: Arc::new(TcpTransport::default()),
+      download_limiter: TorrentRateLimiter::new(
+        download_limiter,
+        Arc::new(Mutex::new(RateLimiter::new(None))),
+        Default::default(),
+        Priority::Normal,
+      ),
+      upload_limiter: TorrentRateLimiter::new(
+        upload_limiter,
+        Arc::new(Mutex::new(RateLimiter::new(None))),
+        Default::default(),
+        Priority::Normal,
+      ),
+      memory: Default::default(),
+    };
+    let channels = Channels {
+      torrent_rx,
+      _disk_rx: disk_rx,
+      _alert_rx: alert_rx,
+    };
+    (Arc::new(ctx), channels)
+  }
+
+  /// Starts an inbound session over an in-memory connection, returning the
+  /// peer's side of the connection after the handshake.
+  async fn connect(
+    torrent: Arc<TorrentContext>,
+  ) -> (Sender, Framed<DuplexStream, PeerCodec>) {
+    connect_with_conf(torrent, SessionConf::default()).await
+  }
+
+  async fn connect_with_conf(
+    torrent: Arc<TorrentContext>,
+    conf: SessionConf,
+  ) -> (Sender, Framed<DuplexStream, PeerCodec>) {
+    let (ours, theirs) = duplex(1 << 20);
+    let addr = "127.0.0.1:6881".parse().unwrap();
+    let (mut session, session_tx) =
+      PeerSession::new(Arc::clone(&torrent), conf, addr);
+    let info_hash = torrent.info_hash;
+    task::spawn(async move {
+      let (socket, handshake) =
+        accept_handshake(Box::new(ours), info_hash, conf.handshake_timeout)
+          .await?;
+      session.start_inbound(socket, handshake).await
+    });
+
+    let mut socket = Framed::new(theirs, HandshakeCodec);
+    socket
+      .send(Handshake::new(torrent.info_hash, [3; 20]))
+      .await
+      .unwrap();
+    socket.next().await.unwrap().unwrap();
+    let old_parts = socket.into_parts();
+    let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
+    new_parts.read_buf = old_parts.read_buf;
+    let mut socket = Framed::from_parts(new_parts);
+
+    // we have no pieces, or all as a seed, which with the Fast extension is
+    // told explicitly, followed by the extension handshake
+    let availability = if torrent.is_seed() {
+      Message::HaveAll
+    } else {
+      Message::HaveNone
+    };
+    assert_eq!(next_msg(&mut socket).await, availability);
+    let Message::Extended { id, payload } = next_msg(&mut socket).await else {
+      panic!("session didn't send extension handshake");
+    };
+    assert_eq!(id, EXTENDED_HANDSHAKE_ID);
+    let handshake = ExtendedHandshake::decode(&payload).unwrap();
+    assert_eq!(handshake.metadata_size, Some(torrent.metadata.len()));
+    assert_eq!(handshake.reqq, Some(conf.max_incoming_request_count));
+    (session_tx, socket)
+  }
+
+  async fn next_msg(socket: &mut Framed<DuplexStream, PeerCodec>) -> Message {
+    timeout(Duration::from_millis(500), socket.next())
+      .await
+      .expect("no message from session")
+      .expect("session closed connection")
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn should_accept_only_timely_handshakes_for_torrent() {
+    let info_hash = [1; 20];
+    let timeout = Duration::from_millis(100);
+    let mut encoded = BytesMut::new();
+    HandshakeCodec
+      .encode(Handshake::new(info_hash, [3; 20]), &mut encoded)
+      .unwrap();
+
+    let (ours, mut theirs) = duplex(1 << 10);
+    theirs.write_all(&encoded).await.unwrap();
+    let (_, handshake) = accept_handshake(Box::new(ours), info_hash, timeout)
+      .await
+      .unwrap();
+    assert_eq!(handshake.peer_id, [3; 20]);
+
+    // a handshake for another torrent is rejected
+    let (ours, mut theirs) = duplex(1 << 10);
+    theirs.write_all(&encoded).await.unwrap();
+    let result = accept_handshake(Box::new(ours), [2; 20], timeout).await;
+    assert!(matches!(result, Err(PeerError::InvalidInfoHash)));
+
+    // another protocol is rejected once the protocol string's length has
+    // arrived, without waiting for the rest
+    let (ours, mut theirs) = duplex(1 << 10);
+    theirs
+      .write_all(b"\x13GET / HTTP/1.1\r\nHost")
+      .await
+      .unwrap();
+    let result = accept_handshake(Box::new(ours), info_hash, timeout).await;
+    assert!(matches!(result, Err(PeerError::Io(_))));
+
+    // and a peer trickling its handshake is dropped after the timeout
+    let (ours, mut theirs) = duplex(1 << 10);
+    theirs
+      .write_all(&encoded[..HANDSHAKE_LEN - 1])
+      .await
+      .unwrap();
+    let result = accept_handshake(Box::new(ours), info_hash, timeout).await;
+    assert!(matches!(result, Err(PeerError::HandshakeTimeout)));
+  }
+
+  #[tokio::test]
+  async fn should_report_peer_client() {
+    let (torrent, mut channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let (_session_tx, mut socket) = connect(torrent).await;
+
+    let handshake = ExtendedHandshake {
+      v: Some("test/1.0".to_owned()),
+      ..Default::default()
+    };
+    socket
+      .send(Message::Extended {
+        id: EXTENDED_HANDSHAKE_ID,
+        payload: handshake.encode().unwrap(),
+      })
+      .await
+      .unwrap();
+
+    loop {
+      let cmd = timeout(Duration::from_millis(500), channels.torrent_rx.recv())
+        .await
+        .expect("session didn't report peer client")
+        .unwrap();
+      if let torrent::Command::PeerClient { client, .. } = cmd {
+        assert_eq!(client, "test/1.0");
+        break;
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn should_serve_metadata_to_peers() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let metadata = torrent.metadata.clone();
+    let (_session_tx, mut socket) = connect(torrent).await;
+
+    // the peer receives metadata messages by its own id
+    let handshake = ExtendedHandshake {
+      m: [("ut_metadata".to_owned(), 3)].into(),
+      ..Default::default()
+    };
+    socket
+      .send(Message::Extended {
+        id: EXTENDED_HANDSHAKE_ID,
+        payload: handshake.encode().unwrap(),
+      })
+      .await
+      .unwrap();
+
+    for (piece, expected) in [
+      (
+        0,
+        MetadataMessage::Data {
+          piece: 0,
+          total_size: metadata.len(),
+          data: metadata.clone().into(),
+        },
+      ),
+      (1, MetadataMessage::Reject { piece: 1 }),
+    ] {
+      socket
+        .send(Message::Extended {
+          id: UT_METADATA_ID,
+          payload: MetadataMessage::Request { piece }.encode().unwrap(),
+        })
+        .await
+        .unwrap();
+      let Message::Extended { id, payload } = next_msg(&mut socket).await
+      else {
+        panic!("session didn't answer metadata request");
+      };
+      assert_eq!(id, 3);
+      assert_eq!(MetadataMessage::decode(&payload).unwrap(), expected);
+    }
+  }
+
+  #[tokio::test]
+  async fn should_reject_requests_of_choked_peer() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let (_session_tx, mut socket) = connect(torrent).await;
+
+    socket.send(Message::HaveAll).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::Interested);
+
+    // the peer isn't unchoked before it's interested, and as it supports
+    // the Fast extension its request is rejected rather than disconnected
+    let block_info = BlockInfo {
+      piece_index: 0,
+      offset: 0,
+      len: BLOCK_LEN,
+    };
+    socket.send(Message::Request(block_info)).await.unwrap();
+    assert_eq!(
+      next_msg(&mut socket).await,
+      Message::RejectRequest(block_info)
+    );
+  }
+
+  #[tokio::test]
+  async fn should_run_seed_session_without_piece_picker() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    *torrent.piece_picker.write().await = None;
+    *torrent.downloads.write().await = None;
+    torrent.is_seed.store(true, Ordering::Release);
+    let (_session_tx, mut socket) = connect(Arc::clone(&torrent)).await;
+
+    // a seed isn't interested in anything the peer has
+    socket.send(Message::HaveAll).await.unwrap();
+    assert!(timeout(Duration::from_millis(100), socket.next())
+      .await
+      .is_err());
+    assert!(torrent.piece_picker.read().await.is_none());
+  }
+
+  #[tokio::test]
+  async fn should_choke_and_unchoke_peer_when_told() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let (session_tx, mut socket) = connect(Arc::clone(&torrent)).await;
+    torrent
+      .piece_picker
+      .write()
+      .await
+      .as_mut()
+      .unwrap()
+      .received_piece(0);
+
+    socket.send(Message::HaveAll).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::Interested);
+
+    // the peer's interest alone doesn't get it unchoked, the torrent's
+    // choker decides that
+    socket.send(Message::Interested).await.unwrap();
+    assert!(timeout(Duration::from_millis(100), socket.next())
+      .await
+      .is_err());
+    session_tx.send(Command::Unchoke).ok();
+    assert_eq!(next_msg(&mut socket).await, Message::Unchoke);
+
+    // once choked, the peer's pending request is rejected
+    let block_info = BlockInfo {
+      piece_index: 0,
+      offset: 0,
+      len: BLOCK_LEN,
+    };
+    socket.send(Message::Request(block_info)).await.unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+    session_tx.send(Command::Choke).ok();
+    assert_eq!(next_msg(&mut socket).await, Message::Choke);
+    assert_eq!(
+      next_msg(&mut socket).await,
+      Message::RejectRequest(block_info)
+    );
+  }
+
+  #[tokio::test]
+  async fn should_reject_requests_that_cant_be_served() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let conf = SessionConf {
+      max_incoming_request_count: 1,
+      ..Default::default()
+    };
+    let (session_tx, mut socket) =
+      connect_with_conf(Arc::clone(&torrent), conf).await;
+    torrent
+      .piece_picker
+      .write()
+      .await
+      .as_mut()
+      .unwrap()
+      .received_piece(0);
+
+    socket.send(Message::HaveNone).await.unwrap();
+    socket.send(Message::Interested).await.unwrap();
+    session_tx.send(Command::Unchoke).ok();
+    assert_eq!(next_msg(&mut socket).await, Message::Unchoke);
+
+    // a piece we don't have can't be served
+    let missing = BlockInfo {
+      piece_index: 1,
+      offset: 0,
+      len: BLOCK_LEN,
+    };
+    socket.send(Message::Request(missing)).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::RejectRequest(missing));
+
+    // while the first request is read from disk, the second one is over
+    // the peer's budget
+    let first = BlockInfo {
+      piece_index: 0,
+      offset: 0,
+      len: BLOCK_LEN,
+    };
+    let second = BlockInfo {
+      offset: BLOCK_LEN,
+      ..first
+    };
+    socket.send(Message::Request(first)).await.unwrap();
+    socket.send(Message::Request(second)).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::RejectRequest(second));
+  }
+
+  #[tokio::test]
+  async fn should_disconnect_peer_flooding_requests() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let (session_tx, mut socket) = connect(Arc::clone(&torrent)).await;
+    torrent
+      .piece_picker
+      .write()
+      .await
+      .as_mut()
+      .unwrap()
+      .received_piece(0);
+
+    socket.send(Message::HaveNone).await.unwrap();
+    socket.send(Message::Interested).await.unwrap();
+    session_tx.send(Command::Unchoke).ok();
+    assert_eq!(next_msg(&mut socket).await, Message::Unchoke);
+
+    // requests longer than the max are rejected, until the peer made too many
+    // of them
+    let too_long = BlockInfo {
+      piece_index: 0,
+      offset: 0,
+      len: 2 * BLOCK_LEN,
+    };
+    for _ in 0..100 {
+      socket.send(Message::Request(too_long)).await.unwrap();
+      let msg = timeout(Duration::from_millis(500), socket.next())
+        .await
+        .expect("no message from session");
+      match msg {
+        Some(Ok(msg)) => assert_eq!(msg, Message::RejectRequest(too_long)),
+        _ => return,
+      }
+    }
+    panic!("session didn't disconnect peer");
+  }
+
+  #[tokio::test]
+  async fn should_disconnect_peer_sending_unsolicited_blocks() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let (_session_tx, mut socket) = connect(Arc::clone(&torrent)).await;
+
+    // blocks we never requested are dropped, until the peer sent too many of
+    // them
+    for _ in 0..100 {
+      let block = Message::Block {
+        piece_index: 0,
+        offset: 0,
+        data: vec![0; BLOCK_LEN as usize].into(),
+      };
+      if socket.send(block).await.is_err() {
+        return;
+      }
+      if let Ok(None | Some(Err(_))) =
+        timeout(Duration::from_millis(50), socket.next()).await
+      {
+        return;
+      }
+    }
+    panic!("session didn't disconnect peer");
+  }
+
+  #[tokio::test]
+  async fn should_request_allowed_fast_pieces_while_choked() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let (_session_tx, mut socket) = connect(torrent).await;
+
+    let mut pieces = Bitfield::repeat(false, PIECE_COUNT);
+    pieces.set(2, true);
+    pieces.resize(8, false);
+    socket.send(Message::Bitfield(pieces)).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::Interested);
+
+    // a piece the peer doesn't have can't be requested
+    socket
+      .send(Message::AllowedFast { piece_index: 1 })
+      .await
+      .unwrap();
+    socket
+      .send(Message::AllowedFast { piece_index: 2 })
+      .await
+      .unwrap();
+    let Message::Request(block_info) = next_msg(&mut socket).await else {
+      panic!("session didn't request allowed fast piece");
+    };
+    assert_eq!(block_info.piece_index, 2);
+  }
+
+  #[tokio::test]
+  async fn should_request_only_pieces_the_peer_has() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let mut common_pieces = Bitfield::repeat(true, PIECE_COUNT);
+    common_pieces.set(3, false);
+    // a peer that's not connected here has the common pieces too, making the
+    // last piece the rarest
+    torrent
+      .piece_picker
+      .write()
+      .await
+      .as_mut()
+      .unwrap()
+      .register_peer_pieces(&common_pieces);
+
+    // one peer has only the rarest piece, but doesn't unchoke us
+    let (_session_tx, mut rare_socket) = connect(Arc::clone(&torrent)).await;
+    let mut pieces = Bitfield::repeat(false, PIECE_COUNT);
+    pieces.set(3, true);
+    pieces.resize(8, false);
+    rare_socket.send(Message::Bitfield(pieces)).await.unwrap();
+    assert_eq!(next_msg(&mut rare_socket).await, Message::Interested);
+
+    // so the other, having the common pieces, is only asked for those
+    let (_session_tx, mut socket) = connect(torrent).await;
+    common_pieces.resize(8, false);
+    socket.send(Message::Bitfield(common_pieces)).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::Interested);
+    socket.send(Message::Unchoke).await.unwrap();
+    for _ in 0..SessionContext::START_REQUEST_QUEUE_LEN {
+      let Message::Request(block_info) = next_msg(&mut socket).await else {
+        panic!("session didn't request piece");
+      };
+      assert_ne!(block_info.piece_index, 3);
+    }
+  }
+
+  #[tokio::test]
+  async fn should_request_blocks_only_while_memory_is_within_budget() {
+    let (mut torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    Arc::get_mut(&mut torrent).unwrap().memory =
+      Arc::new(MemoryCounters::new(Some(BLOCK_LEN as u64)));
+    let charge = MemoryCharge::with_len(
+      &torrent.memory,
+      Buffer::WriteBuf,
+      BLOCK_LEN as u64 + 1,
+    );
+    let conf = SessionConf {
+      tick_interval: Duration::from_millis(50),
+      ..Default::default()
+    };
+    let (_session_tx, mut socket) = connect_with_conf(torrent, conf).await;
+
+    let mut pieces = Bitfield::repeat(true, PIECE_COUNT);
+    pieces.resize(8, false);
+    socket.send(Message::Bitfield(pieces)).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::Interested);
+    socket.send(Message::Unchoke).await.unwrap();
+    assert!(timeout(Duration::from_millis(200), socket.next())
+      .await
+      .is_err());
+
+    // once the buffers drain the idle session requests on its next tick
+    drop(charge);
+    assert!(matches!(next_msg(&mut socket).await, Message::Request(_)));
+  }
+
+  #[tokio::test]
+  async fn should_cancel_requests_of_completed_piece() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let (session_tx, mut socket) = connect(torrent).await;
+
+    let mut pieces = Bitfield::repeat(false, PIECE_COUNT);
+    pieces.set(2, true);
+    pieces.resize(8, false);
+    socket.send(Message::Bitfield(pieces)).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::Interested);
+    socket.send(Message::Unchoke).await.unwrap();
+    let Message::Request(block_info) = next_msg(&mut socket).await else {
+      panic!("session didn't request piece");
+    };
+    assert_eq!(block_info.piece_index, 2);
+
+    // the piece was completed with blocks from other peers, so each request
+    // still pending is cancelled
+    session_tx
+      .send(Command::PieceCompletion {
+        index: 2,
+        in_endgame: false,
+      })
+      .ok();
+    let mut requests = HashSet::from([block_info]);
+    while !requests.is_empty() {
+      match next_msg(&mut socket).await {
+        Message::Request(block_info) => {
+          requests.insert(block_info);
+        }
+        Message::Cancel(block_info) => assert!(requests.remove(&block_info)),
+        msg => panic!("unexpected message {:?}", msg),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn should_send_messages_of_queued_commands_together() {
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), RateLimiter::new(None));
+    let (session_tx, mut socket) = connect(Arc::clone(&torrent)).await;
+    torrent
+      .piece_picker
+      .write()
+      .await
+      .as_mut()
+      .unwrap()
+      .received_piece(0);
+    socket.send(Message::HaveNone).await.unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+
+    // the commands are handled in one go, and even those queued before a
+    // shutdown are sent before the connection is closed
+    for index in 1..PIECE_COUNT {
+      session_tx
+        .send(Command::PieceCompletion {
+          index,
+          in_endgame: false,
+        })
+        .ok();
+    }
+    session_tx.send(Command::Shutdown).ok();
+    for piece_index in 1..PIECE_COUNT {
+      assert_eq!(next_msg(&mut socket).await, Message::Have { piece_index });
+    }
+    assert!(timeout(Duration::from_millis(500), socket.next())
+      .await
+      .unwrap()
+      .is_none());
+  }
+
+  #[tokio::test]
+  async fn should_send_control_msgs_when_upload_limit_is_exhausted() {
+    // the upload limit is deep in debt, as if blocks saturated it
+    let mut upload_limiter = RateLimiter::new(Some(BLOCK_LEN as u64));
+    upload_limiter.consume(100 * BLOCK_LEN as u64);
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(None), upload_limiter);
+    let (session_tx, mut socket) = connect(torrent).await;
+
+    // the peer has all but the last piece
+    let mut pieces = Bitfield::repeat(true, PIECE_COUNT);
+    pieces.set(PIECE_COUNT - 1, false);
+    pieces.resize(8, false);
+    socket.send(Message::Bitfield(pieces)).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::Interested);
+
+    socket.send(Message::Unchoke).await.unwrap();
+    assert!(matches!(next_msg(&mut socket).await, Message::Request(_)));
+
+    session_tx
+      .send(Command::PieceCompletion {
+        index: PIECE_COUNT - 1,
+        in_endgame: false,
+      })
+      .ok();
+    loop {
+      match next_msg(&mut socket).await {
+        Message::Request(_) => continue,
+        msg => {
+          assert_eq!(
+            msg,
+            Message::Have {
+              piece_index: PIECE_COUNT - 1
+            }
           );
-          sink.send(Message::Cancel(*block)).await?;
+          break;
         }
       }
     }
-    Ok(())
+  }
+
+  #[tokio::test]
+  async fn should_charge_protocol_msgs_to_rate_limits() {
+    // the buckets hold a single byte and take seconds to pay off any debt
+    let (torrent, _channels) =
+      torrent_ctx(RateLimiter::new(Some(1)), RateLimiter::new(Some(1)));
+    let (_session_tx, mut socket) = connect(Arc::clone(&torrent)).await;
+
+    let mut pieces = Bitfield::repeat(true, PIECE_COUNT);
+    pieces.resize(8, false);
+    socket.send(Message::Bitfield(pieces)).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::Interested);
+
+    let now = Instant::now();
+    assert_eq!(torrent.download_limiter.available(now), Some(0));
+    assert_eq!(torrent.upload_limiter.available(now), Some(0));
+  }
+
+  #[tokio::test]
+  async fn should_hold_back_only_requests_when_download_limit_is_exhausted() {
+    // requests are what the download limit meters, so they wait for it to
+    // refill, but nothing else does
+    let mut download_limiter = RateLimiter::new(Some(BLOCK_LEN as u64));
+    download_limiter.consume(100 * BLOCK_LEN as u64);
+    let (torrent, _channels) =
+      torrent_ctx(download_limiter, RateLimiter::new(None));
+    let (session_tx, mut socket) = connect(torrent).await;
+
+    let mut pieces = Bitfield::repeat(true, PIECE_COUNT);
+    pieces.set(PIECE_COUNT - 1, false);
+    pieces.resize(8, false);
+    socket.send(Message::Bitfield(pieces)).await.unwrap();
+    assert_eq!(next_msg(&mut socket).await, Message::Interested);
+
+    socket.send(Message::Unchoke).await.unwrap();
+    session_tx
+      .send(Command::PieceCompletion {
+        index: PIECE_COUNT - 1,
+        in_endgame: false,
+      })
+      .ok();
+    assert_eq!(
+      next_msg(&mut socket).await,
+      Message::Have {
+        piece_index: PIECE_COUNT - 1
+      }
+    );
+    assert!(timeout(Duration::from_millis(100), socket.next())
+      .await
+      .is_err());
   }
 }
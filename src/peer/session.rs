@@ -1,9 +1,14 @@
 use std::time::{Duration, Instant};
 
-use crate::{avg::SlidingDurationAvg, counter::ThruputCounters, BLOCK_LEN};
+use serde_derive::Serialize;
+
+use crate::{
+  avg::SlidingDurationAvg, counter::ThruputCounters,
+  torrent::stats::EndgameStats, Bitfield, PieceIndex, BLOCK_LEN,
+};
 
 /// Contains the state of both sides of the connection.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub struct SessionState {
   /// The current state of the connection.
   pub connection: ConnectionState,
@@ -15,6 +20,9 @@ pub struct SessionState {
   pub is_peer_choked: bool,
   /// If peer is interested, they mean to download pieces that we have.
   pub is_peer_interested: bool,
+  /// If we're snubbed, peer hasn't sent us any of the blocks we requested
+  /// for a while, even though it unchoked us.
+  pub is_snubbed: bool,
 }
 
 impl Default for SessionState {
@@ -27,12 +35,14 @@ impl Default for SessionState {
       is_interested: false,
       is_peer_choked: true,
       is_peer_interested: false,
+      is_snubbed: false,
     }
   }
 }
 
 /// At any given time, a connection with a peer is in one the below states.
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ConnectionState {
   /// The peer connection has not yet been connected or it had been connected
   /// before but has been stopped.
@@ -82,6 +92,15 @@ pub struct SessionContext {
   /// - [`Self::counters`]
   pub changed: bool,
 
+  /// The counters as of the last update sent to the torrent, which only
+  /// adds what was transferred since, so that ticks without an update lose
+  /// no bytes.
+  pub reported_counters: ThruputCounters,
+
+  /// Whether the session ended because the peer broke the protocol or our
+  /// limits, which counts against the peer when it's reconnected.
+  pub has_misbehaved: bool,
+
   /// Whether the session is in slow start.
   ///
   /// To keep up with the transport layer's slow start algorithm
@@ -125,6 +144,10 @@ pub struct SessionContext {
 
   /// The last time some requests were sent to the peer.
   pub last_outgoing_request_time: Option<Instant>,
+  /// Since when the peer owes us blocks: when the oldest of our requests
+  /// still outstanding was made, or when the last block arrived if that's
+  /// later. Only meaningful while requests are outstanding.
+  pub outstanding_since: Option<Instant>,
   /// Updated with the time of receipt of the most recently received requested
   /// block.
   pub last_incoming_block_time: Option<Instant>,
@@ -140,8 +163,28 @@ pub struct SessionContext {
   pub request_time_out: bool,
   pub timed_out_request_count: usize,
 
+  /// The number of requests that were dropped instead of sent because the
+  /// same block was already requested from the peer.
+  pub duplicate_request_count: u64,
+  /// The number of blocks the peer sent that we didn't have outstanding
+  /// requests for and that had already been downloaded.
+  pub duplicate_block_count: u64,
+  /// The cost of the duplicate requests made in endgame so far.
+  pub endgame: EndgameStats,
+  /// The number of the peer's requests that were too long, too many, or
+  /// duplicates.
+  pub request_violation_count: usize,
+  /// The number of blocks the peer sent that we never requested.
+  pub unsolicited_block_count: usize,
+
   /// The time the BitTorrent connection was established (i.e. after handshaking).
   pub connected_time: Option<Instant>,
+  /// The last time any message was received from the peer, used to detect
+  /// inactive peers.
+  pub last_incoming_msg_time: Option<Instant>,
+  /// The last time any message was sent to the peer, used to determine when
+  /// to send a keep-alive.
+  pub last_outgoing_msg_time: Option<Instant>,
 
   /// The log header to use for logging.
   pub log_target: String,
@@ -156,7 +199,7 @@ impl SessionContext {
 
   /// The target request queue size is set to this value once we are able to
   /// start downloading.
-  const START_REQUEST_QUEUE_LEN: usize = 4;
+  pub const START_REQUEST_QUEUE_LEN: usize = 4;
 
   /// The smallest timeout value we can give a peer. Very fast peers will have
   /// an average round-trip-times, so a slight deviation would punish them
@@ -184,6 +227,35 @@ impl SessionContext {
     self.changed = true;
   }
 
+  /// Returns whether the peer hasn't sent us a block for the given time
+  /// since it started owing us blocks, i.e. since we made the oldest of our
+  /// outstanding requests or it last sent a block.
+  ///
+  /// This must only be asked while requests are outstanding.
+  pub fn is_snubbing(&self, now: Instant, snub_timeout: Duration) -> bool {
+    self
+      .outstanding_since
+      .is_some_and(|t| now.saturating_duration_since(t) >= snub_timeout)
+  }
+
+  /// Records that requests were made, which the peer owes us blocks for
+  /// from now on unless it already owed us some.
+  pub fn register_requests(&mut self, had_outstanding: bool, now: Instant) {
+    self.last_outgoing_request_time = Some(now);
+    if !had_outstanding {
+      self.outstanding_since = Some(now);
+    }
+  }
+
+  /// Updates state to reflect that peer is snubbing us.
+  pub fn register_snub(&mut self) {
+    // like after a request timeout only a single request is kept
+    // outstanding, but until the peer sends us a block
+    self.target_request_queue_len = Some(1);
+    self.in_slow_start = false;
+    self.update_state(|state| state.is_snubbed = true);
+  }
+
   /// Prepares for requesting blocks.
   ///
   /// This should be called after being unchoked and becoming interested.
@@ -244,6 +316,13 @@ impl SessionContext {
 
     self.counters.payload.down += block_len as u64;
     self.last_incoming_block_time = Some(now);
+    self.outstanding_since = Some(now);
+
+    // the peer recovered from snubbing us, and the queue grows back with
+    // the download rate
+    if self.state.is_snubbed {
+      self.update_state(|state| state.is_snubbed = false);
+    }
 
     // if we're in slow-start mode, we need to increase the target_queue_size
     // every time a block is received.
@@ -262,6 +341,30 @@ impl SessionContext {
     self.changed = true;
   }
 
+  pub fn record_duplicate_request(&mut self) {
+    self.duplicate_request_count += 1;
+    self.changed = true;
+  }
+
+  pub fn record_duplicate_block(&mut self) {
+    self.duplicate_block_count += 1;
+    self.changed = true;
+  }
+
+  /// Records a request of the peer that broke our limits, returning whether
+  /// the peer did so too many times to stay connected.
+  pub fn record_request_violation(&mut self) -> bool {
+    self.request_violation_count += 1;
+    self.request_violation_count >= MAX_REQUEST_VIOLATION_COUNT
+  }
+
+  /// Records a block the peer sent without our requesting it, returning
+  /// whether the peer did so too many times to stay connected.
+  pub fn record_unsolicited_block(&mut self) -> bool {
+    self.unsolicited_block_count += 1;
+    self.unsolicited_block_count >= MAX_UNSOLICITED_BLOCK_COUNT
+  }
+
   pub fn update_upload_stats(&mut self, block_len: u32) {
     self.last_outgoing_block_time = Some(Instant::now());
     self.counters.payload.up += block_len as u64;
@@ -269,39 +372,46 @@ impl SessionContext {
     self.changed = true;
   }
 
+  /// Records that the torrent was sent the current state and counters.
+  pub fn mark_reported(&mut self) {
+    self.reported_counters = self.counters;
+    self.changed = false;
+  }
+
   /// Updates various statistics and session state.
   ///
-  /// This should be called every second.
-  pub fn tick(&mut self) {
-    self.maybe_exit_slow_start();
+  /// This should be called at the end of each tick, which lasts the given
+  /// time.
+  pub fn tick(&mut self, tick_interval: Duration) {
+    self.maybe_exit_slow_start(tick_interval);
 
     // This has to be after `maybe_exit_slow_start`
     // and before `update_target_request_queue_len`,
     // as the first relies on the round being
     // concluded (having this round's download accounted for in the download rate).
-    self.counters.reset();
+    self.counters.reset_over(tick_interval);
 
-    // if we're still in the timeout, we don't want to increase the
-    // target request queue size.
-    if !self.request_time_out {
+    // if we're still in the timeout or snubbed, we don't want to increase
+    // the target request queue size.
+    if !self.request_time_out && !self.state.is_snubbed {
       self.update_target_request_queue_len();
     }
-
-    // rest the dirty flag
-    self.changed = true;
   }
 
   /// Checks if we need to exit slow start.
   ///
   /// We leave slow start if the download rate has not increased significantly
   /// since the last round.
-  fn maybe_exit_slow_start(&mut self) {
+  fn maybe_exit_slow_start(&mut self, tick_interval: Duration) {
+    // the round's download is compared to the per second rate
+    let round_rate = (self.counters.payload.down.round() as f64
+      / tick_interval.as_secs_f64()) as u64;
     // this only makes sense if we're not choked
     if !self.state.is_choked
       && self.in_slow_start
       && self.target_request_queue_len.is_some()
-      && self.counters.payload.down.round() > 0
-      && self.counters.payload.down.round() + Self::SLOW_START_ERROR_MARGINS
+      && round_rate > 0
+      && round_rate + Self::SLOW_START_ERROR_MARGINS
         < self.counters.payload.down.avg()
     {
       self.in_slow_start = false;
@@ -337,6 +447,82 @@ impl SessionContext {
   }
 }
 
+/// Detects peers that request pieces in order, as streaming clients do, so
+/// that the next piece can be read from disk before they request it.
+///
+/// Peers requesting pieces in random order are unaffected, as they very
+/// rarely move on to the next piece several times in a row.
+#[derive(Debug, Default)]
+pub struct SequentialDetector {
+  /// The piece of the peer's last request.
+  last_piece: Option<PieceIndex>,
+  /// The number of consecutive times the peer moved on to the next piece.
+  streak: usize,
+}
+
+impl SequentialDetector {
+  /// After moving on to the next piece this many times in a row, the peer is
+  /// considered to be requesting pieces sequentially.
+  const MIN_STREAK: usize = 2;
+
+  /// Records a request for a block in the given piece.
+  ///
+  /// Returns the piece to read ahead if the peer requesting sequentially
+  /// just moved on to a new piece.
+  pub fn record_request(
+    &mut self,
+    piece_index: PieceIndex,
+  ) -> Option<PieceIndex> {
+    match self.last_piece {
+      // further blocks of the same piece
+      Some(last) if last == piece_index => return None,
+      Some(last) if last + 1 == piece_index => self.streak += 1,
+      _ => self.streak = 0,
+    }
+    self.last_piece = Some(piece_index);
+
+    (self.streak >= Self::MIN_STREAK).then_some(piece_index + 1)
+  }
+}
+
+/// The most requests beyond our limits a peer may make before it's
+/// disconnected. A few may be honest mistakes or races, e.g. requesting a block
+/// again right after cancelling it, but a flood is not.
+const MAX_REQUEST_VIOLATION_COUNT: usize = 20;
+
+/// The most blocks a peer may send without our requesting them before it's
+/// disconnected. A few may be late replies to requests freed long ago, but
+/// a peer that keeps sending them wastes our bandwidth.
+const MAX_UNSOLICITED_BLOCK_COUNT: usize = 20;
+
+/// The most pieces withheld from a lazy bitfield.
+const MAX_WITHHELD_PIECE_COUNT: usize = 50;
+
+/// Clears some of the pieces we have from the bitfield, for a lazy bitfield,
+/// returning the withheld pieces, which are to be announced with have
+/// messages right after the bitfield.
+///
+/// This way the bitfield doesn't give away that we're a seed to ISPs that
+/// throttle seeding connections by inspecting them. About a tenth of the
+/// pieces are withheld, at least one and at most
+/// [`MAX_WITHHELD_PIECE_COUNT`].
+pub fn withhold_pieces(pieces: &mut Bitfield) -> Vec<PieceIndex> {
+  let own: Vec<_> = pieces.iter_ones().collect();
+  let count = (pieces.len() / 10)
+    .clamp(1, MAX_WITHHELD_PIECE_COUNT)
+    .min(own.len());
+  let mut withheld: Vec<_> =
+    rand::seq::index::sample(&mut rand::thread_rng(), own.len(), count)
+      .into_iter()
+      .map(|i| own[i])
+      .collect();
+  withheld.sort_unstable();
+  for &index in &withheld {
+    pieces.set(index, false);
+  }
+  withheld
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -354,6 +540,54 @@ mod tests {
     assert!(s.in_slow_start);
   }
 
+  #[test]
+  fn should_snub_and_recover() {
+    let mut s = SessionContext::default();
+    let timeout = Duration::from_secs(60);
+
+    s.state.is_interested = true;
+    s.state.is_choked = false;
+    s.prepare_for_download();
+
+    // the time before the first request doesn't count
+    let start = Instant::now() + timeout;
+    s.register_requests(false, start);
+    assert!(!s.is_snubbing(start + timeout / 2, timeout));
+    // nor does making more requests reset it
+    s.register_requests(true, start + timeout / 2);
+    assert!(s.is_snubbing(start + timeout, timeout));
+
+    s.register_snub();
+    assert!(s.state.is_snubbed);
+    assert_eq!(s.target_request_queue_len, Some(1));
+    // the queue doesn't grow while snubbed
+    s.tick(Duration::from_secs(1));
+    assert_eq!(s.target_request_queue_len, Some(1));
+
+    s.changed = false;
+    s.update_download_stats(BLOCK_LEN);
+    assert!(!s.state.is_snubbed);
+    assert!(s.changed);
+    let last_block_time = s.last_incoming_block_time.unwrap();
+    assert!(!s.is_snubbing(last_block_time + timeout / 2, timeout));
+  }
+
+  #[test]
+  fn should_withhold_own_pieces() {
+    let mut pieces = Bitfield::repeat(true, 100);
+    let withheld = withhold_pieces(&mut pieces);
+    assert_eq!(withheld.len(), 10);
+    assert_eq!(pieces.count_ones(), 90);
+    assert!(withheld.iter().all(|&index| !pieces[index]));
+
+    // only pieces we have are withheld
+    let mut pieces = Bitfield::repeat(false, 100);
+    pieces.set(42, true);
+    assert_eq!(withhold_pieces(&mut pieces), vec![42]);
+    assert!(pieces.not_any());
+    assert!(withhold_pieces(&mut pieces).is_empty());
+  }
+
   #[test]
   fn should_exit_slow_start() {
     let mut s = SessionContext::default();
@@ -366,7 +600,7 @@ mod tests {
     // rate increasing
     s.counters.payload.down += 10 * BLOCK_LEN as u64;
     // should not exit slow start
-    s.maybe_exit_slow_start();
+    s.maybe_exit_slow_start(Duration::from_secs(1));
     assert!(s.in_slow_start);
 
     // reset counter for next round
@@ -377,7 +611,7 @@ mod tests {
     // rate still increasing
     s.counters.payload.down += 10 * BLOCK_LEN as u64;
     // should not exit slow start yet
-    s.maybe_exit_slow_start();
+    s.maybe_exit_slow_start(Duration::from_secs(1));
     assert!(s.in_slow_start);
 
     // reset counter for next round
@@ -390,7 +624,7 @@ mod tests {
     // this round's increase is much less than that of the previous round,
     // should exit slow start
     s.counters.payload.down += 2 * BLOCK_LEN as u64 + 9000;
-    s.maybe_exit_slow_start();
+    s.maybe_exit_slow_start(Duration::from_secs(1));
     assert!(!s.in_slow_start);
   }
 
@@ -459,4 +693,31 @@ mod tests {
     // download stat should be increased
     assert_eq!(s.counters.payload.down.round(), BLOCK_LEN as u64);
   }
+
+  #[test]
+  fn should_detect_sequential_requests() {
+    let mut d = SequentialDetector::default();
+
+    assert_eq!(d.record_request(0), None);
+    assert_eq!(d.record_request(0), None);
+    assert_eq!(d.record_request(1), None);
+    // the second move to the next piece starts the read ahead
+    assert_eq!(d.record_request(2), Some(3));
+    // but only once per piece
+    assert_eq!(d.record_request(2), None);
+    assert_eq!(d.record_request(3), Some(4));
+
+    // jumping elsewhere resets the detection
+    assert_eq!(d.record_request(10), None);
+    assert_eq!(d.record_request(11), None);
+    assert_eq!(d.record_request(12), Some(13));
+  }
+
+  #[test]
+  fn should_not_read_ahead_for_random_requests() {
+    let mut d = SequentialDetector::default();
+    for piece_index in [5, 2, 9, 10, 4, 7, 8, 1, 3] {
+      assert_eq!(d.record_request(piece_index), None);
+    }
+  }
 }
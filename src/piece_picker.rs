@@ -1,4 +1,8 @@
-use crate::{Bitfield, PieceIndex};
+use std::{cmp::Ordering, time::Instant};
+
+use rand::Rng;
+
+use crate::{torrent::FilePriority, Bitfield, PieceIndex};
 
 pub struct PiecePicker {
   /// Represents the pieces that we have downloaded.
@@ -11,7 +15,8 @@ pub struct PiecePicker {
   /// swarm in this vector.
   ///
   /// The vector is pre-allocated to the number of pieces
-  /// in the torrent.
+  /// in the torrent, unless we have all pieces, in which case there is
+  /// nothing to pick and the vector is left empty.
   pieces: Vec<Piece>,
   /// A cache for the number of pieces we haven't received
   /// yet (but may have picked).
@@ -20,6 +25,10 @@ pub struct PiecePicker {
   free_count: usize,
   /// current peer session available to be used(a cache count of [`Torrent::peers`]).
   peer_count: usize,
+  /// Only the pieces before this index are picked, which is less than the
+  /// number of pieces if only the start of the torrent is downloaded (e.g.
+  /// when sampling it).
+  piece_limit: usize,
 }
 
 /// Metadata about a piece relevant for the piece picker.
@@ -37,20 +46,47 @@ pub struct Piece {
   /// wouldn't be able to download multiple pieces simultaneously (an important
   /// optimization step).
   pub is_pending: bool,
+  /// The highest priority of the files that overlap with this piece. Pieces
+  /// of only skipped files are not picked.
+  pub priority: FilePriority,
+  /// When the piece is needed by, e.g. for streaming. Pieces with a deadline
+  /// are picked before any other, the most urgent first.
+  pub deadline: Option<Instant>,
+}
+
+impl Piece {
+  /// Returns the rank of the piece when picking, the lowest rank being
+  /// picked first: by urgency, then priority, then rarity.
+  fn rank(&self) -> (u8, Option<Instant>, usize) {
+    let (urgency, deadline) = match (self.deadline, self.priority) {
+      (Some(deadline), _) => (0, Some(deadline)),
+      (None, FilePriority::High) => (1, None),
+      (None, FilePriority::Low) => (3, None),
+      (None, _) => (2, None),
+    };
+    (urgency, deadline, self.frequency)
+  }
 }
 
 impl PiecePicker {
   /// Creates a new piece picker with the given own_pieces we already have.
+  ///
+  /// If we have all pieces, no metadata about the swarm's pieces is
+  /// allocated.
   pub fn new(own_pieces: Bitfield) -> Self {
-    let mut pieces = Vec::new();
-    pieces.resize_with(own_pieces.len(), Piece::default);
     let missing_count = own_pieces.count_zeros();
+    let mut pieces = Vec::new();
+    if missing_count > 0 {
+      pieces.resize_with(own_pieces.len(), Piece::default);
+    }
+    let piece_limit = own_pieces.len();
     PiecePicker {
       own_pieces,
       pieces,
       missing_count,
       free_count: missing_count,
       peer_count: 0,
+      piece_limit,
     }
   }
 
@@ -64,6 +100,64 @@ impl PiecePicker {
     self.peer_count -= 1;
   }
 
+  /// Restricts picking to the pieces before the given index, or lifts the
+  /// restriction if `None`.
+  pub fn set_piece_limit(&mut self, limit: Option<usize>) {
+    let piece_count = self.own_pieces.len();
+    self.piece_limit = limit.map_or(piece_count, |l| l.min(piece_count));
+  }
+
+  /// Sets the priority of each piece, derived from the priorities of the
+  /// files overlapping with it.
+  pub fn set_piece_priorities(
+    &mut self,
+    priorities: impl IntoIterator<Item = FilePriority>,
+  ) {
+    // a seed has nothing left to prioritize
+    for (piece, priority) in self.pieces.iter_mut().zip(priorities) {
+      piece.priority = priority;
+    }
+  }
+
+  /// Sets when the piece is needed by, so that it's picked before pieces
+  /// without a deadline. Pieces we already have are ignored.
+  pub fn set_deadline(&mut self, index: PieceIndex, deadline: Instant) {
+    if !self.own_pieces[index] {
+      self.pieces[index].deadline = Some(deadline);
+    }
+  }
+
+  /// Returns whether the peer has any piece that we want and may pick.
+  pub fn is_interested_in(&self, peer_pieces: &Bitfield) -> bool {
+    if self.is_seed() {
+      return false;
+    }
+    peer_pieces
+      .iter_ones()
+      .take_while(|&index| index < self.piece_limit)
+      .any(|index| {
+        !self.own_pieces[index]
+          && self.pieces[index].priority != FilePriority::Skip
+      })
+  }
+
+  /// Carries over the piece limit, priorities and deadlines of the piece
+  /// picker replaced by this one.
+  pub fn inherit_settings(&mut self, prev: &PiecePicker) {
+    self.piece_limit = prev.piece_limit.min(self.own_pieces.len());
+    if self.is_seed() || prev.is_seed() {
+      return;
+    }
+    for (index, (piece, prev)) in
+      self.pieces.iter_mut().zip(&prev.pieces).enumerate()
+    {
+      piece.priority = prev.priority;
+      if !self.own_pieces[index] {
+        piece.deadline = prev.deadline;
+      }
+    }
+  }
+
   /// Returns an immutable reference to a bitfield of pieces we own.
   pub fn own_pieces(&self) -> &Bitfield {
     &self.own_pieces
@@ -75,35 +169,109 @@ impl PiecePicker {
     self.missing_count
   }
 
+  /// Returns true if we have all pieces, in which case the piece picker no
+  /// longer tracks the swarm's pieces.
+  pub fn is_seed(&self) -> bool {
+    self.missing_count == 0
+  }
+
+  /// Returns true if we have all pieces but those of only skipped files, and
+  /// not all pieces, i.e. if we're a partial seed (BEP 21).
+  ///
+  /// A torrent whose files are all skipped isn't, as it has nothing to seed.
+  pub fn is_partial_seed(&self) -> bool {
+    !self.is_seed()
+      && self
+        .own_pieces
+        .iter_zeros()
+        .all(|index| self.pieces[index].priority == FilePriority::Skip)
+      && self
+        .pieces
+        .iter()
+        .any(|piece| piece.priority != FilePriority::Skip)
+  }
+
   /// Returns true if all pieces have been picked (whether pending or received).
   pub fn all_pieces_picked(&self) -> bool {
     self.free_count == 0
   }
 
-  /// Returns the first piece that we don't yet have and isn't already being
+  /// Returns a piece that we don't yet have and isn't already being
   /// downloaded, or None, if no piece can be picked at this time.
   ///
+  /// Pieces with a deadline are picked first, the most urgent first, then
+  /// the pieces of high priority files, and then the rest. Among pieces of
+  /// the same rank the rarest in the swarm is picked, so that pieces few
+  /// peers have are spread before those peers leave, and ties are broken
+  /// randomly, so that peers downloading from the same sources don't all
+  /// pick the same pieces.
   pub fn pick_piece(&mut self) -> Option<PieceIndex> {
+    self.pick_piece_among(|_| true)
+  }
+
+  /// Picks a piece like [`Self::pick_piece`], but only among the pieces for
+  /// which the predicate holds, e.g. those that a peer choking us allows us
+  /// to download (BEP 6).
+  pub fn pick_piece_among(
+    &mut self,
+    is_allowed: impl Fn(PieceIndex) -> bool,
+  ) -> Option<PieceIndex> {
     log::trace!("Picking next piece");
 
-    for index in 0..self.own_pieces.len() {
-      // only consider this piece if we don't have it and if we are not
-      // already downloading it (whether it's not pending)
+    if self.is_seed() {
+      return None;
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut picked = None;
+    // the number of pieces tied with the picked one so far, of which each
+    // replaces the picked one with an equal chance
+    let mut tie_count = 0;
+    for index in 0..self.piece_limit {
+      // only consider this piece if we don't have it, if we are not
+      // already downloading it (whether it's not pending), and if it's
+      // wanted
       debug_assert!(index < self.pieces.len());
-      let piece = &mut self.pieces[index];
-      if !self.own_pieces[index] && piece.frequency > 0 && !piece.is_pending {
+      let piece = &self.pieces[index];
+      if !is_allowed(index)
+        || self.own_pieces[index]
+        || piece.frequency == 0
+        || piece.is_pending
+        || piece.priority == FilePriority::Skip
+      {
+        continue;
+      }
+      let rank = piece.rank();
+      match picked.map(|(_, picked_rank)| rank.cmp(&picked_rank)) {
+        None | Some(Ordering::Less) => {
+          picked = Some((index, rank));
+          tie_count = 1;
+        }
+        Some(Ordering::Equal) => {
+          tie_count += 1;
+          if rng.gen_range(0..tie_count) == 0 {
+            picked = Some((index, rank));
+          }
+        }
+        Some(Ordering::Greater) => {}
+      }
+    }
+
+    match picked {
+      Some((index, _)) => {
         // set pending flag on piece so that this piece is not picked
         // again (see note on field)
-        piece.is_pending = true;
+        self.pieces[index].is_pending = true;
         self.free_count -= 1;
         log::trace!("Pending piece {}", index);
-        return Some(index);
+        Some(index)
+      }
+      None => {
+        // no piece could be picked
+        log::trace!("Could not pick piece");
+        None
       }
     }
-
-    // no piece could be picked
-    log::trace!("Could not pick piece");
-    None
   }
 
   /// Here is the old version:
@@ -125,7 +293,11 @@ impl PiecePicker {
   ) -> Option<PieceIndex> {
     log::trace!("Picking next piece");
 
-    let max_piece = self.own_pieces.len();
+    if self.is_seed() {
+      return None;
+    }
+
+    let max_piece = self.piece_limit;
 
     // x is the square root of the number of peers minus 1.
     let x = ((self.peer_count as f64).sqrt() as usize - 1).max(1);
@@ -139,7 +311,11 @@ impl PiecePicker {
 
     for index in 0..max_piece {
       let piece = self.pieces[index];
-      if !self.own_pieces[index] && piece.frequency > 0 && !piece.is_pending {
+      if !self.own_pieces[index]
+        && piece.frequency > 0
+        && !piece.is_pending
+        && piece.priority != FilePriority::Skip
+      {
         gap += 1;
         if peer_field[index] {
           let piece_rareness = self.pieces[index].frequency;
@@ -184,6 +360,10 @@ impl PiecePicker {
       "peer's bitfield must be the same length as ours"
     );
 
+    if self.is_seed() {
+      return false;
+    }
+
     let mut interested = false;
     for (index, (have_piece, peer_has_piece)) in
       self.own_pieces.iter().zip(pieces.iter()).enumerate()
@@ -191,9 +371,12 @@ impl PiecePicker {
       // increase frequency count for this piece if peer has it
       if *peer_has_piece {
         self.pieces[index].frequency += 1;
-        // if we don't have at least one piece peer has, we're
-        // interested
-        if !have_piece {
+        // if we don't have at least one piece peer has that we may pick,
+        // we're interested
+        if !have_piece
+          && index < self.piece_limit
+          && self.pieces[index].priority != FilePriority::Skip
+        {
           interested = true;
         }
       }
@@ -202,6 +385,20 @@ impl PiecePicker {
     interested
   }
 
+  /// Unregisters the availability of the pieces of a peer that left.
+  ///
+  /// This should be called with the pieces previously registered through
+  /// [`Self::register_peer_pieces`] and [`Self::register_peer_piece`].
+  pub fn unregister_peer_pieces(&mut self, pieces: &Bitfield) {
+    if self.is_seed() {
+      return;
+    }
+    for index in pieces.iter_ones() {
+      let piece = &mut self.pieces[index];
+      piece.frequency = piece.frequency.saturating_sub(1);
+    }
+  }
+
   /// Increments the availability of a piece.
   ///
   /// This should be called when a peer sends us a `have` message of a new
@@ -217,6 +414,10 @@ impl PiecePicker {
     let is_interested =
       self.own_pieces.get(index).expect("invalid piece index");
 
+    if self.is_seed() {
+      return false;
+    }
+
     self.pieces[index].frequency += 1;
     *is_interested
   }
@@ -237,6 +438,7 @@ impl PiecePicker {
 
     // register owned piece
     *have_piece = true;
+    drop(have_piece);
     self.missing_count -= 1;
 
     // This is an edge-case and shouldn't normally happen, but we guard
@@ -246,6 +448,7 @@ impl PiecePicker {
     // we need to decrease the free piece count here, as it is normally done
     // in the `pick_piece` method.
     let piece = &mut self.pieces[index];
+    piece.deadline = None;
     if !piece.is_pending {
       self.free_count -= 1;
       // also set that this piece is no longer pending (even though we
@@ -254,6 +457,11 @@ impl PiecePicker {
       // pick the piece again)
       piece.is_pending = false;
     }
+
+    // the swarm's pieces are no longer needed once we have all of them
+    if self.is_seed() {
+      self.pieces = Vec::new();
+    }
   }
 
   pub fn pieces(&self) -> &[Piece] {
@@ -280,12 +488,8 @@ mod tests {
     let mut picked = HashSet::with_capacity(piece_count);
 
     // pick all pieces one by one
-    for index in 0..piece_count {
-      let pick = piece_picker.pick_piece();
-      // for now we assert that we pick pieces in sequential order, but
-      // later, when we add different algorithms, this line has to change
-      assert_eq!(pick, Some(index));
-      let pick = pick.unwrap();
+    for _ in 0..piece_count {
+      let pick = piece_picker.pick_piece().unwrap();
       // assert that this piece hasn't been picked before
       assert!(!picked.contains(&pick));
       // mark piece as picked
@@ -358,20 +562,21 @@ mod tests {
     assert_eq!(piece_picker.free_count, piece_count);
 
     // picked and received 2 pieces
-    for i in 0..2 {
-      assert!(piece_picker.pick_piece().is_some());
-      piece_picker.received_piece(i);
+    for _ in 0..2 {
+      let index = piece_picker.pick_piece().unwrap();
+      piece_picker.received_piece(index);
     }
     assert_eq!(piece_picker.free_count, 13);
 
     // pick 3 pieces
-    for _ in 0..3 {
-      assert!(piece_picker.pick_piece().is_some());
-    }
+    let picks: Vec<_> = std::iter::from_fn(|| piece_picker.pick_piece())
+      .take(3)
+      .collect();
+    assert_eq!(picks.len(), 3);
     assert_eq!(piece_picker.free_count, 10);
 
     // received 1 of the above picked pieces: shouldn't change outcome
-    piece_picker.received_piece(2);
+    piece_picker.received_piece(picks[1]);
     assert_eq!(piece_picker.free_count, 10);
 
     // pick rest of the pieces
@@ -431,6 +636,154 @@ mod tests {
     assert!(!piece_picker.register_peer_pieces(&available_pieces));
   }
 
+  /// Tests that only the pieces within the limit are picked and make us
+  /// interested.
+  #[test]
+  fn should_pick_pieces_within_limit() {
+    let piece_count = 15;
+    let mut piece_picker = PiecePicker::empty(piece_count);
+    piece_picker.set_piece_limit(Some(3));
+
+    let mut available_pieces = Bitfield::repeat(false, piece_count);
+    available_pieces.set(5, true);
+    assert!(!piece_picker.register_peer_pieces(&available_pieces));
+    available_pieces.set(1, true);
+    assert!(piece_picker.register_peer_pieces(&available_pieces));
+
+    let available_pieces = Bitfield::repeat(true, piece_count);
+    piece_picker.register_peer_pieces(&available_pieces);
+    let picks: HashSet<_> = std::iter::from_fn(|| piece_picker.pick_piece())
+      .take(piece_count)
+      .collect();
+    assert_eq!(picks, HashSet::from([0, 1, 2]));
+
+    // lifting the limit makes the rest of the pieces pickable
+    piece_picker.set_piece_limit(None);
+    assert!(piece_picker.pick_piece().is_some_and(|index| index >= 3));
+  }
+
+  /// Tests that pieces with a deadline are picked first, then pieces of high
+  /// priority, and that skipped pieces are never picked.
+  #[test]
+  fn should_pick_pieces_by_priority() {
+    let piece_count = 7;
+    let mut piece_picker = PiecePicker::empty(piece_count);
+    piece_picker.set_piece_priorities([
+      FilePriority::Skip,
+      FilePriority::Normal,
+      FilePriority::High,
+      FilePriority::Normal,
+      FilePriority::Normal,
+      FilePriority::Skip,
+      FilePriority::Low,
+    ]);
+    let now = Instant::now();
+    piece_picker.set_deadline(4, now + std::time::Duration::from_secs(2));
+    piece_picker.set_deadline(3, now + std::time::Duration::from_secs(1));
+
+    // skipped pieces don't make us interested
+    let mut available_pieces = Bitfield::repeat(false, piece_count);
+    available_pieces.set(5, true);
+    assert!(!piece_picker.register_peer_pieces(&available_pieces));
+
+    piece_picker.register_peer_pieces(&Bitfield::repeat(true, piece_count));
+    let picks: Vec<_> = std::iter::from_fn(|| piece_picker.pick_piece())
+      .take(piece_count)
+      .collect();
+    assert_eq!(picks, vec![3, 4, 2, 1, 6]);
+
+    // once all pieces but the skipped ones are downloaded, we're a partial
+    // seed
+    assert!(!piece_picker.is_partial_seed());
+    for index in picks {
+      piece_picker.received_piece(index);
+    }
+    assert!(piece_picker.is_partial_seed());
+    piece_picker.received_piece(0);
+    piece_picker.received_piece(5);
+    assert!(!piece_picker.is_partial_seed());
+
+    // nor are we one if all files are skipped, as we want no pieces at all
+    let mut piece_picker = PiecePicker::empty(piece_count);
+    piece_picker.set_piece_priorities(vec![FilePriority::Skip; piece_count]);
+    assert!(!piece_picker.is_partial_seed());
+  }
+
+  /// Tests that only the allowed pieces are picked when picking among some.
+  #[test]
+  fn should_pick_among_allowed_pieces() {
+    let piece_count = 15;
+    let mut piece_picker = PiecePicker::empty(piece_count);
+    piece_picker.register_peer_pieces(&Bitfield::repeat(true, piece_count));
+
+    let allowed = HashSet::from([7, 3]);
+    let mut pick = || piece_picker.pick_piece_among(|i| allowed.contains(&i));
+    let picks = HashSet::from([pick().unwrap(), pick().unwrap()]);
+    assert_eq!(picks, allowed);
+    assert_eq!(pick(), None);
+
+    // the allowed pieces are no longer free to pick
+    let pick = piece_picker.pick_piece().unwrap();
+    assert!(!allowed.contains(&pick));
+  }
+
+  /// Tests that the rarest pieces are picked first, and that pieces become
+  /// rarer as the peers having them leave.
+  #[test]
+  fn should_pick_rarest_pieces_first() {
+    let piece_count = 6;
+    let mut piece_picker = PiecePicker::empty(piece_count);
+    let all_pieces = Bitfield::repeat(true, piece_count);
+    let mut some_pieces = Bitfield::repeat(false, piece_count);
+    some_pieces.set(0, true);
+    some_pieces.set(1, true);
+    piece_picker.register_peer_pieces(&all_pieces);
+    piece_picker.register_peer_pieces(&all_pieces);
+    piece_picker.register_peer_pieces(&some_pieces);
+    piece_picker.register_peer_piece(2);
+    piece_picker.register_peer_piece(5);
+
+    // the pieces only two peers have, in any order, then the rest
+    let picks: Vec<_> = std::iter::from_fn(|| piece_picker.pick_piece())
+      .take(2)
+      .collect();
+    assert_eq!(HashSet::from_iter(picks), HashSet::from([3, 4]));
+
+    // a peer with all pieces leaving and another with 0 and 1 joining
+    // leaves 0 and 1 with 3 peers, and 2 and 5 with 2
+    piece_picker.unregister_peer_pieces(&all_pieces);
+    piece_picker.register_peer_pieces(&some_pieces);
+    let pick = piece_picker.pick_piece().unwrap();
+    assert!([2, 5].contains(&pick));
+  }
+
+  /// Tests that a piece picker of a complete torrent doesn't track the
+  /// swarm's pieces and never picks any.
+  #[test]
+  fn should_not_track_pieces_as_seed() {
+    let piece_count = 15;
+    let mut piece_picker =
+      PiecePicker::new(Bitfield::repeat(true, piece_count));
+    assert!(piece_picker.is_seed());
+    assert!(piece_picker.pieces().is_empty());
+
+    let available_pieces = Bitfield::repeat(true, piece_count);
+    assert!(!piece_picker.register_peer_pieces(&available_pieces));
+    assert!(!piece_picker.register_peer_piece(3));
+    assert_eq!(piece_picker.pick_piece(), None);
+    assert_eq!(piece_picker.pick_piece_right_get(&available_pieces), None);
+
+    // completing the download also drops the swarm's pieces
+    let mut piece_picker = PiecePicker::empty(piece_count);
+    piece_picker.register_peer_pieces(&available_pieces);
+    for index in 0..piece_count {
+      assert!(!piece_picker.is_seed());
+      piece_picker.received_piece(index);
+    }
+    assert!(piece_picker.is_seed());
+    assert!(piece_picker.pieces().is_empty());
+  }
+
   impl PiecePicker {
     fn empty(piece_count: usize) -> Self {
       Self::new(Bitfield::repeat(false, piece_count))
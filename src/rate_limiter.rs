@@ -0,0 +1,365 @@
+//! Token buckets that cap the transfer rate of a torrent, shared by all of
+//! its peer sessions, and of the whole engine, shared by all torrents.
+//!
+//! Only block payload is held back. The headers of all messages count
+//! against the limits too, but control messages (have, interested, request,
+//! cancel, keep-alive, etc) are never held back, as otherwise an exhausted
+//! upload limit would delay our requests and so collapse the download rate
+//! too. Upload is limited by holding back the blocks we send, and download
+//! by holding back our requests, as those are what make peers send us
+//! blocks.
+//!
+//! Torrents share the engine's limits by priority: lower priority torrents
+//! must leave part of the engine's bucket for higher priority ones, the same
+//! way they leave connection slots free, so when bandwidth is contended the
+//! more important torrents get most of it.
+
+use std::{
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Instant,
+};
+
+use crate::conf::Priority;
+
+/// Limits the number of bytes transferred per second.
+///
+/// The bucket holds at most a second's worth of bytes, which is also the
+/// largest burst allowed. A transfer may take more than what's left in the
+/// bucket, in which case the bucket goes into debt that is paid off before
+/// anything else may be transferred. This way transfers larger than the rate
+/// itself (e.g. a whole block with a very low limit) are still possible.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+  /// The maximum number of bytes per second, or `None` if unlimited.
+  rate: Option<u64>,
+  /// The bytes that may be transferred right now. Negative if in debt.
+  tokens: i64,
+  /// The last time the bucket was refilled.
+  last_refill: Instant,
+}
+
+impl RateLimiter {
+  pub fn new(rate: Option<u64>) -> Self {
+    Self {
+      rate,
+      tokens: rate.map(to_tokens).unwrap_or_default(),
+      last_refill: Instant::now(),
+    }
+  }
+
+  /// Returns the current limit, in bytes per second.
+  pub fn rate(&self) -> Option<u64> {
+    self.rate
+  }
+
+  /// Changes the limit, taking effect right away. The bytes already in the
+  /// bucket are capped at the new rate.
+  pub fn set_rate(&mut self, rate: Option<u64>, now: Instant) {
+    self.refill(now);
+    self.rate = rate;
+    match rate {
+      Some(rate) => self.tokens = self.tokens.min(to_tokens(rate)),
+      None => self.tokens = 0,
+    }
+  }
+
+  /// Returns the number of bytes that may be transferred right now, or
+  /// `None` if unlimited.
+  pub fn available(&mut self, now: Instant) -> Option<u64> {
+    self.rate?;
+    self.refill(now);
+    Some(self.tokens.max(0) as u64)
+  }
+
+  /// Takes the transferred bytes from the bucket if it holds more than the
+  /// given number of reserved bytes (so is not in debt either), returning
+  /// whether the transfer may go ahead.
+  pub fn try_consume(&mut self, len: u64, reserved: u64, now: Instant) -> bool {
+    if self.rate.is_none() {
+      return true;
+    }
+    self.refill(now);
+    if self.tokens <= to_tokens(reserved) {
+      return false;
+    }
+    self.consume(len);
+    true
+  }
+
+  /// Takes the transferred bytes from the bucket, even if it goes into debt.
+  pub fn consume(&mut self, len: u64) {
+    if self.rate.is_some() {
+      self.tokens = self.tokens.saturating_sub(to_tokens(len));
+    }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.last_refill);
+    self.last_refill = now;
+    if let Some(rate) = self.rate {
+      let refill = (rate as f64 * elapsed.as_secs_f64()) as i64;
+      self.tokens = self.tokens.saturating_add(refill).min(to_tokens(rate));
+    }
+  }
+}
+
+/// An engine-wide rate limiter, shared by the limiters of all torrents.
+pub(crate) type EngineRateLimiter = Arc<Mutex<RateLimiter>>;
+
+/// The number of torrents in the engine, among which the engine's limits are
+/// split.
+pub(crate) type TorrentCount = Arc<AtomicUsize>;
+
+/// Limits the rate of one direction of a torrent's payload, by its own limit
+/// as well as by the engine's limit, so that the latter caps the total rate
+/// of all torrents.
+///
+/// A transfer may only go ahead if neither bucket is in debt, and the
+/// engine's bucket holds more than the torrent's priority reserves for more
+/// important torrents. It's then taken from both.
+#[derive(Debug)]
+pub(crate) struct TorrentRateLimiter {
+  torrent: Mutex<RateLimiter>,
+  engine: EngineRateLimiter,
+  torrent_count: TorrentCount,
+  priority: Mutex<Priority>,
+}
+
+impl TorrentRateLimiter {
+  pub fn new(
+    torrent: RateLimiter,
+    engine: EngineRateLimiter,
+    torrent_count: TorrentCount,
+    priority: Priority,
+  ) -> Self {
+    Self {
+      torrent: Mutex::new(torrent),
+      engine,
+      torrent_count,
+      priority: Mutex::new(priority),
+    }
+  }
+
+  /// Changes the torrent's priority, by which it shares the engine's limit.
+  pub fn set_priority(&self, priority: Priority) {
+    *self.priority.lock().unwrap() = priority;
+  }
+
+  /// Returns whether either the torrent or the engine has a limit.
+  pub fn is_limited(&self) -> bool {
+    self.torrent.lock().unwrap().rate().is_some()
+      || self.engine.lock().unwrap().rate().is_some()
+  }
+
+  /// Returns the rate the torrent may count on: the lower of its own limit
+  /// and its share of the engine's, or `None` if neither is limited.
+  ///
+  /// The engine's limit is split evenly among the torrents in the engine.
+  pub fn share(&self) -> Option<u64> {
+    let torrent = self.torrent.lock().unwrap().rate();
+    let torrent_count =
+      self.torrent_count.load(Ordering::Relaxed).max(1) as u64;
+    let engine = self
+      .engine
+      .lock()
+      .unwrap()
+      .rate()
+      .map(|rate| rate / torrent_count);
+    match (torrent, engine) {
+      (Some(torrent), Some(engine)) => Some(torrent.min(engine)),
+      (torrent, engine) => torrent.or(engine),
+    }
+  }
+
+  /// Changes the torrent's own limit.
+  pub fn set_rate(&self, rate: Option<u64>, now: Instant) {
+    self.torrent.lock().unwrap().set_rate(rate, now);
+  }
+
+  /// Returns the number of bytes that both limits allow right now, or
+  /// `None` if neither is limited.
+  pub fn available(&self, now: Instant) -> Option<u64> {
+    let torrent = self.torrent.lock().unwrap().available(now);
+    let engine = {
+      let mut engine = self.engine.lock().unwrap();
+      let reserved = self.reserved(engine.rate());
+      engine
+        .available(now)
+        .map(|available| available.saturating_sub(reserved))
+    };
+    match (torrent, engine) {
+      (Some(torrent), Some(engine)) => Some(torrent.min(engine)),
+      (torrent, engine) => torrent.or(engine),
+    }
+  }
+
+  /// Takes the transferred bytes from both buckets if neither is in debt,
+  /// returning whether the transfer may go ahead.
+  pub fn try_consume(&self, len: u64, now: Instant) -> bool {
+    // the torrent's bucket is always locked first so that sessions of
+    // different torrents can't deadlock
+    let mut torrent = self.torrent.lock().unwrap();
+    if torrent.available(now) == Some(0) {
+      return false;
+    }
+    let mut engine = self.engine.lock().unwrap();
+    let reserved = self.reserved(engine.rate());
+    if !engine.try_consume(len, reserved, now) {
+      return false;
+    }
+    torrent.consume(len);
+    true
+  }
+
+  /// Takes the transferred bytes from both buckets, even if they go into
+  /// debt.
+  pub fn consume(&self, len: u64) {
+    self.torrent.lock().unwrap().consume(len);
+    self.engine.lock().unwrap().consume(len);
+  }
+
+  /// Returns the bytes of the engine's bucket that this torrent must leave
+  /// for higher priority torrents.
+  fn reserved(&self, engine_rate: Option<u64>) -> u64 {
+    engine_rate
+      .map(|rate| self.priority.lock().unwrap().reserved_bandwidth(rate))
+      .unwrap_or_default()
+  }
+}
+
+fn to_tokens(len: u64) -> i64 {
+  len.min(i64::MAX as u64) as i64
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  #[test]
+  fn test_unlimited() {
+    let now = Instant::now();
+    let mut limiter = RateLimiter::new(None);
+    assert_eq!(limiter.available(now), None);
+    assert!(limiter.try_consume(u64::MAX, 0, now));
+    assert!(limiter.try_consume(u64::MAX, 0, now));
+  }
+
+  #[test]
+  fn test_debt_is_paid_off_before_next_transfer() {
+    let mut limiter = RateLimiter::new(Some(1000));
+    let now = limiter.last_refill;
+
+    // a transfer larger than the bucket is allowed, but leaves it in debt
+    assert!(limiter.try_consume(3000, 0, now));
+    assert_eq!(limiter.available(now), Some(0));
+    assert!(!limiter.try_consume(1, 0, now));
+
+    // after two seconds the debt is paid off, but nothing is left yet
+    let now = now + Duration::from_secs(2);
+    assert!(!limiter.try_consume(1, 0, now));
+    let now = now + Duration::from_millis(500);
+    assert_eq!(limiter.available(now), Some(500));
+    assert!(limiter.try_consume(1, 0, now));
+  }
+
+  #[test]
+  fn test_bucket_is_capped_at_rate() {
+    let mut limiter = RateLimiter::new(Some(1000));
+    let now = limiter.last_refill + Duration::from_secs(10);
+    assert_eq!(limiter.available(now), Some(1000));
+
+    limiter.set_rate(Some(100), now);
+    assert_eq!(limiter.available(now), Some(100));
+    assert_eq!(limiter.rate(), Some(100));
+
+    limiter.set_rate(None, now);
+    assert_eq!(limiter.available(now), None);
+  }
+
+  #[test]
+  fn test_engine_limit_is_shared_by_torrents() {
+    let engine = Arc::new(Mutex::new(RateLimiter::new(Some(1000))));
+    let torrent_count = Arc::new(AtomicUsize::new(2));
+    let now = engine.lock().unwrap().last_refill;
+    let a = TorrentRateLimiter::new(
+      RateLimiter::new(None),
+      Arc::clone(&engine),
+      Arc::clone(&torrent_count),
+      Priority::High,
+    );
+    let b = TorrentRateLimiter::new(
+      RateLimiter::new(Some(300)),
+      Arc::clone(&engine),
+      Arc::clone(&torrent_count),
+      Priority::High,
+    );
+    assert!(a.is_limited());
+    // the engine's limit is shared by the two torrents, however many other
+    // references to it there are
+    let _other = Arc::clone(&engine);
+    assert_eq!(a.share(), Some(500));
+    assert_eq!(b.share(), Some(300));
+    torrent_count.store(4, Ordering::Relaxed);
+    assert_eq!(a.share(), Some(250));
+    assert_eq!(b.share(), Some(250));
+    assert_eq!(a.available(now), Some(1000));
+    assert_eq!(b.available(now), Some(300));
+
+    // one torrent's transfer counts against the other's too
+    assert!(a.try_consume(800, now));
+    assert_eq!(b.available(now), Some(200));
+    assert!(b.try_consume(300, now));
+    assert!(!a.try_consume(1, now));
+    assert!(!b.try_consume(1, now));
+  }
+
+  #[test]
+  fn test_higher_priority_torrent_gets_larger_share() {
+    let engine = Arc::new(Mutex::new(RateLimiter::new(Some(10_000))));
+    let torrent_count = Arc::new(AtomicUsize::new(2));
+    let start = engine.lock().unwrap().last_refill;
+    let high = TorrentRateLimiter::new(
+      RateLimiter::new(None),
+      Arc::clone(&engine),
+      Arc::clone(&torrent_count),
+      Priority::High,
+    );
+    let low = TorrentRateLimiter::new(
+      RateLimiter::new(None),
+      Arc::clone(&engine),
+      Arc::clone(&torrent_count),
+      Priority::Low,
+    );
+    // the low priority torrent must leave its reserve to the high one
+    assert_eq!(high.available(start), Some(10_000));
+    assert_eq!(low.available(start), Some(5_000));
+
+    // both torrents try to send a small block every millisecond for ten
+    // seconds, the low priority one first
+    let (mut high_bytes, mut low_bytes) = (0, 0);
+    for ms in 0..10_000 {
+      let now = start + Duration::from_millis(ms);
+      if low.try_consume(100, now) {
+        low_bytes += 100;
+      }
+      if high.try_consume(100, now) {
+        high_bytes += 100;
+      }
+    }
+    assert!(high_bytes > 2 * low_bytes, "{} {}", high_bytes, low_bytes);
+
+    // once the high priority torrent is idle the low one gets the rest
+    let now = start + Duration::from_secs(20);
+    assert_eq!(low.available(now), Some(5_000));
+    assert!(low.try_consume(100, now));
+
+    // and a changed priority takes effect right away
+    low.set_priority(Priority::High);
+    assert_eq!(low.available(now), Some(9_900));
+  }
+}
@@ -0,0 +1,557 @@
+//! Saving and restoring the engine's torrents, so that they survive restarts
+//! without being downloaded or checked again.
+//!
+//! A session is a directory that holds a bencoded `session` file, which
+//! lists the torrents' resume data, and the torrents' metainfo files, named
+//! after their info hashes.
+//!
+//! While an engine restored from a session runs, the directory also holds a
+//! `running` file, which is removed when the engine shuts down cleanly. If
+//! it's still there on the next restore, the engine crashed or was killed,
+//! and the torrents' most recently completed pieces are verified again.
+//!
+//! A single running torrent may also be handed off to another engine as a
+//! [`TorrentExport`], which is encoded the same way as the session's
+//! entries.
+
+use std::{
+  collections::BTreeMap,
+  fs,
+  io::ErrorKind,
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+  conf::TorrentConf,
+  error::{EngineResult, Error},
+  metainfo::Metainfo,
+  torrent::ResumeData,
+  Bitfield, PieceIndex, Sha1Hash,
+};
+
+/// The name of the file in the session directory that lists the torrents.
+const SESSION_FILE: &str = "session";
+
+/// The name of the file in the session directory that marks an engine
+/// restored from it as running.
+const RUNNING_FILE: &str = "running";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Session {
+  torrents: Vec<TorrentEntry>,
+}
+
+/// A torrent's resume data as it's saved in the session file.
+#[derive(Debug, Serialize, Deserialize)]
+struct TorrentEntry {
+  /// The path of the torrent's metainfo file, relative to the session
+  /// directory.
+  metainfo_path: PathBuf,
+  /// The underlying bytes of the torrent's own pieces bitfield, if its data
+  /// had been checked.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[serde(with = "serde_bytes")]
+  own_pieces: Option<Vec<u8>>,
+  downloaded: u64,
+  uploaded: u64,
+  run_duration: Duration,
+  is_paused: bool,
+  conf: TorrentConf,
+  #[serde(default)]
+  labels: Vec<String>,
+  #[serde(default)]
+  recent_pieces: Vec<PieceIndex>,
+  #[serde(default)]
+  tracker_ids: BTreeMap<String, String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  announce_key: Option<u32>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  trackers: Option<Vec<String>>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  download_dir: Option<PathBuf>,
+}
+
+/// Saves the torrents in the session directory, creating it if it doesn't
+/// exist and replacing the previously saved session.
+///
+/// The session file is written last and atomically, so an interrupted save
+/// leaves the previous session intact.
+pub(crate) async fn save(
+  dir: &Path,
+  torrents: Vec<ResumeData>,
+) -> EngineResult<()> {
+  tokio::fs::create_dir_all(dir).await?;
+
+  let mut session = Session::default();
+  for torrent in torrents {
+    let metainfo_path = metainfo_path(torrent.info_hash);
+    tokio::fs::write(dir.join(&metainfo_path), &torrent.metainfo).await?;
+
+    session.torrents.push(TorrentEntry {
+      metainfo_path,
+      own_pieces: torrent.own_pieces.map(Bitfield::into_vec),
+      downloaded: torrent.downloaded,
+      uploaded: torrent.uploaded,
+      run_duration: torrent.run_duration,
+      is_paused: torrent.is_paused,
+      conf: torrent.conf,
+      labels: torrent.labels,
+      recent_pieces: torrent.recent_pieces,
+      tracker_ids: torrent.tracker_ids,
+      announce_key: torrent.announce_key,
+      trackers: encode_trackers(torrent.trackers.as_deref()),
+      download_dir: torrent.download_dir,
+    });
+  }
+
+  let encoded = serde_bencoded::to_vec(&session).map_err(|e| {
+    log::error!("Failed to encode session: {}", e);
+    Error::InvalidSession
+  })?;
+  let tmp_path = dir.join(format!("{}.tmp", SESSION_FILE));
+  tokio::fs::write(&tmp_path, encoded).await?;
+  tokio::fs::rename(tmp_path, dir.join(SESSION_FILE)).await?;
+
+  Ok(())
+}
+
+/// Removes the metainfo file of a torrent that was removed from the engine,
+/// if it was saved in the session directory.
+pub(crate) async fn remove_metainfo(
+  dir: &Path,
+  info_hash: Sha1Hash,
+) -> EngineResult<()> {
+  match tokio::fs::remove_file(dir.join(metainfo_path(info_hash))).await {
+    Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+    _ => Ok(()),
+  }
+}
+
+/// Returns the path of the torrent's metainfo file, relative to the session
+/// directory.
+fn metainfo_path(info_hash: Sha1Hash) -> PathBuf {
+  PathBuf::from(format!("{}.torrent", hex::encode(info_hash)))
+}
+
+/// Loads the torrents saved in the session directory, in the order they
+/// were saved.
+///
+/// If nothing was saved in the directory yet, the session is empty. Torrents
+/// whose metainfo file is gone were removed after the session was saved, and
+/// are skipped.
+pub(crate) fn load(dir: &Path) -> EngineResult<Vec<(Metainfo, ResumeData)>> {
+  let encoded = match fs::read(dir.join(SESSION_FILE)) {
+    Ok(encoded) => encoded,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => return Err(e.into()),
+  };
+  let session: Session = serde_bencoded::from_bytes(&encoded).map_err(|e| {
+    log::warn!("Invalid session file: {}", e);
+    Error::InvalidSession
+  })?;
+
+  let mut torrents = Vec::with_capacity(session.torrents.len());
+  for entry in session.torrents {
+    let raw = match fs::read(dir.join(&entry.metainfo_path)) {
+      Ok(raw) => raw,
+      Err(e) if e.kind() == ErrorKind::NotFound => {
+        log::info!("Skipping removed torrent {:?}", entry.metainfo_path);
+        continue;
+      }
+      Err(e) => return Err(e.into()),
+    };
+    let metainfo = Metainfo::from_bytes(&raw).map_err(|e| {
+      log::warn!("Invalid metainfo {:?}: {}", entry.metainfo_path, e);
+      Error::InvalidSession
+    })?;
+    entry.conf.validate()?;
+
+    let own_pieces = match entry.own_pieces {
+      Some(bytes) => match decode_own_pieces(bytes, metainfo.piece_count()) {
+        Some(own_pieces) => Some(own_pieces),
+        None => {
+          log::warn!(
+            "Saved bitfield of {:?} doesn't match its pieces",
+            entry.metainfo_path
+          );
+          return Err(Error::InvalidSession);
+        }
+      },
+      None => None,
+    };
+    if entry
+      .recent_pieces
+      .iter()
+      .any(|&index| index >= metainfo.piece_count())
+    {
+      log::warn!("Saved pieces of {:?} are invalid", entry.metainfo_path);
+      return Err(Error::InvalidSession);
+    }
+
+    let resume = ResumeData {
+      info_hash: metainfo.info_hash,
+      metainfo: raw,
+      conf: entry.conf,
+      own_pieces,
+      downloaded: entry.downloaded,
+      uploaded: entry.uploaded,
+      run_duration: entry.run_duration,
+      is_paused: entry.is_paused,
+      labels: entry.labels,
+      recent_pieces: entry.recent_pieces,
+      peers: Vec::new(),
+      tracker_ids: entry.tracker_ids,
+      announce_key: entry.announce_key,
+      trackers: decode_trackers(entry.trackers)?,
+      download_dir: entry.download_dir,
+    };
+    torrents.push((metainfo, resume));
+  }
+
+  Ok(torrents)
+}
+
+/// Decodes the bitfield of the torrent's own pieces, which is saved with the
+/// padding of its last byte, or returns `None` if it doesn't match the
+/// torrent's pieces.
+fn decode_own_pieces(bytes: Vec<u8>, piece_count: usize) -> Option<Bitfield> {
+  if bytes.len() != piece_count.div_ceil(8) {
+    return None;
+  }
+  let mut own_pieces = Bitfield::from_vec(bytes);
+  own_pieces.truncate(piece_count);
+  Some(own_pieces)
+}
+
+/// Encodes the torrent's trackers as their URLs.
+fn encode_trackers(trackers: Option<&[Url]>) -> Option<Vec<String>> {
+  trackers.map(|trackers| trackers.iter().map(Url::to_string).collect())
+}
+
+/// Decodes the torrent's trackers, or returns an
+/// [`Error::InvalidSession`] if any of their URLs is invalid.
+fn decode_trackers(
+  trackers: Option<Vec<String>>,
+) -> EngineResult<Option<Vec<Url>>> {
+  trackers
+    .map(|trackers| {
+      trackers
+        .iter()
+        .map(|url| url.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| Error::InvalidSession)
+    })
+    .transpose()
+}
+
+/// A running torrent's state, as exported from one engine to be imported by
+/// another with [`EngineHandle::import_torrent`], which continues where the
+/// torrent left off: with its pieces, transfer totals, configuration,
+/// labels, peers, and the directory of its files.
+///
+/// Within a process the export may be passed on as is, and for an engine in
+/// another process it may be encoded with [`Self::to_bytes`].
+///
+/// [`EngineHandle::import_torrent`]:
+/// crate::engine::EngineHandle::import_torrent
+#[derive(Clone, Debug)]
+pub struct TorrentExport {
+  pub(crate) resume: ResumeData,
+}
+
+/// A torrent export as it's encoded, which unlike a session entry holds the
+/// metainfo itself.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportEntry {
+  #[serde(with = "serde_bytes")]
+  metainfo: Vec<u8>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[serde(with = "serde_bytes")]
+  own_pieces: Option<Vec<u8>>,
+  downloaded: u64,
+  uploaded: u64,
+  run_duration: Duration,
+  is_paused: bool,
+  conf: TorrentConf,
+  #[serde(default)]
+  labels: Vec<String>,
+  #[serde(default)]
+  peers: Vec<String>,
+  #[serde(default)]
+  tracker_ids: BTreeMap<String, String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  announce_key: Option<u32>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  trackers: Option<Vec<String>>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  download_dir: Option<PathBuf>,
+}
+
+impl TorrentExport {
+  /// Returns the info hash of the exported torrent.
+  pub fn info_hash(&self) -> Sha1Hash {
+    self.resume.info_hash
+  }
+
+  /// Encodes the export, to be decoded by [`Self::from_bytes`] in another
+  /// process.
+  pub fn to_bytes(&self) -> EngineResult<Vec<u8>> {
+    let resume = &self.resume;
+    let entry = ExportEntry {
+      metainfo: resume.metainfo.clone(),
+      own_pieces: resume.own_pieces.clone().map(Bitfield::into_vec),
+      downloaded: resume.downloaded,
+      uploaded: resume.uploaded,
+      run_duration: resume.run_duration,
+      is_paused: resume.is_paused,
+      conf: resume.conf.clone(),
+      labels: resume.labels.clone(),
+      peers: resume.peers.iter().map(SocketAddr::to_string).collect(),
+      tracker_ids: resume.tracker_ids.clone(),
+      announce_key: resume.announce_key,
+      trackers: encode_trackers(resume.trackers.as_deref()),
+      download_dir: resume.download_dir.clone(),
+    };
+    serde_bencoded::to_vec(&entry).map_err(|e| {
+      log::error!("Failed to encode torrent export: {}", e);
+      Error::InvalidSession
+    })
+  }
+
+  /// Decodes an export encoded by [`Self::to_bytes`].
+  ///
+  /// An [`Error::InvalidSession`] is returned if the export is corrupt.
+  pub fn from_bytes(bytes: &[u8]) -> EngineResult<Self> {
+    let entry: ExportEntry =
+      serde_bencoded::from_bytes(bytes).map_err(|e| {
+        log::warn!("Invalid torrent export: {}", e);
+        Error::InvalidSession
+      })?;
+    let metainfo = Metainfo::from_bytes(&entry.metainfo).map_err(|e| {
+      log::warn!("Invalid metainfo in torrent export: {}", e);
+      Error::InvalidSession
+    })?;
+    entry.conf.validate()?;
+
+    let own_pieces = match entry.own_pieces {
+      Some(bytes) => Some(
+        decode_own_pieces(bytes, metainfo.piece_count())
+          .ok_or(Error::InvalidSession)?,
+      ),
+      None => None,
+    };
+    let peers = entry
+      .peers
+      .iter()
+      .map(|addr| addr.parse())
+      .collect::<Result<_, _>>()
+      .map_err(|_| Error::InvalidSession)?;
+
+    Ok(Self {
+      resume: ResumeData {
+        info_hash: metainfo.info_hash,
+        metainfo: entry.metainfo,
+        conf: entry.conf,
+        own_pieces,
+        downloaded: entry.downloaded,
+        uploaded: entry.uploaded,
+        run_duration: entry.run_duration,
+        is_paused: entry.is_paused,
+        labels: entry.labels,
+        recent_pieces: Vec::new(),
+        peers,
+        tracker_ids: entry.tracker_ids,
+        announce_key: entry.announce_key,
+        trackers: decode_trackers(entry.trackers)?,
+        download_dir: entry.download_dir,
+      },
+    })
+  }
+}
+
+/// Marks the session directory as used by a running engine, creating the
+/// directory if it doesn't exist.
+///
+/// Returns whether the previous engine restored from the directory didn't
+/// shut down cleanly, i.e. whether it was still marked as running.
+pub(crate) fn mark_running(dir: &Path) -> EngineResult<bool> {
+  fs::create_dir_all(dir)?;
+  let path = dir.join(RUNNING_FILE);
+  let was_running = path.exists();
+  fs::write(path, b"")?;
+  Ok(was_running)
+}
+
+/// Marks the session directory as no longer used by a running engine, after
+/// the engine shut down cleanly.
+pub(crate) fn mark_stopped(dir: &Path) -> EngineResult<()> {
+  match fs::remove_file(dir.join(RUNNING_FILE)) {
+    Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+    _ => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::tempdir;
+
+  use super::*;
+  use crate::conf::Priority;
+
+  /// A single file torrent of 3 pieces.
+  const METAINFO: &[u8] = b"d4:infod6:lengthi40000e4:name4:file\
+    12:piece lengthi16384e6:pieces60:\
+    aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbccccccccccccccccccccee";
+
+  fn resume_data(own_pieces: Option<Bitfield>) -> ResumeData {
+    let metainfo = Metainfo::from_bytes(METAINFO).unwrap();
+    ResumeData {
+      info_hash: metainfo.info_hash,
+      metainfo: METAINFO.to_vec(),
+      conf: TorrentConf {
+        max_connected_peer_count: 7,
+        priority: Priority::High,
+        ..Default::default()
+      },
+      own_pieces,
+      downloaded: 32768,
+      uploaded: 100,
+      run_duration: Duration::from_secs(42),
+      is_paused: true,
+      labels: vec!["linux-isos".to_owned()],
+      recent_pieces: vec![2, 0],
+      peers: Vec::new(),
+      tracker_ids: BTreeMap::from([(
+        "http://tracker.example.com/announce".to_owned(),
+        "abc".to_owned(),
+      )]),
+      announce_key: Some(0xdeadbeef),
+      trackers: Some(vec!["http://tracker.example.com/announce"
+        .parse()
+        .unwrap()]),
+      download_dir: Some(PathBuf::from("/downloads/moved")),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_save_and_load() {
+    let dir = tempdir().unwrap();
+    let mut own_pieces = Bitfield::repeat(false, 3);
+    own_pieces.set(0, true);
+    own_pieces.set(2, true);
+
+    save(
+      dir.path(),
+      vec![resume_data(Some(own_pieces.clone())), resume_data(None)],
+    )
+    .await
+    .unwrap();
+
+    let torrents = load(dir.path()).unwrap();
+    assert_eq!(torrents.len(), 2);
+
+    let (metainfo, resume) = &torrents[0];
+    assert_eq!(metainfo.name, "file");
+    assert_eq!(resume.info_hash, metainfo.info_hash);
+    assert_eq!(resume.metainfo, METAINFO);
+    assert_eq!(resume.own_pieces, Some(own_pieces));
+    assert_eq!(resume.downloaded, 32768);
+    assert_eq!(resume.uploaded, 100);
+    assert_eq!(resume.run_duration, Duration::from_secs(42));
+    assert!(resume.is_paused);
+    assert_eq!(resume.conf.max_connected_peer_count, 7);
+    assert_eq!(resume.conf.priority, Priority::High);
+    assert_eq!(resume.labels, vec!["linux-isos".to_owned()]);
+    assert_eq!(resume.recent_pieces, vec![2, 0]);
+    assert_eq!(resume.tracker_ids, resume_data(None).tracker_ids);
+    assert_eq!(resume.announce_key, Some(0xdeadbeef));
+    assert_eq!(resume.trackers, resume_data(None).trackers);
+    assert_eq!(resume.download_dir, resume_data(None).download_dir);
+
+    assert_eq!(torrents[1].1.own_pieces, None);
+  }
+
+  #[tokio::test]
+  async fn test_remove_metainfo() {
+    let dir = tempdir().unwrap();
+    save(dir.path(), vec![resume_data(None)]).await.unwrap();
+    let info_hash = resume_data(None).info_hash;
+    assert!(dir.path().join(metainfo_path(info_hash)).exists());
+
+    remove_metainfo(dir.path(), info_hash).await.unwrap();
+    assert!(!dir.path().join(metainfo_path(info_hash)).exists());
+    // the removed torrent is no longer restored, nor is removing it again an
+    // error
+    assert!(load(dir.path()).unwrap().is_empty());
+    remove_metainfo(dir.path(), info_hash).await.unwrap();
+  }
+
+  #[test]
+  fn test_load_empty_dir() {
+    let dir = tempdir().unwrap();
+    assert!(load(dir.path()).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_mark_running() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path().join("session");
+    assert!(!mark_running(&dir).unwrap());
+    // the previous engine didn't stop
+    assert!(mark_running(&dir).unwrap());
+
+    mark_stopped(&dir).unwrap();
+    assert!(!mark_running(&dir).unwrap());
+    mark_stopped(&dir).unwrap();
+    mark_stopped(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_encode_and_decode_export() {
+    let mut own_pieces = Bitfield::repeat(false, 3);
+    own_pieces.set(1, true);
+    let mut resume = resume_data(Some(own_pieces.clone()));
+    resume.peers = vec![
+      "127.0.0.1:6881".parse().unwrap(),
+      "[::1]:51413".parse().unwrap(),
+    ];
+    let export = TorrentExport { resume };
+
+    let decoded = TorrentExport::from_bytes(&export.to_bytes().unwrap())
+      .unwrap()
+      .resume;
+    assert_eq!(decoded.info_hash, export.info_hash());
+    assert_eq!(decoded.metainfo, METAINFO);
+    assert_eq!(decoded.own_pieces, Some(own_pieces));
+    assert_eq!(decoded.downloaded, 32768);
+    assert_eq!(decoded.uploaded, 100);
+    assert_eq!(decoded.run_duration, Duration::from_secs(42));
+    assert!(decoded.is_paused);
+    assert_eq!(decoded.conf.max_connected_peer_count, 7);
+    assert_eq!(decoded.labels, vec!["linux-isos".to_owned()]);
+    assert_eq!(decoded.peers, export.resume.peers);
+    assert_eq!(decoded.tracker_ids, export.resume.tracker_ids);
+    assert_eq!(decoded.announce_key, export.resume.announce_key);
+    assert_eq!(decoded.trackers, export.resume.trackers);
+    assert_eq!(decoded.download_dir, export.resume.download_dir);
+    // the export's pieces were written before the torrent was handed off
+    assert!(decoded.recent_pieces.is_empty());
+
+    assert!(matches!(
+      TorrentExport::from_bytes(b"not bencode"),
+      Err(Error::InvalidSession)
+    ));
+  }
+
+  #[test]
+  fn test_load_corrupt_session() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join(SESSION_FILE), b"not bencode").unwrap();
+    assert!(matches!(load(dir.path()), Err(Error::InvalidSession)));
+  }
+}
@@ -1,9 +1,11 @@
-use std::{ops::Range, path::PathBuf};
+use std::{fs, ops::Range, path::PathBuf};
 
-use crate::{metainfo::Metainfo, FileIndex, PieceIndex};
+use serde_derive::Serialize;
+
+use crate::{metainfo::Metainfo, Bitfield, FileIndex, PieceIndex};
 
 /// Information about the torrent file.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct FileInfo {
   /// file's relative path from the download directory.
   pub path: PathBuf,
@@ -34,8 +36,8 @@ impl FileInfo {
   ///
   /// * `torrent_offset` - A byte offset in the entire torrent.  
   /// * `len` - The length of the byte range, starting from the offset.
-  ///         This may exceed the file length, in which case the returned file
-  ///         length will be smaller.
+  ///   This may exceed the file length, in which case the returned file
+  ///   length will be smaller.
   ///
   /// # Panics
   ///
@@ -204,6 +206,69 @@ impl StorageInfo {
     }
   }
 
+  /// Returns the pieces that hold the first `len` bytes of the file, or all
+  /// of it if the file is shorter. An empty file has no pieces.
+  pub fn file_prefix_pieces(
+    &self,
+    index: FileIndex,
+    len: u64,
+  ) -> Range<PieceIndex> {
+    let file = &self.files[index];
+    let len = len.min(file.len);
+    if len == 0 {
+      return 0..0;
+    }
+    let piece_len = self.piece_len as u64;
+    let first = file.torrent_offset / piece_len;
+    let last = (file.torrent_offset + len - 1) / piece_len;
+    first as PieceIndex..last as PieceIndex + 1
+  }
+
+  /// Returns the number of bytes of each file that are in the given pieces,
+  /// i.e. how much of each file is downloaded.
+  pub fn completed_file_bytes(&self, own_pieces: &Bitfield) -> Vec<u64> {
+    debug_assert_eq!(own_pieces.len(), self.piece_count);
+    let mut completed = vec![0; self.files.len()];
+    for index in own_pieces.iter_ones() {
+      let piece_start = self.torrent_piece_offset(index);
+      let piece_end = piece_start + self.piece_len(index) as u64;
+      for file_index in self.files_intersecting_piece(index) {
+        let file = &self.files[file_index];
+        let overlap = piece_end.min(file.torrent_end_offset())
+          - piece_start.max(file.torrent_offset);
+        completed[file_index] += overlap;
+      }
+    }
+    completed
+  }
+
+  /// Returns whether the files on disk may hold the given pieces, i.e.
+  /// whether none of the files the pieces overlap with is missing, shorter
+  /// than the pieces' end in it, or longer than the file.
+  ///
+  /// Files only grow as pieces are written to them, so otherwise they were
+  /// deleted or replaced since the pieces were saved.
+  pub fn has_files_of(&self, own_pieces: &Bitfield) -> bool {
+    debug_assert_eq!(own_pieces.len(), self.piece_count);
+    let mut min_lens = vec![0; self.files.len()];
+    for index in own_pieces.iter_ones() {
+      let piece_end =
+        self.torrent_piece_offset(index) + self.piece_len(index) as u64;
+      for file_index in self.files_intersecting_piece(index) {
+        let file = &self.files[file_index];
+        let end =
+          piece_end.min(file.torrent_end_offset()) - file.torrent_offset;
+        min_lens[file_index] = min_lens[file_index].max(end);
+      }
+    }
+    self.files.iter().zip(min_lens).all(|(file, min_len)| {
+      match fs::metadata(self.download_dir.join(&file.path)) {
+        Ok(metadata) => (min_len..=file.len).contains(&metadata.len()),
+        Err(_) => min_len == 0,
+      }
+    })
+  }
+
   /// Returns the piece's absolute offset in the torrent.
   pub fn torrent_piece_offset(&self, index: PieceIndex) -> u64 {
     index as u64 * self.piece_len as u64
@@ -232,6 +297,92 @@ impl StorageInfo {
 mod tests {
   use super::*;
 
+  #[test]
+  fn test_completed_file_bytes() {
+    // 3 files over 4 pieces of 10 bytes, the last being 5 bytes long:
+    // | file 0 (12) | file 1 (3) | file 2 (20)  |
+    // | p0 (10) | p1 (10) | p2 (10) | p3 (5) |
+    let files = [12, 3, 20]
+      .iter()
+      .scan(0, |offset, &len| {
+        let file = FileInfo {
+          path: PathBuf::from("file"),
+          len,
+          torrent_offset: *offset,
+        };
+        *offset += len;
+        Some(file)
+      })
+      .collect();
+    let info = StorageInfo {
+      piece_count: 4,
+      piece_len: 10,
+      last_piece_len: 5,
+      download_len: 35,
+      download_dir: PathBuf::from("/"),
+      files,
+    };
+
+    let own_pieces = Bitfield::repeat(false, 4);
+    assert_eq!(info.completed_file_bytes(&own_pieces), vec![0, 0, 0]);
+
+    let mut own_pieces = Bitfield::repeat(false, 4);
+    own_pieces.set(1, true);
+    own_pieces.set(3, true);
+    assert_eq!(info.completed_file_bytes(&own_pieces), vec![2, 3, 10]);
+
+    let own_pieces = Bitfield::repeat(true, 4);
+    assert_eq!(info.completed_file_bytes(&own_pieces), vec![12, 3, 20]);
+
+    assert_eq!(info.file_prefix_pieces(0, 10), 0..1);
+    assert_eq!(info.file_prefix_pieces(0, 11), 0..2);
+    assert_eq!(info.file_prefix_pieces(1, 100), 1..2);
+    assert_eq!(info.file_prefix_pieces(2, 6), 1..3);
+    assert_eq!(info.file_prefix_pieces(2, 100), 1..4);
+    assert_eq!(info.file_prefix_pieces(2, 0), 0..0);
+  }
+
+  #[test]
+  fn test_has_files_of() {
+    // 2 files over 3 pieces of 10 bytes, the last being 5 bytes long:
+    // | file 0 (15)     | file 1 (10) |
+    // | p0 (10) | p1 (10) | p2 (5) |
+    let dir = tempfile::tempdir().unwrap();
+    let file = |path: &str, len, torrent_offset| FileInfo {
+      path: PathBuf::from(path),
+      len,
+      torrent_offset,
+    };
+    let info = StorageInfo {
+      piece_count: 3,
+      piece_len: 10,
+      last_piece_len: 5,
+      download_len: 25,
+      download_dir: dir.path().to_path_buf(),
+      files: vec![file("a", 15, 0), file("b", 10, 15)],
+    };
+    let mut own_pieces = Bitfield::repeat(false, 3);
+
+    // without pieces the files need not exist
+    assert!(info.has_files_of(&own_pieces));
+
+    own_pieces.set(0, true);
+    assert!(!info.has_files_of(&own_pieces));
+    fs::write(dir.path().join("a"), [0; 10]).unwrap();
+    assert!(info.has_files_of(&own_pieces));
+
+    // the second piece ends in the second file
+    own_pieces.set(1, true);
+    fs::write(dir.path().join("b"), [0; 5]).unwrap();
+    assert!(!info.has_files_of(&own_pieces));
+    fs::write(dir.path().join("a"), [0; 15]).unwrap();
+    assert!(info.has_files_of(&own_pieces));
+
+    // a file longer than in the torrent was replaced
+    fs::write(dir.path().join("b"), [0; 11]).unwrap();
+    assert!(!info.has_files_of(&own_pieces));
+  }
+
   #[test]
   fn test_file_get_slice() {
     let file = FileInfo {
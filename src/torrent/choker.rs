@@ -0,0 +1,191 @@
+//! The choking algorithm, which decides which of the interested peers we
+//! upload to.
+//!
+//! The peers that give us the most, i.e. that we download from the fastest,
+//! are unchoked in the hope that they keep reciprocating, or when seeding
+//! those that we upload to the fastest. Besides these, one more peer is
+//! unchoked optimistically regardless of its rate, so that peers we haven't
+//! exchanged data with get the chance to become better partners than the
+//! current ones.
+
+use std::{
+  cmp::Reverse,
+  collections::HashSet,
+  net::SocketAddr,
+  time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+/// How often the peers are ranked anew, which is long enough for a newly
+/// unchoked peer to reach its full rate.
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the optimistic unchoke moves on to another peer.
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The upload rate each slot should get when the slot count is derived from
+/// the upload bandwidth, below which a peer is hardly worth unchoking.
+const AUTO_SLOT_RATE: u64 = 32 * 1024;
+
+/// The fewest slots derived from the upload bandwidth, which is what the
+/// reference client uses regardless of bandwidth, and which is where a
+/// torrent that hasn't uploaded yet starts from.
+const MIN_AUTO_SLOT_COUNT: usize = 4;
+
+/// The most slots derived from the upload bandwidth, beyond which the
+/// rankings of the peers become too noisy to be of use.
+const MAX_AUTO_SLOT_COUNT: usize = 32;
+
+/// Returns the number of unchoke slots the given upload bandwidth, in bytes
+/// per second, is enough for.
+///
+/// As a measured bandwidth grows with the number of peers uploaded to, a
+/// torrent whose uploads saturate its slots opens more of them on the
+/// following rounds, until the connection is saturated instead.
+pub fn auto_slot_count(upload_bandwidth: u64) -> usize {
+  let slot_count = (upload_bandwidth / AUTO_SLOT_RATE) as usize;
+  slot_count.clamp(MIN_AUTO_SLOT_COUNT, MAX_AUTO_SLOT_COUNT)
+}
+
+/// A connected peer competing for an unchoke slot.
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate {
+  pub addr: SocketAddr,
+  /// Only interested peers are unchoked, as the others have no use for it.
+  pub is_interested: bool,
+  /// The rate by which the peer is ranked: our download rate from the peer,
+  /// or when seeding our upload rate to the peer.
+  pub rate: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Choker {
+  /// When the peers were last ranked, which is `None` before the first time.
+  last_run_time: Option<Instant>,
+  /// The peer that is unchoked optimistically, if any.
+  optimistic_unchoke: Option<SocketAddr>,
+  /// When the optimistic unchoke last moved on to another peer.
+  last_optimistic_unchoke_time: Option<Instant>,
+}
+
+impl Choker {
+  /// Ranks the peers if it's due, returning the peers to unchoke, with all
+  /// others to be choked, or `None` if the peers are left as they are.
+  ///
+  /// The given number of fastest peers are unchoked, plus the optimistic
+  /// unchoke, which is kept until its interval is up unless it leaves, loses
+  /// interest, or earns a regular slot.
+  pub fn run(
+    &mut self,
+    now: Instant,
+    mut candidates: Vec<Candidate>,
+    slot_count: usize,
+  ) -> Option<HashSet<SocketAddr>> {
+    let is_due = self
+      .last_run_time
+      .is_none_or(|t| now.saturating_duration_since(t) >= CHOKE_INTERVAL);
+    if !is_due {
+      return None;
+    }
+    self.last_run_time = Some(now);
+
+    candidates.retain(|c| c.is_interested);
+    // the fastest peers first
+    candidates.sort_unstable_by_key(|c| Reverse(c.rate));
+    let slot_count = slot_count.min(candidates.len());
+    let mut unchoked: HashSet<_> =
+      candidates[..slot_count].iter().map(|c| c.addr).collect();
+
+    // the peers left without a slot compete for the optimistic unchoke
+    let rest: Vec<_> =
+      candidates[slot_count..].iter().map(|c| c.addr).collect();
+    let is_current_valid = self
+      .optimistic_unchoke
+      .is_some_and(|addr| rest.contains(&addr));
+    let is_rotation_due = self.last_optimistic_unchoke_time.is_none_or(|t| {
+      now.saturating_duration_since(t) >= OPTIMISTIC_UNCHOKE_INTERVAL
+    });
+    if !is_current_valid || is_rotation_due {
+      // move on to another peer if there is one
+      let others: Vec<_> = rest
+        .iter()
+        .filter(|&&addr| Some(addr) != self.optimistic_unchoke)
+        .collect();
+      self.optimistic_unchoke = others
+        .choose(&mut rand::thread_rng())
+        .map(|&&addr| addr)
+        .or(self.optimistic_unchoke.filter(|_| is_current_valid));
+      self.last_optimistic_unchoke_time = Some(now);
+    }
+    unchoked.extend(self.optimistic_unchoke);
+    Some(unchoked)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn candidate(port: u16, is_interested: bool, rate: u64) -> Candidate {
+    Candidate {
+      addr: SocketAddr::from(([127, 0, 0, 1], port)),
+      is_interested,
+      rate,
+    }
+  }
+
+  #[test]
+  fn test_choker() {
+    let mut choker = Choker::default();
+    let candidates = vec![
+      candidate(1, true, 100),
+      candidate(2, true, 300),
+      candidate(3, false, 1000),
+      candidate(4, true, 200),
+    ];
+    let start = Instant::now();
+
+    // the 2 fastest interested peers are unchoked, and the slowest one
+    // optimistically, being the only one left
+    let unchoked = choker.run(start, candidates.clone(), 2).unwrap();
+    let expected: HashSet<_> =
+      [2, 4, 1].map(|port| candidate(port, true, 0).addr).into();
+    assert_eq!(unchoked, expected);
+    assert_eq!(choker.optimistic_unchoke, Some(candidate(1, true, 0).addr));
+
+    // the peers are left alone until the next run is due
+    assert_eq!(
+      choker.run(start + CHOKE_INTERVAL / 2, candidates.clone(), 2),
+      None
+    );
+
+    // with more peers without a slot, the optimistic unchoke is kept until
+    // its interval is up, after which it moves on
+    let mut candidates = candidates;
+    candidates.push(candidate(5, true, 0));
+    candidates.push(candidate(6, true, 0));
+    let unchoked = choker
+      .run(start + CHOKE_INTERVAL, candidates.clone(), 2)
+      .unwrap();
+    assert!(unchoked.contains(&candidate(1, true, 0).addr));
+    assert_eq!(unchoked.len(), 3);
+
+    let unchoked = choker
+      .run(start + OPTIMISTIC_UNCHOKE_INTERVAL, candidates, 2)
+      .unwrap();
+    let optimistic_unchoke = choker.optimistic_unchoke.unwrap();
+    assert!([5, 6]
+      .map(|port| candidate(port, true, 0).addr)
+      .contains(&optimistic_unchoke));
+    assert!(unchoked.contains(&optimistic_unchoke));
+    assert!(!unchoked.contains(&candidate(1, true, 0).addr));
+  }
+
+  #[test]
+  fn test_auto_slot_count() {
+    assert_eq!(auto_slot_count(0), MIN_AUTO_SLOT_COUNT);
+    assert_eq!(auto_slot_count(10 * AUTO_SLOT_RATE + 1), 10);
+    assert_eq!(auto_slot_count(u64::MAX), MAX_AUTO_SLOT_COUNT);
+  }
+}
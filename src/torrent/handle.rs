@@ -0,0 +1,361 @@
+//! A handle to a single running torrent, through which the user may control
+//! the torrent directly, without going through the engine.
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use reqwest::Url;
+use tokio::sync::oneshot;
+
+use crate::{
+  conf::Priority,
+  engine,
+  error::{EngineResult, Error},
+  tracker::client::TrackerBackend,
+  PieceIndex, TorrentId,
+};
+
+use super::{
+  stats::{FileStats, PeerSessionStats, TorrentStats},
+  Command, FilePriority, Limits, Sender,
+};
+
+/// A handle to a torrent, returned by
+/// [`EngineHandle::create_torrent`](crate::engine::EngineHandle::create_torrent).
+///
+/// The handle may be cloned and used from multiple tasks. Its methods fail
+/// with [`Error::Channel`] once the torrent has been stopped.
+#[derive(Debug, Clone)]
+pub struct TorrentHandle {
+  id: TorrentId,
+  tx: Sender,
+  /// Commands that also concern the engine's other torrents, such as the
+  /// priority, go through the engine, which is also told of the changes it
+  /// applies again if it restarts the torrent.
+  engine_tx: engine::Sender,
+  /// The torrent's tracker backend, which tells the trackers that can be
+  /// added.
+  tracker_backend: Arc<dyn TrackerBackend>,
+}
+
+impl TorrentHandle {
+  pub(crate) fn new(
+    id: TorrentId,
+    tx: Sender,
+    engine_tx: engine::Sender,
+    tracker_backend: Arc<dyn TrackerBackend>,
+  ) -> Self {
+    Self {
+      id,
+      tx,
+      engine_tx,
+      tracker_backend,
+    }
+  }
+
+  /// Returns the id of the torrent, which identifies it in engine commands
+  /// and alerts.
+  pub fn id(&self) -> TorrentId {
+    self.id
+  }
+
+  /// Returns the current statistics of the torrent.
+  pub async fn stats(&self) -> EngineResult<TorrentStats> {
+    let (tx, rx) = oneshot::channel();
+    self.tx.send(Command::GetStats(tx))?;
+    Ok(rx.await?)
+  }
+
+  /// Returns the statistics of each of the torrent's peers.
+  pub async fn peers(&self) -> EngineResult<Vec<PeerSessionStats>> {
+    let (tx, rx) = oneshot::channel();
+    self.tx.send(Command::GetPeers(tx))?;
+    Ok(rx.await?)
+  }
+
+  /// Returns each of the torrent's files with how much of it is downloaded,
+  /// in the order of the metainfo. Until the metadata of a torrent created
+  /// from a magnet link is fetched, no files are returned.
+  pub async fn files(&self) -> EngineResult<Vec<FileStats>> {
+    let (tx, rx) = oneshot::channel();
+    self.tx.send(Command::GetFiles(tx))?;
+    Ok(rx.await?)
+  }
+
+  /// Pauses the torrent: all its peers are disconnected, trackers are
+  /// notified, and no new connections are made until it's resumed.
+  pub fn pause(&self) -> EngineResult<()> {
+    self.tx.send(Command::Pause)?;
+    Ok(())
+  }
+
+  /// Resumes a paused torrent. Resuming a torrent that isn't paused has no
+  /// effect, and a queued torrent only starts once the engine gives it an
+  /// active slot.
+  pub fn resume(&self) -> EngineResult<()> {
+    self.tx.send(Command::Resume)?;
+    Ok(())
+  }
+
+  /// Sets the port on which the torrent is reachable from the outside, e.g.
+  /// after mapping a port on the router, so that it's announced instead of
+  /// the listen port. Trackers are re-announced the new port right away.
+  pub fn set_external_port(&self, port: Option<u16>) -> EngineResult<()> {
+    self.tx.send(Command::SetExternalPort(port))?;
+    Ok(())
+  }
+
+  /// Rebinds the torrent's listener to the given address, re-announcing the
+  /// new port to trackers. If binding fails, an [`Alert::Error`] is posted
+  /// and the torrent keeps listening on its current address.
+  ///
+  /// [`Alert::Error`]: crate::alert::Alert::Error
+  pub fn set_listen_addr(&self, addr: SocketAddr) -> EngineResult<()> {
+    self.tx.send(Command::SetListenAddr(addr))?;
+    Ok(())
+  }
+
+  /// Changes the torrent's priority while it's running. Connections already
+  /// made are kept, the new priority applies to new connections.
+  ///
+  /// The engine's queue is updated right away, so a torrent whose priority
+  /// is raised may take the active slot of a lower priority one, and one
+  /// whose priority is lowered may be queued.
+  pub fn set_priority(&self, priority: Priority) -> EngineResult<()> {
+    self.engine_tx.send(engine::Command::SetPriority {
+      id: self.id,
+      priority,
+    })?;
+    Ok(())
+  }
+
+  /// Changes the torrent's payload download and upload rate limits while it's
+  /// running, in bytes per second, which apply to all of its peers at once.
+  /// `None` removes the limit.
+  ///
+  /// A limit of zero is rejected, use [`Self::pause`] to stop transferring
+  /// instead.
+  pub fn set_rate_limits(
+    &self,
+    down: Option<u64>,
+    up: Option<u64>,
+  ) -> EngineResult<()> {
+    if down == Some(0) || up == Some(0) {
+      return Err(Error::InvalidConf("rate limits must not be zero"));
+    }
+    self.tx.send(Command::SetRateLimits { down, up })?;
+    self.record_change(Command::SetRateLimits { down, up })
+  }
+
+  /// Downloads only the pieces covering the first `len` bytes of the
+  /// torrent, e.g. to test the swarm's speed or preview the content before
+  /// committing to the full download. A paused torrent is resumed.
+  ///
+  /// Once the sample is downloaded, the torrent is paused and an
+  /// [`Alert::SampleComplete`] is posted with the measured download rate and
+  /// the availability of the torrent's pieces. Resuming the torrent then
+  /// downloads the rest of it.
+  ///
+  /// [`Alert::SampleComplete`]: crate::alert::Alert::SampleComplete
+  pub fn sample(&self, len: u64) -> EngineResult<()> {
+    if len == 0 {
+      return Err(Error::InvalidConf("sample length must not be zero"));
+    }
+    self.tx.send(Command::Sample(len))?;
+    self.record_change(Command::Sample(len))
+  }
+
+  /// Moves the torrent's files into the given directory while it's running.
+  /// An archive's directory is moved into it, while a single file is placed
+  /// directly in it.
+  ///
+  /// Once the files are moved, an [`Alert::StorageMoved`] is posted.
+  ///
+  /// [`Alert::StorageMoved`]: crate::alert::Alert::StorageMoved
+  pub fn move_storage(&self, dir: impl Into<PathBuf>) -> EngineResult<()> {
+    self.tx.send(Command::MoveStorage(dir.into()))?;
+    Ok(())
+  }
+
+  /// Sets the priority of each of the torrent's files, given in the order of
+  /// the files in the metainfo. Pieces of high priority files are downloaded
+  /// first and those of low priority files last, while skipped files are
+  /// not downloaded. The priorities may be changed at any time, taking
+  /// effect on the next pieces picked.
+  ///
+  /// If the number of priorities doesn't match the number of files, an
+  /// [`Alert::Error`] is posted.
+  ///
+  /// [`Alert::Error`]: crate::alert::Alert::Error
+  pub fn set_file_priorities(
+    &self,
+    priorities: Vec<FilePriority>,
+  ) -> EngineResult<()> {
+    self
+      .tx
+      .send(Command::SetFilePriorities(priorities.clone()))?;
+    self.record_change(Command::SetFilePriorities(priorities))
+  }
+
+  /// Asks for the piece to be downloaded within `deadline`, e.g. for
+  /// streaming. Pieces with a deadline are picked before all others, the
+  /// most urgent first, though the deadline is not otherwise enforced.
+  ///
+  /// If the index is not within the torrent, an [`Alert::Error`] is posted.
+  ///
+  /// [`Alert::Error`]: crate::alert::Alert::Error
+  pub fn set_piece_deadline(
+    &self,
+    index: PieceIndex,
+    deadline: Duration,
+  ) -> EngineResult<()> {
+    self
+      .tx
+      .send(Command::SetPieceDeadline { index, deadline })?;
+    self.record_change(Command::SetPieceDeadline { index, deadline })
+  }
+
+  /// Adds a tracker to the torrent while it's running, which is announced
+  /// to with the next periodic announces, unless the torrent is paused.
+  /// Adding a tracker the torrent already has has no effect.
+  ///
+  /// Trackers of protocols the torrent's tracker backend doesn't support are
+  /// rejected with an [`Error::UnsupportedTracker`].
+  ///
+  /// The trackers changed at runtime are kept in the torrent's resume data,
+  /// in place of the metainfo's.
+  pub fn add_tracker(&self, url: Url) -> EngineResult<()> {
+    if !self.tracker_backend.supports(&url) {
+      return Err(Error::UnsupportedTracker(url));
+    }
+    self.tx.send(Command::AddTracker(url.clone()))?;
+    self.record_change(Command::AddTracker(url))
+  }
+
+  /// Removes a tracker from the torrent while it's running, e.g. one that's
+  /// dead for good.
+  pub fn remove_tracker(&self, url: Url) -> EngineResult<()> {
+    self.tx.send(Command::RemoveTracker(url.clone()))?;
+    self.record_change(Command::RemoveTracker(url))
+  }
+
+  /// Replaces a tracker of the torrent with another one, e.g. when the
+  /// tracker moved to a new URL, as if it was removed and the new one added.
+  pub fn replace_tracker(&self, old: Url, new: Url) -> EngineResult<()> {
+    if !self.tracker_backend.supports(&new) {
+      return Err(Error::UnsupportedTracker(new));
+    }
+    self.remove_tracker(old)?;
+    self.add_tracker(new)
+  }
+
+  /// Changes the torrent's limits while it's running.
+  ///
+  /// A maximum connected peer count of zero is rejected, use
+  /// [`Self::pause`] to disconnect all peers instead.
+  pub fn set_limits(&self, limits: Limits) -> EngineResult<()> {
+    if limits.max_connected_peer_count == Some(0) {
+      return Err(Error::InvalidConf(
+        "max_connected_peer_count must be positive",
+      ));
+    }
+    self.tx.send(Command::SetLimits(limits))?;
+    self.record_change(Command::SetLimits(limits))
+  }
+
+  /// Tells the engine of the change sent to the torrent, which the engine
+  /// applies again if it restarts the torrent.
+  fn record_change(&self, cmd: Command) -> EngineResult<()> {
+    self.engine_tx.send(engine::Command::TorrentChanged {
+      id: self.id,
+      cmd: Box::new(cmd),
+    })?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{torrent, tracker::client::DefaultTrackerBackend};
+
+  use super::*;
+
+  #[test]
+  fn test_set_limits_rejects_zero_max_peers() {
+    let (tx, mut rx) = torrent::channel();
+    let (engine_tx, _engine_rx) = engine::channel();
+    let handle = TorrentHandle::new(
+      TorrentId::new(),
+      tx,
+      engine_tx,
+      Arc::new(DefaultTrackerBackend),
+    );
+
+    let limits = Limits {
+      max_connected_peer_count: Some(0),
+      ..Default::default()
+    };
+    assert!(matches!(
+      handle.set_limits(limits),
+      Err(Error::InvalidConf(_))
+    ));
+    assert!(rx.try_recv().is_err());
+
+    let limits = Limits {
+      max_connected_peer_count: Some(10),
+      ..Default::default()
+    };
+    handle.set_limits(limits).unwrap();
+    assert!(matches!(
+      rx.try_recv(),
+      Ok(Command::SetLimits(Limits {
+        max_connected_peer_count: Some(10),
+        min_requested_peer_count: None,
+      }))
+    ));
+  }
+
+  #[test]
+  fn test_add_tracker_rejects_unsupported_protocol() {
+    let (tx, mut rx) = torrent::channel();
+    let (engine_tx, _engine_rx) = engine::channel();
+    let handle = TorrentHandle::new(
+      TorrentId::new(),
+      tx,
+      engine_tx,
+      Arc::new(DefaultTrackerBackend),
+    );
+
+    let wss: Url = "wss://tracker.example.com/announce".parse().unwrap();
+    let http: Url = "http://tracker.example.com/announce".parse().unwrap();
+    assert!(matches!(
+      handle.add_tracker(wss.clone()),
+      Err(Error::UnsupportedTracker(_))
+    ));
+    assert!(matches!(
+      handle.replace_tracker(http.clone(), wss),
+      Err(Error::UnsupportedTracker(_))
+    ));
+    assert!(rx.try_recv().is_err());
+
+    handle.add_tracker(http.clone()).unwrap();
+    assert!(
+      matches!(rx.try_recv(), Ok(Command::AddTracker(url)) if url == http)
+    );
+  }
+
+  #[tokio::test]
+  async fn test_stopped_torrent_is_channel_error() {
+    let (tx, rx) = torrent::channel();
+    let (engine_tx, _engine_rx) = engine::channel();
+    let handle = TorrentHandle::new(
+      TorrentId::new(),
+      tx,
+      engine_tx,
+      Arc::new(DefaultTrackerBackend),
+    );
+    drop(rx);
+
+    assert!(matches!(handle.stats().await, Err(Error::Channel)));
+    assert!(matches!(handle.pause(), Err(Error::Channel)));
+  }
+}
@@ -0,0 +1,757 @@
+//! The phase of a torrent before its metadata, i.e. the info dictionary, is
+//! known, as is the case with torrents added from a magnet link or a bare
+//! info hash.
+//!
+//! Without metadata the torrent doesn't know its files and pieces, so it
+//! can't be set up like a regular torrent. Instead, a [`PendingTorrent`]
+//! fetches the metadata while serving the torrent's commands, after which
+//! the regular torrent is set up with the same command channel.
+//!
+//! The metadata is downloaded from the magnet link's exact sources (`xs`)
+//! and, over the metadata exchange (BEP 9), from the torrent's peers, which
+//! are found through its trackers and the DHT.
+
+use std::{
+  io,
+  net::SocketAddr,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use futures::{future, stream, SinkExt, StreamExt};
+use tokio::time;
+use tokio_util::codec::{Framed, FramedParts};
+use url::Url;
+
+use crate::{
+  conf::TorrentConf,
+  dht::{self, DhtHandle},
+  error::{PeerError, PeerResult},
+  magnet::Magnet,
+  metainfo::Metainfo,
+  peer::{
+    codec::{
+      handshake::{Handshake, HandshakeCodec},
+      message::Message,
+      peercodec::PeerCodec,
+    },
+    extension::{
+      ExtendedHandshake, MetadataDownload, MetadataMessage,
+      EXTENDED_HANDSHAKE_ID, METADATA_PIECE_LEN, UT_METADATA_ID,
+    },
+  },
+  tracker::{
+    announce::Announce,
+    client::{TrackerBackend, TrackerClient},
+    tracker::HttpClient,
+    udp::UdpConnections,
+  },
+  transport::PeerTransport,
+  PeerId, Sha1Hash, TorrentId,
+};
+
+use super::{
+  stats::{TorrentState, TorrentStats},
+  Command, Receiver,
+};
+
+/// The number of peers from which the metadata is fetched at the same time.
+const MAX_METADATA_PEER_COUNT: usize = 8;
+
+/// How long a peer is given to send the whole metadata.
+const METADATA_PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A torrent whose metadata is being fetched.
+pub struct PendingTorrent {
+  id: TorrentId,
+  magnet: Magnet,
+  cmd_rx: Receiver,
+  conf: TorrentConf,
+  listen_addr: SocketAddr,
+  is_paused: bool,
+  is_queued: bool,
+  external_port: Option<u16>,
+  sample: Option<u64>,
+  labels: Vec<String>,
+  client_id: PeerId,
+  transport: Arc<dyn PeerTransport>,
+  tracker_backend: Arc<dyn TrackerBackend>,
+  http_client: HttpClient,
+  udp_connections: UdpConnections,
+  dht: Option<DhtHandle>,
+}
+
+/// Parameters for the pending torrent constructor.
+pub struct Params {
+  pub id: TorrentId,
+  pub magnet: Magnet,
+  pub cmd_rx: Receiver,
+  pub conf: TorrentConf,
+  pub listen_addr: SocketAddr,
+  pub labels: Vec<String>,
+  /// The id with which we connect to peers to fetch the metadata.
+  pub client_id: PeerId,
+  pub transport: Arc<dyn PeerTransport>,
+  /// The backend, HTTP client and UDP connection cache with which the
+  /// magnet link's trackers are asked for peers.
+  pub tracker_backend: Arc<dyn TrackerBackend>,
+  pub http_client: HttpClient,
+  pub udp_connections: UdpConnections,
+  /// The DHT node that is asked for peers too, if the DHT is used.
+  pub dht: Option<DhtHandle>,
+}
+
+/// The fetched metadata of a torrent, along with the torrent's settings,
+/// which may have been changed while fetching.
+pub struct FetchedTorrent {
+  pub metainfo: Metainfo,
+  pub cmd_rx: Receiver,
+  pub conf: TorrentConf,
+  pub listen_addr: SocketAddr,
+  pub is_paused: bool,
+  pub is_queued: bool,
+  pub external_port: Option<u16>,
+  /// The length of the sample requested while fetching, if any.
+  pub sample: Option<u64>,
+  pub labels: Vec<String>,
+  /// The peers from the magnet link and the torrent's parameters.
+  pub peers: Vec<SocketAddr>,
+}
+
+impl PendingTorrent {
+  pub fn new(params: Params) -> Self {
+    let Params {
+      id,
+      magnet,
+      cmd_rx,
+      conf,
+      listen_addr,
+      labels,
+      client_id,
+      transport,
+      tracker_backend,
+      http_client,
+      udp_connections,
+      dht,
+    } = params;
+    Self {
+      id,
+      magnet,
+      cmd_rx,
+      conf,
+      listen_addr,
+      is_paused: false,
+      is_queued: false,
+      external_port: None,
+      sample: None,
+      labels,
+      client_id,
+      transport,
+      tracker_backend,
+      http_client,
+      udp_connections,
+      dht,
+    }
+  }
+
+  /// Fetches the torrent's metadata, serving the torrent's commands in the
+  /// meantime.
+  ///
+  /// The metadata is downloaded from the magnet link's exact sources (`xs`)
+  /// and from the peers in the magnet link and those returned by its
+  /// trackers and the DHT, whichever delivers it first. If neither works, this waits
+  /// until the torrent is shut down.
+  ///
+  /// Returns `None` if the torrent was shut down before the metadata was
+  /// fetched.
+  pub async fn fetch(mut self) -> Option<FetchedTorrent> {
+    log::info!("Torrent {} fetching metadata", self.id);
+    let start_time = Instant::now();
+
+    let sources = self.magnet.sources.clone();
+    let info_hash = self.magnet.info_hash;
+    let swarm = Swarm {
+      info_hash,
+      client_id: self.client_id,
+      transport: Arc::clone(&self.transport),
+      peers: self.magnet.peers.clone(),
+      trackers: self
+        .magnet
+        .trackers
+        .iter()
+        .filter(|url| self.tracker_backend.supports(url))
+        .map(|url| {
+          self.tracker_backend.new_client(
+            url.clone(),
+            &self.http_client,
+            &self.udp_connections,
+          )
+        })
+        .collect(),
+      port: self.external_port.unwrap_or(self.listen_addr.port()),
+      tracker_timeout: self.conf.tracker_timeout,
+      dht: self.dht.clone(),
+    };
+    let fetch = async move {
+      let from_sources = fetch_from_sources(&sources, &info_hash);
+      let from_peers = swarm.fetch();
+      tokio::pin!(from_sources, from_peers);
+      // if one of them gives up, the other may still deliver
+      tokio::select! {
+        Some(metainfo) = &mut from_sources => metainfo,
+        Some(metainfo) = &mut from_peers => metainfo,
+        else => {
+          log::warn!("No metadata source or peer available");
+          future::pending().await
+        }
+      }
+    };
+    tokio::pin!(fetch);
+
+    loop {
+      tokio::select! {
+        // paused and queued torrents don't make progress
+        metainfo = &mut fetch, if !self.is_paused && !self.is_queued => {
+          log::info!("Torrent {} metadata fetched", self.id);
+          return Some(self.into_fetched(metainfo));
+        }
+        cmd = self.cmd_rx.recv() => match cmd? {
+          Command::GetStats(result_tx) => {
+            let stats = TorrentStats {
+              start_time: Some(start_time),
+              run_duration: start_time.elapsed(),
+              state: if self.is_paused {
+                TorrentState::Paused
+              } else if self.is_queued {
+                TorrentState::Queued
+              } else {
+                TorrentState::FetchingMetadata
+              },
+              labels: self.labels.clone(),
+              ..Default::default()
+            };
+            result_tx.send(stats).ok();
+          }
+          Command::GetPeers(result_tx) => {
+            result_tx.send(Vec::new()).ok();
+          }
+          // the files are only known once the metadata is
+          Command::GetFiles(result_tx) => {
+            result_tx.send(Vec::new()).ok();
+          }
+          // without metadata there is nothing to restore the torrent from,
+          // so dropping the sender leaves it out of the session
+          Command::GetResumeData(_) => {}
+          Command::Pause => self.is_paused = true,
+          Command::Resume => self.is_paused = false,
+          Command::SetQueued(is_queued) => self.is_queued = is_queued,
+          Command::SetLimits(limits) => limits.apply(&mut self.conf),
+          Command::SetPriority(priority) => self.conf.priority = priority,
+          Command::SetConf(conf) => self.conf = conf,
+          Command::SetRateLimits { down, up } => {
+            self.conf.download_rate_limit = down;
+            self.conf.upload_rate_limit = up;
+          }
+          Command::Ping(ack_tx) => {
+            ack_tx.send(()).ok();
+          }
+          // these apply to the trackers merged into the fetched metainfo
+          Command::AddTracker(url) => {
+            if !self.magnet.trackers.contains(&url) {
+              self.magnet.trackers.push(url);
+            }
+          }
+          Command::RemoveTracker(url) => {
+            self.magnet.trackers.retain(|tracker| *tracker != url)
+          }
+          Command::SetExternalPort(port) => self.external_port = port,
+          Command::SetListenAddr(addr) => self.listen_addr = addr,
+          Command::SetLabels(labels) => self.labels = labels,
+          Command::Sample(len) => {
+            self.sample = Some(len);
+            self.is_paused = false;
+          }
+          // the files are only allocated once the metadata is known
+          Command::MoveStorage(_) => {
+            log::warn!("Cannot move torrent {} storage before metadata", self.id)
+          }
+          // the files and pieces are only known once the metadata is
+          Command::SetFilePriorities(_) | Command::SetPieceDeadline { .. } => {
+            log::warn!(
+              "Cannot prioritize torrent {} data before metadata",
+              self.id
+            )
+          }
+          Command::Shutdown => return None,
+          // the rest are sent by disk and peer sessions, which don't
+          // exist yet
+          _ => log::warn!("Unexpected torrent command before metadata"),
+        }
+      }
+    }
+  }
+
+  /// Adds the magnet link's trackers to the fetched metainfo.
+  fn into_fetched(self, mut metainfo: Metainfo) -> FetchedTorrent {
+    for tracker in self.magnet.trackers {
+      if !metainfo.trackers.contains(&tracker) {
+        metainfo.trackers.push(tracker);
+      }
+    }
+    FetchedTorrent {
+      metainfo,
+      cmd_rx: self.cmd_rx,
+      conf: self.conf,
+      listen_addr: self.listen_addr,
+      is_paused: self.is_paused,
+      is_queued: self.is_queued,
+      external_port: self.external_port,
+      sample: self.sample,
+      labels: self.labels,
+      peers: self.magnet.peers,
+    }
+  }
+}
+
+/// The peers of a torrent, from which its metadata is fetched.
+struct Swarm {
+  info_hash: Sha1Hash,
+  client_id: PeerId,
+  transport: Arc<dyn PeerTransport>,
+  /// The peers known up front, from the magnet link and the torrent's
+  /// parameters.
+  peers: Vec<SocketAddr>,
+  /// The trackers that are asked for more peers.
+  trackers: Vec<Box<dyn TrackerClient>>,
+  /// The port announced to the trackers.
+  port: u16,
+  tracker_timeout: Duration,
+  /// The DHT node that is asked for peers, if the DHT is used.
+  dht: Option<DhtHandle>,
+}
+
+impl Swarm {
+  /// Fetches the metadata from several peers at a time, returning the first
+  /// metadata that matches the info hash, or `None` if no peer sent it.
+  ///
+  /// With the DHT, its peers are looked up again after a while as long as
+  /// none sent the metadata, as the DHT may not have been joined yet.
+  async fn fetch(&self) -> Option<Metainfo> {
+    let mut peers = self.peers.clone();
+    for peer in self.find_peers().await {
+      if !peers.contains(&peer) {
+        peers.push(peer);
+      }
+    }
+    loop {
+      if let Some(metainfo) = self.fetch_from_peers(peers).await {
+        return Some(metainfo);
+      }
+      let dht = self.dht.as_ref()?;
+      time::sleep(dht::MIN_LOOKUP_INTERVAL).await;
+      peers = dht
+        .get_peers(self.info_hash, None)
+        .await
+        .unwrap_or_default();
+    }
+  }
+
+  /// Fetches the metadata from the peers, several at a time.
+  async fn fetch_from_peers(&self, peers: Vec<SocketAddr>) -> Option<Metainfo> {
+    let mut attempts = stream::iter(peers)
+      .map(|addr| async move {
+        let fetch = fetch_from_peer(
+          self.transport.as_ref(),
+          addr,
+          self.info_hash,
+          self.client_id,
+        );
+        (addr, time::timeout(METADATA_PEER_TIMEOUT, fetch).await)
+      })
+      .buffer_unordered(MAX_METADATA_PEER_COUNT);
+    while let Some((addr, result)) = attempts.next().await {
+      match result {
+        Ok(Ok(metainfo)) => {
+          log::info!("Fetched metadata from peer {}", addr);
+          return Some(metainfo);
+        }
+        Ok(Err(e)) => {
+          log::warn!("Failed to fetch metadata from peer {}: {}", addr, e)
+        }
+        Err(_) => log::warn!("Timed out fetching metadata from peer {}", addr),
+      }
+    }
+    None
+  }
+
+  /// Asks each tracker and the DHT for peers, returning those of all
+  /// trackers that responded in time and of the DHT.
+  async fn find_peers(&self) -> Vec<SocketAddr> {
+    let announces = self.trackers.iter().map(|tracker| async move {
+      let params = Announce {
+        info_hash: self.info_hash,
+        peer_id: self.client_id,
+        port: self.port,
+        ip: None,
+        ipv4: None,
+        ipv6: None,
+        downloaded: 0,
+        uploaded: 0,
+        // the torrent's length isn't known yet, but we mustn't look like a
+        // seed, to which trackers don't return other seeds
+        left: METADATA_PIECE_LEN as u64,
+        peer_count: None,
+        tracker_id: None,
+        key: None,
+        event: None,
+      };
+      match time::timeout(self.tracker_timeout, tracker.announce(params, None))
+        .await
+      {
+        Ok(Ok(resp)) if resp.failure_reason.is_none() => {
+          resp.peers.into_iter().chain(resp.peers6).collect()
+        }
+        Ok(Ok(resp)) => {
+          log::warn!(
+            "Tracker {} announce failed: {}",
+            tracker,
+            resp.failure_reason.unwrap_or_default()
+          );
+          Vec::new()
+        }
+        Ok(Err(e)) => {
+          log::warn!("Failed to announce to tracker {}: {}", tracker, e);
+          Vec::new()
+        }
+        Err(_) => {
+          log::warn!("Announce to tracker {} timed out", tracker);
+          Vec::new()
+        }
+      }
+    });
+    // we can't serve the torrent yet, so we don't announce to the DHT
+    let from_dht = async {
+      match &self.dht {
+        Some(dht) => dht
+          .get_peers(self.info_hash, None)
+          .await
+          .unwrap_or_default(),
+        None => Vec::new(),
+      }
+    };
+    let (from_trackers, from_dht) =
+      future::join(future::join_all(announces), from_dht).await;
+    let mut peers = from_trackers.concat();
+    peers.extend(from_dht);
+    peers
+  }
+}
+
+/// Connects to the peer and downloads the metadata from it over the metadata
+/// exchange, piece by piece.
+async fn fetch_from_peer(
+  transport: &dyn PeerTransport,
+  addr: SocketAddr,
+  info_hash: Sha1Hash,
+  client_id: PeerId,
+) -> PeerResult<Metainfo> {
+  log::debug!("Fetching metadata from peer {}", addr);
+  let mut socket = Framed::new(transport.connect(addr).await?, HandshakeCodec);
+  socket.send(Handshake::new(info_hash, client_id)).await?;
+  let handshake = socket
+    .next()
+    .await
+    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))??;
+  if handshake.info_hash != info_hash {
+    return Err(PeerError::InvalidInfoHash);
+  }
+  if !handshake.supports_extensions() {
+    return Err(PeerError::NoMetadata);
+  }
+
+  // keep the bytes the peer may have sent after the handshake
+  let old_parts = socket.into_parts();
+  let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
+  new_parts.read_buf = old_parts.read_buf;
+  new_parts.write_buf = old_parts.write_buf;
+  let mut socket = Framed::from_parts(new_parts);
+
+  socket
+    .send(Message::Extended {
+      id: EXTENDED_HANDSHAKE_ID,
+      payload: ExtendedHandshake::new(None).encode()?,
+    })
+    .await?;
+
+  // the peer's id for metadata messages and the download, once the peer's
+  // extension handshake arrived
+  let mut download: Option<(u8, MetadataDownload)> = None;
+  loop {
+    let msg = socket
+      .next()
+      .await
+      .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))??;
+    // other messages are of no use without the metadata
+    let Message::Extended { id, payload } = msg else {
+      continue;
+    };
+    let (peer_id, msg) = match id {
+      EXTENDED_HANDSHAKE_ID => {
+        let handshake = ExtendedHandshake::decode(&payload)?;
+        let (Some(peer_id), Some(len)) =
+          (handshake.ut_metadata_id(), handshake.metadata_size)
+        else {
+          return Err(PeerError::NoMetadata);
+        };
+        let (_, download) =
+          download.insert((peer_id, MetadataDownload::new(info_hash, len)?));
+        let piece = download.next_piece().ok_or(PeerError::NoMetadata)?;
+        (peer_id, MetadataMessage::Request { piece })
+      }
+      UT_METADATA_ID => {
+        let Some((peer_id, download)) = download.as_mut() else {
+          return Err(PeerError::InvalidExtensionMessage);
+        };
+        match MetadataMessage::decode(&payload)? {
+          MetadataMessage::Data { piece, data, .. } => {
+            if let Some(info) = download.add_piece(piece, &data)? {
+              return Metainfo::from_info(&info)
+                .map_err(|_| PeerError::InvalidMetadata);
+            }
+            let piece = download.next_piece().ok_or(PeerError::NoMetadata)?;
+            (*peer_id, MetadataMessage::Request { piece })
+          }
+          MetadataMessage::Reject { .. } => return Err(PeerError::NoMetadata),
+          // we don't have the metadata either
+          MetadataMessage::Request { piece } => {
+            (*peer_id, MetadataMessage::Reject { piece })
+          }
+        }
+      }
+      _ => continue,
+    };
+    socket
+      .send(Message::Extended {
+        id: peer_id,
+        payload: msg.encode()?,
+      })
+      .await?;
+  }
+}
+
+/// Downloads the metainfo file from each source in order, returning the
+/// first one whose info hash matches.
+async fn fetch_from_sources(
+  sources: &[Url],
+  info_hash: &Sha1Hash,
+) -> Option<Metainfo> {
+  for url in sources {
+    log::debug!("Fetching metainfo from {}", url);
+    let resp = match reqwest::get(url.clone())
+      .await
+      .and_then(|resp| resp.error_for_status())
+    {
+      Ok(resp) => resp,
+      Err(e) => {
+        log::warn!("Failed to fetch metainfo from {}: {}", url, e);
+        continue;
+      }
+    };
+    let bytes = match resp.bytes().await {
+      Ok(bytes) => bytes,
+      Err(e) => {
+        log::warn!("Failed to fetch metainfo from {}: {}", url, e);
+        continue;
+      }
+    };
+    match Metainfo::from_bytes(&bytes) {
+      Ok(metainfo) if metainfo.info_hash == *info_hash => {
+        return Some(metainfo)
+      }
+      Ok(_) => log::warn!("Metainfo from {} has a different info hash", url),
+      Err(e) => log::warn!("Invalid metainfo from {}: {}", url, e),
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use tokio::{net::TcpListener, sync::oneshot};
+
+  use super::*;
+  use crate::{
+    peer::extension::metadata_piece, tracker::client::DefaultTrackerBackend,
+    transport::TcpTransport,
+  };
+
+  fn pending_torrent(magnet: Magnet) -> (PendingTorrent, super::super::Sender) {
+    let (tx, rx) = super::super::channel();
+    let pending = PendingTorrent::new(Params {
+      id: TorrentId::new(),
+      magnet,
+      cmd_rx: rx,
+      conf: TorrentConf::default(),
+      listen_addr: "0.0.0.0:0".parse().unwrap(),
+      labels: Vec::new(),
+      client_id: [1; 20],
+      transport: Arc::new(TcpTransport::default()),
+      tracker_backend: Arc::new(DefaultTrackerBackend),
+      http_client: HttpClient::default(),
+      udp_connections: UdpConnections::default(),
+      dht: None,
+    });
+    (pending, tx)
+  }
+
+  /// Serves the metadata to the first peer that connects, as a peer that
+  /// supports the metadata exchange would.
+  async fn serve_metadata(
+    listener: TcpListener,
+    info_hash: Sha1Hash,
+    metadata: Vec<u8>,
+  ) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut socket = Framed::new(stream, HandshakeCodec);
+    let handshake = socket.next().await.unwrap().unwrap();
+    assert_eq!(handshake.info_hash, info_hash);
+    assert!(handshake.supports_extensions());
+    socket
+      .send(Handshake::new(info_hash, [2; 20]))
+      .await
+      .unwrap();
+
+    let old_parts = socket.into_parts();
+    let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
+    new_parts.read_buf = old_parts.read_buf;
+    let mut socket = Framed::from_parts(new_parts);
+    let handshake = ExtendedHandshake::new(Some(metadata.len()));
+    socket
+      .send(Message::Extended {
+        id: EXTENDED_HANDSHAKE_ID,
+        payload: handshake.encode().unwrap(),
+      })
+      .await
+      .unwrap();
+
+    let mut peer_id = None;
+    while let Some(Ok(Message::Extended { id, payload })) = socket.next().await
+    {
+      if id == EXTENDED_HANDSHAKE_ID {
+        let handshake = ExtendedHandshake::decode(&payload).unwrap();
+        // the fetching peer has no metadata to offer
+        assert_eq!(handshake.metadata_size, None);
+        peer_id = handshake.ut_metadata_id();
+        continue;
+      }
+      let MetadataMessage::Request { piece } =
+        MetadataMessage::decode(&payload).unwrap()
+      else {
+        panic!("unexpected metadata message");
+      };
+      let data = metadata_piece(&metadata, piece).unwrap();
+      let msg = MetadataMessage::Data {
+        piece,
+        total_size: metadata.len(),
+        data: Bytes::copy_from_slice(data),
+      };
+      socket
+        .send(Message::Extended {
+          id: peer_id.unwrap(),
+          payload: msg.encode().unwrap(),
+        })
+        .await
+        .unwrap();
+    }
+  }
+
+  #[tokio::test]
+  async fn should_fetch_metadata_from_peer() {
+    let bytes = std::fs::read("fixtures/debian-iso.torrent").unwrap();
+    let expected = Metainfo::from_bytes(&bytes).unwrap();
+    // the metadata spans several pieces
+    assert!(expected.info.len() > METADATA_PIECE_LEN);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = tokio::spawn(serve_metadata(
+      listener,
+      expected.info_hash,
+      expected.info.clone(),
+    ));
+    let magnet = Magnet {
+      info_hash: expected.info_hash,
+      name: None,
+      trackers: Vec::new(),
+      // the first peer is unreachable and should be skipped
+      peers: vec!["127.0.0.1:1".parse().unwrap(), addr],
+      sources: Vec::new(),
+    };
+
+    let (pending, _tx) = pending_torrent(magnet);
+    let fetched = pending.fetch().await.unwrap();
+    assert_eq!(fetched.metainfo.info_hash, expected.info_hash);
+    assert_eq!(fetched.metainfo.name, expected.name);
+    assert_eq!(fetched.metainfo.piece_len, expected.piece_len);
+    peer.await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn should_fetch_metainfo_from_source() {
+    let bytes = std::fs::read("fixtures/debian-iso.torrent").unwrap();
+    let expected = Metainfo::from_bytes(&bytes).unwrap();
+
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/t.torrent")
+      .with_body(bytes)
+      .create_async()
+      .await;
+    let tracker: Url = "http://tracker.example.com/announce".parse().unwrap();
+    let magnet = Magnet {
+      info_hash: expected.info_hash,
+      name: None,
+      trackers: vec![tracker.clone()],
+      peers: vec!["127.0.0.1:6881".parse().unwrap()],
+      // the first source is unreachable and should be skipped
+      sources: vec![
+        format!("{}/missing.torrent", server.url()).parse().unwrap(),
+        format!("{}/t.torrent", server.url()).parse().unwrap(),
+      ],
+    };
+
+    let (pending, _tx) = pending_torrent(magnet);
+    let fetched = pending.fetch().await.unwrap();
+    mock.assert_async().await;
+    assert_eq!(fetched.metainfo.info_hash, expected.info_hash);
+    assert_eq!(fetched.metainfo.name, expected.name);
+    assert!(fetched.metainfo.trackers.contains(&tracker));
+    assert_eq!(fetched.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+  }
+
+  #[tokio::test]
+  async fn should_serve_commands_while_fetching() {
+    let magnet = Magnet::parse(
+      "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+    )
+    .unwrap();
+    let (pending, tx) = pending_torrent(magnet);
+    let join_handle = tokio::spawn(pending.fetch());
+
+    let (stats_tx, stats_rx) = oneshot::channel();
+    assert!(tx.send(Command::GetStats(stats_tx)).is_ok());
+    assert_eq!(
+      stats_rx.await.unwrap().state,
+      TorrentState::FetchingMetadata
+    );
+
+    assert!(tx.send(Command::Pause).is_ok());
+    let (stats_tx, stats_rx) = oneshot::channel();
+    assert!(tx.send(Command::GetStats(stats_tx)).is_ok());
+    assert_eq!(stats_rx.await.unwrap().state, TorrentState::Paused);
+
+    assert!(tx.send(Command::Shutdown).is_ok());
+    assert!(join_handle.await.unwrap().is_none());
+  }
+}
@@ -1,51 +1,103 @@
 use std::{
-  collections::HashMap,
-  net::SocketAddr,
-  sync::Arc,
+  any::Any,
+  collections::{BTreeMap, HashMap, HashSet, VecDeque},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  panic::AssertUnwindSafe,
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+  },
   time::{Duration, Instant},
 };
 
+use futures::FutureExt;
+use rand::Rng;
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+
 use tokio::{
-  net::{TcpListener, TcpStream},
   sync::{
-    mpsc::{self, UnboundedReceiver, UnboundedSender},
-    RwLock,
+    oneshot, Mutex, OwnedMutexGuard, OwnedSemaphorePermit, RwLock, Semaphore,
   },
   task, time,
 };
+use tokio_util::sync::CancellationToken;
 
 use crate::{
   alert::{Alert, AlertSender},
   blockinfo::BlockInfo,
-  conf::TorrentConf,
-  counter::ThruputCounters,
+  channel::{self, Overflow},
+  conf::{Priority, TorrentConf, TrackerBackoffConf},
+  counter::{Counter, ThruputCounters},
+  dht::{self, DhtHandle},
   disk,
   download::PieceDownload,
+  engine,
   error::*,
+  memory::MemoryCounters,
   peer::{
     self,
+    codec::handshake::Handshake,
     session::{ConnectionState, SessionState},
     PeerSession, SessionTick,
   },
   piece_picker::PiecePicker,
+  rate_limiter::{
+    EngineRateLimiter, RateLimiter, TorrentCount, TorrentRateLimiter,
+  },
   storage_info::StorageInfo,
   tracker::{
+    self,
+    client::{TrackerBackend, TrackerClient},
     prelude::{Announce, Event},
-    tracker::Tracker,
+    tracker::{HttpClient, IpFamily},
+    udp::UdpConnections,
   },
+  transport::{PeerConnection, PeerTransport},
   Bitfield, PeerId, PieceIndex, Sha1Hash, TorrentId,
 };
 
-use self::stats::{Peers, PieceStats, ThruputStats, TorrentStats};
+use self::{
+  choker::{auto_slot_count, Candidate, Choker},
+  peer_pool::PeerPool,
+  peer_priority::{is_canonically_first, peer_priority},
+  stats::{
+    EndgameStats, FileStats, PeerSessionStats, Peers, PieceStats, SampleReport,
+    ThruputStats, TorrentState, TorrentStats,
+  },
+};
 
+mod choker;
+pub mod handle;
+pub mod metadata;
+mod peer_pool;
+mod peer_priority;
 pub mod stats;
 
 /// The channel for communication with torrent.
-pub type Sender = UnboundedSender<Command>;
+pub type Sender = channel::Sender<Command>;
 
 /// The type of channel on which a torrent can listen for
 /// block write completion.
-pub type Receiver = UnboundedReceiver<Command>;
+///
+/// The torrent holds its end of the channel locked, so that the channel can
+/// outlive the torrent's task: a torrent restarted after it stalled takes
+/// over the same channel, and the senders of its handles keep working.
+pub type Receiver = OwnedMutexGuard<channel::Receiver<Command>>;
+
+/// The number of peer state updates that may be queued in a torrent's
+/// channel, past which they are dropped until the torrent catches up.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Creates the channel on which a torrent receives commands.
+pub(crate) fn channel() -> (Sender, Receiver) {
+  let (tx, rx) = channel::channel(CHANNEL_CAPACITY, Overflow::Drop);
+  let rx = Arc::new(Mutex::new(rx))
+    .try_lock_owned()
+    .expect("new lock is free");
+  (tx, rx)
+}
 
 /// The types of message that torrent can receive from parts of
 /// the engine.
@@ -63,9 +115,115 @@ pub enum Command {
   /// A message sent only once, after the peer has been connected.
   PeerConnected { addr: SocketAddr, id: PeerId },
 
+  /// Sent when the peer names its client in the extension handshake.
+  PeerClient { addr: SocketAddr, client: String },
+
   /// Peer sessions periodically send this message when they have a state change.
   PeerState { addr: SocketAddr, info: SessionTick },
 
+  /// Sent by disk when the pieces it holds back while writes are paused
+  /// reach their limit, after which no more blocks are to be requested, and
+  /// again once they're written.
+  WriteStalled(bool),
+
+  /// Sent by disk after checking the torrent's existing data, with the
+  /// pieces that are present and valid.
+  PiecesChecked(Bitfield),
+
+  /// Returns the torrent's current statistics via the sender.
+  GetStats(oneshot::Sender<TorrentStats>),
+
+  /// Returns the statistics of each connected peer via the sender.
+  GetPeers(oneshot::Sender<Vec<PeerSessionStats>>),
+
+  /// Returns each of the torrent's files with its progress via the sender.
+  GetFiles(oneshot::Sender<Vec<FileStats>>),
+
+  /// Returns the state from which the torrent can be restored in a later
+  /// session via the sender.
+  GetResumeData(oneshot::Sender<ResumeData>),
+
+  /// Sent by the engine's watchdog, acknowledged right away via the sender to
+  /// show that the torrent's event loop is not stuck.
+  Ping(oneshot::Sender<()>),
+
+  /// Changes the torrent's priority at runtime.
+  SetPriority(Priority),
+
+  /// Disconnects all peers and stops announcing to trackers until resumed.
+  Pause,
+
+  /// Resumes a paused torrent. A queued torrent stays stopped until the
+  /// engine dequeues it.
+  Resume,
+
+  /// Sent by the engine to stop the torrent while it has no active slot,
+  /// and to start it again once it has one. Unlike pausing, this is not the
+  /// user's choice, so whether the torrent is paused is left as it is.
+  SetQueued(bool),
+
+  /// Changes the torrent's limits at runtime.
+  SetLimits(Limits),
+
+  /// Adds the tracker, unless the torrent already has it.
+  AddTracker(Url),
+
+  /// Removes the tracker, if the torrent has it.
+  RemoveTracker(Url),
+
+  /// Sets the port through which we're reachable from the outside, if it
+  /// differs from the port we listen on (e.g. due to port mapping). `None`
+  /// reverts to announcing the listen port.
+  SetExternalPort(Option<u16>),
+
+  /// Rebinds the torrent's listener to the new address.
+  SetListenAddr(SocketAddr),
+
+  /// Sent when a lookup in the DHT is done, with the peers it found.
+  DhtPeers(Vec<SocketAddr>),
+
+  /// Sent when a peer that connected to us sent a handshake for the
+  /// torrent, after which a session may be started with it.
+  InboundHandshake {
+    addr: SocketAddr,
+    socket: PeerConnection,
+    handshake: Handshake,
+  },
+
+  /// Replaces the torrent's configuration at runtime, e.g. when the engine's
+  /// default configuration is reloaded. Peer sessions already running keep
+  /// their session configuration.
+  SetConf(TorrentConf),
+
+  /// Changes the torrent's payload rate limits at runtime, in bytes per
+  /// second. `None` removes the limit.
+  SetRateLimits { down: Option<u64>, up: Option<u64> },
+
+  /// Downloads only the pieces covering the first given number of bytes,
+  /// after which the torrent reports its measurements and pauses.
+  Sample(u64),
+
+  /// Replaces the labels attached to the torrent.
+  SetLabels(Vec<String>),
+
+  /// Sets the priority of each of the torrent's files, in the order of the
+  /// files in the metainfo.
+  SetFilePriorities(Vec<FilePriority>),
+
+  /// Asks for the piece to be downloaded within the given time, before any
+  /// other piece.
+  SetPieceDeadline {
+    index: PieceIndex,
+    deadline: Duration,
+  },
+
+  /// Moves the torrent's files into the new directory.
+  MoveStorage(PathBuf),
+
+  /// Sent by the disk task when the torrent's files were moved into the
+  /// directory, or they could not be moved.
+  StorageMoved(Result<PathBuf, MoveError>),
+
   /// Graceful shutdown the torrent.
   ///
   /// This command tells all active peer sessions of torrent to do the same,
@@ -82,6 +240,122 @@ pub struct PieceCompletion {
   pub is_valid: bool,
 }
 
+/// The limits of a torrent that may be changed while it's running.
+///
+/// Limits that are `None` are left unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+  /// See [`TorrentConf::min_requested_peer_count`].
+  pub min_requested_peer_count: Option<usize>,
+  /// See [`TorrentConf::max_connected_peer_count`]. If lowered below the
+  /// number of currently connected peers, the surplus peers are
+  /// disconnected.
+  pub max_connected_peer_count: Option<usize>,
+}
+
+impl Limits {
+  /// Sets the limits that are not `None` in the configuration.
+  pub(crate) fn apply(&self, conf: &mut TorrentConf) {
+    if let Some(min_requested_peer_count) = self.min_requested_peer_count {
+      conf.min_requested_peer_count = min_requested_peer_count;
+    }
+    if let Some(max_connected_peer_count) = self.max_connected_peer_count {
+      conf.max_connected_peer_count = max_connected_peer_count;
+    }
+  }
+}
+
+/// How important it is to download a file of the torrent.
+///
+/// Pieces of high priority files are downloaded before the rest, and those
+/// of low priority files after the rest, while skipped files are not
+/// downloaded at all, except for the pieces they share with wanted files.
+///
+/// Only the wanted files count towards what's left to download, so a
+/// torrent is complete once it has all of them.
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  Default,
+  PartialEq,
+  Eq,
+  PartialOrd,
+  Ord,
+  Hash,
+  Serialize,
+  Deserialize,
+)]
+pub enum FilePriority {
+  Skip,
+  Low,
+  #[default]
+  Normal,
+  High,
+}
+
+/// The state of a torrent that is saved with the engine's session, from
+/// which the torrent can be restored after a restart.
+#[derive(Debug, Clone, Default)]
+pub struct ResumeData {
+  /// The info hash of the torrent.
+  pub info_hash: Sha1Hash,
+  /// The bencoded metainfo of the torrent.
+  pub metainfo: Vec<u8>,
+  /// The configuration of the torrent, including any changed limits.
+  pub conf: TorrentConf,
+  /// The pieces we have, or `None` if the torrent's data hadn't been checked
+  /// yet, in which case it's checked on restoring.
+  pub own_pieces: Option<Bitfield>,
+  /// The total payload bytes downloaded.
+  pub downloaded: u64,
+  /// The total payload bytes uploaded.
+  pub uploaded: u64,
+  /// The total time the torrent has been running.
+  pub run_duration: Duration,
+  /// Whether the torrent was paused by the user.
+  pub is_paused: bool,
+  /// The labels attached to the torrent.
+  pub labels: Vec<String>,
+  /// The pieces completed most recently, oldest first. If the engine didn't
+  /// shut down cleanly these are verified again on restoring, as their data
+  /// may not have been written to disk.
+  pub recent_pieces: Vec<PieceIndex>,
+  /// The addresses of the peers we were connected to or could connect to,
+  /// used when the torrent is handed off to another engine. These are not
+  /// saved in sessions, as they go stale by the time the session is
+  /// restored.
+  pub peers: Vec<SocketAddr>,
+  /// The tracker ids the trackers gave us, by their announce URLs, which are
+  /// sent with every announce to them.
+  pub tracker_ids: BTreeMap<String, String>,
+  /// The key sent with announces, by which trackers recognize us across IP
+  /// changes, or `None` if the torrent didn't have one yet.
+  pub announce_key: Option<u32>,
+  /// The torrent's trackers, which replace the metainfo's, as they may have
+  /// been added or removed at runtime, or come from the magnet link the
+  /// torrent was added with. It's `None` in sessions saved before they were
+  /// always saved, in which case the metainfo's trackers are used.
+  pub trackers: Option<Vec<Url>>,
+  /// The directory holding the torrent's files, which differs from the
+  /// engine's download directory if the storage was moved. It's `None` in
+  /// sessions saved before it was, in which case the files are in the
+  /// engine's download directory.
+  pub download_dir: Option<PathBuf>,
+}
+
+/// The changes made to a running torrent that aren't kept in its resume
+/// data, which the engine applies again if it restarts the torrent.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RuntimeChanges {
+  /// The priorities of the torrent's files, if they were set.
+  pub file_priorities: Option<Vec<FilePriority>>,
+  /// The deadlines of the pieces, by their index.
+  pub deadlines: HashMap<PieceIndex, Instant>,
+  /// The length of the sample being downloaded, if any.
+  pub sample: Option<u64>,
+}
+
 /// Information and methods shared with peer sessions in the torrent.
 ///
 /// This type contains fields that need to be read or updated by peer sessions.
@@ -104,7 +378,9 @@ pub struct TorrentContext {
 
   /// The piece picker picks the next most optimal piece to download and
   /// is shared by all peers in a torrent.
-  pub piece_picker: Arc<RwLock<PiecePicker>>,
+  ///
+  /// A seed has nothing to pick, so it has no piece picker.
+  pub piece_picker: Arc<RwLock<Option<PiecePicker>>>,
   /// These are the active piece downloads in which the peer sessions in this
   /// torrent are participating.
   ///
@@ -114,7 +390,22 @@ pub struct TorrentContext {
   /// Peer sessions may be run on different threads, any of which may read and
   /// write to this map and to the pieces in the map. Thus we need to read
   /// write lock on both.
-  pub downloads: RwLock<HashMap<PieceIndex, RwLock<PieceDownload>>>,
+  ///
+  /// Like the piece picker, this is `None` for a seed.
+  pub downloads: RwLock<Option<HashMap<PieceIndex, RwLock<PieceDownload>>>>,
+  /// Whether we have all pieces of the torrent, and so neither a piece
+  /// picker nor downloads.
+  ///
+  /// Peer sessions of a seed run in upload-only mode, in which they neither
+  /// consult the piece picker nor the downloads, so this is kept outside of
+  /// their locks.
+  pub(crate) is_seed: AtomicBool,
+  /// Whether we have all pieces but those of only skipped files (BEP 21),
+  /// which the sessions tell peers in the extension handshake.
+  pub(crate) is_partial_seed: AtomicBool,
+  /// Whether disk holds back so many pieces that no more blocks are to be
+  /// requested until they're written.
+  pub(crate) is_write_stalled: AtomicBool,
 
   /// The channel on which to post alerts to user.
   pub alert_tx: AlertSender,
@@ -125,20 +416,116 @@ pub struct TorrentContext {
 
   /// Info about the torrent's storage (piece length, download length, etc).
   pub storage: StorageInfo,
+  /// The bencoded info dictionary, which is sent to peers that request the
+  /// torrent's metadata (BEP 9).
+  pub metadata: Vec<u8>,
+
+  /// The DHT node, unless the torrent is private or the DHT is not used,
+  /// whose port is told to peers that support the DHT.
+  pub dht: Option<DhtHandle>,
+
+  /// The engine-wide budget of peer connections, shared by all torrents.
+  /// Each peer session holds a permit for as long as it runs.
+  pub connection_permits: Arc<Semaphore>,
+  /// The total number of connection permits, i.e. the engine's connected
+  /// peer limit, which the engine may change at runtime.
+  pub connection_permit_count: Arc<AtomicUsize>,
+
+  /// How connections with the torrent's peers are established.
+  pub transport: Arc<dyn PeerTransport>,
+
+  /// The limiters of the torrent's payload download and upload rates, shared
+  /// by its peer sessions so that the limits apply to the whole torrent,
+  /// which also draw from the engine's limiters.
+  pub(crate) download_limiter: TorrentRateLimiter,
+  pub(crate) upload_limiter: TorrentRateLimiter,
+
+  /// The engine-wide memory counters, to which the peer sessions charge the
+  /// blocks they hold back.
+  pub(crate) memory: Arc<MemoryCounters>,
+}
+
+impl TorrentContext {
+  /// Returns whether we have all pieces of the torrent.
+  pub(crate) fn is_seed(&self) -> bool {
+    self.is_seed.load(Ordering::Acquire)
+  }
+
+  /// Returns whether we have all pieces but those of only skipped files.
+  pub(crate) fn is_partial_seed(&self) -> bool {
+    self.is_partial_seed.load(Ordering::Acquire)
+  }
+
+  /// Returns the pieces we have, which for a seed are all of them.
+  pub(crate) async fn own_pieces(&self) -> Bitfield {
+    match &*self.piece_picker.read().await {
+      Some(piece_picker) => piece_picker.own_pieces().clone(),
+      None => Bitfield::repeat(true, self.storage.piece_count),
+    }
+  }
+
+  /// Returns the number of bytes we have of each file.
+  pub(crate) async fn completed_file_bytes(&self) -> Vec<u64> {
+    match &*self.piece_picker.read().await {
+      Some(piece_picker) => {
+        self.storage.completed_file_bytes(piece_picker.own_pieces())
+      }
+      None => self.storage.files.iter().map(|file| file.len).collect(),
+    }
+  }
+
+  /// Returns whether no more blocks are to be requested until disk writes
+  /// the pieces it holds back.
+  pub(crate) fn is_write_stalled(&self) -> bool {
+    self.is_write_stalled.load(Ordering::Acquire)
+  }
 }
 
 /// Parameters for the torrent constructor.
 pub struct Params {
   pub id: TorrentId,
+  /// The torrent's command channel, created in advance so that commands may
+  /// be sent to the torrent before it's started.
+  pub cmd_tx: Sender,
+  pub cmd_rx: Receiver,
   pub disk_tx: disk::Sender,
   pub info_hash: Sha1Hash,
   pub storage_info: StorageInfo,
-  pub own_pieces: Bitfield,
-  pub trackers: Vec<Tracker>,
+  pub trackers: Vec<Box<dyn TrackerClient>>,
+  /// The backend, HTTP client and UDP connection cache with which the
+  /// clients of the trackers added at runtime are created, the same as the
+  /// initial trackers'.
+  pub tracker_backend: Arc<dyn TrackerBackend>,
+  pub http_client: HttpClient,
+  pub udp_connections: UdpConnections,
   pub client_id: PeerId,
   pub listen_addr: SocketAddr,
   pub conf: TorrentConf,
   pub alert_tx: AlertSender,
+  pub engine_tx: engine::Sender,
+  pub connection_permits: Arc<Semaphore>,
+  pub connection_permit_count: Arc<AtomicUsize>,
+  pub memory: Arc<MemoryCounters>,
+  /// The engine's download and upload rate limiters, which cap the total
+  /// rates of all torrents.
+  pub(crate) engine_download_limiter: EngineRateLimiter,
+  pub(crate) engine_upload_limiter: EngineRateLimiter,
+  /// The number of torrents in the engine, among which its limits are split.
+  pub(crate) engine_torrent_count: TorrentCount,
+  pub transport: Arc<dyn PeerTransport>,
+  /// The bencoded metainfo, kept for the torrent's resume data.
+  pub raw_metainfo: Vec<u8>,
+  /// The bencoded info dictionary of the metainfo.
+  pub metadata: Vec<u8>,
+  /// The DHT node through which the torrent finds peers, unless it's private
+  /// or the DHT is not used.
+  pub dht: Option<DhtHandle>,
+  /// The totals and run time restored from a previous session, if any.
+  pub resume: Option<ResumeData>,
+  pub labels: Vec<String>,
+  /// Cancelled when the torrent is told to shut down, which may be while
+  /// it's waiting on a tracker.
+  pub shutdown_token: CancellationToken,
 }
 
 /// Represents a torrent upload or download
@@ -150,8 +537,16 @@ pub struct Params {
 pub struct Torrent {
   /// The peers in this torrent.
   peers: HashMap<SocketAddr, PeerSessionEntity>,
-  /// The peers returned by tracker to which we can connect.
-  available_peers: Vec<SocketAddr>,
+  /// The peers we know of from trackers, the DHT, the user, or a previous
+  /// run, which are connected to and reconnected from here.
+  peer_pool: PeerPool,
+  /// The number of times the sessions with each peer panicked.
+  session_panic_counts: HashMap<SocketAddr, usize>,
+  /// Limits the peers that connected to us whose handshake is being read.
+  handshake_permits: Arc<Semaphore>,
+  /// The number of pieces failing their hash blamed on each IP, which is
+  /// banned after too many.
+  hash_failure_counts: HashMap<IpAddr, u32>,
   /// Information that is shared with peer sessions.
   ctx: Arc<TorrentContext>,
   /// The port on which other entities in the engine send this torrent
@@ -162,6 +557,11 @@ pub struct Torrent {
   cmd_rx: Receiver,
   /// The trackers we can announce to.
   trackers: Vec<TrackerEntry>,
+  /// The backend, HTTP client and UDP connection cache for the trackers
+  /// added at runtime.
+  tracker_backend: Arc<dyn TrackerBackend>,
+  http_client: HttpClient,
+  udp_connections: UdpConnections,
 
   /// The address on which torrent should listen for new peers.
   listen_addr: SocketAddr,
@@ -189,9 +589,76 @@ pub struct Torrent {
   /// Measure various transfer statistics.
   counters: ThruputCounters,
 
+  /// The end game request overhead of all sessions, summed as the sessions
+  /// report it.
+  endgame: EndgameStats,
+
+  /// The priority of each file of the torrent, which also determines the
+  /// wanted files whose progress is reported.
+  file_priorities: Vec<FilePriority>,
+
+  /// The files that were wanted, i.e. not skipped, when their download was
+  /// last completed, so that completing the same files again, e.g. after
+  /// skipping a file and then wanting it again, isn't told again.
+  completed_wanted_files: Option<Vec<bool>>,
+
+  /// The port announced to trackers instead of the listen port, if set.
+  external_port: Option<u16>,
+
+  /// The only IP family we're reachable over, as found when last announcing,
+  /// or `None` if it's both or neither. Peers of this family are connected
+  /// to first.
+  reachable_family: Option<IpFamily>,
+
+  /// The host's global IPv4 and IPv6 addresses, as found along with the
+  /// reachable family, so that the interfaces aren't enumerated again with
+  /// each round of connecting to peers.
+  global_addrs: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+
+  /// The IP address the trackers see us by, as last reported by one of
+  /// them. It's announced to trackers when the host doesn't know its own
+  /// address of that family, e.g. behind a NAT, and kept for other sources of
+  /// peers that need to tell our address apart.
+  external_ip: Option<IpAddr>,
+
+  /// When the torrent last looked up its peers in the DHT.
+  last_dht_lookup_time: Option<Instant>,
+
+  /// Decides which of the interested peers are unchoked.
+  choker: Choker,
+
+  /// The key sent with announces, generated when the torrent is first added
+  /// and kept in its resume data.
+  announce_key: u32,
+
+  /// Cancelled when the torrent is told to shut down, which abandons the
+  /// announces in flight so that a hung tracker doesn't hold up the
+  /// shutdown.
+  shutdown_token: CancellationToken,
+
+  /// Whether the torrent is waiting for disk to check its existing data, in
+  /// which case, like when paused, it doesn't connect to peers.
+  is_checking: bool,
+
+  /// Whether the torrent is paused by the user, in which case it has no
+  /// peers, doesn't accept new connections, and doesn't announce to
+  /// trackers.
+  is_paused: bool,
+
+  /// Whether the torrent is queued by the engine, as there is no free active
+  /// slot for it, which stops it the same way as pausing.
+  is_queued: bool,
+
   /// The configuration of this particular torrent.
   conf: TorrentConf,
 
+  /// The channel on which the engine is notified of the torrent completing,
+  /// so that it can manage its queue.
+  engine_tx: engine::Sender,
+
+  /// The bencoded metainfo of the torrent, saved with its resume data.
+  raw_metainfo: Vec<u8>,
+
   /// If `TorrentAlertConf::latest_completed_pieces` alert type is set,
   /// each round the torrent collects the pieces that were downloaded,
   /// sends them to peer as an alert, and resets the list.
@@ -199,6 +666,43 @@ pub struct Torrent {
   /// This is set to some if the configuration is enabled, and set to
   /// none if disabled.
   completed_pieces: Option<Vec<PieceIndex>>,
+
+  /// Whether the [`Alert::FilePlayable`] alert of each file was posted, if
+  /// [`TorrentAlertConf::playable_prefix`] is set.
+  ///
+  /// [`TorrentAlertConf::playable_prefix`]:
+  /// crate::conf::TorrentAlertConf::playable_prefix
+  playable_files: Vec<bool>,
+
+  /// Whether a piece was given a deadline, i.e. the torrent is streamed,
+  /// which is the only case in which its files are told playable.
+  is_streaming: bool,
+
+  /// The sample being downloaded, if the torrent was asked to only download
+  /// its start.
+  sample: Option<Sample>,
+
+  /// The labels the user attached to the torrent, e.g. to group torrents.
+  labels: Vec<String>,
+
+  /// The pieces completed most recently, oldest first, saved in the resume
+  /// data.
+  recent_pieces: VecDeque<PieceIndex>,
+
+  /// The directory holding the torrent's files, which changes when its
+  /// storage is moved.
+  download_dir: PathBuf,
+}
+
+/// The state of a torrent's sample download.
+struct Sample {
+  /// The number of pieces in the sample, starting with the first piece.
+  piece_count: usize,
+  /// When the sample was started, or `None` while the torrent's data is
+  /// still being checked.
+  start_time: Option<Instant>,
+  /// The total payload bytes downloaded when the sample was started.
+  start_downloaded: u64,
 }
 
 impl Torrent {
@@ -208,91 +712,154 @@ impl Torrent {
   ///
   /// This constructor only initializes the torrent components but does not
   /// actually start it. See [`Self::start`]
-  pub fn new(params: Params) -> (Self, Sender) {
+  pub fn new(params: Params) -> Self {
     let Params {
       id,
+      cmd_tx,
+      cmd_rx,
       disk_tx,
       info_hash,
       storage_info,
-      own_pieces,
       trackers,
+      tracker_backend,
+      http_client,
+      udp_connections,
       client_id,
       listen_addr,
       conf,
       alert_tx,
+      engine_tx,
+      connection_permits,
+      connection_permit_count,
+      memory,
+      engine_download_limiter,
+      engine_upload_limiter,
+      engine_torrent_count,
+      transport,
+      raw_metainfo,
+      metadata,
+      dht,
+      resume,
+      labels,
+      shutdown_token,
     } = params;
 
-    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-    let piece_picker = PiecePicker::new(own_pieces);
-    let trackers = trackers.into_iter().map(TrackerEntry::new).collect();
+    // until the existing data is checked, we assume we have nothing
+    let piece_picker =
+      PiecePicker::new(Bitfield::repeat(false, storage_info.piece_count));
+    let mut trackers: Vec<_> =
+      trackers.into_iter().map(TrackerEntry::new).collect();
+    let file_count = storage_info.files.len();
+    let file_priorities = vec![FilePriority::default(); file_count];
+    let download_dir = storage_info.download_dir.clone();
     let completed_pieces = if conf.alerts.completed_pieces {
       Some(Vec::new())
     } else {
       None
     };
 
-    (
-      Self {
-        peers: HashMap::new(),
-        available_peers: Vec::new(),
-        ctx: Arc::new(TorrentContext {
-          id,
-          info_hash,
-          client_id,
-          cmd_tx: cmd_tx.clone(),
-          piece_picker: Arc::new(RwLock::new(piece_picker)),
-          downloads: RwLock::new(HashMap::new()),
-          alert_tx,
-          disk_tx,
-          storage: storage_info,
-        }),
-        start_time: None,
-        run_duration: Duration::default(),
-        cmd_rx,
-        trackers,
-        in_endgame: false,
-        counters: Default::default(),
-        listen_addr,
-        conf,
-        completed_pieces,
-      },
-      cmd_tx,
-    )
+    // carry over the totals of the previous session
+    let mut counters = ThruputCounters::default();
+    let mut run_duration = Duration::default();
+    let mut announce_key = None;
+    if let Some(resume) = resume {
+      announce_key = resume.announce_key;
+      counters.payload.down = Counter::with_total(resume.downloaded);
+      counters.payload.up = Counter::with_total(resume.uploaded);
+      run_duration = resume.run_duration;
+      for tracker in trackers.iter_mut() {
+        tracker.id = resume
+          .tracker_ids
+          .get(tracker.client.url().as_str())
+          .cloned();
+      }
+    }
+
+    Self {
+      peers: HashMap::new(),
+      peer_pool: PeerPool::default(),
+      session_panic_counts: HashMap::new(),
+      handshake_permits: Arc::new(Semaphore::new(MAX_PENDING_HANDSHAKE_COUNT)),
+      hash_failure_counts: HashMap::new(),
+      ctx: Arc::new(TorrentContext {
+        id,
+        info_hash,
+        client_id,
+        cmd_tx,
+        piece_picker: Arc::new(RwLock::new(Some(piece_picker))),
+        downloads: RwLock::new(Some(HashMap::new())),
+        is_seed: AtomicBool::new(false),
+        is_partial_seed: AtomicBool::new(false),
+        is_write_stalled: AtomicBool::new(false),
+        alert_tx,
+        disk_tx,
+        storage: storage_info,
+        metadata,
+        dht,
+        connection_permits,
+        connection_permit_count,
+        transport,
+        download_limiter: TorrentRateLimiter::new(
+          RateLimiter::new(conf.download_rate_limit),
+          engine_download_limiter,
+          Arc::clone(&engine_torrent_count),
+          conf.priority,
+        ),
+        upload_limiter: TorrentRateLimiter::new(
+          RateLimiter::new(conf.upload_rate_limit),
+          engine_upload_limiter,
+          engine_torrent_count,
+          conf.priority,
+        ),
+        memory,
+      }),
+      start_time: None,
+      run_duration,
+      cmd_rx,
+      trackers,
+      tracker_backend,
+      http_client,
+      udp_connections,
+      in_endgame: false,
+      counters,
+      endgame: EndgameStats::default(),
+      file_priorities,
+      completed_wanted_files: None,
+      external_port: None,
+      reachable_family: None,
+      global_addrs: tracker::global_addrs(),
+      external_ip: None,
+      last_dht_lookup_time: None,
+      choker: Choker::default(),
+      announce_key: announce_key.unwrap_or_else(rand::random),
+      shutdown_token,
+      is_checking: true,
+      is_paused: false,
+      is_queued: false,
+      listen_addr,
+      conf,
+      engine_tx,
+      raw_metainfo,
+      completed_pieces,
+      playable_files: vec![false; file_count],
+      is_streaming: false,
+      sample: None,
+      labels,
+      recent_pieces: VecDeque::new(),
+      download_dir,
+    }
   }
 
   pub async fn start(&mut self, peers: &[SocketAddr]) -> TorrentResult<()> {
     log::info!("Starting torrent");
 
-    self.available_peers.extend_from_slice(peers);
+    for addr in peers {
+      self.peer_pool.add(*addr);
+    }
 
     // record the torrent start time.
     self.start_time = Some(Instant::now());
 
-    // if the torrent is a seed, don't send the started event,
-    // just an empty announce.
-    let tracker_event =
-      if self.ctx.piece_picker.read().await.missing_piece_count() == 0 {
-        None
-      } else {
-        Some(Event::Started)
-      };
-
-    if let Err(e) = self
-      .announce_to_trackers(Instant::now(), tracker_event)
-      .await
-    {
-      // this is a torrent error, not a tracker error,
-      // as that is handled inside the function.
-      self
-        .ctx
-        .alert_tx
-        .send(Alert::Error(Error::Torrent {
-          id: self.ctx.id,
-          error: e,
-        }))
-        .ok();
-    }
-
     if let Err(e) = self.run().await {
       // send alert of torrent failure to user
       self
@@ -312,7 +879,7 @@ impl Torrent {
     let mut tick_timer = time::interval(Duration::from_secs(1));
     let mut last_tick_time = None;
 
-    let listener = TcpListener::bind(&self.listen_addr).await?;
+    let mut listener = self.ctx.transport.bind(self.listen_addr).await?;
 
     // the bind port may have be 0, so we need to get the actually
     // port in use.
@@ -320,6 +887,12 @@ impl Torrent {
 
     loop {
       tokio::select! {
+          // the engine cancels the token on its own when it restarts the
+          // torrent, as the torrent shares its channel with its successor
+          _ = self.shutdown_token.cancelled() => {
+              self.shutdown().await?;
+              break;
+          }
           trick_time = tick_timer.tick() => {
               self.tick(&mut last_tick_time, trick_time.into_std()).await?;
           }
@@ -334,18 +907,7 @@ impl Torrent {
                       continue;
                   }
               };
-              log::info!(
-                  "New connection {:?}",
-                  addr
-              );
-
-              // start inbound session
-              let (session, tx) = PeerSession::new(
-                  Arc::clone(&self.ctx),
-                  addr,
-              );
-              self.peers.insert(addr, PeerSessionEntity::start_inbound(socket, session, tx));
-              self.ctx.piece_picker.write().await.increase_peer_count();
+              self.accept_connection(socket, addr);
           }
           Some(cmd) = self.cmd_rx.recv() => {
               match cmd {
@@ -379,18 +941,120 @@ impl Torrent {
                       // to stop torrent and send an alert to the API consumer.
                   },
                   Command::PeerConnected { addr, id } => {
+                      self.handle_peer_connected(addr, id);
+                  },
+                  Command::PeerClient { addr, client } => {
                       if let Some(peer) = self.peers.get_mut(&addr) {
-                          log::debug!(
-                              "Peer {} connected with client '{}', \
-                              updating state",
-                              addr,
-                              String::from_utf8_lossy(&id)
-                          );
-                          peer.id = Some(id);
+                          peer.client = Some(client);
                       }
                   },
                   Command::PeerState { addr, info } => {
-                      self.handle_peer_state_change(addr, info).await;
+                      self.handle_peer_state_change(addr, info).await?;
+                  },
+                  Command::PiecesChecked(own_pieces) => {
+                      self.handle_pieces_checked(own_pieces).await?;
+                  },
+                  Command::GetStats(result_tx) => {
+                      // the caller may have given up waiting
+                      result_tx.send(self.build_stats().await).ok();
+                  },
+                  Command::GetPeers(result_tx) => {
+                      result_tx.send(self.peer_stats()).ok();
+                  },
+                  Command::GetFiles(result_tx) => {
+                      let completed_file_bytes =
+                          self.ctx.completed_file_bytes().await;
+                      result_tx.send(self.file_stats(completed_file_bytes)).ok();
+                  },
+                  Command::GetResumeData(result_tx) => {
+                      result_tx.send(self.resume_data().await).ok();
+                  },
+                  Command::Pause => {
+                      self.pause().await?;
+                  },
+                  Command::Resume => {
+                      self.resume().await?;
+                  },
+                  Command::SetQueued(is_queued) => {
+                      self.set_queued(is_queued).await?;
+                  },
+                  Command::Ping(ack_tx) => {
+                      ack_tx.send(()).ok();
+                  },
+                  Command::WriteStalled(is_stalled) => {
+                      log::info!("Write stalled: {}", is_stalled);
+                      self.ctx
+                          .is_write_stalled
+                          .store(is_stalled, Ordering::Release);
+                  },
+                  Command::SetPriority(priority) => {
+                      log::info!("Setting priority to {:?}", priority);
+                      self.conf.priority = priority;
+                      self.ctx.download_limiter.set_priority(priority);
+                      self.ctx.upload_limiter.set_priority(priority);
+                  },
+                  Command::SetLimits(limits) => {
+                      self.set_limits(limits);
+                  },
+                  Command::SetRateLimits { down, up } => {
+                      self.set_rate_limits(down, up);
+                  },
+                  Command::SetConf(conf) => {
+                      self.set_conf(conf);
+                  },
+                  Command::AddTracker(url) => self.add_tracker(url),
+                  Command::DhtPeers(peers) => self.add_dht_peers(peers),
+                  Command::InboundHandshake { addr, socket, handshake } => {
+                      self.handle_inbound_handshake(addr, socket, handshake)
+                          .await;
+                  }
+                  Command::RemoveTracker(url) => self.remove_tracker(&url),
+                  Command::SetExternalPort(port) => {
+                      self.external_port = port;
+                      self.reannounce_port().await?;
+                  },
+                  Command::SetListenAddr(addr) => {
+                      match self.ctx.transport.bind(addr).await.and_then(|l| {
+                          let addr = l.local_addr()?;
+                          Ok((l, addr))
+                      }) {
+                          Ok((new_listener, addr)) => {
+                              log::info!("Rebound listener to {}", addr);
+                              listener = new_listener;
+                              self.listen_addr = addr;
+                              self.reannounce_port().await?;
+                          }
+                          Err(e) => {
+                              // keep listening on the old address
+                              log::warn!("Failed to rebind listener to {}: {}", addr, e);
+                              self.ctx.alert_tx.send(Alert::Error(Error::Torrent {
+                                  id: self.ctx.id,
+                                  error: e.into(),
+                              })).ok();
+                          }
+                      }
+                  },
+                  Command::SetLabels(labels) => {
+                      log::info!("Setting labels to {:?}", labels);
+                      self.labels = labels;
+                  },
+                  Command::Sample(len) => {
+                      self.start_sample(len).await?;
+                  },
+                  Command::SetFilePriorities(priorities) => {
+                      self.set_file_priorities(priorities).await?;
+                  },
+                  Command::SetPieceDeadline { index, deadline } => {
+                      self.set_piece_deadline(index, deadline).await;
+                  },
+                  Command::MoveStorage(new_dir) => {
+                      self.ctx.disk_tx.send(disk::Command::MoveTorrent {
+                          id: self.ctx.id,
+                          new_dir,
+                      })?;
+                  },
+                  Command::StorageMoved(result) => {
+                      self.handle_storage_moved(result);
                   },
                   Command::Shutdown => {
                       self.shutdown().await?;
@@ -413,23 +1077,33 @@ impl Torrent {
     last_tick_time: &mut Option<Instant>,
     now: Instant,
   ) -> TorrentResult<()> {
-    // calculate how long torrent has been running
-
+    // calculate how long torrent has been running, not counting the time
+    // it was paused
     let elapsed_since_last_tick = last_tick_time
       .or(self.start_time)
       .map(|t| now.saturating_duration_since(t))
       .unwrap_or_default();
-    self.run_duration += elapsed_since_last_tick;
+    if !self.is_stopped() {
+      self.run_duration += elapsed_since_last_tick;
+    }
     *last_tick_time = Some(now);
 
-    // check if we can connect some peers
-    // NOTE: do this before announcing as we don't want to block new
-    // connections with the potentially long running announce requests
-    self.connect_peers();
+    self.reap_finished_sessions().await;
 
-    // check if we need to announce to some trackers
-    let event = None;
-    self.announce_to_trackers(now, event).await?;
+    if !self.is_stopped() && !self.is_checking {
+      // check if we can connect some peers
+      // NOTE: do this before announcing as we don't want to block new
+      // connections with the potentially long running announce requests
+      self.connect_peers().await;
+
+      // check if we need to announce to some trackers
+      let event = None;
+      self.announce_to_trackers(now, event).await?;
+
+      self.lookup_dht_peers(now);
+
+      self.run_choker(now);
+    }
 
     log::debug!(
       "Stats: \
@@ -451,12 +1125,12 @@ impl Torrent {
     if log::log_enabled!(log::Level::Debug) {
       let piece_picker_guard = self.ctx.piece_picker.read().await;
       let unavailable_piece_count =
-        piece_picker_guard.pieces().iter().fold(0, |acc, piece| {
-          if piece.frequency == 0 {
-            acc + 1
-          } else {
-            acc
-          }
+        piece_picker_guard.as_ref().map_or(0, |piece_picker| {
+          piece_picker
+            .pieces()
+            .iter()
+            .filter(|piece| piece.frequency == 0)
+            .count()
         });
       if unavailable_piece_count > 0 {
         log::debug!(
@@ -468,6 +1142,18 @@ impl Torrent {
 
     // send periodic stats update to api user
     let stats = self.build_stats().await;
+    // and the figures the engine totals in its session stats
+    self
+      .engine_tx
+      .send_bulk(engine::Command::TorrentTick {
+        id: self.ctx.id,
+        state: stats.state,
+        download_rate: stats.thruput.payload.down.rate,
+        upload_rate: stats.thruput.payload.up.rate,
+        peer_count: stats.peers.len(),
+      })
+      .await
+      .ok();
     self
       .ctx
       .alert_tx
@@ -477,30 +1163,279 @@ impl Torrent {
       })
       .ok();
 
+    // the latest completed pieces are only reported once
+    if let Some(completed_pieces) = &mut self.completed_pieces {
+      completed_pieces.clear();
+    }
     self.counters.reset();
 
     Ok(())
   }
 
   /// Attempts to connect available peers, if we have any.
-  fn connect_peers(&mut self) {
+  async fn connect_peers(&mut self) {
     let connect_count = self
       .conf
       .max_connected_peer_count
-      .saturating_sub(self.peers.len())
-      .min(self.available_peers.len());
-    if connect_count == 0 {
+      .saturating_sub(self.peers.len());
+    // peers of the family we're reachable over are more likely to be
+    // reachable themselves, as are our replies to them
+    let mut addrs: Vec<_> = self
+      .peer_pool
+      .connectable(Instant::now(), self.reachable_family)
+      .into_iter()
+      // inbound peers may have connected from a known address
+      .filter(|addr| !self.peers.contains_key(addr))
+      .collect();
+    // IPv6 peers on the internet are kept for when the host gets an IPv6
+    // address, as until then connecting to them can only fail and back them
+    // off
+    let is_ipv6_peer = |addr: &SocketAddr| match addr.ip() {
+      IpAddr::V6(ip) => tracker::is_global_ipv6(&ip),
+      IpAddr::V4(_) => false,
+    };
+    if addrs.iter().any(is_ipv6_peer) && self.global_addrs.1.is_none() {
+      addrs.retain(|addr| !is_ipv6_peer(addr));
+    }
+    if connect_count == 0 || addrs.is_empty() {
       log::trace!("Cannot connect to peers");
       return;
     }
 
-    log::debug!("Connecting {} peer(s)", connect_count);
-    for addr in self.available_peers.drain(0..connect_count) {
+    log::debug!("Connecting up to {} peer(s)", connect_count);
+    let mut connected_count = 0;
+    for addr in &addrs {
+      if connected_count == connect_count {
+        break;
+      }
+      // checked as we go, as the peers may share IPs among themselves too
+      if self.ip_connection_count(addr.ip()) >= self.conf.max_connections_per_ip
+      {
+        log::debug!("Skipping peer {}, too many connections to its IP", addr);
+        continue;
+      }
+      // the rest of the peers are kept for when other torrents free up
+      // connections
+      let permit = match self.acquire_connection_permit() {
+        Some(permit) => permit,
+        None => {
+          log::debug!("Engine connection limit reached");
+          break;
+        }
+      };
       log::info!("Connecting to peer {}", addr);
-      let (session, tx) = PeerSession::new(Arc::clone(&self.ctx), addr);
-      self
-        .peers
-        .insert(addr, PeerSessionEntity::start_outbound(session, tx));
+      let (session, tx) =
+        PeerSession::new(Arc::clone(&self.ctx), self.conf.session, *addr);
+      self.peers.insert(
+        *addr,
+        PeerSessionEntity::start_outbound(session, tx, permit),
+      );
+      self.peer_pool.mark_connected(addr);
+      connected_count += 1;
+    }
+
+    // outbound peers need to be counted too, as all peers are discounted
+    // when they disconnect
+    if let Some(piece_picker) = self.ctx.piece_picker.write().await.as_mut() {
+      for _ in 0..connected_count {
+        piece_picker.increase_peer_count();
+      }
+    }
+  }
+
+  /// Reads the handshake of a peer that connected to us in a task of its
+  /// own, unless the peer can't be accepted anyway, so that no session is
+  /// allocated for connections that don't complete a handshake for the
+  /// torrent in time.
+  fn accept_connection(&mut self, socket: PeerConnection, addr: SocketAddr) {
+    if !self.may_accept(addr) {
+      return;
+    }
+    // connections that hold off their handshake can't pile up
+    let Ok(permit) = Arc::clone(&self.handshake_permits).try_acquire_owned()
+    else {
+      log::info!("Dropping connection {:?}, too many handshakes", addr);
+      return;
+    };
+    log::info!("New connection {:?}", addr);
+
+    let info_hash = self.ctx.info_hash;
+    let timeout = self.conf.session.handshake_timeout;
+    let cmd_tx = self.ctx.cmd_tx.clone();
+    task::spawn(async move {
+      let _permit = permit;
+      match peer::accept_handshake(socket, info_hash, timeout).await {
+        Ok((socket, handshake)) => {
+          cmd_tx
+            .send(Command::InboundHandshake {
+              addr,
+              socket,
+              handshake,
+            })
+            .ok();
+        }
+        Err(e) => log::info!("Dropping connection {:?}: {}", addr, e),
+      }
+    });
+  }
+
+  /// Starts the session of a peer that connected to us and sent its
+  /// handshake, if it may still be accepted.
+  async fn handle_inbound_handshake(
+    &mut self,
+    addr: SocketAddr,
+    socket: PeerConnection,
+    handshake: Handshake,
+  ) {
+    // the torrent may have been paused or the IP's limit reached since the
+    // peer connected
+    if !self.may_accept(addr) {
+      return;
+    }
+    if !self.make_room_for_inbound(addr) {
+      log::info!("Dropping connection {:?}, lower priority than peers", addr);
+      return;
+    }
+    let Some(permit) = self.acquire_connection_permit() else {
+      log::info!(
+        "Dropping connection {:?}, engine connection limit reached",
+        addr
+      );
+      return;
+    };
+
+    let (session, tx) =
+      PeerSession::new(Arc::clone(&self.ctx), self.conf.session, addr);
+    self.peers.insert(
+      addr,
+      PeerSessionEntity::start_inbound(socket, handshake, session, tx, permit),
+    );
+    if let Some(piece_picker) = self.ctx.piece_picker.write().await.as_mut() {
+      piece_picker.increase_peer_count();
+    }
+  }
+
+  /// Returns whether a peer connecting to us from the address may be
+  /// accepted, as far as the torrent's state and the peer's IP go.
+  fn may_accept(&self, addr: SocketAddr) -> bool {
+    if self.is_stopped() || self.is_checking {
+      log::info!("Dropping connection {:?} while inactive", addr);
+      return false;
+    }
+    if self.peer_pool.is_banned(addr.ip()) {
+      log::info!("Dropping connection {:?} from banned IP", addr);
+      return false;
+    }
+    if self.ip_connection_count(addr.ip()) >= self.conf.max_connections_per_ip {
+      log::info!(
+        "Dropping connection {:?}, too many connections to its IP",
+        addr
+      );
+      return false;
+    }
+    true
+  }
+
+  /// Returns the number of peers connected over the IP, not counting the
+  /// ones being disconnected.
+  fn ip_connection_count(&self, ip: IpAddr) -> usize {
+    self
+      .peers
+      .iter()
+      .filter(|(addr, peer)| addr.ip() == ip && !peer.is_disconnecting)
+      .count()
+  }
+
+  /// Returns the address peers know us by, which canonical peer priorities
+  /// are computed with: our IP as reported by the trackers, or the one we
+  /// listen on until one does, and the port we announce.
+  fn own_addr(&self) -> SocketAddr {
+    SocketAddr::new(
+      self.external_ip.unwrap_or(self.listen_addr.ip()),
+      self.external_port.unwrap_or(self.listen_addr.port()),
+    )
+  }
+
+  /// Makes room for the peer connecting to us if the torrent is full, by
+  /// disconnecting the peer with the lowest canonical priority, unless the
+  /// new peer's is lower still. Returns whether the peer may be accepted.
+  fn make_room_for_inbound(&mut self, addr: SocketAddr) -> bool {
+    let peer_count = self
+      .peers
+      .values()
+      .filter(|peer| !peer.is_disconnecting)
+      .count();
+    if peer_count < self.conf.max_connected_peer_count {
+      return true;
+    }
+
+    let own_addr = self.own_addr();
+    let lowest = self
+      .peers
+      .iter_mut()
+      .filter(|(_, peer)| !peer.is_disconnecting)
+      .min_by_key(|(addr, _)| peer_priority(own_addr, **addr));
+    match lowest {
+      Some((lowest_addr, peer))
+        if peer_priority(own_addr, *lowest_addr)
+          < peer_priority(own_addr, addr) =>
+      {
+        log::info!("Replacing peer {} with {}", lowest_addr, addr);
+        peer.disconnect();
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Registers the id of the peer that completed the handshake, and if we're
+  /// connected to the peer over another connection too, disconnects one of
+  /// them so that the peer's pieces aren't counted twice.
+  ///
+  /// Both ends must drop the same connection, so if the connections were
+  /// opened by different ends, the one opened by the end that comes first in
+  /// the canonical peer priority order ([BEP 40]) is kept. Otherwise the new
+  /// one is dropped.
+  ///
+  /// [BEP 40]: http://bittorrent.org/beps/bep_0040.html
+  fn handle_peer_connected(&mut self, addr: SocketAddr, id: PeerId) {
+    let Some(peer) = self.peers.get_mut(&addr) else {
+      return;
+    };
+    log::debug!(
+      "Peer {} connected with client '{}', updating state",
+      addr,
+      String::from_utf8_lossy(&id)
+    );
+    peer.id = Some(id);
+    let is_outbound = peer.is_outbound;
+
+    let other = self.peers.iter().find(|(&other_addr, other)| {
+      other_addr != addr && other.id == Some(id) && !other.is_disconnecting
+    });
+    let Some((&other_addr, other)) = other else {
+      return;
+    };
+    let duplicate_addr = if other.is_outbound == is_outbound {
+      addr
+    } else {
+      // the peer is ranked by the address it listens on, which the outbound
+      // connection was made to
+      let peer_addr = if is_outbound { addr } else { other_addr };
+      let keeps_outbound = is_canonically_first(self.own_addr(), peer_addr);
+      if is_outbound == keeps_outbound {
+        other_addr
+      } else {
+        addr
+      }
+    };
+    log::info!(
+      "Disconnecting {}, a duplicate connection to peer {}",
+      duplicate_addr,
+      String::from_utf8_lossy(&id)
+    );
+    if let Some(peer) = self.peers.get_mut(&duplicate_addr) {
+      peer.disconnect();
     }
   }
 
@@ -511,23 +1446,41 @@ impl Torrent {
     now: Instant,
     event: Option<Event>,
   ) -> TorrentResult<()> {
-    // calculate transfer statistics in advance
+    // calculate transfer statistics in advance: the totals are the live
+    // counters, which also count data that failed verification, so what's
+    // left is derived from the verified pieces instead, counting only the
+    // files that are wanted
     let uploaded = self.counters.payload.up.total();
     let downloaded = self.counters.payload.down.total();
-    let left = self.ctx.storage.download_len - downloaded;
-
-    // skip trackers that errored too often.
-    // TODO: introduce a retry timeout
-    let tracker_error_threshold = self.conf.tracker_error_threshold;
+    let left = {
+      let completed_file_bytes = self.ctx.completed_file_bytes().await;
+      let (wanted, completed) = self.wanted_file_bytes(&completed_file_bytes);
+      wanted - completed
+    };
+    let port = self.external_port.unwrap_or(self.listen_addr.port());
+    self.global_addrs = tracker::global_addrs();
+    let (ipv4, ipv6) = self.reachable_addrs();
+    self.reachable_family = match (ipv4, ipv6) {
+      (Some(_), None) => Some(IpFamily::V4),
+      (None, Some(_)) => Some(IpFamily::V6),
+      _ => None,
+    };
+
+    // if we have no peers left to connect to and too few connected, the
+    // swarm may die out before the next backed off announce, so only the
+    // minimum interval is respected
+    let is_pool_dry = self.is_peer_pool_dry(now);
+
+    // skip trackers that are disabled after failing
     for tracker in self
       .trackers
       .iter_mut()
-      .filter(|t| t.error_count < tracker_error_threshold)
+      .filter(|t| t.retry_time.is_none_or(|retry_time| now >= retry_time))
     {
       // Check if the torrent's peer has fallen below the minimum.
       // But don't request new peers otherwise or if we're about
       // to stop torrent.
-      let peer_count = self.peers.len() + self.available_peers.len();
+      let peer_count = self.peers.len() + self.peer_pool.idle_count();
       let needed_peer_count = if peer_count
         >= self.conf.min_requested_peer_count
         || event == Some(Event::Stopped)
@@ -540,141 +1493,1140 @@ impl Torrent {
         // need as many. This is because later we may be able to connect
         // to more peers and in that case we don't want to wait till the
         // next tracer request.
-        Some(self.conf.min_requested_peer_count.max(needed))
+        Some(
+          self
+            .conf
+            .numwant
+            .unwrap_or(self.conf.min_requested_peer_count.max(needed)),
+        )
       };
 
+      // once we have enough peers, early announces are no longer backed off
+      if needed_peer_count.is_none() {
+        tracker.early_announce_count = 0;
+      }
       // we can override the normal announce interval if we need peers or
       // if we have an event to announce
-      if event.is_some()
-        || (needed_peer_count > Some(0))
-          && tracker.can_announce(now, self.conf.announce_interval)
-        || tracker.should_announce(now, self.conf.announce_interval)
-      {
-        let params = Announce {
-          tracker_id: tracker.id.clone(),
-          info_hash: self.ctx.info_hash,
-          peer_id: self.ctx.client_id,
-          port: self.listen_addr.port(),
-          peer_count: needed_peer_count,
-          uploaded,
-          downloaded,
-          left,
-          ip: None,
-          event,
+      // or if the tracker knows us by a stale port
+      // or if the tracker failed and its retry delay is up
+      let is_regular_announce = event.is_some()
+        || tracker.retry_time.is_some()
+        || tracker.should_announce(now, &self.conf)
+        || tracker.has_stale_port(port);
+      let is_early_announce = !is_regular_announce
+        && needed_peer_count > Some(0)
+        && if is_pool_dry {
+          tracker.can_announce_unbacked(now, &self.conf)
+        } else {
+          tracker.can_announce(now, &self.conf)
         };
+      if is_regular_announce || is_early_announce {
+        if is_early_announce && is_pool_dry {
+          log::info!(
+            "Torrent ran out of peers, announcing early to tracker {}",
+            tracker.client
+          );
+        } else if is_early_announce {
+          log::info!(
+            "Torrent is short of peers, announcing early to tracker {}",
+            tracker.client
+          );
+          tracker.early_announce_count += 1;
+        } else {
+          tracker.early_announce_count = 0;
+        }
 
-        match tracker.client.announce(params).await {
-          Ok(resp) => {
-            log::info!(
-              "Announced to tracker {}, response: {:?}",
-              tracker.client,
-              resp
-            );
-            if let Some(tracker_id) = resp.tracker_id {
-              tracker.id = Some(tracker_id);
+        // a tracker added while the torrent was running is told that we
+        // started when it's first announced to, while otherwise a partial
+        // seed keeps telling that it's one
+        let event = event
+          .or(
+            Some(Event::Started)
+              .filter(|_| tracker.last_announce_time.is_none()),
+          )
+          .or(Some(Event::Paused).filter(|_| self.ctx.is_partial_seed()));
+
+        // a host with addresses of both families announces over each, so
+        // that the tracker learns both (BEP 7), while otherwise the tracker
+        // is reached over whichever family the OS picks
+        let families = match (ipv4, ipv6) {
+          (Some(_), Some(_)) => vec![Some(IpFamily::V4), Some(IpFamily::V6)],
+          _ => vec![None],
+        };
+        let mut responses = Vec::with_capacity(families.len());
+        let mut errors = Vec::new();
+        for family in families {
+          let params = Announce {
+            tracker_id: tracker.id.clone(),
+            key: Some(self.announce_key),
+            info_hash: self.ctx.info_hash,
+            peer_id: self.ctx.client_id,
+            port,
+            peer_count: needed_peer_count,
+            uploaded,
+            downloaded,
+            left,
+            // an address the tracker may not see, e.g. when it's reached over
+            // the other family
+            ip: self.external_ip.filter(|ip| match ip {
+              IpAddr::V4(_) => ipv4.is_none(),
+              IpAddr::V6(_) => ipv6.is_none(),
+            }),
+            // the tracker already sees the address of the family it's
+            // reached over, so only the other family's address is added, or
+            // both if it's not yet known
+            ipv4: ipv4.filter(|_| match family {
+              Some(family) => family != IpFamily::V4,
+              None => !tracker.remote_addr.is_some_and(|a| a.is_ipv4()),
+            }),
+            ipv6: ipv6.filter(|_| match family {
+              Some(family) => family != IpFamily::V6,
+              None => !tracker.remote_addr.is_some_and(|a| a.is_ipv6()),
+            }),
+            event,
+          };
+          let announce = time::timeout(
+            self.conf.tracker_timeout,
+            tracker.client.announce(params, family),
+          );
+          // the torrent's exit is announced even if it's being shut down,
+          // but any other announce is given up on, or not even started if
+          // the shutdown is already underway
+          let result = if event == Some(Event::Stopped) {
+            announce.await
+          } else {
+            tokio::select! {
+              biased;
+              _ = self.shutdown_token.cancelled() => {
+                log::info!(
+                  "Abandoning announce to tracker {} on shutdown",
+                  tracker.client
+                );
+                return Ok(());
+              }
+              result = announce => result,
             }
-            if let Some(failure_reason) = resp.failure_reason {
+          };
+          match result.unwrap_or(Err(TrackerError::Timeout)) {
+            Ok(resp) => responses.push(resp),
+            Err(e) => {
               log::warn!(
-                "Error contacting tracker {}: {}",
+                "Error announcing to tracker {}{}: {}",
                 tracker.client,
-                failure_reason
+                family.map(|f| format!(" over {}", f)).unwrap_or_default(),
+                e
               );
+              errors.push(e);
             }
+          }
+        }
 
-            if let Some(warning_message) = resp.warning_message {
-              log::warn!(
-                "Warning contacting tracker {}: {}",
-                tracker.client,
-                warning_message
-              );
-            }
-            if let Some(interval) = resp.interval {
+        // the tracker only counts as failing if it can't be reached at all
+        if responses.is_empty() {
+          for error in errors {
+            self.ctx.alert_tx.send(Alert::Error(Error::Tracker {
+              id: self.ctx.id,
+              error,
+            }))?;
+          }
+          tracker.failure_count += 1;
+          let delay = tracker
+            .retry_delay(&self.conf.tracker_backoff)
+            .mul_f64(rand::thread_rng().gen_range(0.75..=1.0));
+          tracker.retry_time = Some(now + delay);
+          log::warn!(
+            "Disabling tracker {} for {} s after {} failure(s)",
+            tracker.client,
+            delay.as_secs(),
+            tracker.failure_count
+          );
+          self.ctx.alert_tx.send(Alert::TrackerDisabled {
+            id: self.ctx.id,
+            url: tracker.client.url().clone(),
+            failure_count: tracker.failure_count,
+            retry_in: delay,
+          })?;
+        } else if tracker.failure_count > 0 {
+          log::info!("Tracker {} recovered", tracker.client);
+          tracker.failure_count = 0;
+          tracker.retry_time = None;
+          self.ctx.alert_tx.send(Alert::TrackerRecovered {
+            id: self.ctx.id,
+            url: tracker.client.url().clone(),
+          })?;
+        }
+
+        for resp in responses {
+          log::info!(
+            "Announced to tracker {}, response: {:?}",
+            tracker.client,
+            resp
+          );
+          if let Some(tracker_id) = resp.tracker_id {
+            tracker.id = Some(tracker_id);
+          }
+          if let Some(external_ip) = resp.external_ip {
+            if self.external_ip != Some(external_ip) {
               log::info!(
-                "Tracker {} interval: {} s",
+                "Tracker {} sees us by {}",
                 tracker.client,
-                interval.as_secs()
+                external_ip
               );
-              tracker.interval = Some(interval);
+              self.external_ip = Some(external_ip);
+              self.ctx.alert_tx.send(Alert::ExternalIp {
+                id: self.ctx.id,
+                url: tracker.client.url().clone(),
+                ip: external_ip,
+              })?;
             }
-            if let Some(min_interval) = resp.min_interval {
+          }
+          if let Some(remote_addr) = resp.remote_addr {
+            if tracker.remote_addr.map(|a| a.is_ipv4())
+              != Some(remote_addr.is_ipv4())
+            {
               log::info!(
-                "Tracker {} min min_interval: {} s",
+                "Tracker {} responded over {}",
                 tracker.client,
-                min_interval.as_secs()
+                IpFamily::of(remote_addr.ip())
               );
-              tracker.min_interval = Some(min_interval);
             }
+            tracker.remote_addr = Some(remote_addr);
+          }
+          if let Some(failure_reason) = resp.failure_reason {
+            log::warn!(
+              "Error contacting tracker {}: {}",
+              tracker.client,
+              failure_reason
+            );
+          }
 
-            if let (Some(seeder_count), Some(leecher_count)) =
-              (resp.seeder_count, resp.leecher_count)
-            {
-              log::debug!(
-                "Torrent seeds: {} and leeches: {}",
-                seeder_count,
-                leecher_count
-              );
-            }
+          if let Some(warning_message) = resp.warning_message {
+            log::warn!(
+              "Warning contacting tracker {}: {}",
+              tracker.client,
+              warning_message
+            );
+          }
+          if let Some(interval) = resp.interval {
+            log::info!(
+              "Tracker {} interval: {} s",
+              tracker.client,
+              interval.as_secs()
+            );
+            tracker.interval = Some(interval);
+          }
+          if let Some(min_interval) = resp.min_interval {
+            log::info!(
+              "Tracker {} min min_interval: {} s",
+              tracker.client,
+              min_interval.as_secs()
+            );
+            tracker.min_interval = Some(min_interval);
+          }
 
-            if !resp.peers.is_empty() {
-              log::debug!(
-                "Received peers from tracker {}: {:?}",
-                tracker.client,
-                resp.peers
-              );
-              self.available_peers.extend(resp.peers.into_iter());
-            }
+          if let (Some(seeder_count), Some(leecher_count)) =
+            (resp.seeder_count, resp.leecher_count)
+          {
+            log::debug!(
+              "Torrent seeds: {} and leeches: {}",
+              seeder_count,
+              leecher_count
+            );
           }
-          Err(e) => {
-            log::warn!("Error announcing to tracker {}: {}", tracker.client, e);
 
-            tracker.error_count += 1;
-            self.ctx.alert_tx.send(Alert::Error(Error::Tracker {
-              id: self.ctx.id,
-              error: e,
-            }))?;
+          if !resp.peers.is_empty() || !resp.peers6.is_empty() {
+            log::debug!(
+              "Received peers from tracker {}: {:?} {:?}",
+              tracker.client,
+              resp.peers,
+              resp.peers6
+            );
+            // announcing over both families may return the same peers
+            for addr in resp.peers.into_iter().chain(resp.peers6) {
+              if !self.peers.contains_key(&addr) {
+                self.peer_pool.add(addr);
+              }
+            }
           }
         }
 
         tracker.last_announce_time = Some(now);
+        tracker.announced_port = Some(port);
       }
     }
     Ok(())
   }
 
+  /// Looks up the torrent's peers in the DHT, announcing that we're one of
+  /// them, periodically and sooner if we run out of peers to connect to.
+  ///
+  /// The lookup runs in the DHT task, whose peers are sent back to the
+  /// torrent once it's done.
+  /// Ranks the connected peers if it's due, and tells the sessions of those
+  /// whose choke state changed to choke or unchoke their peers.
+  fn run_choker(&mut self, now: Instant) {
+    // when seeding we download from no one, so peers are ranked by how
+    // fast they take our uploads
+    let is_seed = self.ctx.is_seed();
+    let candidates = self
+      .peers
+      .iter()
+      .filter(|(_, peer)| peer.state.connection == ConnectionState::Connected)
+      .map(|(addr, peer)| Candidate {
+        addr: *addr,
+        is_interested: peer.state.is_peer_interested,
+        rate: if is_seed {
+          peer.thruput.payload.up.rate
+        } else {
+          peer.thruput.payload.down.rate
+        },
+      })
+      .collect();
+    let slot_count = self.conf.unchoke_slot_count.unwrap_or_else(|| {
+      // the rate the torrent may upload at if it's limited, or else the rate
+      // it has uploaded at recently, as the all-time peak may be long gone
+      let upload_bandwidth = self
+        .ctx
+        .upload_limiter
+        .share()
+        .unwrap_or_else(|| self.counters.payload.up.avg());
+      auto_slot_count(upload_bandwidth)
+    });
+    let Some(unchoked) = self.choker.run(now, candidates, slot_count) else {
+      return;
+    };
+
+    for (addr, peer) in &self.peers {
+      let Some(tx) = &peer.tx else {
+        continue;
+      };
+      let is_unchoked = unchoked.contains(addr);
+      if is_unchoked == peer.state.is_peer_choked {
+        let cmd = if is_unchoked {
+          peer::Command::Unchoke
+        } else {
+          peer::Command::Choke
+        };
+        tx.send(cmd).ok();
+      }
+    }
+  }
+
+  fn lookup_dht_peers(&mut self, now: Instant) {
+    let Some(dht) = &self.ctx.dht else {
+      return;
+    };
+    let is_due = self.last_dht_lookup_time.is_none_or(|t| {
+      let elapsed = now.saturating_duration_since(t);
+      elapsed >= dht::ANNOUNCE_INTERVAL
+        || (self.is_peer_pool_dry(now) && elapsed >= dht::MIN_LOOKUP_INTERVAL)
+    });
+    if !is_due {
+      return;
+    }
+    self.last_dht_lookup_time = Some(now);
+
+    let port = self.external_port.unwrap_or(self.listen_addr.port());
+    let peers_rx = dht.get_peers(self.ctx.info_hash, Some(port));
+    let cmd_tx = self.ctx.cmd_tx.clone();
+    task::spawn(async move {
+      // the lookup is abandoned if the DHT stops
+      if let Ok(peers) = peers_rx.await {
+        cmd_tx.send(Command::DhtPeers(peers)).ok();
+      }
+    });
+  }
+
+  /// Adds the peers found in the DHT to those we may connect to.
+  fn add_dht_peers(&mut self, peers: Vec<SocketAddr>) {
+    log::debug!("Received {} peer(s) from the DHT", peers.len());
+    for addr in peers {
+      if !self.peers.contains_key(&addr) {
+        self.peer_pool.add(addr);
+      }
+    }
+  }
+
+  /// Returns the host's global addresses, as last found, of the IP families
+  /// on which the torrent accepts connections, to be announced to trackers.
+  ///
+  /// Listening on the IPv6 unspecified address accepts IPv4 connections
+  /// too, as the transport binds it dual-stack.
+  fn reachable_addrs(&self) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    let (ipv4, ipv6) = self.global_addrs;
+    let listen_ip = self.listen_addr.ip();
+    let accepts_ipv4 = listen_ip.is_ipv4() || listen_ip.is_unspecified();
+    (
+      ipv4.filter(|_| accepts_ipv4),
+      ipv6.filter(|_| listen_ip.is_ipv6()),
+    )
+  }
+
   /// Returns high-level statistics about the torrent for sending to the user.
-  async fn build_stats(&mut self) -> TorrentStats {
-    let missing_piece_count =
-      self.ctx.piece_picker.read().await.missing_piece_count();
+  async fn build_stats(&self) -> TorrentStats {
+    let missing_piece_count = self
+      .ctx
+      .piece_picker
+      .read()
+      .await
+      .as_ref()
+      .map_or(0, PiecePicker::missing_piece_count);
+    let completed_file_bytes = self.ctx.completed_file_bytes().await;
     let piece_count = self.ctx.storage.piece_count;
-    let completed_pieces = self.completed_pieces.as_mut().map(std::mem::take);
+    let completed_pieces = self.completed_pieces.clone();
     let peers = if self.conf.alerts.peers {
-      let peers = self
-        .peers
-        .iter()
-        .map(|(addr, entry)| stats::PeerSessionStats {
-          addr: *addr,
-          id: entry.id,
-          state: entry.state,
-          piece_count: entry.piece_count,
-          thruput: entry.thruput,
-        })
-        .collect();
-      Peers::Full(peers)
+      Peers::Full(self.peer_stats())
     } else {
       Peers::Count(self.peers.len())
     };
+    let files = self
+      .conf
+      .alerts
+      .files
+      .then(|| self.file_stats(completed_file_bytes.clone()));
+
+    let state = if self.is_paused {
+      TorrentState::Paused
+    } else if self.is_queued {
+      TorrentState::Queued
+    } else if self.is_checking {
+      TorrentState::Checking
+    } else if missing_piece_count == 0 {
+      TorrentState::Seeding
+    } else {
+      TorrentState::Downloading
+    };
+
+    let (wanted_bytes, completed_wanted_bytes) =
+      self.wanted_file_bytes(&completed_file_bytes);
+    let progress_wanted = if wanted_bytes == 0 {
+      1.0
+    } else {
+      completed_wanted_bytes as f64 / wanted_bytes as f64
+    };
 
     TorrentStats {
       start_time: self.start_time,
+      state,
       run_duration: self.run_duration,
       pieces: PieceStats {
         total: piece_count,
         complete: piece_count - missing_piece_count,
-        pending: self.ctx.downloads.read().await.len(),
+        pending: self
+          .ctx
+          .downloads
+          .read()
+          .await
+          .as_ref()
+          .map_or(0, HashMap::len),
         latest_completed: completed_pieces,
       },
+      wanted_bytes,
+      completed_wanted_bytes,
+      progress_wanted,
       thruput: ThruputStats::from(&self.counters),
+      endgame: self.endgame,
       peers,
+      files,
+      labels: self.labels.clone(),
+    }
+  }
+
+  /// Returns each file with the number of its bytes we have, as given.
+  /// Returns the total length of the files that aren't skipped, and how much
+  /// of them is downloaded, given the downloaded bytes of each file.
+  fn wanted_file_bytes(&self, completed_file_bytes: &[u64]) -> (u64, u64) {
+    self
+      .ctx
+      .storage
+      .files
+      .iter()
+      .zip(completed_file_bytes)
+      .zip(&self.file_priorities)
+      .filter(|(_, &priority)| priority != FilePriority::Skip)
+      .fold(
+        (0, 0),
+        |(wanted, completed), ((file, file_completed), _)| {
+          (wanted + file.len, completed + file_completed)
+        },
+      )
+  }
+
+  fn file_stats(&self, completed_file_bytes: Vec<u64>) -> Vec<FileStats> {
+    self
+      .ctx
+      .storage
+      .files
+      .iter()
+      .zip(completed_file_bytes)
+      .zip(&self.file_priorities)
+      .map(|((info, completed_bytes), &priority)| FileStats {
+        info: info.clone(),
+        completed_bytes,
+        priority,
+      })
+      .collect()
+  }
+
+  /// Sets the pieces that were found valid on disk and starts the torrent,
+  /// which until now was waiting for the check to finish.
+  async fn handle_pieces_checked(
+    &mut self,
+    own_pieces: Bitfield,
+  ) -> TorrentResult<()> {
+    debug_assert_eq!(own_pieces.len(), self.ctx.storage.piece_count);
+    let missing_piece_count = own_pieces.count_zeros();
+    log::info!(
+      "Torrent data checked, missing {} piece(s)",
+      missing_piece_count
+    );
+    // there are no peers yet, so the piece picker can be simply replaced,
+    // while a seed needs neither a piece picker nor downloads
+    let mut piece_picker_guard = self.ctx.piece_picker.write().await;
+    if missing_piece_count == 0 {
+      *piece_picker_guard = None;
+      *self.ctx.downloads.write().await = None;
+    } else {
+      let mut piece_picker = PiecePicker::new(own_pieces);
+      if let Some(prev) = piece_picker_guard.as_ref() {
+        piece_picker.inherit_settings(prev);
+      }
+      if let Some(sample) = &self.sample {
+        piece_picker.set_piece_limit(Some(sample.piece_count));
+      }
+      *piece_picker_guard = Some(piece_picker);
+    }
+    drop(piece_picker_guard);
+    if let Some(sample) = &mut self.sample {
+      sample.start_time = Some(Instant::now());
+      sample.start_downloaded = self.counters.payload.down.total();
+    }
+    self
+      .ctx
+      .is_seed
+      .store(missing_piece_count == 0, Ordering::Release);
+    // this only takes note, as the partial seed is told to trackers with
+    // the started event's announce below
+    self.update_partial_seed().await?;
+    self.is_checking = false;
+    self.post_playable_files().await;
+
+    if missing_piece_count == 0 {
+      self
+        .engine_tx
+        .send(engine::Command::TorrentComplete { id: self.ctx.id })?;
+    }
+    // we may already have the sample
+    self.check_sample().await?;
+
+    if self.is_stopped() {
+      return Ok(());
+    }
+
+    // the first announce must be the started event, even for a seed, which
+    // only differs in never sending the completed event
+    self
+      .announce_to_trackers(Instant::now(), Some(Event::Started))
+      .await
+  }
+
+  /// Takes one of the engine's connection permits for a new peer session.
+  ///
+  /// Returns `None` if the engine is at its connection limit, or if the
+  /// permits left are reserved for torrents of higher priority.
+  fn acquire_connection_permit(&self) -> Option<OwnedSemaphorePermit> {
+    let reserved = self.conf.priority.reserved_connections(
+      self.ctx.connection_permit_count.load(Ordering::Relaxed),
+    );
+    if self.ctx.connection_permits.available_permits() <= reserved {
+      return None;
+    }
+    Arc::clone(&self.ctx.connection_permits)
+      .try_acquire_owned()
+      .ok()
+  }
+
+  /// Returns the state needed to restore the torrent in a later session.
+  async fn resume_data(&self) -> ResumeData {
+    // until the check finishes the piece picker doesn't know our pieces, so
+    // the data is checked again on restoring
+    let own_pieces = if self.is_checking {
+      None
+    } else {
+      Some(self.ctx.own_pieces().await)
+    };
+    ResumeData {
+      info_hash: self.ctx.info_hash,
+      metainfo: self.raw_metainfo.clone(),
+      conf: self.conf.clone(),
+      own_pieces,
+      downloaded: self.counters.payload.down.total(),
+      uploaded: self.counters.payload.up.total(),
+      run_duration: self.run_duration,
+      is_paused: self.is_paused,
+      labels: self.labels.clone(),
+      recent_pieces: self.recent_pieces.iter().copied().collect(),
+      // inbound peers connected from a port they don't listen on, so only
+      // the pool is saved, which includes the outbound peers
+      peers: self.peer_pool.addrs().collect(),
+      tracker_ids: self
+        .trackers
+        .iter()
+        .filter_map(|tracker| {
+          let id = tracker.id.clone()?;
+          Some((tracker.client.url().to_string(), id))
+        })
+        .collect(),
+      announce_key: Some(self.announce_key),
+      // the trackers may have been edited at runtime, or come from a magnet
+      // link, so they're not those of the raw metainfo
+      trackers: Some(
+        self
+          .trackers
+          .iter()
+          .map(|tracker| tracker.client.url().clone())
+          .collect(),
+      ),
+      download_dir: Some(self.download_dir.clone()),
+    }
+  }
+
+  /// Adds the tracker, which is announced to on the next tick like any
+  /// tracker not announced to yet.
+  fn add_tracker(&mut self, url: Url) {
+    if self.trackers.iter().any(|t| t.client.url() == &url) {
+      log::info!("Torrent {} already has tracker {}", self.ctx.id, url);
+      return;
+    }
+    log::info!("Torrent {} adding tracker {}", self.ctx.id, url);
+    let tracker = self.tracker_backend.new_client(
+      url,
+      &self.http_client,
+      &self.udp_connections,
+    );
+    self.trackers.push(TrackerEntry::new(tracker));
+  }
+
+  /// Removes the tracker, which isn't told that we're leaving, as it may
+  /// well be dead.
+  fn remove_tracker(&mut self, url: &Url) {
+    let tracker_count = self.trackers.len();
+    self.trackers.retain(|t| t.client.url() != url);
+    if self.trackers.len() == tracker_count {
+      log::warn!("Torrent {} has no tracker {} to remove", self.ctx.id, url);
+      return;
+    }
+    log::info!("Torrent {} removed tracker {}", self.ctx.id, url);
+  }
+
+  /// Re-announces to the trackers that were given a different port than the
+  /// one we can be reached on now, instead of waiting for the next announce.
+  async fn reannounce_port(&mut self) -> TorrentResult<()> {
+    // inactive torrents aren't announced, and they announce the new port
+    // once they become active
+    if self.is_stopped() || self.is_checking {
+      return Ok(());
+    }
+    self.announce_to_trackers(Instant::now(), None).await
+  }
+
+  /// Returns the statistics of each peer in the torrent.
+  fn peer_stats(&self) -> Vec<PeerSessionStats> {
+    self
+      .peers
+      .iter()
+      .map(|(addr, entry)| PeerSessionStats {
+        addr: *addr,
+        id: entry.id,
+        state: entry.state,
+        piece_count: entry.piece_count,
+        client: entry.client.clone(),
+        is_outbound: entry.is_outbound,
+        thruput: entry.thruput,
+        outgoing_requests: entry.outgoing_request_count,
+        incoming_requests: entry.incoming_request_count,
+        duplicate_requests: entry.duplicate_request_count,
+        duplicate_blocks: entry.duplicate_block_count,
+        hash_failures: self
+          .hash_failure_counts
+          .get(&addr.ip())
+          .copied()
+          .unwrap_or_default(),
+      })
+      .collect()
+  }
+
+  /// Marks the torrent paused or queued before it starts, so that it starts
+  /// stopped without waiting for the commands already in its channel.
+  pub(crate) fn set_stopped(&mut self, is_paused: bool, is_queued: bool) {
+    debug_assert!(self.start_time.is_none());
+    self.is_paused = is_paused;
+    self.is_queued = is_queued;
+  }
+
+  /// Applies the changes made to the torrent before it was restarted, before
+  /// it starts.
+  pub(crate) async fn apply_changes(
+    &mut self,
+    changes: RuntimeChanges,
+  ) -> TorrentResult<()> {
+    debug_assert!(self.start_time.is_none());
+    if let Some(priorities) = changes.file_priorities {
+      self.set_file_priorities(priorities).await?;
+    }
+    let now = Instant::now();
+    for (index, deadline) in changes.deadlines {
+      self
+        .set_piece_deadline(index, deadline.saturating_duration_since(now))
+        .await;
+    }
+    if let Some(len) = changes.sample {
+      self.start_sample(len).await?;
+    }
+    Ok(())
+  }
+
+  /// Returns whether the torrent is stopped, either paused by the user or
+  /// queued by the engine.
+  fn is_stopped(&self) -> bool {
+    self.is_paused || self.is_queued
+  }
+
+  /// Pauses the torrent, stopping it unless it's already queued.
+  async fn pause(&mut self) -> TorrentResult<()> {
+    if self.is_paused {
+      return Ok(());
+    }
+    log::info!("Pausing torrent");
+    let was_stopped = self.is_stopped();
+    self.is_paused = true;
+    self.update_stopped(was_stopped).await
+  }
+
+  /// Resumes a paused torrent, starting it again unless it's queued.
+  async fn resume(&mut self) -> TorrentResult<()> {
+    if !self.is_paused {
+      return Ok(());
+    }
+    log::info!("Resuming torrent");
+    let was_stopped = self.is_stopped();
+    self.is_paused = false;
+    self.update_stopped(was_stopped).await
+  }
+
+  /// Queues or dequeues the torrent, stopping or starting it unless it's
+  /// paused.
+  async fn set_queued(&mut self, is_queued: bool) -> TorrentResult<()> {
+    if self.is_queued == is_queued {
+      return Ok(());
+    }
+    log::info!("Torrent queued: {}", is_queued);
+    let was_stopped = self.is_stopped();
+    self.is_queued = is_queued;
+    self.update_stopped(was_stopped).await
+  }
+
+  /// Stops or starts the torrent if pausing, resuming, queueing or
+  /// dequeueing it changed whether it's stopped.
+  async fn update_stopped(&mut self, was_stopped: bool) -> TorrentResult<()> {
+    match (was_stopped, self.is_stopped()) {
+      (false, true) => self.stop().await,
+      (true, false) => self.start_again().await,
+      _ => Ok(()),
+    }
+  }
+
+  /// Disconnects all peers and stops announcing to trackers, until the
+  /// torrent is started again.
+  ///
+  /// The addresses of the outbound peers are kept so that they can be
+  /// reconnected after starting again.
+  async fn stop(&mut self) -> TorrentResult<()> {
+    for addr in self.disconnect_peers().await {
+      self.peer_pool.release(&addr);
+    }
+
+    // trackers are only announced to once the torrent is checked
+    if self.is_checking {
+      return Ok(());
+    }
+    self
+      .announce_to_trackers(Instant::now(), Some(Event::Stopped))
+      .await
+  }
+
+  /// Starts a stopped torrent again, announcing it to trackers, as they were
+  /// told that it stopped.
+  async fn start_again(&mut self) -> TorrentResult<()> {
+    // the torrent is announced when it's done checking
+    if self.is_checking {
+      return Ok(());
+    }
+
+    self
+      .announce_to_trackers(Instant::now(), Some(Event::Started))
+      .await
+  }
+
+  /// Starts downloading only the pieces covering the first `len` bytes of
+  /// the torrent, resuming it if it's paused.
+  ///
+  /// A sample requested while another one is in progress replaces it.
+  async fn start_sample(&mut self, len: u64) -> TorrentResult<()> {
+    let piece_len = u64::from(self.ctx.storage.piece_len);
+    let piece_count =
+      (len.div_ceil(piece_len) as usize).clamp(1, self.ctx.storage.piece_count);
+    log::info!("Sampling the first {} piece(s)", piece_count);
+
+    // the piece picker is replaced once the data is checked, so until then
+    // the sample is only recorded
+    let start_time = if self.is_checking {
+      None
+    } else {
+      if let Some(piece_picker) = self.ctx.piece_picker.write().await.as_mut() {
+        piece_picker.set_piece_limit(Some(piece_count));
+      }
+      Some(Instant::now())
+    };
+    self.sample = Some(Sample {
+      piece_count,
+      start_time,
+      start_downloaded: self.counters.payload.down.total(),
+    });
+
+    self.resume().await?;
+    self.check_sample().await
+  }
+
+  /// Finishes the sample if all of its pieces are downloaded, posting the
+  /// report and pausing the torrent. The torrent downloads the rest of the
+  /// pieces once it's resumed.
+  async fn check_sample(&mut self) -> TorrentResult<()> {
+    let Some(sample) = &self.sample else {
+      return Ok(());
+    };
+    let Some(start_time) = sample.start_time else {
+      return Ok(());
+    };
+
+    // a seed has all pieces, and with them the whole sample
+    let piece_count = self.ctx.storage.piece_count;
+    let mut piece_picker_guard = self.ctx.piece_picker.write().await;
+    let available_count = match piece_picker_guard.as_mut() {
+      Some(piece_picker) => {
+        if !piece_picker.own_pieces()[..sample.piece_count].all() {
+          return Ok(());
+        }
+        piece_picker.set_piece_limit(None);
+        piece_picker
+          .own_pieces()
+          .iter()
+          .zip(piece_picker.pieces())
+          .filter(|(have, piece)| **have || piece.frequency > 0)
+          .count()
+      }
+      None => piece_count,
+    };
+    drop(piece_picker_guard);
+
+    let duration = start_time.elapsed();
+    let downloaded =
+      self.counters.payload.down.total() - sample.start_downloaded;
+    let download_rate = if duration.is_zero() {
+      0
+    } else {
+      (downloaded as f64 / duration.as_secs_f64()) as u64
+    };
+    let report = SampleReport {
+      piece_count: sample.piece_count,
+      downloaded,
+      duration,
+      download_rate,
+      peer_count: self.peers.len(),
+      availability: available_count as f64 / piece_count as f64,
+    };
+    log::info!(
+      "Sample downloaded in {:?} at {} b/s, pausing",
+      report.duration,
+      report.download_rate
+    );
+    self.sample = None;
+    // so that the engine doesn't sample again if it restarts the torrent
+    self
+      .engine_tx
+      .send(engine::Command::SampleComplete { id: self.ctx.id })?;
+
+    self
+      .ctx
+      .alert_tx
+      .send(Alert::SampleComplete {
+        id: self.ctx.id,
+        report: Box::new(report),
+      })
+      .ok();
+    self.pause().await
+  }
+
+  /// Returns the path of the torrent's file if it has a single file, or that
+  /// of the directory holding its files otherwise.
+  fn files_path(&self) -> PathBuf {
+    match self.ctx.storage.files.as_slice() {
+      [file] => self.download_dir.join(&file.path),
+      _ => self.download_dir.clone(),
+    }
+  }
+
+  /// Sets the priority of each file, which takes effect on the next pieces
+  /// picked. Peer sessions recalculate their interest, as they may now have
+  /// pieces we want, or no longer have any.
+  async fn set_file_priorities(
+    &mut self,
+    priorities: Vec<FilePriority>,
+  ) -> TorrentResult<()> {
+    if priorities.len() != self.file_priorities.len() {
+      log::warn!(
+        "Got {} file priorities for {} files",
+        priorities.len(),
+        self.file_priorities.len()
+      );
+      self.post_error(TorrentError::InvalidFilePriorities);
+      return Ok(());
+    }
+    log::info!("Setting file priorities to {:?}", priorities);
+    self.file_priorities = priorities;
+
+    // a piece is as important as the most important file it overlaps with
+    let piece_priorities = (0..self.ctx.storage.piece_count).map(|index| {
+      self.file_priorities[self.ctx.storage.files_intersecting_piece(index)]
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or_default()
+    });
+    if let Some(piece_picker) = self.ctx.piece_picker.write().await.as_mut() {
+      piece_picker.set_piece_priorities(piece_priorities);
+    }
+
+    for peer in self.peers.values() {
+      if let Some(tx) = &peer.tx {
+        tx.send(peer::Command::UpdateInterest).ok();
+      }
+    }
+
+    // skipping the files still missing may leave us a partial seed
+    self.update_partial_seed().await
+  }
+
+  /// Updates whether we have all pieces but those of only skipped files,
+  /// i.e. whether we're a partial seed (BEP 21), which is announced to the
+  /// trackers right away with the paused event.
+  ///
+  /// Only peers connected from then on are told in the extension
+  /// handshake, but the others are of no use to a partial seed anyway, as
+  /// it's not interested in them.
+  async fn update_partial_seed(&mut self) -> TorrentResult<()> {
+    let is_partial_seed = self
+      .ctx
+      .piece_picker
+      .read()
+      .await
+      .as_ref()
+      .is_some_and(PiecePicker::is_partial_seed);
+    if is_partial_seed == self.ctx.is_partial_seed() {
+      return Ok(());
+    }
+    self
+      .ctx
+      .is_partial_seed
+      .store(is_partial_seed, Ordering::Release);
+    if !is_partial_seed {
+      log::info!("Torrent is no longer a partial seed");
+      return Ok(());
+    }
+
+    log::info!("Torrent has all wanted pieces, now a partial seed");
+    // the files already complete on disk are not told, but neither are
+    // they once more later
+    if self.is_checking {
+      self.completed_wanted_files = Some(self.wanted_files());
+      return Ok(());
+    }
+
+    // the download of the wanted files is complete, which is told the same
+    // way as that of a whole torrent, except to the trackers and the
+    // engine's queue, as the skipped files may still be wanted later
+    self.post_completion()?;
+
+    if self.is_stopped() {
+      return Ok(());
+    }
+    self
+      .announce_to_trackers(Instant::now(), Some(Event::Paused))
+      .await
+  }
+
+  /// Returns whether each file is wanted, i.e. not skipped.
+  fn wanted_files(&self) -> Vec<bool> {
+    self
+      .file_priorities
+      .iter()
+      .map(|priority| *priority != FilePriority::Skip)
+      .collect()
+  }
+
+  /// Tells the user and the engine that the wanted files are downloaded,
+  /// unless they were already told of the same files.
+  fn post_completion(&mut self) -> TorrentResult<()> {
+    let wanted_files = self.wanted_files();
+    if self.completed_wanted_files.as_ref() == Some(&wanted_files) {
+      log::debug!("Completion of wanted files already posted");
+      return Ok(());
+    }
+    self.completed_wanted_files = Some(wanted_files);
+
+    self
+      .ctx
+      .alert_tx
+      .send(Alert::TorrentComplete(self.ctx.id))
+      .ok();
+    self.engine_tx.send(engine::Command::TorrentDownloaded {
+      id: self.ctx.id,
+      path: self.files_path(),
+      completion_command: self.conf.completion_command.clone(),
+    })?;
+    Ok(())
+  }
+
+  /// Asks for the piece to be picked before those without a deadline.
+  async fn set_piece_deadline(
+    &mut self,
+    index: PieceIndex,
+    deadline: Duration,
+  ) {
+    if index >= self.ctx.storage.piece_count {
+      log::warn!("Cannot set deadline of invalid piece {}", index);
+      self.post_error(TorrentError::InvalidPieceIndex(index));
+      return;
+    }
+    log::info!("Setting piece {} deadline to {:?}", index, deadline);
+    if let Some(piece_picker) = self.ctx.piece_picker.write().await.as_mut() {
+      piece_picker.set_deadline(index, Instant::now() + deadline);
+    }
+    if !self.is_streaming {
+      self.is_streaming = true;
+      // files whose prefix is already downloaded are playable right away
+      self.post_playable_files().await;
+    }
+  }
+
+  /// Posts the error as an alert, for errors that don't stop the torrent.
+  fn post_error(&self, error: TorrentError) {
+    self
+      .ctx
+      .alert_tx
+      .send(Alert::Error(Error::Torrent {
+        id: self.ctx.id,
+        error,
+      }))
+      .ok();
+  }
+
+  /// Notifies the user of the outcome of moving the torrent's storage.
+  fn handle_storage_moved(&mut self, result: Result<PathBuf, MoveError>) {
+    let alert = match result {
+      Ok(dir) => {
+        log::info!("Torrent storage moved to {:?}", dir);
+        self.download_dir = dir.clone();
+        // the engine restarts the torrent from the new directory
+        self
+          .engine_tx
+          .send(engine::Command::StorageMoved {
+            id: self.ctx.id,
+            dir: dir.clone(),
+          })
+          .ok();
+        Alert::StorageMoved {
+          id: self.ctx.id,
+          dir,
+        }
+      }
+      Err(e) => {
+        log::warn!("Failed to move torrent storage: {}", e);
+        Alert::Error(Error::Torrent {
+          id: self.ctx.id,
+          error: TorrentError::MoveStorage(e),
+        })
+      }
+    };
+    self.ctx.alert_tx.send(alert).ok();
+  }
+
+  /// Posts an [`Alert::FilePlayable`] for each file whose prefix is now
+  /// downloaded, unless it was already posted or the torrent isn't streamed.
+  async fn post_playable_files(&mut self) {
+    let prefix_len = match self.conf.alerts.playable_prefix {
+      Some(prefix_len) if self.is_streaming => prefix_len,
+      _ => return,
+    };
+    let own_pieces = self.ctx.own_pieces().await;
+    let files = &self.ctx.storage.files;
+    for (index, is_posted) in self.playable_files.iter_mut().enumerate() {
+      if *is_posted || files[index].len == 0 {
+        continue;
+      }
+      let pieces = self.ctx.storage.file_prefix_pieces(index, prefix_len);
+      if own_pieces[pieces].all() {
+        log::info!("File {} is playable", index);
+        *is_posted = true;
+        self
+          .ctx
+          .alert_tx
+          .send(Alert::FilePlayable {
+            id: self.ctx.id,
+            file_index: index,
+          })
+          .ok();
+      }
+    }
+  }
+
+  /// Applies the new configuration, including its limits, which take effect
+  /// right away. New peer sessions use the new session configuration.
+  fn set_conf(&mut self, conf: TorrentConf) {
+    log::info!("Reloading torrent configuration");
+    self.set_limits(Limits {
+      min_requested_peer_count: Some(conf.min_requested_peer_count),
+      max_connected_peer_count: Some(conf.max_connected_peer_count),
+    });
+    self.set_rate_limits(conf.download_rate_limit, conf.upload_rate_limit);
+    if conf.alerts.completed_pieces != self.completed_pieces.is_some() {
+      self.completed_pieces = conf.alerts.completed_pieces.then(Vec::new);
+    }
+    self.conf = conf;
+  }
+
+  /// Applies the new rate limits, which the peer sessions pick up right away
+  /// as they share the torrent's rate limiters.
+  fn set_rate_limits(&mut self, down: Option<u64>, up: Option<u64>) {
+    log::info!("Setting rate limits: down {:?} b/s, up {:?} b/s", down, up);
+    self.conf.download_rate_limit = down;
+    self.conf.upload_rate_limit = up;
+    let now = Instant::now();
+    self.ctx.download_limiter.set_rate(down, now);
+    self.ctx.upload_limiter.set_rate(up, now);
+  }
+
+  /// Applies the new limits. If the maximum peer count is lowered below the
+  /// current number of peers, the surplus is disconnected.
+  fn set_limits(&mut self, limits: Limits) {
+    log::info!("Setting torrent limits: {:?}", limits);
+    limits.apply(&mut self.conf);
+    if let Some(max_connected_peer_count) = limits.max_connected_peer_count {
+      // the peers are removed once their sessions report the disconnect
+      let surplus = self.peers.len().saturating_sub(max_connected_peer_count);
+      for peer in self.peers.values_mut().take(surplus) {
+        peer.disconnect();
+      }
     }
   }
 
@@ -689,25 +2641,68 @@ impl Torrent {
     &mut self,
     addr: SocketAddr,
     info: SessionTick,
-  ) {
+  ) -> TorrentResult<()> {
     if let Some(peer) = self.peers.get_mut(&addr) {
       log::debug!("Updating peer {} state", addr);
 
       peer.state = info.state;
       peer.piece_count = info.piece_count;
       peer.thruput = ThruputStats::from(&info.counters);
+      peer.outgoing_request_count = info.outgoing_request_count;
+      peer.incoming_request_count = info.incoming_request_count;
+      peer.duplicate_request_count = info.duplicate_request_count;
+      peer.duplicate_block_count = info.duplicate_block_count;
+      self.endgame.add_growth(&peer.endgame, &info.endgame);
+      peer.endgame = info.endgame;
 
       // update torrent thruput stats
-      self.counters += &info.counters;
+      self.counters += &info.transferred;
 
       // if we disconnected peer, remove it
       if peer.state.connection == ConnectionState::Disconnected {
+        // score the peer by how it did, which decides how soon it's
+        // reconnected
+        let now = Instant::now();
+        let payload = &peer.thruput.payload;
+        self
+          .peer_pool
+          .record_rate(&addr, payload.down.peak + payload.up.peak);
+        if info.has_misbehaved {
+          self.peer_pool.record_offense(&addr, now);
+        }
+        // outbound peers are retried, with a backoff if we never got through
+        // the handshake
+        if peer.is_outbound {
+          if peer.id.is_none() {
+            self.peer_pool.record_failure(&addr, now);
+          } else {
+            self.peer_pool.record_disconnect(&addr, now);
+          }
+        }
         self.peers.remove(&addr);
-        self.ctx.piece_picker.write().await.reduce_peer_count();
+        if let Some(piece_picker) = self.ctx.piece_picker.write().await.as_mut()
+        {
+          piece_picker.reduce_peer_count();
+        }
+
+        // don't wait for the next tick if this was our last hope of peers
+        if self.is_peer_pool_dry(now) && !self.is_stopped() && !self.is_checking
+        {
+          log::info!("Torrent peer pool ran dry after {} left", addr);
+          self.announce_to_trackers(now, None).await?;
+        }
       }
     } else {
       log::debug!("Tried updating non-existent peer {}", addr);
     }
+    Ok(())
+  }
+
+  /// Determines whether the torrent has fewer connected peers than it wants
+  /// and no other peers to connect to.
+  fn is_peer_pool_dry(&self, now: Instant) -> bool {
+    self.peers.len() < self.conf.min_requested_peer_count
+      && !self.peer_pool.has_connectable(now)
   }
 
   /// Does some bookkeeping to mark the piece as finished.
@@ -719,14 +2714,31 @@ impl Torrent {
     // if this were completed a piece,
     // check torrent completion
     if piece.is_valid {
-      // remove download entry
-      self.ctx.downloads.write().await.remove(&piece.index);
+      // remove download entry, and if the piece was on parole, blame those
+      // that sent different blocks in the attempt that failed
+      let download = self
+        .ctx
+        .downloads
+        .write()
+        .await
+        .as_mut()
+        .and_then(|downloads| downloads.remove(&piece.index));
+      if let Some(download) = download {
+        let corrupt_senders = download.read().await.corrupt_senders();
+        for addr in corrupt_senders {
+          self.record_hash_failure(addr);
+        }
+      }
 
-      // register piece in piece picker
-      let mut piece_picker_write_guard = self.ctx.piece_picker.write().await;
+      // register piece in piece picker, unless we're already a seed, which
+      // has nothing more to complete
+      let mut piece_picker_guard = self.ctx.piece_picker.write().await;
+      let Some(piece_picker) = piece_picker_guard.as_mut() else {
+        return Ok(());
+      };
 
-      piece_picker_write_guard.received_piece(piece.index);
-      let missing_piece_count = piece_picker_write_guard.missing_piece_count();
+      piece_picker.received_piece(piece.index);
+      let missing_piece_count = piece_picker.missing_piece_count();
 
       // Even if we don't have all pieces,
       // they may all have already been picked.
@@ -734,13 +2746,20 @@ impl Torrent {
       // if not already in it.
       if !self.in_endgame
         && missing_piece_count > 0
-        && piece_picker_write_guard.all_pieces_picked()
+        && piece_picker.all_pieces_picked()
       {
         log::info!("Torrent entering endgame");
         self.in_endgame = true;
       }
 
-      drop(piece_picker_write_guard);
+      // from now on sessions only upload, so neither the piece picker nor
+      // the downloads are needed
+      if missing_piece_count == 0 {
+        *piece_picker_guard = None;
+        *self.ctx.downloads.write().await = None;
+        self.ctx.is_seed.store(true, Ordering::Release);
+      }
+      drop(piece_picker_guard);
 
       log::info!(
         "Downloaded piece {} (left: {})",
@@ -751,6 +2770,11 @@ impl Torrent {
       if let Some(latest_completed_pieces) = &mut self.completed_pieces {
         latest_completed_pieces.push(piece.index);
       }
+      if self.recent_pieces.len() == RECENT_PIECE_COUNT {
+        self.recent_pieces.pop_front();
+      }
+      self.recent_pieces.push_back(piece.index);
+      self.post_playable_files().await;
 
       // tell all sessions that we got a new piece so that they can send
       // a "have(piece)" message to their peers or cancel potential
@@ -778,31 +2802,223 @@ impl Torrent {
         );
 
         // notify user of torrent completion
+        self.post_completion()?;
         self
-          .ctx
-          .alert_tx
-          .send(Alert::TorrentComplete(self.ctx.id))
-          .ok();
+          .engine_tx
+          .send(engine::Command::TorrentComplete { id: self.ctx.id })?;
 
         // tell trackers we've finished
         self
           .announce_to_trackers(Instant::now(), Some(Event::Completed))
           .await?;
       }
+
+      self.update_partial_seed().await?;
+      self.check_sample().await?;
     } else {
-      // implement parole mode for the peers that sent corrupt data
       log::warn!("Piece {} is invalid", piece.index,);
-      // mark all blocks free to be requested in piece.
-      if let Some(piece) = self.ctx.downloads.read().await.get(&piece.index) {
-        piece.write().await.free_all_blocks();
+      // the whole piece has to be downloaded again
+      self.counters.waste += u64::from(self.ctx.storage.piece_len(piece.index));
+      // a piece sent by a single peer is blamed on it, while one sent by
+      // several is downloaded again from a single peer, to find out which
+      // of them sent corrupt data
+      let downloads_guard = self.ctx.downloads.read().await;
+      let senders = match downloads_guard
+        .as_ref()
+        .and_then(|downloads| downloads.get(&piece.index))
+      {
+        Some(download) => {
+          let mut download = download.write().await;
+          let senders = download.senders();
+          if senders.len() > 1 {
+            log::info!("Putting piece {} on parole", piece.index);
+            download.put_on_parole();
+          } else {
+            download.free_all_blocks();
+          }
+          senders
+        }
+        None => HashSet::new(),
+      };
+      drop(downloads_guard);
+      if let [addr] = senders.into_iter().collect::<Vec<_>>()[..] {
+        self.record_hash_failure(addr);
       }
     }
     Ok(())
   }
 
-  /// Shuts down torrent and all peer sessions, and also announces torrent's
-  /// exit to tracker.
+  /// Blames a piece failing its hash on a peer that sent corrupt blocks of
+  /// it, and bans the peer's IP if it was blamed too many times,
+  /// disconnecting any of its peers.
+  fn record_hash_failure(&mut self, addr: SocketAddr) {
+    let ip = addr.ip();
+    self.peer_pool.record_offense(&addr, Instant::now());
+    let count = self.hash_failure_counts.entry(ip).or_default();
+    *count += 1;
+    log::warn!("Peer {} sent a corrupt piece ({} time(s))", addr, count);
+    if *count < self.conf.max_hash_failures {
+      return;
+    }
+
+    log::warn!("Banning IP {} for sending corrupt data", ip);
+    self.peer_pool.ban(ip);
+    for (_, peer) in self.peers.iter_mut().filter(|(a, _)| a.ip() == ip) {
+      peer.disconnect();
+    }
+  }
+
+  /// Shuts down torrent and all peer sessions, waits for the blocks they
+  /// downloaded to be written, and then announces torrent's exit to
+  /// tracker.
   async fn shutdown(&mut self) -> TorrentResult<()> {
+    self.disconnect_peers().await;
+    self.flush_writes().await?;
+    self
+      .announce_to_trackers(Instant::now(), Some(Event::Stopped))
+      .await
+  }
+
+  /// Waits for the disk task to write the blocks the peer sessions sent it,
+  /// and registers the pieces that were completed by them, so that they're
+  /// not lost on exit and are included in the final announce.
+  ///
+  /// Any other command received in the meantime is put back in the channel,
+  /// for the torrent that takes it over if this one is being restarted.
+  async fn flush_writes(&mut self) -> TorrentResult<()> {
+    let (result_tx, result_rx) = oneshot::channel();
+    self.ctx.disk_tx.send(disk::Command::Flush {
+      id: self.ctx.id,
+      result_tx,
+    })?;
+    // the torrent may have failed to allocate, in which case there is
+    // nothing to flush
+    if result_rx.await.is_err() {
+      return Ok(());
+    }
+    // the disk task sends the pieces' results before the flush is done
+    let mut other_cmds = Vec::new();
+    while let Ok(cmd) = self.cmd_rx.try_recv() {
+      match cmd {
+        Command::PieceCompletion(Ok(piece)) => {
+          self.handle_piece_completion(piece).await?;
+        }
+        cmd => other_cmds.push(cmd),
+      }
+    }
+    for cmd in other_cmds {
+      self.ctx.cmd_tx.send(cmd).ok();
+    }
+    Ok(())
+  }
+
+  /// Unregisters the peers whose session tasks panicked, or failed before
+  /// reporting their state.
+  ///
+  /// Sessions that ended otherwise are removed once their final state is
+  /// received, so their tasks are only joined here.
+  async fn reap_finished_sessions(&mut self) {
+    let finished: Vec<_> = self
+      .peers
+      .iter()
+      .filter(|(_, peer)| {
+        peer.join_handle.as_ref().is_some_and(|h| h.is_finished())
+      })
+      .map(|(addr, _)| *addr)
+      .collect();
+
+    for addr in finished {
+      let join_handle = match self
+        .peers
+        .get_mut(&addr)
+        .and_then(|peer| peer.join_handle.take())
+      {
+        Some(join_handle) => join_handle,
+        None => continue,
+      };
+      match join_handle.await {
+        Err(e) if e.is_panic() => {
+          self.handle_session_panic(addr, e.into_panic()).await
+        }
+        Err(e) => log::error!("Peer {} session task error: {}", addr, e),
+        Ok(Err(e)) => {
+          log::error!("Peer {} session error: {}", addr, e);
+          self.handle_session_failure(addr).await;
+        }
+        Ok(Ok(())) => (),
+      }
+    }
+  }
+
+  /// Removes the peer if its session failed before it could report its
+  /// state, as when we couldn't connect to it or the handshake failed, in
+  /// which case an outbound peer is retried with a backoff.
+  async fn handle_session_failure(&mut self, addr: SocketAddr) {
+    let is_unreported = self
+      .peers
+      .get(&addr)
+      .is_some_and(|peer| peer.state.connection == ConnectionState::Connecting);
+    if !is_unreported {
+      return;
+    }
+    if let Some(peer) = self.peers.remove(&addr) {
+      if let Some(piece_picker) = self.ctx.piece_picker.write().await.as_mut() {
+        piece_picker.reduce_peer_count();
+      }
+      if peer.is_outbound {
+        self.peer_pool.record_failure(&addr, Instant::now());
+      }
+    }
+  }
+
+  /// Removes the peer whose session panicked and reports the panic.
+  ///
+  /// The session's pending requests were already returned by its task, so
+  /// only the torrent's own bookkeeping is left. An outbound peer is
+  /// reconnected later, unless its sessions panicked too many times.
+  async fn handle_session_panic(
+    &mut self,
+    addr: SocketAddr,
+    payload: Box<dyn Any + Send>,
+  ) {
+    let message = panic_message(payload.as_ref());
+    let count = self.session_panic_counts.entry(addr).or_default();
+    *count += 1;
+    let count = *count;
+    log::error!(
+      "Peer {} session panicked ({} time(s)): {}",
+      addr,
+      count,
+      message
+    );
+
+    if let Some(peer) = self.peers.remove(&addr) {
+      if let Some(piece_picker) = self.ctx.piece_picker.write().await.as_mut() {
+        piece_picker.reduce_peer_count();
+      }
+      if peer.is_outbound {
+        if count < MAX_SESSION_PANIC_COUNT {
+          self.peer_pool.record_disconnect(&addr, Instant::now());
+        } else {
+          self.peer_pool.remove(&addr);
+        }
+      }
+    }
+
+    self
+      .ctx
+      .alert_tx
+      .send(Alert::Error(Error::Peer {
+        id: self.ctx.id,
+        addr,
+        error: PeerError::Panic { message, count },
+      }))
+      .ok();
+  }
+
+  /// Shuts down all peer sessions and waits for them to finish, returning
+  /// the addresses of the outbound peers.
+  async fn disconnect_peers(&mut self) -> Vec<SocketAddr> {
     // send shutdown command to all connected peers.
     for peer in self.peers.values() {
       if let Some(tx) = &peer.tx {
@@ -812,21 +3028,37 @@ impl Torrent {
       }
     }
 
-    for peer in self.peers.values_mut() {
-      if let Err(e) = peer
-        .join_handle
-        .take()
-        .expect("peer join handle missing")
-        .await
-        .expect("task error")
-      {
-        log::error!("Peer session error: {}", e);
+    let mut outbound_addrs = Vec::new();
+    let peers: Vec<_> = self.peers.drain().collect();
+    for (addr, mut peer) in peers {
+      if let Some(join_handle) = peer.join_handle.take() {
+        match join_handle.await {
+          Ok(Err(e)) => log::error!("Peer session error: {}", e),
+          Err(e) => log::error!("Peer session task error: {}", e),
+          Ok(Ok(())) => (),
+        }
+      }
+      if let Some(piece_picker) = self.ctx.piece_picker.write().await.as_mut() {
+        piece_picker.reduce_peer_count();
+      }
+      if peer.is_outbound {
+        outbound_addrs.push(addr);
       }
     }
+    outbound_addrs
+  }
+}
 
-    self
-      .announce_to_trackers(Instant::now(), Some(Event::Stopped))
-      .await
+impl Drop for Torrent {
+  /// Aborts the peer sessions still running, which is only the case if the
+  /// torrent's task was aborted, so that they don't hold on to their
+  /// connections and permits.
+  fn drop(&mut self) {
+    for peer in self.peers.values() {
+      if let Some(join_handle) = &peer.join_handle {
+        join_handle.abort();
+      }
+    }
   }
 }
 
@@ -841,6 +3073,9 @@ struct PeerSessionEntity {
   /// Peer's 20 byte BitTorrent id. Updated when the peer sends us its peer
   /// id, in the handshake.
   id: Option<PeerId>,
+  /// The name and version of the peer's client, if it sent them in the
+  /// extension handshake.
+  client: Option<String>,
   /// Cached information about the session state. Updated every time peer
   /// updates us.
   state: SessionState,
@@ -852,32 +3087,73 @@ struct PeerSessionEntity {
 
   /// The peer session task's join handle, used during shutdown.
   join_handle: Option<task::JoinHandle<PeerResult<()>>>,
+
+  /// Whether we initiated the connection, in which case the peer's address
+  /// may be used to reconnect to it.
+  is_outbound: bool,
+
+  /// The session's pending request counts, as last reported.
+  outgoing_request_count: usize,
+  incoming_request_count: usize,
+
+  /// The session's duplicate request and block counts, as last reported.
+  duplicate_request_count: u64,
+  duplicate_block_count: u64,
+
+  /// The session's end game overhead, as last reported.
+  endgame: EndgameStats,
+
+  /// Whether the session was told to shut down to make room for another
+  /// connection, after which the peer is no longer counted against limits.
+  is_disconnecting: bool,
 }
 
 impl PeerSessionEntity {
-  fn start_outbound(mut session: PeerSession, tx: peer::Sender) -> Self {
-    let join_handle =
-      task::spawn(async move { session.start_outbound().await });
-    PeerSessionEntity::new(tx, join_handle)
+  /// Spawns the session that connects to the peer. The connection permit is
+  /// held by the session task until it ends.
+  fn start_outbound(
+    mut session: PeerSession,
+    tx: peer::Sender,
+    permit: OwnedSemaphorePermit,
+  ) -> Self {
+    let join_handle = task::spawn(async move {
+      let _permit = permit;
+      let result = AssertUnwindSafe(session.start_outbound())
+        .catch_unwind()
+        .await;
+      finish_session(&mut session, result).await
+    });
+    PeerSessionEntity::new(tx, join_handle, true)
   }
 
+  /// Spawns the session of a peer that connected to us. The connection
+  /// permit is held by the session task until it ends.
   fn start_inbound(
-    socket: TcpStream,
+    socket: PeerConnection,
+    handshake: Handshake,
     mut session: PeerSession,
     tx: peer::Sender,
+    permit: OwnedSemaphorePermit,
   ) -> Self {
-    let join_handle =
-      task::spawn(async move { session.start_inbound(socket).await });
-    PeerSessionEntity::new(tx, join_handle)
+    let join_handle = task::spawn(async move {
+      let _permit = permit;
+      let result = AssertUnwindSafe(session.start_inbound(socket, handshake))
+        .catch_unwind()
+        .await;
+      finish_session(&mut session, result).await
+    });
+    PeerSessionEntity::new(tx, join_handle, false)
   }
 
   fn new(
     tx: peer::Sender,
     join_handle: task::JoinHandle<PeerResult<()>>,
+    is_outbound: bool,
   ) -> Self {
     PeerSessionEntity {
       tx: Some(tx),
       id: None,
+      client: None,
       state: SessionState {
         connection: ConnectionState::Connecting,
         ..Default::default()
@@ -885,14 +3161,70 @@ impl PeerSessionEntity {
       piece_count: 0,
       thruput: Default::default(),
       join_handle: Some(join_handle),
+      is_outbound,
+      outgoing_request_count: 0,
+      incoming_request_count: 0,
+      duplicate_request_count: 0,
+      duplicate_block_count: 0,
+      endgame: EndgameStats::default(),
+      is_disconnecting: false,
     }
   }
+
+  /// Tells the session to shut down, after which it reports its disconnect.
+  fn disconnect(&mut self) {
+    if let Some(tx) = &self.tx {
+      tx.send(peer::Command::Shutdown).ok();
+    }
+    self.is_disconnecting = true;
+  }
+}
+
+/// The number of times the sessions with a peer may panic before it's no
+/// longer reconnected.
+const MAX_SESSION_PANIC_COUNT: usize = 3;
+
+/// The most peers that connected to us whose handshake may be read at a
+/// time, beyond which new connections are dropped until some are done.
+const MAX_PENDING_HANDSHAKE_COUNT: usize = 32;
+
+/// The number of most recently completed pieces kept in the resume data, as
+/// those are the ones that may not have reached the disk if the engine
+/// didn't shut down cleanly.
+const RECENT_PIECE_COUNT: usize = 32;
+
+/// Returns the result of the session, or if it panicked, returns its pending
+/// requests and its peer's pieces to the torrent and resumes the panic, so
+/// that it's caught by the torrent through the session task's join handle.
+async fn finish_session(
+  session: &mut PeerSession,
+  result: std::thread::Result<PeerResult<()>>,
+) -> PeerResult<()> {
+  match result {
+    Ok(result) => result,
+    Err(payload) => {
+      session.free_pending_blocks().await;
+      session.unregister_peer_pieces().await;
+      std::panic::resume_unwind(payload)
+    }
+  }
+}
+
+/// Returns the message the panic was raised with, if any.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+  if let Some(message) = payload.downcast_ref::<&str>() {
+    message.to_string()
+  } else if let Some(message) = payload.downcast_ref::<String>() {
+    message.clone()
+  } else {
+    "unknown panic".into()
+  }
 }
 
 /// Contains the tracker client as well as additional metadata about the
 /// tracker.
 struct TrackerEntry {
-  client: Tracker,
+  client: Box<dyn TrackerClient>,
   /// If a previous announce contained a tracker_id, it should be included
   /// in next announces. Therefore it is cached here.
   id: Option<String>,
@@ -904,37 +3236,70 @@ struct TrackerEntry {
   /// The interval minimum interval at which we can contact tracker.
   /// This is set after the first announce request.
   min_interval: Option<Duration>,
-  /// Each time we fail to request from tracker, this counter is incremented.
-  /// If it fails too often, we stop requesting from tracker.
-  error_count: usize,
+  /// The number of announces in a row that failed, by which the delay before
+  /// the tracker is retried grows.
+  failure_count: u32,
+  /// When the tracker may be announced to again after failing, before which
+  /// it's disabled.
+  retry_time: Option<Instant>,
+  /// The port included in the last announce, so that we can re-announce if
+  /// it changes.
+  announced_port: Option<u16>,
+  /// The number of consecutive announces made before the regular interval
+  /// because the torrent was short of peers. The minimum interval is backed
+  /// off exponentially with this count.
+  early_announce_count: u32,
+  /// The address the tracker last responded from, which tells over which IP
+  /// family we reach it.
+  remote_addr: Option<SocketAddr>,
 }
 
 impl TrackerEntry {
-  fn new(client: Tracker) -> Self {
+  fn new(client: Box<dyn TrackerClient>) -> Self {
     TrackerEntry {
       client,
       id: None,
       last_announce_time: None,
       interval: None,
       min_interval: None,
-      error_count: 0,
+      failure_count: 0,
+      retry_time: None,
+      announced_port: None,
+      early_announce_count: 0,
+      remote_addr: None,
     }
   }
 
+  /// Determines whether the tracker was last announced a port other than
+  /// the given one. Trackers not announced to yet don't have a stale port.
+  fn has_stale_port(&self, port: u16) -> bool {
+    self.announced_port.is_some_and(|p| p != port)
+  }
+
+  /// Returns the regular interval at which to announce to the tracker.
+  ///
+  /// This is the interval requested by the tracker, kept within the
+  /// configured bounds, or the configured interval if the tracker hasn't
+  /// specified one (e.g. before the first announce).
+  ///
+  /// It's never shorter than the tracker's min interval though, even past
+  /// the configured max, as an overloaded tracker may push back with a longer
+  /// one which we must honor.
+  fn announce_interval(&self, conf: &TorrentConf) -> Duration {
+    let interval = match self.interval {
+      Some(interval) => {
+        interval.clamp(conf.min_announce_interval, conf.max_announce_interval)
+      }
+      None => conf.announce_interval,
+    };
+    interval.max(self.min_announce_interval(conf))
+  }
+
   /// Determines whether we should announce to the tracker at the given time,
   /// based on when we last announced.
-  ///
-  /// Later this function should take into consideration the client's minimum
-  /// announce frequency settings.
-  fn should_announce(
-    &self,
-    t: Instant,
-    default_announce_interval: Duration,
-  ) -> bool {
+  fn should_announce(&self, t: Instant, conf: &TorrentConf) -> bool {
     if let Some(last_announce_time) = self.last_announce_time {
-      let min_next_announce_time =
-        last_announce_time + self.interval.unwrap_or(default_announce_interval);
-      t > min_next_announce_time
+      t > last_announce_time + self.announce_interval(conf)
     } else {
       true
     }
@@ -944,18 +3309,210 @@ impl TrackerEntry {
   ///
   /// We may need peers before the next step in the announce interval.
   /// However, we can't do this too often, so we need to check our last
-  /// announce time first.
-  fn can_announce(
-    &self,
-    t: Instant,
-    default_announce_interval: Duration,
-  ) -> bool {
+  /// announce time first. The minimum interval is doubled for each early
+  /// announce in a row, but it never exceeds the regular interval.
+  fn can_announce(&self, t: Instant, conf: &TorrentConf) -> bool {
     if let Some(last_announce_time) = self.last_announce_time {
-      let min_next_announce_time = last_announce_time
-        + self.min_interval.unwrap_or(default_announce_interval);
-      t > min_next_announce_time
+      let min_interval = self.min_announce_interval(conf);
+      let interval = self.announce_interval(conf);
+      let backoff = 2u32.saturating_pow(self.early_announce_count);
+      let min_interval = min_interval.saturating_mul(backoff).min(interval);
+      t > last_announce_time + min_interval
     } else {
       true
     }
   }
+
+  /// Returns the delay before the tracker is retried after its latest
+  /// failure, before jitter, which doubles with each failure in a row up to
+  /// the configured max.
+  fn retry_delay(&self, conf: &TrackerBackoffConf) -> Duration {
+    let backoff = 2u32.saturating_pow(self.failure_count.saturating_sub(1));
+    conf
+      .initial_delay
+      .saturating_mul(backoff)
+      .min(conf.max_delay)
+  }
+
+  /// Determines whether we're allowed to announce at the given time without
+  /// backing off, which is when the torrent ran out of peers altogether.
+  fn can_announce_unbacked(&self, t: Instant, conf: &TorrentConf) -> bool {
+    self.last_announce_time.is_none_or(|last_announce_time| {
+      t > last_announce_time + self.min_announce_interval(conf)
+    })
+  }
+
+  /// Returns the minimum interval between announces, which is the one the
+  /// tracker requested, or the configured one if it hasn't requested any or
+  /// it's to be ignored.
+  fn min_announce_interval(&self, conf: &TorrentConf) -> Duration {
+    self
+      .min_interval
+      .filter(|_| !conf.ignore_tracker_min_interval)
+      .unwrap_or(conf.min_announce_interval)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tracker::tracker::HttpTracker;
+
+  #[test]
+  fn test_panic_message() {
+    let payload = std::panic::catch_unwind(|| panic!("boom")).unwrap_err();
+    assert_eq!(panic_message(payload.as_ref()), "boom");
+
+    let index = 3;
+    let payload =
+      std::panic::catch_unwind(|| panic!("bad index {}", index)).unwrap_err();
+    assert_eq!(panic_message(payload.as_ref()), "bad index 3");
+
+    let payload =
+      std::panic::catch_unwind(|| std::panic::panic_any(42)).unwrap_err();
+    assert_eq!(panic_message(payload.as_ref()), "unknown panic");
+  }
+
+  #[test]
+  fn test_tracker_stale_port() {
+    let url = "http://tracker.example.com/announce".parse().unwrap();
+    let mut tracker = TrackerEntry::new(Box::new(HttpTracker::new(url)));
+    assert!(!tracker.has_stale_port(6881));
+
+    tracker.announced_port = Some(6881);
+    assert!(!tracker.has_stale_port(6881));
+    assert!(tracker.has_stale_port(6882));
+  }
+
+  #[test]
+  fn test_tracker_announce_interval() {
+    let url = "http://tracker.example.com/announce".parse().unwrap();
+    let mut tracker = TrackerEntry::new(Box::new(HttpTracker::new(url)));
+    let conf = TorrentConf::default();
+    assert_eq!(tracker.announce_interval(&conf), conf.announce_interval);
+
+    // the tracker's interval is used, within bounds
+    tracker.interval = Some(Duration::from_secs(15 * 60));
+    assert_eq!(
+      tracker.announce_interval(&conf),
+      Duration::from_secs(15 * 60)
+    );
+    tracker.interval = Some(Duration::from_secs(1));
+    assert_eq!(tracker.announce_interval(&conf), conf.min_announce_interval);
+    tracker.interval = Some(Duration::from_secs(24 * 60 * 60));
+    assert_eq!(tracker.announce_interval(&conf), conf.max_announce_interval);
+
+    let now = Instant::now();
+    tracker.last_announce_time = Some(now);
+    assert!(!tracker.should_announce(now, &conf));
+    assert!(tracker.should_announce(
+      now + conf.max_announce_interval + Duration::from_secs(1),
+      &conf
+    ));
+
+    // an overloaded tracker's longer min interval is honored past the max,
+    // unless configured not to be
+    let min_interval = conf.max_announce_interval * 2;
+    tracker.min_interval = Some(min_interval);
+    assert_eq!(tracker.announce_interval(&conf), min_interval);
+    assert!(!tracker.should_announce(
+      now + conf.max_announce_interval + Duration::from_secs(1),
+      &conf
+    ));
+    assert!(tracker
+      .should_announce(now + min_interval + Duration::from_secs(1), &conf));
+    let conf = TorrentConf {
+      ignore_tracker_min_interval: true,
+      ..conf
+    };
+    assert_eq!(tracker.announce_interval(&conf), conf.max_announce_interval);
+  }
+
+  #[test]
+  fn test_tracker_early_announce_backoff() {
+    let url = "http://tracker.example.com/announce".parse().unwrap();
+    let mut tracker = TrackerEntry::new(Box::new(HttpTracker::new(url)));
+    let conf = TorrentConf {
+      min_announce_interval: Duration::from_secs(60),
+      announce_interval: Duration::from_secs(60 * 60),
+      ..Default::default()
+    };
+    let interval = conf.announce_interval;
+    let now = Instant::now();
+    assert!(tracker.can_announce(now, &conf));
+
+    tracker.last_announce_time = Some(now);
+    let t = now + Duration::from_secs(61);
+    assert!(tracker.can_announce(t, &conf));
+
+    // each early announce doubles the wait
+    tracker.early_announce_count = 2;
+    assert!(!tracker.can_announce(t, &conf));
+    assert!(tracker.can_announce(now + Duration::from_secs(4 * 60 + 1), &conf));
+
+    // but never beyond the regular interval
+    tracker.early_announce_count = 30;
+    assert!(
+      tracker.can_announce(now + interval + Duration::from_secs(1), &conf)
+    );
+
+    // the tracker's own minimum interval takes precedence
+    tracker.early_announce_count = 0;
+    tracker.min_interval = Some(Duration::from_secs(5 * 60));
+    assert!(!tracker.can_announce(t, &conf));
+
+    // unless it's ignored
+    let conf = TorrentConf {
+      ignore_tracker_min_interval: true,
+      ..conf
+    };
+    assert!(tracker.can_announce(t, &conf));
+  }
+
+  #[test]
+  fn test_tracker_retry_delay() {
+    let url = "http://tracker.example.com/announce".parse().unwrap();
+    let mut tracker = TrackerEntry::new(Box::new(HttpTracker::new(url)));
+    let conf = TrackerBackoffConf {
+      initial_delay: Duration::from_secs(30),
+      max_delay: Duration::from_secs(60 * 60),
+    };
+
+    tracker.failure_count = 1;
+    assert_eq!(tracker.retry_delay(&conf), Duration::from_secs(30));
+    tracker.failure_count = 3;
+    assert_eq!(tracker.retry_delay(&conf), Duration::from_secs(2 * 60));
+    // the delay is capped, even once doubling it would overflow
+    tracker.failure_count = 8;
+    assert_eq!(tracker.retry_delay(&conf), conf.max_delay);
+    tracker.failure_count = 40;
+    assert_eq!(tracker.retry_delay(&conf), conf.max_delay);
+  }
+
+  #[test]
+  fn test_tracker_announce_when_peer_pool_dry() {
+    let url = "http://tracker.example.com/announce".parse().unwrap();
+    let mut tracker = TrackerEntry::new(Box::new(HttpTracker::new(url)));
+    let conf = TorrentConf {
+      min_announce_interval: Duration::from_secs(60),
+      announce_interval: Duration::from_secs(60 * 60),
+      ..Default::default()
+    };
+    let now = Instant::now();
+    assert!(tracker.can_announce_unbacked(now, &conf));
+
+    // backoff doesn't apply once out of peers
+    tracker.last_announce_time = Some(now);
+    tracker.early_announce_count = 5;
+    let t = now + Duration::from_secs(61);
+    assert!(!tracker.can_announce(t, &conf));
+    assert!(tracker.can_announce_unbacked(t, &conf));
+    assert!(!tracker.can_announce_unbacked(now, &conf));
+
+    // but the tracker's minimum interval still does
+    tracker.min_interval = Some(Duration::from_secs(5 * 60));
+    assert!(!tracker.can_announce_unbacked(t, &conf));
+    assert!(tracker
+      .can_announce_unbacked(now + Duration::from_secs(5 * 60 + 1), &conf));
+  }
 }
@@ -0,0 +1,443 @@
+//! The pool of peer addresses the torrent knows of, whether from trackers,
+//! the DHT, the user, or a previous run.
+//!
+//! Each address keeps the history of our connection attempts: a peer we
+//! failed to connect to is retried after a delay that doubles with each
+//! consecutive failure, and is forgotten after too many of them, while a
+//! peer that disconnects after a working connection is reconnected after a
+//! short while.
+//!
+//! Each address is also scored by how the peer did in past sessions: the
+//! fastest it transferred raises its score, while corrupt pieces and
+//! protocol violations lower it. Peers with higher scores are connected to
+//! first, and those whose score falls too low aren't retried for a while.
+
+use std::{
+  cmp::Reverse,
+  collections::{HashMap, HashSet},
+  net::{IpAddr, SocketAddr},
+  time::{Duration, Instant},
+};
+
+use crate::tracker::tracker::IpFamily;
+
+/// The delay before retrying a peer after the first failed connection
+/// attempt, which doubles with each further failure.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// The longest delay between two connection attempts to a peer.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// The delay before reconnecting a peer that disconnected after a working
+/// connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// After this many failed connection attempts in a row the peer is deemed
+/// dead and removed from the pool.
+const MAX_FAILURE_COUNT: u32 = 8;
+
+/// The transfer rate, in bytes per second, that earns a peer a point of
+/// score, which is a block a second.
+const RATE_SCORE_UNIT: u64 = 16 * 1024;
+
+/// The most points a peer may earn by its transfer rate, so that a once
+/// fast peer can't make up for any number of offenses.
+const MAX_RATE_SCORE: i64 = 64;
+
+/// The points a peer loses for each corrupt piece or protocol violation.
+const OFFENSE_PENALTY: i64 = 16;
+
+/// A peer whose score falls to this is not retried for a while, which a
+/// peer that never transferred anything reaches with its second offense.
+const LOW_SCORE: i64 = -2 * OFFENSE_PENALTY;
+
+/// How long a peer with a low score is not retried.
+const LOW_SCORE_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// The most addresses kept in the pool, so that trackers, the DHT and peer
+/// exchange can't make a torrent use an arbitrary amount of memory.
+const MAX_POOL_SIZE: usize = 2000;
+
+#[derive(Debug, Default)]
+pub struct PeerPool {
+  peers: HashMap<SocketAddr, PeerEntry>,
+  /// Incremented for each new address, so that peers are connected to in the
+  /// order we learned of them.
+  next_seq: u64,
+  /// The IPs of the peers banned for sending corrupt data, which are not
+  /// added again whatever the port.
+  banned_ips: HashSet<IpAddr>,
+}
+
+#[derive(Debug)]
+struct PeerEntry {
+  /// When the address was added relative to the other addresses.
+  seq: u64,
+  /// The number of consecutive failed connection attempts.
+  failure_count: u32,
+  /// The earliest time we may connect to the peer again, or `None` if we
+  /// may connect right away.
+  retry_time: Option<Instant>,
+  /// Whether a session with the peer is in progress.
+  is_connected: bool,
+  /// The highest payload rate of any of the sessions with the peer, in
+  /// bytes per second, in both directions combined.
+  best_rate: u64,
+  /// The number of corrupt pieces and protocol violations of the peer.
+  offense_count: u32,
+}
+
+impl PeerPool {
+  /// Adds a peer to the pool, unless it's already there, in which case its
+  /// history is kept, or it's banned.
+  ///
+  /// If the pool is full, the worst peer not connected to is evicted to
+  /// make room, unless it's no worse than a new peer, in which case the new
+  /// peer isn't added.
+  pub fn add(&mut self, addr: SocketAddr) {
+    let addr = canonical(addr);
+    if self.banned_ips.contains(&addr.ip()) || self.peers.contains_key(&addr) {
+      return;
+    }
+    if self.peers.len() >= MAX_POOL_SIZE && !self.evict_worse_than_new() {
+      return;
+    }
+    self.peers.insert(
+      addr,
+      PeerEntry {
+        seq: self.next_seq,
+        failure_count: 0,
+        retry_time: None,
+        is_connected: false,
+        best_rate: 0,
+        offense_count: 0,
+      },
+    );
+    self.next_seq += 1;
+  }
+
+  /// Evicts the worst peer not connected to, if it's worse than a peer we
+  /// know nothing of, and returns whether one was evicted.
+  fn evict_worse_than_new(&mut self) -> bool {
+    let worst = self
+      .peers
+      .iter()
+      .filter(|(_, e)| !e.is_connected)
+      .min_by_key(|(_, e)| e.rank())
+      .filter(|(_, e)| e.rank() < (0, Reverse(0)))
+      .map(|(addr, _)| *addr);
+    match worst {
+      Some(addr) => {
+        log::debug!("Evicting peer {} from full pool", addr);
+        self.peers.remove(&addr);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Removes a peer from the pool along with its history.
+  pub fn remove(&mut self, addr: &SocketAddr) {
+    self.peers.remove(&canonical(*addr));
+  }
+
+  /// Removes the peers of the IP from the pool and keeps them from being
+  /// added again.
+  pub fn ban(&mut self, ip: IpAddr) {
+    let ip = ip.to_canonical();
+    self.peers.retain(|addr, _| addr.ip() != ip);
+    self.banned_ips.insert(ip);
+  }
+
+  /// Returns whether the IP is banned.
+  pub fn is_banned(&self, ip: IpAddr) -> bool {
+    self.banned_ips.contains(&ip.to_canonical())
+  }
+
+  /// Returns the addresses of all peers in the pool, including the ones
+  /// connected to.
+  pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+    self.peers.keys().copied()
+  }
+
+  /// Returns the number of peers not currently connected to, including those
+  /// waiting to be retried.
+  pub fn idle_count(&self) -> usize {
+    self.peers.values().filter(|e| !e.is_connected).count()
+  }
+
+  /// Returns whether there is any peer we may connect to right now.
+  pub fn has_connectable(&self, now: Instant) -> bool {
+    self.peers.values().any(|e| e.is_connectable(now))
+  }
+
+  /// Returns the peers we may connect to right now in the order they should
+  /// be tried: those of the preferred family first, then those with the
+  /// highest score, then those that failed the fewest times, then those we
+  /// learned of first.
+  pub fn connectable(
+    &self,
+    now: Instant,
+    family: Option<IpFamily>,
+  ) -> Vec<SocketAddr> {
+    let mut peers: Vec<_> = self
+      .peers
+      .iter()
+      .filter(|(_, e)| e.is_connectable(now))
+      .collect();
+    peers.sort_unstable_by_key(|(addr, e)| {
+      let is_other_family = family.is_some_and(|f| !f.contains(addr));
+      (is_other_family, Reverse(e.score()), e.failure_count, e.seq)
+    });
+    peers.into_iter().map(|(addr, _)| *addr).collect()
+  }
+
+  /// Marks the peer as connected to, until its session ends.
+  pub fn mark_connected(&mut self, addr: &SocketAddr) {
+    if let Some(entry) = self.peers.get_mut(&canonical(*addr)) {
+      entry.is_connected = true;
+    }
+  }
+
+  /// Records a failed connection attempt, after which the peer is retried
+  /// with a backoff, or removed if it failed too many times.
+  pub fn record_failure(&mut self, addr: &SocketAddr, now: Instant) {
+    let addr = &canonical(*addr);
+    let Some(entry) = self.peers.get_mut(addr) else {
+      return;
+    };
+    entry.failure_count += 1;
+    if entry.failure_count >= MAX_FAILURE_COUNT {
+      log::debug!(
+        "Removing peer {} after {} failed attempts",
+        addr,
+        entry.failure_count
+      );
+      self.peers.remove(addr);
+      return;
+    }
+    let delay = INITIAL_RETRY_DELAY
+      .saturating_mul(1 << (entry.failure_count - 1))
+      .min(MAX_RETRY_DELAY);
+    log::debug!("Retrying peer {} in {:?}", addr, delay);
+    // a low score may keep the peer from being retried for longer
+    entry.delay_retry(now + delay);
+    entry.is_connected = false;
+  }
+
+  /// Records the end of a working connection, after which the peer may be
+  /// reconnected after a short while, unless its score keeps it from being
+  /// retried for longer.
+  pub fn record_disconnect(&mut self, addr: &SocketAddr, now: Instant) {
+    if let Some(entry) = self.peers.get_mut(&canonical(*addr)) {
+      entry.failure_count = 0;
+      entry.delay_retry(now + RECONNECT_DELAY);
+      entry.is_connected = false;
+    }
+  }
+
+  /// Records the payload rate, in bytes per second, of a session with the
+  /// peer, which raises its score if it's the best so far.
+  pub fn record_rate(&mut self, addr: &SocketAddr, rate: u64) {
+    if let Some(entry) = self.peers.get_mut(&canonical(*addr)) {
+      entry.best_rate = entry.best_rate.max(rate);
+    }
+  }
+
+  /// Records that the peer sent a corrupt piece or broke the protocol,
+  /// which lowers its score, and if it falls too low, keeps the peer from
+  /// being retried for a while.
+  pub fn record_offense(&mut self, addr: &SocketAddr, now: Instant) {
+    let Some(entry) = self.peers.get_mut(&canonical(*addr)) else {
+      return;
+    };
+    entry.offense_count += 1;
+    if entry.score() <= LOW_SCORE {
+      log::debug!(
+        "Not retrying peer {} with score {} for {:?}",
+        addr,
+        entry.score(),
+        LOW_SCORE_DELAY
+      );
+      entry.delay_retry(now + LOW_SCORE_DELAY);
+    }
+  }
+
+  /// Marks the peer as not connected to, to be reconnected whenever we can,
+  /// as when we disconnected it ourselves.
+  pub fn release(&mut self, addr: &SocketAddr) {
+    if let Some(entry) = self.peers.get_mut(&canonical(*addr)) {
+      entry.retry_time = None;
+      entry.is_connected = false;
+    }
+  }
+}
+
+/// Returns the address by which the peer is stored in the pool: IPv4-mapped
+/// IPv6 addresses are stored as the IPv4 address, which is how peers
+/// connecting to us are reported, so that the same peer isn't known by two
+/// addresses.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+  SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+impl PeerEntry {
+  fn is_connectable(&self, now: Instant) -> bool {
+    !self.is_connected && self.retry_time.is_none_or(|t| now >= t)
+  }
+
+  /// Keeps the peer from being retried until the given time, unless it
+  /// already is for longer.
+  fn delay_retry(&mut self, until: Instant) {
+    self.retry_time = Some(self.retry_time.map_or(until, |t| t.max(until)));
+  }
+
+  /// Returns how the peer ranks against the others when the pool is full,
+  /// the worst first: by score, then by the failures in a row.
+  fn rank(&self) -> (i64, Reverse<u32>) {
+    (self.score(), Reverse(self.failure_count))
+  }
+
+  /// Returns how good a peer this was in past sessions.
+  fn score(&self) -> i64 {
+    let rate_score =
+      ((self.best_rate / RATE_SCORE_UNIT) as i64).min(MAX_RATE_SCORE);
+    rate_score - OFFENSE_PENALTY * self.offense_count as i64
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+  }
+
+  #[test]
+  fn test_peer_pool() {
+    let mut pool = PeerPool::default();
+    let now = Instant::now();
+    pool.add(addr(1));
+    pool.add(addr(2));
+    pool.add(addr(3));
+    // adding a known peer changes nothing
+    pool.add(addr(1));
+    // nor does adding it by its IPv4-mapped address
+    pool.add("[::ffff:127.0.0.1]:1".parse().unwrap());
+    assert_eq!(pool.connectable(now, None), [addr(1), addr(2), addr(3)]);
+
+    // connected peers aren't connectable
+    pool.mark_connected(&addr(1));
+    pool.mark_connected(&addr(2));
+    assert_eq!(pool.connectable(now, None), [addr(3)]);
+    assert_eq!(pool.idle_count(), 1);
+
+    // a failed peer is retried after a backoff, a disconnected one after
+    // the reconnect delay
+    pool.record_failure(&addr(1), now);
+    pool.record_disconnect(&addr(2), now);
+    assert_eq!(pool.connectable(now, None), [addr(3)]);
+    assert_eq!(
+      pool.connectable(now + INITIAL_RETRY_DELAY, None),
+      [addr(3), addr(1)]
+    );
+    // peers that failed are tried last
+    assert_eq!(
+      pool.connectable(now + RECONNECT_DELAY, None),
+      [addr(2), addr(3), addr(1)]
+    );
+
+    // the backoff doubles with each failure
+    pool.mark_connected(&addr(1));
+    pool.record_failure(&addr(1), now);
+    assert!(!pool
+      .connectable(now + INITIAL_RETRY_DELAY, None)
+      .contains(&addr(1)));
+    assert!(pool
+      .connectable(now + 2 * INITIAL_RETRY_DELAY, None)
+      .contains(&addr(1)));
+
+    // and the peer is removed after too many failures
+    for _ in 2..MAX_FAILURE_COUNT {
+      pool.record_failure(&addr(1), now);
+    }
+    assert!(!pool.addrs().any(|a| a == addr(1)));
+    assert!(pool.has_connectable(now + MAX_RETRY_DELAY));
+
+    // a peer that transferred faster is tried first, while one that
+    // offended too many times isn't tried for a while
+    pool.record_rate(&addr(3), 10 * RATE_SCORE_UNIT);
+    pool.add(addr(5));
+    pool.add(addr(6));
+    assert_eq!(pool.connectable(now, None), [addr(3), addr(5), addr(6)]);
+    pool.record_offense(&addr(5), now);
+    assert_eq!(pool.connectable(now, None), [addr(3), addr(6), addr(5)]);
+    pool.record_offense(&addr(5), now);
+    assert_eq!(pool.connectable(now, None), [addr(3), addr(6)]);
+    assert!(pool
+      .connectable(now + LOW_SCORE_DELAY, None)
+      .contains(&addr(5)));
+
+    // a failure doesn't cut short the delay of a low score
+    pool.mark_connected(&addr(5));
+    pool.record_failure(&addr(5), now);
+    assert!(!pool
+      .connectable(now + INITIAL_RETRY_DELAY, None)
+      .contains(&addr(5)));
+
+    // a banned IP's peers are removed and not added again, whatever the port
+    pool.ban(addr(2).ip());
+    assert!(pool.is_banned(addr(2).ip()));
+    pool.add(addr(4));
+    assert_eq!(pool.addrs().count(), 0);
+  }
+
+  #[test]
+  fn test_peer_pool_canonicalizes_addrs() {
+    let mut pool = PeerPool::default();
+    let now = Instant::now();
+    let mapped: SocketAddr = "[::ffff:127.0.0.1]:1".parse().unwrap();
+    pool.add(mapped);
+    assert_eq!(pool.addrs().collect::<Vec<_>>(), [addr(1)]);
+
+    // the peer is found by either of its addresses
+    pool.mark_connected(&mapped);
+    assert!(pool.connectable(now, None).is_empty());
+    pool.release(&mapped);
+    assert_eq!(pool.connectable(now, None), [addr(1)]);
+    pool.remove(&mapped);
+    assert_eq!(pool.addrs().count(), 0);
+
+    pool.ban(mapped.ip());
+    assert!(pool.is_banned(addr(1).ip()));
+  }
+
+  #[test]
+  fn test_full_peer_pool_evicts_worst_peer() {
+    let mut pool = PeerPool::default();
+    let now = Instant::now();
+    let addr = |i: usize| SocketAddr::from(([10, 0, 0, 1], i as u16 + 1));
+    for i in 0..MAX_POOL_SIZE {
+      pool.add(addr(i));
+    }
+    // a new peer isn't added in place of a peer that's no worse
+    pool.add(addr(MAX_POOL_SIZE));
+    assert_eq!(pool.addrs().count(), MAX_POOL_SIZE);
+    assert!(!pool.addrs().any(|a| a == addr(MAX_POOL_SIZE)));
+
+    // but evicts the worst peer, one that failed, while a connected peer
+    // isn't evicted however bad
+    pool.mark_connected(&addr(0));
+    pool.record_offense(&addr(0), now);
+    pool.record_failure(&addr(1), now);
+    pool.record_failure(&addr(2), now);
+    pool.record_failure(&addr(2), now);
+    pool.add(addr(MAX_POOL_SIZE));
+    assert_eq!(pool.addrs().count(), MAX_POOL_SIZE);
+    assert!(pool.addrs().any(|a| a == addr(MAX_POOL_SIZE)));
+    assert!(pool.addrs().any(|a| a == addr(0)));
+    assert!(pool.addrs().any(|a| a == addr(1)));
+    assert!(!pool.addrs().any(|a| a == addr(2)));
+  }
+}
@@ -0,0 +1,130 @@
+//! The canonical peer priority of [BEP 40], an ordering of peers that both
+//! ends of a connection agree on, so that clients which have to disconnect
+//! some of their peers drop the same connections as their peers would.
+//!
+//! [BEP 40]: http://bittorrent.org/beps/bep_0040.html
+
+use std::{
+  cmp::Ordering,
+  net::{IpAddr, SocketAddr},
+};
+
+/// Returns the priority of the connection between the two addresses, which
+/// is the same whichever way they are given.
+///
+/// The addresses are masked so that peers can't easily pick an address of a
+/// high priority, unless they are in the same subnet, and if they share the
+/// IP, their ports are used instead.
+pub fn peer_priority(a: SocketAddr, b: SocketAddr) -> u32 {
+  let (mut a, mut b) = masked(a, b);
+  if a > b {
+    std::mem::swap(&mut a, &mut b);
+  }
+  a.extend_from_slice(&b);
+  crc32c(&a)
+}
+
+/// Returns whether the first address comes before the second in the order
+/// in which their priority is computed, which, unlike the peer ids, both
+/// ends know before the handshake and agree on.
+pub fn is_canonically_first(a: SocketAddr, b: SocketAddr) -> bool {
+  let (a, b) = masked(a, b);
+  a <= b
+}
+
+/// Returns the bytes of each address that the priority is computed from:
+/// the ports if they share the IP, or else the masked IPs.
+fn masked(a: SocketAddr, b: SocketAddr) -> (Vec<u8>, Vec<u8>) {
+  if a.ip() == b.ip() {
+    return (
+      a.port().to_be_bytes().to_vec(),
+      b.port().to_be_bytes().to_vec(),
+    );
+  }
+
+  let (mut a, mut b) = match (a.ip(), b.ip()) {
+    (IpAddr::V4(a), IpAddr::V4(b)) => {
+      (a.octets().to_vec(), b.octets().to_vec())
+    }
+    (a, b) => (ipv6_octets(a).to_vec(), ipv6_octets(b).to_vec()),
+  };
+  // Only the network prefix, a /16 or a /48, is kept as is, the rest being
+  // masked with 0x55. Addresses that share the prefix have one more byte
+  // kept, and those that share that byte too are kept whole.
+  let prefix_len = if a.len() == 4 { 2 } else { 6 };
+  let shared_len = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+  let kept_len = match shared_len.cmp(&prefix_len) {
+    Ordering::Less => prefix_len,
+    Ordering::Equal => prefix_len + 1,
+    Ordering::Greater => a.len(),
+  };
+  for addr in [&mut a, &mut b] {
+    for byte in &mut addr[kept_len..] {
+      *byte &= 0x55;
+    }
+  }
+  (a, b)
+}
+
+/// Returns the octets of the IPv6 address, or of the IPv4-mapped IPv6
+/// address, so that addresses of different families can be compared.
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+  match ip {
+    IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+    IpAddr::V6(ip) => ip.octets(),
+  }
+}
+
+/// Computes the CRC-32C (Castagnoli) checksum of the bytes.
+fn crc32c(bytes: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for &byte in bytes {
+    crc ^= u32::from(byte);
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ 0x82f6_3b78
+      } else {
+        crc >> 1
+      };
+    }
+  }
+  !crc
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_peer_priority() {
+    // the examples given in the BEP
+    let a: SocketAddr = "123.213.32.10:6881".parse().unwrap();
+    let b: SocketAddr = "98.76.54.32:6881".parse().unwrap();
+    assert_eq!(peer_priority(a, b), 0xec2d7224);
+    assert_eq!(peer_priority(b, a), 0xec2d7224);
+
+    let b: SocketAddr = "123.213.32.234:6881".parse().unwrap();
+    assert_eq!(peer_priority(a, b), 0x99568189);
+
+    // the ports decide between the same IPs
+    let b: SocketAddr = "123.213.32.10:6882".parse().unwrap();
+    assert_eq!(peer_priority(a, b), crc32c(&[0x1a, 0xe1, 0x1a, 0xe2]));
+    assert_eq!(peer_priority(b, a), peer_priority(a, b));
+  }
+
+  #[test]
+  fn test_canonical_order() {
+    let a: SocketAddr = "123.213.32.10:6881".parse().unwrap();
+    let b: SocketAddr = "98.76.54.32:6881".parse().unwrap();
+    assert!(is_canonically_first(b, a));
+    assert!(!is_canonically_first(a, b));
+
+    // the masked bytes decide, not the whole address
+    let b: SocketAddr = "123.213.32.234:6881".parse().unwrap();
+    assert!(is_canonically_first(a, b));
+    assert!(!is_canonically_first(b, a));
+
+    let b: SocketAddr = "123.213.32.10:6880".parse().unwrap();
+    assert!(is_canonically_first(b, a));
+  }
+}
@@ -1,26 +1,59 @@
+//! Statistics of a torrent, sent to the user with each torrent tick.
+//!
+//! All stats types can be serialized, e.g. for sending them to a web UI. The
+//! serialized field and variant names are `snake_case` and are considered
+//! part of the public API. To reduce payload sizes when streaming stats,
+//! [`TorrentStats::diff`] may be used to only send what changed since the
+//! previous tick.
+
 use std::{
   net::SocketAddr,
   time::{Duration, Instant},
 };
 
+use serde_derive::Serialize;
+
 use crate::{
   counter::{ChannelCounter, Counter, ThruputCounters},
   peer::session::SessionState,
+  storage_info::FileInfo,
   PeerId, PieceIndex,
 };
 
+use super::FilePriority;
+
 /// Aggregate statistics of a torrent.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct TorrentStats {
   /// When the torrent was first started.
+  ///
+  /// This is not serialized as an instant is only meaningful within the
+  /// process.
+  #[serde(skip)]
   pub start_time: Option<Instant>,
 
+  /// What the torrent is currently doing.
+  pub state: TorrentState,
+
   /// How long the torrent has been running.
   pub run_duration: Duration,
 
   /// Aggregate statistics about a torrent's pieces.
   pub pieces: PieceStats,
 
+  /// The total length of the files that are to be downloaded.
+  ///
+  /// Unlike the piece stats, this does not include skipped files, so it
+  /// reflects the progress of selective downloads.
+  pub wanted_bytes: u64,
+
+  /// The number of downloaded bytes of the wanted files.
+  pub completed_wanted_bytes: u64,
+
+  /// The ratio of the downloaded bytes of the wanted files, between 0 and 1.
+  /// It's 1 if no files are wanted.
+  pub progress_wanted: f64,
+
   /// The peers of the torrent.
   ///
   /// By default, only the number of connected peers are sent with each
@@ -30,12 +63,144 @@ pub struct TorrentStats {
   /// of peers with aggregate statistics is sent with each tick.
   pub peers: Peers,
 
+  /// The torrent's files with their progress.
+  ///
+  /// These are only sent with each tick if enabled in the torrent's
+  /// configuration, as there may be many of them. They may always be
+  /// queried with [`TorrentHandle::files`].
+  ///
+  /// [`TorrentHandle::files`]: super::handle::TorrentHandle::files
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub files: Option<Vec<FileStats>>,
+
   /// Various thruput statistics of the torrent.
   pub thruput: ThruputStats,
+
+  /// The cost of the duplicate requests made in endgame.
+  pub endgame: EndgameStats,
+
+  /// The labels the user attached to the torrent.
+  pub labels: Vec<String>,
+}
+
+impl TorrentStats {
+  /// Returns the fields that changed since the previous stats of the same
+  /// torrent.
+  pub fn diff(&self, prev: &TorrentStats) -> TorrentStatsDiff {
+    fn changed<T: PartialEq + Clone>(curr: &T, prev: &T) -> Option<T> {
+      if curr != prev {
+        Some(curr.clone())
+      } else {
+        None
+      }
+    }
+
+    TorrentStatsDiff {
+      state: changed(&self.state, &prev.state),
+      run_duration: changed(&self.run_duration, &prev.run_duration),
+      pieces: changed(&self.pieces, &prev.pieces),
+      wanted_bytes: changed(&self.wanted_bytes, &prev.wanted_bytes),
+      completed_wanted_bytes: changed(
+        &self.completed_wanted_bytes,
+        &prev.completed_wanted_bytes,
+      ),
+      progress_wanted: changed(&self.progress_wanted, &prev.progress_wanted),
+      peers: changed(&self.peers, &prev.peers),
+      files: changed(&self.files, &prev.files),
+      thruput: changed(&self.thruput, &prev.thruput),
+      endgame: changed(&self.endgame, &prev.endgame),
+      labels: changed(&self.labels, &prev.labels),
+    }
+  }
+}
+
+/// The fields of [`TorrentStats`] that changed between two snapshots, as
+/// returned by [`TorrentStats::diff`].
+///
+/// Unchanged fields are `None` and are omitted when serialized.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TorrentStatsDiff {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub state: Option<TorrentState>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub run_duration: Option<Duration>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub pieces: Option<PieceStats>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub wanted_bytes: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub completed_wanted_bytes: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub progress_wanted: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub peers: Option<Peers>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub files: Option<Option<Vec<FileStats>>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub thruput: Option<ThruputStats>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub endgame: Option<EndgameStats>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub labels: Option<Vec<String>>,
+}
+
+impl TorrentStatsDiff {
+  /// Returns true if nothing changed between the two snapshots.
+  pub fn is_empty(&self) -> bool {
+    *self == Self::default()
+  }
+}
+
+/// The state of a running torrent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentState {
+  /// The torrent was added without its metadata (e.g. from a magnet link),
+  /// which is being fetched. Until it arrives, the torrent doesn't know its
+  /// files and pieces.
+  FetchingMetadata,
+  /// The torrent's existing data is being verified. Until this is done the
+  /// torrent doesn't connect to peers.
+  Checking,
+  /// The torrent is missing pieces and is downloading them.
+  #[default]
+  Downloading,
+  /// The torrent has all pieces and is only uploading.
+  Seeding,
+  /// The torrent was paused by the user and has no peers.
+  Paused,
+  /// The torrent is waiting for an active slot in the engine's queue, and
+  /// has no peers until it gets one.
+  Queued,
+}
+
+/// One of the torrent's files with how much of it is downloaded.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FileStats {
+  /// The file's path, length, and place in the torrent.
+  pub info: FileInfo,
+  /// The number of the file's bytes in the pieces we have.
+  pub completed_bytes: u64,
+  /// Whether and how urgently the file is downloaded.
+  pub priority: FilePriority,
+}
+
+impl FileStats {
+  /// Returns the ratio of the file's bytes downloaded, between 0 and 1. It's
+  /// 1 for an empty file.
+  pub fn progress(&self) -> f64 {
+    if self.info.len == 0 {
+      1.0
+    } else {
+      self.completed_bytes as f64 / self.info.len as f64
+    }
+  }
 }
 
 /// Statistics of a torrent's pieces.
-#[derive(Debug, Clone, Default, PartialEq, PartialOrd, Ord, Eq, Hash)]
+#[derive(
+  Debug, Clone, Default, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize,
+)]
 pub struct PieceStats {
   /// The total number of pieces in torrent.
   pub total: usize,
@@ -63,8 +228,31 @@ impl PieceStats {
   }
 }
 
+/// The measurements of a torrent's sample download, posted once the sample
+/// requested with [`TorrentHandle::sample`] is complete.
+///
+/// [`TorrentHandle::sample`]: super::handle::TorrentHandle::sample
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SampleReport {
+  /// The number of pieces in the sample, starting with the first piece.
+  pub piece_count: usize,
+  /// The payload bytes downloaded while sampling.
+  pub downloaded: u64,
+  /// How long the sample took to download.
+  pub duration: Duration,
+  /// The average payload download rate while sampling, in bytes per second.
+  pub download_rate: u64,
+  /// The number of peers connected when the sample was complete.
+  pub peer_count: usize,
+  /// The ratio of the torrent's pieces that either we or at least one
+  /// connected peer have, between 0 and 1. Below 1 the torrent can't be
+  /// completed with the current peers.
+  pub availability: f64,
+}
+
 /// Limited or full information of a torrent's peer session.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Peers {
   /// The number of connected peers.
   Count(usize),
@@ -94,22 +282,72 @@ impl Default for Peers {
 }
 
 /// Aggregate statistics of a peer session.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct PeerSessionStats {
   /// The IP-port pair of the peer.
   pub addr: SocketAddr,
   /// Peer's 20 byte BitTorrent id.
   /// Updated when the peer sends us its peer id in the handshake.
   pub id: Option<PeerId>,
-  /// The current state of the session.
+  /// The name and version of the peer's client, if it sent them in the
+  /// extension handshake, e.g. `qBittorrent/4.5.2`.
+  pub client: Option<String>,
+  /// Whether we connected to the peer, rather than it to us.
+  pub is_outbound: bool,
+  /// The current state of the session, with its choke and interest flags.
   pub state: SessionState,
-  /// The number of pieces the peer has.
+  /// The number of pieces the peer has, from which its progress follows.
   pub piece_count: usize,
-  /// Various thruput statistics of this peer.
+  /// Various thruput statistics of this peer, including the current rates.
   pub thruput: ThruputStats,
+  /// The number of our block requests the peer has yet to serve.
+  pub outgoing_requests: usize,
+  /// The number of the peer's block requests we have yet to serve.
+  pub incoming_requests: usize,
+  /// The number of requests to the peer that were dropped as the same block
+  /// was already requested from it.
+  pub duplicate_requests: u64,
+  /// The number of blocks the peer sent that were not requested and were
+  /// already downloaded.
+  pub duplicate_blocks: u64,
+  /// The number of pieces failing their hash that were blamed on the peer's
+  /// IP.
+  pub hash_failures: u32,
+}
+
+/// What requesting blocks from more than one peer in endgame cost, as
+/// bounded by [`EndgameConf`].
+///
+/// [`EndgameConf`]: crate::conf::EndgameConf
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize)]
+pub struct EndgameStats {
+  /// The number of requests of blocks that were already requested from
+  /// another peer.
+  pub duplicate_requests: u64,
+  /// The bytes of the requests that were cancelled as the block was
+  /// downloaded from another peer first.
+  pub cancelled_bytes: u64,
+  /// The bytes of the blocks that arrived after they were downloaded from
+  /// another peer, which were wasted.
+  pub wasted_bytes: u64,
 }
 
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+impl EndgameStats {
+  /// Adds what grew between the previous and the current stats of a session,
+  /// whose stats only ever grow.
+  pub(crate) fn add_growth(&mut self, prev: &Self, curr: &Self) {
+    self.duplicate_requests += curr
+      .duplicate_requests
+      .saturating_sub(prev.duplicate_requests);
+    self.cancelled_bytes +=
+      curr.cancelled_bytes.saturating_sub(prev.cancelled_bytes);
+    self.wasted_bytes += curr.wasted_bytes.saturating_sub(prev.wasted_bytes);
+  }
+}
+
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize,
+)]
 pub struct ThruputStats {
   /// Statistics about the protocol transfer rates in both directions.
   pub protocol: Channel,
@@ -132,7 +370,9 @@ impl From<&ThruputCounters> for ThruputStats {
 
 /// Aggregate statistics about a communication channel,
 /// e.g. protocol chatter or exchanged payload.
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize,
+)]
 pub struct Channel {
   pub down: Thruput,
   pub up: Thruput,
@@ -148,7 +388,9 @@ impl From<&ChannelCounter> for Channel {
 }
 
 /// Statistics of a torrent's current thruput.
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize,
+)]
 pub struct Thruput {
   pub total: u64,
   pub rate: u64,
@@ -164,3 +406,71 @@ impl From<&Counter> for Thruput {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_diff() {
+    let prev = TorrentStats {
+      pieces: PieceStats {
+        total: 10,
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    assert!(prev.diff(&prev).is_empty());
+
+    let mut curr = prev.clone();
+    curr.state = TorrentState::Seeding;
+    curr.pieces.complete = 10;
+    let diff = curr.diff(&prev);
+    assert_eq!(
+      diff,
+      TorrentStatsDiff {
+        state: Some(TorrentState::Seeding),
+        pieces: Some(curr.pieces.clone()),
+        ..Default::default()
+      }
+    );
+  }
+
+  #[test]
+  fn test_serialized_diff_omits_unchanged_fields() {
+    let prev = TorrentStats::default();
+    let curr = TorrentStats {
+      state: TorrentState::Paused,
+      peers: Peers::Count(2),
+      ..Default::default()
+    };
+
+    let json = serde_json::to_value(curr.diff(&prev)).unwrap();
+    assert_eq!(
+      json,
+      serde_json::json!({ "state": "paused", "peers": { "count": 2 } })
+    );
+  }
+
+  #[test]
+  fn test_serialize_stats() {
+    let json = serde_json::to_value(TorrentStats::default()).unwrap();
+    let fields: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+    assert_eq!(
+      fields,
+      vec![
+        "completed_wanted_bytes",
+        "endgame",
+        "labels",
+        "peers",
+        "pieces",
+        "progress_wanted",
+        "run_duration",
+        "state",
+        "thruput",
+        "wanted_bytes"
+      ]
+    );
+    assert_eq!(json["pieces"]["latest_completed"], serde_json::Value::Null);
+  }
+}
@@ -1,4 +1,4 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use crate::{PeerId, Sha1Hash};
 
@@ -18,6 +18,11 @@ pub struct Announce {
   /// (client communication through a proxy or when the tracker is on the
   /// same NAT'd subset as peer)
   pub ip: Option<IpAddr>,
+  /// Our IPv4 and IPv6 addresses, by which peers of either family may reach
+  /// us, besides the address the tracker sees the announce coming from.
+  /// [`More details`](http://bittorrent.org/beps/bep_0007.html)
+  pub ipv4: Option<Ipv4Addr>,
+  pub ipv6: Option<Ipv6Addr>,
 
   /// Number up bytes download so far.
   pub downloaded: u64,
@@ -34,12 +39,14 @@ pub struct Announce {
 
   /// If previously received from the tracker, we must send it with each
   /// announce.
-  #[allow(dead_code)]
   pub tracker_id: Option<String>,
 
+  /// A random number that identifies us to the tracker across changes of
+  /// our IP address, as it's not shared with other peers like the peer id.
+  pub key: Option<u32>,
+
   /// Only need be set during the special events defined in [`Event`].
   /// Otherwise when just requesting peers, no event needs to be set.
-  #[allow(dead_code)]
   pub event: Option<Event>,
 }
 
@@ -57,4 +64,20 @@ pub enum Event {
   Completed,
   /// Must be sent to tracker if the client is shutting down gracefully.
   Stopped,
+  /// Sent by a partial seed, which has all the pieces it wants but not all
+  /// pieces of the torrent, so that the tracker counts it apart from the
+  /// peers still downloading (BEP 21).
+  Paused,
+}
+
+impl Event {
+  /// Returns the event's value in the announce query.
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Started => "started",
+      Self::Completed => "completed",
+      Self::Stopped => "stopped",
+      Self::Paused => "paused",
+    }
+  }
 }
@@ -0,0 +1,85 @@
+//! The interface through which torrents announce to their trackers, whatever
+//! protocol the trackers speak.
+
+use std::{fmt, time::Duration};
+
+use futures::future::BoxFuture;
+use reqwest::Url;
+
+use super::{
+  prelude::Result,
+  response::{Response, Scrape},
+  tracker::{HttpClient, HttpTracker, IpFamily},
+  udp::{UdpConnections, UdpTracker},
+};
+use crate::{tracker::announce::Announce, Sha1Hash};
+
+/// A tracker of a torrent, to which we announce our transfer progress and
+/// from which we request peers.
+pub trait TrackerClient: fmt::Display + Send + Sync {
+  /// Returns the tracker's announce URL.
+  fn url(&self) -> &Url;
+
+  /// Announces to the tracker with the given parameters, over the given IP
+  /// family if set, so that a host with addresses of both families can make
+  /// itself known to the tracker by both of them (BEP 7), or otherwise over
+  /// whichever family the OS picks.
+  fn announce(
+    &self,
+    params: Announce,
+    family: Option<IpFamily>,
+  ) -> BoxFuture<'_, Result<Response>>;
+
+  /// Asks the tracker for the statistics of the torrent's swarm.
+  fn scrape(&self, info_hash: Sha1Hash) -> BoxFuture<'_, Result<Scrape>>;
+
+  /// Checks whether the tracker is reachable, giving up after the given
+  /// duration.
+  fn probe(&self, timeout: Duration) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Creates the clients of trackers by their URL, so that trackers of
+/// protocols other than the built-in ones may be plugged in.
+pub trait TrackerBackend: fmt::Debug + Send + Sync {
+  /// Returns whether trackers of the URL's protocol are supported, as
+  /// trackers that aren't are skipped.
+  fn supports(&self, url: &Url) -> bool;
+
+  /// Creates the client of the tracker at the URL, which is supported. The
+  /// engine's HTTP client is passed for clients that make HTTP requests, as
+  /// it's configured with the engine's headers, proxy and TLS settings, and
+  /// the engine's UDP connection ids for clients of UDP trackers, so that
+  /// the torrents with the same tracker share them.
+  fn new_client(
+    &self,
+    url: Url,
+    http: &HttpClient,
+    udp: &UdpConnections,
+  ) -> Box<dyn TrackerClient>;
+}
+
+/// The default backend, which supports HTTP(S) and UDP (BEP 15) trackers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultTrackerBackend;
+
+impl TrackerBackend for DefaultTrackerBackend {
+  fn supports(&self, url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https" | "udp")
+  }
+
+  fn new_client(
+    &self,
+    url: Url,
+    http: &HttpClient,
+    udp: &UdpConnections,
+  ) -> Box<dyn TrackerClient> {
+    match url.scheme() {
+      "udp" => Box::new(UdpTracker::with_connections(
+        url,
+        http.source_addr(),
+        udp.clone(),
+      )),
+      _ => Box::new(HttpTracker::with_client(url, http.clone())),
+    }
+  }
+}
@@ -1,4 +1,6 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::sync::Mutex;
+use std::time::Instant;
 use std::{net::SocketAddr, time::Duration};
 
 use bytes::Buf;
@@ -10,17 +12,23 @@ use crate::error::metainfo::BencodeDeError;
 use crate::error::tracker::TrackerError;
 
 pub mod announce;
+pub mod client;
 pub mod response;
 mod test;
 #[allow(clippy::module_inception)]
 pub mod tracker;
+pub mod udp;
 
 pub mod prelude {
   pub use super::announce::*;
+  pub use super::client::*;
+  pub use super::deserialize_external_ip;
   pub use super::deserialize_peers;
+  pub use super::deserialize_peers6;
   pub use super::deserialize_seconds;
   pub use super::response::*;
   pub use super::tracker::*;
+  pub use super::udp::*;
   pub use crate::error::tracker::Result;
 }
 
@@ -44,80 +52,207 @@ where
 /// Cos of most of trackers send the compact response by default,
 /// and here we do not use the peer id in the stage of
 /// receiving a peer list from the tracker, so discarding is available.
+///
+/// The compact string holds IPv4 peers, while the list of dicts may hold
+/// peers of either family.
 pub fn deserialize_peers<'de, D>(
   deserializer: D,
 ) -> Result<Vec<SocketAddr>, D::Error>
 where
   D: de::Deserializer<'de>,
 {
-  struct Visitor;
-
-  impl<'de> de::Visitor<'de> for Visitor {
-    type Value = Vec<SocketAddr>;
-    fn expecting(
-      &self,
-      formatter: &mut std::fmt::Formatter,
-    ) -> std::fmt::Result {
-      formatter.write_str("a string or list of dicts representing peer")
+  deserializer.deserialize_any(PeersVisitor { is_ipv6: false })
+}
+
+/// Deserializes the IPv6 peers, which trackers send under the `peers6` key,
+/// in the same two ways as [`deserialize_peers`].
+///
+/// Each entry of the compact string is 18 bytes long, where the first 16
+/// bytes are the IPv6 address, and then the last 2 bytes are the port.
+pub fn deserialize_peers6<'de, D>(
+  deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error>
+where
+  D: de::Deserializer<'de>,
+{
+  deserializer.deserialize_any(PeersVisitor { is_ipv6: true })
+}
+
+/// Deserializes either representation of a peer list, where a compact
+/// string holds peers of the given IP family.
+struct PeersVisitor {
+  is_ipv6: bool,
+}
+
+impl<'de> de::Visitor<'de> for PeersVisitor {
+  type Value = Vec<SocketAddr>;
+  fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    formatter.write_str("a string or list of dicts representing peer")
+  }
+
+  /// Deserializes a compact string of peers.
+  ///
+  /// Each entry is the IPv4 or IPv6 address followed by the 2 byte port,
+  /// both in network byte order.
+  fn visit_bytes<E>(self, mut b: &[u8]) -> Result<Self::Value, E>
+  where
+    E: de::Error,
+  {
+    let entry_len = if self.is_ipv6 { 18 } else { 6 };
+    if !b.len().is_multiple_of(entry_len) {
+      let key = if self.is_ipv6 { "peers6" } else { "peers" };
+      return Err(E::custom(TrackerError::BencodeDe(BencodeDeError::Message(
+        format!("{} compact string must be a multiple of {}", key, entry_len),
+      ))));
     }
 
-    /// Deserializes a compact string of peers.
-    ///
-    /// Each entry is 6 bytes long, where the first 4 bytes are the IPv4 address,
-    /// and then the last 2 bytes are the Port.
-    ///
-    /// Both are in network byte order.
-    fn visit_bytes<E>(self, mut b: &[u8]) -> Result<Self::Value, E>
-    where
-      E: de::Error,
-    {
-      const ENTRY_LEN: usize = 6;
-
-      let buf_len = b.len();
-
-      if buf_len % ENTRY_LEN != 0 {
-        return Err(TrackerError::BencodeDe(BencodeDeError::Message(
-          "peers compact string must be a multiple of 6".into(),
-        )))
-        .map_err(E::custom);
-      }
-
-      let mut peers = Vec::with_capacity(buf_len / ENTRY_LEN);
-
-      for _ in (0..buf_len).step_by(ENTRY_LEN) {
-        let addr = Ipv4Addr::from(b.get_u32());
-        let port = b.get_u16();
-        let peer = SocketAddr::new(IpAddr::V4(addr), port);
-        peers.push(peer);
-      }
-      Ok(peers)
+    let mut peers = Vec::with_capacity(b.len() / entry_len);
+    while b.has_remaining() {
+      let addr = if self.is_ipv6 {
+        IpAddr::V6(Ipv6Addr::from(b.get_u128()))
+      } else {
+        IpAddr::V4(Ipv4Addr::from(b.get_u32()))
+      };
+      let port = b.get_u16();
+      peers.push(SocketAddr::new(addr, port));
     }
+    Ok(peers)
+  }
 
-    /// Deserializes a list of dicts containing the peer information.
-    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
-    where
-      A: de::SeqAccess<'de>,
+  /// Deserializes a list of dicts containing the peer information.
+  ///
+  /// Peers whose IP can't be parsed are skipped. IPv6 addresses may be
+  /// enclosed in brackets.
+  fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+  where
+    A: de::SeqAccess<'de>,
+  {
+    #[derive(Debug, Deserialize)]
+    struct RawPeer {
+      ip: String,
+      port: u16,
+    }
+    let mut peers = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+    while let Some(RawPeer { ip, port }) = seq.next_element()? {
+      let ip = ip.trim_start_matches('[').trim_end_matches(']');
+      let ip = if let Ok(ip) = ip.parse() {
+        ip
+      } else {
+        continue;
+      };
+      peers.push(SocketAddr::new(ip, port));
+    }
+
+    Ok(peers)
+  }
+}
+
+/// Deserializes the external IP that the tracker sees us by (BEP 24), which
+/// is the 4 or 16 bytes of an IPv4 or IPv6 address in network byte order.
+///
+/// An address of any other length is ignored, as it's only informational.
+pub fn deserialize_external_ip<'de, D>(
+  deserializer: D,
+) -> Result<Option<IpAddr>, D::Error>
+where
+  D: de::Deserializer<'de>,
+{
+  let b: Option<serde_bytes::ByteBuf> =
+    de::Deserialize::deserialize(deserializer)?;
+  Ok(b.and_then(|b| match b.len() {
+    4 => Some(IpAddr::V4(Ipv4Addr::from((&b[..]).get_u32()))),
+    16 => Some(IpAddr::V6(Ipv6Addr::from((&b[..]).get_u128()))),
+    _ => None,
+  }))
+}
+
+/// Returns the host's globally routable IPv4 and IPv6 addresses, i.e. the
+/// addresses of the interfaces the OS routes outbound traffic through, if
+/// they are reachable from the internet.
+///
+/// The result is cached for a minute, as finding the addresses takes a few
+/// blocking syscalls and they rarely change.
+pub fn global_addrs() -> GlobalAddrs {
+  static CACHE: Mutex<Option<(Instant, GlobalAddrs)>> = Mutex::new(None);
+
+  let now = Instant::now();
+  let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+  match *cache {
+    Some((found_at, addrs))
+      if now.saturating_duration_since(found_at) < GLOBAL_ADDRS_TTL =>
     {
-      #[derive(Debug, Deserialize)]
-      struct RawPeer {
-        ip: String,
-        port: u16,
-      }
-      let mut peers = Vec::with_capacity(seq.size_hint().unwrap_or(0));
-      while let Some(RawPeer { ip, port }) = seq.next_element()? {
-        let ip = if let Ok(ip) = ip.parse() {
-          ip
-        } else {
-          continue;
-        };
-        peers.push(SocketAddr::new(ip, port));
-      }
-
-      Ok(peers)
+      addrs
+    }
+    _ => {
+      let addrs = find_global_addrs();
+      *cache = Some((now, addrs));
+      addrs
     }
   }
+}
+
+/// How long the host's global addresses are cached for.
+const GLOBAL_ADDRS_TTL: Duration = Duration::from_secs(60);
+
+type GlobalAddrs = (Option<Ipv4Addr>, Option<Ipv6Addr>);
+
+/// Looks up the host's global addresses, see [`global_addrs`].
+///
+/// No traffic is sent: connecting a UDP socket only selects the route and
+/// with it the local address.
+fn find_global_addrs() -> GlobalAddrs {
+  fn route_addr(bind: IpAddr, remote: IpAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(SocketAddr::new(bind, 0)).ok()?;
+    socket.connect(SocketAddr::new(remote, 80)).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+  }
+
+  // the documentation ranges are never local, so they take the default
+  // route
+  let ipv4 = match route_addr(
+    Ipv4Addr::UNSPECIFIED.into(),
+    Ipv4Addr::new(192, 0, 2, 1).into(),
+  ) {
+    Some(IpAddr::V4(addr)) if is_global_ipv4(&addr) => Some(addr),
+    _ => None,
+  };
+  let ipv6 = match route_addr(
+    Ipv6Addr::UNSPECIFIED.into(),
+    Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+  ) {
+    Some(IpAddr::V6(addr)) if is_global_ipv6(&addr) => Some(addr),
+    _ => None,
+  };
+  (ipv4, ipv6)
+}
+
+/// Returns whether the IPv4 address is reachable from the internet.
+fn is_global_ipv4(addr: &Ipv4Addr) -> bool {
+  let [a, b, ..] = addr.octets();
+  // the shared address space of carrier-grade NAT, 100.64.0.0/10
+  let is_shared = a == 100 && (b & 0b1100_0000) == 64;
+  !(addr.is_private()
+    || addr.is_loopback()
+    || addr.is_link_local()
+    || addr.is_unspecified()
+    || addr.is_broadcast()
+    || addr.is_documentation()
+    || is_shared)
+}
 
-  deserializer.deserialize_any(Visitor)
+/// Returns whether the IPv6 address is reachable from the internet.
+pub(crate) fn is_global_ipv6(addr: &Ipv6Addr) -> bool {
+  let segments = addr.segments();
+  // link-local fe80::/10, unique local fc00::/7, and documentation
+  // 2001:db8::/32 addresses
+  let is_link_local = (segments[0] & 0xffc0) == 0xfe80;
+  let is_unique_local = (segments[0] & 0xfe00) == 0xfc00;
+  let is_documentation = segments[0] == 0x2001 && segments[1] == 0xdb8;
+  !(addr.is_loopback()
+    || addr.is_unspecified()
+    || is_link_local
+    || is_unique_local
+    || is_documentation)
 }
 
 /// Contains the characters that need to be URL encoded according to:
@@ -1,10 +1,16 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+  net::{IpAddr, SocketAddr},
+  time::Duration,
+};
 
 use serde_derive::Deserialize;
 
-use super::{deserialize_peers, deserialize_seconds};
+use super::{
+  deserialize_external_ip, deserialize_peers, deserialize_peers6,
+  deserialize_seconds,
+};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 #[cfg_attr(test, derive(PartialEq, serde_derive::Serialize))]
 pub struct Response {
   /// The tracker Id. If set, we must send it with each subsequent announce.
@@ -20,6 +26,8 @@ pub struct Response {
   pub warning_message: Option<String>,
 
   /// The number of seconds the client should wait before recontacting tracker.
+  /// A failure response may leave it out.
+  #[serde(default)]
   #[serde(deserialize_with = "deserialize_seconds")]
   pub interval: Option<Duration>,
 
@@ -37,4 +45,34 @@ pub struct Response {
   #[serde(default)]
   #[serde(deserialize_with = "deserialize_peers")]
   pub peers: Vec<SocketAddr>,
+
+  /// The IPv6 peers, which trackers return separately.
+  #[serde(default)]
+  #[serde(deserialize_with = "deserialize_peers6")]
+  pub peers6: Vec<SocketAddr>,
+
+  /// The IP address the tracker sees us by, if it tells (BEP 24).
+  #[serde(default)]
+  #[serde(rename = "external ip")]
+  #[serde(deserialize_with = "deserialize_external_ip")]
+  pub external_ip: Option<IpAddr>,
+
+  /// The address the tracker responded from, which tells over which IP
+  /// family it was reached. This is not part of the response body.
+  #[serde(skip)]
+  pub remote_addr: Option<SocketAddr>,
+}
+
+/// The statistics of a torrent's swarm that a tracker returns when scraped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct Scrape {
+  /// The number of peers that have the whole torrent.
+  #[serde(rename = "complete")]
+  pub seeder_count: usize,
+  /// The number of peers that are still downloading.
+  #[serde(rename = "incomplete")]
+  pub leecher_count: usize,
+  /// The number of times the torrent was downloaded in full.
+  #[serde(rename = "downloaded")]
+  pub completed_count: usize,
 }
@@ -2,14 +2,16 @@
 #[cfg(test)]
 mod tests {
   use std::{
-    net::{Ipv4Addr, SocketAddr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     time::Duration,
   };
 
   use mockito::Matcher;
   use serde_derive::{Deserialize, Serialize};
 
-  use crate::tracker::prelude::*;
+  use crate::{
+    conf::TrackerHttpConf, error::tracker::TrackerError, tracker::prelude::*,
+  };
 
   #[derive(Deserialize)]
   struct PeersResponse {
@@ -78,11 +80,102 @@ mod tests {
     assert_eq!(decoded.peers, expected);
   }
 
+  #[test]
+  fn should_parse_compact_ipv6_peer_list() {
+    #[derive(Deserialize)]
+    struct Peers6Response {
+      #[serde(deserialize_with = "deserialize_peers6")]
+      peers6: Vec<SocketAddr>,
+    }
+
+    let ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x42);
+    let port = 8989u16;
+    let mut encoded = b"d6:peers618:".to_vec();
+    encoded.extend_from_slice(&ip.octets());
+    encoded.extend_from_slice(&port.to_be_bytes());
+    encoded.push(b'e');
+
+    let decoded: Peers6Response = serde_bencoded::from_bytes(&encoded)
+      .expect("cannot decode bencode string of ipv6 peers");
+    assert_eq!(decoded.peers6, vec![SocketAddr::new(ip.into(), port)]);
+
+    // an entry cut short is rejected
+    let encoded = b"d6:peers63:abce";
+    assert!(serde_bencoded::from_bytes::<Peers6Response>(encoded).is_err());
+  }
+
+  #[test]
+  fn should_parse_full_ipv6_peer_list() {
+    #[derive(Deserialize)]
+    struct Peers6Response {
+      #[serde(deserialize_with = "deserialize_peers")]
+      peers: Vec<SocketAddr>,
+      #[serde(deserialize_with = "deserialize_peers6")]
+      peers6: Vec<SocketAddr>,
+    }
+
+    // the full representation may list peers of both families under either
+    // key, with or without brackets around the IPv6 addresses
+    let encoded = b"d\
+      5:peersld2:ip7:1.2.3.44:porti6881eed2:ip11:2001:db8::14:porti6882eee\
+      6:peers6ld2:ip13:[2001:db8::2]4:porti6883eed2:ip3:bad4:porti1eee\
+      e";
+    let decoded: Peers6Response = serde_bencoded::from_bytes(encoded)
+      .expect("cannot decode bencode list of ipv6 peers");
+    assert_eq!(
+      decoded.peers,
+      vec![
+        "1.2.3.4:6881".parse().unwrap(),
+        "[2001:db8::1]:6882".parse().unwrap(),
+      ]
+    );
+    assert_eq!(decoded.peers6, vec!["[2001:db8::2]:6883".parse().unwrap()]);
+  }
+
+  #[test]
+  fn should_parse_external_ip() {
+    let decoded: Response = serde_bencoded::from_bytes(
+      b"d11:external ip4:\xcb\x00\x71\x078:intervali15ee",
+    )
+    .expect("cannot decode response with external ip");
+    assert_eq!(
+      decoded.external_ip,
+      Some(Ipv4Addr::new(203, 0, 113, 7).into())
+    );
+
+    let mut encoded = b"d11:external ip16:".to_vec();
+    encoded.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    encoded.extend_from_slice(b"8:intervali15ee");
+    let decoded: Response = serde_bencoded::from_bytes(&encoded).unwrap();
+    assert_eq!(decoded.external_ip, Some(Ipv6Addr::LOCALHOST.into()));
+
+    // an address of the wrong length is ignored
+    let decoded: Response =
+      serde_bencoded::from_bytes(b"d11:external ip3:abc8:intervali15ee")
+        .unwrap();
+    assert_eq!(decoded.external_ip, None);
+  }
+
+  #[test]
+  fn should_only_announce_global_addresses() {
+    use crate::tracker::{is_global_ipv4, is_global_ipv6};
+
+    assert!(is_global_ipv4(&Ipv4Addr::new(1, 2, 3, 4)));
+    assert!(!is_global_ipv4(&Ipv4Addr::new(192, 168, 1, 2)));
+    assert!(!is_global_ipv4(&Ipv4Addr::new(100, 64, 0, 1)));
+    assert!(!is_global_ipv4(&Ipv4Addr::LOCALHOST));
+
+    assert!(is_global_ipv6(&"2a00:1450::1".parse().unwrap()));
+    assert!(!is_global_ipv6(&"fe80::1".parse().unwrap()));
+    assert!(!is_global_ipv6(&"fd00::1".parse().unwrap()));
+    assert!(!is_global_ipv6(&Ipv6Addr::LOCALHOST));
+  }
+
   #[tokio::test]
   async fn should_return_peers_on_announce() {
     let mut server = mockito::Server::new_async().await;
     let addr = server.url();
-    let tracker = Tracker::new(addr.parse().unwrap());
+    let tracker = HttpTracker::new(addr.parse().unwrap());
 
     let info_hash_str = "abcdefghij1234567890";
     let mut info_hash = [0; 20];
@@ -102,8 +195,11 @@ mod tests {
       left: 1234,
       peer_count: Some(2),
       ip: None,
+      ipv4: Some(Ipv4Addr::new(203, 0, 113, 7)),
+      ipv6: None,
       event: None,
       tracker_id: None,
+      key: None,
     };
 
     // tracker provide useable peer.
@@ -120,6 +216,9 @@ mod tests {
       seeder_count: Some(5),
       leecher_count: Some(3),
       peers: vec![SocketAddr::new(peer_ip.into(), peer_port)],
+      peers6: Vec::new(),
+      external_ip: None,
+      remote_addr: Some(server.host_with_port().parse().unwrap()),
     };
 
     // expected_response -> bencode
@@ -155,6 +254,7 @@ mod tests {
         ),
         Matcher::UrlEncoded("uploaded".into(), announce.uploaded.to_string()),
         Matcher::UrlEncoded("left".into(), announce.left.to_string()),
+        Matcher::UrlEncoded("ipv4".into(), "203.0.113.7".into()),
         Matcher::UrlEncoded(
           "numwant".into(),
           announce.peer_count.unwrap().to_string(),
@@ -169,6 +269,399 @@ mod tests {
     assert_eq!(resp, expected_resp);
   }
 
+  #[tokio::test]
+  async fn should_send_event_tracker_id_and_key_on_announce() {
+    let mut server = mockito::Server::new_async().await;
+    let tracker = HttpTracker::new(server.url().parse().unwrap());
+    let announce = Announce {
+      info_hash: [1; 20],
+      peer_id: [2; 20],
+      port: 16,
+      downloaded: 0,
+      uploaded: 0,
+      left: 1234,
+      peer_count: None,
+      ip: None,
+      ipv4: None,
+      ipv6: None,
+      event: Some(Event::Started),
+      tracker_id: Some("abc".into()),
+      key: Some(0x1a2b3c),
+    };
+
+    let _m = server
+      .mock("GET", "/")
+      .match_query(Matcher::AllOf(vec![
+        Matcher::UrlEncoded("event".into(), "started".into()),
+        Matcher::UrlEncoded("trackerid".into(), "abc".into()),
+        Matcher::UrlEncoded("key".into(), "001A2B3C".into()),
+      ]))
+      .with_status(200)
+      .with_body(b"d8:intervali15e5:peers0:e")
+      .create_async()
+      .await;
+
+    let resp = tracker.announce(announce).await.unwrap();
+    assert_eq!(resp.interval, Some(Duration::from_secs(15)));
+  }
+
+  #[tokio::test]
+  async fn should_announce_over_ip_family() {
+    let mut server = mockito::Server::new_async().await;
+    let url = format!("http://{}/", server.host_with_port());
+    let tracker = HttpTracker::new(url.parse().unwrap());
+    let announce = || Announce {
+      info_hash: [1; 20],
+      peer_id: [2; 20],
+      port: 16,
+      downloaded: 0,
+      uploaded: 0,
+      left: 1234,
+      peer_count: None,
+      ip: None,
+      ipv4: None,
+      ipv6: None,
+      event: None,
+      tracker_id: None,
+      key: None,
+    };
+
+    let _m = server
+      .mock("GET", "/")
+      .match_query(Matcher::Any)
+      .with_status(200)
+      .with_body(b"d8:intervali15e5:peers0:e")
+      .create_async()
+      .await;
+
+    let resp = tracker
+      .announce_over(announce(), IpFamily::V4)
+      .await
+      .unwrap();
+    assert!(resp.remote_addr.unwrap().is_ipv4());
+
+    // the tracker listens on an IPv4 address only
+    assert!(matches!(
+      tracker.announce_over(announce(), IpFamily::V6).await,
+      Err(TrackerError::NoAddress(IpFamily::V6))
+    ));
+  }
+
+  #[tokio::test]
+  async fn should_fall_back_to_non_compact_announce() {
+    let mut server = mockito::Server::new_async().await;
+    let tracker = HttpTracker::new(server.url().parse().unwrap());
+    let announce = || Announce {
+      info_hash: [1; 20],
+      peer_id: [2; 20],
+      port: 16,
+      downloaded: 0,
+      uploaded: 0,
+      left: 1234,
+      peer_count: None,
+      ip: None,
+      ipv4: None,
+      ipv6: None,
+      event: None,
+      tracker_id: None,
+      key: None,
+    };
+
+    let compact = server
+      .mock("GET", "/")
+      .match_query(Matcher::UrlEncoded("compact".into(), "1".into()))
+      .with_status(200)
+      .with_body(b"d14:failure reason17:compact forbiddene")
+      .expect(1)
+      .create_async()
+      .await;
+    let dict = server
+      .mock("GET", "/")
+      .match_query(Matcher::UrlEncoded("compact".into(), "0".into()))
+      .with_status(200)
+      .with_body(b"d8:intervali15e5:peersld2:ip7:1.2.3.44:porti6881eeee")
+      .expect(2)
+      .create_async()
+      .await;
+
+    // the failed compact announce is retried, and later announces go
+    // straight to the dictionary model
+    for _ in 0..2 {
+      let resp = tracker.announce(announce()).await.unwrap();
+      assert_eq!(resp.failure_reason, None);
+      assert_eq!(resp.peers, vec!["1.2.3.4:6881".parse().unwrap()]);
+    }
+    compact.assert_async().await;
+    dict.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn should_send_configured_headers() {
+    let mut server = mockito::Server::new_async().await;
+    let url = format!("http://{}/", server.host_with_port());
+    let conf = TrackerHttpConf {
+      user_agent: Some("cbt/1.0".into()),
+      headers: vec![("Cookie".into(), "passkey=abc".into())],
+      use_system_proxy: false,
+      ..Default::default()
+    };
+    let tracker = HttpTracker::with_client(
+      url.parse().unwrap(),
+      HttpClient::new(&conf, None).unwrap(),
+    );
+    let announce = || Announce {
+      info_hash: [1; 20],
+      peer_id: [2; 20],
+      port: 16,
+      downloaded: 0,
+      uploaded: 0,
+      left: 1234,
+      peer_count: None,
+      ip: None,
+      ipv4: None,
+      ipv6: None,
+      event: None,
+      tracker_id: None,
+      key: None,
+    };
+
+    let m = server
+      .mock("GET", "/")
+      .match_query(Matcher::Any)
+      .match_header("user-agent", "cbt/1.0")
+      .match_header("cookie", "passkey=abc")
+      .with_status(200)
+      .with_body(b"d8:intervali15e5:peers0:e")
+      .expect(2)
+      .create_async()
+      .await;
+
+    // the headers are also sent when announcing over a single family
+    tracker.announce(announce()).await.unwrap();
+    tracker
+      .announce_over(announce(), IpFamily::V4)
+      .await
+      .unwrap();
+    m.assert_async().await;
+  }
+
+  #[test]
+  fn should_support_http_and_udp_trackers() {
+    for (url, is_supported) in [
+      ("http://tracker.example.com/announce", true),
+      ("https://tracker.example.com/announce", true),
+      ("udp://tracker.example.com:1337/announce", true),
+      ("wss://tracker.example.com/announce", false),
+    ] {
+      let url = url.parse().unwrap();
+      assert_eq!(DefaultTrackerBackend.supports(&url), is_supported);
+      if is_supported {
+        let client = DefaultTrackerBackend.new_client(
+          url.clone(),
+          &Default::default(),
+          &Default::default(),
+        );
+        assert_eq!(client.url(), &url);
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn should_scrape_http_tracker() {
+    let mut server = mockito::Server::new_async().await;
+    let info_hash = [0xab; 20];
+    let m = server
+      .mock("GET", "/x/scrape.php")
+      .match_query(Matcher::Regex(format!("^info_hash={}$", "%AB".repeat(20))))
+      .with_body({
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&info_hash);
+        body.extend_from_slice(
+          b"d8:completei5e10:downloadedi50e10:incompletei10eeee",
+        );
+        body
+      })
+      .create_async()
+      .await;
+
+    let url = format!("{}/x/announce.php", server.url());
+    let tracker = HttpTracker::new(url.parse().unwrap());
+    assert_eq!(
+      tracker.scrape(info_hash).await.unwrap(),
+      Scrape {
+        seeder_count: 5,
+        leecher_count: 10,
+        completed_count: 50,
+      }
+    );
+    m.assert_async().await;
+
+    // a tracker whose URL doesn't end in announce can't be scraped
+    let url = format!("{}/x/a", server.url());
+    let tracker = HttpTracker::new(url.parse().unwrap());
+    assert!(matches!(
+      tracker.scrape(info_hash).await,
+      Err(TrackerError::ScrapeUnsupported)
+    ));
+  }
+
+  #[tokio::test]
+  async fn should_announce_to_udp_tracker() {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+    let tracker = UdpTracker::new(url.parse().unwrap());
+
+    // a fake tracker that tells how many connect requests it got
+    let server = tokio::spawn(async move {
+      let connection_id = 0x0102030405060708u64;
+      let mut connect_count = 0u32;
+      let mut buf = [0; 1024];
+      for _ in 0..3 {
+        let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+        let request = &buf[..len];
+        let transaction_id = &request[12..16];
+        let mut resp = Vec::new();
+        if request[..8] == 0x41727101980u64.to_be_bytes() {
+          connect_count += 1;
+          resp.extend_from_slice(&0u32.to_be_bytes());
+          resp.extend_from_slice(transaction_id);
+          resp.extend_from_slice(&connection_id.to_be_bytes());
+        } else {
+          assert_eq!(request.len(), 98);
+          assert_eq!(request[..8], connection_id.to_be_bytes());
+          // event started
+          assert_eq!(request[80..84], 2u32.to_be_bytes());
+          // the port
+          assert_eq!(request[96..98], 6881u16.to_be_bytes());
+          resp.extend_from_slice(&1u32.to_be_bytes());
+          resp.extend_from_slice(transaction_id);
+          resp.extend_from_slice(&1800u32.to_be_bytes());
+          resp.extend_from_slice(&3u32.to_be_bytes());
+          resp.extend_from_slice(&connect_count.to_be_bytes());
+          resp.extend_from_slice(&[192, 168, 0, 1, 0x1a, 0xe1]);
+        }
+        socket.send_to(&resp, addr).await.unwrap();
+      }
+    });
+
+    let announce = || Announce {
+      info_hash: [0; 20],
+      peer_id: [0; 20],
+      port: 6881,
+      ip: None,
+      ipv4: None,
+      ipv6: None,
+      downloaded: 0,
+      uploaded: 0,
+      left: 1234,
+      peer_count: None,
+      tracker_id: None,
+      key: Some(0xdeadbeef),
+      event: Some(Event::Started),
+    };
+    let resp = tracker.announce(announce(), None).await.unwrap();
+    assert_eq!(resp.interval, Some(Duration::from_secs(1800)));
+    assert_eq!(resp.leecher_count, Some(3));
+    assert_eq!(resp.peers, vec!["192.168.0.1:6881".parse().unwrap()]);
+
+    // the connection id is reused for the next announce, so the seeder count
+    // the fake tracker returns is still its single connect
+    let resp = tracker.announce(announce(), None).await.unwrap();
+    assert_eq!(resp.seeder_count, Some(1));
+    server.await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn should_share_udp_connection_ids_between_trackers() {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}", socket.local_addr().unwrap());
+    let connections = UdpConnections::default();
+    let first = UdpTracker::with_connections(
+      url.parse().unwrap(),
+      None,
+      connections.clone(),
+    );
+    let second =
+      UdpTracker::with_connections(url.parse().unwrap(), None, connections);
+
+    // a fake tracker that returns how many connect requests it got as the
+    // seeder count of a scrape
+    let server = tokio::spawn(async move {
+      let connection_id = 0x0102030405060708u64;
+      let mut connect_count = 0u32;
+      let mut buf = [0; 1024];
+      for _ in 0..3 {
+        let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+        let request = &buf[..len];
+        let transaction_id = &request[12..16];
+        let mut resp = Vec::new();
+        if request[..8] == 0x41727101980u64.to_be_bytes() {
+          connect_count += 1;
+          resp.extend_from_slice(&0u32.to_be_bytes());
+          resp.extend_from_slice(transaction_id);
+          resp.extend_from_slice(&connection_id.to_be_bytes());
+        } else {
+          assert_eq!(request[..8], connection_id.to_be_bytes());
+          resp.extend_from_slice(&2u32.to_be_bytes());
+          resp.extend_from_slice(transaction_id);
+          resp.extend_from_slice(&connect_count.to_be_bytes());
+          resp.extend_from_slice(&0u32.to_be_bytes());
+          resp.extend_from_slice(&0u32.to_be_bytes());
+        }
+        socket.send_to(&resp, addr).await.unwrap();
+      }
+    });
+
+    assert_eq!(first.scrape([0; 20]).await.unwrap().seeder_count, 1);
+    // the second tracker reuses the connection id the first one obtained
+    assert_eq!(second.scrape([1; 20]).await.unwrap().seeder_count, 1);
+    server.await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn should_return_udp_tracker_error() {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}", socket.local_addr().unwrap());
+    let tracker = UdpTracker::new(url.parse().unwrap());
+
+    tokio::spawn(async move {
+      let mut buf = [0; 1024];
+      let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+      let mut resp = 3u32.to_be_bytes().to_vec();
+      resp.extend_from_slice(&buf[12..len.min(16)]);
+      resp.extend_from_slice(b"banned");
+      socket.send_to(&resp, addr).await.unwrap();
+    });
+
+    match tracker.scrape([0; 20]).await {
+      Err(TrackerError::Failure(reason)) => assert_eq!(reason, "banned"),
+      result => panic!("unexpected result: {:?}", result),
+    }
+  }
+
+  #[tokio::test]
+  async fn should_probe_tracker_reachability() {
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+      .mock("HEAD", "/announce")
+      .with_status(400)
+      .create_async()
+      .await;
+
+    // any response means the tracker is up, even a rejection
+    let url = format!("{}/announce", server.url());
+    let tracker = HttpTracker::new(url.parse().unwrap());
+    assert!(tracker.probe(Duration::from_secs(5)).await.is_ok());
+
+    // nothing listens on the port of a dropped listener
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    let url = format!("http://{}/announce", addr);
+    let tracker = HttpTracker::new(url.parse().unwrap());
+    assert!(tracker.probe(Duration::from_secs(5)).await.is_err());
+  }
+
   fn encode_compact_peers_list(peers: &[(Ipv4Addr, u16)]) -> Vec<u8> {
     let encoded_peers: Vec<_> = peers
       .iter()
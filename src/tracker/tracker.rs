@@ -1,38 +1,216 @@
-use std::fmt;
+use std::{
+  collections::HashMap,
+  fmt,
+  net::{IpAddr, SocketAddr},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
 
-use reqwest::{Client, Url};
+use futures::future::{BoxFuture, FutureExt};
+use reqwest::{header::HeaderMap, Certificate, Client, ClientBuilder, Url};
+use serde_derive::Deserialize;
+use url::Host;
 
 use super::prelude::Result;
 use super::URL_ENCODE_RESERVED;
-use super::{announce::Announce, response::Response};
+use super::{
+  announce::Announce,
+  client::TrackerClient,
+  response::{Response, Scrape},
+};
+use crate::{
+  conf::TrackerHttpConf,
+  error::{tracker::TrackerError, EngineResult, Error},
+  Sha1Hash,
+};
 
-/// The HTTP tracker for a tonnert for which we can request peers as well as to announce transfer progress.
-pub struct Tracker {
-  /// The HTTP client (from reqwest::Client)
+/// The HTTP client with which trackers are requested, as configured in
+/// [`TrackerHttpConf`].
+///
+/// It's shared by all trackers of the engine, so that connections and TLS
+/// sessions to the same hosts are reused.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
   client: Client,
+  /// The headers sent with each request.
+  headers: HeaderMap,
+  /// The TLS and proxy configuration, kept to build the clients that reach
+  /// a tracker over a given IP family the same way.
+  root_certificates: Vec<Certificate>,
+  use_system_proxy: bool,
+  danger_accept_invalid_certs: bool,
+  /// The local address trackers are connected to from, if set, which UDP
+  /// trackers use too.
+  source_addr: Option<IpAddr>,
+  /// The clients that reach a tracker host over one IP family, by the host
+  /// and family, along with the address each was built for, so that they
+  /// keep their connections until the host resolves to another address.
+  family_clients: Arc<Mutex<FamilyClients>>,
+}
+
+/// The clients that reach a tracker host over one IP family, see
+/// [`HttpClient::family_client`].
+type FamilyClients = HashMap<(String, IpFamily), (SocketAddr, Client)>;
+
+impl HttpClient {
+  /// Builds the client as configured, connecting from the source address if
+  /// given, or returns an error if the configuration is invalid.
+  pub fn new(
+    conf: &TrackerHttpConf,
+    source_addr: Option<IpAddr>,
+  ) -> EngineResult<Self> {
+    let mut client = HttpClient {
+      client: Client::new(),
+      headers: conf.header_map()?,
+      root_certificates: conf.root_certificates()?,
+      use_system_proxy: conf.use_system_proxy,
+      danger_accept_invalid_certs: conf.danger_accept_invalid_certs,
+      source_addr,
+      family_clients: Default::default(),
+    };
+    client.client = client.builder().build().map_err(|e| {
+      log::warn!("Cannot build tracker HTTP client: {}", e);
+      Error::InvalidConf("invalid tracker TLS configuration")
+    })?;
+    Ok(client)
+  }
+
+  /// Returns a builder for a client configured like this one.
+  fn builder(&self) -> ClientBuilder {
+    let mut builder = Client::builder()
+      .danger_accept_invalid_certs(self.danger_accept_invalid_certs);
+    for certificate in &self.root_certificates {
+      builder = builder.add_root_certificate(certificate.clone());
+    }
+    if !self.use_system_proxy {
+      builder = builder.no_proxy();
+    }
+    builder.local_address(self.source_addr)
+  }
+
+  /// Returns the client that reaches the host at the given address, which
+  /// is built only if there's none yet for the host over the address's
+  /// family, or the host resolved to another address before.
+  fn family_client(&self, domain: &str, addr: SocketAddr) -> Result<Client> {
+    let key = (domain.to_owned(), IpFamily::of(addr.ip()));
+    let mut clients = self.family_clients.lock().unwrap();
+    match clients.get(&key) {
+      Some((cached_addr, client)) if *cached_addr == addr => Ok(client.clone()),
+      _ => {
+        let client = self.builder().resolve(domain, addr).build()?;
+        clients.insert(key, (addr, client.clone()));
+        Ok(client)
+      }
+    }
+  }
+
+  /// Returns the local address trackers are connected to from, if set.
+  pub fn source_addr(&self) -> Option<IpAddr> {
+    self.source_addr
+  }
+}
+
+impl Default for HttpClient {
+  fn default() -> Self {
+    HttpClient {
+      client: Client::new(),
+      headers: HeaderMap::new(),
+      root_certificates: Vec::new(),
+      use_system_proxy: true,
+      danger_accept_invalid_certs: false,
+      source_addr: None,
+      family_clients: Default::default(),
+    }
+  }
+}
+
+/// The HTTP tracker for a tonnert for which we can request peers as well as to announce transfer progress.
+pub struct HttpTracker {
+  /// The HTTP client, which may be shared with other trackers.
+  http: HttpClient,
   /// The URL of the tracker.
   url: Url,
+  /// Whether the compact peer list is asked for, which is given up on once
+  /// the tracker rejects it but accepts the dictionary model instead.
+  is_compact: AtomicBool,
 }
 
-impl Tracker {
+impl HttpTracker {
   pub fn new(url: Url) -> Self {
-    Tracker {
-      client: Client::new(),
+    Self::with_client(url, HttpClient::default())
+  }
+
+  /// Creates a tracker that makes its requests with the given client, which
+  /// may be shared with other trackers.
+  pub fn with_client(url: Url, http: HttpClient) -> Self {
+    HttpTracker {
+      http,
       url,
+      is_compact: AtomicBool::new(true),
     }
   }
 
+  /// Returns the tracker's announce URL.
+  pub fn url(&self) -> &Url {
+    &self.url
+  }
+
   /// Sends an announce request to the tracker with the specified parameters.
   ///
   /// This may be used by a torrent to request peers to download form.
   /// And report the current status information to the the tracker.
   pub async fn announce(&self, params: Announce) -> Result<Response> {
+    self.send_announce(&self.http.client, params).await
+  }
+
+  /// Sends an announce request to the tracker over the given IP family, so
+  /// that a host with addresses of both families can make itself known to
+  /// the tracker by both of them (BEP 7).
+  ///
+  /// The tracker's host is resolved in advance to an address of the family,
+  /// and an error is returned if it has none.
+  pub async fn announce_over(
+    &self,
+    params: Announce,
+    family: IpFamily,
+  ) -> Result<Response> {
+    if self
+      .http
+      .source_addr
+      .is_some_and(|ip| IpFamily::of(ip) != family)
+    {
+      return Err(TrackerError::NoAddress(family));
+    }
+    let port = self.url.port_or_known_default().unwrap_or(80);
+    let client = match self.url.host() {
+      Some(Host::Domain(domain)) => {
+        let addr = tokio::net::lookup_host((domain, port))
+          .await?
+          .find(|addr| IpFamily::of(addr.ip()) == family)
+          .ok_or(TrackerError::NoAddress(family))?;
+        self.http.family_client(domain, addr)?
+      }
+      // a literal address can't be reached over the other family
+      Some(Host::Ipv4(_)) if family == IpFamily::V4 => self.http.client.clone(),
+      Some(Host::Ipv6(_)) if family == IpFamily::V6 => self.http.client.clone(),
+      _ => return Err(TrackerError::NoAddress(family)),
+    };
+    self.send_announce(&client, params).await
+  }
+
+  async fn send_announce(
+    &self,
+    client: &Client,
+    params: Announce,
+  ) -> Result<Response> {
     let mut query = vec![
       ("port", params.port.to_string()),
       ("downloaded", params.downloaded.to_string()),
       ("uploaded", params.uploaded.to_string()),
       ("left", params.left.to_string()),
-      ("compact", "1".to_string()),
     ];
 
     if let Some(peer_count) = params.peer_count {
@@ -41,6 +219,21 @@ impl Tracker {
     if let Some(ip) = &params.ip {
       query.push(("ip", ip.to_string()));
     }
+    if let Some(ipv4) = &params.ipv4 {
+      query.push(("ipv4", ipv4.to_string()));
+    }
+    if let Some(ipv6) = &params.ipv6 {
+      query.push(("ipv6", ipv6.to_string()));
+    }
+    if let Some(event) = params.event {
+      query.push(("event", event.as_str().to_string()));
+    }
+    if let Some(tracker_id) = params.tracker_id {
+      query.push(("trackerid", tracker_id));
+    }
+    if let Some(key) = params.key {
+      query.push(("key", format!("{:08X}", key)));
+    }
 
     let url = format!(
       "{url}\
@@ -55,23 +248,213 @@ impl Tracker {
         percent_encoding::percent_encode(&params.peer_id, URL_ENCODE_RESERVED)
     );
 
+    let is_compact = self.is_compact.load(Ordering::Relaxed);
+    let resp = self.get(client, &url, &query, is_compact).await?;
+    // some private trackers insist on the dictionary model, which is then
+    // asked for from now on, but only if the tracker accepts it
+    if is_compact && resp.failure_reason.is_some() {
+      if let Ok(fallback) = self.get(client, &url, &query, false).await {
+        if fallback.failure_reason.is_none() {
+          log::info!("Tracker {} rejects compact peer lists", self);
+          self.is_compact.store(false, Ordering::Relaxed);
+          return Ok(fallback);
+        }
+      }
+    }
+    Ok(resp)
+  }
+
+  /// Sends the announce request with the given query, asking for either the
+  /// compact or the dictionary model of the peer list.
+  async fn get(
+    &self,
+    client: &Client,
+    url: &str,
+    query: &[(&str, String)],
+    is_compact: bool,
+  ) -> Result<Response> {
+    let compact = if is_compact { "1" } else { "0" };
+    let resp = client
+      .get(url)
+      .headers(self.http.headers.clone())
+      .query(query)
+      .query(&[("compact", compact)])
+      .send()
+      .await?
+      .error_for_status()?;
+    let remote_addr = resp.remote_addr();
+    let resp = resp.bytes().await?;
+
+    let mut resp: Response = serde_bencoded::from_bytes(&resp)?;
+    resp.remote_addr = remote_addr;
+    Ok(resp)
+  }
+
+  /// Checks whether the tracker is reachable by sending it a `HEAD` request
+  /// that times out after the given duration.
+  ///
+  /// Any response counts, as trackers may reject a request that lacks the
+  /// announce parameters: the tracker is only considered unreachable if it
+  /// can't be connected to or doesn't respond in time.
+  pub async fn probe(&self, timeout: Duration) -> Result<()> {
+    self
+      .http
+      .client
+      .head(self.url.clone())
+      .headers(self.http.headers.clone())
+      .timeout(timeout)
+      .send()
+      .await?;
+    Ok(())
+  }
+
+  /// Asks the tracker for the statistics of the torrent's swarm, at the
+  /// scrape URL derived from the announce URL (BEP 48).
+  pub async fn scrape(&self, info_hash: Sha1Hash) -> Result<Scrape> {
+    let url = self.scrape_url().ok_or(TrackerError::ScrapeUnsupported)?;
+    let url = format!(
+      "{url}{separator}info_hash={info_hash}",
+      separator = if url.query().is_some() { '&' } else { '?' },
+      info_hash =
+        percent_encoding::percent_encode(&info_hash, URL_ENCODE_RESERVED),
+    );
     let resp = self
+      .http
       .client
-      .get(&url)
-      .query(&query)
+      .get(url)
+      .headers(self.http.headers.clone())
       .send()
       .await?
       .error_for_status()?
       .bytes()
       .await?;
+    let resp: ScrapeResponse = serde_bencoded::from_bytes(&resp)?;
+    if let Some(failure_reason) = resp.failure_reason {
+      return Err(TrackerError::Failure(failure_reason));
+    }
+    // the tracker only returns the statistics of the torrent asked for
+    resp
+      .files
+      .into_values()
+      .next()
+      .ok_or(TrackerError::InvalidResponse)
+  }
 
-    let resp = serde_bencoded::from_bytes(&resp)?;
-    Ok(resp)
+  /// Returns the scrape URL, which trackers support by convention if the
+  /// last path segment of their announce URL starts with `announce`, which
+  /// is replaced by `scrape`.
+  fn scrape_url(&self) -> Option<Url> {
+    let (path, segment) = self.url.path().rsplit_once('/')?;
+    let rest = segment.strip_prefix("announce")?;
+    let mut url = self.url.clone();
+    url.set_path(&format!("{}/scrape{}", path, rest));
+    Some(url)
   }
 }
 
-impl fmt::Display for Tracker {
+/// The body of a scrape response, which holds the statistics of each
+/// torrent asked for by its info hash.
+#[derive(Deserialize)]
+struct ScrapeResponse {
+  #[serde(default)]
+  files: HashMap<serde_bytes::ByteBuf, Scrape>,
+  #[serde(rename = "failure reason")]
+  failure_reason: Option<String>,
+}
+
+impl TrackerClient for HttpTracker {
+  fn url(&self) -> &Url {
+    &self.url
+  }
+
+  fn announce(
+    &self,
+    params: Announce,
+    family: Option<IpFamily>,
+  ) -> BoxFuture<'_, Result<Response>> {
+    match family {
+      Some(family) => self.announce_over(params, family).boxed(),
+      None => HttpTracker::announce(self, params).boxed(),
+    }
+  }
+
+  fn scrape(&self, info_hash: Sha1Hash) -> BoxFuture<'_, Result<Scrape>> {
+    HttpTracker::scrape(self, info_hash).boxed()
+  }
+
+  fn probe(&self, timeout: Duration) -> BoxFuture<'_, Result<()>> {
+    HttpTracker::probe(self, timeout).boxed()
+  }
+}
+
+/// The IP family over which a tracker is announced to, or of a peer's
+/// address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IpFamily {
+  V4,
+  V6,
+}
+
+impl IpFamily {
+  /// Returns the family of the address.
+  pub fn of(ip: IpAddr) -> Self {
+    match ip {
+      IpAddr::V4(_) => Self::V4,
+      IpAddr::V6(_) => Self::V6,
+    }
+  }
+
+  /// Returns whether the socket address is of this family.
+  pub fn contains(&self, addr: &SocketAddr) -> bool {
+    Self::of(addr.ip()) == *self
+  }
+}
+
+impl fmt::Display for IpFamily {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::V4 => f.write_str("IPv4"),
+      Self::V6 => f.write_str("IPv6"),
+    }
+  }
+}
+
+impl fmt::Display for HttpTracker {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(f, "'{}'", self.url)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_family_client_is_rebuilt_on_new_address() {
+    let http = HttpClient::default();
+    let v4 = "127.0.0.1:80".parse().unwrap();
+    let v6 = "[::1]:80".parse().unwrap();
+    let other_v4 = "127.0.0.2:80".parse().unwrap();
+    let cached_addrs = |http: &HttpClient| {
+      let mut addrs: Vec<_> = http
+        .family_clients
+        .lock()
+        .unwrap()
+        .values()
+        .map(|(addr, _)| *addr)
+        .collect();
+      addrs.sort();
+      addrs
+    };
+
+    // one client is kept for each family of the host
+    http.family_client("tracker.example", v4).unwrap();
+    http.family_client("tracker.example", v4).unwrap();
+    http.family_client("tracker.example", v6).unwrap();
+    assert_eq!(cached_addrs(&http), vec![v4, v6]);
+
+    // and replaced once the host resolves to another address
+    http.family_client("tracker.example", other_v4).unwrap();
+    assert_eq!(cached_addrs(&http), vec![other_v4, v6]);
+  }
+}
@@ -0,0 +1,387 @@
+//! The client of UDP trackers (BEP 15), which answer announces with less
+//! overhead than HTTP trackers.
+
+use std::{
+  collections::HashMap,
+  fmt, io,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, Bytes};
+use futures::future::{BoxFuture, FutureExt};
+use reqwest::Url;
+use tokio::{net::UdpSocket, time};
+use url::Host;
+
+use super::{
+  announce::{Announce, Event},
+  client::TrackerClient,
+  prelude::Result,
+  response::{Response, Scrape},
+  tracker::IpFamily,
+};
+use crate::{error::tracker::TrackerError, Sha1Hash};
+
+/// The magic number with which connect requests start.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a connection id may be used for after it's obtained.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+/// The time after which a request is first sent again if the tracker
+/// doesn't respond, which doubles with each retransmission.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The number of times a request is sent again before giving up, although
+/// announces are usually given up on earlier, when the tracker timeout is
+/// up.
+const MAX_RETRANSMIT_COUNT: u32 = 8;
+
+/// The largest response that is read, which leaves room for hundreds of
+/// peers.
+const MAX_RESPONSE_LEN: usize = 8192;
+
+/// A UDP tracker, which is first connected to in order to obtain a
+/// connection id, which is then included in its announces for a minute.
+pub struct UdpTracker {
+  url: Url,
+  /// The local address the tracker is sent to from, or any address of the
+  /// tracker's family if not set.
+  source_addr: Option<IpAddr>,
+  /// The connection ids obtained, which may be shared with the trackers of
+  /// other torrents.
+  connections: UdpConnections,
+}
+
+/// The connection ids obtained from UDP trackers, by the tracker's address.
+///
+/// The engine shares these among the trackers of all its torrents, so that
+/// the announces and scrapes of torrents with the same tracker only connect
+/// to it once a minute.
+#[derive(Clone, Debug, Default)]
+pub struct UdpConnections(Arc<Mutex<HashMap<SocketAddr, Connection>>>);
+
+#[derive(Clone, Copy, Debug)]
+struct Connection {
+  id: u64,
+  time: Instant,
+}
+
+impl UdpConnections {
+  /// Returns the connection id of the tracker's address, if it's still
+  /// valid.
+  fn get(&self, addr: SocketAddr) -> Option<u64> {
+    let connections = self.0.lock().unwrap();
+    connections
+      .get(&addr)
+      .filter(|c| c.time.elapsed() < CONNECTION_ID_TTL)
+      .map(|c| c.id)
+  }
+
+  /// Saves the connection id obtained from the tracker's address, dropping
+  /// those that expired.
+  fn insert(&self, addr: SocketAddr, id: u64) {
+    let mut connections = self.0.lock().unwrap();
+    connections.retain(|_, c| c.time.elapsed() < CONNECTION_ID_TTL);
+    let time = Instant::now();
+    connections.insert(addr, Connection { id, time });
+  }
+}
+
+impl UdpTracker {
+  pub fn new(url: Url) -> Self {
+    Self::with_source_addr(url, None)
+  }
+
+  /// Creates a tracker that is sent to from the given local address, if
+  /// set, in which case only its addresses of the same IP family are used.
+  pub fn with_source_addr(url: Url, source_addr: Option<IpAddr>) -> Self {
+    Self::with_connections(url, source_addr, UdpConnections::default())
+  }
+
+  /// Creates a tracker like [`UdpTracker::with_source_addr`], which uses and
+  /// saves the connection ids shared with other trackers.
+  pub fn with_connections(
+    url: Url,
+    source_addr: Option<IpAddr>,
+    connections: UdpConnections,
+  ) -> Self {
+    UdpTracker {
+      url,
+      source_addr,
+      connections,
+    }
+  }
+
+  /// Sends an announce request to the tracker, over the given IP family if
+  /// set.
+  pub async fn announce(
+    &self,
+    params: Announce,
+    family: Option<IpFamily>,
+  ) -> Result<Response> {
+    let (socket, addr) = self.connect_socket(family).await?;
+    let connection_id = self.connection_id(&socket, addr).await?;
+
+    // the UDP protocol has no event for partial seeds
+    let event = match params.event {
+      None | Some(Event::Paused) => 0,
+      Some(Event::Completed) => 1,
+      Some(Event::Started) => 2,
+      Some(Event::Stopped) => 3,
+    };
+    // only an IPv4 address can be given, and only over IPv4
+    let ip = match params.ip {
+      Some(IpAddr::V4(ip)) if addr.is_ipv4() => u32::from(ip),
+      _ => 0,
+    };
+    let num_want = params
+      .peer_count
+      .map_or(-1, |count| count.min(i32::MAX as usize) as i32);
+
+    let transaction_id = rand::random();
+    let mut request = Vec::with_capacity(98);
+    request.put_u64(connection_id);
+    request.put_u32(ACTION_ANNOUNCE);
+    request.put_u32(transaction_id);
+    request.put_slice(&params.info_hash);
+    request.put_slice(&params.peer_id);
+    request.put_u64(params.downloaded);
+    request.put_u64(params.left);
+    request.put_u64(params.uploaded);
+    request.put_u32(event);
+    request.put_u32(ip);
+    request.put_u32(params.key.unwrap_or_default());
+    request.put_i32(num_want);
+    request.put_u16(params.port);
+
+    let (action, mut body) =
+      transact(&socket, &request, transaction_id).await?;
+    match action {
+      ACTION_ANNOUNCE if body.len() >= 12 => {}
+      ACTION_ERROR => {
+        return Ok(Response {
+          failure_reason: Some(String::from_utf8_lossy(&body).into_owned()),
+          remote_addr: Some(addr),
+          ..Default::default()
+        });
+      }
+      _ => return Err(TrackerError::InvalidResponse),
+    }
+    let interval = Duration::from_secs(body.get_u32().into());
+    let leecher_count = body.get_u32() as usize;
+    let seeder_count = body.get_u32() as usize;
+    // the peers are of the family over which the tracker is reached
+    let mut resp = Response {
+      interval: Some(interval),
+      seeder_count: Some(seeder_count),
+      leecher_count: Some(leecher_count),
+      remote_addr: Some(addr),
+      ..Default::default()
+    };
+    if addr.is_ipv4() {
+      resp.peers = body
+        .chunks_exact(6)
+        .map(|mut peer| {
+          let ip = Ipv4Addr::from(peer.get_u32());
+          SocketAddr::new(ip.into(), peer.get_u16())
+        })
+        .collect();
+    } else {
+      resp.peers6 = body
+        .chunks_exact(18)
+        .map(|mut peer| {
+          let ip = Ipv6Addr::from(peer.get_u128());
+          SocketAddr::new(ip.into(), peer.get_u16())
+        })
+        .collect();
+    }
+    Ok(resp)
+  }
+
+  /// Asks the tracker for the statistics of the torrent's swarm.
+  pub async fn scrape(&self, info_hash: Sha1Hash) -> Result<Scrape> {
+    let (socket, addr) = self.connect_socket(None).await?;
+    let connection_id = self.connection_id(&socket, addr).await?;
+
+    let transaction_id = rand::random();
+    let mut request = Vec::with_capacity(36);
+    request.put_u64(connection_id);
+    request.put_u32(ACTION_SCRAPE);
+    request.put_u32(transaction_id);
+    request.put_slice(&info_hash);
+
+    let (action, mut body) =
+      transact(&socket, &request, transaction_id).await?;
+    match action {
+      ACTION_SCRAPE if body.len() >= 12 => Ok(Scrape {
+        seeder_count: body.get_u32() as usize,
+        completed_count: body.get_u32() as usize,
+        leecher_count: body.get_u32() as usize,
+      }),
+      ACTION_ERROR => Err(TrackerError::Failure(
+        String::from_utf8_lossy(&body).into_owned(),
+      )),
+      _ => Err(TrackerError::InvalidResponse),
+    }
+  }
+
+  /// Checks whether the tracker is reachable by connecting to it, which
+  /// times out after the given duration.
+  pub async fn probe(&self, timeout: Duration) -> Result<()> {
+    time::timeout(timeout, async {
+      let (socket, addr) = self.connect_socket(None).await?;
+      self.connection_id(&socket, addr).await?;
+      Ok(())
+    })
+    .await
+    .unwrap_or(Err(TrackerError::Timeout))
+  }
+
+  /// Resolves the tracker's address, of the given IP family if set, and
+  /// returns a socket connected to it.
+  async fn connect_socket(
+    &self,
+    family: Option<IpFamily>,
+  ) -> Result<(UdpSocket, SocketAddr)> {
+    // UDP trackers have no default port
+    let port = self.url.port().ok_or_else(|| {
+      io::Error::new(io::ErrorKind::InvalidInput, "tracker URL has no port")
+    })?;
+    let addrs = match self.url.host() {
+      Some(Host::Domain(domain)) => {
+        tokio::net::lookup_host((domain, port)).await?.collect()
+      }
+      Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+      Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+      None => Vec::new(),
+    };
+    // the source address decides the family if set
+    let family = family.or(self.source_addr.map(IpFamily::of));
+    let addr = addrs.into_iter().find(|addr| {
+      family.is_none_or(|family| family.contains(addr))
+        && self
+          .source_addr
+          .is_none_or(|ip| ip.is_ipv4() == addr.is_ipv4())
+    });
+    let addr = match (addr, family) {
+      (Some(addr), _) => addr,
+      (None, Some(family)) => return Err(TrackerError::NoAddress(family)),
+      (None, None) => {
+        return Err(
+          io::Error::new(io::ErrorKind::NotFound, "tracker has no address")
+            .into(),
+        )
+      }
+    };
+
+    let local_addr = match (self.source_addr, addr) {
+      (Some(ip), _) => SocketAddr::new(ip, 0),
+      (None, SocketAddr::V4(_)) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+      (None, SocketAddr::V6(_)) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local_addr).await?;
+    socket.connect(addr).await?;
+    Ok((socket, addr))
+  }
+
+  /// Returns the connection id to include in requests to the tracker's
+  /// address, which is reused while it's valid or obtained anew otherwise.
+  async fn connection_id(
+    &self,
+    socket: &UdpSocket,
+    addr: SocketAddr,
+  ) -> Result<u64> {
+    if let Some(id) = self.connections.get(addr) {
+      return Ok(id);
+    }
+
+    let transaction_id = rand::random();
+    let mut request = Vec::with_capacity(16);
+    request.put_u64(PROTOCOL_ID);
+    request.put_u32(ACTION_CONNECT);
+    request.put_u32(transaction_id);
+
+    let (action, mut body) = transact(socket, &request, transaction_id).await?;
+    match action {
+      ACTION_CONNECT if body.len() >= 8 => {
+        let id = body.get_u64();
+        self.connections.insert(addr, id);
+        Ok(id)
+      }
+      ACTION_ERROR => Err(TrackerError::Failure(
+        String::from_utf8_lossy(&body).into_owned(),
+      )),
+      _ => Err(TrackerError::InvalidResponse),
+    }
+  }
+}
+
+/// Sends the request, and again with a growing delay until the tracker
+/// responds, returning the response's action and the body that follows it.
+///
+/// Datagrams of other transactions, e.g. late responses to earlier
+/// requests, are ignored.
+async fn transact(
+  socket: &UdpSocket,
+  request: &[u8],
+  transaction_id: u32,
+) -> Result<(u32, Bytes)> {
+  let mut buf = vec![0; MAX_RESPONSE_LEN];
+  for retransmit_count in 0..=MAX_RETRANSMIT_COUNT {
+    socket.send(request).await?;
+    let timeout = RETRANSMIT_TIMEOUT * 2u32.pow(retransmit_count);
+    let recv = async {
+      loop {
+        let len = socket.recv(&mut buf).await?;
+        let mut resp = &buf[..len];
+        if resp.len() < 8 {
+          continue;
+        }
+        let action = resp.get_u32();
+        if resp.get_u32() == transaction_id {
+          return Ok::<_, TrackerError>((action, Bytes::copy_from_slice(resp)));
+        }
+      }
+    };
+    if let Ok(result) = time::timeout(timeout, recv).await {
+      return result;
+    }
+  }
+  Err(TrackerError::Timeout)
+}
+
+impl TrackerClient for UdpTracker {
+  fn url(&self) -> &Url {
+    &self.url
+  }
+
+  fn announce(
+    &self,
+    params: Announce,
+    family: Option<IpFamily>,
+  ) -> BoxFuture<'_, Result<Response>> {
+    UdpTracker::announce(self, params, family).boxed()
+  }
+
+  fn scrape(&self, info_hash: Sha1Hash) -> BoxFuture<'_, Result<Scrape>> {
+    UdpTracker::scrape(self, info_hash).boxed()
+  }
+
+  fn probe(&self, timeout: Duration) -> BoxFuture<'_, Result<()>> {
+    UdpTracker::probe(self, timeout).boxed()
+  }
+}
+
+impl fmt::Display for UdpTracker {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "'{}'", self.url)
+  }
+}
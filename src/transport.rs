@@ -0,0 +1,242 @@
+//! The transports over which peer sessions talk to peers.
+//!
+//! Peer sessions only need a byte stream to run the wire protocol over, so
+//! how the stream is established is left to a [`PeerTransport`]. TCP is the
+//! default, and other transports, such as QUIC or an in-memory one for
+//! tests, may be given per torrent in
+//! [`TorrentParams::transport`](crate::engine::TorrentParams::transport).
+
+use std::{
+  fmt, io,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use futures::future::{BoxFuture, FutureExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::{TcpListener, TcpSocket, TcpStream},
+};
+
+/// A byte stream to a peer, over which the peer wire protocol is run.
+pub trait PeerStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> PeerStream for T {}
+
+/// A connection to a peer, as returned by a transport.
+pub type PeerConnection = Box<dyn PeerStream>;
+
+/// Establishes connections with peers, in both directions.
+pub trait PeerTransport: fmt::Debug + Send + Sync {
+  /// Connects to the peer at the address.
+  fn connect(
+    &self,
+    addr: SocketAddr,
+  ) -> BoxFuture<'_, io::Result<PeerConnection>>;
+
+  /// Starts listening for peers connecting to us on the address. The port
+  /// may be 0, in which case the actual port is returned by the listener's
+  /// [`PeerListener::local_addr`].
+  fn bind(
+    &self,
+    addr: SocketAddr,
+  ) -> BoxFuture<'_, io::Result<Box<dyn PeerListener>>>;
+}
+
+/// Accepts the connections of peers, created by [`PeerTransport::bind`].
+pub trait PeerListener: Send + Sync {
+  /// Returns the address on which the listener is reachable.
+  fn local_addr(&self) -> io::Result<SocketAddr>;
+
+  /// Waits for the next peer to connect, returning the connection and the
+  /// peer's address, with IPv4 peers reported by their IPv4 address even if
+  /// they connected to a dual-stack socket.
+  fn accept(&self) -> BoxFuture<'_, io::Result<(PeerConnection, SocketAddr)>>;
+}
+
+/// The default transport, connecting to peers over TCP.
+///
+/// Binding the IPv6 unspecified address listens on a dual-stack socket,
+/// accepting peers of both families whatever the OS default, or falls back
+/// to the IPv4 unspecified address on hosts without IPv6.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpTransport {
+  /// The local address peers are connected to from, or the one the OS picks
+  /// if not set.
+  source_addr: Option<IpAddr>,
+}
+
+impl TcpTransport {
+  /// Creates a transport that connects to peers from the given local
+  /// address, as configured in
+  /// [`EngineConf::source_addr`](crate::conf::EngineConf::source_addr).
+  pub fn with_source_addr(source_addr: Option<IpAddr>) -> Self {
+    TcpTransport { source_addr }
+  }
+}
+
+impl PeerTransport for TcpTransport {
+  fn connect(
+    &self,
+    addr: SocketAddr,
+  ) -> BoxFuture<'_, io::Result<PeerConnection>> {
+    async move {
+      let socket = match self.source_addr {
+        Some(source_addr) => {
+          if source_addr.is_ipv4() != addr.is_ipv4() {
+            return Err(io::Error::new(
+              io::ErrorKind::AddrNotAvailable,
+              "peer is of the other IP family than the source address",
+            ));
+          }
+          let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+          };
+          socket.bind(SocketAddr::new(source_addr, 0))?;
+          socket.connect(addr).await?
+        }
+        None => TcpStream::connect(addr).await?,
+      };
+      Ok(Box::new(socket) as PeerConnection)
+    }
+    .boxed()
+  }
+
+  fn bind(
+    &self,
+    addr: SocketAddr,
+  ) -> BoxFuture<'_, io::Result<Box<dyn PeerListener>>> {
+    async move {
+      let listener = if addr.ip() == Ipv6Addr::UNSPECIFIED {
+        bind_dual_stack(addr)?
+      } else {
+        TcpListener::bind(addr).await?
+      };
+      Ok(Box::new(listener) as Box<dyn PeerListener>)
+    }
+    .boxed()
+  }
+}
+
+/// Listens on the IPv6 unspecified address for peers of both families, or
+/// only on the IPv4 one if the host doesn't support IPv6.
+fn bind_dual_stack(addr: SocketAddr) -> io::Result<TcpListener> {
+  let socket =
+    match Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)) {
+      Ok(socket) => socket,
+      Err(e) => return bind_ipv4_only(addr.port(), e),
+    };
+  socket.set_only_v6(false)?;
+  // like tokio's own listeners, so that a restarted torrent may reuse its
+  // port right away
+  #[cfg(unix)]
+  socket.set_reuse_address(true)?;
+  socket.set_nonblocking(true)?;
+  if let Err(e) = socket.bind(&addr.into()) {
+    // with IPv6 disabled, e.g. by sysctl, the socket may still be created
+    // but it can't be bound
+    let is_ipv6_unavailable = e.kind() == io::ErrorKind::AddrNotAvailable
+      || e.raw_os_error() == Some(nix::errno::Errno::EAFNOSUPPORT as i32);
+    if is_ipv6_unavailable {
+      return bind_ipv4_only(addr.port(), e);
+    }
+    return Err(e);
+  }
+  socket.listen(1024)?;
+  TcpListener::from_std(socket.into())
+}
+
+/// Listens on the IPv4 unspecified address, as IPv6 is unavailable for the
+/// given reason.
+fn bind_ipv4_only(port: u16, reason: io::Error) -> io::Result<TcpListener> {
+  log::info!("IPv6 unavailable ({}), listening on IPv4 only", reason);
+  let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+  let listener = std::net::TcpListener::bind(addr)?;
+  listener.set_nonblocking(true)?;
+  TcpListener::from_std(listener)
+}
+
+impl PeerListener for TcpListener {
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    TcpListener::local_addr(self)
+  }
+
+  fn accept(&self) -> BoxFuture<'_, io::Result<(PeerConnection, SocketAddr)>> {
+    async move {
+      let (socket, addr) = TcpListener::accept(self).await?;
+      let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+      Ok((Box::new(socket) as PeerConnection, addr))
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  use super::*;
+
+  #[tokio::test]
+  async fn test_tcp_transport_connects_to_listener() {
+    let transport = TcpTransport::default();
+    let listener = transport
+      .bind("127.0.0.1:0".parse().unwrap())
+      .await
+      .unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+
+    let (outbound, inbound) =
+      tokio::join!(transport.connect(addr), listener.accept());
+    let mut outbound = outbound.unwrap();
+    let (mut inbound, peer_addr) = inbound.unwrap();
+    assert!(peer_addr.ip().is_loopback());
+
+    outbound.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    inbound.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+  }
+
+  #[tokio::test]
+  async fn test_tcp_transport_accepts_ipv4_on_unspecified_ipv6() {
+    let transport = TcpTransport::default();
+    let listener = transport.bind("[::]:0".parse().unwrap()).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // IPv4 peers are accepted whether the socket is dual-stack or fell back
+    // to IPv4, and are reported by their IPv4 address
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let (outbound, inbound) =
+      tokio::join!(transport.connect(addr), listener.accept());
+    outbound.unwrap();
+    let (_, peer_addr) = inbound.unwrap();
+    assert_eq!(peer_addr.ip(), Ipv4Addr::LOCALHOST);
+  }
+
+  #[tokio::test]
+  async fn test_tcp_transport_connects_from_source_addr() {
+    let source_addr = IpAddr::from(Ipv4Addr::LOCALHOST);
+    let transport = TcpTransport::with_source_addr(Some(source_addr));
+    let listener = transport
+      .bind("127.0.0.1:0".parse().unwrap())
+      .await
+      .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (outbound, inbound) =
+      tokio::join!(transport.connect(addr), listener.accept());
+    outbound.unwrap();
+    assert_eq!(inbound.unwrap().1.ip(), source_addr);
+
+    // peers of the other family can't be reached from the source address
+    let error = transport
+      .connect("[::1]:6881".parse().unwrap())
+      .await
+      .err()
+      .unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::AddrNotAvailable);
+  }
+}
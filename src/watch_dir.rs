@@ -0,0 +1,225 @@
+//! The engine's watch directory service, which adds the torrents whose files
+//! are dropped in a directory.
+//!
+//! The directory is polled rather than watched for filesystem events, as
+//! that works the same on all platforms and network filesystems. Each file
+//! picked up is renamed afterwards, so that it's not added again on the next
+//! poll or after a restart.
+
+use std::{
+  ffi::OsString,
+  fs,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+use tokio::{task, time};
+
+use crate::{
+  alert::{Alert, AlertSender},
+  conf::WatchDirConf,
+  engine::{self, TorrentParams, TorrentSource},
+  error::WatchError,
+  magnet::Magnet,
+  metainfo::Metainfo,
+  torrent, TorrentId,
+};
+
+/// Files modified more recently than this are skipped until the next poll,
+/// as they may still be being written.
+const MIN_FILE_AGE: Duration = Duration::from_secs(1);
+
+/// Spawns the task that polls the directory, creating a torrent on the
+/// engine for each new file.
+///
+/// The task runs until it's aborted or the engine stops, or until the
+/// directory can't be read, e.g. because it was removed, in which case the
+/// error is logged.
+pub(crate) fn spawn(
+  conf: WatchDirConf,
+  engine_tx: engine::Sender,
+  alert_tx: AlertSender,
+) -> task::JoinHandle<()> {
+  log::info!("Watching {:?} for new torrents", conf.dir);
+  task::spawn(async move {
+    let mut poll_timer = time::interval(conf.poll_interval);
+    loop {
+      poll_timer.tick().await;
+
+      let dir = conf.dir.clone();
+      let files =
+        match task::spawn_blocking(move || scan(&dir, MIN_FILE_AGE)).await {
+          Ok(Ok(files)) => files,
+          Ok(Err(e)) => {
+            log::error!(
+              "Failed to scan watch directory {:?}, no longer watching: {}",
+              conf.dir,
+              e
+            );
+            return;
+          }
+          Err(e) => {
+            log::error!("Watch directory scan failed: {}", e);
+            return;
+          }
+        };
+
+      for (path, result) in files {
+        match result {
+          Ok(source) => {
+            log::info!("Adding torrent from {:?}", path);
+            // the torrent is controlled through the engine, so its own
+            // handle is not needed
+            let (torrent_tx, torrent_rx) = torrent::channel();
+            let cmd = engine::Command::CreateTorrent {
+              id: TorrentId::new(),
+              params: Box::new(TorrentParams::new(source)),
+              torrent_tx,
+              torrent_rx,
+              resume: None,
+            };
+            if engine_tx.send(cmd).is_err() {
+              log::info!("Engine stopped, no longer watching {:?}", conf.dir);
+              return;
+            }
+          }
+          Err(error) => {
+            log::warn!("Failed to add torrent from {:?}: {}", path, error);
+            if alert_tx.send(Alert::WatchDirError { path, error }).is_err() {
+              log::info!("Alerts closed, no longer watching {:?}", conf.dir);
+              return;
+            }
+          }
+        }
+      }
+    }
+  })
+}
+
+/// Reads the `.torrent` and `.magnet` files in the directory, ordered by
+/// name, and renames them so that they're not read again.
+///
+/// Files that could not be renamed are not added either, as they would be
+/// added again on the next scan.
+fn scan(
+  dir: &Path,
+  min_age: Duration,
+) -> std::io::Result<Vec<(PathBuf, Result<TorrentSource, WatchError>)>> {
+  let mut paths = Vec::new();
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let metadata = entry.metadata()?;
+    if !metadata.is_file() {
+      continue;
+    }
+    let is_recent = metadata
+      .modified()
+      .ok()
+      .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+      .is_some_and(|age| age < min_age);
+    if !is_recent {
+      paths.push(entry.path());
+    }
+  }
+  paths.sort_unstable();
+
+  let mut files = Vec::new();
+  for path in paths {
+    let result = match path.extension().and_then(|ext| ext.to_str()) {
+      Some("torrent") => read_metainfo(&path),
+      Some("magnet") => read_magnet(&path),
+      _ => continue,
+    };
+    let suffix = if result.is_ok() { "added" } else { "invalid" };
+    let result = match fs::rename(&path, with_suffix(&path, suffix)) {
+      Ok(()) => result,
+      Err(e) => Err(e.into()),
+    };
+    files.push((path, result));
+  }
+  Ok(files)
+}
+
+fn read_metainfo(path: &Path) -> Result<TorrentSource, WatchError> {
+  let metainfo = Metainfo::from_bytes(&fs::read(path)?)?;
+  Ok(TorrentSource::Metainfo(metainfo))
+}
+
+fn read_magnet(path: &Path) -> Result<TorrentSource, WatchError> {
+  let magnet = Magnet::parse(fs::read_to_string(path)?.trim())?;
+  Ok(TorrentSource::Magnet(magnet))
+}
+
+/// Returns the path with the suffix appended to its file name, e.g.
+/// `a.torrent.added`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+  file_name.push(".");
+  file_name.push(suffix);
+  path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::tempdir;
+
+  use super::*;
+
+  #[test]
+  fn should_read_and_rename_new_files() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::copy("fixtures/debian-iso.torrent", dir.join("a.torrent")).unwrap();
+    fs::write(dir.join("b.torrent"), b"not bencode").unwrap();
+    fs::write(
+      dir.join("c.magnet"),
+      "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567\n",
+    )
+    .unwrap();
+    fs::write(dir.join("d.txt"), b"ignored").unwrap();
+
+    let files = scan(dir, Duration::ZERO).unwrap();
+    assert_eq!(files.len(), 3);
+    assert_eq!(files[0].0, dir.join("a.torrent"));
+    assert!(matches!(files[0].1, Ok(TorrentSource::Metainfo(_))));
+    assert!(matches!(files[1].1, Err(WatchError::Metainfo(_))));
+    assert!(matches!(files[2].1, Ok(TorrentSource::Magnet(_))));
+
+    assert!(dir.join("a.torrent.added").exists());
+    assert!(dir.join("b.torrent.invalid").exists());
+    assert!(dir.join("c.magnet.added").exists());
+    assert!(dir.join("d.txt").exists());
+
+    // the renamed files are not picked up again
+    assert!(scan(dir, Duration::ZERO).unwrap().is_empty());
+  }
+
+  #[test]
+  fn should_skip_recently_modified_files() {
+    let dir = tempdir().unwrap();
+    fs::copy("fixtures/debian-iso.torrent", dir.path().join("a.torrent"))
+      .unwrap();
+    assert!(scan(dir.path(), Duration::from_secs(60))
+      .unwrap()
+      .is_empty());
+    assert!(dir.path().join("a.torrent").exists());
+  }
+
+  #[tokio::test]
+  async fn should_stop_when_dir_vanishes() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().to_owned();
+    drop(dir);
+    let (engine_tx, _engine_rx) = engine::channel();
+    let (alert_tx, _alert_rx) = tokio::sync::mpsc::unbounded_channel();
+    let conf = WatchDirConf {
+      dir: path,
+      poll_interval: Duration::from_millis(10),
+    };
+    let task = spawn(conf, engine_tx, alert_tx);
+    time::timeout(Duration::from_secs(1), task)
+      .await
+      .expect("watch directory task didn't stop")
+      .expect("watch directory task panicked");
+  }
+}
@@ -0,0 +1,141 @@
+//! The engine's watchdog, which detects torrent and disk tasks that got
+//! stuck.
+//!
+//! Every so often the engine pings each task, which the task acknowledges
+//! from its event loop. A task that doesn't acknowledge its ping within the
+//! stall timeout is considered stalled, as its event loop is blocked and it
+//! can't process any other command either.
+
+use std::{fmt, time::Duration};
+
+use tokio::{
+  sync::oneshot::{self, error::TryRecvError},
+  time::Instant,
+};
+
+use crate::TorrentId;
+
+/// A task of the engine that is watched for stalls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Component {
+  Torrent(TorrentId),
+  Disk,
+}
+
+impl fmt::Display for Component {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Component::Torrent(id) => write!(f, "torrent {}", id),
+      Component::Disk => write!(f, "disk task"),
+    }
+  }
+}
+
+/// Tracks the pings sent to a single task.
+#[derive(Debug, Default)]
+pub(crate) struct Heartbeat {
+  /// The time the unacknowledged ping was sent and the receiver of its
+  /// acknowledgment.
+  pending: Option<(Instant, oneshot::Receiver<()>)>,
+  /// Whether the task has been reported as stalled, so that it's only
+  /// reported once per stall.
+  is_stalled: bool,
+}
+
+impl Heartbeat {
+  /// Returns a new ping to send to the task, unless the last one is not yet
+  /// acknowledged.
+  pub fn next_ping(&mut self, now: Instant) -> Option<oneshot::Sender<()>> {
+    if self.pending.is_some() {
+      return None;
+    }
+    let (tx, rx) = oneshot::channel();
+    self.pending = Some((now, rx));
+    Some(tx)
+  }
+
+  /// Checks whether the last ping was acknowledged.
+  ///
+  /// Returns how long the task has been unresponsive if it has just now
+  /// exceeded the stall timeout.
+  pub fn check(
+    &mut self,
+    now: Instant,
+    stall_timeout: Duration,
+  ) -> Option<Duration> {
+    let (sent_at, rx) = self.pending.as_mut()?;
+    match rx.try_recv() {
+      Err(TryRecvError::Empty) => {
+        let elapsed = now.saturating_duration_since(*sent_at);
+        if elapsed >= stall_timeout && !self.is_stalled {
+          self.is_stalled = true;
+          Some(elapsed)
+        } else {
+          None
+        }
+      }
+      // the ping was either acknowledged or the task is no longer running,
+      // neither of which is a stall
+      Ok(()) | Err(TryRecvError::Closed) => {
+        self.pending = None;
+        self.is_stalled = false;
+        None
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+  #[test]
+  fn test_acknowledged_ping() {
+    let mut heartbeat = Heartbeat::default();
+    let now = Instant::now();
+
+    let ping = heartbeat.next_ping(now).unwrap();
+    // no new ping is sent until the last one is acknowledged
+    assert!(heartbeat.next_ping(now).is_none());
+
+    ping.send(()).unwrap();
+    assert_eq!(heartbeat.check(now + STALL_TIMEOUT, STALL_TIMEOUT), None);
+    assert!(!heartbeat.is_stalled);
+    assert!(heartbeat.next_ping(now).is_some());
+  }
+
+  #[test]
+  fn test_stalled_task_is_reported_once() {
+    let mut heartbeat = Heartbeat::default();
+    let now = Instant::now();
+    let ping = heartbeat.next_ping(now).unwrap();
+
+    let later = now + Duration::from_secs(10);
+    assert_eq!(heartbeat.check(later, STALL_TIMEOUT), None);
+
+    let later = now + Duration::from_secs(31);
+    assert_eq!(
+      heartbeat.check(later, STALL_TIMEOUT),
+      Some(Duration::from_secs(31))
+    );
+    assert!(heartbeat.is_stalled);
+    assert_eq!(heartbeat.check(later, STALL_TIMEOUT), None);
+
+    // the task recovers
+    ping.send(()).unwrap();
+    assert_eq!(heartbeat.check(later, STALL_TIMEOUT), None);
+    assert!(!heartbeat.is_stalled);
+  }
+
+  #[test]
+  fn test_stopped_task_is_not_stalled() {
+    let mut heartbeat = Heartbeat::default();
+    let now = Instant::now();
+    drop(heartbeat.next_ping(now));
+
+    assert_eq!(heartbeat.check(now + STALL_TIMEOUT, STALL_TIMEOUT), None);
+    assert!(!heartbeat.is_stalled);
+  }
+}